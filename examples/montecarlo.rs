@@ -41,6 +41,7 @@ use qm::instruments::{
 };
 use qm::models::{
     RcMonteCarloModelFactory,
+    VarianceReduction,
     blackdiffusion::BlackDiffusionFactory,
 };
 use qm::pricers::{
@@ -173,7 +174,7 @@ fn main() {
     let correlation_substep = 20;
     let path_substep = 0.01;
     let model_factory = RcMonteCarloModelFactory::new(Arc::new(BlackDiffusionFactory::new(
-        correlation_substep, path_substep, n_paths)));
+        correlation_substep, path_substep, n_paths, VarianceReduction::None, None).unwrap()));
     let factory = MonteCarloPricerFactory::new(model_factory);
     let mut pricer = factory.new(instrument, fixings, market_data).unwrap();
     let mut save = pricer.as_bumpable().new_saveable();
@@ -181,6 +181,17 @@ uint64_t qm_market_data_from_json_file(const char *source);
  */
 uint64_t qm_market_data_from_json_string(const char *source);
 
+/*
+ * Prices a complete JSON request in one call, returning a JSON response with the
+ * price and any requested reports. See facade::price_request for the format of
+ * the request and response. The source is inline UTF8 text.
+ *
+ * In the event of error, the returned string is a JSON object with a single
+ * 'error' field containing the error message. Either way, the string must be
+ * freed using qm_free_string.
+ */
+char *qm_price_request(const char *source);
+
 /*
  * Loads a pricer factory from a JSON UTF8 file. Pricer factories specify how pricing is to be done. For
  * example, a Monte-Carlo pricer factory specifies the number of paths, and the stochastic model to be
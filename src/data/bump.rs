@@ -3,15 +3,21 @@ use data::bumpdivs::BumpDivs;
 use data::bumpvol::BumpVol;
 use data::bumpyield::BumpYield;
 use data::bumpspotdate::BumpSpotDate;
+use data::bumpcorrelation::BumpCorrelation;
 
-/// Enumeration spanning all bumps of market data
+/// Enumeration spanning all bumps of market data. Bumps compare by value,
+/// so they can be used to identify a bumped state, for example when caching
+/// bumped prices.
+#[derive(Clone, PartialEq)]
 pub enum Bump {
     Spot ( String, BumpSpot ),
     Divs ( String, BumpDivs ),
     Borrow ( String, BumpYield ),
     Vol ( String, BumpVol ),
     Yield ( String, BumpYield ),
-    SpotDate ( BumpSpotDate )
+    Discount ( String, BumpYield ),
+    SpotDate ( BumpSpotDate ),
+    Correlation ( BumpCorrelation )
 }
 
 impl Bump {
@@ -35,9 +41,22 @@ impl Bump {
         Bump::Yield ( credit_id.to_string(), bump )
     }
 
+    /// Bumps the yield curve with the given credit id only where it is used
+    /// for discounting, leaving any forwards that depend on it unchanged.
+    /// This isolates the sensitivity to discounting, as used for rho.
+    pub fn new_discount(credit_id: &str, bump: BumpYield) -> Bump {
+        Bump::Discount ( credit_id.to_string(), bump )
+    }
+
     pub fn new_spot_date(bump: BumpSpotDate) -> Bump {
         Bump::SpotDate ( bump )
     }
+
+    /// Bumps the correlations between assets. Models that generate
+    /// correlated paths must regenerate them, so this can be slow.
+    pub fn new_correlation(bump: BumpCorrelation) -> Bump {
+        Bump::Correlation ( bump )
+    }
 }
 
 /// An interface for applying bumps
@@ -0,0 +1,36 @@
+use core::qm;
+use data::correlation::CorrelationMatrix;
+
+/// Bump that defines all the supported bumps to a correlation matrix
+#[derive(Clone, Debug, PartialEq)]
+pub enum BumpCorrelation {
+    Pairwise { first: String, second: String, delta: f64 }
+}
+
+impl BumpCorrelation {
+    /// Shifts the correlation between two distinct assets by an absolute
+    /// amount. The bumped correlation is clamped to [-1, 1], and if needed
+    /// the shift is reduced so that the matrix stays positive semi-definite.
+    /// See CorrelationMatrix::shift_pair.
+    pub fn new_pairwise(first: &str, second: &str, delta: f64) -> BumpCorrelation {
+        BumpCorrelation::Pairwise { first: first.to_string(),
+            second: second.to_string(), delta: delta }
+    }
+
+    /// Whether the matrix contains all the correlations this bump affects
+    pub fn applies_to(&self, matrix: &CorrelationMatrix) -> bool {
+        match self {
+            &BumpCorrelation::Pairwise { ref first, ref second, .. }
+                => matrix.contains(first) && matrix.contains(second)
+        }
+    }
+
+    /// Applies the bump, returning the bumped matrix
+    pub fn apply(&self, matrix: &CorrelationMatrix)
+        -> Result<CorrelationMatrix, qm::Error> {
+        match self {
+            &BumpCorrelation::Pairwise { ref first, ref second, delta }
+                => matrix.shift_pair(first, second, delta)
+        }
+    }
+}
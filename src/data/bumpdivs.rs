@@ -1,18 +1,29 @@
 use data::divstream::RcDividendStream;
+use dates::Date;
 use data::divstream::DividendStream;
 use data::bump::Bumper;
 use std::sync::Arc;
 
 /// Bump that defines all the supported bumps and risk transformations of a
 /// vol surface.
+#[derive(Clone, PartialEq)]
 pub enum BumpDivs {
     BumpAllRelative { size: f64 },
+    BumpWindowRelative { from: Date, to: Date, size: f64 },
 }
 
 impl BumpDivs {
     pub fn new_all_relative(size: f64) -> BumpDivs {
         BumpDivs::BumpAllRelative { size: size }
     }
+
+    /// Scales only the dividends whose ex-dates are within the window from
+    /// and to, including any that go ex on either boundary. This gives a
+    /// term structure of dividend risk. The dividend yield is left unchanged,
+    /// as it has no ex-dates to place it within the window.
+    pub fn new_window_relative(from: Date, to: Date, size: f64) -> BumpDivs {
+        BumpDivs::BumpWindowRelative { from: from, to: to, size: size }
+    }
 }
 
 impl Bumper<RcDividendStream> for BumpDivs {
@@ -20,7 +31,9 @@ impl Bumper<RcDividendStream> for BumpDivs {
     fn apply(&self, divs: RcDividendStream) -> RcDividendStream {
         match self {
             &BumpDivs::BumpAllRelative { size }
-                => RcDividendStream::new(Arc::new(DividendStream::new_bump_all(&*divs, size)))
+                => RcDividendStream::new(Arc::new(DividendStream::new_bump_all(&*divs, size))),
+            &BumpDivs::BumpWindowRelative { from, to, size }
+                => RcDividendStream::new(Arc::new(DividendStream::new_bump_window(&*divs, from, to, size)))
         }
     }
 }
@@ -1,19 +1,45 @@
 use data::bump::Bumper;
+use data::volsurface::VolForwardDynamics;
+
+/// Defines how the vol surface is re-anchored when the spot is bumped. Under
+/// sticky strike, the vol at any given strike is unchanged. Under sticky
+/// delta, the vol at any given moneyness is unchanged, so the whole smile
+/// moves with the forward.
+pub type VolDynamics = VolForwardDynamics;
 
 /// Bump that defines all the supported bumps to a spot value
-#[derive(Clone)]
+#[derive(Clone, PartialEq)]
 pub enum BumpSpot {
-    Relative { bump: f64 },
-    Replace { spot: f64 }
+    Relative { bump: f64, dynamics: VolDynamics },
+    Replace { spot: f64, dynamics: VolDynamics }
 }
 
 impl BumpSpot {
     pub fn new_relative(bump: f64) -> BumpSpot {
-        BumpSpot::Relative { bump: bump }
+        BumpSpot::new_relative_with_dynamics(bump, VolDynamics::StickyStrike)
     }
 
     pub fn new_replace(spot: f64) -> BumpSpot {
-        BumpSpot::Replace { spot: spot }
+        BumpSpot::new_replace_with_dynamics(spot, VolDynamics::StickyStrike)
+    }
+
+    pub fn new_relative_with_dynamics(bump: f64, dynamics: VolDynamics) -> BumpSpot {
+        BumpSpot::Relative { bump: bump, dynamics: dynamics }
+    }
+
+    pub fn new_replace_with_dynamics(spot: f64, dynamics: VolDynamics) -> BumpSpot {
+        BumpSpot::Replace { spot: spot, dynamics: dynamics }
+    }
+
+    /// The vol dynamics to apply to any vol surface on the bumped
+    /// underlying. Note that these are only applied by contexts that know
+    /// the forward, such as PricingContextPrefetch. Raw market data only
+    /// bumps the spot.
+    pub fn dynamics(&self) -> VolDynamics {
+        match self {
+            &BumpSpot::Relative { dynamics, .. } => dynamics,
+            &BumpSpot::Replace { dynamics, .. } => dynamics
+        }
     }
 }
 
@@ -21,10 +47,8 @@ impl Bumper<f64> for BumpSpot {
 
     fn apply(&self, old_spot: f64) -> f64 {
         match self {
-            &BumpSpot::Relative { bump } => old_spot * (1.0 + bump),
-            &BumpSpot::Replace { spot } => spot
+            &BumpSpot::Relative { bump, .. } => old_spot * (1.0 + bump),
+            &BumpSpot::Replace { spot, .. } => spot
         }
     }
 }
-
-                
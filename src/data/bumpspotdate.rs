@@ -5,7 +5,7 @@ use dates::Date;
 /// data, but does not modify any instruments. As a result, you may end up with
 /// code that works most of the time, but fails when the change of spot date
 /// straddles a lifecycle event.
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct BumpSpotDate {
     spot_date: Date,
     spot_dynamics: SpotDynamics
@@ -21,9 +21,16 @@ impl BumpSpotDate {
 }
 
 /// Enum that defines how spot moves when time is bumped.
+///
+/// The choice matters for theta. Under StickyForward, the forward to expiry
+/// of an option is unchanged, so theta is just the decay of time value plus
+/// the change in discounting. Under StickySpot, the forward also loses a day
+/// of carry. With positive rates net of borrow, this makes the theta of a
+/// call more negative, and that of a put less negative, or even positive.
 #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Hash)]
 pub enum SpotDynamics {
-    /// Spot stays the same, except that any dividends going ex are subtracted
+    /// Spot stays the same, except that any dividends going ex are
+    /// subtracted. The forwards are recalculated from the new spot date.
     StickySpot,
     /// Forwards after the spot date stay the same. In other words, spot moves
     /// up the forward.
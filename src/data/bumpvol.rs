@@ -4,15 +4,19 @@ use data::volsurface::RcVolSurface;
 use data::volsurface::FlatVolSurface;
 use data::voldecorators::TimeScaledBumpVol;
 use data::voldecorators::ParallelBumpVol;
+use data::voldecorators::TenorBumpVol;
+use data::voldecorators::SkewBumpVol;
 use data::bump::Bumper;
 
 /// Bump that defines all the supported bumps and risk transformations of a
 /// vol surface.
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub enum BumpVol {
     FlatAdditive { size: f64 },
+    TenorAdditive { tenor: VolTenor, size: f64 },
     TimeScaled { size: f64, floor: f64 },
-    Replace { vol: f64 }
+    Replace { vol: f64 },
+    Skew { pivot: f64, slope: f64 }
 }
 
 impl BumpVol {
@@ -20,6 +24,11 @@ impl BumpVol {
         BumpVol::FlatAdditive { size: size }
     }
 
+    /// Additive bump to the vols in a single tenor bucket. See VolTenor.
+    pub fn new_tenor_additive(tenor: VolTenor, size: f64) -> BumpVol {
+        BumpVol::TenorAdditive { tenor: tenor, size: size }
+    }
+
     pub fn new_time_scaled(size: f64, floor: f64) -> BumpVol {
         BumpVol::TimeScaled { size: size, floor: floor }
     }
@@ -28,11 +37,21 @@ impl BumpVol {
         BumpVol::Replace { vol }
     }
 
+    /// Rotates the smile about a pivot moneyness (strike over forward). Vols
+    /// change by -slope_change times the log of the strike over the pivot
+    /// strike, so a positive slope_change raises vols below the pivot and
+    /// lowers them above it. See SkewBumpVol.
+    pub fn new_skew(pivot_moneyness: f64, slope_change: f64) -> BumpVol {
+        BumpVol::Skew { pivot: pivot_moneyness, slope: slope_change }
+    }
+
     pub fn bumpsize(&self) -> f64 {
         match self {
             &BumpVol::FlatAdditive { size } => size,
+            &BumpVol::TenorAdditive { tenor: _, size } => size,
             &BumpVol::TimeScaled { size, floor: _ } => size,
-            &BumpVol::Replace { vol: _ } => NAN
+            &BumpVol::Replace { vol: _ } => NAN,
+            &BumpVol::Skew { pivot: _, slope } => slope
         }
     }
 
@@ -46,10 +65,14 @@ impl BumpVol {
         match self {
             &BumpVol::FlatAdditive { size: _ } 
                 => BumpVol::FlatAdditive { size : down_bump },
+            &BumpVol::TenorAdditive { tenor, size: _ }
+                => BumpVol::TenorAdditive { tenor: tenor, size : down_bump },
             &BumpVol::TimeScaled { size: _, floor } 
                 => BumpVol::TimeScaled { size : down_bump, floor: floor },
             &BumpVol::Replace { vol: _ } 
-                => BumpVol::Replace { vol: NAN }
+                => BumpVol::Replace { vol: NAN },
+            &BumpVol::Skew { pivot, slope: _ }
+                => BumpVol::Skew { pivot: pivot, slope: down_bump }
         }
     }
 }
@@ -61,14 +84,79 @@ impl Bumper<RcVolSurface> for BumpVol {
             &BumpVol::FlatAdditive { size }
                 => RcVolSurface::new(Arc::new(ParallelBumpVol::new(surface.clone(), size))),
 
+            &BumpVol::TenorAdditive { tenor, size }
+                => RcVolSurface::new(Arc::new(TenorBumpVol::new(surface.clone(), tenor, size))),
+
             &BumpVol::TimeScaled { size, floor }
                 => RcVolSurface::new(Arc::new(TimeScaledBumpVol::new(surface.clone(), size, floor))),
 
             &BumpVol::Replace { vol }
                 => RcVolSurface::new(Arc::new(FlatVolSurface::new(vol, 
-                    surface.calendar().clone(), surface.base_date())))
+                    surface.calendar().clone(), surface.base_date()))),
+
+            &BumpVol::Skew { pivot, slope }
+                => RcVolSurface::new(Arc::new(SkewBumpVol::new(surface.clone(), pivot, slope)))
         }
     }
 }
 
-                
+/// A bucket of vol time, used for bucketed vega. A bump to the bucket applies
+/// in full to expiries at its tenor, and tapers linearly in vol time to
+/// nothing at the neighbouring tenors. Beyond the first and last tenors of
+/// a ladder, the bump is flat. This means that bumping every bucket of a
+/// ladder by the same amount is the same as a flat bump.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct VolTenor {
+    previous: Option<f64>,
+    tenor: f64,
+    next: Option<f64>
+}
+
+impl VolTenor {
+    /// Creates the buckets for a ladder of tenors, which are vol times
+    /// in increasing order.
+    pub fn ladder(tenors: &[f64]) -> Vec<VolTenor> {
+        let n = tenors.len();
+        (0..n).map(|i| VolTenor {
+            previous: if i > 0 { Some(tenors[i - 1]) } else { None },
+            tenor: tenors[i],
+            next: if i + 1 < n { Some(tenors[i + 1]) } else { None } })
+            .collect()
+    }
+
+    /// The vol time at which this bucket applies in full
+    pub fn tenor(&self) -> f64 { self.tenor }
+
+    /// The fraction of the bump that applies at the given vol time
+    pub fn weight(&self, vol_time: f64) -> f64 {
+        if vol_time <= self.tenor {
+            match self.previous {
+                None => 1.0,
+                Some(previous) => ((vol_time - previous) / (self.tenor - previous)).max(0.0)
+            }
+        } else {
+            match self.next {
+                None => 1.0,
+                Some(next) => ((next - vol_time) / (next - self.tenor)).max(0.0)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vol_tenor_weights_sum_to_one() {
+        let ladder = VolTenor::ladder(&[0.25, 0.5, 1.0, 2.0]);
+        for &t in [0.0, 0.25, 0.3, 0.75, 1.0, 1.7, 2.0, 5.0].iter() {
+            let total: f64 = ladder.iter().map(|b| b.weight(t)).sum();
+            assert!((total - 1.0).abs() < 1e-12, "t={} total={}", t, total);
+        }
+        assert_eq!(ladder[2].weight(1.5), 0.5);
+        assert_eq!(ladder[3].weight(1.5), 0.5);
+        assert_eq!(ladder[1].weight(1.5), 0.0);
+        assert_eq!(ladder[0].weight(0.1), 1.0);
+    }
+}
@@ -1,14 +1,18 @@
 use std::sync::Arc;
+use dates::Date;
 use data::curves::AnnualisedFlatBump;
+use data::curves::AnnualisedTenorBump;
 use data::curves::ContinuouslyCompoundedFlatBump;
 use data::bump::Bumper;
 use data::curves::RcRateCurve;
 
 /// Bump that defines all the supported bumps and risk transformations of a
 /// rate curve such as a borrow curve or a yield curve.
+#[derive(Clone, PartialEq)]
 pub enum BumpYield {
     FlatAnnualised { size: f64 },
-    FlatContinuouslyCompounded { size: f64 }
+    FlatContinuouslyCompounded { size: f64 },
+    TenorAnnualised { tenor: RateTenor, size: f64 }
 }
 
 impl BumpYield {
@@ -19,6 +23,12 @@ impl BumpYield {
     pub fn new_flat_continuously_compounded(size: f64) -> BumpYield {
         BumpYield::FlatContinuouslyCompounded { size: size }
     }
+
+    /// Bump in annualised yield to a single pillar of the curve. See
+    /// RateTenor.
+    pub fn new_tenor_annualised(tenor: RateTenor, size: f64) -> BumpYield {
+        BumpYield::TenorAnnualised { tenor: tenor, size: size }
+    }
 }
 
 impl Bumper<RcRateCurve> for BumpYield {
@@ -34,9 +44,91 @@ impl Bumper<RcRateCurve> for BumpYield {
             // to be a bottleneck.
             &BumpYield::FlatContinuouslyCompounded { size }
                 => RcRateCurve::new(Arc::new(ContinuouslyCompoundedFlatBump::new(
-                    surface.clone(), size)))
+                    surface.clone(), size))),
+
+            &BumpYield::TenorAnnualised { tenor, size }
+                => RcRateCurve::new(Arc::new(AnnualisedTenorBump::new(
+                    surface.clone(), tenor, size)))
         }
     }
 }
 
-                
+/// A bucket of dates around a pillar of a rate curve, used for key-rate
+/// rho. A bump to the bucket applies in full at the pillar, and tapers
+/// linearly in days to nothing at the neighbouring pillars. Beyond the first
+/// and last pillars, the bump is flat. As with VolTenor, bumping every
+/// bucket of a ladder by the same amount is the same as a flat bump.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct RateTenor {
+    previous: Option<Date>,
+    pillar: Date,
+    next: Option<Date>
+}
+
+impl RateTenor {
+    /// Creates the buckets for a ladder of pillar dates, which must be in
+    /// increasing order.
+    pub fn ladder(pillars: &[Date]) -> Vec<RateTenor> {
+        let n = pillars.len();
+        (0..n).map(|i| RateTenor {
+            previous: if i > 0 { Some(pillars[i - 1]) } else { None },
+            pillar: pillars[i],
+            next: if i + 1 < n { Some(pillars[i + 1]) } else { None } })
+            .collect()
+    }
+
+    /// The date at which this bucket applies in full
+    pub fn pillar(&self) -> Date { self.pillar }
+
+    /// Returns true if the bump has no effect on any date up to and
+    /// including the given one, for example if the bucket lies entirely
+    /// beyond the maturity of an instrument.
+    pub fn is_after(&self, date: Date) -> bool {
+        match self.previous {
+            Some(previous) => previous >= date,
+            None => false
+        }
+    }
+
+    /// The fraction of the bump that applies at the given date
+    pub fn weight(&self, date: Date) -> f64 {
+        if date <= self.pillar {
+            match self.previous {
+                None => 1.0,
+                Some(previous) => ((date - previous) as f64
+                    / (self.pillar - previous) as f64).max(0.0)
+            }
+        } else {
+            match self.next {
+                None => 1.0,
+                Some(next) => ((next - date) as f64
+                    / (next - self.pillar) as f64).max(0.0)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_tenor_weights_sum_to_one() {
+        let d = Date::from_ymd(2017, 01, 02);
+        let ladder = RateTenor::ladder(&[d, d + 100, d + 200, d + 400]);
+        for &offset in [-10, 0, 30, 100, 150, 250, 400, 1000].iter() {
+            let total: f64 = ladder.iter().map(|b| b.weight(d + offset)).sum();
+            assert!((total - 1.0).abs() < 1e-12, "offset={} total={}", offset, total);
+        }
+        assert_eq!(ladder[2].weight(d + 300), 0.5);
+        assert_eq!(ladder[3].weight(d + 300), 0.5);
+        assert_eq!(ladder[1].weight(d + 300), 0.0);
+        assert_eq!(ladder[0].weight(d - 10), 1.0);
+
+        // the last bucket has no effect up to the previous pillar
+        assert!(ladder[3].is_after(d + 150));
+        assert!(ladder[3].is_after(d + 200));
+        assert!(!ladder[3].is_after(d + 201));
+        assert!(!ladder[0].is_after(d - 10));
+    }
+}
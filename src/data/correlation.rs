@@ -0,0 +1,212 @@
+use core::qm;
+use std::collections::HashSet;
+use ndarray::Array2;
+use math::cholesky::cholesky_psd;
+
+/// Tolerance used when checking that a bumped matrix is positive
+/// semi-definite, below which a pivot is treated as zero.
+const PSD_TOLERANCE: f64 = 1e-10;
+
+/// Number of bisections used to find the largest shift to a correlation that
+/// keeps the matrix positive semi-definite.
+const PSD_BISECTIONS: usize = 50;
+
+/// A correlation matrix between assets, such as equities, identified by
+/// their ids. The matrix must be square, symmetric, with ones on the diagonal
+/// and all entries between minus one and one. It is not checked here for
+/// positive semi-definiteness, which is only detected when the matrix is
+/// decomposed, for example when generating correlated Monte-Carlo paths.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CorrelationMatrix {
+    ids: Vec<String>,
+    correlations: Vec<Vec<f64>>
+}
+
+impl CorrelationMatrix {
+    /// Creates a correlation matrix, given the ids of the assets and the
+    /// rows of correlations, in the same order as the ids.
+    pub fn new(ids: &[&str], correlations: Vec<Vec<f64>>)
+        -> Result<CorrelationMatrix, qm::Error> {
+
+        let matrix = CorrelationMatrix {
+            ids: ids.iter().map(|id| id.to_string()).collect(),
+            correlations: correlations };
+        matrix.validate()?;
+        Ok(matrix)
+    }
+
+    /// Checks the matrix is a valid correlation matrix. This should be
+    /// invoked after the matrix is deserialized.
+    pub fn validate(&self) -> Result<(), qm::Error> {
+        let n = self.ids.len();
+        if self.correlations.len() != n
+            || self.correlations.iter().any(|row| row.len() != n) {
+            return Err(qm::Error::new(&format!(
+                "Correlation matrix must be square, with {} rows and columns", n)))
+        }
+
+        for i in 0..n {
+            if self.correlations[i][i] != 1.0 {
+                return Err(qm::Error::new(&format!(
+                    "Correlation of '{}' with itself must be one", self.ids[i])))
+            }
+            for j in 0..i {
+                let c = self.correlations[i][j];
+                if !(-1.0..=1.0).contains(&c) {
+                    return Err(qm::Error::new(&format!(
+                        "Correlation {} between '{}' and '{}' is outside [-1, 1]",
+                        c, self.ids[i], self.ids[j])))
+                }
+                if c != self.correlations[j][i] {
+                    return Err(qm::Error::new(&format!(
+                        "Correlation matrix is not symmetric for '{}' and '{}'",
+                        self.ids[i], self.ids[j])))
+                }
+            }
+        }
+
+        let mut unique = HashSet::new();
+        for id in self.ids.iter() {
+            if !unique.insert(id) {
+                return Err(qm::Error::new(&format!(
+                    "Duplicate id '{}' in correlation matrix", id)))
+            }
+        }
+        Ok(())
+    }
+
+    pub fn ids(&self) -> &[String] { &self.ids }
+
+    /// Gets the correlation between two assets, or an error if either of
+    /// them is not in the matrix.
+    pub fn get(&self, first: &str, second: &str) -> Result<f64, qm::Error> {
+        let i = self.find(first)?;
+        let j = self.find(second)?;
+        Ok(self.correlations[i][j])
+    }
+
+    /// Whether the matrix has correlations for the given asset
+    pub fn contains(&self, id: &str) -> bool {
+        self.ids.iter().any(|other| other == id)
+    }
+
+    /// Returns a copy of this matrix with the correlation between two
+    /// distinct assets shifted by delta. The result is clamped to [-1, 1]. If
+    /// the full shift would leave the matrix not positive semi-definite, it
+    /// is reduced to the largest shift in the same direction that does not,
+    /// so callers should check the correlation actually achieved. It is an
+    /// error if either asset is not in the matrix, or if they are the same.
+    pub fn shift_pair(&self, first: &str, second: &str, delta: f64)
+        -> Result<CorrelationMatrix, qm::Error> {
+
+        let i = self.find(first)?;
+        let j = self.find(second)?;
+        if i == j {
+            return Err(qm::Error::new(&format!(
+                "Cannot shift the correlation of '{}' with itself", first)))
+        }
+
+        let old = self.correlations[i][j];
+        let target = (old + delta).max(-1.0).min(1.0);
+        let mut shifted = self.clone();
+        shifted.set_pair(i, j, target);
+        if shifted.is_psd() {
+            return Ok(shifted)
+        }
+
+        // The positive semi-definite matrices are a convex set, so if this
+        // matrix is one of them, the shifts that keep it so form an interval
+        // containing zero. Bisect to find the end of it.
+        let mut lower = 0.0;
+        let mut upper = 1.0;
+        for _ in 0..PSD_BISECTIONS {
+            let mid = 0.5 * (lower + upper);
+            shifted.set_pair(i, j, old + mid * (target - old));
+            if shifted.is_psd() {
+                lower = mid;
+            } else {
+                upper = mid;
+            }
+        }
+        shifted.set_pair(i, j, old + lower * (target - old));
+        Ok(shifted)
+    }
+
+    fn set_pair(&mut self, i: usize, j: usize, correlation: f64) {
+        self.correlations[i][j] = correlation;
+        self.correlations[j][i] = correlation;
+    }
+
+    fn is_psd(&self) -> bool {
+        let n = self.ids.len();
+        let matrix = Array2::from_shape_fn((n, n), |(i, j)| self.correlations[i][j]);
+        cholesky_psd(&matrix, PSD_TOLERANCE).is_ok()
+    }
+
+    fn find(&self, id: &str) -> Result<usize, qm::Error> {
+        self.ids.iter().position(|other| other == id).ok_or_else(||
+            qm::Error::new(&format!("No correlation supplied for '{}'", id)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+
+    #[test]
+    fn correlation_lookup() {
+        let matrix = CorrelationMatrix::new(&["BP.L", "GSK.L"],
+            vec![vec![1.0, 0.6], vec![0.6, 1.0]]).unwrap();
+        assert_eq!(matrix.get("BP.L", "GSK.L").unwrap(), 0.6);
+        assert_eq!(matrix.get("GSK.L", "BP.L").unwrap(), 0.6);
+        assert_eq!(matrix.get("GSK.L", "GSK.L").unwrap(), 1.0);
+        assert!(matrix.get("BP.L", "RIO.L").is_err());
+    }
+
+    #[test]
+    fn invalid_matrices_are_rejected() {
+        let ids = ["BP.L", "GSK.L"];
+        assert!(CorrelationMatrix::new(&ids, vec![vec![1.0, 0.6]]).is_err());
+        assert!(CorrelationMatrix::new(&ids, vec![vec![1.0, 0.6], vec![0.5, 1.0]]).is_err());
+        assert!(CorrelationMatrix::new(&ids, vec![vec![0.9, 0.6], vec![0.6, 1.0]]).is_err());
+        assert!(CorrelationMatrix::new(&ids, vec![vec![1.0, 1.1], vec![1.1, 1.0]]).is_err());
+        assert!(CorrelationMatrix::new(&["BP.L", "BP.L"],
+            vec![vec![1.0, 0.6], vec![0.6, 1.0]]).is_err());
+    }
+
+    #[test]
+    fn shift_pair_clamps_to_positive_semi_definite() {
+        let matrix = CorrelationMatrix::new(&["BP.L", "GSK.L"],
+            vec![vec![1.0, 0.6], vec![0.6, 1.0]]).unwrap();
+        let shifted = matrix.shift_pair("GSK.L", "BP.L", 0.1).unwrap();
+        assert!((shifted.get("BP.L", "GSK.L").unwrap() - 0.7).abs() < 1e-12);
+        assert_eq!(shifted.get("BP.L", "GSK.L").unwrap(),
+            shifted.get("GSK.L", "BP.L").unwrap());
+        assert_eq!(matrix.shift_pair("BP.L", "GSK.L", 0.5).unwrap()
+            .get("BP.L", "GSK.L").unwrap(), 1.0);
+        assert!(matrix.shift_pair("BP.L", "BP.L", 0.1).is_err());
+        assert!(matrix.shift_pair("BP.L", "RIO.L", 0.1).is_err());
+
+        // With BP.L highly correlated to both the others, they must be
+        // correlated to each other by at least 2 * 0.9^2 - 1 = 0.62
+        let ids = ["BP.L", "GSK.L", "RIO.L"];
+        let matrix = CorrelationMatrix::new(&ids, vec![vec![1.0, 0.9, 0.9],
+            vec![0.9, 1.0, 0.9], vec![0.9, 0.9, 1.0]]).unwrap();
+        let shifted = matrix.shift_pair("GSK.L", "RIO.L", -1.0).unwrap();
+        let correlation = shifted.get("GSK.L", "RIO.L").unwrap();
+        assert!((correlation - 0.62).abs() < 1e-6, "correlation={}", correlation);
+        assert!(correlation >= 0.62 - 1e-10, "correlation={}", correlation);
+        shifted.validate().unwrap();
+    }
+
+    #[test]
+    fn serde_correlation_roundtrip() {
+        let matrix = CorrelationMatrix::new(&["BP.L", "GSK.L"],
+            vec![vec![1.0, 0.6], vec![0.6, 1.0]]).unwrap();
+        let serialized = serde_json::to_string_pretty(&matrix).unwrap();
+        let deserialized: CorrelationMatrix = serde_json::from_str(&serialized).unwrap();
+        deserialized.validate().unwrap();
+        assert_eq!(deserialized.get("BP.L", "GSK.L").unwrap(), 0.6);
+    }
+}
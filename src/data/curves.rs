@@ -1,6 +1,9 @@
 use dates::Date;
+use dates::daycount::DayCount;
+use data::bumpyield::RateTenor;
 use math::interpolation::Interpolate;
 use math::interpolation::Linear;
+use math::interpolation::CubicSpline;
 use math::interpolation::Extrap;
 use core::qm;
 use core::factories::TypeId;
@@ -21,6 +24,9 @@ use serde::Deserialize;
 /// calculated. It contains yields, represented by the letter r, which is
 /// a function of time such that the discount factor between time t1 and t2
 /// (fractions of a year) is exp(-r(t2) * t2) / exp(-r(t1) * t1).
+///
+/// Yields may be negative, as they are in some currencies, in which case
+/// discount factors are greater than one.
 pub trait RateCurve : esd::Serialize + TypeId + Sync + Send + Debug {
 
     /// Returns the base date
@@ -34,6 +40,11 @@ pub trait RateCurve : esd::Serialize + TypeId + Sync + Send + Debug {
     /// class.
     fn r_and_t(&self, date: Date) -> Result<(f64, f64), qm::Error>;
 
+    /// Returns the dates of the pillars the curve is interpolated between,
+    /// in increasing order. These define the buckets for tenor bumps. Curves
+    /// with no pillars, such as zero curves, return an empty vector.
+    fn pillar_dates(&self) -> Vec<Date> { Vec::new() }
+
     /// Utility method to return the rate times the time. For example, returns
     /// the log of the discount factor from the base date to the given date
     /// times -1.
@@ -78,9 +89,11 @@ pub fn get_registry() -> &'static TypeRegistry {
             let mut reg = TypeRegistry::new();
             reg.insert("ZeroRateCurve", BoxFnSeed::new(ZeroRateCurve::from_serial));
             reg.insert("RateCurveAct365", BoxFnSeed::new(RateCurveAct365::from_serial));
+            reg.insert("InterpolatedRateCurve", BoxFnSeed::new(InterpolatedRateCurve::from_serial));
             reg.insert("AnnualisedFlatBump", BoxFnSeed::new(AnnualisedFlatBump::from_serial));
             reg.insert("ContinuouslyCompoundedFlatBump", BoxFnSeed::new(ContinuouslyCompoundedFlatBump::from_serial));
             reg.insert("RelativeBump", BoxFnSeed::new(RelativeBump::from_serial));
+            reg.insert("AnnualisedTenorBump", BoxFnSeed::new(AnnualisedTenorBump::from_serial));
             reg
         };
     }
@@ -145,11 +158,15 @@ impl RateCurve for RateCurveAct365 {
             return Ok((0.0, 0.0))
         }
 
-        let t = (act as f64) / 365.0;
+        let t = DayCount::Act365F.year_fraction(self.base, date);
         let r = self.interp.interpolate(date)?;
         Ok((r, t))
     }
 
+    fn pillar_dates(&self) -> Vec<Date> {
+        self.interp.points().iter().map(|&(date, _)| date).collect()
+    }
+
     fn base_date(&self) -> Date {
         self.base
     }
@@ -170,6 +187,167 @@ impl RateCurveAct365 {
     }
 }
 
+/// Methods of interpolating between the pillars of a rate curve.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum Interpolation {
+    /// Linear in yield. Simple, but gives forward rates that jump at the
+    /// pillars.
+    #[default]
+    Linear,
+
+    /// Linear in the log of the discount factor, which gives flat forward
+    /// rates between the pillars. The forwards are positive so long as the
+    /// discount factors decrease. Outside the pillars, the forward rate of
+    /// the nearest section is extended.
+    LogLinear,
+
+    /// Natural cubic spline in yield, which gives continuous forward rates.
+    /// With fewer than three pillars, there is no curvature to fit, so this
+    /// is the same as linear.
+    NaturalCubicSpline
+}
+
+impl Interpolation {
+    fn is_linear(&self) -> bool { *self == Interpolation::Linear }
+}
+
+/// A rate curve interpolated in yield, like RateCurveAct365, but with the
+/// day count convention and the interpolation supplied. The day count
+/// defines the time t used to convert yields to discount factors, so the
+/// same yields give different discount factors under different conventions.
+#[derive(Debug)]
+pub struct InterpolatedRateCurve {
+    inputs: InterpolatedRateCurveInputs,
+    interpolator: RateInterpolator
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct InterpolatedRateCurveInputs {
+    base: Date,
+    interp: Linear<Date>,
+    day_count: DayCount,
+    #[serde(default, skip_serializing_if = "Interpolation::is_linear")]
+    interpolation: Interpolation
+}
+
+/// The interpolator actually used, which is derived from the inputs. For
+/// linear interpolation, we use the inputs directly. The others interpolate
+/// in the time t given by the curve's day count, so that it is the same
+/// time that converts yields to discount factors.
+#[derive(Debug)]
+enum RateInterpolator {
+    Linear,
+    LogLinear(Linear<f64>),
+    CubicSpline(CubicSpline<f64>)
+}
+
+impl TypeId for InterpolatedRateCurve {
+    fn get_type_id(&self) -> &'static str { "InterpolatedRateCurve" }
+}
+
+impl RateCurve for InterpolatedRateCurve {
+    fn r_and_t(&self, date: Date) -> Result<(f64, f64), qm::Error> {
+
+        // Small optimisation if time is zero
+        let base = self.inputs.base;
+        if date == base {
+            return Ok((0.0, 0.0))
+        }
+
+        let t = self.inputs.day_count.year_fraction(base, date);
+        let r = match self.interpolator {
+            RateInterpolator::Linear => self.inputs.interp.interpolate(date)?,
+            RateInterpolator::LogLinear(ref rt) => rt.interpolate(t)? / t,
+            RateInterpolator::CubicSpline(ref spline) => spline.interpolate(t)?
+        };
+        Ok((r, t))
+    }
+
+    fn pillar_dates(&self) -> Vec<Date> {
+        self.inputs.interp.points().iter().map(|&(date, _)| date).collect()
+    }
+
+    fn base_date(&self) -> Date {
+        self.inputs.base
+    }
+}
+
+impl InterpolatedRateCurve {
+
+    pub fn new(base: Date, curve: &[(Date, f64)], left: Extrap, right: Extrap,
+        day_count: DayCount, interpolation: Interpolation)
+        -> Result<InterpolatedRateCurve, qm::Error> {
+
+        let interp = Linear::new(curve, left, right)?;
+        InterpolatedRateCurve::from_inputs(InterpolatedRateCurveInputs {
+            base: base, interp: interp, day_count: day_count,
+            interpolation: interpolation })
+    }
+
+    fn from_inputs(inputs: InterpolatedRateCurveInputs)
+        -> Result<InterpolatedRateCurve, qm::Error> {
+
+        let base = inputs.base;
+        let day_count = inputs.day_count;
+        let points: Vec<(f64, f64)> = inputs.interp.points().iter()
+            .map(|&(date, r)| (day_count.year_fraction(base, date), r)).collect();
+        let interpolator = match inputs.interpolation {
+            Interpolation::Linear => RateInterpolator::Linear,
+
+            // The log of the discount factor is -rt, which is zero at the
+            // base date. Extrapolating it linearly extends the forwards.
+            Interpolation::LogLinear => {
+                let mut rt = Vec::with_capacity(points.len() + 1);
+                if points[0].0 > 0.0 {
+                    rt.push((0.0, 0.0));
+                }
+                for &(t, r) in points.iter() {
+                    rt.push((t, r * t));
+                }
+                if rt.len() < 2 {
+                    RateInterpolator::Linear
+                } else {
+                    RateInterpolator::LogLinear(Linear::new(&rt,
+                        Extrap::Natural, Extrap::Natural)?)
+                }
+            },
+
+            Interpolation::NaturalCubicSpline => if points.len() < 3 {
+                RateInterpolator::Linear
+            } else {
+                RateInterpolator::CubicSpline(CubicSpline::natural(&points,
+                    inputs.interp.left(), inputs.interp.right())?)
+            }
+        };
+
+        Ok(InterpolatedRateCurve { inputs: inputs, interpolator: interpolator })
+    }
+
+    pub fn day_count(&self) -> DayCount { self.inputs.day_count }
+    pub fn interpolation(&self) -> Interpolation { self.inputs.interpolation }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<RcRateCurve, esd::Error> {
+        Ok(Qrc::new(Arc::new(InterpolatedRateCurve::deserialize(de)?)))
+    }
+}
+
+// As with CubicSpline, we serialize just the inputs, and calculate the
+// interpolator when deserializing.
+impl sd::Serialize for InterpolatedRateCurve {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where S: sd::Serializer {
+        self.inputs.serialize(serializer)
+    }
+}
+
+impl<'de> sd::Deserialize<'de> for InterpolatedRateCurve {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where D: sd::Deserializer<'de> {
+        let inputs = InterpolatedRateCurveInputs::deserialize(deserializer)?;
+        InterpolatedRateCurve::from_inputs(inputs).map_err(sd::de::Error::custom)
+    }
+}
+
 /// Decorator that applies a flat bump in annualised yield to a rate curve
 #[derive(Serialize, Deserialize, Debug)]
 pub struct AnnualisedFlatBump {
@@ -196,11 +374,11 @@ impl RateCurve for AnnualisedFlatBump {
         //                = t * log(exp(r) + dy)
          
         let (r, t) = self.curve.r_and_t(date)?;
-
-        let r_bumped = (r.exp() + self.bump).ln();
-        Ok((r_bumped, t))
+        Ok((bump_annualised(r, self.bump)?, t))
     }
 
+    fn pillar_dates(&self) -> Vec<Date> { self.curve.pillar_dates() }
+
     fn base_date(&self) -> Date {
         self.curve.base_date()
     }
@@ -216,6 +394,63 @@ impl AnnualisedFlatBump {
     }
 }
 
+/// Applies a bump in annualised yield to the continuously compounded yield r
+fn bump_annualised(r: f64, bump: f64) -> Result<f64, qm::Error> {
+
+    // With negative rates, exp(r) is less than one, so a large negative
+    // bump could make the annualised yield negative, which has no log
+    let annualised = r.exp() + bump;
+    if annualised <= 0.0 {
+        return Err(qm::Error::new(&format!(
+            "Annualised yield bump {} is too negative for the yield {}",
+            bump, r)))
+    }
+
+    Ok(annualised.ln())
+}
+
+/// Decorator that applies a bump in annualised yield to a single tenor
+/// bucket of a rate curve, as defined by the RateTenor. Dates outside the
+/// bucket are left exactly unchanged.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AnnualisedTenorBump {
+    curve: RcRateCurve,
+    tenor: RateTenor,
+    bump: f64
+}
+
+impl TypeId for AnnualisedTenorBump {
+    fn get_type_id(&self) -> &'static str { "AnnualisedTenorBump" }
+}
+
+impl RateCurve for AnnualisedTenorBump {
+
+    fn r_and_t(&self, date: Date) -> Result<(f64, f64), qm::Error> {
+        let (r, t) = self.curve.r_and_t(date)?;
+        let weight = self.tenor.weight(date);
+        if weight == 0.0 {
+            return Ok((r, t))
+        }
+        Ok((bump_annualised(r, self.bump * weight)?, t))
+    }
+
+    fn pillar_dates(&self) -> Vec<Date> { self.curve.pillar_dates() }
+
+    fn base_date(&self) -> Date {
+        self.curve.base_date()
+    }
+}
+
+impl AnnualisedTenorBump {
+    pub fn new(curve: RcRateCurve, tenor: RateTenor, bump: f64) -> AnnualisedTenorBump {
+        AnnualisedTenorBump { curve: curve, tenor: tenor, bump: bump }
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<RcRateCurve, esd::Error> {
+        Ok(Qrc::new(Arc::new(AnnualisedTenorBump::deserialize(de)?)))
+    }
+}
+
 /// Decorator that applies a flat bump in contnuously compounded yield
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ContinuouslyCompoundedFlatBump {
@@ -234,6 +469,8 @@ impl RateCurve for ContinuouslyCompoundedFlatBump {
         Ok((r + self.bump, t))
     }
 
+    fn pillar_dates(&self) -> Vec<Date> { self.curve.pillar_dates() }
+
     fn base_date(&self) -> Date {
         self.curve.base_date()
     }
@@ -269,6 +506,10 @@ impl RateCurve for RelativeBump {
         Ok((r * self.one_plus_bump, t))
     }
 
+    fn is_zero(&self) -> bool { self.curve.is_zero() }
+
+    fn pillar_dates(&self) -> Vec<Date> { self.curve.pillar_dates() }
+
     fn base_date(&self) -> Date {
         self.curve.base_date()
     }
@@ -299,6 +540,36 @@ mod tests {
         assert_rt(c.rt(base + 7), 0.0);
     }
     
+    #[test]
+    fn negative_rates() {
+
+        // A flat negative yield gives discount factors above one, whatever
+        // the interpolation
+        let base = Date::from_ymd(2017, 01, 01);
+        let points = [(base + 30, -0.005), (base + 365, -0.005), (base + 730, -0.005)];
+        let act365 = RateCurveAct365::new(base, &points,
+            Extrap::Flat, Extrap::Flat).unwrap();
+        let expected = (0.005f64 * 500.0 / 365.0).exp();
+        assert_approx(act365.df(base + 500, base).unwrap(), expected, 1e-14);
+        for &interpolation in [Interpolation::Linear, Interpolation::LogLinear,
+            Interpolation::NaturalCubicSpline].iter() {
+            let curve = InterpolatedRateCurve::new(base, &points, Extrap::Flat,
+                Extrap::Flat, DayCount::Act365F, interpolation).unwrap();
+            let df = curve.df(base + 500, base).unwrap();
+            assert_approx(df, expected, 1e-12);
+            assert!(df > 1.0);
+        }
+
+        // Bumps work as normal, but an annualised bump so negative that
+        // there is no equivalent continuously compounded yield is an error
+        let curve = RcRateCurve::new(Arc::new(act365));
+        let bumped = AnnualisedFlatBump::new(curve.clone(), -0.001);
+        let r = ((-0.005f64).exp() - 0.001).ln();
+        assert_rt(bumped.rt(base + 500), r * 500.0 / 365.0);
+        let bumped = AnnualisedFlatBump::new(curve.clone(), -1.0);
+        assert!(bumped.rt(base + 500).is_err());
+    }
+
     #[test]
     fn check_curves() {
 
@@ -316,6 +587,135 @@ mod tests {
         assert_rt(c.rt(d + 365), 0.082 * 365.0 / 365.0);
     }
 
+    #[test]
+    fn curves_with_day_counts() {
+
+        // the same yields with different day counts
+        let base = Date::from_ymd(2019, 02, 28);
+        let d = base;
+        let points = [(d, 0.05), (d + 365, 0.05)];
+        let end = Date::from_ymd(2019, 03, 30);
+        for &(day_count, t) in [(DayCount::Act365F, 30.0 / 365.0),
+            (DayCount::Act360, 30.0 / 360.0), (DayCount::Thirty360US, 30.0 / 360.0)].iter() {
+
+            let c = InterpolatedRateCurve::new(base, &points,
+                Extrap::Flat, Extrap::Flat, day_count, Interpolation::Linear).unwrap();
+            assert_rt(c.rt(end), 0.05 * t);
+        }
+
+        // Act/365F matches the standard curve
+        let c = InterpolatedRateCurve::new(base, &points,
+            Extrap::Flat, Extrap::Flat, DayCount::Act365F, Interpolation::Linear).unwrap();
+        let standard = RateCurveAct365::new(base, &points,
+            Extrap::Flat, Extrap::Flat).unwrap();
+        for i in 0..400 {
+            assert_eq!(c.rt(d + i).unwrap(), standard.rt(d + i).unwrap());
+        }
+    }
+
+    fn interpolated_curve(interpolation: Interpolation, points: &[(Date, f64)])
+        -> InterpolatedRateCurve {
+        let base = Date::from_ymd(2017, 01, 01);
+        InterpolatedRateCurve::new(base, points, Extrap::Flat, Extrap::Flat,
+            DayCount::Act365F, interpolation).unwrap()
+    }
+
+    fn sample_pillars() -> Vec<(Date, f64)> {
+        let d = Date::from_ymd(2017, 01, 01);
+        vec![(d + 100, 0.01), (d + 200, 0.03), (d + 300, 0.02)]
+    }
+
+    #[test]
+    fn interpolated_curves_exact_at_pillars() {
+        let points = sample_pillars();
+        for &interpolation in [Interpolation::Linear, Interpolation::LogLinear,
+            Interpolation::NaturalCubicSpline].iter() {
+
+            let c = interpolated_curve(interpolation, &points);
+            for &(date, r) in points.iter() {
+                let t = (date - c.base_date()) as f64 / 365.0;
+                assert_rt(c.rt(date), r * t);
+            }
+        }
+    }
+
+    #[test]
+    fn interpolated_curves_at_midpoints() {
+        let points = sample_pillars();
+        let d = Date::from_ymd(2017, 01, 01);
+        let t = 150.0 / 365.0;
+
+        // linear in yield
+        let c = interpolated_curve(Interpolation::Linear, &points);
+        assert_rt(c.rt(d + 150), 0.02 * t);
+
+        // Log-linear in discount factor means rt is linear in time, and the
+        // forward is flat between the pillars and from the base date
+        let c = interpolated_curve(Interpolation::LogLinear, &points);
+        assert_rt(c.rt(d + 150), 0.5 * (0.01 * 100.0 + 0.03 * 200.0) / 365.0);
+        assert_rt(c.rt(d + 50), 0.01 * 50.0 / 365.0);
+        let forward = |from: i32, to: i32| c.rt(d + to).unwrap() - c.rt(d + from).unwrap();
+        assert!(approx_eq(forward(110, 120), forward(180, 190), 1e-14));
+        assert!(forward(110, 120) > 0.0);
+
+        // For a natural spline with three equally spaced pillars, the second
+        // derivative at the middle pillar is 1.5 (y0 - 2 y1 + y2) / h^2, so
+        // the midpoint of the first section is the linear value less
+        // h^2 / 16 times that
+        let c = interpolated_curve(Interpolation::NaturalCubicSpline, &points);
+        let curvature = 1.5 * (0.01 - 2.0 * 0.03 + 0.02);
+        assert_rt(c.rt(d + 150), (0.02 - curvature / 16.0) * t);
+        assert_rt(c.rt(d + 250), (0.025 - curvature / 16.0) * t * 250.0 / 150.0);
+    }
+
+    #[test]
+    fn log_linear_curve_interpolates_in_day_count_time() {
+        // Under 30/360, April 1st is 40% of the way from February 1st to
+        // July 1st, though it is less than that in calendar days
+        let base = Date::from_ymd(2017, 01, 01);
+        let points = [(Date::from_ymd(2017, 02, 01), 0.01),
+            (Date::from_ymd(2017, 07, 01), 0.03)];
+        let c = InterpolatedRateCurve::new(base, &points, Extrap::Flat,
+            Extrap::Flat, DayCount::Thirty360US, Interpolation::LogLinear).unwrap();
+        let rt0 = 0.01 * 30.0 / 360.0;
+        let rt1 = 0.03 * 180.0 / 360.0;
+        assert_rt(c.rt(Date::from_ymd(2017, 04, 01)), rt0 + 0.4 * (rt1 - rt0));
+    }
+
+    #[test]
+    fn cubic_spline_curve_with_two_pillars_is_linear() {
+        let points = &sample_pillars()[..2];
+        let spline = interpolated_curve(Interpolation::NaturalCubicSpline, points);
+        let linear = interpolated_curve(Interpolation::Linear, points);
+        let d = Date::from_ymd(2017, 01, 01);
+        for i in 0..400 {
+            assert_eq!(spline.rt(d + i).unwrap(), linear.rt(d + i).unwrap());
+        }
+
+        let single = interpolated_curve(Interpolation::NaturalCubicSpline, &points[..1]);
+        assert_rt(single.rt(d + 365), 0.01);
+    }
+
+    #[test]
+    fn interpolated_curve_serde() {
+        let points = sample_pillars();
+        let curve = RcRateCurve::new(Arc::new(interpolated_curve(
+            Interpolation::NaturalCubicSpline, &points)));
+        let serialized = serde_json::to_string(&curve).unwrap();
+        let deserialized: RcRateCurve = serde_json::from_str(&serialized).unwrap();
+        let d = Date::from_ymd(2017, 01, 01);
+        for i in 0..400 {
+            assert_eq!(deserialized.rt(d + i).unwrap(), curve.rt(d + i).unwrap());
+        }
+
+        // linear is the default, so it is not written out
+        let linear = interpolated_curve(Interpolation::Linear, &points);
+        let serialized = serde_json::to_string(&linear).unwrap();
+        assert!(!serialized.contains("interpolation"), "{}", serialized);
+        let deserialized: InterpolatedRateCurve = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(deserialized.interpolation(), Interpolation::Linear);
+    }
+
     #[test]
     fn rate_curve_serde() {
 
@@ -370,4 +770,9 @@ mod tests {
         assert!(approx_eq(interpolated, v, 1e-12), "interpolated={} v={}",
             interpolated, v);
     }
+
+    fn assert_approx(value: f64, expected: f64, tolerance: f64) {
+        assert!(approx_eq(value, expected, tolerance),
+            "value={} expected={}", value, expected);
+    }
 }
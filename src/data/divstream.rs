@@ -78,8 +78,10 @@ impl Dividend {
 //#[derive(Serialize, Deserialize)]
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct DividendStream {
+    #[serde(default)]
     dividends: Vec<Dividend>,
     div_yield: RcRateCurve,
+    #[serde(default = "Date::from_nil")]
     last_cash_ex_date: Date
 }
 
@@ -103,6 +105,13 @@ impl DividendStream {
             last_cash_ex_date: last_cash_ex_date }
     }
 
+    /// Creates a dividend stream with no discrete dividends, where all the
+    /// dividends are expressed as a continuous yield. This is normal for
+    /// indices, where individual dividends are not modelled.
+    pub fn from_yield(div_yield: RcRateCurve) -> DividendStream {
+        DividendStream::new(&[], div_yield)
+    }
+
     /// Constructor used when bumping. Applies a relative bump to all dividends
     /// and the dividend yield
     pub fn new_bump_all(divs: &DividendStream, bump: f64) -> DividendStream {
@@ -121,6 +130,26 @@ impl DividendStream {
             last_cash_ex_date: divs.last_cash_ex_date }
     }
 
+    /// Constructor used when bumping. Applies a relative bump to the
+    /// dividends that go ex within the window from and to inclusive, leaving
+    /// the other dividends and the dividend yield unchanged.
+    pub fn new_bump_window(divs: &DividendStream, from: Date, to: Date, bump: f64)
+        -> DividendStream {
+
+        let mut bumped_divs = divs.dividends.to_vec();
+        let one_plus_bump = bump + 1.0;
+        for div in bumped_divs.iter_mut() {
+            if div.ex_date() >= from && div.ex_date() <= to {
+                div.bump_all_relative(one_plus_bump);
+            }
+        }
+
+        DividendStream {
+            dividends: bumped_divs,
+            div_yield: divs.div_yield(),
+            last_cash_ex_date: divs.last_cash_ex_date }
+    }
+
     pub fn dividends(&self) -> &[Dividend] { &self.dividends }
     pub fn div_yield(&self) -> RcRateCurve { self.div_yield.clone() }
     pub fn last_cash_ex_date(&self) -> Date { self.last_cash_ex_date }
@@ -170,7 +199,8 @@ pub struct DividendAccumulation {
     undiscounted_sum: f64,	    // sum of all divs to this point
     discounted_sum: f64,            // NPV of all divs to this point 
     discounted_cash: f64,           // NPV of cash divs to this point
-    discounted_cash_remaining: f64  // NPV of cash divs beyond this point 
+    discounted_cash_remaining: f64, // NPV of cash divs beyond this point 
+    discounted_sum_delta: f64       // d(discounted_sum)/d(spot)
 }
 
 pub struct DividendBootstrap {
@@ -224,7 +254,9 @@ impl DividendBootstrap {
         let mut undiscounted_sum = 0.0;
         let mut discounted_sum = 0.0;
         let mut discounted_cash = 0.0;
+        let mut discounted_sum_delta = 0.0;
         let mut prev_discounted_sum = 0.0;
+        let mut prev_discounted_sum_delta = 0.0;
 
         for dividend in div_stream.dividends.iter() {
             let ex_date = dividend.ex_date;
@@ -243,9 +275,11 @@ impl DividendBootstrap {
                     undiscounted_sum : undiscounted_sum, 
                     discounted_sum : discounted_sum,
                     discounted_cash : discounted_cash,
-                    discounted_cash_remaining : NAN });
+                    discounted_cash_remaining : NAN,
+                    discounted_sum_delta : discounted_sum_delta });
                 prev_ex_date = ex_date;
                 prev_discounted_sum = discounted_sum;
+                prev_discounted_sum_delta = discounted_sum_delta;
             }
 
             // add cash dividends
@@ -269,6 +303,10 @@ impl DividendBootstrap {
                 let relative_amount = relative * fwd;
                 undiscounted_sum += relative_amount;
                 discounted_sum += relative_amount * df;
+
+                // the relative amount is affine in spot, so track its slope
+                let fwd_delta = (1.0 - prev_discounted_sum_delta) * growth;
+                discounted_sum_delta += relative * fwd_delta * df;
             }
         }
 
@@ -279,7 +317,8 @@ impl DividendBootstrap {
                 undiscounted_sum : undiscounted_sum, 
                 discounted_sum : discounted_sum,
                 discounted_cash : discounted_cash,
-                discounted_cash_remaining : NAN });
+                discounted_cash_remaining : NAN,
+                discounted_sum_delta : discounted_sum_delta });
         } 
 
         // Now walk backward, filling in the discounted_cash_remaining
@@ -288,7 +327,7 @@ impl DividendBootstrap {
                 discounted_cash;
             for acc in accumulation.iter_mut().rev() {
                 acc.discounted_cash_remaining = 
-                    final_discounted_cash - acc.discounted_cash_remaining;
+                    final_discounted_cash - acc.discounted_cash;
             }
         }
 
@@ -386,8 +425,29 @@ impl DividendBootstrap {
     }
 
 
+    /// Returns the derivative with respect to the spot passed to the
+    /// bootstrap of discounted_sum_from_base. Only relative dividends
+    /// contribute, as they are proportional to the forward on their ex date.
+    pub fn discounted_sum_delta_from_base(&self, to: Date)
+        -> Result<f64, qm::Error> {
+
+        if to > self.high_water_mark {
+            return Err(qm::Error::new("Accessing dividend stream \
+                past the previously stated high_water mark"))
+        }
+
+        match self.accumulation.binary_search_by(
+            |p| p.ex_date.cmp(&to)) {
+            Ok(i) => Ok(self.accumulation[i].discounted_sum_delta),
+            Err(i) => if i == 0 { Ok(0.0) } else {
+                Ok(self.accumulation[i-1].discounted_sum_delta)
+            }
+        }
+    }
+
     /// Returns the NPV of all cash dividend amounts after the given date.
-    /// All amounts are discounted to the base date.
+    /// Dividends on the given date are not included, so this steps down on
+    /// each ex date. All amounts are discounted to the base date.
     pub fn discounted_cash_divs_after(&self, from: Date)
         -> Result<f64, qm::Error> {
 
@@ -403,12 +463,16 @@ impl DividendBootstrap {
             // if we found it, return the remaining cash at this div
             Ok(i) => Ok(self.accumulation[i].discounted_cash_remaining),
 
-            // If we missed and it was after the last date, return zero.
-            // This also catches the case where there are no dividends
-            Err(i) => if i == self.accumulation.len() { Ok(0.0) } else {
+            // If we missed and it was before the first date, return all
+            // the cash. This also catches the case where there are no
+            // dividends
+            Err(i) => if i == 0 {
+                Ok(self.accumulation.first().map_or(0.0,
+                    |acc| acc.discounted_cash + acc.discounted_cash_remaining))
+            } else {
 
-                // otherwise, return the remaining cash at the next div
-                Ok(self.accumulation[i].discounted_cash_remaining)
+                // otherwise, return the remaining cash at the previous div
+                Ok(self.accumulation[i-1].discounted_cash_remaining)
             }
         }
     }
@@ -470,6 +534,29 @@ mod tests {
         assert_cash(b.discounted_sum(d + 28, d + 210), 0.9562351685379344);
     }
 
+    #[test]
+    fn check_discounted_cash_divs_after() {
+
+        let d = Date::from_ymd(2017, 01, 02);
+        let divs = create_sample_divstream();
+        let b = create_sample_bootstrap(&divs, d + 1000);
+
+        // the remaining cash steps down by the NPV of the cash part of each
+        // dividend on its ex date, and is constant in between
+        let all_cash = b.discounted_cash_divs_after(d).unwrap();
+        assert_cash(b.discounted_cash_divs_after(d + 27), all_cash);
+        assert_cash(b.discounted_cash_divs_after(d + 28),
+            all_cash - 1.192633077939713);
+        let after_first = b.discounted_cash_divs_after(d + 28).unwrap();
+        assert_cash(b.discounted_cash_divs_after(d + 209), after_first);
+        assert!(b.discounted_cash_divs_after(d + 210).unwrap() < after_first);
+        assert!(b.discounted_cash_divs_after(d + 391).unwrap() > 0.0);
+
+        // the last dividend is purely relative
+        assert_cash(b.discounted_cash_divs_after(d + 392), 0.0);
+        assert_cash(b.discounted_cash_divs_after(d + 800), 0.0);
+    }
+
     fn create_sample_divstream() -> DividendStream {
 
         // Early divs are purely cash. Later ones are mixed cash/relative
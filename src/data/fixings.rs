@@ -1,5 +1,8 @@
 use dates::Date;
 use dates::datetime::DateTime;
+use dates::calendar::Calendar;
+use dates::calendar::RcCalendar;
+use dates::calendar::RollConvention;
 use core::qm;
 use std::collections::HashMap;
 use std::sync::Arc;
@@ -7,12 +10,51 @@ use std::ops::Deref;
 use serde as sd;
 
 /// A fixing table is a collection of fixing curves, keyed by instrument id.
-#[derive(Debug, Clone, Deserialize, Serialize)]
+///
+/// Optionally, the table can have a calendar and roll convention. Fixing
+/// dates are then rolled onto business days before looking up the fixing,
+/// so an instrument whose schedule is rolled out with simple date arithmetic
+/// finds the fixing taken on the relevant business day.
+#[derive(Debug, Clone, Serialize)]
 pub struct FixingTable {
     fixings_known_until: Date,
-    fixings_by_id: HashMap<String, Fixings>
+    fixings_by_id: HashMap<String, Fixings>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    calendar: Option<(RcCalendar, RollConvention)>,
+    #[serde(default = "default_roll_window", skip_serializing_if = "is_default_roll_window")]
+    roll_window: u32
 }
 
+/// The serialized form of a fixing table, which is validated before the
+/// table is built from it.
+#[derive(Deserialize)]
+struct FixingTableInputs {
+    fixings_known_until: Date,
+    fixings_by_id: HashMap<String, Fixings>,
+    #[serde(default)]
+    calendar: Option<(RcCalendar, RollConvention)>,
+    #[serde(default = "default_roll_window")]
+    roll_window: u32
+}
+
+impl<'de> sd::Deserialize<'de> for FixingTable {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where D: sd::Deserializer<'de> {
+        let inputs: FixingTableInputs = sd::Deserialize::deserialize(deserializer)?;
+        for (id, fixings) in inputs.fixings_by_id.iter() {
+            check_known(id, fixings, inputs.fixings_known_until)
+                .map_err(sd::de::Error::custom)?;
+        }
+        Ok(FixingTable { fixings_known_until: inputs.fixings_known_until,
+            fixings_by_id: inputs.fixings_by_id, calendar: inputs.calendar,
+            roll_window: inputs.roll_window })
+    }
+}
+
+fn default_roll_window() -> u32 { 7 }
+
+fn is_default_roll_window(days: &u32) -> bool { *days == default_roll_window() }
+
 impl FixingTable {
 
     /// Creates a fixing table, given a date to which fixings are known and an
@@ -49,12 +91,42 @@ impl FixingTable {
     /// Creates an empty fixing table, given a date to which fixings are known.
     pub fn new(fixings_known_until: Date) -> FixingTable {
         FixingTable { fixings_known_until: fixings_known_until,
-            fixings_by_id: HashMap::new() }
+            fixings_by_id: HashMap::new(), calendar: None,
+            roll_window: default_roll_window() }
+    }
+
+    /// Makes fixing lookups roll the fixing date onto a business day in the
+    /// given calendar, using the given convention. The time of day is
+    /// unchanged.
+    pub fn set_calendar(&mut self, calendar: RcCalendar, convention: RollConvention) {
+        self.calendar = Some((calendar, convention));
+    }
+
+    /// Resolves the date and time at which a fixing is actually taken. This
+    /// is the date time itself unless there is a calendar.
+    pub fn fixing_date_time(&self, date_time: DateTime) -> DateTime {
+        match self.calendar {
+            None => date_time,
+            Some((ref calendar, convention)) => DateTime::new(
+                calendar.roll(date_time.date(), convention), date_time.time_of_day())
+        }
     }
 
-    /// Adds a fixings curve
+    /// Sets the maximum number of calendar days that fixing_or_roll will
+    /// search back for a fixing. The default is seven days, which covers a
+    /// weekend next to a couple of holidays.
+    pub fn set_roll_window(&mut self, days: u32) {
+        self.roll_window = days;
+    }
+
+    /// Adds a fixings curve. It is an error for the curve to contain a
+    /// fixing after the date to which fixings are known, as this would be
+    /// an observation that cannot have happened yet. Fixings on that date
+    /// are allowed at any time of day, as a close fixing may be known by
+    /// the time of pricing.
     pub fn insert(&mut self, id: &str, fixings: Fixings) 
         -> Result<(), qm::Error> {
+        check_known(id, &fixings, self.fixings_known_until)?;
         if let Some(_) = self.fixings_by_id.insert(id.to_string(), fixings) {
             return Err(duplicate_fixing_curve(id))
         }
@@ -66,11 +138,12 @@ impl FixingTable {
     pub fn get(&self, id: &str, date_time: DateTime)
         -> Result<Option<f64>, qm::Error> {
 
-        match self.get_optional(id, date_time) {
+        let fixing_date_time = self.fixing_date_time(date_time);
+        match self.get_fixings(id).and_then(|f| f.get_optional(fixing_date_time)) {
             Some(fixing) => Ok(Some(fixing)),
 
-            None => { if date_time.date() < self.fixings_known_until {
-                Err(missing_fixing(id, date_time)) } else { Ok(None) }
+            None => { if fixing_date_time.date() < self.fixings_known_until {
+                Err(missing_fixing(id, fixing_date_time)) } else { Ok(None) }
             }
         }
     }
@@ -79,11 +152,56 @@ impl FixingTable {
     /// if the fixing is not found.
     pub fn get_optional(&self, id: &str, date_time: DateTime) -> Option<f64> {
         match self.get_fixings(id) {
-            Some(fixings) => fixings.get_optional(date_time),
+            Some(fixings) => fixings.get_optional(self.fixing_date_time(date_time)),
             None => None
         }
     }
 
+    /// Tries to get a fixing for the given instrument and date, as get, but
+    /// tolerating gaps in the data. If there is no fixing at the given date
+    /// and time, we look for one at the same time of day on each earlier
+    /// business day in the given calendar, going back as far as the roll
+    /// window. This is for data vendors that skip holidays, rather than
+    /// supplying the previous business day's value.
+    ///
+    /// If the date is a business day that is not yet in the past, a missing
+    /// fixing is simply not yet known, so this returns None. Otherwise, if
+    /// there is no fixing within the window, it is an error.
+    pub fn fixing_or_roll(&self, id: &str, date_time: DateTime, calendar: &Calendar)
+        -> Result<Option<f64>, qm::Error> {
+
+        if let Some(fixing) = self.get_optional(id, date_time) {
+            return Ok(Some(fixing))
+        }
+
+        let date = date_time.date();
+        let past = date < self.fixings_known_until;
+        if !past && !calendar.is_holiday(date) {
+            return Ok(None)
+        }
+
+        if let Some(fixings) = self.get_fixings(id) {
+            let mut business_day = date;
+            loop {
+                business_day = calendar.step(business_day - 1, 0, false);
+                if date - business_day > self.roll_window as i32 {
+                    break
+                }
+                let rolled = DateTime::new(business_day, date_time.time_of_day());
+                if let Some(fixing) = fixings.get_optional(rolled) {
+                    return Ok(Some(fixing))
+                }
+            }
+        }
+
+        if past {
+            Err(qm::Error::new(&format!("Missing fixing for \"{}\" at {}, \
+                and none in the {} days before", id, date_time, self.roll_window)))
+        } else {
+            Ok(None)
+        }
+    }
+
     /// Tries to get an entire fixing curve by id. Return None if there is
     /// none.
     pub fn get_fixings(&self, id: &str) -> Option<&Fixings> {
@@ -104,6 +222,23 @@ pub fn missing_fixing(id: &str, date_time: DateTime) -> qm::Error {
     qm::Error::new(&format!("Missing fixing for \"{}\" at {}", id, date_time))
 }
 
+fn future_fixing(id: &str, date_time: DateTime, fixings_known_until: Date)
+    -> qm::Error {
+    qm::Error::new(&format!("Fixing for \"{}\" at {} is after {}, the date \
+        to which fixings are known", id, date_time, fixings_known_until))
+}
+
+/// Checks that a fixings curve has nothing after the date to which fixings
+/// are known.
+fn check_known(id: &str, fixings: &Fixings, fixings_known_until: Date)
+    -> Result<(), qm::Error> {
+    if let Some(date_time) = fixings.fixing_by_date.keys()
+        .filter(|d| d.date() > fixings_known_until).min() {
+        return Err(future_fixing(id, *date_time, fixings_known_until))
+    }
+    Ok(())
+}
+
 fn duplicate_fixing_curve(id: &str) -> qm::Error {
     qm::Error::new(&format!("Duplicate fixing curve supplied for {}", id))
 }
@@ -247,6 +382,7 @@ fn duplicate_fixing(id: &str, v1: f64, v2: f64, date_time: DateTime)
 mod tests {
     use super::*;
     use dates::datetime::TimeOfDay;
+    use dates::calendar::HolidayCalendar;
     use serde_json;
 
     fn sample_fixings() -> FixingTable {
@@ -334,6 +470,126 @@ mod tests {
         }
     }
 
+    /// Fixings on business days around a year-end holiday cluster
+    fn sample_year_end_fixings(convention: RollConvention) -> FixingTable {
+        let d = |y, m, d| DateTime::new(Date::from_ymd(y, m, d), TimeOfDay::Close);
+        let holidays = [Date::from_ymd(2018, 12, 25), Date::from_ymd(2018, 12, 26),
+            Date::from_ymd(2018, 12, 31), Date::from_ymd(2019, 01, 01)];
+        let calendar = RcCalendar::new(Arc::new(
+            HolidayCalendar::new_weekdays("TST", &holidays)));
+        let mut fixings = FixingTable::from_fixings(Date::from_ymd(2019, 01, 03), &[
+            ("BT.L", &[(d(2018, 12, 24), 100.0), (d(2018, 12, 27), 101.0),
+                (d(2018, 12, 28), 102.0), (d(2019, 01, 02), 103.0)])]).unwrap();
+        fixings.set_calendar(calendar, convention);
+        fixings
+    }
+
+    #[test]
+    fn fixings_rolled_by_calendar() {
+        let d = |y, m, d| DateTime::new(Date::from_ymd(y, m, d), TimeOfDay::Close);
+
+        let fixings = sample_year_end_fixings(RollConvention::Following);
+        assert_eq!(fixings.get("BT.L", d(2018, 12, 25)).unwrap(), Some(101.0));
+        assert_eq!(fixings.get("BT.L", d(2018, 12, 31)).unwrap(), Some(103.0));
+        assert_eq!(fixings.get("BT.L", d(2018, 12, 28)).unwrap(), Some(102.0));
+
+        let fixings = sample_year_end_fixings(RollConvention::ModifiedFollowing);
+        assert_eq!(fixings.get("BT.L", d(2018, 12, 25)).unwrap(), Some(101.0));
+        assert_eq!(fixings.get("BT.L", d(2018, 12, 31)).unwrap(), Some(102.0));
+        assert_eq!(fixings.get("BT.L", d(2019, 01, 01)).unwrap(), Some(103.0));
+
+        let fixings = sample_year_end_fixings(RollConvention::Preceding);
+        assert_eq!(fixings.get("BT.L", d(2018, 12, 26)).unwrap(), Some(100.0));
+        assert_eq!(fixings.get_optional("BT.L", d(2019, 01, 01)), Some(102.0));
+
+        // without a calendar, holidays have no fixings
+        let mut fixings = sample_year_end_fixings(RollConvention::Following);
+        fixings.calendar = None;
+        assert!(fixings.get("BT.L", d(2018, 12, 25)).is_err());
+    }
+
+    #[test]
+    fn fixing_or_roll_to_previous_business_day() {
+        let d = |y, m, d| DateTime::new(Date::from_ymd(y, m, d), TimeOfDay::Close);
+
+        // the table itself has no calendar, so holidays have no fixings
+        let mut fixings = sample_year_end_fixings(RollConvention::Following);
+        let calendar = fixings.calendar.take().unwrap().0;
+        assert!(fixings.get("BT.L", d(2018, 12, 26)).is_err());
+
+        // Christmas and Boxing Day roll back to the 24th, and New Year's Day
+        // back over the holiday on the 31st to the 28th
+        assert_eq!(fixings.fixing_or_roll("BT.L", d(2018, 12, 25), &*calendar).unwrap(), Some(100.0));
+        assert_eq!(fixings.fixing_or_roll("BT.L", d(2018, 12, 26), &*calendar).unwrap(), Some(100.0));
+        assert_eq!(fixings.fixing_or_roll("BT.L", d(2019, 01, 01), &*calendar).unwrap(), Some(102.0));
+        assert_eq!(fixings.fixing_or_roll("BT.L", d(2018, 12, 27), &*calendar).unwrap(), Some(101.0));
+
+        // a fixing at a different time of day does not count
+        let open = DateTime::new(Date::from_ymd(2018, 12, 25), TimeOfDay::Open);
+        assert!(fixings.fixing_or_roll("BT.L", open, &*calendar).is_err());
+
+        // a gap longer than the window is an error
+        fixings.set_roll_window(3);
+        assert!(fixings.fixing_or_roll("BT.L", d(2018, 12, 26), &*calendar).is_ok());
+        let error = fixings.fixing_or_roll("BT.L", d(2019, 01, 01), &*calendar).unwrap_err();
+        assert!(error.to_string().contains("none in the 3 days before"), "error={}", error);
+
+        // a business day that is not yet in the past is simply not yet known
+        assert_eq!(fixings.fixing_or_roll("BT.L", d(2019, 01, 03), &*calendar).unwrap(), None);
+    }
+
+    #[test]
+    fn serde_fixing_table_with_calendar() {
+        let fixings = sample_year_end_fixings(RollConvention::Preceding);
+        let serialized = serde_json::to_string(&fixings).unwrap();
+        let deserialized: FixingTable = serde_json::from_str(&serialized).unwrap();
+        let date_time = DateTime::new(Date::from_ymd(2018, 12, 26), TimeOfDay::Close);
+        assert_eq!(deserialized.get("BT.L", date_time).unwrap(), Some(100.0));
+    }
+
+    #[test]
+    fn same_day_close_fixing_allowed() {
+        let today = Date::from_ymd(2018, 01, 01);
+        let fixings = FixingTable::from_fixings(today, &[
+            ("BT.L", &[(DateTime::new(today, TimeOfDay::Close), 123.5)])]).unwrap();
+        let fixing = fixings.get("BT.L", DateTime::new(today, TimeOfDay::Close)).unwrap();
+        assert_eq!(fixing, Some(123.5));
+    }
+
+    #[test]
+    fn future_fixing_rejected() {
+        let today = Date::from_ymd(2018, 01, 01);
+        let result = FixingTable::from_fixings(today, &[
+            ("BT.L", &[(DateTime::new(today, TimeOfDay::Close), 123.5)]),
+            ("GSK.L", &[(DateTime::new(today - 1, TimeOfDay::Close), 223.4),
+                (DateTime::new(today + 1, TimeOfDay::Open), 223.5)])]);
+        match result {
+            Ok(_) => assert!(false, "future fixing accepted"),
+            Err(e) => {
+                let message = e.to_string();
+                assert!(message.contains("GSK.L") && message.contains("2018-01-02"),
+                    "message={}", message);
+            }
+        }
+    }
+
+    #[test]
+    fn serde_future_fixing_rejected() {
+        let today = Date::from_ymd(2018, 01, 01);
+        let fixings = FixingTable::from_fixings(today, &[
+            ("GSK.L", &[(DateTime::new(today - 1, TimeOfDay::Close), 223.4)])]).unwrap();
+        let serialized = serde_json::to_string(&fixings).unwrap();
+        assert!(serde_json::from_str::<FixingTable>(&serialized).is_ok());
+
+        // the same table claiming to know fixings only until the day before
+        let stale = serialized.replace("2018-01-01", "2017-12-30");
+        assert_ne!(stale, serialized);
+        let error = serde_json::from_str::<FixingTable>(&stale).unwrap_err();
+        let message = error.to_string();
+        assert!(message.contains("GSK.L") && message.contains("2017-12-31"),
+            "message={}", message);
+    }
+
     #[test]
     fn serde_fixing_table_roundtrip() {
 
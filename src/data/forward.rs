@@ -1,4 +1,5 @@
 use dates::Date;
+use dates::daycount::DayCount;
 use dates::rules::RcDateRule;
 use math::interpolation::Interpolate;
 use data::divstream::DividendBootstrap;
@@ -26,6 +27,13 @@ pub trait Forward : Interpolate<Date> + Sync + Send {
     fn fixed_divs_after(&self, _date: Date) -> Result<f64, qm::Error> {
         Ok(0.0)
     }
+
+    /// Returns the derivative of the forward on the given date with respect
+    /// to the spot, with all other market data unchanged. Defaults to an
+    /// error, because not all forwards are driven by a spot.
+    fn spot_delta(&self, _date: Date) -> Result<f64, qm::Error> {
+        Err(qm::Error::new("This forward has no sensitivity to spot"))
+    }
 }
 
 /// Allow any forward to be treated as an interpolator by date
@@ -77,6 +85,27 @@ impl InterpolatedForward {
     }
 }
  
+/// How the discrete dividends in a dividend stream are modelled by an equity
+/// forward. Any continuous dividend yield in the stream is unaffected.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DividendModel {
+    /// Each dividend is paid on its ex date, as a cash amount or as a
+    /// fraction of spot, so the forward drops on each ex date.
+    #[default]
+    Discrete,
+
+    /// The discrete dividends up to the high water mark, normally the expiry
+    /// of the option, are replaced by a constant continuous yield, chosen so
+    /// that the forward at the high water mark is unchanged. The forward is
+    /// smooth, with no drops on ex dates and no fixed cash dividends, which
+    /// gives smoother greeks.
+    ContinuousYield
+}
+
+impl DividendModel {
+    pub fn is_discrete(&self) -> bool { *self == DividendModel::Discrete }
+}
+
 /// An equity forward has a spot, a discount rate which, together with a
 /// borrow, defines the rate of growth, plus a dividend stream.
 pub struct EquityForward {
@@ -86,7 +115,14 @@ pub struct EquityForward {
     div_yield: RcRateCurve,
     bootstrap: DividendBootstrap,
     reference_spot: f64,
-    base_log_discount: f64
+    base_log_discount: f64,
+    base_log_div_yield: f64,
+    base_date: Date,
+    spot_df: f64,
+
+    // the continuous yield and its derivative with respect to the
+    // reference spot, if the dividends are modelled as a yield
+    continuous_yield: Option<(f64, f64)>
 }
 
 impl Forward for EquityForward {
@@ -94,24 +130,47 @@ impl Forward for EquityForward {
 
     fn forward(&self, date: Date) -> Result<f64, qm::Error> {
 
-        // add up any dividends before and including the given date
-        let divs = self.bootstrap.discounted_sum_from_base(date)?;
-
-        // calculate the settlement period
-        let pay_date = self.settlement.apply(date);
-
-        // calculate the growth up to the pay date
-        let log_df = log_discount_with_borrow(&*self.rate, &*self.borrow,
-            pay_date)?;
-        let log_div_yield = self.div_yield.rt(pay_date)?;
-        let growth = (self.base_log_discount + log_div_yield - log_df).exp();
-
-        // return the forward
-        Ok((self.reference_spot - divs) * growth)
+        let growth = self.growth(date)?;
+        match self.continuous_yield {
+            // the dividends are an additional continuous yield
+            Some((q, _)) => {
+                let t = DayCount::Act365F.year_fraction(self.base_date, date);
+                Ok(self.reference_spot * growth * (-q * t).exp())
+            },
+
+            // add up any dividends before and including the given date
+            None => {
+                let divs = self.bootstrap.discounted_sum_from_base(date)?;
+                Ok((self.reference_spot - divs) * growth)
+            }
+        }
     }
 
     fn fixed_divs_after(&self, date: Date) -> Result<f64, qm::Error> {
-        self.bootstrap.discounted_cash_divs_after(date)
+        match self.continuous_yield {
+            Some(_) => Ok(0.0),
+            None => self.bootstrap.discounted_cash_divs_after(date)
+        }
+    }
+
+    /// The forward is affine in spot, because cash dividends are fixed and
+    /// relative ones scale with the forward, so this is exact
+    fn spot_delta(&self, date: Date) -> Result<f64, qm::Error> {
+
+        let growth = self.growth(date)?;
+        match self.continuous_yield {
+            // the yield itself depends on spot, unless there are no cash divs
+            Some((q, q_delta)) => {
+                let t = DayCount::Act365F.year_fraction(self.base_date, date);
+                Ok(self.spot_df * growth * (-q * t).exp()
+                    * (1.0 - self.reference_spot * q_delta * t))
+            },
+
+            None => {
+                let divs_delta = self.bootstrap.discounted_sum_delta_from_base(date)?;
+                Ok(self.spot_df * (1.0 - divs_delta) * growth)
+            }
+        }
     }
 }
 
@@ -123,7 +182,8 @@ impl EquityForward {
         rate: RcRateCurve,
         borrow: RcRateCurve,
         divs: &DividendStream,
-        high_water_mark: Date) -> Result<EquityForward, qm::Error> {
+        high_water_mark: Date,
+        dividend_model: DividendModel) -> Result<EquityForward, qm::Error> {
 
         // If the base dates of the rate and borrow curves do not match,
         // we may need to add a correction
@@ -143,14 +203,64 @@ impl EquityForward {
         let bootstrap = DividendBootstrap::new(&divs, &*rate, &*borrow,
             reference_spot, base_date, high_water_mark)?;
 
+        // For a continuous yield, find the yield that gives the same
+        // forward at the high water mark. The growth is the same either way,
+        // so this only depends on the dividends.
+        let continuous_yield = match dividend_model {
+            DividendModel::Discrete => None,
+            DividendModel::ContinuousYield => {
+                let t = DayCount::Act365F.year_fraction(base_date, high_water_mark);
+                let divs = bootstrap.discounted_sum_from_base(high_water_mark)?;
+                if divs >= reference_spot {
+                    return Err(qm::Error::new("Dividends exceed spot, so \
+                        cannot be modelled as a continuous yield"))
+                }
+                if t > 0.0 {
+                    let divs_delta = bootstrap.discounted_sum_delta_from_base(
+                        high_water_mark)?;
+                    let remaining = 1.0 - divs / reference_spot;
+                    let q = -remaining.ln() / t;
+                    let q_delta = -(divs - divs_delta * reference_spot)
+                        / (reference_spot * reference_spot * remaining * t);
+                    Some((q, q_delta))
+                } else {
+                    Some((0.0, 0.0))
+                }
+            }
+        };
+
+        let div_yield = divs.div_yield();
+        let base_log_div_yield = div_yield.rt(base_date)?;
+
         Ok(EquityForward {
             settlement: settlement,
             rate: rate,
             borrow: borrow,
-            div_yield: divs.div_yield(),
+            div_yield: div_yield,
             bootstrap: bootstrap,
             reference_spot: reference_spot,
-            base_log_discount: base_log_discount })
+            base_log_discount: base_log_discount,
+            base_log_div_yield: base_log_div_yield,
+            base_date: base_date,
+            spot_df: spot_df,
+            continuous_yield: continuous_yield })
+    }
+
+    /// The growth of the forward from the base date to the given date, not
+    /// including the discrete dividends
+    fn growth(&self, date: Date) -> Result<f64, qm::Error> {
+
+        // calculate the settlement period
+        let pay_date = self.settlement.apply(date);
+
+        // calculate the growth up to the pay date
+        let log_df = log_discount_with_borrow(&*self.rate, &*self.borrow,
+            pay_date)?;
+
+        // the dividend yield runs from the base date to the date itself,
+        // whatever the base date of the yield curve
+        let log_div_yield = self.div_yield.rt(date)? - self.base_log_div_yield;
+        Ok((self.base_log_discount - log_df - log_div_yield).exp())
     }
 }
 
@@ -232,7 +342,7 @@ mod tests {
         let settlement = RcDateRule::new(Arc::new(BusinessDays::new_step(calendar, 2)));
 
         let fwd = EquityForward::new(d, spot, settlement, rate, borrow, &divs,
-            d + 1500).unwrap();
+            d + 1500, DividendModel::Discrete).unwrap();
 
         assert_match(fwd.forward(d), 97.0);
         assert_match(fwd.forward(d+27), 97.55511831033844);
@@ -248,9 +358,41 @@ mod tests {
         assert_match(fwd.forward(d+270), 100.34720455926485);
         assert_match(fwd.forward(d+300), 100.87344226359396);
         assert_match(fwd.forward(d+600), 104.51748569914179);
-        assert_match(fwd.forward(d+900), 110.7100396163593);
-        assert_match(fwd.forward(d+1200), 117.8483691785027);
-        assert_match(fwd.forward(d+1500), 125.93011849243018);
+        assert_match(fwd.forward(d+900), 110.40343355593875);
+        assert_match(fwd.forward(d+1200), 116.37047797615895);
+        assert_match(fwd.forward(d+1500), 122.08662557451592);
+    }
+
+    #[test]
+    fn equity_forward_continuous_yield() {
+        let d = Date::from_ymd(2017, 01, 02);
+        let spot = 97.0;
+        let calendar = RcCalendar::new(Arc::new(WeekdayCalendar{}));
+        let settlement = RcDateRule::new(Arc::new(BusinessDays::new_step(calendar, 2)));
+        let divs = create_sample_divstream();
+        let hwm = d + 600;
+
+        let discrete = EquityForward::new(d, spot, settlement.clone(),
+            create_sample_rate(), create_sample_borrow(), &divs, hwm,
+            DividendModel::Discrete).unwrap();
+        let continuous = EquityForward::new(d, spot, settlement,
+            create_sample_rate(), create_sample_borrow(), &divs, hwm,
+            DividendModel::ContinuousYield).unwrap();
+
+        // the forwards match today and at the high water mark
+        assert_match(continuous.forward(d), 97.0);
+        assert_match(continuous.forward(hwm), discrete.forward(hwm).unwrap());
+
+        // but there is no drop on the ex date, so the continuous forward
+        // is lower before the first dividend and higher after it
+        let before = continuous.forward(d + 27).unwrap();
+        let after = continuous.forward(d + 28).unwrap();
+        assert!((after - before).abs() < 0.05, "before={} after={}", before, after);
+        assert!(before < discrete.forward(d + 27).unwrap());
+        assert!(after > discrete.forward(d + 28).unwrap());
+
+        // there are no fixed cash dividends
+        assert_match(continuous.fixed_divs_after(d), 0.0);
     }
 
     fn create_sample_divstream() -> DividendStream {
@@ -0,0 +1,67 @@
+use core::qm;
+
+/// An FX rate between two currencies, quoted as the number of units of the
+/// quote currency per unit of the base currency. For example, a GBP/USD
+/// rate of 1.3 means one pound buys 1.3 dollars.
+///
+/// The rate has a flat volatility, which is what is needed to adjust the
+/// drift of an asset when it is diffused in a currency other than its own,
+/// as for a quanto. At present there is no term structure of FX forwards,
+/// as they are implied by the yield curves of the two currencies.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FxRate {
+    spot: f64,
+    vol: f64
+}
+
+impl FxRate {
+    pub fn new(spot: f64, vol: f64) -> Result<FxRate, qm::Error> {
+        if !(spot > 0.0) {
+            return Err(qm::Error::new("FX rate must be greater than zero"))
+        }
+        if !(vol >= 0.0) {
+            return Err(qm::Error::new("FX vol must not be negative"))
+        }
+        Ok(FxRate { spot, vol })
+    }
+
+    /// The rate when a currency is quoted against itself
+    pub fn unity() -> FxRate {
+        FxRate { spot: 1.0, vol: 0.0 }
+    }
+
+    pub fn spot(&self) -> f64 { self.spot }
+    pub fn vol(&self) -> f64 { self.vol }
+
+    /// The same rate, quoted the other way round. The vol of the log of the
+    /// rate is unchanged.
+    pub fn inverse(&self) -> FxRate {
+        FxRate { spot: 1.0 / self.spot, vol: self.vol }
+    }
+}
+
+/// The conventional id for the FX rate between two currencies, used for
+/// keying the rates in market data, and their correlations with assets.
+pub fn fx_id(base_currency: &str, quote_currency: &str) -> String {
+    format!("{}/{}", base_currency, quote_currency)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fx_rate_inverse() {
+        let rate = FxRate::new(1.25, 0.1).unwrap();
+        let inverse = rate.inverse();
+        assert_eq!(inverse.spot(), 0.8);
+        assert_eq!(inverse.vol(), 0.1);
+        assert_eq!(fx_id("GBP", "USD"), "GBP/USD");
+    }
+
+    #[test]
+    fn fx_rate_rejects_bad_inputs() {
+        assert!(FxRate::new(0.0, 0.1).is_err());
+        assert!(FxRate::new(1.25, -0.1).is_err());
+    }
+}
@@ -1,12 +1,15 @@
 pub mod bump;
+pub mod bumpcorrelation;
 pub mod bumpdivs;
 pub mod bumpspot;
 pub mod bumpspotdate;
 pub mod bumpvol;
 pub mod bumpyield;
+pub mod correlation;
 pub mod curves;
 pub mod divstream;
 pub mod fixings;
+pub mod fx;
 pub mod forward;
 pub mod voldecorators;
 pub mod volsmile;
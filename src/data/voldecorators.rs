@@ -1,12 +1,16 @@
 use std::sync::Arc;
+use std::f64::NAN;
+use std::f64::consts::PI;
 use data::volsurface::VolSurface;
 use data::volsurface::RcVolSurface;
 use data::forward::Forward;
 use data::volsurface::DivAssumptions;
+use data::bumpvol::VolTenor;
 use dates::datetime::DateDayFraction;
 use dates::calendar::RcCalendar;
 use dates::Date;
 use math::interpolation::Interpolate;
+use math::optionpricing::Black76;
 use core::qm;
 use core::factories::TypeId;
 use core::factories::Qrc;
@@ -74,6 +78,17 @@ impl VolSurface for ConstantExpiryTimeEvolution {
         self.base_date
     }
 
+    /// Vol times are shifted by the offset, and pillars that are now in the
+    /// past are dropped
+    fn pillar_vol_times(&self) -> Vec<f64> {
+        self.base_vol.pillar_vol_times().iter()
+            .map(|t| t - self.vol_time_offset).filter(|t| *t > 0.0).collect()
+    }
+
+    fn pillar_dates(&self) -> Vec<DateDayFraction> {
+        self.base_vol.pillar_dates()
+    }
+
     fn div_assumptions(&self) -> DivAssumptions {
         self.base_vol.div_assumptions()
     }
@@ -151,6 +166,14 @@ impl VolSurface for RollingExpiryTimeEvolution {
         self.base_date
     }
 
+    fn pillar_vol_times(&self) -> Vec<f64> {
+        self.base_vol.pillar_vol_times()
+    }
+
+    fn pillar_dates(&self) -> Vec<DateDayFraction> {
+        self.base_vol.pillar_dates()
+    }
+
     fn div_assumptions(&self) -> DivAssumptions {
         self.base_vol.div_assumptions()
     }
@@ -213,6 +236,14 @@ impl VolSurface for ParallelBumpVol {
         self.base_vol.base_date()
     }
 
+    fn pillar_vol_times(&self) -> Vec<f64> {
+        self.base_vol.pillar_vol_times()
+    }
+
+    fn pillar_dates(&self) -> Vec<DateDayFraction> {
+        self.base_vol.pillar_dates()
+    }
+
     fn div_assumptions(&self) -> DivAssumptions {
         self.base_vol.div_assumptions()
     }
@@ -281,6 +312,212 @@ impl VolSurface for TimeScaledBumpVol {
         self.base_vol.base_date()
     }
 
+    fn pillar_vol_times(&self) -> Vec<f64> {
+        self.base_vol.pillar_vol_times()
+    }
+
+    fn pillar_dates(&self) -> Vec<DateDayFraction> {
+        self.base_vol.pillar_dates()
+    }
+
+    fn div_assumptions(&self) -> DivAssumptions {
+        self.base_vol.div_assumptions()
+    }
+
+    fn displacement(&self, date: Date) -> Result<f64, qm::Error> {
+        self.base_vol.displacement(date)
+    }
+}
+
+/// Apply an additive vol bump to a single tenor bucket of a vol surface,
+/// for bucketed vega. The size of the bump depends on the vol time of the
+/// expiry, as defined by the VolTenor. If the bump size is negative, vols
+/// are floored at zero.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TenorBumpVol {
+    base_vol: RcVolSurface,
+    tenor: VolTenor,
+    bump: f64
+}
+
+impl TypeId for TenorBumpVol {
+    fn get_type_id(&self) -> &'static str { "TenorBumpVol" }
+}
+
+impl TenorBumpVol {
+    pub fn new(base_vol: RcVolSurface, tenor: VolTenor, bump: f64) -> TenorBumpVol {
+        TenorBumpVol { base_vol: base_vol, tenor: tenor, bump: bump }
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<RcVolSurface, esd::Error> {
+        Ok(Qrc::new(Arc::new(TenorBumpVol::deserialize(de)?)))
+    }
+}
+
+impl VolSurface for TenorBumpVol {
+
+    fn volatilities(&self,
+        date_time: DateDayFraction,
+        strikes: &[f64],
+        out: &mut[f64]) -> Result<(f64), qm::Error> {
+
+        let vol_time = self.base_vol.volatilities(date_time, strikes, out)?;
+        let bump = self.bump * self.tenor.weight(vol_time);
+
+        for i in 0..out.len() {
+            let vol = out[i] + bump;
+            out[i] = vol.max(0.0);
+        }
+
+        Ok(vol_time)
+    }
+
+    fn calendar(&self) -> &RcCalendar {
+        self.base_vol.calendar()
+    }
+
+    fn forward(&self) -> Option<&Interpolate<Date>> {
+        self.base_vol.forward()
+    }
+
+    fn base_date(&self) -> DateDayFraction {
+        self.base_vol.base_date()
+    }
+
+    fn pillar_vol_times(&self) -> Vec<f64> {
+        self.base_vol.pillar_vol_times()
+    }
+
+    fn pillar_dates(&self) -> Vec<DateDayFraction> {
+        self.base_vol.pillar_dates()
+    }
+
+    fn div_assumptions(&self) -> DivAssumptions {
+        self.base_vol.div_assumptions()
+    }
+
+    fn displacement(&self, date: Date) -> Result<f64, qm::Error> {
+        self.base_vol.displacement(date)
+    }
+}
+
+/// The relative shift in strike used to find the slope of the smile, when
+/// checking a skew bump for arbitrage
+const SKEW_SLOPE_SHIFT: f64 = 1e-4;
+
+/// Rotate the smile of a vol surface about a pivot, for skew risk. The pivot
+/// is a moneyness, strike over forward, so the surface must have a forward.
+/// Vols change by -slope * ln(strike / (pivot * forward)), so a positive slope
+/// raises vols below the pivot and lowers them above it, steepening a normal
+/// equity skew. Vols at the pivot are unchanged.
+///
+/// A large rotation can make the smile nonsensical, so the bumped vols are
+/// checked. They must be positive, and the slope of the smile must not make
+/// call or put spreads negative in value. Otherwise, fetching the vols is an
+/// error.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SkewBumpVol {
+    base_vol: RcVolSurface,
+    pivot: f64,
+    slope: f64
+}
+
+impl TypeId for SkewBumpVol {
+    fn get_type_id(&self) -> &'static str { "SkewBumpVol" }
+}
+
+impl SkewBumpVol {
+    pub fn new(base_vol: RcVolSurface, pivot: f64, slope: f64) -> SkewBumpVol {
+        SkewBumpVol { base_vol: base_vol, pivot: pivot, slope: slope }
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<RcVolSurface, esd::Error> {
+        Ok(Qrc::new(Arc::new(SkewBumpVol::deserialize(de)?)))
+    }
+}
+
+impl VolSurface for SkewBumpVol {
+
+    fn volatilities(&self,
+        date_time: DateDayFraction,
+        strikes: &[f64],
+        out: &mut[f64]) -> Result<(f64), qm::Error> {
+
+        let n = strikes.len();
+        if !(self.pivot > 0.0) {
+            return Err(qm::Error::new("Skew bump pivot must be positive"))
+        }
+        let forward = match self.base_vol.forward() {
+            Some(fwd) => fwd.interpolate(date_time.date())?,
+            None => return Err(qm::Error::new(
+                "Skew bump requires a vol surface with a forward"))
+        };
+
+        // Fetch the unbumped vols, plus vols either side of each strike so
+        // we can find the slope of the smile
+        let mut all_strikes = Vec::with_capacity(3 * n);
+        all_strikes.extend_from_slice(strikes);
+        all_strikes.extend(strikes.iter().map(|k| k * (1.0 + SKEW_SLOPE_SHIFT)));
+        all_strikes.extend(strikes.iter().map(|k| k * (1.0 - SKEW_SLOPE_SHIFT)));
+        let mut all_vols = vec![NAN; 3 * n];
+        let vol_time = self.base_vol.volatilities(date_time, &all_strikes, &mut all_vols)?;
+
+        let log_shift = (1.0 + SKEW_SLOPE_SHIFT).ln() - (1.0 - SKEW_SLOPE_SHIFT).ln();
+        let sqrt_time = vol_time.max(0.0).sqrt();
+        let black76 = Black76::new()?;
+        for i in 0..n {
+            if !(strikes[i] > 0.0) {
+                return Err(qm::Error::new("Skew bump requires positive strikes"))
+            }
+            let log_moneyness = (strikes[i] / forward).ln();
+            let vol = all_vols[i] - self.slope * (log_moneyness - self.pivot.ln());
+            if !(vol > 0.0) {
+                return Err(qm::Error::new(&format!(
+                    "Skew bump gives a non-positive vol at strike {}", strikes[i])))
+            }
+            out[i] = vol;
+
+            // The derivative of a call with respect to strike, divided by the
+            // discount factor, is -N(d2) + n(d2) sqrt(t) dvol/dlogstrike. It
+            // must lie between -1 and 0.
+            if sqrt_time > 0.0 {
+                let vol_slope = (all_vols[n + i] - all_vols[2 * n + i]) / log_shift
+                    - self.slope;
+                let sd = vol * sqrt_time;
+                let d2 = -log_moneyness / sd - 0.5 * sd;
+                let density = (-0.5 * d2 * d2).exp() / (2.0 * PI).sqrt();
+                let slope_term = density * sqrt_time * vol_slope;
+                if slope_term > black76.cdf(d2) || -slope_term > black76.cdf(-d2) {
+                    return Err(qm::Error::new(&format!(
+                        "Skew bump gives an arbitrageable smile at strike {}",
+                        strikes[i])))
+                }
+            }
+        }
+
+        Ok(vol_time)
+    }
+
+    fn calendar(&self) -> &RcCalendar {
+        self.base_vol.calendar()
+    }
+
+    fn forward(&self) -> Option<&Interpolate<Date>> {
+        self.base_vol.forward()
+    }
+
+    fn base_date(&self) -> DateDayFraction {
+        self.base_vol.base_date()
+    }
+
+    fn pillar_vol_times(&self) -> Vec<f64> {
+        self.base_vol.pillar_vol_times()
+    }
+
+    fn pillar_dates(&self) -> Vec<DateDayFraction> {
+        self.base_vol.pillar_dates()
+    }
+
     fn div_assumptions(&self) -> DivAssumptions {
         self.base_vol.div_assumptions()
     }
@@ -367,6 +604,14 @@ impl VolSurface for StickyDeltaBumpVol {
         self.base_vol.base_date()
     }
 
+    fn pillar_vol_times(&self) -> Vec<f64> {
+        self.base_vol.pillar_vol_times()
+    }
+
+    fn pillar_dates(&self) -> Vec<DateDayFraction> {
+        self.base_vol.pillar_dates()
+    }
+
     fn div_assumptions(&self) -> DivAssumptions {
         self.base_vol.div_assumptions()
     }
@@ -396,6 +641,8 @@ mod tests {
     use data::volsurface::RcVolSurface;
     use math::interpolation::Extrap;
     use math::interpolation::Linear;
+    use data::bumpvol::BumpVol;
+    use data::bump::Bumper;
 
     #[test]
     fn constant_expiry_vol_surface() {
@@ -591,6 +838,60 @@ mod tests {
         }
     }
 
+    #[test]
+    fn skew_bump_rotates_about_pivot() {
+
+        let base_date = DateDayFraction::new(Date::from_ymd(2012, 05, 25), 0.2);
+        let unbumped = RcVolSurface::new(Arc::new(sample_vol_surface(base_date)));
+        let expiry = DateDayFraction::new(base_date.date() + 112, 0.7);
+        let forward = unbumped.forward().unwrap().interpolate(expiry.date()).unwrap();
+
+        // a positive slope steepens the skew, raising vols below the pivot
+        let slope = 0.05;
+        let bumped = BumpVol::new_skew(1.0, slope).apply(unbumped.clone());
+        let strikes = vec![70.0, 80.0, forward, 100.0, 110.0];
+        let mut unbumped_vols = vec![0.0; strikes.len()];
+        let mut bumped_vols = vec![0.0; strikes.len()];
+        unbumped.volatilities(expiry, &strikes, &mut unbumped_vols).unwrap();
+        bumped.volatilities(expiry, &strikes, &mut bumped_vols).unwrap();
+
+        for i in 0..strikes.len() {
+            let expected = -slope * (strikes[i] / forward).ln();
+            assert_approx(bumped_vols[i] - unbumped_vols[i], expected, 1e-12);
+        }
+        assert_approx(bumped_vols[2], unbumped_vols[2], 1e-12);
+        assert!(bumped_vols[0] > unbumped_vols[0]);
+        assert!(bumped_vols[4] < unbumped_vols[4]);
+
+        // a negative slope flattens it, moving the wings the other way
+        let flattened = BumpVol::new_skew(1.0, -slope).apply(unbumped.clone());
+        flattened.volatilities(expiry, &strikes, &mut bumped_vols).unwrap();
+        assert!(bumped_vols[0] < unbumped_vols[0]);
+        assert!(bumped_vols[4] > unbumped_vols[4]);
+    }
+
+    #[test]
+    fn skew_bump_rejects_nonsense() {
+
+        let base_date = DateDayFraction::new(Date::from_ymd(2012, 05, 25), 0.2);
+        let unbumped = RcVolSurface::new(Arc::new(sample_vol_surface(base_date)));
+        let expiry = DateDayFraction::new(base_date.date() + 112, 0.7);
+        let strikes = vec![70.0, 90.0, 110.0];
+        let mut vols = vec![0.0; strikes.len()];
+
+        // so steep a rotation gives negative vols above the money and put
+        // spread arbitrage below it
+        let bumped = BumpVol::new_skew(1.0, 3.0).apply(unbumped.clone());
+        assert!(bumped.volatilities(expiry, &strikes, &mut vols).is_err());
+        let bumped = BumpVol::new_skew(1.0, 3.0).apply(unbumped.clone());
+        assert!(bumped.volatilities(expiry, &strikes[0..1], &mut vols[0..1]).is_err());
+
+        // a flat surface has no forward, so there is no pivot
+        let flat = BumpVol::new_replace(0.2).apply(unbumped);
+        let bumped = BumpVol::new_skew(1.0, 0.05).apply(flat);
+        assert!(bumped.volatilities(expiry, &strikes, &mut vols).is_err());
+    }
+
     fn assert_approx(value: f64, expected: f64, tolerance: f64) {
         assert!(approx_eq(value, expected, tolerance),
             "value={} expected={} tolerance={}", value, expected, tolerance);
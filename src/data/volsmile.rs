@@ -28,6 +28,13 @@ pub trait VolSmile : Serialize + Clone + Debug {
         self.volatilities(&strikes, &mut vols)?;
         Ok(vols[0])
     }
+
+    /// The lowest and highest strikes the smile was fitted to, outside which
+    /// its volatilities are extrapolated. None if the smile is defined for
+    /// all strikes, which is the default.
+    fn strike_range(&self) -> Option<(f64, f64)> {
+        None
+    }
 }
 
 /// A flat smile, where the vol is the same for all strikes. (It may be
@@ -83,6 +90,10 @@ impl VolSmile for CubicSplineSmile {
         }
         Ok(())
     }
+
+    fn strike_range(&self) -> Option<(f64, f64)> {
+        Some(self.smile.bounds())
+    }
 }
 
 impl CubicSplineSmile {
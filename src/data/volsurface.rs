@@ -9,12 +9,15 @@ use data::voldecorators::ConstantExpiryTimeEvolution;
 use data::voldecorators::RollingExpiryTimeEvolution;
 use data::voldecorators::ParallelBumpVol;
 use data::voldecorators::TimeScaledBumpVol;
+use data::voldecorators::TenorBumpVol;
+use data::voldecorators::SkewBumpVol;
 use data::voldecorators::StickyDeltaBumpVol;
 use math::interpolation::lerp;
 use math::interpolation::Interpolable;
 use math::interpolation::Interpolate;
 use math::interpolation::Linear;
 use math::numerics::approx_eq;
+use math::optionpricing::Black76;
 use core::qm;
 use core::factories::TypeId;
 use core::factories::Registry;
@@ -59,6 +62,23 @@ pub trait VolSurface : esd::Serialize + TypeId + Send + Sync + Debug {
     /// most users do not need to worry about this.)
     fn base_date(&self) -> DateDayFraction;
 
+    /// The vol times of the pillars that define the term structure of this
+    /// surface, in increasing order. This is used for bucketed risk. Vol
+    /// surfaces with no term structure, such as flat ones, return no pillars.
+    fn pillar_vol_times(&self) -> Vec<f64> {
+        Vec::new()
+    }
+
+    /// The date/day-fractions of the pillars that define the term structure
+    /// of this surface, in increasing order. These are the natural dates at
+    /// which to look for arbitrage. Decorators that move the surface in time
+    /// report the dates of the surface they decorate, which may then not be
+    /// exactly on the pillars. Vol surfaces with no term structure return no
+    /// dates.
+    fn pillar_dates(&self) -> Vec<DateDayFraction> {
+        Vec::new()
+    }
+
     /// For vol surfaces that have a smile, gives access to the forward
     /// curve that centres the smile. For vol surfaces with no smile, returns
     /// None.
@@ -110,17 +130,23 @@ pub trait VolSurface : esd::Serialize + TypeId + Send + Sync + Debug {
 
     /// Fetches the forward variances between two date/day-fractions, across a
     /// range of strikes. Forward variances are used when valuing forward-
-    /// starting options and cliquets. The default methodology implemented here
-    /// uses the at the forward vols from the vol surface itself, but the
-    /// shape of the smile comes by displacing the whole vol surface forward
-    /// such that the original base date of the vol surface slides to the from
-    /// date requested.
+    /// starting options and cliquets. The forward variance at each strike is
+    /// the total variance to the to date minus the total variance to the
+    /// from date, at that strike. If that is negative, the surface has
+    /// calendar arbitrage between the two dates, and we return an error.
     ///
-    /// Note that other methodologies exist, particularly direct calibration
-    /// from cliquets (though these do not trade liquidly, and the prices are
-    /// just broker quotes from competitors). It would be worth considering
-    /// implementing these as a decorator on top of the default implementation,
-    /// so that sensitivities to standard vol bumps are maintained.
+    /// This replaces the earlier methodology, where surfaces with a forward
+    /// took the at-the-money forward variance from the surface, but the
+    /// shape of the smile from displacing the whole surface forward, so that
+    /// the base date slid to the from date. Forward-starting prices off such
+    /// surfaces change as a result. See find_smile_date.
+    ///
+    /// Note that other methodologies exist, for example displacing the smile
+    /// forward in time, or direct calibration from cliquets (though these do
+    /// not trade liquidly, and the prices are just broker quotes from
+    /// competitors). It would be worth considering implementing these as a
+    /// decorator on top of the default implementation, so that sensitivities
+    /// to standard vol bumps are maintained.
     fn forward_variances(&self,
         from: DateDayFraction,
         to: DateDayFraction,
@@ -135,60 +161,27 @@ pub trait VolSurface : esd::Serialize + TypeId + Send + Sync + Debug {
         let n = strikes.len();
         assert!(n == variances.len());
 
-        if let Some(forward) = self.forward() {
-
-            // If there is a forward, use it to find the at the money variance
-            // at the from and to dates
-            let from_forward = forward.interpolate(from.date())?;
-            let to_forward = forward.interpolate(to.date())?;
-            let from_var = self.variance(from, to_forward)?;
-            let to_var = self.variance(from, from_forward)?;
-            let fwd_atm_var = to_var - from_var;
-            if fwd_atm_var < 0.0 {
-                return Err(qm::Error::new("Negative atm forward variance"))
-            }
-
-            // find a smile date, which is roughly the same time after the
-            // base date as the to date is after the from date, and use it
-            // to correct the smiles
-            let smile_date = self.find_smile_date(from, to);
-            let smile_forward = forward.interpolate(smile_date.date())?;
-            let atm_smile_var = self.variance(smile_date, smile_forward)?;
-            self.variances(smile_date, strikes, variances)?;
-
-            for i in 0..n {
-                let fwd_var = variances[i] - atm_smile_var + fwd_atm_var;
-                if fwd_var < 0.0 {
-                    return Err(qm::Error::new("Negative forward variance"))
-                }
-                variances[i] = fwd_var;
-            }
-
-
-        } else {
+        let mut from_variances = vec!(NAN; n);
+        self.variances(from, strikes, &mut from_variances)?;
+        self.variances(to, strikes, variances)?;
 
-            // if there is no forward associated with the vol surface, for
-            // example flat vol, just return the forward variances with no
-            // smile correction.
-            let mut from_variances = vec!(NAN; n);
-            self.variances(from, strikes, &mut from_variances)?;
-            self.variances(to, strikes, variances)?;
-
-            for i in 0..n {
-                let fwd_var = variances[i] - from_variances[i];
-                if fwd_var < 0.0 {
-                    return Err(qm::Error::new("Negative forward variance"))
-                }
-                variances[i] = fwd_var;
+        for i in 0..n {
+            let fwd_var = variances[i] - from_variances[i];
+            if fwd_var < 0.0 {
+                return Err(qm::Error::new(&format!(
+                    "Calendar arbitrage at strike {}: total variance falls \
+                    from {} on {} to {} on {}", strikes[i], from_variances[i],
+                    from.date(), variances[i], to.date())))
             }
+            variances[i] = fwd_var;
         }
 
         Ok(())
     }
 
-    /// Convenience method that fetches a single variance. It is generally
-    /// much more efficient to use the vector method if you need variances
-    /// for multiple strikes.
+    /// Convenience method that fetches a single forward variance. It is
+    /// generally much more efficient to use the vector method if you need
+    /// forward variances for multiple strikes.
     fn forward_variance(&self, from: DateDayFraction, to: DateDayFraction,
         strike: f64) -> Result<f64, qm::Error> {
 
@@ -198,23 +191,105 @@ pub trait VolSurface : esd::Serialize + TypeId + Send + Sync + Debug {
         Ok(variances[0])
     }
 
-    /// Finds the date to use for forward variance calculations. This is not
-    /// normally overridden or invoked externally.
+    /// Finds the date the earlier forward variance methodology took the
+    /// shape of the smile from: roughly the same time after the base date as
+    /// the to date is after the from date, at the same time of day as the to
+    /// date. It is no longer used by forward_variances.
+    #[deprecated(note = "forward_variances no longer slides the smile forward")]
     fn find_smile_date(&self, from: DateDayFraction, to: DateDayFraction)
         -> DateDayFraction {
-     
-        // find the date which most closely matches the distance after the
-        // base date, as the to date is after the from date.  
+
         let calendar = self.calendar();
         let base_date = self.base_date();
-        let days = calendar.count_business_days(from.date(), 
+        let days = calendar.count_business_days(from.date(),
             from.day_fraction(), to.date(), to.day_fraction());
         let smile_date = calendar.step_partial(base_date.date(), days, true);
-
-        // we always use the same time of day as the to date
         DateDayFraction::new(smile_date, to.day_fraction())
     }
 
+    /// Checks that the surface is free of calendar and butterfly arbitrage,
+    /// returning an error that names the offending strike and date if not.
+    /// The checks are made on each pillar date after the base date, on a grid
+    /// of strikes spanning three standard deviations either side of the
+    /// forward:
+    ///
+    /// * Calendar: total variance must not decrease from one pillar date to
+    ///   the next, at the same strike relative to the forward.
+    /// * Butterfly: undiscounted call prices must be convex in strike.
+    ///
+    /// Surfaces with no smile or no term structure have nothing to check.
+    fn check_arbitrage(&self) -> Result<(), qm::Error> {
+
+        let forward = match self.forward() {
+            Some(forward) => forward,
+            None => return Ok(())
+        };
+
+        let base_date = self.base_date();
+        let black76 = Black76::new()?;
+        let mut previous: Option<(DateDayFraction, f64, f64)> = None;
+        for date in self.pillar_dates().into_iter().filter(|d| *d > base_date) {
+
+            let fwd = forward.interpolate(date.date())?;
+            let sqrt_variance = self.variance(date, fwd)?.sqrt();
+            if !(sqrt_variance > 0.0) {
+                return Err(qm::Error::new(&format!(
+                    "Calendar arbitrage: zero at the money variance on {}",
+                    date.date())))
+            }
+
+            // Compare with the previous pillar on its own strike grid, which
+            // is the narrower of the two
+            if let Some((prev_date, prev_fwd, prev_sqrt_variance)) = previous {
+                let moneyness = moneyness_grid(prev_sqrt_variance);
+                let prev_strikes: Vec<f64> = moneyness.iter()
+                    .map(|m| m * prev_fwd).collect();
+                let strikes: Vec<f64> = moneyness.iter()
+                    .map(|m| m * fwd).collect();
+                let mut prev_variances = vec!(NAN; moneyness.len());
+                let mut variances = vec!(NAN; moneyness.len());
+                self.variances(prev_date, &prev_strikes, &mut prev_variances)?;
+                self.variances(date, &strikes, &mut variances)?;
+                for i in 0..moneyness.len() {
+                    if variances[i] < prev_variances[i] {
+                        return Err(qm::Error::new(&format!(
+                            "Calendar arbitrage at strike {} ({} of the \
+                            forward): total variance falls from {} on {} to \
+                            {} on {}", strikes[i], moneyness[i],
+                            prev_variances[i], prev_date.date(), variances[i],
+                            date.date())))
+                    }
+                }
+            }
+
+            // Each call price must lie on or below the chord between the
+            // prices at the neighbouring strikes
+            let strikes: Vec<f64> = moneyness_grid(sqrt_variance).iter()
+                .map(|m| m * fwd).collect();
+            let n = strikes.len();
+            let mut variances = vec!(NAN; n);
+            self.variances(date, &strikes, &mut variances)?;
+            let calls: Vec<f64> = strikes.iter().zip(variances.iter())
+                .map(|(k, v)| black76.call_price(1.0, fwd, *k, v.sqrt()))
+                .collect();
+            for i in 1..n - 1 {
+                let (low, mid, high) = (strikes[i - 1], strikes[i], strikes[i + 1]);
+                let chord = (calls[i - 1] * (high - mid)
+                    + calls[i + 1] * (mid - low)) / (high - low);
+                if calls[i] > chord + ARBITRAGE_TOLERANCE * fwd {
+                    return Err(qm::Error::new(&format!(
+                        "Butterfly arbitrage at strike {} on {}: call price {} \
+                        is above {}, interpolated from strikes {} and {}",
+                        mid, date.date(), calls[i], chord, low, high)))
+                }
+            }
+
+            previous = Some((date, fwd, sqrt_variance));
+        }
+
+        Ok(())
+    }
+
     /// Specifies what dividend assumptions were used
     /// when calibrating the vol surface. This has implications for how it
     /// can be used for pricing.
@@ -229,6 +304,25 @@ pub trait VolSurface : esd::Serialize + TypeId + Send + Sync + Debug {
     fn displacement(&self, date: Date) -> Result<f64, qm::Error>;
 }
 
+/// Number of at the money standard deviations either side of the forward
+/// spanned by the strikes used for arbitrage checks
+const ARBITRAGE_CHECK_STDEVS: f64 = 3.0;
+
+/// Number of strikes either side of the forward used for arbitrage checks
+const ARBITRAGE_CHECK_STEPS: usize = 12;
+
+/// Call prices may exceed the convex hull by this fraction of the forward
+/// before it counts as butterfly arbitrage, to allow for rounding
+const ARBITRAGE_TOLERANCE: f64 = 1e-12;
+
+/// Strikes as fractions of the forward, evenly spaced in log strike and
+/// spanning the given number of standard deviations either side of it
+fn moneyness_grid(sqrt_variance: f64) -> Vec<f64> {
+    let steps = ARBITRAGE_CHECK_STEPS as i32;
+    let step = ARBITRAGE_CHECK_STDEVS * sqrt_variance / (steps as f64);
+    (-steps..steps + 1).map(|i| (i as f64 * step).exp()).collect()
+}
+
 // Get serialization to work recursively for rate curves by using the
 // technology defined in core/factories. RcRateCurve is a container
 // class holding an RcRateCurve
@@ -255,6 +349,8 @@ pub fn get_registry() -> &'static TypeRegistry {
             reg.insert("RollingExpiryTimeEvolution", BoxFnSeed::new(RollingExpiryTimeEvolution::from_serial));
             reg.insert("ParallelBumpVol", BoxFnSeed::new(ParallelBumpVol::from_serial));
             reg.insert("TimeScaledBumpVol", BoxFnSeed::new(TimeScaledBumpVol::from_serial));
+            reg.insert("TenorBumpVol", BoxFnSeed::new(TenorBumpVol::from_serial));
+            reg.insert("SkewBumpVol", BoxFnSeed::new(SkewBumpVol::from_serial));
             reg
         };
     }
@@ -295,6 +391,29 @@ pub enum DivAssumptions {
     JumpDivs
 }
 
+/// Enum which defines how a vol surface behaves when it is queried outside
+/// the box of strikes and dates it was fitted to. The strike range is that of
+/// each pillar smile, and the dates run up to the last pillar.
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Debug)]
+pub enum ExtrapolationPolicy {
+    /// Strikes outside the range of a smile take the vol of the nearest
+    /// strike in the range. Beyond the last pillar, the smile of the last
+    /// pillar is used, in normalised strike.
+    Flat,
+
+    /// Each smile extrapolates in its own way, for example linearly in
+    /// strike for a natural cubic spline. This is the default.
+    Linear,
+
+    /// Any query outside the box is an error, unless it only asks for the
+    /// vol time
+    Error
+}
+
+impl Default for ExtrapolationPolicy {
+    fn default() -> ExtrapolationPolicy { ExtrapolationPolicy::Linear }
+}
+
 /// Enum which defines how a vol surface is time evolved if it is out of date,
 /// and also how it changes during a theta or time forward calculation.
 #[derive(Clone, Copy, PartialEq, Eq)]
@@ -512,7 +631,13 @@ struct VolByProbabilityInput<T> where T: VolSmile + Clone + Debug {
     base_date: DateDayFraction,
     forward: Linear<Date>,
     fixed_divs_after: Linear<Date>,
-    div_assumptions: DivAssumptions
+    div_assumptions: DivAssumptions,
+    #[serde(default, skip_serializing_if = "is_linear")]
+    extrapolation: ExtrapolationPolicy
+}
+
+fn is_linear(policy: &ExtrapolationPolicy) -> bool {
+    *policy == ExtrapolationPolicy::Linear
 }
 
 impl<T> VolByProbabilityInput<T> where T: VolSmile + Clone + Debug {
@@ -530,7 +655,8 @@ impl<T> VolByProbabilityInput<T> where T: VolSmile + Clone + Debug {
             base_date: base_date,
             forward: forward,
             fixed_divs_after: fixed_divs_after,
-            div_assumptions: div_assumptions }
+            div_assumptions: div_assumptions,
+            extrapolation: ExtrapolationPolicy::default() }
     }
 }
 
@@ -556,7 +682,7 @@ impl<T: VolSmile + Clone + Sync + Send + Debug> VolSurface for VolByProbability<
         match found {
             // If we find it, return the vols and vol time on that pillar
             Ok(i) => {
-                self.input.smiles[i].1.volatilities(&strikes, &mut out)?;
+                self.smile_volatilities(i, &strikes, &mut out)?;
                 //print!("at pillar: out={:?} vol_time={}\n", out, self.pillar_vol_times[i]);
                 Ok(self.pillar_vol_times[i]) 
             },
@@ -565,6 +691,13 @@ impl<T: VolSmile + Clone + Sync + Send + Debug> VolSurface for VolByProbability<
             Err(i) => if i == 0 {
                 self.extrapolate(0, date_time, &strikes, &mut out)
             } else if i >= n {
+                if self.input.extrapolation == ExtrapolationPolicy::Error
+                    && !strikes.is_empty() {
+                    return Err(qm::Error::new(&format!(
+                        "Vol requested on {} is after the last pillar of the \
+                        vol surface, on {}", date_time.date(),
+                        self.input.smiles[n - 1].0.date())))
+                }
                 self.extrapolate(n - 1, date_time, &strikes, &mut out)
             } else {
                 self.interpolate(i - 1, i, date_time, &strikes, &mut out)
@@ -584,6 +717,14 @@ impl<T: VolSmile + Clone + Sync + Send + Debug> VolSurface for VolByProbability<
         self.input.base_date
     }
 
+    fn pillar_vol_times(&self) -> Vec<f64> {
+        self.pillar_vol_times.clone()
+    }
+
+    fn pillar_dates(&self) -> Vec<DateDayFraction> {
+        self.input.smiles.iter().map(|smile| smile.0).collect()
+    }
+
     fn div_assumptions(&self) -> DivAssumptions {
         self.input.div_assumptions
     }
@@ -631,7 +772,10 @@ impl<T: VolSmile + Clone> VolByProbability<T> {
         let mut prev_date = input.base_date;
         for smile in input.smiles.iter() {
             let f = input.forward.interpolate(smile.0.date())?;
-            let vol = smile.1.volatility(f)?;
+            let mut vol = [NAN];
+            smile_volatilities(&smile.1, smile.0, input.extrapolation,
+                &[f], &mut vol)?;
+            let vol = vol[0];
             let time = input.calendar.year_fraction(input.base_date, smile.0);
             let variance = time * vol * vol;
             if variance < prev_variance {
@@ -684,7 +828,7 @@ impl<T: VolSmile + Clone> VolByProbability<T> {
             pillar_time.sqrt()); 
 
         // flat extrapolation in normalised strike space
-        self.input.smiles[pillar].1.volatilities(&adj_strikes, &mut out)?;
+        self.smile_volatilities(pillar, &adj_strikes, &mut out)?;
         //print!("extrapolate: out={:?} vol_time={}\n", out, vol_time);
         Ok(vol_time)
     }
@@ -759,12 +903,59 @@ impl<T: VolSmile + Clone> VolByProbability<T> {
         mut variances: &mut[f64]) -> Result<(), qm::Error> {
 
         // we use the variances vector as workspace to fetch vols
-        self.input.smiles[pillar].1.volatilities(&strikes, &mut variances)?;
+        self.smile_volatilities(pillar, &strikes, &mut variances)?;
         for i in 0..variances.len() {
             variances[i] = variances[i] * variances[i] * vol_time;
         }
         Ok(())
     }
+
+    fn smile_volatilities(&self,
+        pillar: usize,
+        strikes: &[f64],
+        out: &mut[f64]) -> Result<(), qm::Error> {
+
+        let smile = &self.input.smiles[pillar];
+        smile_volatilities(&smile.1, smile.0, self.input.extrapolation,
+            strikes, out)
+    }
+
+    /// Returns a copy of this surface with a different extrapolation policy
+    fn with_extrapolation(self, policy: ExtrapolationPolicy)
+        -> Result<VolByProbability<T>, qm::Error> {
+        let mut input = self.input;
+        input.extrapolation = policy;
+        VolByProbability::new(input)
+    }
+}
+
+/// Fetches the vols of a pillar smile, applying the extrapolation policy to
+/// any strikes outside the range the smile was fitted to.
+fn smile_volatilities<T: VolSmile>(smile: &T, date: DateDayFraction,
+    policy: ExtrapolationPolicy, strikes: &[f64], out: &mut[f64])
+    -> Result<(), qm::Error> {
+
+    let (low, high) = match smile.strike_range() {
+        Some(range) => range,
+        None => return smile.volatilities(strikes, out)
+    };
+
+    match policy {
+        ExtrapolationPolicy::Linear => smile.volatilities(strikes, out),
+        ExtrapolationPolicy::Flat => {
+            let clamped: Vec<f64> = strikes.iter()
+                .map(|strike| strike.max(low).min(high)).collect();
+            smile.volatilities(&clamped, out)
+        },
+        ExtrapolationPolicy::Error => {
+            if let Some(strike) = strikes.iter().find(|&&k| k < low || k > high) {
+                return Err(qm::Error::new(&format!(
+                    "Strike {} is outside the range {} to {} of the vol \
+                    smile on {}", strike, low, high, date.date())))
+            }
+            smile.volatilities(strikes, out)
+        }
+    }
 }
 
 /// Create a new type for a VolByProbability<FlatSmile> so it can have its own
@@ -792,6 +983,13 @@ impl VolByProbabilityFlatSmile {
         Ok(VolByProbabilityFlatSmile(surface))
     }
 
+    /// Chooses how the surface behaves when queried outside the strikes and
+    /// dates it was fitted to. The default is ExtrapolationPolicy::Linear.
+    pub fn with_extrapolation(self, policy: ExtrapolationPolicy)
+        -> Result<VolByProbabilityFlatSmile, qm::Error> {
+        Ok(VolByProbabilityFlatSmile(self.0.with_extrapolation(policy)?))
+    }
+
     // We split VolByProbability into two parts: VolByProbabilityInputs, which
     // can be serialized and deserialized easily, and the precomputed fields,
     // which are calculated on load. We manually implement the
@@ -813,6 +1011,8 @@ impl VolSurface for VolByProbabilityFlatSmile {
     }
     fn calendar(&self) -> &RcCalendar { self.0.calendar() }
     fn base_date(&self) -> DateDayFraction { self.0.base_date() }
+    fn pillar_vol_times(&self) -> Vec<f64> { self.0.pillar_vol_times() }
+    fn pillar_dates(&self) -> Vec<DateDayFraction> { self.0.pillar_dates() }
     fn forward(&self) -> Option<&Interpolate<Date>> { self.0.forward() }
     fn div_assumptions(&self) -> DivAssumptions { self.0.div_assumptions() }
     fn displacement(&self, date: Date) -> Result<f64, qm::Error> {
@@ -846,6 +1046,13 @@ impl VolByProbabilityCubicSplineSmile {
         Ok(VolByProbabilityCubicSplineSmile(surface))
     }
 
+    /// Chooses how the surface behaves when queried outside the strikes and
+    /// dates it was fitted to. The default is ExtrapolationPolicy::Linear.
+    pub fn with_extrapolation(self, policy: ExtrapolationPolicy)
+        -> Result<VolByProbabilityCubicSplineSmile, qm::Error> {
+        Ok(VolByProbabilityCubicSplineSmile(self.0.with_extrapolation(policy)?))
+    }
+
     // We split VolByProbability into two parts: VolByProbabilityInputs, which
     // can be serialized and deserialized easily, and the precomputed fields,
     // which are calculated on load. We manually implement the
@@ -867,6 +1074,8 @@ impl VolSurface for VolByProbabilityCubicSplineSmile {
     }
     fn calendar(&self) -> &RcCalendar { self.0.calendar() }
     fn base_date(&self) -> DateDayFraction { self.0.base_date() }
+    fn pillar_vol_times(&self) -> Vec<f64> { self.0.pillar_vol_times() }
+    fn pillar_dates(&self) -> Vec<DateDayFraction> { self.0.pillar_dates() }
     fn forward(&self) -> Option<&Interpolate<Date>> { self.0.forward() }
     fn div_assumptions(&self) -> DivAssumptions { self.0.div_assumptions() }
     fn displacement(&self, date: Date) -> Result<f64, qm::Error> {
@@ -1034,6 +1243,152 @@ pub mod tests {
         assert_vars(&variances, &serde_variances);
     }
 
+    fn two_pillar_surface(first: &[(f64, f64)], second: &[(f64, f64)])
+        -> Result<VolByProbabilityCubicSplineSmile, qm::Error> {
+
+        let calendar = RcCalendar::new(Arc::new(WeekdayCalendar()));
+        let base_date = Date::from_ymd(2012, 05, 25);
+        let base = DateDayFraction::new(base_date, 0.2);
+        let fwd = Linear::new(&[(base_date, 100.0)], Extrap::Flat, Extrap::Flat)?;
+        let divs = Linear::new(&[(base_date, 0.0)], Extrap::Flat, Extrap::Flat)?;
+        let smiles = [
+            (DateDayFraction::new(base_date + 91, 0.7), CubicSplineSmile::new(first)?),
+            (DateDayFraction::new(base_date + 182, 0.7), CubicSplineSmile::new(second)?)];
+        VolByProbabilityCubicSplineSmile::new(&smiles, calendar, base, fwd, divs,
+            DivAssumptions::NoCashDivs)
+    }
+
+    #[test]
+    fn extrapolation_policies() {
+
+        let smile = [(70.0, 0.26), (100.0, 0.2), (130.0, 0.22)];
+        let linear = two_pillar_surface(&smile, &smile).unwrap();
+        let flat = two_pillar_surface(&smile, &smile).unwrap()
+            .with_extrapolation(ExtrapolationPolicy::Flat).unwrap();
+        let error = two_pillar_surface(&smile, &smile).unwrap()
+            .with_extrapolation(ExtrapolationPolicy::Error).unwrap();
+
+        // query on the first pillar, so the strikes are not adjusted
+        fn vols(surface: &VolSurface, strike: f64) -> Result<f64, qm::Error> {
+            let pillar = DateDayFraction::new(Date::from_ymd(2012, 08, 24), 0.7);
+            let mut out = [NAN];
+            surface.volatilities(pillar, &[strike], &mut out)?;
+            Ok(out[0])
+        }
+
+        // inside the strike range, all policies interpolate the smile
+        let inside = vols(&linear, 85.0).unwrap();
+        assert!(inside > 0.2 && inside < 0.26, "inside={}", inside);
+        assert_eq!(vols(&flat, 85.0).unwrap(), inside);
+        assert_eq!(vols(&error, 85.0).unwrap(), inside);
+
+        // just outside, the spline extrapolates linearly, or is clamped to
+        // the vol at the edge of the range
+        let linear_outside = vols(&linear, 135.0).unwrap();
+        assert!(linear_outside > 0.22, "linear_outside={}", linear_outside);
+        assert_approx(vols(&flat, 135.0).unwrap(), 0.22, 1e-12);
+        assert_approx(vols(&flat, 60.0).unwrap(), 0.26, 1e-12);
+        assert!(vols(&error, 135.0).is_err());
+
+        // far outside, flat still clamps, and the error says why
+        assert_approx(vols(&flat, 200.0).unwrap(), 0.22, 1e-12);
+        let message = format!("{}", vols(&error, 200.0).unwrap_err());
+        assert!(message.contains(
+            "Strike 200 is outside the range 70 to 130 of the vol smile on 2012-08-24"),
+            "unexpected error: {}", message);
+
+        // after the last pillar, only the error policy refuses, and then
+        // only if vols are requested rather than just the vol time
+        let late = DateDayFraction::new(Date::from_ymd(2013, 05, 24), 0.7);
+        let mut out = [NAN];
+        assert!(flat.volatilities(late, &[100.0], &mut out).is_ok());
+        assert!(error.vol_time(late).is_ok());
+        let message = format!("{}", error.volatilities(late, &[100.0], &mut out).unwrap_err());
+        assert!(message.contains("Vol requested on 2013-05-24 is after the last \
+            pillar of the vol surface, on 2012-11-23"), "unexpected error: {}", message);
+
+        // the policy is serialized only if it is not the default
+        let serialized = serde_json::to_string(&flat).unwrap();
+        assert!(serialized.contains("\"extrapolation\":\"Flat\""), "{}", serialized);
+        let serialized = serde_json::to_string(&linear).unwrap();
+        assert!(!serialized.contains("extrapolation"), "{}", serialized);
+    }
+
+    #[test]
+    fn clean_surface_has_no_arbitrage() {
+        let v = two_pillar_surface(
+            &[(70.0, 0.26), (100.0, 0.2), (130.0, 0.22)],
+            &[(70.0, 0.25), (100.0, 0.19), (130.0, 0.21)]).unwrap();
+        assert_eq!(v.pillar_dates().len(), 2);
+        v.check_arbitrage().unwrap();
+
+        // flat vol has no smile and no term structure to check
+        let calendar = RcCalendar::new(Arc::new(WeekdayCalendar()));
+        let base = DateDayFraction::new(Date::from_ymd(2012, 05, 25), 0.2);
+        FlatVolSurface::new(0.3, calendar, base).check_arbitrage().unwrap();
+    }
+
+    #[test]
+    fn inverted_calendar_is_arbitrage() {
+
+        // The at the money variance increases, so the surface can be built,
+        // but the steep skew of the first smile puts more variance on the
+        // low strikes than the flatter second smile
+        let v = two_pillar_surface(
+            &[(70.0, 0.35), (100.0, 0.2), (130.0, 0.25)],
+            &[(70.0, 0.2), (100.0, 0.2), (130.0, 0.25)]).unwrap();
+        let err = v.check_arbitrage().unwrap_err();
+        let message = format!("{}", err);
+        assert!(message.contains("Calendar arbitrage at strike 73.6"),
+            "unexpected error: {}", message);
+        assert!(message.contains("on 2012-08-24 to"), "unexpected error: {}", message);
+        assert!(message.contains("on 2012-11-23"), "unexpected error: {}", message);
+    }
+
+    #[test]
+    fn flat_forward_variance() {
+        let calendar = RcCalendar::new(Arc::new(WeekdayCalendar()));
+        let base_date = Date::from_ymd(2012, 05, 25);
+        let base = DateDayFraction::new(base_date, 0.2);
+        let from = DateDayFraction::new(base_date + 3, 0.9);
+        let to = DateDayFraction::new(base_date + 10, 0.9);
+
+        // From Friday(0.2) to the following Monday(0.9) is 1.7 days, and on
+        // to the Monday after is a further 5 days
+        let v = FlatVolSurface::new(0.3, calendar, base);
+        let fwd_var = v.forward_variance(from, to, 10.0).unwrap();
+        assert_approx(fwd_var, 0.3 * 0.3 * 5.0 / 252.0, 1e-12);
+        let vol_time = v.vol_time(to).unwrap() - v.vol_time(from).unwrap();
+        assert_approx(fwd_var, 0.3 * 0.3 * vol_time, 1e-12);
+
+        // starting on or before the base date, it is just the variance
+        assert_approx(v.forward_variance(base, to, 10.0).unwrap(),
+            v.variance(to, 10.0).unwrap(), 1e-12);
+    }
+
+    #[test]
+    fn forward_variance_detects_calendar_arbitrage() {
+        let v = two_pillar_surface(
+            &[(70.0, 0.35), (100.0, 0.2), (130.0, 0.25)],
+            &[(70.0, 0.2), (100.0, 0.2), (130.0, 0.25)]).unwrap();
+        let base_date = Date::from_ymd(2012, 05, 25);
+        let from = DateDayFraction::new(base_date + 91, 0.7);
+        let to = DateDayFraction::new(base_date + 182, 0.7);
+
+        // at the money the variance grows, so there is no problem
+        let atm = v.forward_variance(from, to, 100.0).unwrap();
+        assert_approx(atm, v.variance(to, 100.0).unwrap()
+            - v.variance(from, 100.0).unwrap(), 1e-12);
+        assert!(atm > 0.0);
+
+        // but the steep skew of the first smile makes low strikes fall
+        let err = v.forward_variance(from, to, 70.0).unwrap_err();
+        let message = format!("{}", err);
+        assert!(message.contains("Calendar arbitrage at strike 70"),
+            "unexpected error: {}", message);
+        assert!(message.contains("on 2012-08-24 to"), "unexpected error: {}", message);
+    }
+
     fn assert_approx(value: f64, expected: f64, tolerance: f64) {
         assert!(approx_eq(value, expected, tolerance),
             "value={} expected={} tolerance={}", value, expected, tolerance);
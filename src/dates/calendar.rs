@@ -11,6 +11,7 @@ use serde::Deserialize;
 use core::factories::TypeId;
 use core::factories::Registry;
 use core::factories::Qrc;
+use core::qm;
 
 /// Calendars define when business holidays are scheduled. They are used for
 /// business day volatility, settlement calculations, and the roll-out of
@@ -78,6 +79,53 @@ pub trait Calendar : esd::Serialize + TypeId + Sync + Send + Debug {
         let basis = self.standard_basis();
         days / basis
     }
+
+    /// Is the given date a business day? The opposite of is_holiday.
+    fn is_business_day(&self, date: Date) -> bool {
+        !self.is_holiday(date)
+    }
+
+    /// Rolls a date onto a business day according to the given convention.
+    /// Business days are unaffected.
+    fn roll(&self, date: Date, convention: RollConvention) -> Date {
+        match convention {
+            RollConvention::Following => self.step(date, 0, true),
+            RollConvention::Preceding => self.step(date, 0, false),
+            RollConvention::ModifiedFollowing => {
+                // roll forwards unless that takes us into the next month
+                let (_, month, _) = date.ymd();
+                let forward = self.step(date, 0, true);
+                let (_, forward_month, _) = forward.ymd();
+                if forward_month == month {
+                    forward
+                } else {
+                    // assumes this is not a month of holidays
+                    self.step(date, 0, false)
+                }
+            }
+        }
+    }
+
+    /// Adds a number of business days, which may be negative. If the date
+    /// is not a business day, it first slips in the direction of the step,
+    /// so adding zero business days to a holiday gives the following
+    /// business day.
+    fn add_business_days(&self, date: Date, business_days: i32) -> Date {
+        self.step(date, business_days, business_days >= 0)
+    }
+}
+
+/// Conventions for rolling a date that falls on a weekend or holiday onto a
+/// business day.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RollConvention {
+    /// Move to the next business day
+    Following,
+    /// Move to the next business day, unless that is in the next month, in
+    /// which case move to the previous business day
+    ModifiedFollowing,
+    /// Move to the previous business day
+    Preceding
 }
 
 // Get serialization to work recursively for rate curves by using the
@@ -103,6 +151,7 @@ pub fn get_registry() -> &'static TypeRegistry {
             reg.insert("EveryDayCalendar", BoxFnSeed::new(EveryDayCalendar::from_serial));
             reg.insert("WeekdayCalendar", BoxFnSeed::new(WeekdayCalendar::from_serial));
             reg.insert("WeekdayAndHolidayCalendar", BoxFnSeed::new(WeekdayAndHolidayCalendar::from_serial));
+            reg.insert("HolidayCalendar", BoxFnSeed::new(HolidayCalendar::from_serial));
             reg.insert("VolatilityCalendar", BoxFnSeed::new(VolatilityCalendar::from_serial));
             reg
         };
@@ -473,6 +522,133 @@ impl Calendar for WeekdayAndHolidayCalendar {
     }
 }
 
+/// A calendar with an arbitrary set of weekend days, such as Friday and
+/// Saturday in some Middle Eastern markets, together with a list of business
+/// holidays. It is more general than WeekdayAndHolidayCalendar, but steps
+/// and counts a day at a time, so is slower over long ranges.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HolidayCalendar {
+    name: String,
+    weekend: [bool; 7],     // indexed by day of week, starting Monday
+    holidays: Vec<Date>     // in date order, with no duplicates
+}
+
+impl TypeId for HolidayCalendar {
+    fn get_type_id(&self) -> &'static str { "HolidayCalendar" }
+}
+
+impl HolidayCalendar {
+
+    /// Creates a calendar given a weekend mask, which is true for days of
+    /// the week that are not business days, starting with Monday. The
+    /// holidays may be in any order. It is an error if the mask leaves no
+    /// business days in the week.
+    pub fn new(name: &str, weekend: [bool; 7], holidays: &[Date])
+        -> Result<HolidayCalendar, qm::Error> {
+        let calendar = HolidayCalendar::with_weekend(name, weekend, holidays);
+        calendar.validate()?;
+        Ok(calendar)
+    }
+
+    /// Creates a calendar with Saturday and Sunday as the weekend
+    pub fn new_weekdays(name: &str, holidays: &[Date]) -> HolidayCalendar {
+        HolidayCalendar::with_weekend(name,
+            [false, false, false, false, false, true, true], holidays)
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<RcCalendar, esd::Error> {
+        let calendar = HolidayCalendar::deserialize(de)?;
+        if let Err(e) = calendar.validate() {
+            return Err(sd::de::Error::custom(e))
+        }
+        Ok(Qrc::new(Arc::new(calendar)))
+    }
+
+    fn with_weekend(name: &str, weekend: [bool; 7], holidays: &[Date]) -> HolidayCalendar {
+        let mut sorted = holidays.to_vec();
+        sorted.sort();
+        sorted.dedup();
+        HolidayCalendar { name: name.to_string(), weekend: weekend, holidays: sorted }
+    }
+
+    // with no business day in the week, there would be nothing for
+    // slip_to_next to slip onto, so rolling and stepping would never end
+    fn validate(&self) -> Result<(), qm::Error> {
+        if self.weekend.iter().all(|&is_weekend| is_weekend) {
+            return Err(qm::Error::new(&format!("Calendar '{}' has a weekend \
+                covering the whole week, so no business days", self.name)))
+        }
+        Ok(())
+    }
+
+    // slide off a holiday or weekend onto the nearest business day,
+    // stepping either forward or backward. (Step is +/- 1.)
+    fn slip_to_next(&self, from: Date, step: i32) -> Date {
+        let mut date = from;
+        while self.is_holiday(date) {
+            date += step;
+        }
+        date
+    }
+}
+
+impl Calendar for HolidayCalendar {
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn is_holiday(&self, date: Date) -> bool {
+        self.weekend[date.day_of_week() as usize]
+            || self.holidays.binary_search(&date).is_ok()
+    }
+
+    fn count_business_days(&self,
+        from: Date, from_fraction: f64,
+        to: Date, to_fraction: f64) -> f64 {
+
+        // cope with starting or ending on a weekend or holiday
+        // by slipping forward (at the from date) or backward (at the to date)
+        let adj_from = self.slip_to_next(from, 1);
+        let adj_to = self.slip_to_next(to, -1);
+        if adj_from > adj_to {
+            return 0.0
+        }
+
+        let mut count = 0;
+        let mut date = adj_from;
+        while date < adj_to {
+            if !self.is_holiday(date) {
+                count += 1;
+            }
+            date += 1;
+        }
+
+        // corrections at the ends if the fractions are not one
+        let from_adj = if self.is_holiday(from) { 0.0 } else { from_fraction };
+        let to_adj = if self.is_holiday(to) { 1.0 } else { to_fraction };
+        count as f64 + to_adj - from_adj
+    }
+
+    fn step(&self, from: Date, step: i32, slip_forward: bool) -> Date {
+
+        // slip off to the nearest business day in the required direction
+        let direction = if slip_forward { 1 } else { -1 };
+        let mut date = self.slip_to_next(from, direction);
+
+        // then step one business day at a time
+        let increment = if step > 0 { 1 } else { -1 };
+        for _ in 0..step.abs() {
+            date = self.slip_to_next(date + increment, increment);
+        }
+        date
+    }
+
+    fn standard_basis(&self) -> f64 {
+        252.0
+    }
+}
+
 /// A volatility calendar can have a non-zero weight for weekends. 25% is
 /// common. This affects the basis and the business day count. It also
 /// affects the step size. For example, a forward volatility model has its
@@ -693,6 +869,117 @@ mod tests {
         consistency_check_step(&calendar);
     }
 
+    /// Christmas, Boxing Day, New Year's Eve and New Year's Day, around the
+    /// weekend of 29th and 30th December 2018
+    fn year_end_calendar() -> HolidayCalendar {
+        let holidays = [Date::from_ymd(2019, 01, 01), Date::from_ymd(2018, 12, 25),
+            Date::from_ymd(2018, 12, 26), Date::from_ymd(2018, 12, 31)];
+        HolidayCalendar::new_weekdays("TST", &holidays)
+    }
+
+    #[test]
+    fn holiday_calendar_year_end_rolls() {
+        let calendar = year_end_calendar();
+        let d = |y, m, d| Date::from_ymd(y, m, d);
+        let following = RollConvention::Following;
+        let modified = RollConvention::ModifiedFollowing;
+        let preceding = RollConvention::Preceding;
+
+        // business days are unaffected
+        for convention in [following, modified, preceding].iter() {
+            assert_eq!(calendar.roll(d(2018, 12, 24), *convention), d(2018, 12, 24));
+            assert_eq!(calendar.roll(d(2018, 12, 28), *convention), d(2018, 12, 28));
+        }
+
+        // Christmas rolls within the month either way
+        assert_eq!(calendar.roll(d(2018, 12, 25), following), d(2018, 12, 27));
+        assert_eq!(calendar.roll(d(2018, 12, 25), modified), d(2018, 12, 27));
+        assert_eq!(calendar.roll(d(2018, 12, 25), preceding), d(2018, 12, 24));
+
+        // the weekend before year end: following crosses into January, so
+        // modified following rolls back into December
+        for day in [29, 30, 31].iter() {
+            assert_eq!(calendar.roll(d(2018, 12, *day), following), d(2019, 01, 02));
+            assert_eq!(calendar.roll(d(2018, 12, *day), modified), d(2018, 12, 28));
+            assert_eq!(calendar.roll(d(2018, 12, *day), preceding), d(2018, 12, 28));
+        }
+
+        // New Year's Day rolls forward within January
+        assert_eq!(calendar.roll(d(2019, 01, 01), following), d(2019, 01, 02));
+        assert_eq!(calendar.roll(d(2019, 01, 01), modified), d(2019, 01, 02));
+        assert_eq!(calendar.roll(d(2019, 01, 01), preceding), d(2018, 12, 28));
+    }
+
+    #[test]
+    fn holiday_calendar_add_business_days() {
+        let calendar = year_end_calendar();
+        let d = |y, m, d| Date::from_ymd(y, m, d);
+
+        assert!(calendar.is_business_day(d(2018, 12, 27)));
+        assert!(!calendar.is_business_day(d(2018, 12, 31)));
+        assert_eq!(calendar.add_business_days(d(2018, 12, 24), 1), d(2018, 12, 27));
+        assert_eq!(calendar.add_business_days(d(2018, 12, 24), 3), d(2019, 01, 02));
+        assert_eq!(calendar.add_business_days(d(2019, 01, 02), -1), d(2018, 12, 28));
+        assert_eq!(calendar.add_business_days(d(2018, 12, 25), 0), d(2018, 12, 27));
+        assert_eq!(calendar.count_business_days(
+            d(2018, 12, 24), 0.0, d(2019, 01, 02), 0.0), 3.0);
+    }
+
+    #[test]
+    fn holiday_calendar_weekend_mask() {
+        // Friday and Saturday weekend
+        let calendar = HolidayCalendar::new("TST",
+            [false, false, false, false, true, true, false], &[]).unwrap();
+        let thursday = Date::from_ymd(2018, 12, 27);
+        assert!(calendar.is_business_day(thursday));
+        assert!(!calendar.is_business_day(thursday + 1));
+        assert!(!calendar.is_business_day(thursday + 2));
+        assert!(calendar.is_business_day(thursday + 3));
+        assert_eq!(calendar.add_business_days(thursday, 1), thursday + 3);
+    }
+
+    #[test]
+    fn holiday_calendar_rejects_weekend_of_whole_week() {
+        assert!(HolidayCalendar::new("TST", [true; 7], &[]).is_err());
+
+        // a single business day is enough
+        let calendar = HolidayCalendar::new("TST",
+            [true, true, true, false, true, true, true], &[]).unwrap();
+        let thursday = Date::from_ymd(2018, 12, 27);
+        assert_eq!(calendar.add_business_days(thursday, 1), thursday + 7);
+    }
+
+    #[test]
+    fn holiday_calendar_matches_weekday_and_holiday() {
+        let holidays = [Date::from_ymd(2018, 12, 25), Date::from_ymd(2018, 12, 26),
+            Date::from_ymd(2019, 01, 01)];
+        let calendar = HolidayCalendar::new_weekdays("TST", &holidays);
+        let other = WeekdayAndHolidayCalendar::new("TST", &holidays);
+        let start = Date::from_ymd(2018, 12, 01);
+        for i in 0..60 {
+            let date = start + i;
+            assert_eq!(calendar.is_holiday(date), other.is_holiday(date));
+            for step in -3..4 {
+                assert_eq!(calendar.step(date, step, step >= 0),
+                    other.step(date, step, step >= 0), "date={} step={}", date, step);
+            }
+            assert_eq!(calendar.count_business_days(start, 0.5, date, 0.25),
+                other.count_business_days(start, 0.5, date, 0.25), "date={}", date);
+        }
+    }
+
+    #[test]
+    fn holiday_calendar_count_consistency() {
+        let calendar = year_end_calendar();
+        consistency_check_count(&calendar, true);
+    }
+
+    #[test]
+    fn holiday_calendar_step_consistency() {
+        let calendar = year_end_calendar();
+        consistency_check_step(&calendar);
+    }
+
     #[test]
     fn volatility_check_count() {
         let calendar = new_test_volatility_calendar();
@@ -5,6 +5,8 @@ use std::ops::Add;
 use std::ops::AddAssign;
 use std::fmt::Display;
 use std::fmt;
+use std::str::FromStr;
+use core::qm;
 
 /// We define some commonly used times of day. These map to different amounts
 /// of volatility day_fraction depending on the exchange etc.
@@ -35,6 +37,21 @@ impl Display for TimeOfDay {
     }
 }
 
+impl FromStr for TimeOfDay {
+    type Err = qm::Error;
+
+    /// Reads a time of day as written by Display, for example "Close"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "Open" => Ok(TimeOfDay::Open),
+            "EDSP" => Ok(TimeOfDay::EDSP),
+            "Close" => Ok(TimeOfDay::Close),
+            _ => Err(qm::Error::new(&format!(
+                "Invalid time of day '{}': expected Open, EDSP or Close", s)))
+        }
+    }
+}
+
 /// Convenience struct that groups a date and a time of day. For example, this
 /// represents the time of a fixing.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
@@ -48,10 +65,38 @@ impl DateTime {
         DateTime { date: date, time_of_day: time_of_day }
     }
 
+    /// Parses a date and time of day of the form YYYY-MM-DD/Open, as
+    /// written by to_iso. The date is validated as in Date::from_iso.
+    pub fn from_iso(s: &str) -> Result<DateTime, qm::Error> {
+        let mut parts = s.splitn(2, '/');
+        let date = parts.next().unwrap_or("");
+        let time_of_day = match parts.next() {
+            Some(time_of_day) => time_of_day,
+            None => return Err(qm::Error::new(&format!(
+                "Invalid date time '{}': expected YYYY-MM-DD/TimeOfDay", s)))
+        };
+        Ok(DateTime::new(Date::from_iso(date)?, time_of_day.parse()?))
+    }
+
+    /// Writes the date in ISO 8601 format followed by the time of day, for
+    /// example 2017-01-02/Open. This is the same as the Display format.
+    pub fn to_iso(&self) -> String {
+        self.to_string()
+    }
+
     pub fn date(&self) -> Date { self.date }
     pub fn time_of_day(&self) -> TimeOfDay { self.time_of_day }
 }
 
+impl FromStr for DateTime {
+    type Err = qm::Error;
+
+    /// Reads a date time of the form YYYY-MM-DD/Open. See DateTime::from_iso.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        DateTime::from_iso(s)
+    }
+}
+
 impl Add<i32> for DateTime {
     type Output = DateTime;
 
@@ -170,6 +215,24 @@ mod tests {
         assert!(thursday_late <= friday_early);
     }
 
+    #[test]
+    fn iso_date_times() {
+        let date = Date::from_ymd(2017, 02, 28);
+        for &time_of_day in [TimeOfDay::Open, TimeOfDay::EDSP, TimeOfDay::Close].iter() {
+            let date_time = DateTime::new(date, time_of_day);
+            let text = date_time.to_iso();
+            assert_eq!(text, format!("2017-02-28/{}", time_of_day));
+            assert_eq!(DateTime::from_iso(&text).unwrap(), date_time);
+            assert_eq!(text.parse::<DateTime>().unwrap(), date_time);
+        }
+
+        assert!(DateTime::from_iso("2017-13-01/Open").is_err());
+        assert!(DateTime::from_iso("2017-02-29/Close").is_err());
+        assert!(DateTime::from_iso("2017-02-28").is_err());
+        assert!(DateTime::from_iso("2017-02-28/Noon").is_err());
+        assert!(DateTime::from_iso("2017-02-28/open").is_err());
+    }
+
     #[test]
     fn equality_and_order_for_date_day_fractions() {
 
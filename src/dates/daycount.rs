@@ -0,0 +1,131 @@
+use dates::Date;
+
+/// Day count conventions define how the interval between two dates is
+/// converted into a fraction of a year, for example when accruing interest
+/// or converting between yields and discount factors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DayCount {
+    /// Actual number of days divided by 365, regardless of leap years
+    Act365F,
+    /// Actual number of days divided by 360
+    Act360,
+    /// 30/360 US (also known as 30U/360 or bond basis), with the
+    /// end-of-February adjustments
+    Thirty360US
+}
+
+impl DayCount {
+    /// Returns the fraction of a year between the start and end dates. This
+    /// is negative if the end is before the start.
+    pub fn year_fraction(&self, start: Date, end: Date) -> f64 {
+        match *self {
+            DayCount::Act365F => ((end - start) as f64) / 365.0,
+            DayCount::Act360 => ((end - start) as f64) / 360.0,
+            DayCount::Thirty360US => thirty_360_us_days(start, end) as f64 / 360.0
+        }
+    }
+}
+
+/// Counts the days between two dates under 30/360 US rules. These are
+/// applied in order:
+///
+/// 1. If both dates are the last day of February, the end day becomes 30
+/// 2. If the start is the last day of February, the start day becomes 30
+/// 3. If the end day is 31 and the start day is 30 or 31, the end day
+///    becomes 30
+/// 4. If the start day is 31, it becomes 30
+fn thirty_360_us_days(start: Date, end: Date) -> i32 {
+    let (y1, m1, mut d1) = start.ymd();
+    let (y2, m2, mut d2) = end.ymd();
+
+    let start_end_feb = is_last_day_of_february(start);
+    if start_end_feb && is_last_day_of_february(end) {
+        d2 = 30;
+    }
+    if start_end_feb {
+        d1 = 30;
+    }
+    if d2 == 31 && d1 >= 30 {
+        d2 = 30;
+    }
+    if d1 == 31 {
+        d1 = 30;
+    }
+
+    360 * (y2 - y1) + 30 * (m2 - m1) + (d2 - d1)
+}
+
+fn is_last_day_of_february(date: Date) -> bool {
+    let (_, month, _) = date.ymd();
+    let (_, next_month, _) = (date + 1).ymd();
+    month == 2 && next_month == 3
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+    use serde_json;
+
+    #[test]
+    fn end_of_february_to_march_30() {
+
+        // Non-leap year: 28th Feb is the end of February
+        let start = Date::from_ymd(2019, 02, 28);
+        let end = Date::from_ymd(2019, 03, 30);
+        assert_fraction(DayCount::Act365F.year_fraction(start, end), 30.0 / 365.0);
+        assert_fraction(DayCount::Act360.year_fraction(start, end), 30.0 / 360.0);
+        assert_fraction(DayCount::Thirty360US.year_fraction(start, end), 30.0 / 360.0);
+
+        // Leap year: 29th Feb is the end of February
+        let start = Date::from_ymd(2020, 02, 29);
+        let end = Date::from_ymd(2020, 03, 30);
+        assert_fraction(DayCount::Act365F.year_fraction(start, end), 30.0 / 365.0);
+        assert_fraction(DayCount::Act360.year_fraction(start, end), 30.0 / 360.0);
+        assert_fraction(DayCount::Thirty360US.year_fraction(start, end), 30.0 / 360.0);
+
+        // Leap year: 28th Feb is not the end of February, so no adjustment
+        let start = Date::from_ymd(2020, 02, 28);
+        let end = Date::from_ymd(2020, 03, 30);
+        assert_fraction(DayCount::Act365F.year_fraction(start, end), 31.0 / 365.0);
+        assert_fraction(DayCount::Act360.year_fraction(start, end), 31.0 / 360.0);
+        assert_fraction(DayCount::Thirty360US.year_fraction(start, end), 32.0 / 360.0);
+    }
+
+    #[test]
+    fn thirty_360_us_rules() {
+
+        // both ends of February
+        let start = Date::from_ymd(2019, 02, 28);
+        let end = Date::from_ymd(2020, 02, 29);
+        assert_fraction(DayCount::Thirty360US.year_fraction(start, end), 1.0);
+
+        // end of February to the 31st
+        let end = Date::from_ymd(2019, 03, 31);
+        assert_fraction(DayCount::Thirty360US.year_fraction(start, end), 30.0 / 360.0);
+
+        // 31st to 31st
+        let start = Date::from_ymd(2019, 01, 31);
+        assert_fraction(DayCount::Thirty360US.year_fraction(start, end), 60.0 / 360.0);
+
+        // the end of a month with 31 days is only adjusted from the 30th or 31st
+        let start = Date::from_ymd(2019, 01, 29);
+        assert_fraction(DayCount::Thirty360US.year_fraction(start, end), 62.0 / 360.0);
+
+        // and backwards
+        assert_fraction(DayCount::Thirty360US.year_fraction(end, start), -61.0 / 360.0);
+    }
+
+    #[test]
+    fn day_count_serde() {
+        let serialized = serde_json::to_string(&DayCount::Thirty360US).unwrap();
+        assert_eq!(serialized, r#""Thirty360US""#);
+        let deserialized: DayCount = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(deserialized, DayCount::Thirty360US);
+    }
+
+    fn assert_fraction(value: f64, expected: f64) {
+        assert!(approx_eq(value, expected, 1e-14),
+            "value={} expected={}", value, expected);
+    }
+}
@@ -1,6 +1,7 @@
 pub mod calendar;
 pub mod rules;
 pub mod datetime;
+pub mod daycount;
 
 use serde::Serializer;
 use serde::Serialize;
@@ -17,6 +18,8 @@ use std::fmt::Display;
 use std::str::FromStr;
 use std::cmp::Ordering;
 use core::qm;
+use dates::calendar::Calendar;
+use dates::calendar::RollConvention;
 use math::interpolation::Interpolable;
 
 /// Date represents a date in risk space. In practice, this starts at the
@@ -99,30 +102,9 @@ impl FromStr for Date {
     type Err = qm::Error;
 
     /// Reads a date from a string, which must be an ISO format date
-    /// of the form YYYY-MM-DD
+    /// of the form YYYY-MM-DD. See Date::from_iso.
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-
-        // It upsets me how inefficient this is. In C I'd use scanf,
-        // which would allow me to parse the string without any heap
-        // access or intermediate objects.
-        let ymd: Vec<&str> = s.split("-").collect();
-
-        let mut array: [i32; 3] = [0; 3];
-        for (i, elem) in array.iter_mut().enumerate() {
-            *elem = match ymd[i].parse::<i32>() {
-                Ok(number) => number,
-                Err(_e) => return Err(qm::Error::new(s))
-            };
-        }
-
-        // this call does no validation, so we check the resulting date
-        // before returning it
-        let result = Date::from_ymd(array[0], array[1], array[2]);
-        if !result.is_valid() {
-            return Err(qm::Error::new(s))
-        }
-
-        Ok(result)
+        Date::from_iso(s)
     }
 }
 
@@ -213,6 +195,50 @@ impl Date {
         Date(julian)
     }
 
+    /// Parses an ISO 8601 date of the form YYYY-MM-DD. Unlike from_ymd,
+    /// this validates strictly: the month must be 1 to 12, the day must
+    /// exist in that month, so 2017-02-30 is an error rather than a synonym
+    /// for 2017-03-02, and the date must satisfy is_valid.
+    pub fn from_iso(s: &str) -> Result<Date, qm::Error> {
+
+        // Check the layout first, so we can parse the fields by position
+        // without allowing signs, spaces or missing leading zeros
+        let bytes = s.as_bytes();
+        let layout_ok = bytes.len() == 10 && bytes.iter().enumerate().all(
+            |(i, &b)| if i == 4 || i == 7 { b == b'-' } else { b.is_ascii_digit() });
+        if !layout_ok {
+            return Err(qm::Error::new(&format!(
+                "Invalid date '{}': expected YYYY-MM-DD", s)))
+        }
+
+        // the layout check means these cannot fail
+        let year = s[0..4].parse::<i32>().unwrap();
+        let month = s[5..7].parse::<i32>().unwrap();
+        let day = s[8..10].parse::<i32>().unwrap();
+
+        if month < 1 || month > 12 {
+            return Err(qm::Error::new(&format!(
+                "Invalid date '{}': month must be 1 to 12", s)))
+        }
+        if day < 1 || day > days_in_month(year, month) {
+            return Err(qm::Error::new(&format!(
+                "Invalid date '{}': day must be 1 to {}", s, days_in_month(year, month))))
+        }
+
+        let result = Date::from_ymd(year, month, day);
+        if !result.is_valid() {
+            return Err(qm::Error::new(&format!(
+                "Invalid date '{}': outside the supported range of dates", s)))
+        }
+        Ok(result)
+    }
+
+    /// Writes the date in ISO 8601 format, YYYY-MM-DD. This is the same as
+    /// the Display format, and is read back by from_iso.
+    pub fn to_iso(self) -> String {
+        self.to_string()
+    }
+
     /// Returns the year, month and day associated with this date
     pub fn ymd(self) -> (i32, i32, i32) {
         ymd_from_truncated_julian(self.0)
@@ -239,6 +265,78 @@ impl Date {
     }
 }
 
+/// Is the given year a leap year in the Gregorian calendar?
+pub fn is_leap_year(year: i32) -> bool {
+    (year % 4 == 0 && year % 100 != 0) || year % 400 == 0
+}
+
+/// The number of days in the given month, numbered from 1 to 12, of the
+/// given year. Returns zero if the month is out of range.
+pub fn days_in_month(year: i32, month: i32) -> i32 {
+    match month {
+        1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+        4 | 6 | 9 | 11 => 30,
+        2 => if is_leap_year(year) { 29 } else { 28 },
+        _ => 0
+    }
+}
+
+/// Larger counts than this in a tenor cannot give a valid date, whatever
+/// the unit
+const MAX_TENOR_COUNT: i32 = 100_000;
+
+/// Adds a tenor such as "3M" to a date, and rolls the result onto a business
+/// day in the given calendar. A tenor is a whole number, which may be
+/// negative, followed by a unit of D for days, W for weeks, M for months or
+/// Y for years. Units may be upper or lower case.
+///
+/// Days and weeks are calendar days. Months and years keep the day of the
+/// month, unless the resulting month is too short, in which case the result
+/// is its last day, so 31st January plus one month is the end of February.
+/// The result is rolled using the modified following convention, so a tenor
+/// in months or years never rolls into the next month.
+pub fn add_tenor(base: Date, tenor: &str, calendar: &Calendar)
+    -> Result<Date, qm::Error> {
+
+    let invalid = |reason: &str| qm::Error::new(&format!(
+        "Invalid tenor '{}': {}", tenor, reason));
+
+    let unit = tenor.chars().last().ok_or_else(|| invalid("the tenor is empty"))?;
+    let number = &tenor[..tenor.len() - unit.len_utf8()];
+    let digits = if number.starts_with('-') { &number[1..] } else { number };
+    let not_a_number = || invalid("expected a whole number followed by D, W, M or Y");
+    if !digits.chars().all(|c| c.is_ascii_digit()) {
+        return Err(not_a_number())
+    }
+    let count = number.parse::<i32>().map_err(|_| not_a_number())?;
+    if count.abs() > MAX_TENOR_COUNT {
+        return Err(invalid("the number is too large"))
+    }
+
+    let date = match unit.to_ascii_uppercase() {
+        'D' => base + count,
+        'W' => base + 7 * count,
+        'M' => add_months(base, count),
+        'Y' => add_months(base, 12 * count),
+        _ => return Err(invalid("the unit must be D, W, M or Y"))
+    };
+    if !date.is_valid() {
+        return Err(invalid("the result is outside the supported range of dates"))
+    }
+    Ok(calendar.roll(date, RollConvention::ModifiedFollowing))
+}
+
+/// Adds a number of months to a date, which may be negative, keeping the
+/// day of the month if possible, or otherwise using the last day of the
+/// month
+fn add_months(date: Date, months: i32) -> Date {
+    let (year, month, day) = date.ymd();
+    let zero_based = year * 12 + (month - 1) + months;
+    let year = if zero_based < 0 { (zero_based + 1) / 12 - 1 } else { zero_based / 12 };
+    let month = zero_based - year * 12 + 1;
+    Date::from_ymd(year, month, day.min(days_in_month(year, month)))
+}
+
 /// Calculates a julian date given a year, month and day. (Code adapted
 /// from FORTRAN code in http://aa.usno.navy.mil/faq/docs/JD_Formula.php)
 pub fn truncated_julian_from_ymd(year: i32, month: i32, date: i32) -> i32 {
@@ -275,6 +373,7 @@ mod tests {
     use math::interpolation::Extrap;
     use math::interpolation::Interpolate;
     use serde_json;
+    use dates::calendar::WeekdayCalendar;
 
     #[test]
     fn date_creation_and_access() {
@@ -375,6 +474,52 @@ mod tests {
         }
     }
 
+    #[test]
+    fn iso_dates() {
+        let date = Date::from_iso("2017-01-02").unwrap();
+        assert_eq!(date, Date::from_ymd(2017, 01, 02));
+        assert_eq!(Date::from_iso("2016-02-29").unwrap(), Date::from_ymd(2016, 02, 29));
+        assert_eq!(Date::from_iso("2000-02-29").unwrap(), Date::from_ymd(2000, 02, 29));
+        assert_eq!(Date::from_iso("2017-12-31").unwrap(), Date::from_ymd(2017, 12, 31));
+        assert_eq!(date.to_iso(), "2017-01-02");
+    }
+
+    #[test]
+    fn iso_date_rejects_invalid_month() {
+        let err = Date::from_iso("2017-13-01").unwrap_err();
+        assert!(format!("{}", err).contains("2017-13-01"), "{}", err);
+        assert!(Date::from_iso("2017-00-01").is_err());
+        assert!(Date::from_str("2017-13-01").is_err());
+    }
+
+    #[test]
+    fn iso_date_rejects_invalid_day_of_month() {
+        let err = Date::from_iso("2017-02-30").unwrap_err();
+        assert!(format!("{}", err).contains("2017-02-30"), "{}", err);
+        assert!(Date::from_iso("2017-02-29").is_err());
+        assert!(Date::from_iso("1900-02-29").is_err());
+        assert!(Date::from_iso("2017-04-31").is_err());
+        assert!(Date::from_iso("2017-01-00").is_err());
+    }
+
+    #[test]
+    fn iso_date_rejects_bad_layout() {
+        assert!(Date::from_iso("2017-1-02").is_err());
+        assert!(Date::from_iso("2017-01-02 ").is_err());
+        assert!(Date::from_iso("+017-01-02").is_err());
+        assert!(Date::from_iso("2017/01/02").is_err());
+    }
+
+    #[test]
+    fn round_trip_date_via_iso() {
+        // roughly from 1970 to 2120
+        for i in 588..(588 + 150 * 365) {
+            let d1 = Date::from_truncated_julian(i);
+            let d2 = Date::from_iso(&d1.to_iso()).unwrap();
+            assert_eq!(d1, d2);
+        }
+    }
+
     #[test]
     fn day_of_week() {
         let thursday = Date::from_ymd(2018, 05, 10);
@@ -474,6 +619,67 @@ mod tests {
         assert_eq!(deserialized, date);
     }
 
+    #[test]
+    fn add_tenor_days_and_weeks() {
+        let calendar = WeekdayCalendar::new();
+        let monday = Date::from_ymd(2017, 01, 02);
+
+        assert_eq!(add_tenor(monday, "4D", &calendar).unwrap(),
+            Date::from_ymd(2017, 01, 06));
+        // Saturday rolls forward to Monday
+        assert_eq!(add_tenor(monday, "5D", &calendar).unwrap(),
+            Date::from_ymd(2017, 01, 09));
+        assert_eq!(add_tenor(monday, "-3d", &calendar).unwrap(),
+            Date::from_ymd(2016, 12, 30));
+        assert_eq!(add_tenor(monday, "2W", &calendar).unwrap(),
+            Date::from_ymd(2017, 01, 16));
+        assert_eq!(add_tenor(monday, "0W", &calendar).unwrap(), monday);
+    }
+
+    #[test]
+    fn add_tenor_months_and_years() {
+        let calendar = WeekdayCalendar::new();
+        let monday = Date::from_ymd(2017, 01, 02);
+
+        // Sunday 2nd April rolls forward to Monday
+        assert_eq!(add_tenor(monday, "3M", &calendar).unwrap(),
+            Date::from_ymd(2017, 04, 03));
+        assert_eq!(add_tenor(monday, "-2M", &calendar).unwrap(),
+            Date::from_ymd(2016, 11, 02));
+        assert_eq!(add_tenor(monday, "1Y", &calendar).unwrap(),
+            Date::from_ymd(2018, 01, 02));
+        assert_eq!(add_tenor(monday, "-1y", &calendar).unwrap(),
+            Date::from_ymd(2016, 01, 04));
+    }
+
+    #[test]
+    fn add_tenor_end_of_month() {
+        let calendar = WeekdayCalendar::new();
+
+        assert_eq!(add_tenor(Date::from_ymd(2017, 01, 31), "1M", &calendar).unwrap(),
+            Date::from_ymd(2017, 02, 28));
+        assert_eq!(add_tenor(Date::from_ymd(2016, 01, 31), "1M", &calendar).unwrap(),
+            Date::from_ymd(2016, 02, 29));
+        assert_eq!(add_tenor(Date::from_ymd(2016, 02, 29), "1Y", &calendar).unwrap(),
+            Date::from_ymd(2017, 02, 28));
+
+        // Sunday 30th April would roll into May, so rolls back to Friday
+        assert_eq!(add_tenor(Date::from_ymd(2017, 03, 31), "1M", &calendar).unwrap(),
+            Date::from_ymd(2017, 04, 28));
+    }
+
+    #[test]
+    fn add_tenor_rejects_invalid_strings() {
+        let calendar = WeekdayCalendar::new();
+        let date = Date::from_ymd(2017, 01, 02);
+
+        let err = add_tenor(date, "3X", &calendar).unwrap_err();
+        assert!(format!("{}", err).contains("'3X'"), "{}", err);
+        for tenor in &["", "M", "1.5Y", "M3", "3 M", "+3M", "-+3M", "1000000D", "300Y"] {
+            assert!(add_tenor(date, tenor, &calendar).is_err(), "tenor={}", tenor);
+        }
+    }
+
     #[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
     struct Foo {
         start: Date,
@@ -1,5 +1,6 @@
 use dates::Date;
 use dates::calendar::RcCalendar;
+use dates::calendar::RollConvention;
 use core::factories::TypeId;
 use core::factories::Registry;
 use core::factories::Qrc;
@@ -152,17 +153,7 @@ impl ModifiedFollowing {
 
 impl DateRule for ModifiedFollowing {
     fn apply(&self, date: Date) -> Date {
-        let (_, m1, _) = date.ymd();
-
-        // try stepping forwards and return the result if in same month
-        let forward = self.calendar.step(date, 0, true);
-        let (_, m2, _) = forward.ymd();
-        if m2 == m1 {
-            forward
-        } else {
-            // go backwards instead (assumes this is not a month of holidays)
-            self.calendar.step(date, 0, false)
-        }
+        self.calendar.roll(date, RollConvention::ModifiedFollowing)
     }
 }
 
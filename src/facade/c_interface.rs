@@ -7,6 +7,7 @@ use std::error::Error;
 use std::panic::catch_unwind;
 use std::panic::RefUnwindSafe;
 use libc::c_char;
+use serde_json;
 use core::qm;
 use core::dedup::DedupControl;
 use facade::handle::extern_handle as eh;
@@ -307,6 +308,28 @@ pub extern "C" fn qm_calculate(pricer_factory: u64, instrument: u64,
     })
 }
 
+/// Prices a complete JSON request in one call, returning a JSON response with the
+/// price and any requested reports. See facade::price_request for the format of
+/// the request and response. The source is inline UTF8 text.
+///
+/// In the event of error, the returned string is a JSON object with a single
+/// 'error' field containing the error message. Either way, the string must be
+/// freed using qm_free_string.
+#[no_mangle]
+pub extern "C" fn qm_price_request(source: *const c_char) -> *mut c_char {
+    let response = match catch_unwind(|| {
+        let request = str::from_utf8(bytes_from_c_string(source))?;
+        facade::price_request(request)
+    }) {
+        Ok(Ok(response)) => response,
+        Ok(Err(e)) => error_json(e.description()),
+        Err(_) => error_json("Caught panic when pricing request")
+    };
+
+    // the JSON serializer escapes any embedded nulls, so the unwrap cannot fail
+    CString::new(response).unwrap().into_raw()
+}
+
 /// Compares two reports for equality. If they are equal, it returns a handle of type Empty, which
 /// is not an error. If they are not equal, it returns a handle of type Error, where the error
 /// message details the differences between the reports. This is normally used for testing.
@@ -402,6 +425,16 @@ fn open_c_file(source: *const c_char) -> Result<File, qm::Error> {
     Ok(file)
 }
 
+#[derive(Serialize)]
+struct ErrorResponse<'a> {
+    error: &'a str
+}
+
+fn error_json(message: &str) -> String {
+    // serializing a single string cannot fail
+    serde_json::to_string(&ErrorResponse { error: message }).unwrap()
+}
+
 fn bytes_from_c_string<'a>(source: *const c_char) -> &'a[u8] {
     unsafe { CStr::from_ptr(source).to_bytes() }
 }
@@ -555,6 +588,36 @@ pub mod tests {
         Ok(())
     }
 
+    fn price_request_using_c_interface(request: &str) -> serde_json::Value {
+        let request_c = CString::new(request).unwrap();
+        let response = qm_price_request(request_c.as_ptr());
+        let response_bytes = unsafe { CStr::from_ptr(response).to_bytes() };
+        let value = serde_json::from_slice(response_bytes).unwrap();
+        qm_free_string(response);
+        value
+    }
+
+    #[test]
+    fn c_interface_price_request() {
+        let request = format!(r#"{{ "pricer_factory": {}, "instrument": {},
+            "market_data": {}, "fixing_table": {}, "reports": [{}] }}"#,
+            str::from_utf8(sample_pricer_factory_json()).unwrap(),
+            str::from_utf8(sample_forward_european_json()).unwrap(),
+            str::from_utf8(sample_market_data_json()).unwrap(),
+            str::from_utf8(sample_fixing_table_json()).unwrap(),
+            str::from_utf8(sample_report_generator_json()).unwrap());
+        let response = price_request_using_c_interface(&request);
+        assert!(response["price"].as_f64().unwrap() > 0.0);
+        assert_eq!(response["reports"].as_array().unwrap().len(), 1);
+        assert!(response.get("error").is_none());
+
+        // errors come back as JSON too
+        let response = price_request_using_c_interface(&request[..100]);
+        let error = response["error"].as_str().unwrap();
+        assert!(error.starts_with("Invalid pricing request"), "{}", error);
+        assert!(response.get("price").is_none());
+    }
+
     fn convert_error(handle: u64) -> Result<(), qm::Error> {
         // get the message from rust as a c string
         let message = qm_error_string(handle);
@@ -5,7 +5,7 @@ use instruments::{RcInstrument, Instrument, DEDUP_INSTRUMENT};
 use instruments::assets::{RcCurrency, Currency, DEDUP_CURRENCY};
 use pricers::RcPricerFactory;
 use data::fixings::RcFixingTable;
-use risk::{RcReportGenerator, BoxReport};
+use risk::{RcReportGenerator, BoxReport, generate_reports};
 use risk::marketdata::RcMarketData;
 use core::dedup::{Dedup, DedupControl, dedup_map_from_slice};
 use core::factories::Qrc;
@@ -15,6 +15,8 @@ use serde::Deserialize;
 use serde::Serialize;
 use serde_json as sdj;
 use risk::ReportTolerances;
+use data::fixings::FixingTable;
+use instruments::PricingContext;
 use std::io::{Read, Write};
 use std::sync::Arc;
 use std::fmt;
@@ -137,15 +139,75 @@ pub fn calculate(pricer_factory: RcPricerFactory, instrument: RcInstrument,
 
     let mut pricer = pricer_factory.new(instrument, fixing_table, market_data)?;
     let price = pricer.price()?;
-    let mut saveable = pricer.as_bumpable().new_saveable();
+    generate_reports(&mut *pricer, report_generators, price)
+}
 
-    let mut reports = Vec::with_capacity(report_generators.len());
-    for report_generator in report_generators.iter() {
-        let report = report_generator.generate(&mut *pricer, &mut *saveable, price)?;
-        reports.push(report);
-    }
+/// A complete pricing request, as accepted by price_request. The fixing
+/// table and reports are optional.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PriceRequest {
+    pricer_factory: RcPricerFactory,
+    instrument: RcInstrument,
+    market_data: RcMarketData,
+    #[serde(default)]
+    fixing_table: Option<RcFixingTable>,
+    #[serde(default)]
+    reports: Vec<RcReportGenerator>
+}
 
-    Ok(reports)
+/// The response written by price_request
+#[derive(Serialize)]
+struct PriceResponse<'a> {
+    price: f64,
+    reports: &'a [BoxReport]
+}
+
+/// Single entry point for pricing, taking a JSON request and returning a JSON
+/// response, for use where it is easier to pass strings than handles, for
+/// example over FFI. The request is an object with the following fields,
+/// each in the same format as the corresponding from_json function:
+///
+/// * 'pricer_factory' - How to price, for example Monte-Carlo or analytic
+/// * 'instrument'     - What to price, with currencies and subcomponents inline
+/// * 'market_data'    - The market data for pricing
+/// * 'fixing_table'   - Optional historical fixings, defaulting to none
+/// * 'reports'        - Optional list of report generators, such as delta
+///
+/// The response is an object with a 'price' field and a 'reports' field, which
+/// contains the reports in the same order as the report generators, in the
+/// format written by write_results.
+///
+/// Without a fixing table, no fixings are known before the spot date of the
+/// market data, so instruments that need past fixings give an error.
+/// Malformed or incomplete requests result in an error describing what is
+/// wrong and where in the JSON it was found.
+pub fn price_request(json: &str) -> Result<String, qm::Error> {
+
+    // currencies and subinstruments must all be supplied inline, as there is
+    // nowhere else to supply them
+    let mut ccy = Dedup::<Currency, Arc<Currency>>::new(DedupControl::WriteOnce,
+        dedup_map_from_slice(&[]));
+    let mut opt = Dedup::<Instrument, Qrc<Instrument>>::new(DedupControl::WriteOnce,
+        dedup_map_from_slice(&[]));
+    let request: PriceRequest = ccy.with(&DEDUP_CURRENCY,
+        || opt.with(&DEDUP_INSTRUMENT,
+        || sdj::from_str(json))).map_err(|e| qm::Error::new(&format!(
+            "Invalid pricing request: {}", e)))?;
+
+    let fixing_table = match request.fixing_table {
+        Some(fixing_table) => fixing_table,
+        None => RcFixingTable::new(Arc::new(FixingTable::new(
+            request.market_data.spot_date())))
+    };
+
+    let mut pricer = request.pricer_factory.new(request.instrument,
+        fixing_table, request.market_data)?;
+    let price = pricer.price()?;
+    let reports = generate_reports(&mut *pricer, &request.reports, price)?;
+
+    let response = PriceResponse { price: price, reports: &reports };
+    Ok(sdj::to_string(&response)?)
 }
 
 /// Unpacks a set of calculation results to the given stream. For example, they may be
@@ -197,6 +259,7 @@ pub mod tests {
     use super::*;
     use std::io::Cursor;
     use std::str::from_utf8;
+    use std::error::Error;
 
     #[test]
     fn facade_forward_starting_european_price() {
@@ -236,6 +299,88 @@ pub mod tests {
         assert_approx_eq_reports(&results, &baseline, 1e-12, 1e-12, 1e-12).unwrap();
     }
 
+    fn sample_price_request(reports: &str) -> String {
+        format!(r#"{{
+            "pricer_factory": {},
+            "instrument": {},
+            "market_data": {},
+            "fixing_table": {},
+            "reports": [{}]
+        }}"#,
+            from_utf8(sample_pricer_factory_json()).unwrap(),
+            from_utf8(sample_forward_european_json()).unwrap(),
+            from_utf8(sample_market_data_json()).unwrap(),
+            from_utf8(sample_fixing_table_json()).unwrap(),
+            reports)
+    }
+
+    #[test]
+    fn facade_price_request() {
+
+        let request = sample_price_request(
+            from_utf8(sample_report_generator_json()).unwrap());
+        let response = price_request(&request).unwrap();
+        let response: sdj::Value = sdj::from_str(&response).unwrap();
+
+        // the price matches pricing directly via the API
+        let pricer_factory = pricer_factory_from_json(
+            &mut Cursor::new(sample_pricer_factory_json())).unwrap();
+        let european = instrument_from_json(
+            &mut Cursor::new(sample_forward_european_json()),
+            DedupControl::WriteOnce, &[],
+            DedupControl::WriteOnce, &[]).unwrap();
+        let market_data = market_data_from_json(
+            &mut Cursor::new(sample_market_data_json())).unwrap();
+        let fixing_table = fixing_table_from_json(
+            &mut Cursor::new(sample_fixing_table_json())).unwrap();
+        let pricer = pricer_factory.new(european, fixing_table, market_data).unwrap();
+        let expected = pricer.price().unwrap();
+        assert_eq!(response["price"].as_f64().unwrap(), expected);
+
+        // and so do the reports
+        let reports: Vec<BoxReport> = sdj::from_value(response["reports"].clone()).unwrap();
+        let baseline: Vec<BoxReport> = sdj::from_slice(sample_results_json()).unwrap();
+        assert_approx_eq_reports(&reports, &baseline, 1e-12, 1e-12, 1e-12).unwrap();
+    }
+
+    #[test]
+    fn facade_price_request_without_reports_or_fixings() {
+        let request = format!(r#"{{
+            "pricer_factory": {},
+            "instrument": {},
+            "market_data": {}
+        }}"#,
+            from_utf8(sample_pricer_factory_json()).unwrap(),
+            from_utf8(sample_forward_european_json()).unwrap(),
+            from_utf8(sample_market_data_json()).unwrap());
+        let response: sdj::Value = sdj::from_str(&price_request(&request).unwrap()).unwrap();
+        assert!(response["price"].as_f64().unwrap() > 0.0);
+        assert_eq!(response["reports"].as_array().unwrap().len(), 0);
+    }
+
+    #[test]
+    fn facade_price_request_errors() {
+        let request = sample_price_request("");
+
+        // truncated JSON
+        let error = price_request(&request[..request.len() / 2]).unwrap_err();
+        assert!(error.description().starts_with("Invalid pricing request: EOF"),
+            "{}", error.description());
+
+        // missing and unknown fields
+        let error = price_request(r#"{ "pricer_factory": { "SelfPricerFactory": {} } }"#)
+            .unwrap_err();
+        assert!(error.description().contains("missing field"), "{}", error.description());
+        let error = price_request(&request.replacen("\"reports\"", "\"risks\"", 1))
+            .unwrap_err();
+        assert!(error.description().contains("unknown field `risks`"), "{}", error.description());
+
+        // not an object at all, or an unknown type of instrument
+        assert!(price_request("42").is_err());
+        assert!(price_request(&request.replacen("ForwardStartingEuropean",
+            "Unknown", 1)).is_err());
+    }
+
     #[test]
     fn facade_read_currency() {
         let _ = currency_from_json(
@@ -0,0 +1,432 @@
+use std::sync::Arc;
+use instruments::Instrument;
+use instruments::RcInstrument;
+use instruments::DependencyContext;
+use instruments::SpotRequirement;
+use instruments::MonteCarloPriceable;
+use instruments::MonteCarloDependencies;
+use instruments::ObservationKind;
+use instruments::MonteCarloContext;
+use instruments::assets::Currency;
+use instruments::assets::RcCurrency;
+use instruments::bonds::ZeroCoupon;
+use data::fixings::FixingTable;
+use dates::Date;
+use dates::rules::RcDateRule;
+use dates::datetime::DateTime;
+use dates::datetime::DateDayFraction;
+use core::qm;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use core::dedup::InstanceId;
+use ndarray::Axis;
+use ndarray::Array2;
+use erased_serde as esd;
+use serde::Deserialize;
+
+/// An accumulator commits the holder to buy a quantity of the underlying at
+/// a fixed strike on each observation date, normally at a discount to the
+/// forward. If the underlying is below the strike on an observation date,
+/// the quantity is multiplied by the leverage, so the holder accumulates
+/// more just when it is losing money. If the underlying is at or above the
+/// knock-out barrier on an observation date, the accumulator knocks out,
+/// and nothing more is accumulated on that date or any later one.
+///
+/// Each observation accumulates the quantity times the difference between
+/// the underlying and the strike, and the total is paid in cash at the
+/// settlement date following the last observation date, even if the
+/// accumulator knocked out early. The payment may be negative.
+///
+/// Once some of the observation dates are in the past, fixing the
+/// accumulator removes them and records the amount accumulated so far. If
+/// it has knocked out, or all the dates have fixed, it becomes a cash
+/// payment.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct Accumulator {
+    id: String,
+    credit_id: String,
+    underlying: RcInstrument,
+    settlement: RcDateRule,
+    observation_dates: Vec<DateTime>,
+    strike: f64,
+    barrier: f64,
+    quantity: f64,
+    leverage: f64,
+    #[serde(default)]
+    past_accumulated: f64,
+
+    // fields precomputed for performance and simplicity
+    observation_times: Vec<DateDayFraction>,
+    pay_date: Date
+}
+
+impl TypeId for Accumulator {
+    fn get_type_id(&self) -> &'static str { "Accumulator" }
+}
+
+impl InstanceId for Accumulator {
+    fn id(&self) -> &str { &self.id }
+}
+
+impl Accumulator {
+    /// Creates an accumulator with the given observation dates, which must
+    /// be supplied in strictly increasing order. The quantity is accumulated
+    /// on each date, or the quantity times the leverage if the underlying is
+    /// below the strike. The barrier must be above the strike.
+    pub fn new(
+        id: &str,
+        credit_id: &str,
+        underlying: RcInstrument,
+        settlement: RcDateRule,
+        observation_dates: &[DateTime],
+        strike: f64,
+        barrier: f64,
+        quantity: f64,
+        leverage: f64)
+        -> Result<Accumulator, qm::Error> {
+
+        Accumulator::with_past_accumulation(id, credit_id, underlying,
+            settlement, observation_dates, strike, barrier, quantity, leverage,
+            0.0)
+    }
+
+    /// Creates an accumulator where some of the observations have already
+    /// happened without knocking out, accumulating the given amount.
+    fn with_past_accumulation(
+        id: &str,
+        credit_id: &str,
+        underlying: RcInstrument,
+        settlement: RcDateRule,
+        observation_dates: &[DateTime],
+        strike: f64,
+        barrier: f64,
+        quantity: f64,
+        leverage: f64,
+        past_accumulated: f64)
+        -> Result<Accumulator, qm::Error> {
+
+        if barrier <= strike {
+            return Err(qm::Error::new(
+                "The knock-out barrier of an accumulator must be above the strike"))
+        }
+        if leverage < 0.0 {
+            return Err(qm::Error::new(
+                "The leverage of an accumulator must not be negative"))
+        }
+
+        let last = observation_dates.last().ok_or_else(|| qm::Error::new(
+            "An accumulator must have at least one observation date"))?;
+        for pair in observation_dates.windows(2) {
+            if pair[0] >= pair[1] {
+                return Err(qm::Error::new(
+                    "Observation dates must be in strictly increasing order"))
+            }
+        }
+
+        let pay_date = settlement.apply(last.date());
+        let mut observation_times = Vec::with_capacity(observation_dates.len());
+        for date in observation_dates.iter() {
+            observation_times.push(underlying.time_to_day_fraction(*date)?);
+        }
+
+        Ok(Accumulator {
+            id: id.to_string(),
+            credit_id: credit_id.to_string(),
+            underlying: underlying,
+            settlement: settlement,
+            observation_dates: observation_dates.to_vec(),
+            strike: strike,
+            barrier: barrier,
+            quantity: quantity,
+            leverage: leverage,
+            past_accumulated: past_accumulated,
+            observation_times: observation_times,
+            pay_date: pay_date })
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<Instrument>, esd::Error> {
+        Ok(Qrc::new(Arc::new(Accumulator::deserialize(de)?)))
+    }
+
+    fn expiry(&self) -> DateTime {
+        *self.observation_dates.last().unwrap()
+    }
+
+    /// Walks through the values of the underlying on successive observation
+    /// dates, returning the total accumulated, including any accumulation
+    /// in the past, and whether the accumulator knocked out. Values after
+    /// the knock-out are ignored.
+    fn accumulate<I: Iterator<Item = f64>>(&self, spots: I) -> (f64, bool) {
+        let mut accumulated = self.past_accumulated;
+        for spot in spots {
+            if spot >= self.barrier {
+                return (accumulated, true)
+            }
+            let quantity = if spot < self.strike {
+                self.quantity * self.leverage
+            } else {
+                self.quantity
+            };
+            accumulated += quantity * (spot - self.strike);
+        }
+        (accumulated, false)
+    }
+
+    /// The cash payment at the pay date
+    fn payment(&self) -> RcInstrument {
+        // TODO this feels inefficient and ugly
+        let currency = RcCurrency::new(Arc::new(self.payoff_currency().clone()));
+        RcInstrument::new(Qrc::new(Arc::new(
+            ZeroCoupon::new(&format!("{}:Expiry", self.id),
+            &self.credit_id, currency, self.expiry(), self.pay_date,
+            self.settlement.clone()))))
+    }
+}
+
+impl Instrument for Accumulator {
+    fn payoff_currency(&self) -> &Currency { self.underlying.payoff_currency() }
+    fn credit_id(&self) -> &str { &self.credit_id }
+    fn settlement(&self) -> &RcDateRule { &self.settlement }
+    fn as_mc_priceable(&self) -> Option<&MonteCarloPriceable> { Some(self) }
+
+    fn dependencies(&self, context: &mut DependencyContext)
+        -> SpotRequirement {
+
+        // one fixing on each observation date
+        for date in self.observation_dates.iter() {
+            context.fixing(self.underlying.id(), *date);
+        }
+
+        let expiry_date = self.expiry().date();
+        context.yield_curve(self.credit_id(), self.pay_date);
+        context.forward_curve(&self.underlying, expiry_date);
+        context.vol_surface(&self.underlying, expiry_date);
+
+        SpotRequirement::NotRequired
+    }
+
+    fn fix(&self, fixing_table: &FixingTable)
+        -> Result<Option<Vec<(f64, RcInstrument)>>, qm::Error> {
+
+        // Collect the fixings that are known (the fetch errors if a fixing in
+        // the past is missing). These must all come before the dates that
+        // have not fixed.
+        let mut fixings = Vec::new();
+        let mut unfixed = false;
+        for date in self.observation_dates.iter() {
+            match fixing_table.get(self.underlying.id(), *date)? {
+                Some(_) if unfixed => return Err(qm::Error::new(&format!(
+                    "Accumulator {} has a fixing after an unfixed \
+                    observation date", self.id))),
+                Some(fixing) => fixings.push(fixing),
+                None => unfixed = true
+            }
+        }
+        if fixings.is_empty() {
+            return Ok(None)
+        }
+
+        // If it has knocked out or all dates have fixed, the accumulator
+        // becomes a cash payment
+        let n_fixed = fixings.len();
+        let (accumulated, knocked_out) = self.accumulate(fixings.into_iter());
+        if knocked_out || n_fixed == self.observation_dates.len() {
+            let mut decomp = Vec::new();
+            if accumulated != 0.0 {
+                decomp.push((accumulated, self.payment()));
+            }
+            return Ok(Some(decomp))
+        }
+
+        let fixed = Accumulator::with_past_accumulation(&self.id,
+            &self.credit_id, self.underlying.clone(), self.settlement.clone(),
+            &self.observation_dates[n_fixed..], self.strike, self.barrier,
+            self.quantity, self.leverage, accumulated)?;
+        Ok(Some(vec!((1.0, RcInstrument::new(Qrc::new(Arc::new(fixed)))))))
+    }
+}
+
+impl MonteCarloPriceable for Accumulator {
+    fn as_instrument(&self) -> &Instrument { self }
+
+    fn mc_dependencies(&self, _dates: &[DateDayFraction],
+        output: &mut MonteCarloDependencies) -> Result<(), qm::Error> {
+
+        // one observation for each accumulation date
+        for time in self.observation_times.iter() {
+            output.observation(&self.underlying, *time, ObservationKind::Fixing);
+        }
+
+        // a single cash payment at the pay date
+        output.flow(&self.payment());
+        Ok(())
+    }
+
+    fn start_date(&self) -> Option<DateDayFraction> {
+        None
+    }
+
+    fn mc_price(&self, context: &MonteCarloContext)
+        -> Result<f64, qm::Error> {
+
+        let ref paths = context.paths(&self.underlying)?;
+        let n_paths = paths.shape()[0];
+        let n_obs = paths.shape()[1];
+        assert_eq!(n_obs, self.observation_times.len());
+
+        let mut quantities = Array2::zeros((n_paths, 1));
+        {
+            let ref mut flow_column = quantities.subview_mut(Axis(1), 0);
+            for (path, flow) in paths.axis_iter(Axis(0)).zip(flow_column.iter_mut()) {
+                let (accumulated, _) = self.accumulate(path.iter().cloned());
+                *flow = accumulated;
+            }
+        }
+
+        context.evaluate_flows(quantities.view())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pricers::montecarlo::tests::black_diffusion_price_with_stderr;
+    use math::numerics::approx_eq;
+    use dates::datetime::TimeOfDay;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::marketdata::tests::sample_currency;
+    use risk::marketdata::tests::sample_settlement;
+    use risk::marketdata::tests::sample_equity;
+    use instruments::PricingContext;
+    use serde_json;
+
+    /// Observed at the close on the first business day of each month from
+    /// February to July 2017
+    fn sample_dates() -> Vec<DateTime> {
+        [(2, 1), (3, 1), (4, 3), (5, 2), (6, 1), (7, 3)].iter()
+            .map(|&(month, day)| DateTime::new(
+                Date::from_ymd(2017, month, day), TimeOfDay::Close))
+            .collect()
+    }
+
+    fn sample_accumulator(strike: f64, barrier: f64, leverage: f64) -> Accumulator {
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        let equity = RcInstrument::new(Qrc::new(Arc::new(sample_equity(currency, 2))));
+        Accumulator::new("SampleAccumulator", "OPT", equity, sample_settlement(2),
+            &sample_dates(), strike, barrier, 10.0, leverage).unwrap()
+    }
+
+    fn mc_price_with_stderr(accumulator: Accumulator) -> (f64, f64) {
+        let instrument = RcInstrument::new(Qrc::new(Arc::new(accumulator)));
+        black_diffusion_price_with_stderr(instrument, &sample_market_data(), 0.01, 10000)
+            .unwrap()
+    }
+
+    #[test]
+    fn accumulator_with_distant_barrier_is_strip_of_forwards() {
+
+        // With a barrier that is never reached and no leverage, each
+        // observation is a forward struck at the strike, all paid at the
+        // pay date of the last observation.
+        let accumulator = sample_accumulator(95.0, 1e6, 1.0);
+        let market_data = sample_market_data();
+        let val_date = DateTime::new(market_data.spot_date(), TimeOfDay::Open);
+        let df = accumulator.payment().as_priceable().unwrap()
+            .price(&market_data, val_date).unwrap();
+        let equity = accumulator.underlying.clone();
+        let mut expected = 0.0;
+        for date in sample_dates().iter() {
+            let forward = market_data.forward(&**equity, date.date()).unwrap();
+            expected += 10.0 * (forward - 95.0) * df;
+        }
+
+        let (price, stderr) = mc_price_with_stderr(accumulator);
+        assert!(stderr > 0.0);
+        assert!((price - expected).abs() < 4.0 * stderr,
+            "price={} expected={} stderr={}", price, expected, stderr);
+    }
+
+    #[test]
+    fn accumulator_barrier_and_leverage_lower_the_value() {
+        let (strip, _) = mc_price_with_stderr(sample_accumulator(95.0, 1e6, 1.0));
+        let (knock_out, _) = mc_price_with_stderr(sample_accumulator(95.0, 105.0, 1.0));
+        let (leveraged, _) = mc_price_with_stderr(sample_accumulator(95.0, 1e6, 2.0));
+        assert!(knock_out < strip, "knock_out={} strip={}", knock_out, strip);
+        assert!(leveraged < strip, "leveraged={} strip={}", leveraged, strip);
+    }
+
+    #[test]
+    fn accumulator_stops_accumulating_after_knock_out() {
+
+        // below the strike accumulates double, then the knock-out at 125
+        // stops any further accumulation, even back below the barrier
+        let accumulator = sample_accumulator(100.0, 120.0, 2.0);
+        let (accumulated, knocked_out) = accumulator.accumulate(
+            [95.0, 105.0, 125.0, 90.0].iter().cloned());
+        assert!(knocked_out);
+        assert_approx(accumulated, 10.0 * (2.0 * -5.0 + 5.0), 1e-12);
+
+        let (accumulated, knocked_out) = accumulator.accumulate(
+            [95.0, 105.0, 119.0].iter().cloned());
+        assert!(!knocked_out);
+        assert_approx(accumulated, 10.0 * (-10.0 + 5.0 + 19.0), 1e-12);
+    }
+
+    #[test]
+    fn accumulator_fix_before_and_after_knock_out() {
+        let dates = sample_dates();
+
+        // two fixings without knocking out leave an accumulator with the
+        // remaining dates and the amount accumulated so far
+        let today = Date::from_ymd(2017, 03, 02);
+        let fixings = [(dates[0], 95.0), (dates[1], 105.0)];
+        let fixing_table = FixingTable::from_fixings(today, &[("BP.L", &fixings)]).unwrap();
+        let decomp = sample_accumulator(100.0, 120.0, 2.0).fix(&fixing_table)
+            .unwrap().unwrap();
+        assert_eq!(decomp.len(), 1);
+        assert_approx(decomp[0].0, 1.0, 1e-12);
+        let value = serde_json::to_value(&decomp[0].1).unwrap();
+        let fixed = &value["Accumulator"];
+        assert_eq!(fixed["observation_dates"].as_array().unwrap().len(), 4);
+        assert_eq!(fixed["past_accumulated"], -50.0);
+
+        // knocking out on the third date turns it into a cash payment of
+        // what had accumulated before
+        let today = Date::from_ymd(2017, 04, 04);
+        let fixings = [(dates[0], 95.0), (dates[1], 105.0), (dates[2], 125.0)];
+        let fixing_table = FixingTable::from_fixings(today, &[("BP.L", &fixings)]).unwrap();
+        let decomp = sample_accumulator(100.0, 120.0, 2.0).fix(&fixing_table)
+            .unwrap().unwrap();
+        assert_eq!(decomp.len(), 1);
+        assert_approx(decomp[0].0, -50.0, 1e-12);
+        assert_eq!(decomp[0].1.id(), "SampleAccumulator:Expiry");
+    }
+
+    #[test]
+    fn accumulator_rejects_bad_inputs() {
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        let equity = RcInstrument::new(Qrc::new(Arc::new(sample_equity(currency, 2))));
+        let dates = sample_dates();
+        assert!(Accumulator::new("Bad", "OPT", equity.clone(), sample_settlement(2),
+            &dates, 100.0, 90.0, 10.0, 2.0).is_err());
+        assert!(Accumulator::new("Bad", "OPT", equity.clone(), sample_settlement(2),
+            &[], 100.0, 120.0, 10.0, 2.0).is_err());
+        let reversed: Vec<DateTime> = dates.iter().rev().cloned().collect();
+        assert!(Accumulator::new("Bad", "OPT", equity, sample_settlement(2),
+            &reversed, 100.0, 120.0, 10.0, 2.0).is_err());
+    }
+
+    #[test]
+    fn accumulator_serde() {
+        let accumulator = sample_accumulator(95.0, 110.0, 2.0);
+        let serialized = serde_json::to_string(&accumulator).unwrap();
+        let deserialized: Accumulator = serde_json::from_str(&serialized).unwrap();
+        let reserialized = serde_json::to_string(&deserialized).unwrap();
+        assert_eq!(serialized, reserialized);
+    }
+
+    fn assert_approx(value: f64, expected: f64, tolerance: f64) {
+        assert!(approx_eq(value, expected, tolerance),
+            "value={} expected={}", value, expected);
+    }
+}
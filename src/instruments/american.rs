@@ -0,0 +1,471 @@
+use std::sync::Arc;
+use instruments::Instrument;
+use instruments::RcInstrument;
+use instruments::DependencyContext;
+use instruments::SpotRequirement;
+use instruments::MonteCarloPriceable;
+use instruments::MonteCarloDependencies;
+use instruments::ObservationKind;
+use instruments::MonteCarloContext;
+use instruments::options::PutOrCall;
+use instruments::assets::Currency;
+use instruments::assets::RcCurrency;
+use instruments::bonds::ZeroCoupon;
+use math::regression::polynomial_fit;
+use math::regression::polynomial_value;
+use data::fixings::FixingTable;
+use dates::Date;
+use dates::rules::RcDateRule;
+use dates::datetime::DateTime;
+use dates::datetime::DateDayFraction;
+use dates::datetime::TimeOfDay;
+use core::qm;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use core::dedup::InstanceId;
+use ndarray::Array2;
+use erased_serde as esd;
+use serde::Deserialize;
+
+/// An American option may be exercised at any time up to and including its
+/// expiry. On exercise, a call pays (S-K) and a put pays (K-S), settled in
+/// cash at the settlement date following the exercise.
+///
+/// Continuous exercise is approximated by a set of evenly spaced exercise
+/// dates. The option is priced by Monte-Carlo using the Longstaff-Schwartz
+/// algorithm: working backwards from expiry, the continuation value is
+/// estimated on each exercise date by a least-squares regression of the
+/// realised future cashflows against a polynomial in the spot, using only
+/// the paths that are in the money.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct AmericanOption {
+    id: String,
+    credit_id: String,
+    underlying: RcInstrument,
+    settlement: RcDateRule,
+    exercise_dates: Vec<DateTime>,
+    strike: f64,
+    put_or_call: PutOrCall,
+    basis_degree: usize,
+
+    // fields precomputed for performance and simplicity
+    exercise_times: Vec<DateDayFraction>
+}
+
+impl TypeId for AmericanOption {
+    fn get_type_id(&self) -> &'static str { "AmericanOption" }
+}
+
+impl InstanceId for AmericanOption {
+    fn id(&self) -> &str { &self.id }
+}
+
+impl AmericanOption {
+    /// Creates an American option, exercisable between the first exercise
+    /// date and the expiry. Exercise is approximated by exercise_steps
+    /// evenly spaced dates, the last of which is the expiry. All exercise
+    /// dates are at the same time of day as the expiry. The basis_degree is
+    /// the degree of the polynomial used in the Longstaff-Schwartz regression.
+    pub fn new(
+        id: &str,
+        credit_id: &str,
+        underlying: RcInstrument,
+        settlement: RcDateRule,
+        first_exercise: Date,
+        expiry: DateTime,
+        exercise_steps: usize,
+        strike: f64,
+        put_or_call: PutOrCall,
+        basis_degree: usize)
+        -> Result<AmericanOption, qm::Error> {
+
+        if exercise_steps == 0 {
+            return Err(qm::Error::new("There must be at least one exercise step"))
+        }
+        let days = expiry.date() - first_exercise;
+        if days < 0 {
+            return Err(qm::Error::new("First exercise date must not be after expiry"))
+        }
+
+        // roll out the exercise dates, ignoring duplicates if there are more
+        // steps than days
+        let mut exercise_dates: Vec<DateTime> = Vec::with_capacity(exercise_steps);
+        for step in 1..(exercise_steps + 1) {
+            let offset = (days as f64 * step as f64 / exercise_steps as f64).round() as i32;
+            let date = DateTime::new(first_exercise + offset, expiry.time_of_day());
+            if exercise_dates.last() != Some(&date) {
+                exercise_dates.push(date);
+            }
+        }
+
+        AmericanOption::from_dates(id, credit_id, underlying, settlement,
+            exercise_dates, strike, put_or_call, basis_degree)
+    }
+
+    fn from_dates(
+        id: &str,
+        credit_id: &str,
+        underlying: RcInstrument,
+        settlement: RcDateRule,
+        exercise_dates: Vec<DateTime>,
+        strike: f64,
+        put_or_call: PutOrCall,
+        basis_degree: usize)
+        -> Result<AmericanOption, qm::Error> {
+
+        if strike < 0.0 {
+            return Err(qm::Error::new("Strike must be greater or equal to zero"))
+        }
+
+        let mut exercise_times = Vec::with_capacity(exercise_dates.len());
+        for date in exercise_dates.iter() {
+            exercise_times.push(underlying.time_to_day_fraction(*date)?);
+        }
+
+        Ok(AmericanOption {
+            id: id.to_string(),
+            credit_id: credit_id.to_string(),
+            underlying: underlying,
+            settlement: settlement,
+            exercise_dates: exercise_dates,
+            strike: strike,
+            put_or_call: put_or_call,
+            basis_degree: basis_degree,
+            exercise_times: exercise_times })
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<Instrument>, esd::Error> {
+        Ok(Qrc::new(Arc::new(AmericanOption::deserialize(de)?)))
+    }
+
+    fn expiry(&self) -> DateTime {
+        *self.exercise_dates.last().unwrap()
+    }
+
+    fn intrinsic(&self, spot: f64) -> f64 {
+        match self.put_or_call {
+            PutOrCall::Call => (spot - self.strike).max(0.0),
+            PutOrCall::Put => (self.strike - spot).max(0.0) }
+    }
+
+    /// The cash payment resulting from exercise on the given date
+    fn payment(&self, exercise: DateTime) -> RcInstrument {
+        // TODO this feels inefficient and ugly
+        let currency = RcCurrency::new(Arc::new(self.payoff_currency().clone()));
+        RcInstrument::new(Qrc::new(Arc::new(ZeroCoupon::new(
+            &format!("{}:Exercise:{}", self.id, exercise),
+            &self.credit_id, currency, exercise,
+            self.settlement.apply(exercise.date()),
+            self.settlement.clone()))))
+    }
+}
+
+impl Instrument for AmericanOption {
+    fn payoff_currency(&self) -> &Currency { self.underlying.payoff_currency() }
+    fn credit_id(&self) -> &str { &self.credit_id }
+    fn settlement(&self) -> &RcDateRule { &self.settlement }
+    fn as_mc_priceable(&self) -> Option<&MonteCarloPriceable> { Some(self) }
+
+    fn dependencies(&self, context: &mut DependencyContext)
+        -> SpotRequirement {
+
+        // the only fixing we need is at expiry. Earlier exercise dates that
+        // have passed are assumed not to have been exercised.
+        let expiry = self.expiry();
+        context.fixing(self.underlying.id(), expiry);
+
+        let expiry_date = expiry.date();
+        context.yield_curve(self.credit_id(), self.settlement.apply(expiry_date));
+        context.forward_curve(&self.underlying, expiry_date);
+        context.vol_surface(&self.underlying, expiry_date);
+
+        SpotRequirement::NotRequired
+    }
+
+    fn fix(&self, fixing_table: &FixingTable)
+        -> Result<Option<Vec<(f64, RcInstrument)>>, qm::Error> {
+
+        // If the expiry has fixed, the option turns into a cash payment, or
+        // nothing at all if it expired out of the money.
+        let expiry = self.expiry();
+        if let Some(spot) = fixing_table.get(self.underlying.id(), expiry)? {
+            let mut decomp = Vec::new();
+            let payment = self.intrinsic(spot);
+            if payment > 0.0 {
+                decomp.push((payment, self.payment(expiry)));
+            }
+            return Ok(Some(decomp))
+        }
+
+        // Otherwise, drop any exercise dates that are in the past. (If the
+        // option had been exercised, it would no longer be in the book.)
+        let known_until = fixing_table.fixings_known_until();
+        let id = self.underlying.id();
+        let remaining: Vec<DateTime> = self.exercise_dates.iter()
+            .filter(|d| d.date() >= known_until
+                && fixing_table.get_optional(id, **d).is_none())
+            .cloned().collect();
+        if remaining.len() == self.exercise_dates.len() {
+            return Ok(None)
+        }
+
+        let unexercised = AmericanOption::from_dates(&self.id, &self.credit_id,
+            self.underlying.clone(), self.settlement.clone(), remaining,
+            self.strike, self.put_or_call, self.basis_degree)?;
+        Ok(Some(vec!((1.0, RcInstrument::new(Qrc::new(Arc::new(unexercised)))))))
+    }
+}
+
+impl MonteCarloPriceable for AmericanOption {
+    fn as_instrument(&self) -> &Instrument { self }
+
+    fn mc_dependencies(&self, _dates: &[DateDayFraction],
+        output: &mut MonteCarloDependencies) -> Result<(), qm::Error> {
+
+        // an observation and a potential payment on each exercise date,
+        // in date order
+        for (date, time) in self.exercise_dates.iter().zip(self.exercise_times.iter()) {
+            output.observation(&self.underlying, *time, ObservationKind::Exercise);
+            output.flow(&self.payment(*date));
+        }
+        Ok(())
+    }
+
+    fn start_date(&self) -> Option<DateDayFraction> {
+        None
+    }
+
+    fn mc_price(&self, context: &MonteCarloContext)
+        -> Result<f64, qm::Error> {
+
+        let payments: Vec<RcInstrument> = self.exercise_dates.iter()
+            .map(|date| self.payment(*date)).collect();
+        longstaff_schwartz(context, &self.underlying, &payments,
+            &|spot| self.intrinsic(spot), self.strike, self.basis_degree)
+    }
+}
+
+/// Prices an option that may be exercised on a discrete set of dates, by
+/// Monte-Carlo using the Longstaff-Schwartz algorithm. The paths of the
+/// underlying must have one observation for each exercise date, in date
+/// order, and the payments are the unit cash flows resulting from exercise
+/// on each date, which must also have been registered as flows. The
+/// intrinsic function gives the amount paid on exercise for a given spot,
+/// and the spot is divided by the scale, normally the strike, before it is
+/// used in the regression.
+pub fn longstaff_schwartz(context: &MonteCarloContext, underlying: &RcInstrument,
+    payments: &[RcInstrument], intrinsic: &Fn(f64) -> f64, scale: f64,
+    basis_degree: usize) -> Result<f64, qm::Error> {
+
+    let ref paths = context.paths(underlying)?;
+    let n_paths = paths.shape()[0];
+    let n_exercises = paths.shape()[1];
+    assert_eq!(n_exercises, payments.len());
+
+    // Discount factors for the payment on each exercise date. We work
+    // with present values throughout, so there is no need to discount
+    // from one exercise date to the next.
+    let pricing_context = context.pricing_context();
+    let val_date = DateTime::new(pricing_context.spot_date(), TimeOfDay::Open);
+    let mut discounts = Vec::with_capacity(n_exercises);
+    for payment in payments.iter() {
+        let priceable = payment.as_priceable().ok_or_else(|| qm::Error::new(
+            "Exercise payment must be priceable"))?;
+        discounts.push(priceable.price(pricing_context, val_date)?);
+    }
+
+    // At expiry, every path in the money is exercised
+    let last = n_exercises - 1;
+    let mut exercise_index = vec![last; n_paths];
+    let mut cashflows: Vec<f64> = (0..n_paths).map(|p|
+        discounts[last] * intrinsic(paths[[p, last]])).collect();
+
+    // Work backwards through the earlier exercise dates, comparing the
+    // value of exercise with the estimated continuation value
+    let mut x = Vec::with_capacity(n_paths);
+    let mut y = Vec::with_capacity(n_paths);
+    let mut in_the_money = Vec::with_capacity(n_paths);
+    for i in (0..last).rev() {
+        x.clear();
+        y.clear();
+        in_the_money.clear();
+        for p in 0..n_paths {
+            let spot = paths[[p, i]];
+            if intrinsic(spot) > 0.0 {
+                in_the_money.push(p);
+                x.push(spot / scale);
+                y.push(cashflows[p]);
+            }
+        }
+
+        // If there are too few paths in the money to regress, we never
+        // exercise here, so the continuation value stands.
+        if let Some(beta) = polynomial_fit(&x, &y, basis_degree) {
+            for (&p, &xp) in in_the_money.iter().zip(x.iter()) {
+                let exercise = discounts[i] * intrinsic(paths[[p, i]]);
+                if exercise > polynomial_value(&beta, xp) {
+                    cashflows[p] = exercise;
+                    exercise_index[p] = i;
+                }
+            }
+        }
+    }
+
+    // Each path pays its intrinsic value on its exercise date
+    let mut quantities = Array2::zeros((n_paths, n_exercises));
+    for (p, &i) in exercise_index.iter().enumerate() {
+        quantities[[p, i]] = intrinsic(paths[[p, i]]);
+    }
+
+    context.evaluate_flows(quantities.view())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use models::random::BASE_SEED;
+    use std::collections::HashMap;
+    use math::optionpricing::Black76;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::marketdata::tests::sample_currency;
+    use risk::marketdata::tests::sample_settlement;
+    use risk::marketdata::tests::sample_equity;
+    use instruments::PricingContext;
+    use models::RcMonteCarloModelFactory;
+    use models::VarianceReduction;
+    use models::blackdiffusion::BlackDiffusionFactory;
+    use pricers::montecarlo::MonteCarloPricer;
+    use risk::Pricer;
+    use serde_json;
+
+    fn sample_american_put(exercise_steps: usize) -> AmericanOption {
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        let equity = RcInstrument::new(Qrc::new(Arc::new(sample_equity(currency, 2))));
+        let expiry = DateTime::new(Date::from_ymd(2018, 06, 01), TimeOfDay::Close);
+        AmericanOption::new("SampleAmerican", "OPT", equity, sample_settlement(2),
+            Date::from_ymd(2017, 01, 02), expiry, exercise_steps, 100.0,
+            PutOrCall::Put, 3).unwrap()
+    }
+
+    /// Binomial tree in the same dynamics as BlackDiffusion: the underlying
+    /// is the forward times a driftless log-normal, stepped in variance,
+    /// and we may exercise only on the exercise dates.
+    fn binomial_price(american: &AmericanOption, context: &PricingContext,
+        n_steps: usize) -> f64 {
+
+        let expiry = american.expiry().date();
+        let fwd = context.forward_curve(&*american.underlying, expiry).unwrap();
+        let vol = context.vol_surface(&*american.underlying, expiry,
+            &|| Ok(fwd.clone())).unwrap();
+        let val_date = DateTime::new(context.spot_date(), TimeOfDay::Open);
+
+        // the forward, discount and variance at each exercise
+        let mut exercises = Vec::new();
+        for (date, time) in american.exercise_dates.iter().zip(american.exercise_times.iter()) {
+            let forward = fwd.forward(date.date()).unwrap();
+            let variance = vol.variance(*time, forward).unwrap();
+            let payment = american.payment(*date);
+            let df = payment.as_priceable().unwrap().price(context, val_date).unwrap();
+            exercises.push((forward, variance, df));
+        }
+        let total_variance = exercises.last().unwrap().1;
+        let dv = total_variance / n_steps as f64;
+        let u = dv.sqrt().exp();
+        let d = 1.0 / u;
+        let p = (1.0 - d) / (u - d);
+
+        // map each step to the exercise on it, if any
+        let mut exercise_at: HashMap<usize, usize> = HashMap::new();
+        for (i, &(_, variance, _)) in exercises.iter().enumerate() {
+            exercise_at.insert((variance / dv).round() as usize, i);
+        }
+
+        // roll back from expiry
+        let (forward, _, df) = exercises[exercises.len() - 1];
+        let mut values: Vec<f64> = (0..n_steps + 1).map(|j| {
+            let x = u.powi(j as i32) * d.powi((n_steps - j) as i32);
+            df * american.intrinsic(forward * x) }).collect();
+        for step in (0..n_steps).rev() {
+            for j in 0..step + 1 {
+                values[j] = p * values[j + 1] + (1.0 - p) * values[j];
+            }
+            if let Some(&i) = exercise_at.get(&step) {
+                let (forward, _, df) = exercises[i];
+                for j in 0..step + 1 {
+                    let x = u.powi(j as i32) * d.powi((step - j) as i32);
+                    values[j] = values[j].max(df * american.intrinsic(forward * x));
+                }
+            }
+            values.truncate(step + 1);
+        }
+        values[0]
+    }
+
+    #[test]
+    fn american_put_against_binomial() {
+
+        let market_data = sample_market_data();
+        let american = sample_american_put(24);
+        let baseline = binomial_price(&american, &market_data, 2000);
+
+        // the baseline should be worth more than the European put
+        let expiry = american.expiry().date();
+        let fwd = market_data.forward_curve(&*american.underlying, expiry).unwrap();
+        let vol = market_data.vol_surface(&*american.underlying, expiry,
+            &|| Ok(fwd.clone())).unwrap();
+        let forward = fwd.forward(expiry).unwrap();
+        let variance = vol.variance(*american.exercise_times.last().unwrap(), forward).unwrap();
+        let val_date = DateTime::new(market_data.spot_date(), TimeOfDay::Open);
+        let df = american.payment(american.expiry()).as_priceable().unwrap()
+            .price(&market_data, val_date).unwrap();
+        let european = Black76::new().unwrap().put_price(df, forward, 100.0, variance.sqrt());
+        assert!(baseline > european + 0.1, "baseline={} european={}", baseline, european);
+
+        let model_factory = RcMonteCarloModelFactory::new(Arc::new(BlackDiffusionFactory::new(
+            20, 0.01, 50000, VarianceReduction::Antithetic, Some(BASE_SEED)).unwrap()));
+        let instrument = RcInstrument::new(Qrc::new(Arc::new(american)));
+        let pricer = MonteCarloPricer::new(vec!((1.0, instrument)),
+            model_factory, &market_data).unwrap();
+        let price = pricer.price().unwrap();
+
+        assert!((price - baseline).abs() < 0.2, "price={} baseline={}", price, baseline);
+    }
+
+    #[test]
+    fn american_deep_out_of_the_money_does_not_panic() {
+
+        // a put struck at zero is never in the money, so the regression
+        // never has any paths to work with
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        let equity = RcInstrument::new(Qrc::new(Arc::new(sample_equity(currency, 2))));
+        let expiry = DateTime::new(Date::from_ymd(2018, 06, 01), TimeOfDay::Close);
+        let american = AmericanOption::new("Worthless", "OPT", equity, sample_settlement(2),
+            Date::from_ymd(2017, 01, 02), expiry, 12, 0.0, PutOrCall::Put, 2).unwrap();
+
+        let market_data = sample_market_data();
+        let model_factory = RcMonteCarloModelFactory::new(Arc::new(BlackDiffusionFactory::new(
+            20, 0.01, 1000, VarianceReduction::None, Some(BASE_SEED)).unwrap()));
+        let instrument = RcInstrument::new(Qrc::new(Arc::new(american)));
+        let pricer = MonteCarloPricer::new(vec!((1.0, instrument)),
+            model_factory, &market_data).unwrap();
+        assert_eq!(pricer.price().unwrap(), 0.0);
+    }
+
+    #[test]
+    fn american_fix_drops_past_exercise_dates() {
+        let american = sample_american_put(12);
+        let today = Date::from_ymd(2017, 06, 15);
+        let fixings = FixingTable::new(today);
+        let fixed = american.fix(&fixings).unwrap().unwrap();
+        assert_eq!(fixed.len(), 1);
+        let (weight, ref remaining) = fixed[0];
+        assert_eq!(weight, 1.0);
+        let serialized = serde_json::to_string(&remaining).unwrap();
+        let unexercised: HashMap<String, AmericanOption> =
+            serde_json::from_str(&serialized).unwrap();
+        let dates = &unexercised["AmericanOption"].exercise_dates;
+        assert!(dates.iter().all(|d| d.date() >= today));
+        assert_eq!(*dates.last().unwrap(), american.expiry());
+        assert!(dates.len() < american.exercise_dates.len());
+    }
+}
@@ -0,0 +1,600 @@
+use std::sync::Arc;
+use instruments::Instrument;
+use instruments::RcInstrument;
+use instruments::PricingContext;
+use instruments::DependencyContext;
+use instruments::SpotRequirement;
+use instruments::MonteCarloPriceable;
+use instruments::MonteCarloDependencies;
+use instruments::ObservationKind;
+use instruments::MonteCarloContext;
+use instruments::ControlVariate;
+use instruments::options::PutOrCall;
+use instruments::assets::Currency;
+use instruments::assets::RcCurrency;
+use instruments::bonds::ZeroCoupon;
+use math::optionpricing::Black76;
+use data::fixings::FixingTable;
+use dates::Date;
+use dates::rules::RcDateRule;
+use dates::datetime::DateTime;
+use dates::datetime::DateDayFraction;
+use dates::datetime::TimeOfDay;
+use core::qm;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use core::dedup::InstanceId;
+use ndarray::Axis;
+use ndarray::Array2;
+use erased_serde as esd;
+use serde::Deserialize;
+
+/// An Asian option pays off on the arithmetic average of the underlying,
+/// observed on a set of averaging dates. A call pays (A-K).max(0) and a put
+/// pays (K-A).max(0), where A is the average and K the strike. The payment
+/// is made at the settlement date following the last averaging date.
+///
+/// There is no analytic formula for the arithmetic average, so Asian options
+/// are priced by Monte-Carlo. However, the geometric average of a log-normal
+/// underlying is itself log-normal, so the geometric-average option is
+/// supplied as a control variate.
+///
+/// Once some of the averaging dates are in the past, fixing the option
+/// removes them from the averaging dates and records their values in the
+/// past fixings, which are included in the average.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct AsianOption {
+    id: String,
+    credit_id: String,
+    underlying: RcInstrument,
+    settlement: RcDateRule,
+    averaging_dates: Vec<DateTime>,
+    #[serde(default)]
+    past_fixings: Vec<f64>,
+    strike: f64,
+    put_or_call: PutOrCall,
+
+    // fields precomputed for performance and simplicity
+    averaging_times: Vec<DateDayFraction>,
+    pay_date: Date
+}
+
+impl TypeId for AsianOption {
+    fn get_type_id(&self) -> &'static str { "AsianOption" }
+}
+
+impl InstanceId for AsianOption {
+    fn id(&self) -> &str { &self.id }
+}
+
+impl AsianOption {
+    /// Creates an Asian option. The averaging dates must be supplied in
+    /// strictly increasing order, and there must be at least one of them.
+    pub fn new(
+        id: &str,
+        credit_id: &str,
+        underlying: RcInstrument,
+        settlement: RcDateRule,
+        averaging_dates: &[DateTime],
+        strike: f64,
+        put_or_call: PutOrCall)
+        -> Result<AsianOption, qm::Error> {
+
+        AsianOption::with_past_fixings(id, credit_id, underlying, settlement,
+            averaging_dates, &[], strike, put_or_call)
+    }
+
+    /// Creates an Asian option where some of the averaging has already
+    /// happened. The past fixings are included in the average, along with
+    /// the observations on the remaining averaging dates.
+    fn with_past_fixings(
+        id: &str,
+        credit_id: &str,
+        underlying: RcInstrument,
+        settlement: RcDateRule,
+        averaging_dates: &[DateTime],
+        past_fixings: &[f64],
+        strike: f64,
+        put_or_call: PutOrCall)
+        -> Result<AsianOption, qm::Error> {
+
+        if strike < 0.0 {
+            return Err(qm::Error::new("Strike must be greater or equal to zero"))
+        }
+
+        let last = averaging_dates.last().ok_or_else(|| qm::Error::new(
+            "An Asian option must have at least one averaging date"))?;
+        for pair in averaging_dates.windows(2) {
+            if pair[0] >= pair[1] {
+                return Err(qm::Error::new(
+                    "Averaging dates must be in strictly increasing order"))
+            }
+        }
+
+        let pay_date = settlement.apply(last.date());
+        let mut averaging_times = Vec::with_capacity(averaging_dates.len());
+        for date in averaging_dates.iter() {
+            averaging_times.push(underlying.time_to_day_fraction(*date)?);
+        }
+
+        Ok(AsianOption {
+            id: id.to_string(),
+            credit_id: credit_id.to_string(),
+            underlying: underlying,
+            settlement: settlement,
+            averaging_dates: averaging_dates.to_vec(),
+            past_fixings: past_fixings.to_vec(),
+            strike: strike,
+            put_or_call: put_or_call,
+            averaging_times: averaging_times,
+            pay_date: pay_date })
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<Instrument>, esd::Error> {
+        Ok(Qrc::new(Arc::new(AsianOption::deserialize(de)?)))
+    }
+
+    fn expiry(&self) -> DateTime {
+        *self.averaging_dates.last().unwrap()
+    }
+
+    /// The total number of values in the average, past and future
+    fn n_averaging(&self) -> f64 {
+        (self.past_fixings.len() + self.averaging_dates.len()) as f64
+    }
+
+    fn sign(&self) -> f64 {
+        match self.put_or_call {
+            PutOrCall::Call => 1.0,
+            PutOrCall::Put => -1.0 }
+    }
+
+    /// The cash payment at the pay date. Both the Monte-Carlo valuation and
+    /// the control variate pay a quantity of this.
+    fn payment(&self) -> RcInstrument {
+        // TODO this feels inefficient and ugly
+        let currency = RcCurrency::new(Arc::new(self.payoff_currency().clone()));
+        RcInstrument::new(Qrc::new(Arc::new(
+            ZeroCoupon::new(&format!("{}:Expiry", self.id),
+            &self.credit_id, currency, self.expiry(), self.pay_date,
+            self.settlement.clone()))))
+    }
+
+    /// Displacements of the underlying diffusion on each averaging date.
+    /// The control variate averages the undisplaced part of the underlying,
+    /// so that it remains log-normal.
+    fn displacements(&self, context: &PricingContext)
+        -> Result<Vec<f64>, qm::Error> {
+
+        let hwm = self.expiry().date();
+        let fwd = context.forward_curve(&*self.underlying, hwm)?;
+        let vol = context.vol_surface(&*self.underlying, hwm, &|| Ok(fwd.clone()))?;
+
+        let mut displacements = Vec::with_capacity(self.averaging_times.len());
+        for time in self.averaging_times.iter() {
+            displacements.push(vol.displacement(time.date())?);
+        }
+        Ok(displacements)
+    }
+}
+
+impl Instrument for AsianOption {
+    fn payoff_currency(&self) -> &Currency { self.underlying.payoff_currency() }
+    fn credit_id(&self) -> &str { &self.credit_id }
+    fn settlement(&self) -> &RcDateRule { &self.settlement }
+    fn as_mc_priceable(&self) -> Option<&MonteCarloPriceable> { Some(self) }
+    fn as_control_variate(&self) -> Option<&ControlVariate> { Some(self) }
+
+    fn dependencies(&self, context: &mut DependencyContext)
+        -> SpotRequirement {
+
+        // one fixing on each averaging date
+        for date in self.averaging_dates.iter() {
+            context.fixing(self.underlying.id(), *date);
+        }
+
+        // discounting, plus the forwards and vols we need for the control
+        let expiry_date = self.expiry().date();
+        context.yield_curve(self.credit_id(), self.pay_date);
+        context.forward_curve(&self.underlying, expiry_date);
+        context.vol_surface(&self.underlying, expiry_date);
+
+        SpotRequirement::NotRequired
+    }
+
+    fn fix(&self, fixing_table: &FixingTable)
+        -> Result<Option<Vec<(f64, RcInstrument)>>, qm::Error> {
+
+        // Find the averaging dates that have already fixed (the fetch errors
+        // if a fixing in the past is missing). These must all come before
+        // the dates that have not fixed.
+        let mut past_fixings = self.past_fixings.clone();
+        let mut n_fixed = 0;
+        let mut unfixed = false;
+        for date in self.averaging_dates.iter() {
+            match fixing_table.get(self.underlying.id(), *date)? {
+                Some(_) if unfixed => return Err(qm::Error::new(&format!(
+                    "Asian option {} has a fixing after an unfixed \
+                    averaging date", self.id))),
+                Some(fixing) => {
+                    past_fixings.push(fixing);
+                    n_fixed += 1;
+                },
+                None => unfixed = true
+            }
+        }
+        if n_fixed == 0 {
+            return Ok(None)
+        }
+
+        // If all dates have fixed, the option becomes a cash payment
+        if n_fixed == self.averaging_dates.len() {
+            let average = past_fixings.iter().sum::<f64>() / past_fixings.len() as f64;
+            let payment = (self.sign() * (average - self.strike)).max(0.0);
+            let mut decomp = Vec::new();
+            if payment > 0.0 {
+                decomp.push((payment, self.payment()));
+            }
+            return Ok(Some(decomp))
+        }
+
+        let fixed = AsianOption::with_past_fixings(&self.id, &self.credit_id,
+            self.underlying.clone(), self.settlement.clone(),
+            &self.averaging_dates[n_fixed..], &past_fixings, self.strike,
+            self.put_or_call)?;
+        Ok(Some(vec!((1.0, RcInstrument::new(Qrc::new(Arc::new(fixed)))))))
+    }
+}
+
+impl MonteCarloPriceable for AsianOption {
+    fn as_instrument(&self) -> &Instrument { self }
+
+    fn mc_dependencies(&self, _dates: &[DateDayFraction],
+        output: &mut MonteCarloDependencies) -> Result<(), qm::Error> {
+
+        // one observation for each averaging date
+        for time in self.averaging_times.iter() {
+            output.observation(&self.underlying, *time, ObservationKind::Averaging);
+        }
+
+        // a single cash payment at the pay date
+        output.flow(&self.payment());
+        Ok(())
+    }
+
+    fn start_date(&self) -> Option<DateDayFraction> {
+        None
+    }
+
+    fn mc_price(&self, context: &MonteCarloContext)
+        -> Result<f64, qm::Error> {
+
+        let ref paths = context.paths(&self.underlying)?;
+        let n_paths = paths.shape()[0];
+        let n_obs = paths.shape()[1];
+        assert_eq!(n_obs, self.averaging_times.len());
+
+        let strike = self.strike;
+        let sign = self.sign();
+        let past_sum: f64 = self.past_fixings.iter().sum();
+        let n = self.n_averaging();
+        let mut quantities = Array2::zeros((n_paths, 1));
+        {
+            let ref mut flow_column = quantities.subview_mut(Axis(1), 0);
+            for (path, flow) in paths.axis_iter(Axis(0)).zip(flow_column.iter_mut()) {
+                let average = (past_sum + path.scalar_sum()) / n;
+                *flow = (sign * (average - strike)).max(0.0);
+            }
+        }
+
+        context.evaluate_flows(quantities.view())
+    }
+}
+
+impl ControlVariate for AsianOption {
+
+    /// The control is an option on the geometric average of the underlying,
+    /// less its displacement on each date. The strike is reduced by the
+    /// average displacement. With no displacement, this is just the standard
+    /// geometric-average Asian option. Past fixings enter the geometric
+    /// average undisplaced, as constants.
+    ///
+    /// The log of each undisplaced observation has variance v_i, and the
+    /// covariance of two observations is the smaller of their variances, so
+    /// the log of the geometric average is normal with a variance that is the
+    /// mean of min(v_i, v_j) over all pairs.
+    fn control_value(&self, context: &PricingContext) -> Result<f64, qm::Error> {
+
+        let hwm = self.expiry().date();
+        let fwd = context.forward_curve(&*self.underlying, hwm)?;
+        let vol = context.vol_surface(&*self.underlying, hwm, &|| Ok(fwd.clone()))?;
+
+        let n = self.n_averaging();
+        let mut log_mean = 0.0;
+        for fixing in self.past_fixings.iter() {
+            if *fixing <= 0.0 {
+                return Err(qm::Error::new("Non-positive past fixing"))
+            }
+            log_mean += fixing.ln() / n;
+        }
+        let mut mean_displacement = 0.0;
+        let mut variances = Vec::with_capacity(self.averaging_times.len());
+        for time in self.averaging_times.iter() {
+            let forward = fwd.forward(time.date())?;
+            let variance = vol.variance(*time, forward)?;
+            let displacement = vol.displacement(time.date())?;
+            let undisplaced = forward - displacement;
+            if undisplaced <= 0.0 {
+                return Err(qm::Error::new("Negative forward"))
+            }
+            log_mean += (undisplaced.ln() - 0.5 * variance) / n;
+            mean_displacement += displacement / n;
+            variances.push(variance);
+        }
+
+        let mut variance = 0.0;
+        for vi in variances.iter() {
+            for vj in variances.iter() {
+                variance += vi.min(*vj);
+            }
+        }
+        variance /= n * n;
+        if variance < 0.0 {
+            return Err(qm::Error::new("Negative variance"))
+        }
+
+        let forward = (log_mean + 0.5 * variance).exp();
+        let strike = self.strike - mean_displacement;
+        let undiscounted = if strike <= 0.0 {
+            // the option is certain to be exercised if a call, or worthless
+            // if a put
+            (self.sign() * (forward - strike)).max(0.0)
+        } else {
+            let black76 = Black76::new()?;
+            match self.put_or_call {
+                PutOrCall::Call => black76.call_price(1.0, forward, strike, variance.sqrt()),
+                PutOrCall::Put => black76.put_price(1.0, forward, strike, variance.sqrt())
+            }
+        };
+
+        // discount in the same way as the Monte-Carlo flows
+        let val_date = DateTime::new(context.spot_date(), TimeOfDay::Open);
+        let payment = self.payment();
+        let priceable = payment.as_priceable().ok_or_else(|| qm::Error::new(
+            "Asian option payment must be priceable"))?;
+        Ok(undiscounted * priceable.price(context, val_date)?)
+    }
+
+    fn mc_control_price(&self, context: &MonteCarloContext)
+        -> Result<f64, qm::Error> {
+
+        let ref paths = context.paths(&self.underlying)?;
+        let n_paths = paths.shape()[0];
+        let n_obs = paths.shape()[1];
+        assert_eq!(n_obs, self.averaging_times.len());
+
+        let displacements = self.displacements(context.pricing_context())?;
+        let n = self.n_averaging();
+        let strike = self.strike - displacements.iter().sum::<f64>() / n;
+        let sign = self.sign();
+        let past_log_sum: f64 = self.past_fixings.iter().map(|f| f.ln()).sum();
+
+        let mut quantities = Array2::zeros((n_paths, 1));
+        {
+            let ref mut flow_column = quantities.subview_mut(Axis(1), 0);
+            for (path, flow) in paths.axis_iter(Axis(0)).zip(flow_column.iter_mut()) {
+                let mut log_sum = past_log_sum;
+                for (spot, displacement) in path.iter().zip(displacements.iter()) {
+                    log_sum += (spot - displacement).ln();
+                }
+                let average = (log_sum / n).exp();
+                *flow = (sign * (average - strike)).max(0.0);
+            }
+        }
+
+        context.evaluate_flows(quantities.view())
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+    use dates::datetime::TimeOfDay;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::marketdata::tests::sample_european;
+    use risk::marketdata::tests::sample_currency;
+    use risk::marketdata::tests::sample_settlement;
+    use risk::marketdata::tests::sample_equity;
+    use instruments::Priceable;
+    use serde_json;
+
+    /// An at-the-money call, averaging monthly over a year
+    pub fn sample_asian() -> Arc<AsianOption> {
+        let mut averaging_dates = Vec::new();
+        for month in 1..13 {
+            averaging_dates.push(DateTime::new(
+                Date::from_ymd(2017, month, 1), TimeOfDay::Close));
+        }
+        sample_asian_with_dates(&averaging_dates)
+    }
+
+    pub fn sample_asian_with_dates(averaging_dates: &[DateTime]) -> Arc<AsianOption> {
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        let settlement = sample_settlement(2);
+        let equity = RcInstrument::new(Qrc::new(Arc::new(sample_equity(currency, 2))));
+        Arc::new(AsianOption::new("SampleAsian", "OPT", equity, settlement,
+            averaging_dates, 100.0, PutOrCall::Call).unwrap())
+    }
+
+    /// Benchmark price for an arithmetic Asian option, matching the first two
+    /// moments of the average to a log-normal distribution (Levy, 1992). This
+    /// is accurate to a few cents for moderate vols and maturities. Assumes
+    /// there is no displacement.
+    pub fn moment_matched_price(asian: &AsianOption, context: &PricingContext)
+        -> f64 {
+
+        let hwm = asian.expiry().date();
+        let fwd = context.forward_curve(&*asian.underlying, hwm).unwrap();
+        let vol = context.vol_surface(&*asian.underlying, hwm,
+            &|| Ok(fwd.clone())).unwrap();
+
+        // past fixings contribute constants to the average, with no variance
+        let mut observations: Vec<(f64, f64)> = asian.past_fixings.iter()
+            .map(|f| (*f, 0.0)).collect();
+        for time in asian.averaging_times.iter() {
+            let forward = fwd.forward(time.date()).unwrap();
+            observations.push((forward, vol.variance(*time, forward).unwrap()));
+        }
+
+        let n = asian.n_averaging();
+        let mut first = 0.0;
+        let mut second = 0.0;
+        for &(fi, vi) in observations.iter() {
+            first += fi / n;
+            for &(fj, vj) in observations.iter() {
+                second += fi * fj * vi.min(vj).exp() / (n * n);
+            }
+        }
+        let sd = (second / (first * first)).ln().sqrt();
+
+        let black76 = Black76::new().unwrap();
+        let undiscounted = match asian.put_or_call {
+            PutOrCall::Call => black76.call_price(1.0, first, asian.strike, sd),
+            PutOrCall::Put => black76.put_price(1.0, first, asian.strike, sd) };
+
+        let val_date = DateTime::new(context.spot_date(), TimeOfDay::Open);
+        let payment = asian.payment();
+        undiscounted * payment.as_priceable().unwrap().price(context, val_date).unwrap()
+    }
+
+    /// Averaging monthly over the year to June 2017, where the first three
+    /// averaging dates are in the past
+    fn straddling_dates() -> Vec<DateTime> {
+        let mut averaging_dates = Vec::new();
+        for &(year, month) in [(2016, 10), (2016, 11), (2016, 12)].iter() {
+            averaging_dates.push(DateTime::new(
+                Date::from_ymd(year, month, 3), TimeOfDay::Close));
+        }
+        for month in 1..7 {
+            averaging_dates.push(DateTime::new(
+                Date::from_ymd(2017, month, 3), TimeOfDay::Close));
+        }
+        averaging_dates
+    }
+
+    /// The Asian option on straddling dates, once the past dates have fixed
+    pub fn sample_fixed_asian() -> Arc<AsianOption> {
+        let asian = sample_asian_with_dates(&straddling_dates());
+        Arc::new(AsianOption::with_past_fixings("SampleAsian", "OPT",
+            asian.underlying.clone(), asian.settlement.clone(),
+            &straddling_dates()[3..], &[95.0, 98.0, 104.0], 100.0,
+            PutOrCall::Call).unwrap())
+    }
+
+    fn past_fixings(values: &[f64]) -> FixingTable {
+        let today = Date::from_ymd(2017, 01, 02);
+        let fixings: Vec<(DateTime, f64)> = straddling_dates().iter()
+            .zip(values.iter()).map(|(d, v)| (*d, *v)).collect();
+        FixingTable::from_fixings(today, &[("BP.L", &fixings)]).unwrap()
+    }
+
+    #[test]
+    fn asian_fix_with_past_averaging_dates() {
+
+        let asian = sample_asian_with_dates(&straddling_dates());
+        let decomp = asian.fix(&past_fixings(&[95.0, 98.0, 104.0]))
+            .unwrap().unwrap();
+        assert_eq!(decomp.len(), 1);
+        let (weight, ref fixed) = decomp[0];
+        assert_approx(weight, 1.0, 1e-12);
+        assert_eq!(fixed.id(), "SampleAsian");
+
+        // the fixed option should match one constructed directly
+        let expected = sample_fixed_asian();
+        let market_data = sample_market_data();
+        let control = fixed.as_control_variate().unwrap()
+            .control_value(&market_data).unwrap();
+        assert_approx(control, expected.control_value(&market_data).unwrap(), 1e-12);
+
+        // the past fixings survive serialization
+        let serialized = serde_json::to_string(&*expected).unwrap();
+        let deserialized: AsianOption = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(deserialized.past_fixings, vec!(95.0, 98.0, 104.0));
+        assert_eq!(deserialized.averaging_dates.len(), 6);
+    }
+
+    #[test]
+    fn asian_fix_when_fully_fixed() {
+
+        // fixed above the strike, the option becomes a cash payment
+        let asian = sample_asian_with_dates(&straddling_dates()[..3]);
+        let decomp = asian.fix(&past_fixings(&[95.0, 98.0, 113.0]))
+            .unwrap().unwrap();
+        assert_eq!(decomp.len(), 1);
+        assert_approx(decomp[0].0, 2.0, 1e-12);
+        assert_eq!(decomp[0].1.id(), "SampleAsian:Expiry");
+
+        // fixed below the strike, the option is worthless
+        let decomp = asian.fix(&past_fixings(&[95.0, 98.0, 104.0]))
+            .unwrap().unwrap();
+        assert!(decomp.is_empty());
+    }
+
+    #[test]
+    fn asian_fix_errors_on_missing_past_fixing() {
+        let asian = sample_asian_with_dates(&straddling_dates());
+        assert!(asian.fix(&past_fixings(&[95.0, 98.0])).is_err());
+    }
+
+    #[test]
+    fn asian_control_with_single_date_matches_european() {
+
+        // with a single averaging date, the geometric control is a European
+        let expiry = DateTime::new(Date::from_ymd(2018, 06, 01), TimeOfDay::Close);
+        let asian = sample_asian_with_dates(&[expiry]);
+        let market_data = sample_market_data();
+
+        let control = asian.control_value(&market_data).unwrap();
+        let european = sample_european();
+        let val_date = DateTime::new(market_data.spot_date(), TimeOfDay::Open);
+        let expected = european.price(&market_data, val_date).unwrap();
+        assert_approx(control, expected, 1e-10);
+    }
+
+    #[test]
+    fn asian_control_less_than_european() {
+
+        // averaging reduces the variance, so an at-the-money geometric
+        // Asian should be cheaper than a European on the last date
+        let asian = sample_asian();
+        let market_data = sample_market_data();
+        let control = asian.control_value(&market_data).unwrap();
+        assert!(control > 0.0 && control < 16.710717400832973, "control={}", control);
+    }
+
+    #[test]
+    fn asian_rejects_unordered_dates() {
+        let dates = [
+            DateTime::new(Date::from_ymd(2017, 02, 01), TimeOfDay::Close),
+            DateTime::new(Date::from_ymd(2017, 01, 01), TimeOfDay::Close)];
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        let equity = RcInstrument::new(Qrc::new(Arc::new(sample_equity(currency, 2))));
+        assert!(AsianOption::new("Bad", "OPT", equity, sample_settlement(2),
+            &dates, 100.0, PutOrCall::Call).is_err());
+    }
+
+    #[test]
+    fn asian_serde() {
+        let asian = sample_asian();
+        let serialized = serde_json::to_string(&*asian).unwrap();
+        let deserialized: AsianOption = serde_json::from_str(&serialized).unwrap();
+        let reserialized = serde_json::to_string(&deserialized).unwrap();
+        assert_eq!(serialized, reserialized);
+    }
+
+    fn assert_approx(value: f64, expected: f64, tolerance: f64) {
+        assert!(approx_eq(value, expected, tolerance),
+            "value={} expected={}", value, expected);
+    }
+}
@@ -0,0 +1,862 @@
+use std::sync::Arc;
+use instruments::Instrument;
+use instruments::RcInstrument;
+use instruments::DependencyContext;
+use instruments::SpotRequirement;
+use instruments::MonteCarloPriceable;
+use instruments::MonteCarloDependencies;
+use instruments::ObservationKind;
+use instruments::MonteCarloContext;
+use instruments::options::PutOrCall;
+use instruments::options::OptionSettlement;
+use instruments::options::PayoffSmoothing;
+use instruments::options::SpotStartingEuropean;
+use instruments::assets::Currency;
+use instruments::assets::RcCurrency;
+use instruments::bonds::ZeroCoupon;
+use data::fixings::FixingTable;
+use dates::Date;
+use dates::rules::RcDateRule;
+use dates::datetime::DateTime;
+use dates::datetime::DateDayFraction;
+use core::qm;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use core::dedup::InstanceId;
+use ndarray::Axis;
+use ndarray::Array2;
+use erased_serde as esd;
+use serde::Deserialize;
+
+/// Whether the barrier is breached by the underlying rising to or above it,
+/// or falling to or below it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BarrierDirection { Up, Down }
+
+/// A knock-out option is cancelled if the barrier is breached. A knock-in
+/// option only comes into existence if the barrier is breached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum KnockType { In, Out }
+
+/// How the barrier is monitored between the observations of the underlying.
+/// Continuous monitoring means the barrier is breached if the underlying
+/// touches it at any time in the monitoring window. Discrete monitoring
+/// means only the observations themselves are checked against the barrier.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BarrierMonitoring { Continuous, Discrete }
+
+impl Default for BarrierMonitoring {
+    fn default() -> BarrierMonitoring { BarrierMonitoring::Continuous }
+}
+
+impl BarrierMonitoring {
+    pub fn is_continuous(&self) -> bool { *self == BarrierMonitoring::Continuous }
+}
+
+/// A barrier option is a European option that is knocked in or out if the
+/// underlying breaches a barrier at any time in a monitoring window, which
+/// ends at the expiry. The payoff is settled in cash at the settlement date
+/// following the expiry.
+///
+/// Monitoring is continuous, but the underlying is only observed on a set of
+/// evenly spaced monitoring dates. When priced by Monte-Carlo, the
+/// probability that the underlying breached the barrier between two
+/// observations is estimated using a Brownian bridge, so that discretely
+/// observed paths approximate continuous monitoring. The bridge makes the
+/// payoff continuous in the path, other than at the first observation, which
+/// may optionally be smoothed. See PayoffSmoothing.
+///
+/// The bridge gives the probability of a crossing between two observations
+/// on the same side of the barrier, and uses it to weight the payoff rather
+/// than sampling whether the crossing happened, which gives the same price
+/// with less noise. Observations on opposite sides always count as a breach.
+/// The barrier may instead be monitored only on the observations, in which
+/// case there is no bridge. See BarrierMonitoring.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct BarrierOption {
+    id: String,
+    credit_id: String,
+    underlying: RcInstrument,
+    settlement: RcDateRule,
+    monitoring_dates: Vec<DateTime>,
+    strike: f64,
+    put_or_call: PutOrCall,
+    barrier: f64,
+    direction: BarrierDirection,
+    knock: KnockType,
+    #[serde(default, skip_serializing_if = "PayoffSmoothing::is_none")]
+    smoothing: PayoffSmoothing,
+    #[serde(default, skip_serializing_if = "BarrierMonitoring::is_continuous")]
+    monitoring: BarrierMonitoring,
+
+    // set when earlier monitoring dates have fixed and been dropped, so
+    // the barrier is being monitored from today
+    #[serde(default, skip_serializing_if = "is_false")]
+    monitoring_started: bool,
+
+    // fields precomputed for performance and simplicity
+    monitoring_times: Vec<DateDayFraction>,
+    pay_date: Date
+}
+
+impl TypeId for BarrierOption {
+    fn get_type_id(&self) -> &'static str { "BarrierOption" }
+}
+
+impl InstanceId for BarrierOption {
+    fn id(&self) -> &str { &self.id }
+}
+
+impl BarrierOption {
+    /// Creates a barrier option, monitored from monitoring_start up to and
+    /// including the expiry. The underlying is observed on monitoring_steps
+    /// evenly spaced dates after the start, as well as on the start itself.
+    /// All the monitoring dates after the start are at the same time of day
+    /// as the expiry. Weekly observation is normally fine enough, given the
+    /// Brownian bridge correction between observations.
+    pub fn new(
+        id: &str,
+        credit_id: &str,
+        underlying: RcInstrument,
+        settlement: RcDateRule,
+        monitoring_start: DateTime,
+        expiry: DateTime,
+        monitoring_steps: usize,
+        strike: f64,
+        put_or_call: PutOrCall,
+        barrier: f64,
+        direction: BarrierDirection,
+        knock: KnockType)
+        -> Result<BarrierOption, qm::Error> {
+
+        if monitoring_steps == 0 {
+            return Err(qm::Error::new("There must be at least one monitoring step"))
+        }
+        let days = expiry.date() - monitoring_start.date();
+        if days < 0 {
+            return Err(qm::Error::new("Monitoring must not start after expiry"))
+        }
+
+        // roll out the monitoring dates, ignoring duplicates if there are
+        // more steps than days
+        let mut monitoring_dates = Vec::with_capacity(monitoring_steps + 1);
+        monitoring_dates.push(monitoring_start);
+        for step in 1..(monitoring_steps + 1) {
+            let offset = (days as f64 * step as f64 / monitoring_steps as f64).round() as i32;
+            let date = DateTime::new(monitoring_start.date() + offset, expiry.time_of_day());
+            if *monitoring_dates.last().unwrap() < date {
+                monitoring_dates.push(date);
+            }
+        }
+
+        BarrierOption::from_dates(id, credit_id, underlying, settlement,
+            monitoring_dates, strike, put_or_call, barrier, direction, knock)
+    }
+
+    fn from_dates(
+        id: &str,
+        credit_id: &str,
+        underlying: RcInstrument,
+        settlement: RcDateRule,
+        monitoring_dates: Vec<DateTime>,
+        strike: f64,
+        put_or_call: PutOrCall,
+        barrier: f64,
+        direction: BarrierDirection,
+        knock: KnockType)
+        -> Result<BarrierOption, qm::Error> {
+
+        if strike < 0.0 {
+            return Err(qm::Error::new("Strike must be greater or equal to zero"))
+        }
+        if barrier <= 0.0 {
+            return Err(qm::Error::new("Barrier must be greater than zero"))
+        }
+
+        let pay_date = {
+            let expiry = monitoring_dates.last().ok_or_else(|| qm::Error::new(
+                "A barrier option must have at least one monitoring date"))?;
+            settlement.apply(expiry.date())
+        };
+        let mut monitoring_times = Vec::with_capacity(monitoring_dates.len());
+        for date in monitoring_dates.iter() {
+            monitoring_times.push(underlying.time_to_day_fraction(*date)?);
+        }
+
+        Ok(BarrierOption {
+            id: id.to_string(),
+            credit_id: credit_id.to_string(),
+            underlying: underlying,
+            settlement: settlement,
+            monitoring_dates: monitoring_dates,
+            strike: strike,
+            put_or_call: put_or_call,
+            barrier: barrier,
+            direction: direction,
+            knock: knock,
+            smoothing: PayoffSmoothing::None,
+            monitoring: BarrierMonitoring::Continuous,
+            monitoring_started: false,
+            monitoring_times: monitoring_times,
+            pay_date: pay_date })
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<Instrument>, esd::Error> {
+        Ok(Qrc::new(Arc::new(BarrierOption::deserialize(de)?)))
+    }
+
+    /// Smooths the barrier when valued by Monte-Carlo, which makes bumped
+    /// Greeks less noisy at the cost of a small bias in the price.
+    pub fn with_smoothing(mut self, smoothing: PayoffSmoothing)
+        -> Result<BarrierOption, qm::Error> {
+        smoothing.validate(self.barrier)?;
+        self.smoothing = smoothing;
+        Ok(self)
+    }
+
+    /// Changes how the barrier is monitored between observations. By
+    /// default, monitoring is continuous.
+    pub fn with_monitoring(mut self, monitoring: BarrierMonitoring) -> BarrierOption {
+        self.monitoring = monitoring;
+        self
+    }
+
+    fn expiry(&self) -> DateTime {
+        *self.monitoring_dates.last().unwrap()
+    }
+
+    /// Whether each path starts from today's spot, which it does if
+    /// monitoring has already started, either because the first monitoring
+    /// date is not in the future, or because earlier dates have fixed. In
+    /// the latter case, spot only matters if monitoring is continuous, so
+    /// that the bridge covers the interval up to the first observation.
+    fn starts_from_spot(&self, spot_date: Date) -> bool {
+        self.monitoring_dates[0].date() <= spot_date
+            || (self.monitoring_started && self.monitoring.is_continuous())
+    }
+
+    fn intrinsic(&self, spot: f64) -> f64 {
+        match self.put_or_call {
+            PutOrCall::Call => (spot - self.strike).max(0.0),
+            PutOrCall::Put => (self.strike - spot).max(0.0) }
+    }
+
+    fn breached(&self, spot: f64) -> bool {
+        match self.direction {
+            BarrierDirection::Up => spot >= self.barrier,
+            BarrierDirection::Down => spot <= self.barrier }
+    }
+
+    /// The weight given to the spot having breached the barrier in a
+    /// Monte-Carlo valuation. Without smoothing, this is consistent with
+    /// breached.
+    fn smoothed_breached(&self, spot: f64) -> f64 {
+        match self.direction {
+            BarrierDirection::Up => self.smoothing.above(spot, self.barrier, true),
+            BarrierDirection::Down => self.smoothing.below(spot, self.barrier, true) }
+    }
+
+    /// The probability that the barrier is not breached between two
+    /// observations, given the variance of the log of the underlying at each.
+    /// If there is no earlier observation, or monitoring is discrete, this is
+    /// just whether the later observation breaches the barrier.
+    fn survival(&self, from: Option<(f64, f64)>, spot: f64, variance: f64) -> f64 {
+        let survived = 1.0 - self.smoothed_breached(spot);
+        if survived == 0.0 {
+            return 0.0
+        }
+        match from {
+            None => survived,
+            Some(_) if self.monitoring == BarrierMonitoring::Discrete => survived,
+            Some((_, prev_variance)) if variance <= prev_variance => survived,
+            Some((prev_spot, prev_variance)) => {
+                // The probability that a Brownian bridge between the logs of
+                // the two spots crosses the log of the barrier. If smoothing
+                // lets either spot be beyond the barrier, the bridge must
+                // have crossed it. If both are on the same side, there is
+                // still a chance of crossing, which grows with the variance
+                // between them.
+                let a = (prev_spot / self.barrier).ln();
+                let b = (spot / self.barrier).ln();
+                let ab = a * b;
+                if ab <= 0.0 {
+                    return 0.0
+                }
+                survived * (1.0 - (-2.0 * ab / (variance - prev_variance)).exp())
+            }
+        }
+    }
+
+    /// The cash payment at the pay date
+    fn payment(&self) -> RcInstrument {
+        // TODO this feels inefficient and ugly
+        let currency = RcCurrency::new(Arc::new(self.payoff_currency().clone()));
+        RcInstrument::new(Qrc::new(Arc::new(
+            ZeroCoupon::new(&format!("{}:Expiry", self.id),
+            &self.credit_id, currency, self.expiry(), self.pay_date,
+            self.settlement.clone()))))
+    }
+}
+
+fn is_false(value: &bool) -> bool { !*value }
+
+impl Instrument for BarrierOption {
+    fn payoff_currency(&self) -> &Currency { self.underlying.payoff_currency() }
+    fn credit_id(&self) -> &str { &self.credit_id }
+    fn settlement(&self) -> &RcDateRule { &self.settlement }
+    fn as_mc_priceable(&self) -> Option<&MonteCarloPriceable> { Some(self) }
+
+    fn dependencies(&self, context: &mut DependencyContext)
+        -> SpotRequirement {
+
+        // one fixing on each monitoring date
+        for date in self.monitoring_dates.iter() {
+            context.fixing(self.underlying.id(), *date);
+        }
+
+        // If monitoring has started, the barrier is checked against the
+        // spot of the underlying. The barrier option itself has no spot.
+        if self.starts_from_spot(context.spot_date()) {
+            context.spot(&self.underlying);
+        }
+
+        let expiry_date = self.expiry().date();
+        context.yield_curve(self.credit_id(), self.pay_date);
+        context.forward_curve(&self.underlying, expiry_date);
+        context.vol_surface(&self.underlying, expiry_date);
+
+        SpotRequirement::NotRequired
+    }
+
+    fn fix(&self, fixing_table: &FixingTable)
+        -> Result<Option<Vec<(f64, RcInstrument)>>, qm::Error> {
+
+        // Look through the monitoring dates that have fixed. (The fetch
+        // errors if a fixing in the past is missing.)
+        let mut n_fixed = 0;
+        let mut breached = false;
+        let mut last_fixing = None;
+        for date in self.monitoring_dates.iter() {
+            match fixing_table.get(self.underlying.id(), *date)? {
+                Some(fixing) => {
+                    n_fixed += 1;
+                    breached = breached || self.breached(fixing);
+                    last_fixing = Some(fixing);
+                },
+                None => break
+            }
+        }
+        if n_fixed == 0 {
+            return Ok(None)
+        }
+
+        // A knock-out that has breached is worthless. So is a knock-in that
+        // expired without breaching.
+        let knocked_in = match self.knock {
+            KnockType::Out if breached => return Ok(Some(Vec::new())),
+            KnockType::Out => false,
+            KnockType::In => breached };
+        let expired = n_fixed == self.monitoring_dates.len();
+        if expired && self.knock == KnockType::In && !knocked_in {
+            return Ok(Some(Vec::new()))
+        }
+
+        // If the option has expired, it turns into a cash payment
+        if expired {
+            let mut decomp = Vec::new();
+            let payment = self.intrinsic(last_fixing.unwrap());
+            if payment > 0.0 {
+                decomp.push((payment, self.payment()));
+            }
+            return Ok(Some(decomp))
+        }
+
+        // A knock-in that has breached is now a European option
+        if knocked_in {
+            let european = SpotStartingEuropean::new(&self.id, &self.credit_id,
+                self.underlying.clone(), self.settlement.clone(), self.expiry(),
+                self.strike, self.put_or_call, OptionSettlement::Cash)?;
+            return Ok(Some(vec!((1.0, RcInstrument::new(Qrc::new(Arc::new(european)))))))
+        }
+
+        // Otherwise, drop the monitoring dates that are in the past
+        let mut remaining = BarrierOption::from_dates(&self.id, &self.credit_id,
+            self.underlying.clone(), self.settlement.clone(),
+            self.monitoring_dates[n_fixed..].to_vec(), self.strike,
+            self.put_or_call, self.barrier, self.direction, self.knock)?;
+        remaining.smoothing = self.smoothing;
+        remaining.monitoring = self.monitoring;
+        remaining.monitoring_started = true;
+        Ok(Some(vec!((1.0, RcInstrument::new(Qrc::new(Arc::new(remaining)))))))
+    }
+}
+
+impl MonteCarloPriceable for BarrierOption {
+    fn as_instrument(&self) -> &Instrument { self }
+
+    fn mc_dependencies(&self, _dates: &[DateDayFraction],
+        output: &mut MonteCarloDependencies) -> Result<(), qm::Error> {
+
+        // an observation on each monitoring date, so the timeline is fine
+        // throughout the monitoring window
+        for time in self.monitoring_times.iter() {
+            output.observation(&self.underlying, *time, ObservationKind::Barrier);
+        }
+
+        // a single cash payment at the pay date
+        output.flow(&self.payment());
+        Ok(())
+    }
+
+    fn start_date(&self) -> Option<DateDayFraction> {
+        None
+    }
+
+    fn mc_price(&self, context: &MonteCarloContext)
+        -> Result<f64, qm::Error> {
+
+        let ref paths = context.paths(&self.underlying)?;
+        let n_paths = paths.shape()[0];
+        let n_obs = paths.shape()[1];
+        assert_eq!(n_obs, self.monitoring_times.len());
+
+        // The variance of the log of the underlying at each monitoring date,
+        // measured at the barrier level
+        let pricing_context = context.pricing_context();
+        let hwm = self.expiry().date();
+        let fwd = pricing_context.forward_curve(&*self.underlying, hwm)?;
+        let vol = pricing_context.vol_surface(&*self.underlying, hwm,
+            &|| Ok(fwd.clone()))?;
+        let mut variances = Vec::with_capacity(n_obs);
+        for time in self.monitoring_times.iter() {
+            variances.push(vol.variance(*time, self.barrier)?);
+        }
+
+        // If monitoring has already started, each path starts from today's
+        // spot, with no variance. If spot is already beyond the barrier, all
+        // paths are breached immediately.
+        let start = if self.starts_from_spot(pricing_context.spot_date()) {
+            Some((pricing_context.spot(self.underlying.id())?, 0.0))
+        } else {
+            None
+        };
+
+        let mut quantities = Array2::zeros((n_paths, 1));
+        {
+            let ref mut flow_column = quantities.subview_mut(Axis(1), 0);
+            for (path, flow) in paths.axis_iter(Axis(0)).zip(flow_column.iter_mut()) {
+                let mut survival = match start {
+                    Some((spot, _)) => 1.0 - self.smoothed_breached(spot),
+                    None => 1.0 };
+                let mut prev = start;
+                for (spot, variance) in path.iter().zip(variances.iter()) {
+                    if survival == 0.0 {
+                        break
+                    }
+                    survival *= self.survival(prev, *spot, *variance);
+                    prev = Some((*spot, *variance));
+                }
+
+                let intrinsic = self.intrinsic(path[n_obs - 1]);
+                *flow = match self.knock {
+                    KnockType::Out => intrinsic * survival,
+                    KnockType::In => intrinsic * (1.0 - survival) };
+            }
+        }
+
+        context.evaluate_flows(quantities.view())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use models::random::BASE_SEED;
+    use pricers::montecarlo::tests::black_diffusion_price_with_stderr;
+    use std::collections::HashMap;
+    use math::optionpricing::Black76;
+    use math::interpolation::Extrap;
+    use data::curves::RateCurveAct365;
+    use data::curves::RcRateCurve;
+    use data::divstream::DividendStream;
+    use data::divstream::RcDividendStream;
+    use dates::datetime::TimeOfDay;
+    use risk::marketdata::MarketData;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::marketdata::tests::sample_currency;
+    use risk::marketdata::tests::sample_settlement;
+    use risk::marketdata::tests::sample_equity;
+    use risk::marketdata::tests::create_sample_rate;
+    use risk::marketdata::tests::create_sample_flat_vol;
+    use instruments::PricingContext;
+    use models::RcMonteCarloModelFactory;
+    use models::VarianceReduction;
+    use models::blackdiffusion::BlackDiffusionFactory;
+    use models::heston::HestonFactory;
+    use pricers::montecarlo::MonteCarloPricer;
+    use serde_json;
+
+    fn sample_barrier(barrier: f64, direction: BarrierDirection, knock: KnockType)
+        -> BarrierOption {
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        let equity = RcInstrument::new(Qrc::new(Arc::new(sample_equity(currency, 2))));
+        let start = DateTime::new(Date::from_ymd(2017, 01, 02), TimeOfDay::Open);
+        let expiry = DateTime::new(Date::from_ymd(2018, 06, 01), TimeOfDay::Close);
+        BarrierOption::new("SampleBarrier", "OPT", equity, sample_settlement(2),
+            start, expiry, 73, 100.0, PutOrCall::Call, barrier, direction,
+            knock).unwrap()
+    }
+
+    /// Market data where the forward equals spot, so that the underlying is
+    /// a driftless log-normal and the analytic barrier formulae are exact.
+    /// There is still discounting.
+    fn driftless_market_data() -> MarketData {
+        let d = Date::from_ymd(2016, 12, 30);
+        let zero = RcRateCurve::new(Arc::new(RateCurveAct365::new(d,
+            &[(d, 0.0), (d + 3650, 0.0)], Extrap::Flat, Extrap::Flat).unwrap()));
+
+        let mut spots = HashMap::new();
+        spots.insert("BP.L".to_string(), 100.0);
+        let mut dividends = HashMap::new();
+        dividends.insert("BP.L".to_string(), RcDividendStream::new(Arc::new(
+            DividendStream::new(&[], zero.clone()))));
+        let mut yield_curves = HashMap::new();
+        yield_curves.insert("OPT".to_string(), create_sample_rate());
+        yield_curves.insert("LSE".to_string(), zero.clone());
+        let mut borrow_curves = HashMap::new();
+        borrow_curves.insert("BP.L".to_string(), zero);
+        let mut vol_surfaces = HashMap::new();
+        vol_surfaces.insert("BP.L".to_string(), create_sample_flat_vol());
+
+        MarketData::new(Date::from_ymd(2017, 01, 02), spots, yield_curves,
+            borrow_curves, dividends, vol_surfaces)
+    }
+
+    /// Continuously monitored down-and-out call with the barrier below the
+    /// strike, on a driftless underlying. By the reflection principle, the
+    /// knock-in is worth S/H calls on a forward of H^2/S.
+    fn analytic_down_and_out_call(barrier: &BarrierOption,
+        context: &PricingContext) -> f64 {
+
+        let expiry = barrier.expiry().date();
+        let fwd = context.forward_curve(&*barrier.underlying, expiry).unwrap();
+        let vol = context.vol_surface(&*barrier.underlying, expiry,
+            &|| Ok(fwd.clone())).unwrap();
+        let variance = vol.variance(
+            *barrier.monitoring_times.last().unwrap(), barrier.barrier).unwrap();
+        analytic_down_and_out_call_with_variance(barrier, context, variance)
+    }
+
+    /// As above, but with the given variance to expiry. By a change of time,
+    /// the formula holds for any deterministic variance.
+    fn analytic_down_and_out_call_with_variance(barrier: &BarrierOption,
+        context: &PricingContext, variance: f64) -> f64 {
+
+        let spot = context.spot("BP.L").unwrap();
+        let val_date = DateTime::new(context.spot_date(), TimeOfDay::Open);
+        let df = barrier.payment().as_priceable().unwrap()
+            .price(context, val_date).unwrap();
+
+        let h = barrier.barrier;
+        let k = barrier.strike;
+        let black76 = Black76::new().unwrap();
+        let vanilla = black76.call_price(df, spot, k, variance.sqrt());
+        let knock_in = spot / h * black76.call_price(df, h * h / spot, k, variance.sqrt());
+        vanilla - knock_in
+    }
+
+    fn mc_price_with_stderr(barrier: BarrierOption, market_data: &MarketData)
+        -> (f64, f64) {
+        let instrument = RcInstrument::new(Qrc::new(Arc::new(barrier)));
+        black_diffusion_price_with_stderr(instrument, market_data, 0.01, 50000).unwrap()
+    }
+
+    #[test]
+    fn barrier_down_and_out_against_analytic() {
+
+        let market_data = driftless_market_data();
+        let barrier = sample_barrier(90.0, BarrierDirection::Down, KnockType::Out);
+        let analytic = analytic_down_and_out_call(&barrier, &market_data);
+        let (price, stderr) = mc_price_with_stderr(barrier, &market_data);
+        assert!((price - analytic).abs() < 3.0 * stderr,
+            "price={} stderr={} analytic={}", price, stderr, analytic);
+    }
+
+    #[test]
+    fn barrier_correction_approximates_continuous_monitoring() {
+
+        // Even with only monthly observation, the correction should bring
+        // the price close to continuous monitoring. (Without it, the price
+        // would be more than two dollars too high.)
+        let market_data = driftless_market_data();
+        let weekly = sample_barrier(90.0, BarrierDirection::Down, KnockType::Out);
+        let analytic = analytic_down_and_out_call(&weekly, &market_data);
+        let monthly = BarrierOption::new("SampleBarrier", "OPT",
+            weekly.underlying.clone(), weekly.settlement.clone(),
+            weekly.monitoring_dates[0], weekly.expiry(), 17, 100.0,
+            PutOrCall::Call, 90.0, BarrierDirection::Down, KnockType::Out).unwrap();
+        let (price, stderr) = mc_price_with_stderr(monthly, &market_data);
+        assert!((price - analytic).abs() < 3.0 * stderr + 0.05,
+            "price={} stderr={} analytic={}", price, stderr, analytic);
+    }
+
+    #[test]
+    fn barrier_bridge_converges_faster_than_discrete_monitoring() {
+
+        // On a driftless underlying with flat vol, the bridge is exact
+        // however coarse the observations, whereas discretely monitored
+        // prices approach the continuous price slowly as observations are
+        // added. Even with quarterly observation the bridge beats weekly
+        // discrete monitoring.
+        let market_data = driftless_market_data();
+        let weekly = sample_barrier(90.0, BarrierDirection::Down, KnockType::Out);
+        let analytic = analytic_down_and_out_call(&weekly, &market_data);
+        let with_steps = |steps: usize, monitoring: BarrierMonitoring| {
+            let barrier = BarrierOption::new("SampleBarrier", "OPT",
+                weekly.underlying.clone(), weekly.settlement.clone(),
+                weekly.monitoring_dates[0], weekly.expiry(), steps, 100.0,
+                PutOrCall::Call, 90.0, BarrierDirection::Down,
+                KnockType::Out).unwrap().with_monitoring(monitoring);
+            let (price, stderr) = mc_price_with_stderr(barrier, &market_data);
+            ((price - analytic).abs(), stderr)
+        };
+
+        let (bridge_coarse, stderr) = with_steps(6, BarrierMonitoring::Continuous);
+        let (discrete_coarse, _) = with_steps(6, BarrierMonitoring::Discrete);
+        let (discrete_fine, _) = with_steps(73, BarrierMonitoring::Discrete);
+        assert!(bridge_coarse < 3.0 * stderr + 0.05,
+            "bridge error={} stderr={}", bridge_coarse, stderr);
+        assert!(discrete_fine < discrete_coarse,
+            "discrete errors fine={} coarse={}", discrete_fine, discrete_coarse);
+        assert!(discrete_fine > 5.0 * stderr && bridge_coarse < discrete_fine,
+            "bridge error={} discrete error={} stderr={}",
+            bridge_coarse, discrete_fine, stderr);
+    }
+
+    #[test]
+    fn barrier_bridge_crosses_between_observations_on_same_side() {
+
+        // both observations above a down barrier, but the chance of a
+        // crossing between them grows with the variance
+        let barrier = sample_barrier(90.0, BarrierDirection::Down, KnockType::Out);
+        let low_vol = barrier.survival(Some((95.0, 0.0)), 95.0, 0.001);
+        let high_vol = barrier.survival(Some((95.0, 0.0)), 95.0, 0.5);
+        assert!(low_vol > high_vol && high_vol > 0.0 && low_vol < 1.0,
+            "low_vol={} high_vol={}", low_vol, high_vol);
+        assert_eq!(barrier.survival(Some((95.0, 0.0)), 85.0, 0.5), 0.0);
+
+        let discrete = barrier.with_monitoring(BarrierMonitoring::Discrete);
+        assert_eq!(discrete.survival(Some((95.0, 0.0)), 95.0, 0.5), 1.0);
+    }
+
+    #[test]
+    fn barrier_extrapolated_price_reduces_discretization_bias() {
+
+        // Heston with no vol of vol has a deterministic variance, so on a
+        // driftless underlying the continuously monitored price is known
+        // analytically from the integrated variance to expiry. The Euler
+        // scheme gets the integrated variance wrong to first order in the
+        // step size, which is significant when the variance reverts fast
+        // from a low level.
+        let market_data = driftless_market_data();
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        let equity = RcInstrument::new(Qrc::new(Arc::new(sample_equity(currency, 2))));
+        let start = DateTime::new(Date::from_ymd(2017, 01, 02), TimeOfDay::Open);
+        let expiry = DateTime::new(Date::from_ymd(2017, 01, 16), TimeOfDay::Close);
+        let barrier = BarrierOption::new("SampleBarrier", "OPT", equity,
+            sample_settlement(2), start, expiry, 2, 100.0, PutOrCall::Call,
+            80.0, BarrierDirection::Down, KnockType::Out).unwrap();
+
+        let (v0, kappa, theta) = (0.01, 26.0, 0.5);
+        let time = {
+            let fwd = market_data.forward_curve(&*barrier.underlying,
+                expiry.date()).unwrap();
+            let vol = market_data.vol_surface(&*barrier.underlying,
+                expiry.date(), &|| Ok(fwd.clone())).unwrap();
+            vol.vol_time(*barrier.monitoring_times.last().unwrap()).unwrap()
+        };
+        let variance = theta * time
+            - (theta - v0) * (1.0 - (-kappa * time).exp()) / kappa;
+        let analytic = analytic_down_and_out_call_with_variance(
+            &barrier, &market_data, variance);
+
+        let model_factory = RcMonteCarloModelFactory::new(Arc::new(
+            HestonFactory::new(20, 200000, v0, kappa, theta, 0.0, 0.0).with_seed(BASE_SEED)));
+        let instrument = RcInstrument::new(Qrc::new(Arc::new(barrier)));
+        let pricer = MonteCarloPricer::new(vec!((1.0, instrument)),
+            model_factory, &market_data).unwrap();
+        let (coarse, stderr) = pricer.price_with_stderr().unwrap();
+        let (extrapolated, warning) = pricer.extrapolated_price().unwrap();
+
+        assert!(warning.is_none(), "warning={:?}", warning);
+        assert!((coarse - analytic).abs() > 5.0 * stderr,
+            "coarse={} stderr={} analytic={}", coarse, stderr, analytic);
+        assert!((extrapolated - analytic).abs() < (coarse - analytic).abs(),
+            "extrapolated={} coarse={} analytic={}", extrapolated, coarse, analytic);
+    }
+
+    #[test]
+    fn barrier_extrapolated_price_warns_if_indistinguishable() {
+
+        // A down-and-out that has already knocked out is worth nothing
+        // however finely we step, so there is nothing to extrapolate
+        let market_data = sample_market_data();
+        let model_factory = RcMonteCarloModelFactory::new(Arc::new(BlackDiffusionFactory::new(
+            20, 0.01, 10000, VarianceReduction::None, Some(BASE_SEED)).unwrap()));
+        let instrument = RcInstrument::new(Qrc::new(Arc::new(
+            sample_barrier(105.0, BarrierDirection::Down, KnockType::Out))));
+        let pricer = MonteCarloPricer::new(vec!((1.0, instrument)),
+            model_factory, &market_data).unwrap();
+        let (price, warning) = pricer.extrapolated_price().unwrap();
+        assert_eq!(price, 0.0);
+        assert!(warning.is_some());
+    }
+
+    #[test]
+    fn barrier_in_out_parity() {
+
+        // knock-in plus knock-out is the European, path by path
+        let market_data = sample_market_data();
+        let (knock_in, _) = mc_price_with_stderr(
+            sample_barrier(120.0, BarrierDirection::Up, KnockType::In), &market_data);
+        let (knock_out, _) = mc_price_with_stderr(
+            sample_barrier(120.0, BarrierDirection::Up, KnockType::Out), &market_data);
+        let (european, _) = mc_price_with_stderr(
+            sample_barrier(1e10, BarrierDirection::Up, KnockType::Out), &market_data);
+        assert!(knock_in > 0.0 && knock_out > 0.0);
+        assert!((knock_in + knock_out - european).abs() < 1e-10,
+            "in={} out={} european={}", knock_in, knock_out, european);
+    }
+
+    #[test]
+    fn barrier_starting_beyond_barrier() {
+
+        // Spot is 100, so a down-and-out with a barrier at 105 has already
+        // knocked out and must be worth nothing. The knock-in is a European.
+        let market_data = sample_market_data();
+        let (knock_out, stderr) = mc_price_with_stderr(
+            sample_barrier(105.0, BarrierDirection::Down, KnockType::Out), &market_data);
+        assert_eq!(knock_out, 0.0);
+        assert_eq!(stderr, 0.0);
+
+        let (knock_in, stderr) = mc_price_with_stderr(
+            sample_barrier(105.0, BarrierDirection::Down, KnockType::In), &market_data);
+        assert!((knock_in - 16.710717400832973).abs() < 3.0 * stderr,
+            "knock_in={} stderr={}", knock_in, stderr);
+    }
+
+    /// Fixings on the first few monitoring dates, known until the day after
+    fn sample_fixings(barrier: &BarrierOption, values: &[f64]) -> FixingTable {
+        let fixed: Vec<(DateTime, f64)> = barrier.monitoring_dates.iter()
+            .zip(values.iter()).map(|(d, v)| (*d, *v)).collect();
+        let today = fixed.last().unwrap().0.date() + 1;
+        FixingTable::from_fixings(today, &[("BP.L", &fixed)]).unwrap()
+    }
+
+    #[test]
+    fn barrier_fix_drops_past_dates() {
+        let barrier = sample_barrier(90.0, BarrierDirection::Down, KnockType::Out);
+        let n_past = barrier.monitoring_dates.iter()
+            .filter(|d| d.date() < Date::from_ymd(2017, 03, 01)).count();
+        let values = vec!(100.0; n_past);
+        let decomp = barrier.fix(&sample_fixings(&barrier, &values)).unwrap().unwrap();
+        assert_eq!(decomp.len(), 1);
+        assert_eq!(decomp[0].1.id(), "SampleBarrier");
+        assert_eq!(decomp[0].1.get_type_id(), "BarrierOption");
+    }
+
+    #[test]
+    fn barrier_fix_keeps_bridge_from_today() {
+
+        // Once the first of quarterly monitoring dates has fixed, the
+        // remaining option is monitored from today, so the bridge must
+        // cover the months up to the next monitoring date. On a driftless
+        // underlying, the bridge makes the price match continuous monitoring.
+        let market_data = driftless_market_data();
+        let weekly = sample_barrier(90.0, BarrierDirection::Down, KnockType::Out);
+        let start = DateTime::new(Date::from_ymd(2016, 12, 01), TimeOfDay::Close);
+        let barrier = BarrierOption::new("SampleBarrier", "OPT",
+            weekly.underlying.clone(), weekly.settlement.clone(), start,
+            weekly.expiry(), 6, 100.0, PutOrCall::Call, 90.0,
+            BarrierDirection::Down, KnockType::Out).unwrap();
+        let decomp = barrier.fix(&sample_fixings(&barrier, &[100.0]))
+            .unwrap().unwrap();
+        assert_eq!(decomp.len(), 1);
+        let remaining = decomp[0].1.clone();
+
+        let model_factory = RcMonteCarloModelFactory::new(Arc::new(BlackDiffusionFactory::new(
+            20, 0.01, 50000, VarianceReduction::None, Some(BASE_SEED)).unwrap()));
+        let pricer = MonteCarloPricer::new(vec!((1.0, remaining)),
+            model_factory, &market_data).unwrap();
+        let (price, stderr) = pricer.price_with_stderr().unwrap();
+
+        // the same dates, but not yet started, miss any crossing before
+        // the first of them
+        let unstarted = BarrierOption::from_dates("SampleBarrier", "OPT",
+            barrier.underlying.clone(), barrier.settlement.clone(),
+            barrier.monitoring_dates[1..].to_vec(), 100.0, PutOrCall::Call,
+            90.0, BarrierDirection::Down, KnockType::Out).unwrap();
+        let analytic = analytic_down_and_out_call(&unstarted, &market_data);
+        let (unstarted_price, _) = mc_price_with_stderr(unstarted, &market_data);
+
+        assert!((price - analytic).abs() < 3.0 * stderr,
+            "price={} stderr={} analytic={}", price, stderr, analytic);
+        assert!(unstarted_price - analytic > 5.0 * stderr,
+            "unstarted={} stderr={} analytic={}", unstarted_price, stderr, analytic);
+    }
+
+    #[test]
+    fn barrier_fix_knocked_out() {
+        let barrier = sample_barrier(90.0, BarrierDirection::Down, KnockType::Out);
+        let decomp = barrier.fix(&sample_fixings(&barrier, &[100.0, 95.0, 89.0]))
+            .unwrap().unwrap();
+        assert!(decomp.is_empty());
+    }
+
+    #[test]
+    fn barrier_fix_knocked_in() {
+        let barrier = sample_barrier(90.0, BarrierDirection::Down, KnockType::In);
+        let decomp = barrier.fix(&sample_fixings(&barrier, &[100.0, 95.0, 89.0]))
+            .unwrap().unwrap();
+        assert_eq!(decomp.len(), 1);
+        assert_eq!(decomp[0].1.get_type_id(), "SpotStartingEuropean");
+    }
+
+    #[test]
+    fn barrier_fix_expired() {
+        let barrier = sample_barrier(90.0, BarrierDirection::Down, KnockType::Out);
+        let mut values = vec!(100.0; barrier.monitoring_dates.len());
+        *values.last_mut().unwrap() = 110.0;
+        let table = sample_fixings(&barrier, &values);
+        let decomp = barrier.fix(&table).unwrap().unwrap();
+        assert_eq!(decomp.len(), 1);
+        assert_eq!(decomp[0].0, 10.0);
+        assert_eq!(decomp[0].1.id(), "SampleBarrier:Expiry");
+    }
+
+    #[test]
+    fn barrier_rejects_bad_inputs() {
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        let equity = RcInstrument::new(Qrc::new(Arc::new(sample_equity(currency, 2))));
+        let start = DateTime::new(Date::from_ymd(2017, 01, 02), TimeOfDay::Open);
+        let expiry = DateTime::new(Date::from_ymd(2018, 06, 01), TimeOfDay::Close);
+        assert!(BarrierOption::new("Bad", "OPT", equity.clone(), sample_settlement(2),
+            expiry, start, 10, 100.0, PutOrCall::Call, 90.0,
+            BarrierDirection::Down, KnockType::Out).is_err());
+        assert!(BarrierOption::new("Bad", "OPT", equity.clone(), sample_settlement(2),
+            start, expiry, 0, 100.0, PutOrCall::Call, 90.0,
+            BarrierDirection::Down, KnockType::Out).is_err());
+        assert!(BarrierOption::new("Bad", "OPT", equity, sample_settlement(2),
+            start, expiry, 10, 100.0, PutOrCall::Call, 0.0,
+            BarrierDirection::Down, KnockType::Out).is_err());
+    }
+
+    #[test]
+    fn barrier_serde() {
+        let barrier = sample_barrier(90.0, BarrierDirection::Down, KnockType::Out);
+        let serialized = serde_json::to_string(&barrier).unwrap();
+        let deserialized: BarrierOption = serde_json::from_str(&serialized).unwrap();
+        let reserialized = serde_json::to_string(&deserialized).unwrap();
+        assert_eq!(serialized, reserialized);
+    }
+}
@@ -12,8 +12,15 @@ use instruments::Priceable;
 use instruments::PricingContext;
 use instruments::DependencyContext;
 use instruments::SpotRequirement;
+use instruments::MonteCarloPriceable;
+use instruments::MonteCarloDependencies;
+use instruments::ObservationKind;
+use instruments::MonteCarloContext;
+use instruments::options::PutOrCall;
+use instruments::bonds::ZeroCoupon;
 use instruments::assets::Currency;
 use instruments::assets::RcCurrency;
+use dates::Date;
 use dates::rules::RcDateRule;
 use dates::datetime::TimeOfDay;
 use dates::datetime::DateTime;
@@ -22,6 +29,9 @@ use data::fixings::FixingTable;
 use core::qm;
 use core::factories::Qrc;
 use core::dedup::InstanceId;
+use ndarray::Axis;
+use ndarray::Array1;
+use ndarray::Array2;
 use erased_serde as esd;
 use serde::Deserialize;
 
@@ -168,9 +178,195 @@ impl Priceable for Basket {
     }
 }
 
+/// A European option on a weighted basket of underlyings, such as equities.
+/// At expiry, it pays the amount by which the weighted sum of the
+/// underlyings is above (call) or below (put) the strike, in cash at the
+/// settlement date following the expiry.
+///
+/// There is no closed form for the value, so it can only be priced by
+/// Monte-Carlo, which needs correlations between the underlyings.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct BasketOption {
+    id: String,
+    credit_id: String,
+    underlyings: Vec<(f64, RcInstrument)>,
+    settlement: RcDateRule,
+    expiry: DateTime,
+    strike: f64,
+    put_or_call: PutOrCall,
+
+    // fields precomputed for performance and simplicity
+    expiry_time: DateDayFraction,
+    pay_date: Date
+}
+
+impl TypeId for BasketOption {
+    fn get_type_id(&self) -> &'static str { "BasketOption" }
+}
+
+impl InstanceId for BasketOption {
+    fn id(&self) -> &str { &self.id }
+}
+
+impl BasketOption {
+    /// Creates a basket option. The underlyings are pairs of weight and
+    /// instrument, and must all be in the same currency. The expiry time is
+    /// taken from the first underlying.
+    pub fn new(
+        id: &str,
+        credit_id: &str,
+        underlyings: Vec<(f64, RcInstrument)>,
+        settlement: RcDateRule,
+        expiry: DateTime,
+        strike: f64,
+        put_or_call: PutOrCall) -> Result<BasketOption, qm::Error> {
+
+        if strike < 0.0 {
+            return Err(qm::Error::new("Strike must be greater or equal to zero"))
+        }
+
+        let expiry_time = {
+            let first = &underlyings.first().ok_or_else(|| qm::Error::new(
+                "A basket option must have at least one underlying"))?.1;
+            let currency = first.payoff_currency();
+            if underlyings.iter().any(|&(_, ref u)| u.payoff_currency() != currency) {
+                return Err(qm::Error::new(
+                    "The underlyings of a basket option must all be in the same currency"))
+            }
+            first.time_to_day_fraction(expiry)?
+        };
+
+        let pay_date = settlement.apply(expiry.date());
+        Ok(BasketOption {
+            id: id.to_string(),
+            credit_id: credit_id.to_string(),
+            underlyings: underlyings,
+            settlement: settlement,
+            expiry: expiry,
+            strike: strike,
+            put_or_call: put_or_call,
+            expiry_time: expiry_time,
+            pay_date: pay_date })
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<Instrument>, esd::Error> {
+        Ok(Qrc::new(Arc::new(BasketOption::deserialize(de)?)))
+    }
+
+    /// The payoff, given the value of the basket at expiry
+    fn intrinsic(&self, basket: f64) -> f64 {
+        match self.put_or_call {
+            PutOrCall::Call => (basket - self.strike).max(0.0),
+            PutOrCall::Put => (self.strike - basket).max(0.0) }
+    }
+
+    /// The cash payment at the pay date
+    fn payment(&self) -> RcInstrument {
+        let currency = RcCurrency::new(Arc::new(self.payoff_currency().clone()));
+        RcInstrument::new(Qrc::new(Arc::new(
+            ZeroCoupon::new(&format!("{}:Expiry", self.id),
+            &self.credit_id, currency, self.expiry, self.pay_date,
+            self.settlement.clone()))))
+    }
+}
+
+impl Instrument for BasketOption {
+    fn payoff_currency(&self) -> &Currency { self.underlyings[0].1.payoff_currency() }
+    fn credit_id(&self) -> &str { &self.credit_id }
+    fn settlement(&self) -> &RcDateRule { &self.settlement }
+    fn as_mc_priceable(&self) -> Option<&MonteCarloPriceable> { Some(self) }
+
+    fn dependencies(&self, context: &mut DependencyContext)
+        -> SpotRequirement {
+
+        // one fixing of each underlying, at expiry
+        let expiry_date = self.expiry.date();
+        for &(_, ref underlying) in self.underlyings.iter() {
+            context.fixing(underlying.id(), self.expiry);
+            context.forward_curve(underlying, expiry_date);
+            context.vol_surface(underlying, expiry_date);
+        }
+        context.yield_curve(self.credit_id(), self.pay_date);
+
+        SpotRequirement::NotRequired
+    }
+
+    fn fix(&self, fixing_table: &FixingTable)
+        -> Result<Option<Vec<(f64, RcInstrument)>>, qm::Error> {
+
+        // All the underlyings fix at the same time, so either all or none of
+        // them are fixed. Once they are, the option becomes a cash payment.
+        let mut basket = 0.0;
+        let mut n_fixed = 0;
+        for &(weight, ref underlying) in self.underlyings.iter() {
+            if let Some(fixing) = fixing_table.get(underlying.id(), self.expiry)? {
+                basket += weight * fixing;
+                n_fixed += 1;
+            }
+        }
+        if n_fixed == 0 {
+            return Ok(None)
+        }
+        if n_fixed != self.underlyings.len() {
+            return Err(qm::Error::new(&format!(
+                "Basket option {} has only some of its underlyings fixed", self.id)))
+        }
+
+        let payment = self.intrinsic(basket);
+        let mut decomp = Vec::new();
+        if payment > 0.0 {
+            decomp.push((payment, self.payment()));
+        }
+        Ok(Some(decomp))
+    }
+}
+
+impl MonteCarloPriceable for BasketOption {
+    fn as_instrument(&self) -> &Instrument { self }
+
+    fn mc_dependencies(&self, _dates: &[DateDayFraction],
+        output: &mut MonteCarloDependencies) -> Result<(), qm::Error> {
+
+        // one observation of each underlying, at expiry, and one cash flow
+        for &(_, ref underlying) in self.underlyings.iter() {
+            output.observation(underlying, self.expiry_time, ObservationKind::Fixing);
+        }
+        output.flow(&self.payment());
+        Ok(())
+    }
+
+    fn start_date(&self) -> Option<DateDayFraction> {
+        None
+    }
+
+    fn mc_price(&self, context: &MonteCarloContext)
+        -> Result<f64, qm::Error> {
+
+        // accumulate the weighted terminal values of the underlyings
+        let mut basket = Array1::zeros(0);
+        for &(weight, ref underlying) in self.underlyings.iter() {
+            let paths = context.paths(underlying)?;
+            assert_eq!(paths.shape()[1], 1);
+            let terminal = paths.subview(Axis(1), 0);
+            if basket.is_empty() {
+                basket = Array1::zeros(terminal.len());
+            }
+            basket.scaled_add(weight, &terminal);
+        }
+
+        let mut quantities = Array2::zeros((basket.len(), 1));
+        for (value, flow) in basket.iter().zip(quantities.iter_mut()) {
+            *flow = self.intrinsic(*value);
+        }
+
+        context.evaluate_flows(quantities.view())
+    }
+}
+
 #[cfg(test)]
 pub mod tests {
     use super::*;
+    use pricers::montecarlo::tests::black_diffusion_price_with_stderr;
     use math::numerics::approx_eq;
     use math::interpolation::Extrap;
     use data::forward::Forward;
@@ -179,11 +375,22 @@ pub mod tests {
     use data::curves::RcRateCurve;
     use dates::Date;
     use data::forward::EquityForward;
+    use data::forward::DividendModel;
     use data::curves::ZeroRateCurve;
     use data::divstream::DividendStream;
     use std::sync::Arc;
     use instruments::assets::tests::sample_currency;
     use instruments::assets::tests::sample_equity;
+    use instruments::PricingContext;
+    use risk::marketdata::MarketData;
+    use risk::marketdata::tests::sample_european;
+    use risk::marketdata::tests::create_sample_divstream;
+    use risk::marketdata::tests::create_sample_rate;
+    use risk::marketdata::tests::create_sample_borrow;
+    use risk::marketdata::tests::create_sample_flat_vol;
+    use data::correlation::CorrelationMatrix;
+    use std::collections::HashMap;
+    use serde_json;
 
     pub fn sample_basket(step: u32) -> Basket {
         let currency = RcCurrency::new(Arc::new(sample_currency(step)));
@@ -233,7 +440,8 @@ pub mod tests {
             let borrow = RcRateCurve::new(Arc::new(ZeroRateCurve::new(base_date)));
             let divs = DividendStream::new(&Vec::new(), RcRateCurve::new(Arc::new(ZeroRateCurve::new(base_date))));
             let forward = EquityForward::new(
-                base_date, spot, settlement, rate, borrow, &divs, high_water_mark)?;
+                base_date, spot, settlement, rate, borrow, &divs, high_water_mark,
+                DividendModel::Discrete)?;
             Ok(Arc::new(forward))
         }
 
@@ -266,6 +474,120 @@ pub mod tests {
         assert_approx(forward, 201.95832229014877);
     }
 
+    fn sample_basket_option(correlation: f64) -> (BasketOption, MarketData) {
+
+        // two underlyings with identical market data
+        let option = sample_option_on(&["BP.L", "BP2.L"]);
+        let market_data = sample_basket_market_data(&["BP.L", "BP2.L"],
+            vec![vec![1.0, correlation], vec![correlation, 1.0]]);
+        (option, market_data)
+    }
+
+    fn sample_option_on(ids: &[&str]) -> BasketOption {
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        let weight = 1.0 / ids.len() as f64;
+        let underlyings: Vec<(f64, RcInstrument)> = ids.iter().map(|id| (weight,
+            RcInstrument::new(Qrc::new(Arc::new(sample_equity(currency.clone(), id, 2))))))
+            .collect();
+        let settlement = underlyings[0].1.settlement().clone();
+        let expiry = DateTime::new(Date::from_ymd(2018, 06, 01), TimeOfDay::Close);
+        BasketOption::new("SampleBasketOption", "LSE", underlyings, settlement,
+            expiry, 100.0, PutOrCall::Call).unwrap()
+    }
+
+    pub fn sample_basket_market_data(ids: &[&str], correlations: Vec<Vec<f64>>)
+        -> MarketData {
+
+        let mut spots = HashMap::new();
+        let mut dividends = HashMap::new();
+        let mut borrow_curves = HashMap::new();
+        let mut vol_surfaces = HashMap::new();
+        for id in ids.iter() {
+            spots.insert(id.to_string(), 100.0);
+            dividends.insert(id.to_string(), create_sample_divstream());
+            borrow_curves.insert(id.to_string(), create_sample_borrow());
+            vol_surfaces.insert(id.to_string(), create_sample_flat_vol());
+        }
+        let mut yield_curves = HashMap::new();
+        yield_curves.insert("OPT".to_string(), create_sample_rate());
+        yield_curves.insert("LSE".to_string(), create_sample_rate());
+
+        let mut market_data = MarketData::new(Date::from_ymd(2017, 01, 02), spots,
+            yield_curves, borrow_curves, dividends, vol_surfaces);
+        market_data.set_correlations(CorrelationMatrix::new(ids, correlations).unwrap());
+        market_data
+    }
+
+    fn mc_price_with_stderr(option: BasketOption, market_data: &MarketData)
+        -> Result<(f64, f64), qm::Error> {
+        let instrument = RcInstrument::new(Qrc::new(Arc::new(option)));
+        black_diffusion_price_with_stderr(instrument, market_data, 0.01, 20000)
+    }
+
+    #[test]
+    fn perfectly_correlated_basket_matches_european() {
+
+        // With correlation one, the two identical underlyings move together,
+        // so the basket is just a single asset, and the option has the same
+        // value as a European on that asset. The sample European is cash
+        // settled, paying on the same date as the basket option.
+        let (option, market_data) = sample_basket_option(1.0);
+        let (price, stderr) = mc_price_with_stderr(option, &market_data).unwrap();
+        let val_date = DateTime::new(market_data.spot_date(), TimeOfDay::Open);
+        let european = sample_european().price(&market_data, val_date).unwrap();
+        assert_approx(european, 16.710717400832973);
+        assert!((price - european).abs() < 3.0 * stderr,
+            "price={} european={} stderr={}", price, european, stderr);
+
+        // with less correlation, the basket is less volatile, so it is cheaper
+        let (option, market_data) = sample_basket_option(0.0);
+        let (uncorrelated, stderr) = mc_price_with_stderr(option, &market_data).unwrap();
+        assert!(uncorrelated < european - 10.0 * stderr,
+            "uncorrelated={} european={} stderr={}", uncorrelated, european, stderr);
+    }
+
+    #[test]
+    fn basket_option_rejects_invalid_correlation() {
+
+        // three pairwise correlations that cannot all hold at once
+        let ids = ["BP.L", "BP2.L", "BP3.L"];
+        let option = sample_option_on(&ids);
+        let market_data = sample_basket_market_data(&ids,
+            vec![vec![1.0, 0.9, -0.9], vec![0.9, 1.0, 0.9], vec![-0.9, 0.9, 1.0]]);
+
+        let error = mc_price_with_stderr(option, &market_data).unwrap_err();
+        assert!(error.to_string().contains("not positive semi-definite"),
+            "error={}", error);
+    }
+
+    #[test]
+    fn basket_option_fix() {
+        let (option, _) = sample_basket_option(1.0);
+        let fixings = FixingTable::from_fixings(Date::from_ymd(2018, 06, 02), &[
+            ("BP.L", &[(option.expiry, 110.0)]),
+            ("BP2.L", &[(option.expiry, 120.0)])]).unwrap();
+        let decomp = option.fix(&fixings).unwrap().unwrap();
+        assert_eq!(decomp.len(), 1);
+        assert_approx(decomp[0].0, 15.0);
+        assert_eq!(decomp[0].1.id(), "SampleBasketOption:Expiry");
+
+        let fixings = FixingTable::from_fixings(Date::from_ymd(2018, 06, 02), &[
+            ("BP.L", &[(option.expiry, 110.0)])]).unwrap();
+        assert!(option.fix(&fixings).is_err());
+
+        let fixings = FixingTable::from_fixings(Date::from_ymd(2017, 01, 02), &[]).unwrap();
+        assert!(option.fix(&fixings).unwrap().is_none());
+    }
+
+    #[test]
+    fn basket_option_serde() {
+        let (option, _) = sample_basket_option(1.0);
+        let serialized = serde_json::to_string(&option).unwrap();
+        let deserialized: BasketOption = serde_json::from_str(&serialized).unwrap();
+        let reserialized = serde_json::to_string(&deserialized).unwrap();
+        assert_eq!(serialized, reserialized);
+    }
+
     fn assert_approx(value: f64, expected: f64) {
         assert!(approx_eq(value, expected, 1e-12),
             "value={} expected={}", value, expected);
@@ -0,0 +1,300 @@
+use std::sync::Arc;
+use instruments::Instrument;
+use instruments::RcInstrument;
+use instruments::DependencyContext;
+use instruments::SpotRequirement;
+use instruments::MonteCarloPriceable;
+use instruments::MonteCarloDependencies;
+use instruments::ObservationKind;
+use instruments::MonteCarloContext;
+use instruments::options::PutOrCall;
+use instruments::american::longstaff_schwartz;
+use instruments::assets::Currency;
+use instruments::assets::RcCurrency;
+use instruments::bonds::ZeroCoupon;
+use data::fixings::FixingTable;
+use dates::rules::RcDateRule;
+use dates::datetime::DateTime;
+use dates::datetime::DateDayFraction;
+use core::qm;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use core::dedup::InstanceId;
+use erased_serde as esd;
+use serde::Deserialize;
+
+/// A Bermudan option may be exercised on any one of a discrete schedule of
+/// exercise dates, the last of which is the expiry. On exercise, a call pays
+/// (S-K) and a put pays (K-S), settled in cash at the settlement date
+/// following the exercise.
+///
+/// It is priced by Monte-Carlo using the same Longstaff-Schwartz algorithm
+/// as AmericanOption, but continuation is only compared with exercise on
+/// the scheduled dates. With a single exercise date, it is a European.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct BermudanOption {
+    id: String,
+    credit_id: String,
+    underlying: RcInstrument,
+    settlement: RcDateRule,
+    exercise_dates: Vec<DateTime>,
+    strike: f64,
+    put_or_call: PutOrCall,
+    basis_degree: usize,
+
+    // fields precomputed for performance and simplicity
+    exercise_times: Vec<DateDayFraction>
+}
+
+impl TypeId for BermudanOption {
+    fn get_type_id(&self) -> &'static str { "BermudanOption" }
+}
+
+impl InstanceId for BermudanOption {
+    fn id(&self) -> &str { &self.id }
+}
+
+impl BermudanOption {
+    /// Creates a Bermudan option. The exercise dates must be in strictly
+    /// increasing order, and the last of them is the expiry. The
+    /// basis_degree is the degree of the polynomial used in the
+    /// Longstaff-Schwartz regression.
+    pub fn new(
+        id: &str,
+        credit_id: &str,
+        underlying: RcInstrument,
+        settlement: RcDateRule,
+        exercise_dates: &[DateTime],
+        strike: f64,
+        put_or_call: PutOrCall,
+        basis_degree: usize)
+        -> Result<BermudanOption, qm::Error> {
+
+        if exercise_dates.is_empty() {
+            return Err(qm::Error::new("There must be at least one exercise date"))
+        }
+        for pair in exercise_dates.windows(2) {
+            if pair[1] <= pair[0] {
+                return Err(qm::Error::new(&format!(
+                    "Exercise dates must be strictly increasing: {} is not after {}",
+                    pair[1], pair[0])))
+            }
+        }
+        if strike < 0.0 {
+            return Err(qm::Error::new("Strike must be greater or equal to zero"))
+        }
+
+        let mut exercise_times = Vec::with_capacity(exercise_dates.len());
+        for date in exercise_dates.iter() {
+            exercise_times.push(underlying.time_to_day_fraction(*date)?);
+        }
+
+        Ok(BermudanOption {
+            id: id.to_string(),
+            credit_id: credit_id.to_string(),
+            underlying: underlying,
+            settlement: settlement,
+            exercise_dates: exercise_dates.to_vec(),
+            strike: strike,
+            put_or_call: put_or_call,
+            basis_degree: basis_degree,
+            exercise_times: exercise_times })
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<Instrument>, esd::Error> {
+        Ok(Qrc::new(Arc::new(BermudanOption::deserialize(de)?)))
+    }
+
+    pub fn exercise_dates(&self) -> &[DateTime] { &self.exercise_dates }
+
+    fn expiry(&self) -> DateTime {
+        *self.exercise_dates.last().unwrap()
+    }
+
+    fn intrinsic(&self, spot: f64) -> f64 {
+        match self.put_or_call {
+            PutOrCall::Call => (spot - self.strike).max(0.0),
+            PutOrCall::Put => (self.strike - spot).max(0.0) }
+    }
+
+    /// The cash payment resulting from exercise on the given date
+    fn payment(&self, exercise: DateTime) -> RcInstrument {
+        let currency = RcCurrency::new(Arc::new(self.payoff_currency().clone()));
+        RcInstrument::new(Qrc::new(Arc::new(ZeroCoupon::new(
+            &format!("{}:Exercise:{}", self.id, exercise),
+            &self.credit_id, currency, exercise,
+            self.settlement.apply(exercise.date()),
+            self.settlement.clone()))))
+    }
+}
+
+impl Instrument for BermudanOption {
+    fn payoff_currency(&self) -> &Currency { self.underlying.payoff_currency() }
+    fn credit_id(&self) -> &str { &self.credit_id }
+    fn settlement(&self) -> &RcDateRule { &self.settlement }
+    fn as_mc_priceable(&self) -> Option<&MonteCarloPriceable> { Some(self) }
+
+    fn dependencies(&self, context: &mut DependencyContext)
+        -> SpotRequirement {
+
+        // As for the American, the only fixing we need is at expiry.
+        // Earlier exercise dates that have passed are assumed not to have
+        // been exercised.
+        let expiry = self.expiry();
+        context.fixing(self.underlying.id(), expiry);
+
+        let expiry_date = expiry.date();
+        context.yield_curve(self.credit_id(), self.settlement.apply(expiry_date));
+        context.forward_curve(&self.underlying, expiry_date);
+        context.vol_surface(&self.underlying, expiry_date);
+
+        SpotRequirement::NotRequired
+    }
+
+    fn fix(&self, fixing_table: &FixingTable)
+        -> Result<Option<Vec<(f64, RcInstrument)>>, qm::Error> {
+
+        // If the expiry has fixed, the option turns into a cash payment, or
+        // nothing at all if it expired out of the money.
+        let expiry = self.expiry();
+        if let Some(spot) = fixing_table.get(self.underlying.id(), expiry)? {
+            let mut decomp = Vec::new();
+            let payment = self.intrinsic(spot);
+            if payment > 0.0 {
+                decomp.push((payment, self.payment(expiry)));
+            }
+            return Ok(Some(decomp))
+        }
+
+        // Otherwise, drop any exercise dates that are in the past.
+        let known_until = fixing_table.fixings_known_until();
+        let id = self.underlying.id();
+        let remaining: Vec<DateTime> = self.exercise_dates.iter()
+            .filter(|d| d.date() >= known_until
+                && fixing_table.get_optional(id, **d).is_none())
+            .cloned().collect();
+        if remaining.len() == self.exercise_dates.len() {
+            return Ok(None)
+        }
+
+        let unexercised = BermudanOption::new(&self.id, &self.credit_id,
+            self.underlying.clone(), self.settlement.clone(), &remaining,
+            self.strike, self.put_or_call, self.basis_degree)?;
+        Ok(Some(vec!((1.0, RcInstrument::new(Qrc::new(Arc::new(unexercised)))))))
+    }
+}
+
+impl MonteCarloPriceable for BermudanOption {
+    fn as_instrument(&self) -> &Instrument { self }
+
+    fn mc_dependencies(&self, _dates: &[DateDayFraction],
+        output: &mut MonteCarloDependencies) -> Result<(), qm::Error> {
+
+        // an observation and a potential payment on each exercise date, so
+        // the timeline only has the scheduled dates to regress on
+        for (date, time) in self.exercise_dates.iter().zip(self.exercise_times.iter()) {
+            output.observation(&self.underlying, *time, ObservationKind::Exercise);
+            output.flow(&self.payment(*date));
+        }
+        Ok(())
+    }
+
+    fn start_date(&self) -> Option<DateDayFraction> {
+        None
+    }
+
+    fn mc_price(&self, context: &MonteCarloContext)
+        -> Result<f64, qm::Error> {
+
+        let payments: Vec<RcInstrument> = self.exercise_dates.iter()
+            .map(|date| self.payment(*date)).collect();
+        longstaff_schwartz(context, &self.underlying, &payments,
+            &|spot| self.intrinsic(spot), self.strike, self.basis_degree)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use models::random::BASE_SEED;
+    use dates::Date;
+    use dates::datetime::TimeOfDay;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::marketdata::tests::sample_currency;
+    use risk::marketdata::tests::sample_settlement;
+    use risk::marketdata::tests::sample_equity;
+    use instruments::options::SpotStartingEuropean;
+    use instruments::options::OptionSettlement;
+    use models::RcMonteCarloModelFactory;
+    use models::VarianceReduction;
+    use models::blackdiffusion::BlackDiffusionFactory;
+    use pricers::montecarlo::MonteCarloPricer;
+    use risk::Pricer;
+
+    fn sample_equity_instrument() -> RcInstrument {
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        RcInstrument::new(Qrc::new(Arc::new(sample_equity(currency, 2))))
+    }
+
+    fn sample_bermudan_put(id: &str, exercise_dates: &[DateTime], strike: f64)
+        -> RcInstrument {
+        RcInstrument::new(Qrc::new(Arc::new(BermudanOption::new(id, "OPT",
+            sample_equity_instrument(), sample_settlement(2), exercise_dates,
+            strike, PutOrCall::Put, 3).unwrap())))
+    }
+
+    /// Prices the instrument by Monte-Carlo. The paths are seeded, so
+    /// instruments with the same timeline are priced on the same paths.
+    fn mc_price(instrument: RcInstrument) -> f64 {
+        let model_factory = RcMonteCarloModelFactory::new(Arc::new(BlackDiffusionFactory::new(
+            20, 0.01, 50000, VarianceReduction::Antithetic, Some(BASE_SEED)).unwrap()));
+        let pricer = MonteCarloPricer::new(vec!((1.0, instrument)),
+            model_factory, &sample_market_data()).unwrap();
+        pricer.price().unwrap()
+    }
+
+    #[test]
+    fn bermudan_single_exercise_is_european() {
+
+        let expiry = DateTime::new(Date::from_ymd(2018, 06, 01), TimeOfDay::Close);
+        let bermudan = sample_bermudan_put("SingleBermudan", &[expiry], 100.0);
+        let european = RcInstrument::new(Qrc::new(Arc::new(SpotStartingEuropean::new(
+            "SampleEuropeanPut", "OPT", sample_equity_instrument(), sample_settlement(2),
+            expiry, 100.0, PutOrCall::Put, OptionSettlement::Cash).unwrap())));
+
+        // both observe once at expiry, so they see the same paths, and with
+        // nothing to regress the prices match
+        let prices = [mc_price(bermudan), mc_price(european)];
+        assert!(prices[0] > 0.0, "prices={:?}", prices);
+        assert!((prices[0] - prices[1]).abs() < 1e-10, "prices={:?}", prices);
+    }
+
+    #[test]
+    fn bermudan_put_increases_with_exercise_dates() {
+
+        // nested schedules, each adding dates to the one before, all ending
+        // at the same expiry. The put is in the money and rates are high,
+        // so early exercise is worth far more than the Monte-Carlo noise.
+        let expiry = Date::from_ymd(2018, 06, 01);
+        let schedule = |n: i32| -> Vec<DateTime> {
+            (1..n + 1).map(|i| DateTime::new(expiry - (n - i) * 512 / n, TimeOfDay::Close))
+                .collect()
+        };
+        let prices: Vec<f64> = [1, 2, 4, 8].iter().map(|&n| mc_price(sample_bermudan_put(
+            &format!("Bermudan{}", n), &schedule(n), 120.0))).collect();
+        for pair in prices.windows(2) {
+            assert!(pair[1] > pair[0], "prices={:?}", prices);
+        }
+    }
+
+    #[test]
+    fn bermudan_requires_increasing_dates() {
+        let expiry = DateTime::new(Date::from_ymd(2018, 06, 01), TimeOfDay::Close);
+        let earlier = DateTime::new(Date::from_ymd(2017, 06, 01), TimeOfDay::Close);
+        let equity = sample_equity_instrument();
+        assert!(BermudanOption::new("B", "OPT", equity.clone(), sample_settlement(2),
+            &[expiry, earlier], 100.0, PutOrCall::Put, 3).is_err());
+        assert!(BermudanOption::new("B", "OPT", equity, sample_settlement(2),
+            &[], 100.0, PutOrCall::Put, 3).is_err());
+    }
+}
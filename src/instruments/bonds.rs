@@ -141,6 +141,124 @@ impl Priceable for ZeroCoupon {
     }
 }
 
+/// A single known cashflow within a CashflowStream. The amount is paid on
+/// the payment date to whoever holds the stream at the ex date.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct Cashflow {
+    ex_date: DateTime,
+    payment_date: Date,
+    amount: f64
+}
+
+impl Cashflow {
+    pub fn new(ex_date: DateTime, payment_date: Date, amount: f64) -> Cashflow {
+        Cashflow { ex_date: ex_date, payment_date: payment_date, amount: amount }
+    }
+
+    pub fn ex_date(&self) -> DateTime { self.ex_date }
+    pub fn payment_date(&self) -> Date { self.payment_date }
+    pub fn amount(&self) -> f64 { self.amount }
+}
+
+/// A stream of known cashflows in a single currency, such as the fixed leg
+/// of a swap, or a floating leg whose rates have all been set. It is valued
+/// analytically, by discounting each cashflow off the yield curve matching
+/// its credit id, so it needs no simulation even when it is valued within a
+/// Monte-Carlo pricer.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct CashflowStream {
+    id: String,
+    credit_id: String,
+    currency: RcCurrency,
+    settlement: RcDateRule,
+    cashflows: Vec<Cashflow>
+}
+
+impl TypeId for CashflowStream {
+    fn get_type_id(&self) -> &'static str { "CashflowStream" }
+}
+
+impl InstanceId for CashflowStream {
+    fn id(&self) -> &str { &self.id }
+}
+
+impl CashflowStream {
+    /// Creates a cashflow stream. The cashflows must be in increasing order
+    /// of payment date. As with a zero coupon, the settlement rule is only
+    /// used to find the date to discount to.
+    pub fn new(id: &str, credit_id: &str, currency: RcCurrency,
+        settlement: RcDateRule, cashflows: Vec<Cashflow>)
+        -> Result<CashflowStream, qm::Error> {
+
+        if cashflows.is_empty() {
+            return Err(qm::Error::new("A cashflow stream must have at least one cashflow"))
+        }
+        for pair in cashflows.windows(2) {
+            if pair[0].payment_date > pair[1].payment_date {
+                return Err(qm::Error::new(
+                    "Cashflows must be in increasing order of payment date"))
+            }
+        }
+
+        Ok(CashflowStream { id: id.to_string(), credit_id: credit_id.to_string(),
+            currency: currency, settlement: settlement, cashflows: cashflows })
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<Instrument>, esd::Error> {
+        Ok(Qrc::new(Arc::new(CashflowStream::deserialize(de)?)))
+    }
+
+    pub fn cashflows(&self) -> &[Cashflow] { &self.cashflows }
+
+    fn last_payment_date(&self) -> Date {
+        // we validate on construction that there is at least one cashflow
+        self.cashflows.last().unwrap().payment_date
+    }
+}
+
+impl Instrument for CashflowStream {
+    fn payoff_currency(&self) -> &Currency { &*self.currency }
+    fn credit_id(&self) -> &str { &self.credit_id }
+    fn settlement(&self) -> &RcDateRule { &self.settlement }
+
+    fn dependencies(&self, context: &mut DependencyContext)
+        -> SpotRequirement {
+        context.yield_curve(&self.credit_id, self.last_payment_date());
+        SpotRequirement::NotRequired
+    }
+
+    fn is_pure_rates(&self) -> bool {
+        true
+    }
+
+    fn as_priceable(&self) -> Option<&Priceable> {
+        Some(self)
+    }
+}
+
+impl Priceable for CashflowStream {
+    fn as_instrument(&self) -> &Instrument { self }
+
+    /// The sum of the discounted cashflows that have not yet gone ex.
+    fn prices(&self, context: &PricingContext, dates: &[DateTime], out: &mut [f64])
+        -> Result<(), qm::Error> {
+        assert_eq!(dates.len(), out.len());
+
+        let yc = context.yield_curve(&self.credit_id, self.last_payment_date())?;
+
+        for (date, output) in dates.iter().zip(out.iter_mut()) {
+            let settlement_date = self.settlement.apply(date.date());
+            let mut total = 0.0;
+            for cashflow in self.cashflows.iter().filter(|c| *date <= c.ex_date) {
+                total += cashflow.amount * yc.df(cashflow.payment_date, settlement_date)?;
+            }
+            *output = total;
+        }
+
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -226,6 +344,50 @@ mod tests {
         assert_approx(price, 0.9930885737840461);
     }
 
+    #[test]
+    fn cashflow_stream() {
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        let calendar = RcCalendar::new(Arc::new(WeekdayCalendar::new()));
+        let settlement = RcDateRule::new(Arc::new(BusinessDays::new_step(calendar, 2)));
+        let flows = [(Date::from_ymd(2018, 07, 05), 5.0),
+            (Date::from_ymd(2018, 10, 05), 5.0), (Date::from_ymd(2019, 01, 07), 105.0)];
+        let cashflows = flows.iter().map(|&(pay, amount)| Cashflow::new(
+            DateTime::new(pay - 2, TimeOfDay::Open), pay, amount)).collect();
+        let stream = CashflowStream::new("SampleStream", "OPT", currency,
+            settlement.clone(), cashflows).unwrap();
+
+        // the stream is worth the sum of its discounted cashflows
+        let context = sample_pricing_context();
+        let yc = context.yield_curve("OPT", Date::from_ymd(2019, 01, 07)).unwrap();
+        let val_date = DateTime::new(Date::from_ymd(2018, 06, 05), TimeOfDay::Open);
+        let settlement_date = settlement.apply(val_date.date());
+        let expected: f64 = flows.iter().map(|&(pay, amount)|
+            amount * yc.df(pay, settlement_date).unwrap()).sum();
+        assert_approx(stream.price(&context, val_date).unwrap(), expected);
+
+        // once the first cashflow has gone ex, it is no longer included
+        let val_date = DateTime::new(Date::from_ymd(2018, 07, 04), TimeOfDay::Open);
+        let settlement_date = settlement.apply(val_date.date());
+        let expected: f64 = flows[1..].iter().map(|&(pay, amount)|
+            amount * yc.df(pay, settlement_date).unwrap()).sum();
+        assert_approx(stream.price(&context, val_date).unwrap(), expected);
+    }
+
+    #[test]
+    fn cashflow_stream_must_be_ordered() {
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        let calendar = RcCalendar::new(Arc::new(WeekdayCalendar::new()));
+        let settlement = RcDateRule::new(Arc::new(BusinessDays::new_step(calendar, 2)));
+        let ex_date = DateTime::new(Date::from_ymd(2018, 07, 03), TimeOfDay::Open);
+        let cashflows = vec![
+            Cashflow::new(ex_date, Date::from_ymd(2018, 10, 05), 5.0),
+            Cashflow::new(ex_date, Date::from_ymd(2018, 07, 05), 5.0)];
+        assert!(CashflowStream::new("SampleStream", "OPT", currency.clone(),
+            settlement.clone(), cashflows).is_err());
+        assert!(CashflowStream::new("SampleStream", "OPT", currency,
+            settlement, Vec::new()).is_err());
+    }
+
     fn assert_approx(value: f64, expected: f64) {
         assert!(approx_eq(value, expected, 1e-12),
             "value={} expected={}", value, expected);
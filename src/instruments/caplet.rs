@@ -0,0 +1,531 @@
+use std::sync::Arc;
+use instruments::Instrument;
+use instruments::RcInstrument;
+use instruments::Priceable;
+use instruments::PricingContext;
+use instruments::DependencyContext;
+use instruments::SpotRequirement;
+use instruments::assets::Currency;
+use instruments::assets::RcCurrency;
+use instruments::bonds::ZeroCoupon;
+use instruments::options::PutOrCall;
+use math::optionpricing::Black76;
+use math::optionpricing::Bachelier;
+use dates::Date;
+use dates::datetime::DateTime;
+use dates::datetime::DateDayFraction;
+use dates::datetime::TimeOfDay;
+use dates::rules::RcDateRule;
+use data::fixings::FixingTable;
+use core::qm;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use core::dedup::InstanceId;
+use serde::Deserialize;
+use erased_serde as esd;
+
+/// A caplet pays notional * tau * max(L - K, 0) at the end of an accrual
+/// period, where L is the simple forward rate over the period, fixed on the
+/// fixing date, tau is the length of the period in years and K is the
+/// strike rate. A floorlet pays notional * tau * max(K - L, 0). We use
+/// PutOrCall to distinguish them, with a call being a caplet.
+///
+/// The caplet is priced analytically, by default with the Black-76 formula.
+/// For negative-rate environments, it can instead be priced with the
+/// Bachelier formula, which is normal in the rate. The forward rate is
+/// implied by the yield curve matching the credit id, which is also used for
+/// discounting from the payment date. The caplet vol is the vol surface in
+/// the market data with the caplet's id, so vol bumps reach it, and the rate
+/// fixing is also recorded against that id. Once the rate has fixed, the
+/// caplet turns into a zero coupon paying the fixed amount. Accrual is
+/// measured as Act/365.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct Caplet {
+    id: String,
+    credit_id: String,
+    currency: RcCurrency,
+    settlement: RcDateRule,
+    fixing_date: DateTime,
+    accrual_start: Date,
+    accrual_end: Date,
+    strike: f64,
+    notional: f64,
+    cap_or_floor: PutOrCall,
+    #[serde(default)]
+    dynamics: RateDynamics
+}
+
+/// The distribution of the rate at the fixing date, which determines how
+/// the caplet vol is interpreted. A lognormal vol is relative to the rate,
+/// as in Black-76, and requires positive forwards and strikes. A normal vol
+/// is in the same units as the rate, as in Bachelier, and allows them to be
+/// zero or negative.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RateDynamics { Lognormal, Normal }
+
+impl Default for RateDynamics {
+    fn default() -> RateDynamics { RateDynamics::Lognormal }
+}
+
+impl TypeId for Caplet {
+    fn get_type_id(&self) -> &'static str { "Caplet" }
+}
+
+impl InstanceId for Caplet {
+    fn id(&self) -> &str { &self.id }
+}
+
+impl Caplet {
+    /// Creates a caplet or floorlet. It pays at the end of the accrual
+    /// period, which must be after the start. With lognormal dynamics, the strike must be positive, but with normal
+    /// dynamics it may be any finite rate. As with a zero coupon, the
+    /// settlement rule is only used to find the date to discount to.
+    pub fn new(id: &str, credit_id: &str, currency: RcCurrency,
+        settlement: RcDateRule, fixing_date: DateTime, accrual_start: Date,
+        accrual_end: Date, strike: f64, notional: f64,
+        cap_or_floor: PutOrCall, dynamics: RateDynamics)
+        -> Result<Caplet, qm::Error> {
+
+        if accrual_end <= accrual_start {
+            return Err(qm::Error::new(&format!("Accrual end {} must be after \
+                accrual start {}", accrual_end, accrual_start)))
+        }
+        match dynamics {
+            RateDynamics::Lognormal => if !(strike > 0.0) {
+                return Err(qm::Error::new("Caplet strike must be positive \
+                    with lognormal dynamics"))
+            },
+            RateDynamics::Normal => if !strike.is_finite() {
+                return Err(qm::Error::new("Caplet strike must be finite"))
+            }
+        }
+        Ok(Caplet { id: id.to_string(), credit_id: credit_id.to_string(),
+            currency: currency, settlement: settlement,
+            fixing_date: fixing_date, accrual_start: accrual_start,
+            accrual_end: accrual_end, strike: strike, notional: notional,
+            cap_or_floor: cap_or_floor, dynamics: dynamics })
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<Instrument>, esd::Error> {
+        Ok(Qrc::new(Arc::new(Caplet::deserialize(de)?)))
+    }
+
+    pub fn fixing_date(&self) -> DateTime { self.fixing_date }
+    pub fn payment_date(&self) -> Date { self.accrual_end }
+    pub fn strike(&self) -> f64 { self.strike }
+    pub fn dynamics(&self) -> RateDynamics { self.dynamics }
+
+    /// The length of the accrual period in years
+    pub fn accrual(&self) -> f64 {
+        (self.accrual_end - self.accrual_start) as f64 / 365.0
+    }
+
+    /// Undiscounted value per unit notional and accrual if the rate fixed
+    /// at the given forward
+    fn intrinsic(&self, forward: f64) -> f64 {
+        match self.cap_or_floor {
+            PutOrCall::Call => (forward - self.strike).max(0.0),
+            PutOrCall::Put => (self.strike - forward).max(0.0)
+        }
+    }
+}
+
+impl Instrument for Caplet {
+    fn payoff_currency(&self) -> &Currency { &*self.currency }
+    fn credit_id(&self) -> &str { &self.credit_id }
+    fn settlement(&self) -> &RcDateRule { &self.settlement }
+
+    fn dependencies(&self, context: &mut DependencyContext)
+        -> SpotRequirement {
+        context.yield_curve(&self.credit_id, self.accrual_end);
+
+        // the vol surface and the rate fixing are both keyed on the caplet
+        let fixing_date = self.fixing_date.date();
+        context.vol_surface(&RcInstrument::new(Qrc::new(Arc::new(self.clone()))),
+            fixing_date);
+        context.fixing(&self.id, self.fixing_date);
+        SpotRequirement::NotRequired
+    }
+
+    fn time_to_day_fraction(&self, date_time: DateTime)
+        -> Result<DateDayFraction, qm::Error> {
+
+        // the same conversion as for equities
+        let day_fraction = match date_time.time_of_day() {
+            TimeOfDay::Open => 0.0,
+            TimeOfDay::EDSP => 0.0,
+            TimeOfDay::Close => 0.8 };
+        Ok(DateDayFraction::new(date_time.date(), day_fraction))
+    }
+
+    fn fix(&self, fixing_table: &FixingTable)
+        -> Result<Option<Vec<(f64, RcInstrument)>>, qm::Error> {
+
+        // once the rate has fixed, the caplet pays a known amount at the end
+        // of the accrual period, if anything
+        match fixing_table.get(&self.id, self.fixing_date)? {
+            None => Ok(None),
+            Some(rate) => {
+                let mut decomp: Vec<(f64, RcInstrument)> = Vec::new();
+                let payment = self.notional * self.accrual() * self.intrinsic(rate);
+                if payment != 0.0 {
+                    let payment_id = format!("{}:payment", self.id);
+                    decomp.push((payment, RcInstrument::new(Qrc::new(Arc::new(
+                        ZeroCoupon::new(&payment_id, &self.credit_id,
+                            self.currency.clone(), self.fixing_date,
+                            self.accrual_end, self.settlement.clone()))))));
+                }
+                Ok(Some(decomp))
+            }
+        }
+    }
+
+    fn is_pure_rates(&self) -> bool {
+        // the caplet vol is not the vol of any simulated underlying, so the
+        // caplet can still be valued off market data during Monte-Carlo
+        true
+    }
+
+    fn as_priceable(&self) -> Option<&Priceable> {
+        Some(self)
+    }
+}
+
+impl Priceable for Caplet {
+    fn as_instrument(&self) -> &Instrument { self }
+
+    /// The Black-76 or Bachelier value of the caplet, discounted from the
+    /// payment date, using the variance of the caplet vol surface up to the
+    /// fixing. On or after the fixing date, there is no variance left, so the
+    /// caplet is worth its intrinsic value off the forward rate. Once it has
+    /// paid, it is worth nothing.
+    fn prices(&self, context: &PricingContext, dates: &[DateTime], out: &mut [f64])
+        -> Result<(), qm::Error> {
+        assert_eq!(dates.len(), out.len());
+
+        let yc = context.yield_curve(&self.credit_id, self.accrual_end)?;
+        let tau = self.accrual();
+        let forward = (1.0 / yc.df(self.accrual_end, self.accrual_start)? - 1.0) / tau;
+        let vol = context.vol_surface(self, self.fixing_date.date(),
+            &|| Err(qm::Error::new("Caplet vols do not depend on a forward")))?;
+        let fixing_time = self.time_to_day_fraction(self.fixing_date)?;
+        let black76 = Black76::new()?;
+        let bachelier = Bachelier::new()?;
+
+        for (date, output) in dates.iter().zip(out.iter_mut()) {
+            if date.date() > self.accrual_end {
+                *output = 0.0;
+                continue;
+            }
+
+            let settlement_date = self.settlement.apply(date.date());
+            let df = yc.df(self.accrual_end, settlement_date)?;
+            let variance = if *date < self.fixing_date {
+                let val_time = self.time_to_day_fraction(*date)?;
+                vol.forward_variance(val_time, fixing_time, self.strike)?
+            } else {
+                0.0
+            };
+            if variance < 0.0 {
+                return Err(qm::Error::new("Negative variance"));
+            }
+            let sqrt_variance = variance.sqrt();
+
+            let value = match self.dynamics {
+                RateDynamics::Lognormal if !(sqrt_variance > 0.0)
+                    || !(forward > 0.0) => df * self.intrinsic(forward),
+                RateDynamics::Lognormal => match self.cap_or_floor {
+                    PutOrCall::Call => black76.call_price(df, forward,
+                        self.strike, sqrt_variance),
+                    PutOrCall::Put => black76.put_price(df, forward,
+                        self.strike, sqrt_variance)
+                },
+                RateDynamics::Normal => match self.cap_or_floor {
+                    PutOrCall::Call => bachelier.call_price(df, forward,
+                        self.strike, sqrt_variance),
+                    PutOrCall::Put => bachelier.put_price(df, forward,
+                        self.strike, sqrt_variance)
+                }
+            };
+            *output = self.notional * tau * value;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use serde_json;
+    use math::numerics::approx_eq;
+    use dates::calendar::WeekdayCalendar;
+    use dates::calendar::RcCalendar;
+    use data::bump::Bump;
+    use data::bumpyield::BumpYield;
+    use data::bumpvol::BumpVol;
+    use data::volsurface::FlatVolSurface;
+    use data::volsurface::RcVolSurface;
+    use data::volsurface::VolSurface;
+    use pricers::selfpricer::SelfPricer;
+    use risk::Pricer;
+    use risk::Bumpable;
+    use risk::marketdata::MarketData;
+    use risk::marketdata::tests::create_sample_rate;
+    use risk::marketdata::tests::sample_currency;
+    use risk::marketdata::tests::sample_settlement;
+
+    fn sample_caplet(cap_or_floor: PutOrCall, strike: f64) -> Caplet {
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        Caplet::new("SampleCaplet", "OPT", currency, sample_settlement(2),
+            DateTime::new(Date::from_ymd(2018, 01, 02), TimeOfDay::Close),
+            Date::from_ymd(2018, 01, 04), Date::from_ymd(2018, 07, 04),
+            strike, 1000000.0, cap_or_floor, RateDynamics::Lognormal).unwrap()
+    }
+
+    fn flat_vol(vol: f64) -> RcVolSurface {
+        let calendar = RcCalendar::new(Arc::new(WeekdayCalendar()));
+        let base = DateDayFraction::new(Date::from_ymd(2016, 12, 30), 0.2);
+        RcVolSurface::new(Arc::new(FlatVolSurface::new(vol, calendar, base)))
+    }
+
+    /// Market data with the sample yield curve and a lognormal vol of 20%
+    /// for the sample caplet, plus a normal vol of 100bp for a caplet with
+    /// id "NormalCaplet"
+    fn sample_market_data() -> MarketData {
+        let mut yield_curves = HashMap::new();
+        yield_curves.insert("OPT".to_string(), create_sample_rate());
+
+        let mut vol_surfaces = HashMap::new();
+        vol_surfaces.insert("SampleCaplet".to_string(), flat_vol(0.2));
+        vol_surfaces.insert("NormalCaplet".to_string(), flat_vol(0.01));
+
+        MarketData::new(Date::from_ymd(2017, 01, 02), HashMap::new(),
+            yield_curves, HashMap::new(), HashMap::new(), vol_surfaces)
+    }
+
+    #[test]
+    fn caplet_matches_black76() {
+
+        let market_data = sample_market_data();
+        let caplet = sample_caplet(PutOrCall::Call, 0.08);
+        let val_date = DateTime::new(Date::from_ymd(2017, 01, 02), TimeOfDay::Open);
+        let price = caplet.price(&market_data, val_date).unwrap();
+
+        // the forward rate over the accrual period, and the caplet as an
+        // option on it, paid at the end of the period
+        let start = Date::from_ymd(2018, 01, 04);
+        let end = Date::from_ymd(2018, 07, 04);
+        let yc = market_data.yield_curve("OPT", end).unwrap();
+        let tau = 181.0 / 365.0;
+        let forward = (1.0 / yc.df(end, start).unwrap() - 1.0) / tau;
+        let df = yc.df(end, Date::from_ymd(2017, 01, 04)).unwrap();
+        let vol = market_data.vol_surface(&caplet, end,
+            &|| Err(qm::Error::new("unused"))).unwrap();
+        let sqrt_var = vol.forward_variance(
+            DateDayFraction::new(Date::from_ymd(2017, 01, 02), 0.0),
+            DateDayFraction::new(Date::from_ymd(2018, 01, 02), 0.8),
+            0.08).unwrap().sqrt();
+        assert_approx(sqrt_var, 0.2, 0.01);
+        let d1 = ((forward / 0.08).ln() + 0.5 * sqrt_var * sqrt_var) / sqrt_var;
+        let d2 = d1 - sqrt_var;
+        let n = Black76::new().unwrap();
+        let expected = 1000000.0 * tau * df
+            * (forward * n.cdf(d1) - 0.08 * n.cdf(d2));
+        assert!(expected > 0.0);
+        assert_approx(price, expected, 1e-8);
+
+        // put/call parity with the floorlet gives the forward rate agreement
+        let floorlet = sample_caplet(PutOrCall::Put, 0.08);
+        let floor_price = floorlet.price(&market_data, val_date).unwrap();
+        assert_approx(price - floor_price,
+            1000000.0 * tau * df * (forward - 0.08), 1e-8);
+    }
+
+    #[test]
+    fn caplet_at_expiry_is_intrinsic() {
+
+        // valued at the fixing time, so no variance remains
+        let market_data = sample_market_data();
+        let val_date = DateTime::new(Date::from_ymd(2018, 01, 02), TimeOfDay::Close);
+        let end = Date::from_ymd(2018, 07, 04);
+        let yc = market_data.yield_curve("OPT", end).unwrap();
+        let tau = 181.0 / 365.0;
+        let forward = (1.0 / yc.df(end, Date::from_ymd(2018, 01, 04)).unwrap() - 1.0) / tau;
+        let df = yc.df(end, Date::from_ymd(2018, 01, 04)).unwrap();
+
+        for &strike in [forward - 0.01, forward + 0.01].iter() {
+            let caplet = sample_caplet(PutOrCall::Call, strike);
+            let price = caplet.price(&market_data, val_date).unwrap();
+            assert_approx(price,
+                1000000.0 * tau * df * (forward - strike).max(0.0), 1e-8);
+        }
+    }
+
+    #[test]
+    fn caplet_yield_sensitivity() {
+
+        // a parallel yield bump raises the forward rate as well as the
+        // discounting, and the forward dominates, so a caplet gains and a
+        // floorlet loses
+        let market_data = sample_market_data();
+        let bumpsize = 0.0001;
+        for &(cap_or_floor, sign) in [(PutOrCall::Call, 1.0), (PutOrCall::Put, -1.0)].iter() {
+            let caplet = RcInstrument::new(Qrc::new(Arc::new(
+                sample_caplet(cap_or_floor, 0.08))));
+            let mut pricer = SelfPricer::new(vec!((1.0, caplet.clone())),
+                &market_data).unwrap();
+            let unbumped = pricer.price().unwrap();
+            let mut save = pricer.as_bumpable().new_saveable();
+
+            let mut bumped = Vec::new();
+            for size in [bumpsize, -bumpsize].iter() {
+                let bump = Bump::new_yield("OPT",
+                    BumpYield::new_flat_continuously_compounded(*size));
+                assert!(pricer.as_mut_bumpable().bump(&bump, Some(&mut *save)).unwrap());
+                let price = pricer.price().unwrap();
+
+                // the bumped price matches pricing off bumped market data
+                let mut bumped_data = sample_market_data();
+                assert!(bumped_data.bump(&bump, None).unwrap());
+                let val_date = DateTime::new(Date::from_ymd(2017, 01, 02), TimeOfDay::Open);
+                let expected = caplet.as_priceable().unwrap()
+                    .price(&bumped_data, val_date).unwrap();
+                assert_approx(price, expected, 1e-8);
+
+                bumped.push(price);
+                pricer.as_mut_bumpable().restore(&*save).unwrap();
+                save.clear();
+            }
+            let sensitivity = (bumped[0] - bumped[1]) / (2.0 * bumpsize);
+            assert!(sign * sensitivity > 0.0, "sensitivity={}", sensitivity);
+            assert_eq!(pricer.price().unwrap(), unbumped);
+        }
+    }
+
+    #[test]
+    fn bachelier_caplet_with_negative_forward() {
+
+        // rates 10% lower make the forward rate over the period negative
+        let mut market_data = sample_market_data();
+        assert!(market_data.bump(&Bump::new_yield("OPT",
+            BumpYield::new_flat_continuously_compounded(-0.1)), None).unwrap());
+        let start = Date::from_ymd(2018, 01, 04);
+        let end = Date::from_ymd(2018, 07, 04);
+        let yc = market_data.yield_curve("OPT", end).unwrap();
+        let tau = 181.0 / 365.0;
+        let forward = (1.0 / yc.df(end, start).unwrap() - 1.0) / tau;
+        let df = yc.df(end, Date::from_ymd(2017, 01, 04)).unwrap();
+        assert!(forward < 0.0, "forward={}", forward);
+
+        // the lognormal formula has no meaning here
+        let black76 = Black76::new().unwrap();
+        assert!(black76.call_price(df, forward, 0.01, 0.2).is_nan());
+
+        // a caplet struck at -1% with a normal vol of 100bp
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        let fixing = DateTime::new(Date::from_ymd(2018, 01, 02), TimeOfDay::Close);
+        let val_date = DateTime::new(Date::from_ymd(2017, 01, 02), TimeOfDay::Open);
+        let mut prices = Vec::new();
+        for &cap_or_floor in [PutOrCall::Call, PutOrCall::Put].iter() {
+            let caplet = Caplet::new("NormalCaplet", "OPT", currency.clone(),
+                sample_settlement(2), fixing, start, end, -0.01, 1000000.0,
+                cap_or_floor, RateDynamics::Normal).unwrap();
+            let price = caplet.price(&market_data, val_date).unwrap();
+            let intrinsic = 1000000.0 * tau * df * caplet.intrinsic(forward);
+            assert!(price.is_finite() && price > intrinsic,
+                "price={} intrinsic={}", price, intrinsic);
+            prices.push(price);
+        }
+
+        // put/call parity still gives the forward rate agreement
+        assert_approx(prices[0] - prices[1],
+            1000000.0 * tau * df * (forward + 0.01), 1e-8);
+
+        // a negative strike is only allowed with normal dynamics
+        assert!(Caplet::new("C", "OPT", currency, sample_settlement(2), fixing,
+            start, end, -0.01, 1.0, PutOrCall::Call,
+            RateDynamics::Lognormal).is_err());
+    }
+
+    #[test]
+    fn caplet_requires_valid_inputs() {
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        let fixing = DateTime::new(Date::from_ymd(2018, 01, 02), TimeOfDay::Close);
+        let start = Date::from_ymd(2018, 01, 04);
+        let end = Date::from_ymd(2018, 07, 04);
+        assert!(Caplet::new("C", "OPT", currency.clone(), sample_settlement(2),
+            fixing, end, start, 0.08, 1.0, PutOrCall::Call,
+            RateDynamics::Lognormal).is_err());
+        assert!(Caplet::new("C", "OPT", currency, sample_settlement(2),
+            fixing, start, end, 0.0, 1.0, PutOrCall::Call,
+            RateDynamics::Lognormal).is_err());
+    }
+
+    #[test]
+    fn caplet_vol_bump_reaches_price() {
+
+        let market_data = sample_market_data();
+        let caplet = RcInstrument::new(Qrc::new(Arc::new(
+            sample_caplet(PutOrCall::Call, 0.08))));
+        let mut pricer = SelfPricer::new(vec!((1.0, caplet.clone())),
+            &market_data).unwrap();
+        let unbumped = pricer.price().unwrap();
+        let mut save = pricer.as_bumpable().new_saveable();
+
+        let bump = Bump::new_vol("SampleCaplet", BumpVol::new_flat_additive(0.01));
+        assert!(pricer.as_mut_bumpable().bump(&bump, Some(&mut *save)).unwrap());
+        let bumped = pricer.price().unwrap();
+        assert!(bumped > unbumped, "bumped={} unbumped={}", bumped, unbumped);
+
+        // the bumped price matches pricing off bumped market data
+        let mut bumped_data = sample_market_data();
+        assert!(bumped_data.bump(&bump, None).unwrap());
+        let val_date = DateTime::new(Date::from_ymd(2017, 01, 02), TimeOfDay::Open);
+        let expected = caplet.as_priceable().unwrap()
+            .price(&bumped_data, val_date).unwrap();
+        assert_approx(bumped, expected, 1e-8);
+
+        pricer.as_mut_bumpable().restore(&*save).unwrap();
+        assert_eq!(pricer.price().unwrap(), unbumped);
+    }
+
+    #[test]
+    fn caplet_fixes_to_zero_coupon() {
+
+        let fixing = DateTime::new(Date::from_ymd(2018, 01, 02), TimeOfDay::Close);
+        let tau = 181.0 / 365.0;
+
+        // before the fixing date, nothing happens
+        let table = FixingTable::new(Date::from_ymd(2017, 06, 01));
+        assert!(sample_caplet(PutOrCall::Call, 0.08).fix(&table).unwrap().is_none());
+
+        // a fixing of 10% makes the caplet pay 2% over the period, and the
+        // floorlet pay nothing
+        let today = Date::from_ymd(2018, 01, 03);
+        let fixings = [(fixing, 0.1)];
+        let table = FixingTable::from_fixings(today,
+            &[("SampleCaplet", &fixings)]).unwrap();
+        let decomp = sample_caplet(PutOrCall::Call, 0.08).fix(&table)
+            .unwrap().unwrap();
+        assert_eq!(decomp.len(), 1);
+        assert_approx(decomp[0].0, 1000000.0 * tau * 0.02, 1e-8);
+        assert_eq!(decomp[0].1.id(), "SampleCaplet:payment");
+        let value = serde_json::to_value(&decomp[0].1).unwrap();
+        assert_eq!(value["ZeroCoupon"]["payment_date"], "2018-07-04");
+
+        let decomp = sample_caplet(PutOrCall::Put, 0.08).fix(&table)
+            .unwrap().unwrap();
+        assert!(decomp.is_empty());
+
+        // a missing fixing in the past is an error
+        let table = FixingTable::new(today);
+        assert!(sample_caplet(PutOrCall::Call, 0.08).fix(&table).is_err());
+    }
+
+    fn assert_approx(value: f64, expected: f64, tolerance: f64) {
+        assert!(approx_eq(value, expected, tolerance),
+            "value={} expected={}", value, expected);
+    }
+}
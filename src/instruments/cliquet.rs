@@ -0,0 +1,538 @@
+use std::sync::Arc;
+use instruments::Instrument;
+use instruments::RcInstrument;
+use instruments::DependencyContext;
+use instruments::SpotRequirement;
+use instruments::MonteCarloPriceable;
+use instruments::MonteCarloDependencies;
+use instruments::ObservationKind;
+use instruments::MonteCarloContext;
+use instruments::assets::Currency;
+use instruments::assets::RcCurrency;
+use instruments::bonds::ZeroCoupon;
+use data::fixings::FixingTable;
+use dates::Date;
+use dates::rules::RcDateRule;
+use dates::datetime::DateTime;
+use dates::datetime::DateDayFraction;
+use core::qm;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use core::dedup::InstanceId;
+use ndarray::Axis;
+use ndarray::Array2;
+use erased_serde as esd;
+use serde::Deserialize;
+
+/// A floor and an optional cap, applied to a return. A return can never be
+/// less than -1, so a floor of -1 or less has no effect.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ReturnCollar {
+    floor: f64,
+    cap: Option<f64>
+}
+
+impl ReturnCollar {
+    pub fn new(floor: f64, cap: Option<f64>) -> Result<ReturnCollar, qm::Error> {
+        if let Some(cap) = cap {
+            if cap < floor {
+                return Err(qm::Error::new("Cap must not be less than the floor"))
+            }
+        }
+        Ok(ReturnCollar { floor: floor, cap: cap })
+    }
+
+    /// A collar that has no effect on any return
+    pub fn unbounded() -> ReturnCollar {
+        ReturnCollar { floor: -1.0, cap: None }
+    }
+
+    /// Applies the floor and then the cap to the given return
+    pub fn apply(&self, value: f64) -> f64 {
+        let floored = value.max(self.floor);
+        match self.cap {
+            Some(cap) => floored.min(cap),
+            None => floored }
+    }
+}
+
+/// A cliquet, or ratchet, option pays off on the sum of the returns of the
+/// underlying over a sequence of periods. The first reset date starts the
+/// first period, and each later reset date ends one period and starts the
+/// next. The return over each period is limited by a local collar, then the
+/// sum of these returns is limited by a global collar. The payment is the
+/// notional times the result, made at the settlement date following the
+/// last reset date.
+///
+/// For example, a local floor of zero with no caps pays the notional times
+/// the sum of the returns of a strip of at-the-money forward-starting calls,
+/// each expressed as a fraction of its strike.
+///
+/// Once some of the reset dates are in the past, fixing the option removes
+/// them, recording the sum of the collared returns of the completed periods
+/// and the most recent fixing, which starts the current period. This
+/// includes a reset today, if its fixing is in the fixing table.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct CliquetOption {
+    id: String,
+    credit_id: String,
+    underlying: RcInstrument,
+    settlement: RcDateRule,
+    reset_dates: Vec<DateTime>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_fixing: Option<f64>,
+    #[serde(default)]
+    past_returns: f64,
+    notional: f64,
+    local_collar: ReturnCollar,
+    global_collar: ReturnCollar,
+
+    // fields precomputed for performance and simplicity
+    reset_times: Vec<DateDayFraction>,
+    pay_date: Date
+}
+
+impl TypeId for CliquetOption {
+    fn get_type_id(&self) -> &'static str { "CliquetOption" }
+}
+
+impl InstanceId for CliquetOption {
+    fn id(&self) -> &str { &self.id }
+}
+
+impl CliquetOption {
+    /// Creates a cliquet option. The reset dates must be supplied in strictly
+    /// increasing order, and there must be at least two of them, to make at
+    /// least one period.
+    pub fn new(
+        id: &str,
+        credit_id: &str,
+        underlying: RcInstrument,
+        settlement: RcDateRule,
+        reset_dates: &[DateTime],
+        notional: f64,
+        local_collar: ReturnCollar,
+        global_collar: ReturnCollar)
+        -> Result<CliquetOption, qm::Error> {
+
+        if reset_dates.len() < 2 {
+            return Err(qm::Error::new(
+                "A cliquet option must have at least two reset dates"))
+        }
+
+        CliquetOption::with_past_fixings(id, credit_id, underlying, settlement,
+            reset_dates, None, 0.0, notional, local_collar, global_collar)
+    }
+
+    /// Creates a cliquet option where some of the reset dates are in the
+    /// past. The last fixing starts the period ending on the first of the
+    /// reset dates, and the past returns are the sum of the collared returns
+    /// of the periods already completed.
+    fn with_past_fixings(
+        id: &str,
+        credit_id: &str,
+        underlying: RcInstrument,
+        settlement: RcDateRule,
+        reset_dates: &[DateTime],
+        last_fixing: Option<f64>,
+        past_returns: f64,
+        notional: f64,
+        local_collar: ReturnCollar,
+        global_collar: ReturnCollar)
+        -> Result<CliquetOption, qm::Error> {
+
+        if let Some(fixing) = last_fixing {
+            if !(fixing > 0.0) {
+                return Err(qm::Error::new("Non-positive past fixing"))
+            }
+        }
+
+        let last = reset_dates.last().ok_or_else(|| qm::Error::new(
+            "A cliquet option must have at least one reset date"))?;
+        for pair in reset_dates.windows(2) {
+            if pair[0] >= pair[1] {
+                return Err(qm::Error::new(
+                    "Reset dates must be in strictly increasing order"))
+            }
+        }
+
+        let pay_date = settlement.apply(last.date());
+        let mut reset_times = Vec::with_capacity(reset_dates.len());
+        for date in reset_dates.iter() {
+            reset_times.push(underlying.time_to_day_fraction(*date)?);
+        }
+
+        Ok(CliquetOption {
+            id: id.to_string(),
+            credit_id: credit_id.to_string(),
+            underlying: underlying,
+            settlement: settlement,
+            reset_dates: reset_dates.to_vec(),
+            last_fixing: last_fixing,
+            past_returns: past_returns,
+            notional: notional,
+            local_collar: local_collar,
+            global_collar: global_collar,
+            reset_times: reset_times,
+            pay_date: pay_date })
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<Instrument>, esd::Error> {
+        Ok(Qrc::new(Arc::new(CliquetOption::deserialize(de)?)))
+    }
+
+    fn expiry(&self) -> DateTime {
+        *self.reset_dates.last().unwrap()
+    }
+
+    /// The collared return of a single period
+    fn period_return(&self, start: f64, end: f64) -> f64 {
+        self.local_collar.apply(end / start - 1.0)
+    }
+
+    /// The payoff given the sum of the collared returns of every period
+    fn payoff(&self, sum_of_returns: f64) -> f64 {
+        self.notional * self.global_collar.apply(sum_of_returns)
+    }
+
+    /// The cash payment at the pay date
+    fn payment(&self) -> RcInstrument {
+        // TODO this feels inefficient and ugly
+        let currency = RcCurrency::new(Arc::new(self.payoff_currency().clone()));
+        RcInstrument::new(Qrc::new(Arc::new(
+            ZeroCoupon::new(&format!("{}:Expiry", self.id),
+            &self.credit_id, currency, self.expiry(), self.pay_date,
+            self.settlement.clone()))))
+    }
+}
+
+impl Instrument for CliquetOption {
+    fn payoff_currency(&self) -> &Currency { self.underlying.payoff_currency() }
+    fn credit_id(&self) -> &str { &self.credit_id }
+    fn settlement(&self) -> &RcDateRule { &self.settlement }
+    fn as_mc_priceable(&self) -> Option<&MonteCarloPriceable> { Some(self) }
+
+    fn dependencies(&self, context: &mut DependencyContext)
+        -> SpotRequirement {
+
+        // one fixing on each reset date
+        for date in self.reset_dates.iter() {
+            context.fixing(self.underlying.id(), *date);
+        }
+
+        let expiry_date = self.expiry().date();
+        context.yield_curve(self.credit_id(), self.pay_date);
+        context.forward_curve(&self.underlying, expiry_date);
+        context.vol_surface(&self.underlying, expiry_date);
+
+        SpotRequirement::NotRequired
+    }
+
+    fn fix(&self, fixing_table: &FixingTable)
+        -> Result<Option<Vec<(f64, RcInstrument)>>, qm::Error> {
+
+        // Find the reset dates that have fixed (the fetch errors if a fixing
+        // in the past is missing). These must all come before the dates that
+        // have not fixed. Each fixing completes the period started by the
+        // one before.
+        let mut last_fixing = self.last_fixing;
+        let mut past_returns = self.past_returns;
+        let mut n_fixed = 0;
+        let mut unfixed = false;
+        for date in self.reset_dates.iter() {
+            match fixing_table.get(self.underlying.id(), *date)? {
+                Some(_) if unfixed => return Err(qm::Error::new(&format!(
+                    "Cliquet option {} has a fixing after an unfixed \
+                    reset date", self.id))),
+                Some(fixing) => {
+                    if !(fixing > 0.0) {
+                        return Err(qm::Error::new(&format!(
+                            "Cliquet option {} has a non-positive fixing",
+                            self.id)))
+                    }
+                    if let Some(start) = last_fixing {
+                        past_returns += self.period_return(start, fixing);
+                    }
+                    last_fixing = Some(fixing);
+                    n_fixed += 1;
+                },
+                None => unfixed = true
+            }
+        }
+        if n_fixed == 0 {
+            return Ok(None)
+        }
+
+        // If all dates have fixed, the option becomes a cash payment
+        if n_fixed == self.reset_dates.len() {
+            let payment = self.payoff(past_returns);
+            let mut decomp = Vec::new();
+            if payment != 0.0 {
+                decomp.push((payment, self.payment()));
+            }
+            return Ok(Some(decomp))
+        }
+
+        let fixed = CliquetOption::with_past_fixings(&self.id, &self.credit_id,
+            self.underlying.clone(), self.settlement.clone(),
+            &self.reset_dates[n_fixed..], last_fixing, past_returns,
+            self.notional, self.local_collar, self.global_collar)?;
+        Ok(Some(vec!((1.0, RcInstrument::new(Qrc::new(Arc::new(fixed)))))))
+    }
+}
+
+impl MonteCarloPriceable for CliquetOption {
+    fn as_instrument(&self) -> &Instrument { self }
+
+    fn mc_dependencies(&self, _dates: &[DateDayFraction],
+        output: &mut MonteCarloDependencies) -> Result<(), qm::Error> {
+
+        // an observation on every reset date
+        for time in self.reset_times.iter() {
+            output.observation(&self.underlying, *time, ObservationKind::Fixing);
+        }
+
+        // a single cash payment at the pay date
+        output.flow(&self.payment());
+        Ok(())
+    }
+
+    /// Until the first reset has fixed, the cliquet is forward-starting
+    fn start_date(&self) -> Option<DateDayFraction> {
+        match self.last_fixing {
+            Some(_) => None,
+            None => Some(self.reset_times[0]) }
+    }
+
+    fn mc_price(&self, context: &MonteCarloContext)
+        -> Result<f64, qm::Error> {
+
+        let ref paths = context.paths(&self.underlying)?;
+        let n_paths = paths.shape()[0];
+        let n_obs = paths.shape()[1];
+        assert_eq!(n_obs, self.reset_times.len());
+
+        // If the first reset has not fixed, the first observation starts
+        // the first period, rather than ending one
+        let first_end = if self.last_fixing.is_some() { 0 } else { 1 };
+
+        let mut quantities = Array2::zeros((n_paths, 1));
+        {
+            let ref mut flow_column = quantities.subview_mut(Axis(1), 0);
+            for (path, flow) in paths.axis_iter(Axis(0)).zip(flow_column.iter_mut()) {
+                let mut start = self.last_fixing.unwrap_or(path[0]);
+                let mut sum = self.past_returns;
+                for end in path.iter().skip(first_end) {
+                    sum += self.period_return(start, *end);
+                    start = *end;
+                }
+                *flow = self.payoff(sum);
+            }
+        }
+
+        context.evaluate_flows(quantities.view())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pricers::montecarlo::tests::black_diffusion_price_with_stderr;
+    use math::numerics::approx_eq;
+    use dates::datetime::TimeOfDay;
+    use risk::marketdata::MarketData;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::marketdata::tests::sample_currency;
+    use risk::marketdata::tests::sample_settlement;
+    use risk::marketdata::tests::sample_equity;
+    use instruments::Priceable;
+    use instruments::PricingContext;
+    use instruments::options::ForwardStartingEuropean;
+    use instruments::options::PutOrCall;
+    use instruments::options::OptionSettlement;
+    use serde_json;
+
+    fn sample_underlying() -> RcInstrument {
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        RcInstrument::new(Qrc::new(Arc::new(sample_equity(currency, 2))))
+    }
+
+    /// Quarterly resets over 2017, the first of them at the open today
+    fn reset_dates() -> Vec<DateTime> {
+        let mut dates = vec!(DateTime::new(Date::from_ymd(2017, 01, 02), TimeOfDay::Open));
+        for &(year, month) in [(2017, 4), (2017, 7), (2017, 10), (2018, 1)].iter() {
+            dates.push(DateTime::new(Date::from_ymd(year, month, 3), TimeOfDay::Close));
+        }
+        dates
+    }
+
+    fn sample_cliquet(local_collar: ReturnCollar, global_collar: ReturnCollar)
+        -> CliquetOption {
+        CliquetOption::new("SampleCliquet", "OPT", sample_underlying(),
+            sample_settlement(2), &reset_dates(), 100.0, local_collar,
+            global_collar).unwrap()
+    }
+
+    /// A typical cliquet, with each quarter's return floored at -2% and
+    /// capped at 5%, and the total floored at zero
+    fn capped_cliquet() -> CliquetOption {
+        sample_cliquet(ReturnCollar::new(-0.02, Some(0.05)).unwrap(),
+            ReturnCollar::new(0.0, None).unwrap())
+    }
+
+    fn mc_price_with_stderr(instrument: RcInstrument, market_data: &MarketData)
+        -> (f64, f64) {
+        black_diffusion_price_with_stderr(instrument, market_data, 0.002, 20000).unwrap()
+    }
+
+    fn cliquet_price(cliquet: CliquetOption) -> (f64, f64) {
+        mc_price_with_stderr(RcInstrument::new(Qrc::new(Arc::new(cliquet))),
+            &sample_market_data())
+    }
+
+    #[test]
+    fn cliquet_with_degenerate_collars_is_strip_of_forward_starts() {
+
+        // With a local floor of zero and no caps, each period pays the
+        // return of an at-the-money forward-starting call. With no
+        // displacement in the diffusion, the return is independent of the
+        // level at the start of the period, so each is worth the forward-
+        // starting call divided by the forward at its strike date.
+        let cliquet = sample_cliquet(ReturnCollar::new(0.0, None).unwrap(),
+            ReturnCollar::unbounded());
+        let (price, stderr) = cliquet_price(cliquet);
+
+        let market_data = sample_market_data();
+        let val_date = DateTime::new(market_data.spot_date(), TimeOfDay::Open);
+        let underlying = sample_underlying();
+        let dates = reset_dates();
+        let curve = market_data.forward_curve(&*underlying,
+            dates.last().unwrap().date()).unwrap();
+        let mut expected = 0.0;
+        for (i, pair) in dates.windows(2).enumerate() {
+            let european = ForwardStartingEuropean::new(
+                &format!("SampleForwardStart{}", i), "OPT", underlying.clone(),
+                sample_settlement(2), pair[1], 1.0, pair[0], PutOrCall::Call,
+                OptionSettlement::Cash).unwrap();
+            let value = european.price(&market_data, val_date).unwrap();
+            expected += 100.0 * value / curve.forward(pair[0].date()).unwrap();
+        }
+
+        assert!((price - expected).abs() < 4.0 * stderr,
+            "price={} expected={} stderr={}", price, expected, stderr);
+    }
+
+    #[test]
+    fn cliquet_caps_reduce_value() {
+        let (unbounded, unbounded_err) = cliquet_price(sample_cliquet(
+            ReturnCollar::new(0.0, None).unwrap(), ReturnCollar::unbounded()));
+        let (capped, capped_err) = cliquet_price(capped_cliquet());
+        assert!(capped > 0.0 && unbounded - capped > 3.0 * (unbounded_err + capped_err),
+            "capped={} unbounded={}", capped, unbounded);
+
+        // a global cap below the sum of the local caps reduces it further
+        let (globally_capped, _) = cliquet_price(sample_cliquet(
+            ReturnCollar::new(-0.02, Some(0.05)).unwrap(),
+            ReturnCollar::new(0.0, Some(0.1)).unwrap()));
+        assert!(globally_capped < capped && globally_capped <= 100.0 * 0.1,
+            "globally_capped={} capped={}", globally_capped, capped);
+    }
+
+    fn past_fixings(values: &[f64], today: Date) -> FixingTable {
+        let fixings: Vec<(DateTime, f64)> = reset_dates().iter()
+            .cloned().zip(values.iter().cloned()).collect();
+        FixingTable::from_fixings(today, &[("BP.L", &fixings)]).unwrap()
+    }
+
+    #[test]
+    fn cliquet_reset_today_takes_fixing_from_table() {
+
+        // With no fixing today, the first period starts from the simulated
+        // spot at the open, so the cliquet is forward-starting
+        let today = Date::from_ymd(2017, 01, 02);
+        let cliquet = capped_cliquet();
+        assert!(cliquet.fix(&past_fixings(&[], today)).unwrap().is_none());
+        assert!(cliquet.start_date().is_some());
+
+        // With a fixing today, the first period starts from the fixing
+        let decomp = cliquet.fix(&past_fixings(&[100.0], today)).unwrap().unwrap();
+        assert_eq!(decomp.len(), 1);
+        assert_approx(decomp[0].0, 1.0, 1e-12);
+        let value = serde_json::to_value(&decomp[0].1).unwrap();
+        assert_eq!(value["CliquetOption"]["last_fixing"], 100.0);
+        assert_eq!(value["CliquetOption"]["past_returns"], 0.0);
+        assert_eq!(value["CliquetOption"]["reset_dates"].as_array().unwrap().len(), 4);
+
+        // Fixing at spot is worth the same as the forward-starting cliquet
+        let market_data = sample_market_data();
+        let (unfixed, unfixed_err) = mc_price_with_stderr(
+            RcInstrument::new(Qrc::new(Arc::new(cliquet.clone()))), &market_data);
+        let (fixed, fixed_err) = mc_price_with_stderr(decomp[0].1.clone(), &market_data);
+        assert!((fixed - unfixed).abs() < 3.0 * (fixed_err + unfixed_err),
+            "fixed={} unfixed={}", fixed, unfixed);
+
+        // A lower fixing today makes the first return larger
+        let decomp = cliquet.fix(&past_fixings(&[90.0], today)).unwrap().unwrap();
+        let (low, _) = mc_price_with_stderr(decomp[0].1.clone(), &market_data);
+        assert!(low > fixed + 1.0, "low={} fixed={}", low, fixed);
+    }
+
+    #[test]
+    fn cliquet_fix_with_past_reset_dates() {
+        let cliquet = capped_cliquet();
+        let today = Date::from_ymd(2017, 07, 04);
+        let decomp = cliquet.fix(&past_fixings(&[100.0, 108.0, 99.0], today))
+            .unwrap().unwrap();
+        let value = serde_json::to_value(&decomp[0].1).unwrap();
+        assert_eq!(value["CliquetOption"]["last_fixing"], 99.0);
+        assert_eq!(value["CliquetOption"]["reset_dates"].as_array().unwrap().len(), 2);
+
+        // the first period is capped at 5% and the second floored at -2%
+        let past_returns = value["CliquetOption"]["past_returns"].as_f64().unwrap();
+        assert_approx(past_returns, 0.03, 1e-12);
+    }
+
+    #[test]
+    fn cliquet_fix_when_fully_fixed() {
+        let cliquet = capped_cliquet();
+        let today = Date::from_ymd(2018, 01, 04);
+        let decomp = cliquet.fix(&past_fixings(
+            &[100.0, 104.0, 110.0, 105.0, 108.0], today)).unwrap().unwrap();
+        assert_eq!(decomp.len(), 1);
+        let expected = 100.0 * (0.04 + 0.05 - 0.02 + (108.0 / 105.0 - 1.0));
+        assert_approx(decomp[0].0, expected, 1e-12);
+        assert_eq!(decomp[0].1.id(), "SampleCliquet:Expiry");
+
+        // the global floor means a cliquet with losses pays nothing
+        let decomp = cliquet.fix(&past_fixings(
+            &[100.0, 97.0, 94.0, 91.0, 88.0], today)).unwrap().unwrap();
+        assert!(decomp.is_empty());
+    }
+
+    #[test]
+    fn cliquet_rejects_bad_inputs() {
+        assert!(ReturnCollar::new(0.1, Some(0.05)).is_err());
+        let dates = reset_dates();
+        assert!(CliquetOption::new("Bad", "OPT", sample_underlying(),
+            sample_settlement(2), &dates[..1], 100.0, ReturnCollar::unbounded(),
+            ReturnCollar::unbounded()).is_err());
+        let unordered = [dates[1], dates[0]];
+        assert!(CliquetOption::new("Bad", "OPT", sample_underlying(),
+            sample_settlement(2), &unordered, 100.0, ReturnCollar::unbounded(),
+            ReturnCollar::unbounded()).is_err());
+    }
+
+    #[test]
+    fn cliquet_serde() {
+        let cliquet = capped_cliquet();
+        let serialized = serde_json::to_string(&cliquet).unwrap();
+        let deserialized: CliquetOption = serde_json::from_str(&serialized).unwrap();
+        let reserialized = serde_json::to_string(&deserialized).unwrap();
+        assert_eq!(serialized, reserialized);
+    }
+
+    fn assert_approx(value: f64, expected: f64, tolerance: f64) {
+        assert!(approx_eq(value, expected, tolerance),
+            "value={} expected={}", value, expected);
+    }
+}
@@ -0,0 +1,471 @@
+use std::sync::Arc;
+use instruments::Instrument;
+use instruments::RcInstrument;
+use instruments::Priceable;
+use instruments::PricingContext;
+use instruments::DependencyContext;
+use instruments::SpotRequirement;
+use instruments::MonteCarloPriceable;
+use instruments::MonteCarloDependencies;
+use instruments::ObservationKind;
+use instruments::MonteCarloContext;
+use instruments::options::PutOrCall;
+use instruments::options::PayoffSmoothing;
+use instruments::assets::Currency;
+use instruments::assets::RcCurrency;
+use instruments::bonds::ZeroCoupon;
+use math::optionpricing::Black76;
+use data::fixings::FixingTable;
+use dates::Date;
+use dates::rules::RcDateRule;
+use dates::datetime::DateTime;
+use dates::datetime::DateDayFraction;
+use core::qm;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use core::dedup::InstanceId;
+use ndarray::Axis;
+use ndarray::Array2;
+use erased_serde as esd;
+use serde::Deserialize;
+
+/// A cash-or-nothing digital option pays a fixed amount of cash at the
+/// settlement date following the expiry if the underlying finishes in the
+/// money, and nothing otherwise.
+///
+/// If the underlying fixes exactly at the strike, a call pays and a put does
+/// not. This means a call and a put with the same strike and expiry always
+/// pay exactly the cash amount between them, and it is applied consistently
+/// whether the option is fixed, valued by Monte-Carlo or valued analytically
+/// at or after expiry.
+///
+/// The analytic valuation uses the Black formula with the vol at the strike.
+/// It ignores the slope of the smile, which matters for digitals on skewed
+/// surfaces.
+///
+/// Bumped Greeks from Monte-Carlo are very noisy, because of the step in the
+/// payoff. The step can be smoothed for Monte-Carlo valuation, at the cost
+/// of a small bias. See PayoffSmoothing.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct DigitalOption {
+    id: String,
+    credit_id: String,
+    underlying: RcInstrument,
+    settlement: RcDateRule,
+    expiry: DateTime,
+    strike: f64,
+    put_or_call: PutOrCall,
+    cash: f64,
+    #[serde(default, skip_serializing_if = "PayoffSmoothing::is_none")]
+    smoothing: PayoffSmoothing,
+
+    // fields precomputed for performance and simplicity
+    expiry_time: DateDayFraction,
+    pay_date: Date
+}
+
+impl TypeId for DigitalOption {
+    fn get_type_id(&self) -> &'static str { "DigitalOption" }
+}
+
+impl InstanceId for DigitalOption {
+    fn id(&self) -> &str { &self.id }
+}
+
+impl DigitalOption {
+    pub fn new(
+        id: &str,
+        credit_id: &str,
+        underlying: RcInstrument,
+        settlement: RcDateRule,
+        expiry: DateTime,
+        strike: f64,
+        put_or_call: PutOrCall,
+        cash: f64) -> Result<DigitalOption, qm::Error> {
+
+        if strike <= 0.0 {
+            return Err(qm::Error::new("Digital strike must be positive"))
+        }
+
+        let pay_date = settlement.apply(expiry.date());
+        let expiry_time = underlying.time_to_day_fraction(expiry)?;
+        Ok(DigitalOption {
+            id: id.to_string(),
+            credit_id: credit_id.to_string(),
+            underlying: underlying,
+            settlement: settlement,
+            expiry: expiry,
+            strike: strike,
+            put_or_call: put_or_call,
+            cash: cash,
+            smoothing: PayoffSmoothing::None,
+            expiry_time: expiry_time,
+            pay_date: pay_date })
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<Instrument>, esd::Error> {
+        Ok(Qrc::new(Arc::new(DigitalOption::deserialize(de)?)))
+    }
+
+    /// Smooths the payoff when valued by Monte-Carlo, which makes bumped
+    /// Greeks far less noisy at the cost of a small bias in the price.
+    pub fn with_smoothing(mut self, smoothing: PayoffSmoothing)
+        -> Result<DigitalOption, qm::Error> {
+        smoothing.validate(self.strike)?;
+        self.smoothing = smoothing;
+        Ok(self)
+    }
+
+    /// Whether the digital pays, given the underlying at expiry. At the
+    /// strike, calls pay and puts do not.
+    fn pays(&self, spot: f64) -> bool {
+        match self.put_or_call {
+            PutOrCall::Call => spot >= self.strike,
+            PutOrCall::Put => spot < self.strike }
+    }
+
+    /// The fraction of the cash paid in a Monte-Carlo valuation, given the
+    /// underlying at expiry. Without smoothing, this is consistent with pays.
+    fn smoothed_pays(&self, spot: f64) -> f64 {
+        match self.put_or_call {
+            PutOrCall::Call => self.smoothing.above(spot, self.strike, true),
+            PutOrCall::Put => self.smoothing.below(spot, self.strike, false) }
+    }
+
+    /// The cash payment at the pay date
+    fn payment(&self) -> RcInstrument {
+        // TODO this feels inefficient and ugly
+        let currency = RcCurrency::new(Arc::new(self.payoff_currency().clone()));
+        RcInstrument::new(Qrc::new(Arc::new(
+            ZeroCoupon::new(&format!("{}:Expiry", self.id),
+            &self.credit_id, currency, self.expiry, self.pay_date,
+            self.settlement.clone()))))
+    }
+}
+
+impl Instrument for DigitalOption {
+    fn payoff_currency(&self) -> &Currency { self.underlying.payoff_currency() }
+    fn credit_id(&self) -> &str { &self.credit_id }
+    fn settlement(&self) -> &RcDateRule { &self.settlement }
+    fn as_priceable(&self) -> Option<&Priceable> { Some(self) }
+    fn as_mc_priceable(&self) -> Option<&MonteCarloPriceable> { Some(self) }
+
+    fn dependencies(&self, context: &mut DependencyContext)
+        -> SpotRequirement {
+
+        // just one fixing, at expiry
+        context.fixing(self.underlying.id(), self.expiry);
+
+        let expiry_date = self.expiry.date();
+        context.yield_curve(self.credit_id(), self.pay_date);
+        context.forward_curve(&self.underlying, expiry_date);
+        context.vol_surface(&self.underlying, expiry_date);
+
+        SpotRequirement::NotRequired
+    }
+
+    fn fix(&self, fixing_table: &FixingTable)
+        -> Result<Option<Vec<(f64, RcInstrument)>>, qm::Error> {
+
+        // Once the expiry has fixed, the digital turns into a cash payment
+        // or nothing at all
+        match fixing_table.get(self.underlying.id(), self.expiry)? {
+            None => Ok(None),
+            Some(fixing) => {
+                let mut decomp = Vec::new();
+                if self.pays(fixing) {
+                    decomp.push((self.cash, self.payment()));
+                }
+                Ok(Some(decomp))
+            }
+        }
+    }
+}
+
+impl Priceable for DigitalOption {
+    fn as_instrument(&self) -> &Instrument { self }
+
+    /// Values the digital analytically, as the discounted probability under
+    /// the Black model of finishing in the money.
+    fn prices(&self, context: &PricingContext, dates: &[DateTime], out: &mut [f64])
+        -> Result<(), qm::Error> {
+
+        assert_eq!(dates.len(), out.len());
+        if dates.is_empty() {
+            return Ok(())  // nothing to do
+        }
+
+        let expiry_date = self.expiry.date();
+        let yc = context.yield_curve(&self.credit_id, self.pay_date)?;
+        let vol = context.vol_surface(&*self.underlying, expiry_date,
+            &|| context.forward_curve(&*self.underlying, expiry_date))?;
+        let underlying = self.underlying.as_priceable().ok_or_else(|| qm::Error::new(
+            "The underlying of a digital must itself be priceable"))?;
+        let forward = underlying.price(context, self.expiry)?;
+        let df_from_base = (-yc.rt(self.pay_date)?).exp();
+
+        // For some div assumptions, we must displace the forward and strike
+        let displacement = vol.displacement(expiry_date)?;
+        let k = self.strike + displacement;
+        let f = forward - displacement;
+        if f < 0.0 {
+            return Err(qm::Error::new("Negative forward"));
+        }
+
+        let black76 = Black76::new()?;
+
+        // We assume the option goes ex just after its expiry date/time
+        for (date, output) in dates.iter().zip(out.iter_mut()) {
+            *output = if *date <= self.expiry {
+                let settlement_date = self.settlement.apply(date.date());
+                let df = df_from_base * yc.rt(settlement_date)?.exp();
+
+                let val_date = self.underlying.time_to_day_fraction(*date)?;
+                let variance = vol.forward_variance(val_date, self.expiry_time, self.strike)?;
+                if variance < 0.0 {
+                    return Err(qm::Error::new("Negative variance"));
+                }
+
+                // With no variance left, the forward is the fixing, so use
+                // the same rule at the strike as the other valuations.
+                let probability = if variance == 0.0 {
+                    if self.pays(forward) { 1.0 } else { 0.0 }
+                } else {
+                    let sqrt_var = variance.sqrt();
+                    let d2 = (f / k).ln() / sqrt_var - 0.5 * sqrt_var;
+                    match self.put_or_call {
+                        PutOrCall::Call => black76.cdf(d2),
+                        PutOrCall::Put => black76.cdf(-d2) }
+                };
+
+                df * self.cash * probability
+            } else {
+                0.0
+            };
+        }
+
+        Ok(())
+    }
+}
+
+impl MonteCarloPriceable for DigitalOption {
+    fn as_instrument(&self) -> &Instrument { self }
+
+    fn mc_dependencies(&self, _dates: &[DateDayFraction],
+        output: &mut MonteCarloDependencies) -> Result<(), qm::Error> {
+
+        // one observation, at expiry, and one cash flow
+        output.observation(&self.underlying, self.expiry_time, ObservationKind::Fixing);
+        output.flow(&self.payment());
+        Ok(())
+    }
+
+    fn start_date(&self) -> Option<DateDayFraction> {
+        None
+    }
+
+    fn mc_price(&self, context: &MonteCarloContext)
+        -> Result<f64, qm::Error> {
+
+        // This is asserting what the context should know from our response
+        // to the mc_dependencies call. No need for proper error handling.
+        let ref paths = context.paths(&self.underlying)?;
+        let shape = paths.shape();
+        assert_eq!(shape.len(), 2);
+        let n_paths = shape[0];
+        assert_eq!(shape[1], 1);
+        let ref path_column = paths.subview(Axis(1), 0);
+
+        // Unless smoothed, the payoff is discontinuous, so bumped risks from
+        // this are noisy. Use the analytic valuation for reconciling risks.
+        let mut quantities = Array2::zeros((n_paths, 1));
+        {
+            let ref mut flow_column = quantities.subview_mut(Axis(1), 0);
+            for (spot, flow) in path_column.iter().zip(flow_column.iter_mut()) {
+                *flow = self.cash * self.smoothed_pays(*spot);
+            }
+        }
+
+        context.evaluate_flows(quantities.view())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use models::random::BASE_SEED;
+    use std::f64::{INFINITY, NEG_INFINITY};
+    use math::numerics::approx_eq;
+    use dates::datetime::TimeOfDay;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::marketdata::tests::sample_currency;
+    use risk::marketdata::tests::sample_settlement;
+    use risk::marketdata::tests::sample_equity;
+    use models::RcMonteCarloModelFactory;
+    use models::VarianceReduction;
+    use models::blackdiffusion::BlackDiffusionFactory;
+    use pricers::montecarlo::MonteCarloPricer;
+    use data::bump::Bump;
+    use data::bumpspot::BumpSpot;
+    use risk::Pricer;
+    use serde_json;
+
+    fn sample_digital(strike: f64, put_or_call: PutOrCall) -> DigitalOption {
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        let equity = RcInstrument::new(Qrc::new(Arc::new(sample_equity(currency, 2))));
+        let expiry = DateTime::new(Date::from_ymd(2018, 06, 01), TimeOfDay::Close);
+        DigitalOption::new("SampleDigital", "OPT", equity, sample_settlement(2),
+            expiry, strike, put_or_call, 10.0).unwrap()
+    }
+
+    fn sample_fixings(digital: &DigitalOption, fixing: f64) -> FixingTable {
+        FixingTable::from_fixings(Date::from_ymd(2018, 06, 02), &[
+            ("BP.L", &[(digital.expiry, fixing)])]).unwrap()
+    }
+
+    #[test]
+    fn digital_mc_matches_analytic() {
+
+        let market_data = sample_market_data();
+        let val_date = DateTime::new(market_data.spot_date(), TimeOfDay::Open);
+        let model_factory = RcMonteCarloModelFactory::new(Arc::new(BlackDiffusionFactory::new(
+            20, 0.01, 20000, VarianceReduction::None, Some(BASE_SEED)).unwrap()));
+
+        for &(strike, put_or_call) in [(100.0, PutOrCall::Call), (100.0, PutOrCall::Put),
+            (80.0, PutOrCall::Call), (120.0, PutOrCall::Put)].iter() {
+
+            let digital = Arc::new(sample_digital(strike, put_or_call));
+            let analytic = digital.price(&market_data, val_date).unwrap();
+            let instrument = RcInstrument::new(Qrc::new(digital));
+            let pricer = MonteCarloPricer::new(vec!((1.0, instrument)),
+                model_factory.clone(), &market_data).unwrap();
+            let (price, stderr) = pricer.price_with_stderr().unwrap();
+            assert!((price - analytic).abs() < 3.0 * stderr,
+                "strike={} {:?} price={} analytic={} stderr={}",
+                strike, put_or_call, price, analytic, stderr);
+        }
+    }
+
+    #[test]
+    fn digital_call_put_parity() {
+
+        // a call and put together pay the cash for sure
+        let market_data = sample_market_data();
+        let val_date = DateTime::new(market_data.spot_date(), TimeOfDay::Open);
+        let call = sample_digital(100.0, PutOrCall::Call);
+        let put = sample_digital(100.0, PutOrCall::Put);
+        let cash = call.payment().as_priceable().unwrap()
+            .price(&market_data, val_date).unwrap() * 10.0;
+        let call_price = call.price(&market_data, val_date).unwrap();
+        let put_price = put.price(&market_data, val_date).unwrap();
+        assert!(approx_eq(call_price + put_price, cash, 1e-12),
+            "call={} put={} cash={}", call_price, put_price, cash);
+    }
+
+    #[test]
+    fn digital_fix_at_the_strike() {
+
+        // exactly at the strike, the call pays and the put does not
+        let call = sample_digital(100.0, PutOrCall::Call);
+        let decomp = call.fix(&sample_fixings(&call, 100.0)).unwrap().unwrap();
+        assert_eq!(decomp.len(), 1);
+        assert_eq!(decomp[0].0, 10.0);
+        assert_eq!(decomp[0].1.id(), "SampleDigital:Expiry");
+
+        let put = sample_digital(100.0, PutOrCall::Put);
+        let decomp = put.fix(&sample_fixings(&put, 100.0)).unwrap().unwrap();
+        assert!(decomp.is_empty());
+
+        let decomp = put.fix(&sample_fixings(&put, 99.99)).unwrap().unwrap();
+        assert_eq!(decomp.len(), 1);
+    }
+
+    #[test]
+    fn digital_unfixed() {
+        let call = sample_digital(100.0, PutOrCall::Call);
+        let fixings = FixingTable::from_fixings(Date::from_ymd(2017, 01, 02),
+            &[]).unwrap();
+        assert!(call.fix(&fixings).unwrap().is_none());
+    }
+
+    /// Delta of the digital by central differences with a small relative
+    /// spot bump, from Monte-Carlo with the given seed
+    fn mc_delta(digital: &DigitalOption, seed: u64) -> f64 {
+        let model_factory = RcMonteCarloModelFactory::new(Arc::new(BlackDiffusionFactory::new(
+            20, 0.01, 20000, VarianceReduction::None, Some(seed)).unwrap()));
+        let instrument = RcInstrument::new(Qrc::new(Arc::new(digital.clone())));
+        let mut pricer = MonteCarloPricer::new(vec!((1.0, instrument)),
+            model_factory, &sample_market_data()).unwrap();
+        let mut save = pricer.as_bumpable().new_saveable();
+
+        let bumpsize = 0.0001;
+        let mut prices = [0.0; 2];
+        for (price, size) in prices.iter_mut().zip([bumpsize, -bumpsize].iter()) {
+            let bump = Bump::new_spot("BP.L", BumpSpot::new_relative(*size));
+            assert!(pricer.as_mut_bumpable().bump(&bump, Some(&mut *save)).unwrap());
+            *price = pricer.price().unwrap();
+            pricer.as_mut_bumpable().restore(&*save).unwrap();
+            save.clear();
+        }
+        (prices[0] - prices[1]) / (2.0 * bumpsize * 100.0)
+    }
+
+    #[test]
+    fn digital_smoothed_delta_is_stable() {
+
+        // With a bump of a cent, only a handful of paths cross the strike,
+        // so the unsmoothed delta is mostly noise. Smoothing over ten
+        // percent of the strike gives a stable delta from every seed.
+        let digital = sample_digital(100.0, PutOrCall::Call);
+        let smoothed = digital.clone().with_smoothing(
+            PayoffSmoothing::Ramp { width: 0.1 }).unwrap();
+
+        let seeds = [1, 2, 3, 4, 5, 6, 7, 8];
+        let raw: Vec<f64> = seeds.iter().map(|s| mc_delta(&digital, *s)).collect();
+        let smooth: Vec<f64> = seeds.iter().map(|s| mc_delta(&smoothed, *s)).collect();
+
+        let spread = |deltas: &[f64]| -> f64 {
+            let max = deltas.iter().fold(NEG_INFINITY, |a, &b| b.max(a));
+            let min = deltas.iter().fold(INFINITY, |a, &b| b.min(a));
+            max - min
+        };
+        let mean = smooth.iter().sum::<f64>() / smooth.len() as f64;
+        assert!(mean > 0.0 && mean.is_finite(), "smooth={:?}", smooth);
+        assert!(spread(&smooth) < 0.15 * mean, "smooth={:?}", smooth);
+        assert!(spread(&raw) > 4.0 * spread(&smooth), "raw={:?} smooth={:?}", raw, smooth);
+    }
+
+    #[test]
+    fn digital_smoothing_bias_is_small() {
+
+        // The logistic smoothing biases the price, but only slightly
+        let market_data = sample_market_data();
+        let model_factory = RcMonteCarloModelFactory::new(Arc::new(BlackDiffusionFactory::new(
+            20, 0.01, 20000, VarianceReduction::None, Some(BASE_SEED)).unwrap()));
+        let digital = sample_digital(100.0, PutOrCall::Put);
+        let smoothed = digital.clone().with_smoothing(
+            PayoffSmoothing::Logistic { width: 0.02 }).unwrap();
+        let mut prices = Vec::new();
+        for instrument in vec!(digital, smoothed).into_iter() {
+            let pricer = MonteCarloPricer::new(
+                vec!((1.0, RcInstrument::new(Qrc::new(Arc::new(instrument))))),
+                model_factory.clone(), &market_data).unwrap();
+            prices.push(pricer.price().unwrap());
+        }
+        assert!((prices[0] - prices[1]).abs() < 0.01 * prices[0], "prices={:?}", prices);
+
+        assert!(sample_digital(100.0, PutOrCall::Call).with_smoothing(
+            PayoffSmoothing::Ramp { width: 0.0 }).is_err());
+        assert!(PayoffSmoothing::Ramp { width: 0.1 }.validate(0.0).is_err());
+        assert!(PayoffSmoothing::None.validate(0.0).is_ok());
+    }
+
+    #[test]
+    fn digital_serde() {
+        let digital = sample_digital(100.0, PutOrCall::Call);
+        let serialized = serde_json::to_string(&digital).unwrap();
+        let deserialized: DigitalOption = serde_json::from_str(&serialized).unwrap();
+        let reserialized = serde_json::to_string(&deserialized).unwrap();
+        assert_eq!(serialized, reserialized);
+    }
+}
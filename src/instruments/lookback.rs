@@ -0,0 +1,524 @@
+use std::sync::Arc;
+use instruments::Instrument;
+use instruments::RcInstrument;
+use instruments::DependencyContext;
+use instruments::SpotRequirement;
+use instruments::MonteCarloPriceable;
+use instruments::MonteCarloDependencies;
+use instruments::ObservationKind;
+use instruments::MonteCarloContext;
+use instruments::options::PutOrCall;
+use instruments::assets::Currency;
+use instruments::assets::RcCurrency;
+use instruments::bonds::ZeroCoupon;
+use data::fixings::FixingTable;
+use dates::Date;
+use dates::calendar::RcCalendar;
+use dates::rules::RcDateRule;
+use dates::datetime::DateTime;
+use dates::datetime::DateDayFraction;
+use core::qm;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use core::dedup::InstanceId;
+use ndarray::Axis;
+use ndarray::Array2;
+use erased_serde as esd;
+use serde::Deserialize;
+
+/// A fixed-strike lookback pays off on the extreme of the underlying against
+/// a strike. A floating-strike lookback pays off on the final value of the
+/// underlying against its extreme, which acts as the strike.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum LookbackStrike { Fixed(f64), Floating }
+
+/// A lookback option pays off on the maximum or minimum of the underlying
+/// over a monitoring window, which ends at the expiry. The payment is made
+/// at the settlement date following the expiry. The extreme is always the
+/// one that favours the holder:
+///
+/// * Fixed-strike call: (max - K).max(0)
+/// * Fixed-strike put: (K - min).max(0)
+/// * Floating-strike call: S - min
+/// * Floating-strike put: max - S
+///
+/// where S is the underlying at expiry.
+///
+/// The extreme is only taken over the monitoring dates, so it is biased
+/// towards the centre compared with continuous monitoring, and lookbacks
+/// are undervalued by an amount that shrinks roughly as the square root of
+/// the spacing of the dates. (Broadie, Glasserman and Kou show that the
+/// discrete extreme behaves like the continuous one moved towards spot by
+/// a factor of exp(0.5826 sigma sqrt(dt)).) Unlike a barrier, there is no
+/// simple bridge correction, so the monitoring dates should be dense:
+/// normally every business day, and each of them is an observation on the
+/// Monte-Carlo timeline.
+///
+/// Once some of the monitoring dates are in the past, fixing the option
+/// removes them from the monitoring dates and records the extreme of their
+/// fixings.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct LookbackOption {
+    id: String,
+    credit_id: String,
+    underlying: RcInstrument,
+    settlement: RcDateRule,
+    monitoring_dates: Vec<DateTime>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    past_extreme: Option<f64>,
+    strike: LookbackStrike,
+    put_or_call: PutOrCall,
+
+    // fields precomputed for performance and simplicity
+    monitoring_times: Vec<DateDayFraction>,
+    pay_date: Date
+}
+
+impl TypeId for LookbackOption {
+    fn get_type_id(&self) -> &'static str { "LookbackOption" }
+}
+
+impl InstanceId for LookbackOption {
+    fn id(&self) -> &str { &self.id }
+}
+
+impl LookbackOption {
+    /// Creates a lookback option monitored daily, on every business day in
+    /// the calendar from monitoring_start up to and including the expiry.
+    /// All the monitoring dates are at the same time of day as the expiry.
+    pub fn new(
+        id: &str,
+        credit_id: &str,
+        underlying: RcInstrument,
+        settlement: RcDateRule,
+        calendar: &RcCalendar,
+        monitoring_start: Date,
+        expiry: DateTime,
+        strike: LookbackStrike,
+        put_or_call: PutOrCall)
+        -> Result<LookbackOption, qm::Error> {
+
+        let mut monitoring_dates = Vec::new();
+        let mut date = calendar.step(monitoring_start, 0, true);
+        while date < expiry.date() {
+            monitoring_dates.push(DateTime::new(date, expiry.time_of_day()));
+            date = calendar.step(date, 1, true);
+        }
+        monitoring_dates.push(expiry);
+
+        LookbackOption::from_dates(id, credit_id, underlying, settlement,
+            &monitoring_dates, strike, put_or_call)
+    }
+
+    /// Creates a lookback option with an arbitrary set of monitoring dates,
+    /// which must be supplied in strictly increasing order. The last of them
+    /// is the expiry.
+    pub fn from_dates(
+        id: &str,
+        credit_id: &str,
+        underlying: RcInstrument,
+        settlement: RcDateRule,
+        monitoring_dates: &[DateTime],
+        strike: LookbackStrike,
+        put_or_call: PutOrCall)
+        -> Result<LookbackOption, qm::Error> {
+
+        LookbackOption::with_past_extreme(id, credit_id, underlying,
+            settlement, monitoring_dates, None, strike, put_or_call)
+    }
+
+    /// Creates a lookback option where monitoring has already started, so
+    /// there is an extreme from the past fixings.
+    fn with_past_extreme(
+        id: &str,
+        credit_id: &str,
+        underlying: RcInstrument,
+        settlement: RcDateRule,
+        monitoring_dates: &[DateTime],
+        past_extreme: Option<f64>,
+        strike: LookbackStrike,
+        put_or_call: PutOrCall)
+        -> Result<LookbackOption, qm::Error> {
+
+        if let LookbackStrike::Fixed(strike) = strike {
+            if strike < 0.0 {
+                return Err(qm::Error::new("Strike must be greater or equal to zero"))
+            }
+        }
+
+        let last = monitoring_dates.last().ok_or_else(|| qm::Error::new(
+            "A lookback option must have at least one monitoring date"))?;
+        for pair in monitoring_dates.windows(2) {
+            if pair[0] >= pair[1] {
+                return Err(qm::Error::new(
+                    "Monitoring dates must be in strictly increasing order"))
+            }
+        }
+
+        let pay_date = settlement.apply(last.date());
+        let mut monitoring_times = Vec::with_capacity(monitoring_dates.len());
+        for date in monitoring_dates.iter() {
+            monitoring_times.push(underlying.time_to_day_fraction(*date)?);
+        }
+
+        Ok(LookbackOption {
+            id: id.to_string(),
+            credit_id: credit_id.to_string(),
+            underlying: underlying,
+            settlement: settlement,
+            monitoring_dates: monitoring_dates.to_vec(),
+            past_extreme: past_extreme,
+            strike: strike,
+            put_or_call: put_or_call,
+            monitoring_times: monitoring_times,
+            pay_date: pay_date })
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<Instrument>, esd::Error> {
+        Ok(Qrc::new(Arc::new(LookbackOption::deserialize(de)?)))
+    }
+
+    fn expiry(&self) -> DateTime {
+        *self.monitoring_dates.last().unwrap()
+    }
+
+    /// Does the payoff depend on the maximum of the underlying, rather than
+    /// the minimum?
+    fn tracks_maximum(&self) -> bool {
+        matches!((self.strike, self.put_or_call),
+            (LookbackStrike::Fixed(_), PutOrCall::Call)
+            | (LookbackStrike::Floating, PutOrCall::Put))
+    }
+
+    /// Combines an extreme so far with another value of the underlying
+    fn extreme(&self, extreme: f64, spot: f64) -> f64 {
+        if self.tracks_maximum() { extreme.max(spot) } else { extreme.min(spot) }
+    }
+
+    /// The payoff given the extreme and the underlying at expiry
+    fn payoff(&self, extreme: f64, spot: f64) -> f64 {
+        match (self.strike, self.put_or_call) {
+            (LookbackStrike::Fixed(strike), PutOrCall::Call) => (extreme - strike).max(0.0),
+            (LookbackStrike::Fixed(strike), PutOrCall::Put) => (strike - extreme).max(0.0),
+            (LookbackStrike::Floating, PutOrCall::Call) => (spot - extreme).max(0.0),
+            (LookbackStrike::Floating, PutOrCall::Put) => (extreme - spot).max(0.0) }
+    }
+
+    /// The cash payment at the pay date
+    fn payment(&self) -> RcInstrument {
+        // TODO this feels inefficient and ugly
+        let currency = RcCurrency::new(Arc::new(self.payoff_currency().clone()));
+        RcInstrument::new(Qrc::new(Arc::new(
+            ZeroCoupon::new(&format!("{}:Expiry", self.id),
+            &self.credit_id, currency, self.expiry(), self.pay_date,
+            self.settlement.clone()))))
+    }
+}
+
+impl Instrument for LookbackOption {
+    fn payoff_currency(&self) -> &Currency { self.underlying.payoff_currency() }
+    fn credit_id(&self) -> &str { &self.credit_id }
+    fn settlement(&self) -> &RcDateRule { &self.settlement }
+    fn as_mc_priceable(&self) -> Option<&MonteCarloPriceable> { Some(self) }
+
+    fn dependencies(&self, context: &mut DependencyContext)
+        -> SpotRequirement {
+
+        // one fixing on each monitoring date
+        for date in self.monitoring_dates.iter() {
+            context.fixing(self.underlying.id(), *date);
+        }
+
+        let expiry_date = self.expiry().date();
+        context.yield_curve(self.credit_id(), self.pay_date);
+        context.forward_curve(&self.underlying, expiry_date);
+        context.vol_surface(&self.underlying, expiry_date);
+
+        SpotRequirement::NotRequired
+    }
+
+    fn fix(&self, fixing_table: &FixingTable)
+        -> Result<Option<Vec<(f64, RcInstrument)>>, qm::Error> {
+
+        // Find the extreme of the monitoring dates that have fixed (the
+        // fetch errors if a fixing in the past is missing). These must all
+        // come before the dates that have not fixed.
+        let mut extreme = self.past_extreme;
+        let mut last_fixing = None;
+        let mut n_fixed = 0;
+        let mut unfixed = false;
+        for date in self.monitoring_dates.iter() {
+            match fixing_table.get(self.underlying.id(), *date)? {
+                Some(_) if unfixed => return Err(qm::Error::new(&format!(
+                    "Lookback option {} has a fixing after an unfixed \
+                    monitoring date", self.id))),
+                Some(fixing) => {
+                    extreme = Some(match extreme {
+                        Some(extreme) => self.extreme(extreme, fixing),
+                        None => fixing });
+                    last_fixing = Some(fixing);
+                    n_fixed += 1;
+                },
+                None => unfixed = true
+            }
+        }
+        if n_fixed == 0 {
+            return Ok(None)
+        }
+
+        // If all dates have fixed, the option becomes a cash payment
+        if n_fixed == self.monitoring_dates.len() {
+            let payment = self.payoff(extreme.unwrap(), last_fixing.unwrap());
+            let mut decomp = Vec::new();
+            if payment > 0.0 {
+                decomp.push((payment, self.payment()));
+            }
+            return Ok(Some(decomp))
+        }
+
+        let fixed = LookbackOption::with_past_extreme(&self.id, &self.credit_id,
+            self.underlying.clone(), self.settlement.clone(),
+            &self.monitoring_dates[n_fixed..], extreme, self.strike,
+            self.put_or_call)?;
+        Ok(Some(vec!((1.0, RcInstrument::new(Qrc::new(Arc::new(fixed)))))))
+    }
+}
+
+impl MonteCarloPriceable for LookbackOption {
+    fn as_instrument(&self) -> &Instrument { self }
+
+    fn mc_dependencies(&self, _dates: &[DateDayFraction],
+        output: &mut MonteCarloDependencies) -> Result<(), qm::Error> {
+
+        // an observation on every monitoring date, so the timeline is as
+        // dense as the monitoring
+        for time in self.monitoring_times.iter() {
+            output.observation(&self.underlying, *time, ObservationKind::Fixing);
+        }
+
+        // a single cash payment at the pay date
+        output.flow(&self.payment());
+        Ok(())
+    }
+
+    fn start_date(&self) -> Option<DateDayFraction> {
+        None
+    }
+
+    fn mc_price(&self, context: &MonteCarloContext)
+        -> Result<f64, qm::Error> {
+
+        let ref paths = context.paths(&self.underlying)?;
+        let n_paths = paths.shape()[0];
+        let n_obs = paths.shape()[1];
+        assert_eq!(n_obs, self.monitoring_times.len());
+
+        let mut quantities = Array2::zeros((n_paths, 1));
+        {
+            let ref mut flow_column = quantities.subview_mut(Axis(1), 0);
+            for (path, flow) in paths.axis_iter(Axis(0)).zip(flow_column.iter_mut()) {
+                let spot = path[n_obs - 1];
+                let start = self.past_extreme.unwrap_or(path[0]);
+                let extreme = path.iter().fold(start, |e, s| self.extreme(e, *s));
+                *flow = self.payoff(extreme, spot);
+            }
+        }
+
+        context.evaluate_flows(quantities.view())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pricers::montecarlo::tests::black_diffusion_price_with_stderr;
+    use math::numerics::approx_eq;
+    use dates::datetime::TimeOfDay;
+    use dates::calendar::WeekdayCalendar;
+    use risk::marketdata::MarketData;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::marketdata::tests::sample_european;
+    use risk::marketdata::tests::sample_currency;
+    use risk::marketdata::tests::sample_settlement;
+    use risk::marketdata::tests::sample_equity;
+    use serde_json;
+
+    fn sample_underlying() -> RcInstrument {
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        RcInstrument::new(Qrc::new(Arc::new(sample_equity(currency, 2))))
+    }
+
+    fn expiry() -> DateTime {
+        DateTime::new(Date::from_ymd(2017, 06, 30), TimeOfDay::Close)
+    }
+
+    /// Monitored daily for the first half of 2017
+    fn sample_lookback(strike: LookbackStrike, put_or_call: PutOrCall)
+        -> LookbackOption {
+        let calendar = RcCalendar::new(Arc::new(WeekdayCalendar::new()));
+        LookbackOption::new("SampleLookback", "OPT", sample_underlying(),
+            sample_settlement(2), &calendar, Date::from_ymd(2017, 01, 03),
+            expiry(), strike, put_or_call).unwrap()
+    }
+
+    /// The same as the sample lookback, but only monitored on every fifth
+    /// of its dates, roughly weekly
+    fn sparse_lookback(strike: LookbackStrike, put_or_call: PutOrCall)
+        -> LookbackOption {
+        let daily = sample_lookback(strike, put_or_call);
+        let n = daily.monitoring_dates.len();
+        let weekly: Vec<DateTime> = daily.monitoring_dates.iter().cloned()
+            .enumerate().filter(|&(i, _)| (n - 1 - i) % 5 == 0)
+            .map(|(_, d)| d).collect();
+        LookbackOption::from_dates("SampleLookback", "OPT", sample_underlying(),
+            sample_settlement(2), &weekly, strike, put_or_call).unwrap()
+    }
+
+    fn mc_price_with_stderr(instrument: RcInstrument, market_data: &MarketData)
+        -> (f64, f64) {
+        black_diffusion_price_with_stderr(instrument, market_data, 0.01, 20000).unwrap()
+    }
+
+    fn lookback_price(lookback: LookbackOption) -> (f64, f64) {
+        mc_price_with_stderr(RcInstrument::new(Qrc::new(Arc::new(lookback))),
+            &sample_market_data())
+    }
+
+    #[test]
+    fn lookback_rolls_out_daily_monitoring() {
+        let lookback = sample_lookback(LookbackStrike::Floating, PutOrCall::Call);
+        assert_eq!(lookback.monitoring_dates.len(), 129);
+        assert_eq!(lookback.monitoring_dates[0].date(), Date::from_ymd(2017, 01, 03));
+        assert_eq!(lookback.expiry(), expiry());
+        assert_eq!(sparse_lookback(LookbackStrike::Floating, PutOrCall::Call)
+            .monitoring_dates.len(), 26);
+    }
+
+    #[test]
+    fn lookback_with_single_date_matches_european() {
+
+        // Monitored only at expiry, a fixed-strike call is a European. It
+        // sees exactly the same paths, so the prices match exactly.
+        let european = sample_european();
+        let expiry = DateTime::new(Date::from_ymd(2018, 06, 01), TimeOfDay::Close);
+        let lookback = LookbackOption::from_dates("SampleLookback", "OPT",
+            sample_underlying(), sample_settlement(2), &[expiry],
+            LookbackStrike::Fixed(100.0), PutOrCall::Call).unwrap();
+        let market_data = sample_market_data();
+        let (expected, _) = mc_price_with_stderr(
+            RcInstrument::new(Qrc::new(european)), &market_data);
+        let (price, _) = mc_price_with_stderr(
+            RcInstrument::new(Qrc::new(Arc::new(lookback))), &market_data);
+        assert_approx(price, expected, 1e-10);
+    }
+
+    #[test]
+    fn lookback_discretization_bias() {
+
+        // For each kind of lookback, sparser monitoring misses some of the
+        // extremes, so undervalues the option by much more than the noise.
+        for &(strike, put_or_call) in [
+            (LookbackStrike::Fixed(100.0), PutOrCall::Call),
+            (LookbackStrike::Fixed(100.0), PutOrCall::Put),
+            (LookbackStrike::Floating, PutOrCall::Call),
+            (LookbackStrike::Floating, PutOrCall::Put)].iter() {
+
+            let (daily, daily_err) = lookback_price(sample_lookback(strike, put_or_call));
+            let (weekly, weekly_err) = lookback_price(sparse_lookback(strike, put_or_call));
+            let noise = (daily_err * daily_err + weekly_err * weekly_err).sqrt();
+            assert!(daily - weekly > 3.0 * noise,
+                "{:?} {:?}: daily={} weekly={} noise={}",
+                strike, put_or_call, daily, weekly, noise);
+
+            // The Broadie-Glasserman-Kou shift of the extreme predicts the
+            // bias to within a fraction of itself. With a vol of 30%, the
+            // difference in spacing of the dates accounts for roughly one
+            // percent of spot in the extreme.
+            let dt_daily: f64 = 1.0 / 252.0;
+            let dt_weekly: f64 = 5.0 / 252.0;
+            let shift = 0.5826 * 0.3 * (dt_weekly.sqrt() - dt_daily.sqrt());
+            let predicted = 100.0 * shift;
+            assert!((daily - weekly) > 0.5 * predicted && (daily - weekly) < 1.5 * predicted,
+                "{:?} {:?}: bias={} predicted={}", strike, put_or_call,
+                daily - weekly, predicted);
+        }
+    }
+
+    #[test]
+    fn lookback_worth_more_than_european() {
+        let (lookback, _) = lookback_price(
+            sample_lookback(LookbackStrike::Fixed(100.0), PutOrCall::Call));
+        let european = LookbackOption::from_dates("SampleLookback", "OPT",
+            sample_underlying(), sample_settlement(2), &[expiry()],
+            LookbackStrike::Fixed(100.0), PutOrCall::Call).unwrap();
+        let (european, _) = lookback_price(european);
+        assert!(lookback > 1.5 * european, "lookback={} european={}", lookback, european);
+    }
+
+    fn past_fixings(lookback: &LookbackOption, values: &[f64]) -> FixingTable {
+        let fixings: Vec<(DateTime, f64)> = lookback.monitoring_dates.iter()
+            .cloned().zip(values.iter().cloned()).collect();
+        let today = fixings.last().unwrap().0.date() + 1;
+        FixingTable::from_fixings(today, &[("BP.L", &fixings)]).unwrap()
+    }
+
+    #[test]
+    fn lookback_fix_with_past_monitoring_dates() {
+
+        // the running maximum is kept for a fixed-strike call
+        let lookback = sample_lookback(LookbackStrike::Fixed(100.0), PutOrCall::Call);
+        let decomp = lookback.fix(&past_fixings(&lookback, &[101.0, 108.0, 97.0]))
+            .unwrap().unwrap();
+        assert_eq!(decomp.len(), 1);
+        assert_approx(decomp[0].0, 1.0, 1e-12);
+        let value = serde_json::to_value(&decomp[0].1).unwrap();
+        assert_eq!(value["LookbackOption"]["past_extreme"], 108.0);
+        assert_eq!(value["LookbackOption"]["monitoring_dates"].as_array().unwrap().len(), 126);
+
+        // and the running minimum for a floating-strike call
+        let lookback = sample_lookback(LookbackStrike::Floating, PutOrCall::Call);
+        let decomp = lookback.fix(&past_fixings(&lookback, &[101.0, 108.0, 97.0]))
+            .unwrap().unwrap();
+        let value = serde_json::to_value(&decomp[0].1).unwrap();
+        assert_eq!(value["LookbackOption"]["past_extreme"], 97.0);
+    }
+
+    #[test]
+    fn lookback_fix_when_fully_fixed() {
+        let dates: Vec<DateTime> = (3..6).map(|day| DateTime::new(
+            Date::from_ymd(2017, 01, day), TimeOfDay::Close)).collect();
+        for &(strike, put_or_call, expected) in [
+            (LookbackStrike::Fixed(100.0), PutOrCall::Call, 8.0),
+            (LookbackStrike::Fixed(100.0), PutOrCall::Put, 0.0),
+            (LookbackStrike::Floating, PutOrCall::Call, 6.0),
+            (LookbackStrike::Floating, PutOrCall::Put, 2.0)].iter() {
+
+            let lookback = LookbackOption::from_dates("SampleLookback", "OPT",
+                sample_underlying(), sample_settlement(2), &dates, strike,
+                put_or_call).unwrap();
+            let decomp = lookback.fix(&past_fixings(&lookback, &[100.0, 108.0, 106.0]))
+                .unwrap().unwrap();
+            if expected == 0.0 {
+                assert!(decomp.is_empty());
+            } else {
+                assert_eq!(decomp.len(), 1);
+                assert_approx(decomp[0].0, expected, 1e-12);
+                assert_eq!(decomp[0].1.id(), "SampleLookback:Expiry");
+            }
+        }
+    }
+
+    #[test]
+    fn lookback_serde() {
+        let lookback = sample_lookback(LookbackStrike::Fixed(100.0), PutOrCall::Put);
+        let serialized = serde_json::to_string(&lookback).unwrap();
+        let deserialized: LookbackOption = serde_json::from_str(&serialized).unwrap();
+        let reserialized = serde_json::to_string(&deserialized).unwrap();
+        assert_eq!(serialized, reserialized);
+    }
+
+    fn assert_approx(value: f64, expected: f64, tolerance: f64) {
+        assert!(approx_eq(value, expected, tolerance),
+            "value={} expected={}", value, expected);
+    }
+}
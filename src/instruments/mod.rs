@@ -1,26 +1,58 @@
 pub mod assets;
 pub mod bonds;
+pub mod caplet;
 pub mod options;
 pub mod basket;
+pub mod rainbow;
+pub mod spread;
+pub mod asian;
+pub mod american;
+pub mod bermudan;
+pub mod barrier;
+pub mod digital;
+pub mod rangeaccrual;
+pub mod lookback;
+pub mod cliquet;
+pub mod varianceswap;
+pub mod accumulator;
+pub mod structured;
 
 use instruments::assets::Currency;
 use instruments::assets::CreditEntity;
 use instruments::assets::Equity;
 use instruments::bonds::ZeroCoupon;
+use instruments::bonds::CashflowStream;
+use instruments::caplet::Caplet;
 use instruments::basket::Basket;
+use instruments::basket::BasketOption;
+use instruments::rainbow::RainbowOption;
+use instruments::spread::SpreadOption;
 use instruments::options::SpotStartingEuropean;
 use instruments::options::ForwardStartingEuropean;
+use instruments::asian::AsianOption;
+use instruments::american::AmericanOption;
+use instruments::bermudan::BermudanOption;
+use instruments::barrier::BarrierOption;
+use instruments::digital::DigitalOption;
+use instruments::rangeaccrual::RangeAccrual;
+use instruments::lookback::LookbackOption;
+use instruments::cliquet::CliquetOption;
+use instruments::varianceswap::VarianceSwap;
+use instruments::accumulator::Accumulator;
+use instruments::structured::StructuredNote;
 use dates::Date;
 use dates::datetime::TimeOfDay;
 use dates::rules::RcDateRule;
 use dates::datetime::DateTime;
 use dates::datetime::DateDayFraction;
 use data::curves::RcRateCurve;
+use data::fx::FxRate;
 use data::forward::Forward;
 use data::volsurface::RcVolSurface;
 use data::volsurface::VolTimeDynamics;
 use data::volsurface::VolForwardDynamics;
 use data::fixings::FixingTable;
+use risk::dependencies::DependencyCollector;
 use core::qm;
 use core::factories::TypeId;
 use core::factories::Registry;
@@ -126,10 +158,35 @@ pub trait Instrument : esd::Serialize + TypeId + InstanceId + Sync + Send + Debu
         None
     }
 
+    /// Cast from instrument to a priceable, but only if its price is exact,
+    /// rather than an approximation. Pricer factories that can choose how to
+    /// value an instrument use this to decide whether a simulation is needed.
+    /// By default, every priceable instrument is assumed to be exact.
+    fn as_analytic_priceable(&self) -> Option<&Priceable> {
+        self.as_priceable()
+    }
+
     /// Cast from instrument to an mc_priceable. Returns None if not possible.
     fn as_mc_priceable(&self) -> Option<&MonteCarloPriceable> {
         None
     }
+
+    /// Cast from instrument to a pde_priceable. Returns None if not possible.
+    fn as_pde_priceable(&self) -> Option<&PdePriceable> {
+        None
+    }
+
+    /// Cast from instrument to a control variate. Returns None if the
+    /// instrument does not supply one, which is the default.
+    fn as_control_variate(&self) -> Option<&ControlVariate> {
+        None
+    }
+
+    /// Cast from instrument to one with closed-form greeks. Returns None if
+    /// not possible, which is the default.
+    fn as_closed_form_greeks(&self) -> Option<&ClosedFormGreeks> {
+        None
+    }
 }
 
 /// Utility method to fix all instruments in a vector, returning them as a weighted vector.
@@ -198,9 +255,25 @@ pub fn get_registry() -> &'static TypeRegistry {
             reg.insert("Equity", BoxFnSeed::new(Equity::from_serial));
             reg.insert("Equity", BoxFnSeed::new(Equity::from_serial));
             reg.insert("ZeroCoupon", BoxFnSeed::new(ZeroCoupon::from_serial));
+            reg.insert("CashflowStream", BoxFnSeed::new(CashflowStream::from_serial));
+            reg.insert("Caplet", BoxFnSeed::new(Caplet::from_serial));
             reg.insert("Basket", BoxFnSeed::new(Basket::from_serial));
             reg.insert("SpotStartingEuropean", BoxFnSeed::new(SpotStartingEuropean::from_serial));
             reg.insert("ForwardStartingEuropean", BoxFnSeed::new(ForwardStartingEuropean::from_serial));
+            reg.insert("AsianOption", BoxFnSeed::new(AsianOption::from_serial));
+            reg.insert("AmericanOption", BoxFnSeed::new(AmericanOption::from_serial));
+            reg.insert("BermudanOption", BoxFnSeed::new(BermudanOption::from_serial));
+            reg.insert("BarrierOption", BoxFnSeed::new(BarrierOption::from_serial));
+            reg.insert("DigitalOption", BoxFnSeed::new(DigitalOption::from_serial));
+            reg.insert("BasketOption", BoxFnSeed::new(BasketOption::from_serial));
+            reg.insert("RainbowOption", BoxFnSeed::new(RainbowOption::from_serial));
+            reg.insert("SpreadOption", BoxFnSeed::new(SpreadOption::from_serial));
+            reg.insert("RangeAccrual", BoxFnSeed::new(RangeAccrual::from_serial));
+            reg.insert("LookbackOption", BoxFnSeed::new(LookbackOption::from_serial));
+            reg.insert("CliquetOption", BoxFnSeed::new(CliquetOption::from_serial));
+            reg.insert("VarianceSwap", BoxFnSeed::new(VarianceSwap::from_serial));
+            reg.insert("Accumulator", BoxFnSeed::new(Accumulator::from_serial));
+            reg.insert("StructuredNote", BoxFnSeed::new(StructuredNote::from_serial));
             reg
         };
     }
@@ -348,6 +421,11 @@ pub trait DependencyContext {
     /// Specify a dependency on a specific fixing, by underlier id and
     /// date-time
     fn fixing(&mut self, id: &str, date: DateTime);
+
+    /// Specify a dependency on the FX rate between two currencies. Also
+    /// specify a date beyond which we never need the rate.
+    fn fx_rate(&mut self, base_currency: &str, quote_currency: &str,
+        high_water_mark: Date);
 }
 
 /// The external dependencies of an instrument. For example, valuation may
@@ -440,6 +518,64 @@ pub trait Priceable : Instrument {
     fn as_instrument(&self) -> &Instrument;
 }
 
+/// Some simple instruments, such as European options, have closed-form
+/// sensitivities as well as a closed-form price. These give noise-free
+/// benchmarks for greeks calculated by bumping and repricing.
+pub trait ClosedFormGreeks : Priceable {
+    /// The id of the underlying the delta and gamma are with respect to
+    fn greeks_underlying(&self) -> &str;
+
+    /// Calculates the sensitivities of the price on the given val date, which
+    /// is discounted in the same way as by Priceable::price.
+    fn analytic_greeks(&self, context: &PricingContext, val_date: DateTime)
+        -> Result<Greeks, qm::Error>;
+}
+
+/// Sensitivities of the price of an instrument to a single underlying and
+/// to rates. Vega is per unit of flat additive vol, so it is comparable with
+/// a BumpVol::new_flat_additive bump divided by its size, theta is per year
+/// of vol time and rho is per unit of continuously compounded rate.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct Greeks {
+    delta: f64,
+    gamma: f64,
+    vega: f64,
+    theta: f64,
+    rho: f64
+}
+
+impl Greeks {
+    pub fn new(delta: f64, gamma: f64, vega: f64, theta: f64, rho: f64) -> Greeks {
+        Greeks { delta: delta, gamma: gamma, vega: vega, theta: theta, rho: rho }
+    }
+
+    /// The first derivative of the price with respect to spot
+    pub fn delta(&self) -> f64 { self.delta }
+
+    /// The second derivative of the price with respect to spot
+    pub fn gamma(&self) -> f64 { self.gamma }
+
+    /// The derivative of the price with respect to a flat shift in vol
+    pub fn vega(&self) -> f64 { self.vega }
+
+    /// The rate of change of the price from the passage of vol time, with
+    /// the forward and discounting unchanged
+    pub fn theta(&self) -> f64 { self.theta }
+
+    /// The derivative of the price with respect to a parallel shift in rates
+    pub fn rho(&self) -> f64 { self.rho }
+
+    /// Adds the greeks of a position of the given weight in another
+    /// instrument to these.
+    pub fn add_weighted(&mut self, weight: f64, other: &Greeks) {
+        self.delta += weight * other.delta;
+        self.gamma += weight * other.gamma;
+        self.vega += weight * other.vega;
+        self.theta += weight * other.theta;
+        self.rho += weight * other.rho;
+    }
+}
+
 /// Sometimes it is useful to treat a priceable as if it were a forward curve.
 /// The only issue is that a priceable takes a DateTime and a forward takes a date.
 /// We require the user to pass in a time of day, so we can convert.
@@ -487,6 +623,15 @@ pub trait PricingContext : Sync + Send {
     fn forward_curve(&self, instrument: &Instrument, high_water_mark: Date)
         -> Result<Arc<Forward>, qm::Error>;
 
+    /// Gets the forward of any instrument on the given date. Discrete
+    /// dividends are taken on their ex dates, so the forward on an ex date
+    /// is already net of that date's dividends, and steps down from the day
+    /// before. Defaults to fetching the forward curve out to the given date.
+    fn forward(&self, instrument: &Instrument, date: Date)
+        -> Result<f64, qm::Error> {
+        self.forward_curve(instrument, date)?.forward(date)
+    }
+
     /// Gets a Vol Surface, given any instrument, for example an equity.  Also
     /// specify a high water mark, beyond which we never directly ask for
     /// vols.
@@ -506,6 +651,66 @@ pub trait PricingContext : Sync + Send {
     /// structure of at the money vols for each asset.
     fn correlation(&self, first: &Instrument, second: &Instrument)
         -> Result<f64, qm::Error>;
+
+    /// Gets the FX rate between two currencies, as the number of units of
+    /// the quote currency per unit of the base currency. A currency quoted
+    /// against itself always has a rate of one. Defaults to an error, for
+    /// contexts that do not supply FX rates.
+    fn fx_rate(&self, base_currency: &str, quote_currency: &str)
+        -> Result<FxRate, qm::Error> {
+        if base_currency == quote_currency {
+            Ok(FxRate::unity())
+        } else {
+            Err(qm::Error::new("No FX rates supplied"))
+        }
+    }
+
+    /// Gets the instantaneous correlation between the FX rate for the given
+    /// pair of currencies and an instrument. Defaults to an error, for
+    /// contexts that do not supply FX rates.
+    fn fx_correlation(&self, _base_currency: &str, _quote_currency: &str,
+        _instrument: &Instrument) -> Result<f64, qm::Error> {
+        Err(qm::Error::new("No FX correlations supplied"))
+    }
+
+    /// Gets the drift correction for diffusing an instrument under the
+    /// measure of the given currency rather than its own, per unit of its
+    /// instantaneous vol and of vol time. This is -rho sigma_X, where sigma_X
+    /// is the vol of the FX rate in units of the given currency per unit of
+    /// the instrument's currency, and rho is its correlation with the
+    /// instrument. It is exactly zero in the instrument's own currency.
+    fn quanto_drift(&self, instrument: &Instrument, currency: &str)
+        -> Result<f64, qm::Error> {
+        let own = instrument.payoff_currency().id();
+        if own == currency {
+            return Ok(0.0)
+        }
+        let fx = self.fx_rate(own, currency)?;
+        let rho = self.fx_correlation(own, currency, instrument)?;
+        Ok(-rho * fx.vol())
+    }
+
+    /// Gets the discount factor from the given date back to the value date,
+    /// using the yield curve for the given credit id. Prices are discounted
+    /// to the value date, which is the settlement date of the spot date for
+    /// the instrument being priced, so this is the same discounting that
+    /// both the analytic and Monte-Carlo pricers apply, and dividing a price
+    /// by it gives a forward value.
+    ///
+    /// Dates before the spot date are an error, rather than extrapolating.
+    fn discount_to_value_date(&self, credit_id: &str, date: Date,
+        value_date: Date) -> Result<f64, qm::Error> {
+
+        let spot_date = self.spot_date();
+        if date < spot_date {
+            return Err(qm::Error::new(&format!(
+                "Cannot discount from {} as it is before the spot date {}",
+                date, spot_date)))
+        }
+
+        let yc = self.yield_curve(credit_id, date)?;
+        yc.df(date, value_date)
+    }
 }
 
 /// Allow an instrument to be priced using Monte-Carlo. The way this works is
@@ -549,28 +754,150 @@ pub trait MonteCarloPriceable : Instrument {
     fn start_date(&self) -> Option<DateDayFraction>;
 
     /// Allow an instrument to price itself in a MonteCarlo context. Returns
-    /// the price, discounted to the discount date. If additional output
-    /// information is required such as convergence graphs or per-flow pricing,
-    /// this is collected by a decorator to the context. (The context is
-    /// immutable, so this must be done using RefCell.)
+    /// the present value as of the spot date, in the same terms as the price
+    /// from Priceable: the expected payoff under the risk-neutral measure,
+    /// discounted from each payment date. Instruments should not discount
+    /// their payoffs themselves. Instead, they express each payment as a
+    /// quantity of one of the flows registered in mc_dependencies, and pass
+    /// the quantities to evaluate_flows on the context, which discounts them.
+    /// If additional output information is required such as convergence
+    /// graphs or per-flow pricing, this is collected by a decorator to the
+    /// context. (The context is immutable, so this must be done using
+    /// RefCell.)
     fn mc_price(&self, context: &MonteCarloContext) -> Result<f64, qm::Error>;
 
+    /// The expected cashflows of the instrument before discounting, as
+    /// pairs of payment date and amount, in order of payment date.
+    /// Discounting each amount from its payment date to the settlement of
+    /// the spot date, and summing, gives the present value from mc_price.
+    ///
+    /// The default suits payoffs that pay on a single date. It reports the
+    /// whole of the price from mc_price as one cashflow, on the last date
+    /// for which the instrument needs its yield curve. Instruments that pay
+    /// on several dates should override it.
+    fn mc_cashflows(&self, context: &MonteCarloContext)
+        -> Result<Vec<(Date, f64)>, qm::Error> {
+        let value = self.mc_price(context)?;
+        terminal_cashflow(self.as_instrument(), context.pricing_context(), value)
+    }
+
+    /// The value of the instrument if it is already known without any
+    /// simulation, in the same terms as mc_price, or None if it must be
+    /// simulated. This is for degenerate cases such as an option expiring
+    /// on the spot date, where the observation is taken to be today's spot
+    /// and simulating would only add noise around the intrinsic value.
+    ///
+    /// Implementations must be conservative. If there is any doubt that the
+    /// payoff is deterministic, they should return None. The default always
+    /// returns None.
+    fn deterministic_value(&self, _context: &PricingContext)
+        -> Result<Option<f64>, qm::Error> {
+        Ok(None)
+    }
+
     /// Return this object as an instrument
     fn as_instrument(&self) -> &Instrument;
 }
 
+/// Turns the present value of an instrument into a single undiscounted
+/// cashflow, on the last date for which the instrument needs its yield
+/// curve. If the instrument needs no discounting, the cashflow is on the
+/// spot date.
+pub fn terminal_cashflow(instrument: &Instrument, context: &PricingContext,
+    value: f64) -> Result<Vec<(Date, f64)>, qm::Error> {
+
+    let spot_date = context.spot_date();
+    let mut collector = DependencyCollector::new(spot_date);
+    instrument.dependencies(&mut collector);
+    let credit_id = instrument.credit_id();
+    let pay_date = match collector.yield_curve_hwm(credit_id) {
+        Some(date) => date,
+        None => return Ok(vec!((spot_date, value)))
+    };
+
+    let yc = context.yield_curve(credit_id, pay_date)?;
+    let df = yc.df(pay_date, instrument.settlement().apply(spot_date))?;
+    Ok(vec!((pay_date, value / df)))
+}
+
+/// Allow an instrument to be priced by solving a one-dimensional partial
+/// differential equation, backwards in time from expiry. At present, this
+/// is limited to instruments with a single underlying and a payoff that
+/// depends only on the value of the underlying at expiry, paid as a single
+/// cashflow.
+pub trait PdePriceable : Instrument {
+
+    /// The single underlying on which the payoff depends
+    fn pde_underlying(&self) -> &RcInstrument;
+
+    /// The time of expiry, when the payoff is determined
+    fn pde_expiry(&self) -> DateDayFraction;
+
+    /// The payment made at the pay date. The payoff is expressed as a
+    /// quantity of this, so it should normally be a zero coupon.
+    fn pde_payment(&self) -> RcInstrument;
+
+    /// The quantity of the payment received, given the value of the
+    /// underlying at expiry
+    fn pde_payoff(&self, spot: f64) -> f64;
+
+    /// Return this object as an instrument
+    fn as_instrument(&self) -> &Instrument;
+}
+
+/// A control variate is a payoff closely related to that of an instrument,
+/// but with a value that is known analytically. During Monte-Carlo pricing,
+/// the control is valued over the same paths as the instrument. The error in
+/// the simulated value of the control is then used to correct the price of
+/// the instrument. For example, a geometric-average Asian option makes a good
+/// control for an arithmetic-average one.
+///
+/// The control must be valued using the same flows that the instrument
+/// specified in mc_dependencies, and must not require any observations that
+/// the instrument did not ask for.
+pub trait ControlVariate : MonteCarloPriceable {
+
+    /// The analytic value of the control, discounted in the same way as the
+    /// flows in the Monte-Carlo valuation.
+    fn control_value(&self, context: &PricingContext) -> Result<f64, qm::Error>;
+
+    /// The value of the control, estimated by Monte-Carlo over the same
+    /// paths as are used by mc_price.
+    fn mc_control_price(&self, context: &MonteCarloContext)
+        -> Result<f64, qm::Error>;
+}
+
+/// Why an instrument needs an observation of an underlying. Models may use
+/// this to treat observations differently, for example to distinguish
+/// barrier monitoring from the fixings a payoff depends on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ObservationKind {
+    /// A fixing that determines the payoff, such as the spot at expiry
+    Fixing,
+    /// One of a set of fixings that are averaged, as in an Asian option
+    Averaging,
+    /// A date on which the holder may choose to exercise
+    Exercise,
+    /// A date on which the underlying is checked against a barrier
+    Barrier,
+    /// A date on which the mark-to-market of the instrument is measured,
+    /// rather than one the payoff depends on
+    Exposure
+}
+
 /// Collects the dependencies needed for Monte-Carlo pricing
 pub trait MonteCarloDependencies {
 
-    /// Specifies an observation of an underlying. (Note that it is very
-    /// common for observations of multiple underlyings to be on slightly
-    /// different dates, if they have different calendars.)
+    /// Specifies an observation of an underlying, tagged with the reason it
+    /// is needed. (Note that it is very common for observations of multiple
+    /// underlyings to be on slightly different dates, if they have different
+    /// calendars.)
     ///
     /// All the returned observations should be in the future (or unfixed,
     /// today). Historical observations should have been handled by the freeze
     /// method.
     fn observation(&mut self, instrument: &RcInstrument,
-        date_time: DateDayFraction);
+        date_time: DateDayFraction, kind: ObservationKind);
 
     /// Specifies a potential cashflow or stock transfer. In theory, any
     /// instrument may be specified. However, the instruments must be either
@@ -600,6 +927,8 @@ pub trait MonteCarloContext {
     /// Value the flows resulting from the valuation. The quantities argument is
     /// an array ordered by paths then flows, where flows are in the same
     /// order as they were passed to the flow method in MonteCarloDependencies.
+    /// Returns the present value as of the spot date at the open, averaged
+    /// over the paths, so the result needs no further discounting.
     fn evaluate_flows(&self, quantities: ArrayView2<f64>) 
         -> Result<f64, qm::Error>;
 
@@ -11,12 +11,17 @@ use instruments::bonds::ZeroCoupon;
 use instruments::SpotRequirement;
 use instruments::MonteCarloPriceable;
 use instruments::MonteCarloDependencies;
+use instruments::ObservationKind;
 use instruments::MonteCarloContext;
+use instruments::PdePriceable;
+use instruments::ClosedFormGreeks;
+use instruments::Greeks;
 use math::optionpricing::Black76;
 use data::fixings::FixingTable;
 use dates::Date;
 use dates::rules::RcDateRule;
 use dates::datetime::DateTime;
+use dates::datetime::TimeOfDay;
 use dates::datetime::DateDayFraction;
 use core::qm;
 use core::factories::TypeId;
@@ -39,6 +44,84 @@ pub enum PutOrCall { Put, Call }
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum OptionSettlement { Cash, Physical }
 
+/// Smoothing of a discontinuous payoff, such as a digital or a barrier, when
+/// valued by Monte-Carlo. The step at the strike or barrier is replaced by a
+/// ramp or a logistic curve, whose width is given as a fraction of the level
+/// of the step. Both are centred on the level, and the logistic curve has
+/// the same slope there as the ramp.
+///
+/// Smoothing trades variance for bias. Bumped Greeks of an unsmoothed step
+/// only see the few paths that cross it, so they are very noisy, and their
+/// noise grows without limit as the bump gets smaller. A smoothed payoff
+/// gives Greeks whose noise is bounded by the width, but the price is biased
+/// by roughly the gamma of the step times the square of the width. The width
+/// should be somewhat larger than the spot bump used for risk, and small
+/// compared with the standard deviation of the underlying at the step.
+///
+/// Smoothing only affects Monte-Carlo valuations. Fixings are always
+/// applied to the exact payoff.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum PayoffSmoothing {
+    None,
+    Ramp { width: f64 },
+    Logistic { width: f64 }
+}
+
+impl Default for PayoffSmoothing {
+    fn default() -> PayoffSmoothing { PayoffSmoothing::None }
+}
+
+impl PayoffSmoothing {
+    pub fn is_none(&self) -> bool { *self == PayoffSmoothing::None }
+
+    /// Checks that the width, if any, is positive and finite, and that the
+    /// level it is relative to, such as a strike or barrier, is positive.
+    pub fn validate(&self, level: f64) -> Result<(), qm::Error> {
+        match *self {
+            PayoffSmoothing::None => Ok(()),
+            PayoffSmoothing::Ramp { width } | PayoffSmoothing::Logistic { width } => {
+                if !(width > 0.0 && width.is_finite()) {
+                    Err(qm::Error::new(&format!(
+                        "Payoff smoothing width must be positive: {}", width)))
+                } else if !(level > 0.0 && level.is_finite()) {
+                    Err(qm::Error::new(&format!(
+                        "Payoff smoothing needs a positive level to smooth \
+                        around: {}", level)))
+                } else {
+                    Ok(())
+                }
+            }
+        }
+    }
+
+    /// The weight given to x being above the level, between zero and one.
+    /// Without smoothing, this is one or zero, and inclusive controls which
+    /// applies when x is exactly at the level. With smoothing, it is one half
+    /// at the level.
+    pub fn above(&self, x: f64, level: f64, inclusive: bool) -> f64 {
+        match *self {
+            PayoffSmoothing::None => {
+                if x > level || (inclusive && x == level) { 1.0 } else { 0.0 }
+            },
+            PayoffSmoothing::Ramp { width } => {
+                let w = width * level.abs();
+                ((x - level) / w + 0.5).max(0.0).min(1.0)
+            },
+            PayoffSmoothing::Logistic { width } => {
+                let w = width * level.abs();
+                1.0 / (1.0 + (-4.0 * (x - level) / w).exp())
+            }
+        }
+    }
+
+    /// The weight given to x being below the level. This is the complement
+    /// of above, so inclusive again controls what happens exactly at the
+    /// level without smoothing.
+    pub fn below(&self, x: f64, level: f64, inclusive: bool) -> f64 {
+        1.0 - self.above(x, level, !inclusive)
+    }
+}
+
 /// A VanillaOption is an internal data structure to help share code between
 /// types of vanilla.
 #[derive(Clone, Serialize, Deserialize, Debug)]
@@ -51,11 +134,26 @@ struct VanillaOption {
     put_or_call: PutOrCall,
     cash_or_physical: OptionSettlement,
 
+    // the currency of the payoff, if it differs from that of the underlying
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    currency: Option<RcCurrency>,
+
+    // the number of options, so that prices are in units of currency. Only
+    // the vanilla options have a notional. Other instruments either have a
+    // notional of their own, such as the cliquet and variance swap, or are
+    // priced per unit, and must be scaled by their weight in the pricer.
+    #[serde(default = "default_notional", skip_serializing_if = "is_unit_notional")]
+    notional: f64,
+
     // fields precomputed for performance and simplicity
     expiry_time: DateDayFraction,
     pay_date: Date,
 }
 
+fn default_notional() -> f64 { 1.0 }
+
+fn is_unit_notional(notional: &f64) -> bool { *notional == 1.0 }
+
 impl TypeId for VanillaOption {
     fn get_type_id(&self) -> &'static str {
         // should never get here, as VanillaOption should not need tagged serialization
@@ -79,10 +177,47 @@ impl VanillaOption {
             expiry: expiry,
             put_or_call: put_or_call,
             cash_or_physical: cash_or_physical,
+            currency: None,
+            notional: default_notional(),
             expiry_time: expiry_time,
             pay_date: pay_date })
     }
 
+    /// The currency of the payoff, if it is different from the currency of
+    /// the underlying, making this a quanto.
+    fn quanto_currency(&self) -> Option<&Currency> {
+        match self.currency {
+            Some(ref currency)
+                if currency.id() != self.underlying.payoff_currency().id()
+                => Some(&**currency),
+            _ => None
+        }
+    }
+
+    /// The factor to scale the forward at expiry by, so that the underlying
+    /// is diffused under the measure of the payment currency rather than its
+    /// own. This is exp(-rho sigma_S sigma_X t), where rho is the correlation
+    /// between the underlying and the FX rate in units of the payment
+    /// currency per unit of the underlying's currency, sigma_S and sigma_X
+    /// are their vols, and t is the vol time to expiry. It is exactly one if
+    /// this is not a quanto. Monte-Carlo models apply the same correction to
+    /// the drift of the underlying, rather than to the payoff.
+    fn quanto_factor(&self, context: &PricingContext) -> Result<f64, qm::Error> {
+        let currency = match self.quanto_currency() {
+            Some(currency) => currency.id(),
+            None => return Ok(1.0)
+        };
+        let drift = context.quanto_drift(&*self.underlying, currency)?;
+
+        let expiry_date = self.expiry.date();
+        let fwd = context.forward_curve(&*self.underlying, expiry_date)?;
+        let vol = context.vol_surface(&*self.underlying, expiry_date,
+            &|| Ok(fwd.clone()))?;
+        let time = vol.vol_time(self.expiry_time)?;
+        let variance = vol.variance(self.expiry_time, fwd.forward(expiry_date)?)?;
+        Ok((drift * (time * variance).sqrt()).exp())
+    }
+
     /// Prices this option with a range of val dates, and given a closure that
     /// calculates the strike
     fn prices(&self, context: &PricingContext, dates: &[DateTime], out: &mut [f64], 
@@ -98,8 +233,13 @@ impl VanillaOption {
         // fetch the market data we need. Note that the forward curve is only fetched if
         // sticky delta dynamics forces it. Otherwise, there is nothing to stop the underlying
         // being calculated rather than supplied directly as a forward curve.
+        // A quanto is discounted in its payment currency rather than that of
+        // the underlying.
         let expiry_date = self.expiry.date();
-        let yc = context.yield_curve(self.underlying.credit_id(), self.pay_date)?;
+        let discount_id = match self.quanto_currency() {
+            Some(_) => self.credit_id(),
+            None => self.underlying.credit_id() };
+        let yc = context.yield_curve(discount_id, self.pay_date)?;
         let vol = context.vol_surface(&*self.underlying, expiry_date, 
             &|| context.forward_curve(&*self.underlying, expiry_date))?;
 
@@ -109,6 +249,7 @@ impl VanillaOption {
         let underlying = self.underlying.as_priceable().ok_or_else(|| qm::Error::new(
             "The underlying of an option must itself be priceable"))?;
         let (strike, forward) = strike_and_forward(underlying)?;
+        let forward = forward * self.quanto_factor(context)?;
         let df_from_base = (-yc.rt(self.pay_date)?).exp();
  
         // For some div assumptions, we must displace the forward and strike.
@@ -145,7 +286,7 @@ impl VanillaOption {
                 // between now and the val date. Whether this is the right thing to
                 // do depends on how forward valuation will be used. The first real
                 // use case should drive the behaviour.
-                let price = match self.put_or_call {
+                let price = self.notional * match self.put_or_call {
                     PutOrCall::Put => black76.put_price(df, f, k, sqrt_var),
                     PutOrCall::Call => black76.call_price(df, f, k, sqrt_var)
                 };
@@ -162,6 +303,132 @@ impl VanillaOption {
 
         Ok(())
     }
+
+    /// Calculates the closed-form sensitivities of this option, given a
+    /// fixed strike. The option must be spot starting, so only the forward
+    /// depends on spot. The quanto factor and displacement are treated as
+    /// independent of spot and rates, and the forward as growing at the
+    /// discount rate from the val date to expiry when calculating rho.
+    fn greeks(&self, context: &PricingContext, val_date: DateTime, strike: f64)
+        -> Result<Greeks, qm::Error> {
+
+        // after the ex time, the option is worth nothing to us
+        if val_date > self.expiry {
+            return Ok(Greeks::default())
+        }
+
+        // fetch the market data in the same way as prices
+        let expiry_date = self.expiry.date();
+        let discount_id = match self.quanto_currency() {
+            Some(_) => self.credit_id(),
+            None => self.underlying.credit_id() };
+        let yc = context.yield_curve(discount_id, self.pay_date)?;
+        let fwd_curve = context.forward_curve(&*self.underlying, expiry_date)?;
+        let vol = context.vol_surface(&*self.underlying, expiry_date,
+            &|| Ok(fwd_curve.clone()))?;
+
+        let quanto_factor = self.quanto_factor(context)?;
+        let forward = fwd_curve.forward(expiry_date)? * quanto_factor;
+        let forward_delta = fwd_curve.spot_delta(expiry_date)? * quanto_factor;
+        let displacement = vol.displacement(expiry_date)?;
+        let k = strike + displacement;
+        let f = forward - displacement;
+        if f < 0.0 {
+            return Err(qm::Error::new("Negative forward"));
+        }
+
+        let settlement_date = self.settlement().apply(val_date.date());
+        let df = (yc.rt(settlement_date)? - yc.rt(self.pay_date)?).exp();
+
+        let val_time = self.underlying.time_to_day_fraction(val_date)?;
+        let variance = vol.forward_variance(val_time, self.expiry_time, strike)?;
+        if variance < 0.0 {
+            return Err(qm::Error::new("Negative variance"));
+        }
+        let sqrt_var = variance.sqrt();
+        let vol_time = (vol.vol_time(self.expiry_time)? - vol.vol_time(val_time)?)
+            .max(0.0);
+
+        let black76 = Black76::new()?;
+        let (price, black) = match self.put_or_call {
+            PutOrCall::Put => (black76.put_price(df, f, k, sqrt_var),
+                black76.put_greeks(df, f, k, sqrt_var)),
+            PutOrCall::Call => (black76.call_price(df, f, k, sqrt_var),
+                black76.call_greeks(df, f, k, sqrt_var))
+        };
+
+        // The sqrt variance is the vol times the square root of the vol time,
+        // so the sensitivities to vol and to vol time follow from the Black76
+        // vega. Theta is zero at expiry, where it is otherwise unbounded.
+        let vega = black.vega() * vol_time.sqrt();
+        let theta = if vol_time > 0.0 {
+            -black.vega() * sqrt_var / (2.0 * vol_time)
+        } else {
+            0.0
+        };
+
+        // a parallel shift in rates grows the forward to expiry, and shrinks
+        // the discount factor to the pay date
+        let forward_time = (expiry_date - val_date.date()) as f64 / 365.0;
+        let discount_time = (self.pay_date - settlement_date) as f64 / 365.0;
+        let rho = black.delta() * forward * forward_time - price * discount_time;
+
+        Ok(Greeks::new(
+            self.notional * black.delta() * forward_delta,
+            self.notional * black.gamma() * forward_delta * forward_delta,
+            self.notional * vega,
+            self.notional * theta,
+            self.notional * rho))
+    }
+
+    /// Finds the flat vol at which this option, with the given fixed strike,
+    /// would be worth the given price. Puts are converted to calls using
+    /// put/call parity, so the achievable range of prices is from discounted
+    /// intrinsic value up to the discounted forward for a call, or the
+    /// discounted strike for a put.
+    fn breakeven_vol(&self, context: &PricingContext, val_date: DateTime,
+        strike: f64, price: f64) -> Result<f64, qm::Error> {
+
+        if val_date > self.expiry {
+            return Err(qm::Error::new(&format!("Option {} has expired, so \
+                there is no breakeven vol", self.id)))
+        }
+        if self.notional == 0.0 {
+            return Err(qm::Error::new(&format!("Option {} has zero notional, \
+                so there is no breakeven vol", self.id)))
+        }
+
+        // fetch the market data in the same way as prices, though apart from
+        // any quanto correction, the vol surface only supplies displacement
+        // and the measure of time
+        let expiry_date = self.expiry.date();
+        let discount_id = match self.quanto_currency() {
+            Some(_) => self.credit_id(),
+            None => self.underlying.credit_id() };
+        let yc = context.yield_curve(discount_id, self.pay_date)?;
+        let fwd_curve = context.forward_curve(&*self.underlying, expiry_date)?;
+        let vol = context.vol_surface(&*self.underlying, expiry_date,
+            &|| Ok(fwd_curve.clone()))?;
+
+        let forward = fwd_curve.forward(expiry_date)? * self.quanto_factor(context)?;
+        let displacement = vol.displacement(expiry_date)?;
+        let k = strike + displacement;
+        let f = forward - displacement;
+        let settlement_date = self.settlement().apply(val_date.date());
+        let df = (yc.rt(settlement_date)? - yc.rt(self.pay_date)?).exp();
+        let val_time = self.underlying.time_to_day_fraction(val_date)?;
+        let vol_time = vol.vol_time(self.expiry_time)? - vol.vol_time(val_time)?;
+
+        let unit_price = price / self.notional;
+        let call_price = match self.put_or_call {
+            PutOrCall::Put => unit_price + df * (f - k),
+            PutOrCall::Call => unit_price
+        };
+
+        Black76::new()?.implied_vol(df, f, k, vol_time, call_price)
+            .map_err(|e| qm::Error::new(&format!("No breakeven vol for \
+                price {} of option {}: {}", price, self.id, e)))
+    }
 }
 
 /// A European option gives the buyer the option but not the obligation to
@@ -238,6 +505,53 @@ impl SpotStartingEuropean {
         }
     }
 
+    /// Creates a quanto European, which pays in the given currency rather
+    /// than the currency of the underlying. The payoff is the same number of
+    /// units as a plain European, but of the payment currency, so it is
+    /// always cash settled. When valued, the underlying is diffused under
+    /// the measure of the payment currency, which needs the FX rate and its
+    /// correlation with the underlying. If the payment currency is that of
+    /// the underlying, this is exactly a plain European.
+    pub fn new_quanto(
+        id: &str,
+        credit_id: &str,
+        underlying: RcInstrument,
+        currency: RcCurrency,
+        settlement: RcDateRule,
+        expiry: DateTime,
+        strike: f64,
+        put_or_call: PutOrCall)
+        -> Result<SpotStartingEuropean, qm::Error> {
+
+        let mut european = SpotStartingEuropean::new(id, credit_id, underlying,
+            settlement, expiry, strike, put_or_call, OptionSettlement::Cash)?;
+        european.vanilla.currency = Some(currency);
+        Ok(european)
+    }
+
+    /// Sets the notional, which is the number of options, so that the price
+    /// and risks are in units of currency. Prices and greeks scale linearly
+    /// with the notional, which defaults to one.
+    ///
+    /// Only the European options support this. Exotics such as barriers,
+    /// Asians and digitals are priced per unit of notional, so a position
+    /// in them should be sized by the weight of the instrument in the pricer.
+    pub fn with_notional(mut self, notional: f64) -> SpotStartingEuropean {
+        self.vanilla.notional = notional;
+        self
+    }
+
+    /// Expresses a price, such as the Monte-Carlo price of an exotic, as the
+    /// flat Black-Scholes vol at which this European would be worth the same.
+    /// Only the forward, discounting and time to expiry come from the market,
+    /// so the result does not depend on the vols in the market, other than
+    /// through any quanto correction. It is an error if the price is outside
+    /// the range a European can be worth at some vol.
+    pub fn breakeven_vol(&self, context: &PricingContext, val_date: DateTime,
+        price: f64) -> Result<f64, qm::Error> {
+        self.vanilla.breakeven_vol(context, val_date, self.strike, price)
+    }
+
     fn from_vanilla(vanilla: VanillaOption, strike: f64)
         -> SpotStartingEuropean {
         SpotStartingEuropean { vanilla: vanilla, strike: strike }
@@ -273,6 +587,13 @@ impl ForwardStartingEuropean {
         }
     }
 
+    /// Sets the notional, which is the number of options. See
+    /// SpotStartingEuropean::with_notional.
+    pub fn with_notional(mut self, notional: f64) -> ForwardStartingEuropean {
+        self.vanilla.notional = notional;
+        self
+    }
+
     pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<Instrument>, esd::Error> {
         Ok(Qrc::new(Arc::new(ForwardStartingEuropean::deserialize(de)?)))
     }
@@ -290,7 +611,10 @@ impl InstanceId for VanillaOption {
 impl Instrument for VanillaOption {
 
     fn payoff_currency(&self) -> &Currency {
-        self.underlying.payoff_currency()
+        match self.currency {
+            Some(ref currency) => &**currency,
+            None => self.underlying.payoff_currency()
+        }
     }
 
     fn credit_id(&self) -> &str {
@@ -317,6 +641,12 @@ impl Instrument for VanillaOption {
         context.forward_curve(&self.underlying, expiry_date);
         context.vol_surface(&self.underlying, expiry_date);
 
+        // a quanto also depends on the FX rate, for its drift correction
+        if let Some(currency) = self.quanto_currency() {
+            context.fx_rate(self.underlying.payoff_currency().id(),
+                currency.id(), expiry_date);
+        }
+
         // A listed option does not need a spot, because the vols are
         // calibrated to match the market. An OTC option cannot have a spot,
         // because they are not published (for equities, anyway).
@@ -337,6 +667,14 @@ impl Instrument for SpotStartingEuropean {
         -> SpotRequirement { self.vanilla.dependencies(context) }
     fn as_priceable(&self) -> Option<&Priceable> { Some(self) }
     fn as_mc_priceable(&self) -> Option<&MonteCarloPriceable> { Some(self) }
+    fn as_closed_form_greeks(&self) -> Option<&ClosedFormGreeks> { Some(self) }
+    fn as_pde_priceable(&self) -> Option<&PdePriceable> {
+        // the PDE pricer has no quanto drift correction
+        match self.vanilla.quanto_currency() {
+            Some(_) => None,
+            None => Some(self)
+        }
+    }
 
     // We cannot delegate fix to the contained vanilla, because it needs
     // to know the strike
@@ -351,6 +689,7 @@ impl Instrument for SpotStartingEuropean {
         if let Some(spot_fixing) = fixing {
             let mut decomp : Vec<(f64, RcInstrument)> = Vec::new();
             let strike = self.strike;
+            let notional = self.vanilla.notional;
             let sign = match self.vanilla.put_or_call {
                         PutOrCall::Call => 1.0,
                         PutOrCall::Put => -1.0 };
@@ -359,8 +698,8 @@ impl Instrument for SpotStartingEuropean {
 
                 // cash settlement -- pay a zero coupon if payment > 0
                 OptionSettlement::Cash => {
-                    let payment = sign * (spot_fixing - strike);
-                    if payment > 0.0 {
+                    let payment = notional * sign * (spot_fixing - strike);
+                    if sign * (spot_fixing - strike) > 0.0 {
                         decomp.push((payment, RcInstrument::new(Qrc::new(Arc::new(ZeroCoupon::new(
                             &payment_id, self.credit_id(), 
                             RcCurrency::new(Arc::new(self.payoff_currency().clone())),
@@ -372,13 +711,13 @@ impl Instrument for SpotStartingEuropean {
 
                 OptionSettlement::Physical => {
                     if sign * (spot_fixing - strike) > 0.0 {
-                        decomp.push((-strike * sign, RcInstrument::new(Qrc::new(Arc::new(ZeroCoupon::new(
+                        decomp.push((-notional * strike * sign, RcInstrument::new(Qrc::new(Arc::new(ZeroCoupon::new(
                             &payment_id, self.credit_id(), 
                             RcCurrency::new(Arc::new(self.payoff_currency().clone())), 
                             self.vanilla.expiry,
                             self.vanilla.pay_date,
                             self.vanilla.settlement.clone()))))));
-                        decomp.push((sign, self.vanilla.underlying.clone()));
+                        decomp.push((notional * sign, self.vanilla.underlying.clone()));
                     }
                 }
             }
@@ -452,6 +791,15 @@ impl Priceable for SpotStartingEuropean {
     }
 }
 
+impl ClosedFormGreeks for SpotStartingEuropean {
+    fn greeks_underlying(&self) -> &str { self.vanilla.underlying.id() }
+
+    fn analytic_greeks(&self, context: &PricingContext, val_date: DateTime)
+        -> Result<Greeks, qm::Error> {
+        self.vanilla.greeks(context, val_date, self.strike)
+    }
+}
+
 impl Priceable for ForwardStartingEuropean {
     fn as_instrument(&self) -> &Instrument { self }
 
@@ -483,7 +831,8 @@ impl MonteCarloPriceable for SpotStartingEuropean {
         output: &mut MonteCarloDependencies) -> Result<(), qm::Error> {
 
         // one observation, at expiry
-        output.observation(&self.vanilla.underlying, self.vanilla.expiry_time);
+        output.observation(&self.vanilla.underlying, self.vanilla.expiry_time,
+            ObservationKind::Fixing);
 
         // TODO this feels inefficient and ugly
         let currency = RcCurrency::new(Arc::new(self.payoff_currency().clone()));
@@ -527,18 +876,67 @@ impl MonteCarloPriceable for SpotStartingEuropean {
             PutOrCall::Call => 1.0,
             PutOrCall::Put => -1.0 };
 
+        // For a quanto, the model diffuses the underlying under the measure
+        // of the payment currency, so the payoff needs no correction.
+        let notional = self.vanilla.notional;
+
         // Calculate the quantity of each flow for each path
         {
             let ref mut flow_column = quantities.subview_mut(Axis(1), 0);
             for (spot, flow) in path_column.iter().zip(flow_column.iter_mut()) {
                 let intrinsic = (sign * (spot - strike)).max(0.0);
-                *flow = intrinsic;
+                *flow = notional * intrinsic;
             }
         }
 
         // sum and discount the flows
         context.evaluate_flows(quantities.view())
     }
+
+    /// An option expiring on the spot date is worth its intrinsic value
+    /// against today's spot, paid at the pay date.
+    fn deterministic_value(&self, context: &PricingContext)
+        -> Result<Option<f64>, qm::Error> {
+
+        let spot_date = context.spot_date();
+        if self.vanilla.expiry.date() > spot_date {
+            return Ok(None)
+        }
+
+        let spot = context.spot(self.vanilla.underlying.id())?;
+        let quanto = self.vanilla.quanto_factor(context)?;
+        let intrinsic = match self.vanilla.put_or_call {
+            PutOrCall::Call => (quanto * spot - self.strike).max(0.0),
+            PutOrCall::Put => (self.strike - quanto * spot).max(0.0) };
+
+        let val_date = DateTime::new(spot_date, TimeOfDay::Open);
+        let payment = self.pde_payment();
+        let df = payment.as_priceable().ok_or_else(|| qm::Error::new(
+            "The payment of a European must be priceable"))?
+            .price(context, val_date)?;
+        Ok(Some(self.vanilla.notional * intrinsic * df))
+    }
+}
+
+impl PdePriceable for SpotStartingEuropean {
+    fn as_instrument(&self) -> &Instrument { self }
+    fn pde_underlying(&self) -> &RcInstrument { &self.vanilla.underlying }
+    fn pde_expiry(&self) -> DateDayFraction { self.vanilla.expiry_time }
+
+    fn pde_payment(&self) -> RcInstrument {
+        // TODO this feels inefficient and ugly
+        let currency = RcCurrency::new(Arc::new(self.payoff_currency().clone()));
+        RcInstrument::new(Qrc::new(Arc::new(
+            ZeroCoupon::new(&format!("{}:Expiry", self.vanilla.id),
+            &self.vanilla.credit_id, currency, self.vanilla.expiry, self.vanilla.pay_date,
+            self.vanilla.settlement.clone()))))
+    }
+
+    fn pde_payoff(&self, spot: f64) -> f64 {
+        self.vanilla.notional * match self.vanilla.put_or_call {
+            PutOrCall::Call => (spot - self.strike).max(0.0),
+            PutOrCall::Put => (self.strike - spot).max(0.0) }
+    }
 }
 
 impl MonteCarloPriceable for ForwardStartingEuropean {
@@ -548,8 +946,10 @@ impl MonteCarloPriceable for ForwardStartingEuropean {
         output: &mut MonteCarloDependencies) -> Result<(), qm::Error> {
 
         // two observations, at strike and expiry
-        output.observation(&self.vanilla.underlying, self.strike_time);
-        output.observation(&self.vanilla.underlying, self.vanilla.expiry_time);
+        output.observation(&self.vanilla.underlying, self.strike_time,
+            ObservationKind::Fixing);
+        output.observation(&self.vanilla.underlying, self.vanilla.expiry_time,
+            ObservationKind::Fixing);
 
         // TODO this feels inefficient and ugly
         let currency = RcCurrency::new(Arc::new(self.payoff_currency().clone()));
@@ -588,6 +988,7 @@ impl MonteCarloPriceable for ForwardStartingEuropean {
         let mut quantities = Array2::zeros((n_paths, 1));
 
         let strike_fraction = self.strike_fraction;
+        let notional = self.vanilla.notional;
         let sign = match self.vanilla.put_or_call {
             PutOrCall::Call => 1.0,
             PutOrCall::Put => -1.0 };
@@ -599,7 +1000,7 @@ impl MonteCarloPriceable for ForwardStartingEuropean {
                 let strike = strike_fraction * path[0];
                 let spot = path[1];
                 let intrinsic = (sign * (spot - strike)).max(0.0);
-                *flow = intrinsic;
+                *flow = notional * intrinsic;
             }
         }
 
@@ -611,6 +1012,7 @@ impl MonteCarloPriceable for ForwardStartingEuropean {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use models::random::BASE_SEED;
     use std::collections::HashMap;
     use core::dedup::{Dedup, DedupControl};
     use math::numerics::approx_eq;
@@ -631,6 +1033,26 @@ mod tests {
     use instruments::assets::tests::sample_equity;
     use instruments::assets::DEDUP_CURRENCY;
     use instruments::DEDUP_INSTRUMENT;
+    use data::fx::FxRate;
+    use data::correlation::CorrelationMatrix;
+    use risk::marketdata::MarketData;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::marketdata::tests::sample_european;
+    use risk::marketdata::tests::sample_settlement;
+    use risk::marketdata::tests::sample_currency as market_sample_currency;
+    use risk::marketdata::tests::sample_equity as market_sample_equity;
+    use risk::dependencies::DependencyCollector;
+    use models::RcMonteCarloModelFactory;
+    use models::VarianceReduction;
+    use models::blackdiffusion::BlackDiffusionFactory;
+    use models::heston::HestonFactory;
+    use models::localvol::LocalVolFactory;
+    use pricers::Pricer;
+    use pricers::montecarlo::MonteCarloPricer;
+    use pricers::selfpricer::SelfPricer;
+    use risk::ReportGenerator;
+    use risk::deltagamma::DeltaGammaReportGenerator;
+    use risk::deltagamma::DeltaGammaReport;
     use serde_json;
     use serde::Serialize;
 
@@ -1000,6 +1422,178 @@ mod tests {
             strike_date, PutOrCall::Call, cash_or_physical).unwrap()
     }
 
+    /// A quanto on the sample equity, paying in dollars rather than pounds,
+    /// with the given correlation between the equity and GBP/USD.
+    fn sample_quanto(correlation: f64) -> (SpotStartingEuropean, MarketData) {
+        let equity = RcInstrument::new(Qrc::new(Arc::new(market_sample_equity(
+            RcCurrency::new(Arc::new(market_sample_currency(2))), 2))));
+        let dollars = RcCurrency::new(Arc::new(Currency::new("USD",
+            sample_settlement(2))));
+        let expiry = DateTime::new(Date::from_ymd(2018, 06, 01), TimeOfDay::Close);
+        let quanto = SpotStartingEuropean::new_quanto("SampleQuanto", "OPT",
+            equity, dollars, sample_settlement(2), expiry, 100.0,
+            PutOrCall::Call).unwrap();
+
+        let mut market_data = sample_market_data();
+        market_data.set_fx_rate("GBP", "USD", FxRate::new(1.3, 0.1).unwrap());
+        market_data.set_correlations(CorrelationMatrix::new(&["BP.L", "GBP/USD"],
+            vec![vec![1.0, correlation], vec![correlation, 1.0]]).unwrap());
+        (quanto, market_data)
+    }
+
+    #[test]
+    fn quanto_european_with_zero_correlation_matches_converted_european() {
+
+        // With no correlation, there is no drift correction, so the quanto
+        // is worth the same number of dollars as the plain European is worth
+        // in pounds
+        let (quanto, market_data) = sample_quanto(0.0);
+        let plain = sample_european();
+        let val_date = DateTime::new(market_data.spot_date(), TimeOfDay::Open);
+        assert_approx(quanto.price(&market_data, val_date).unwrap(),
+            plain.price(&market_data, val_date).unwrap(), 1e-12);
+
+        // The same is true by Monte-Carlo, where with the same seed the paths
+        // are identical
+        let model_factory = RcMonteCarloModelFactory::new(Arc::new(BlackDiffusionFactory::new(
+            20, 0.01, 2000, VarianceReduction::None, Some(BASE_SEED)).unwrap()));
+        let mc_price = |instrument: RcInstrument| {
+            MonteCarloPricer::new(vec!((1.0, instrument)), model_factory.clone(),
+                &market_data).unwrap().price().unwrap()
+        };
+        let quanto_mc = mc_price(RcInstrument::new(Qrc::new(Arc::new(quanto))));
+        let plain_mc = mc_price(RcInstrument::new(Qrc::new(plain)));
+        assert_approx(quanto_mc, plain_mc, 1e-12);
+    }
+
+    #[test]
+    fn european_price_and_delta_scale_with_notional() {
+
+        let market_data = sample_market_data();
+        let single = RcInstrument::new(Qrc::new(sample_european()));
+        let double = RcInstrument::new(Qrc::new(Arc::new(
+            (*sample_european()).clone().with_notional(2.0))));
+
+        // analytic price and delta, from a one percent bump
+        let price_and_delta = |instrument: RcInstrument| {
+            let mut pricer = SelfPricer::new(vec!((1.0, instrument)),
+                &market_data).unwrap();
+            let unbumped = pricer.price().unwrap();
+            let mut save = pricer.as_bumpable().new_saveable();
+            let report = DeltaGammaReportGenerator::new(0.01).generate(
+                &mut pricer, &mut *save, unbumped).unwrap();
+            let delta = report.as_any().downcast_ref::<DeltaGammaReport>()
+                .unwrap().results().get("BP.L").unwrap().delta();
+            (unbumped, delta)
+        };
+        let (price, delta) = price_and_delta(single.clone());
+        let (double_price, double_delta) = price_and_delta(double.clone());
+        assert_approx(price, 16.710717400832973, 1e-12);
+        assert_approx(double_price, 2.0 * price, 1e-12);
+        assert_approx(double_delta, 2.0 * delta, 1e-12);
+
+        // the weight still scales the notional, so a portfolio of two
+        // single options is worth the same as one double
+        let weighted = SelfPricer::new(vec!((2.0, single.clone())),
+            &market_data).unwrap().price().unwrap();
+        assert_approx(weighted, double_price, 1e-12);
+
+        // the same is true by Monte-Carlo, where the paths are identical
+        let model_factory = RcMonteCarloModelFactory::new(Arc::new(BlackDiffusionFactory::new(
+            20, 0.01, 2000, VarianceReduction::None, Some(BASE_SEED)).unwrap()));
+        let mc_price = |instrument: RcInstrument| {
+            MonteCarloPricer::new(vec!((1.0, instrument)), model_factory.clone(),
+                &market_data).unwrap().price().unwrap()
+        };
+        assert_approx(mc_price(double.clone()), 2.0 * mc_price(single), 1e-12);
+
+        // and a fixed option pays out the notional times the intrinsic
+        let expiry = DateTime::new(Date::from_ymd(2018, 06, 01), TimeOfDay::Close);
+        let fixings = FixingTable::from_fixings(Date::from_ymd(2018, 06, 02),
+            &[("BP.L", &[(expiry, 110.0)])]).unwrap();
+        let fixed = double.fix(&fixings).unwrap().unwrap();
+        assert_eq!(fixed.len(), 1);
+        assert_approx(fixed[0].0, 20.0, 1e-12);
+    }
+
+    #[test]
+    fn quanto_european_drift_correction() {
+
+        // Positive correlation between the equity and the pound lowers the
+        // forward under the dollar measure, so the call is worth less
+        let (uncorrelated, market_data) = sample_quanto(0.0);
+        let (correlated, correlated_market_data) = sample_quanto(0.5);
+        let val_date = DateTime::new(market_data.spot_date(), TimeOfDay::Open);
+        let price = uncorrelated.price(&market_data, val_date).unwrap();
+        let correlated_price = correlated.price(&correlated_market_data, val_date).unwrap();
+        assert!(correlated_price < price, "correlated={} uncorrelated={}",
+            correlated_price, price);
+
+        // and the quanto depends on the FX rate as well as the equity
+        let instrument = RcInstrument::new(Qrc::new(Arc::new(correlated)));
+        let mut dependencies = DependencyCollector::new(market_data.spot_date());
+        dependencies.spot(&instrument);
+        assert_eq!(dependencies.fx_rate_hwm("GBP", "USD"),
+            Some(Date::from_ymd(2018, 06, 01)));
+    }
+
+    #[test]
+    fn quanto_european_drift_is_applied_by_each_model() {
+
+        // Each model applies the quanto correction to the drift of the
+        // underlying. With the same random numbers for the quanto and the
+        // plain European, the change in the Monte-Carlo price matches the
+        // change in the analytic price, with much less noise than either.
+        let (quanto, market_data) = sample_quanto(0.5);
+        let plain = sample_european();
+        let val_date = DateTime::new(market_data.spot_date(), TimeOfDay::Open);
+        let expected = quanto.price(&market_data, val_date).unwrap()
+            - plain.price(&market_data, val_date).unwrap();
+        assert!(expected < -0.5, "expected={}", expected);
+
+        let quanto = RcInstrument::new(Qrc::new(Arc::new(quanto)));
+        let plain = RcInstrument::new(Qrc::new(plain));
+        let factories = [
+            RcMonteCarloModelFactory::new(Arc::new(BlackDiffusionFactory::new(
+                20, 0.01, 2000, VarianceReduction::None, Some(BASE_SEED)))),
+            RcMonteCarloModelFactory::new(Arc::new(HestonFactory::new(
+                20, 2000, 0.09, 1.0, 0.09, 0.0, 0.0).with_seed(BASE_SEED))),
+            RcMonteCarloModelFactory::new(Arc::new(LocalVolFactory::new(2000).with_seed(BASE_SEED)))];
+        for model_factory in factories.iter() {
+            let mc_price = |instrument: &RcInstrument| {
+                MonteCarloPricer::new(vec!((1.0, instrument.clone())),
+                    model_factory.clone(), &market_data).unwrap().price().unwrap()
+            };
+            let change = mc_price(&quanto) - mc_price(&plain);
+            assert_approx(change, expected, 0.05);
+        }
+    }
+
+    #[test]
+    fn quanto_european_in_its_own_currency_is_plain() {
+
+        // Paying in pounds is exactly the plain European, with no need for
+        // any FX rate
+        let equity = RcInstrument::new(Qrc::new(Arc::new(market_sample_equity(
+            RcCurrency::new(Arc::new(market_sample_currency(2))), 2))));
+        let pounds = RcCurrency::new(Arc::new(market_sample_currency(2)));
+        let expiry = DateTime::new(Date::from_ymd(2018, 06, 01), TimeOfDay::Close);
+        let quanto = SpotStartingEuropean::new_quanto("SampleQuanto", "OPT",
+            equity, pounds, sample_settlement(2), expiry, 100.0,
+            PutOrCall::Call).unwrap();
+
+        let market_data = sample_market_data();
+        let val_date = DateTime::new(market_data.spot_date(), TimeOfDay::Open);
+        assert_eq!(quanto.price(&market_data, val_date).unwrap(),
+            sample_european().price(&market_data, val_date).unwrap());
+        assert!(quanto.as_pde_priceable().is_some());
+
+        let instrument = RcInstrument::new(Qrc::new(Arc::new(quanto)));
+        let mut dependencies = DependencyCollector::new(market_data.spot_date());
+        dependencies.spot(&instrument);
+        assert!(dependencies.fx_rates().is_empty());
+    }
+
     #[test]
     fn european_serde() {
 
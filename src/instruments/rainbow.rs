@@ -0,0 +1,437 @@
+use std::sync::Arc;
+use instruments::Instrument;
+use instruments::RcInstrument;
+use instruments::DependencyContext;
+use instruments::SpotRequirement;
+use instruments::MonteCarloPriceable;
+use instruments::MonteCarloDependencies;
+use instruments::ObservationKind;
+use instruments::MonteCarloContext;
+use instruments::options::PutOrCall;
+use instruments::bonds::ZeroCoupon;
+use instruments::assets::Currency;
+use instruments::assets::RcCurrency;
+use dates::Date;
+use dates::rules::RcDateRule;
+use dates::datetime::DateTime;
+use dates::datetime::DateDayFraction;
+use data::fixings::FixingTable;
+use core::qm;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use core::dedup::InstanceId;
+use ndarray::Array2;
+use erased_serde as esd;
+use serde::Deserialize;
+
+/// Whether a rainbow option selects the worst or the best performing of its
+/// underlyings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RainbowType { WorstOf, BestOf }
+
+/// A rainbow option is a European option on whichever of several
+/// underlyings performs worst (or best) at expiry, where the performance of
+/// each underlying is its value at expiry relative to its own strike. The
+/// option pays the amount by which the selected underlying is above (call)
+/// or below (put) its strike, in cash at the settlement date following the
+/// expiry.
+///
+/// If two underlyings perform exactly equally, the one that comes first in
+/// the list of underlyings is selected, so that ties are broken the same
+/// way whether the option is fixed or valued by Monte-Carlo.
+///
+/// Like the basket option, it can only be priced by Monte-Carlo, which
+/// needs correlations between the underlyings.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct RainbowOption {
+    id: String,
+    credit_id: String,
+    underlyings: Vec<(f64, RcInstrument)>,
+    settlement: RcDateRule,
+    expiry: DateTime,
+    rainbow_type: RainbowType,
+    put_or_call: PutOrCall,
+
+    // fields precomputed for performance and simplicity
+    expiry_time: DateDayFraction,
+    pay_date: Date
+}
+
+impl TypeId for RainbowOption {
+    fn get_type_id(&self) -> &'static str { "RainbowOption" }
+}
+
+impl InstanceId for RainbowOption {
+    fn id(&self) -> &str { &self.id }
+}
+
+impl RainbowOption {
+    /// Creates a rainbow option. The underlyings are pairs of strike and
+    /// instrument, and must all be in the same currency. The strikes must
+    /// be positive, as performance is measured relative to them. The expiry
+    /// time is taken from the first underlying.
+    pub fn new(
+        id: &str,
+        credit_id: &str,
+        underlyings: Vec<(f64, RcInstrument)>,
+        settlement: RcDateRule,
+        expiry: DateTime,
+        rainbow_type: RainbowType,
+        put_or_call: PutOrCall) -> Result<RainbowOption, qm::Error> {
+
+        if underlyings.iter().any(|&(strike, _)| strike <= 0.0) {
+            return Err(qm::Error::new("The strikes of a rainbow option must be positive"))
+        }
+
+        let expiry_time = {
+            let first = &underlyings.first().ok_or_else(|| qm::Error::new(
+                "A rainbow option must have at least one underlying"))?.1;
+            let currency = first.payoff_currency();
+            if underlyings.iter().any(|&(_, ref u)| u.payoff_currency() != currency) {
+                return Err(qm::Error::new(
+                    "The underlyings of a rainbow option must all be in the same currency"))
+            }
+            first.time_to_day_fraction(expiry)?
+        };
+
+        let pay_date = settlement.apply(expiry.date());
+        Ok(RainbowOption {
+            id: id.to_string(),
+            credit_id: credit_id.to_string(),
+            underlyings: underlyings,
+            settlement: settlement,
+            expiry: expiry,
+            rainbow_type: rainbow_type,
+            put_or_call: put_or_call,
+            expiry_time: expiry_time,
+            pay_date: pay_date })
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<Instrument>, esd::Error> {
+        Ok(Qrc::new(Arc::new(RainbowOption::deserialize(de)?)))
+    }
+
+    /// The index of the underlying selected, given the value of each at
+    /// expiry. Ties go to the earliest underlying.
+    fn selected(&self, values: &[f64]) -> usize {
+        assert_eq!(values.len(), self.underlyings.len());
+        let mut selected = 0;
+        let mut selected_performance = values[0] / self.underlyings[0].0;
+        for (i, (value, &(strike, _))) in values.iter().zip(self.underlyings.iter())
+            .enumerate().skip(1) {
+            let performance = value / strike;
+            let better = match self.rainbow_type {
+                RainbowType::WorstOf => performance < selected_performance,
+                RainbowType::BestOf => performance > selected_performance };
+            if better {
+                selected = i;
+                selected_performance = performance;
+            }
+        }
+        selected
+    }
+
+    /// The payoff, given the value of each underlying at expiry
+    fn intrinsic(&self, values: &[f64]) -> f64 {
+        let i = self.selected(values);
+        let strike = self.underlyings[i].0;
+        match self.put_or_call {
+            PutOrCall::Call => (values[i] - strike).max(0.0),
+            PutOrCall::Put => (strike - values[i]).max(0.0) }
+    }
+
+    /// The cash payment at the pay date
+    fn payment(&self) -> RcInstrument {
+        let currency = RcCurrency::new(Arc::new(self.payoff_currency().clone()));
+        RcInstrument::new(Qrc::new(Arc::new(
+            ZeroCoupon::new(&format!("{}:Expiry", self.id),
+            &self.credit_id, currency, self.expiry, self.pay_date,
+            self.settlement.clone()))))
+    }
+}
+
+impl Instrument for RainbowOption {
+    fn payoff_currency(&self) -> &Currency { self.underlyings[0].1.payoff_currency() }
+    fn credit_id(&self) -> &str { &self.credit_id }
+    fn settlement(&self) -> &RcDateRule { &self.settlement }
+    fn as_mc_priceable(&self) -> Option<&MonteCarloPriceable> { Some(self) }
+
+    fn dependencies(&self, context: &mut DependencyContext)
+        -> SpotRequirement {
+
+        // one fixing of each underlying, at expiry
+        let expiry_date = self.expiry.date();
+        for &(_, ref underlying) in self.underlyings.iter() {
+            context.fixing(underlying.id(), self.expiry);
+            context.forward_curve(underlying, expiry_date);
+            context.vol_surface(underlying, expiry_date);
+        }
+        context.yield_curve(self.credit_id(), self.pay_date);
+
+        SpotRequirement::NotRequired
+    }
+
+    fn fix(&self, fixing_table: &FixingTable)
+        -> Result<Option<Vec<(f64, RcInstrument)>>, qm::Error> {
+
+        // All the underlyings fix at the same time, so either all or none of
+        // them are fixed. Once they are, the option becomes a cash payment.
+        let mut values = Vec::with_capacity(self.underlyings.len());
+        for &(_, ref underlying) in self.underlyings.iter() {
+            if let Some(fixing) = fixing_table.get(underlying.id(), self.expiry)? {
+                values.push(fixing);
+            }
+        }
+        if values.is_empty() {
+            return Ok(None)
+        }
+        if values.len() != self.underlyings.len() {
+            return Err(qm::Error::new(&format!(
+                "Rainbow option {} has only some of its underlyings fixed", self.id)))
+        }
+
+        let payment = self.intrinsic(&values);
+        let mut decomp = Vec::new();
+        if payment > 0.0 {
+            decomp.push((payment, self.payment()));
+        }
+        Ok(Some(decomp))
+    }
+}
+
+impl MonteCarloPriceable for RainbowOption {
+    fn as_instrument(&self) -> &Instrument { self }
+
+    fn mc_dependencies(&self, _dates: &[DateDayFraction],
+        output: &mut MonteCarloDependencies) -> Result<(), qm::Error> {
+
+        // one observation of each underlying, at expiry, and one cash flow
+        for &(_, ref underlying) in self.underlyings.iter() {
+            output.observation(underlying, self.expiry_time, ObservationKind::Fixing);
+        }
+        output.flow(&self.payment());
+        Ok(())
+    }
+
+    fn start_date(&self) -> Option<DateDayFraction> {
+        None
+    }
+
+    fn mc_price(&self, context: &MonteCarloContext)
+        -> Result<f64, qm::Error> {
+
+        let mut paths = Vec::with_capacity(self.underlyings.len());
+        for &(_, ref underlying) in self.underlyings.iter() {
+            let underlying_paths = context.paths(underlying)?;
+            assert_eq!(underlying_paths.shape()[1], 1);
+            paths.push(underlying_paths);
+        }
+
+        let n_paths = paths[0].shape()[0];
+        let mut values = vec![0.0; paths.len()];
+        let mut quantities = Array2::zeros((n_paths, 1));
+        for (p, flow) in quantities.iter_mut().enumerate() {
+            for (value, underlying_paths) in values.iter_mut().zip(paths.iter()) {
+                *value = underlying_paths[[p, 0]];
+            }
+            *flow = self.intrinsic(&values);
+        }
+
+        context.evaluate_flows(quantities.view())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pricers::montecarlo::tests::black_diffusion_mc_pricer;
+    use math::numerics::approx_eq;
+    use dates::datetime::TimeOfDay;
+    use instruments::Priceable;
+    use instruments::assets::tests::sample_currency;
+    use instruments::assets::tests::sample_equity;
+    use instruments::basket::tests::sample_basket_market_data;
+    use instruments::options::SpotStartingEuropean;
+    use instruments::options::OptionSettlement;
+    use risk::marketdata::MarketData;
+    use risk::Pricer;
+    use risk::ReportGenerator;
+    use risk::cega::CegaReport;
+    use risk::cega::CegaReportGenerator;
+    use data::bump::Bump;
+    use data::bumpcorrelation::BumpCorrelation;
+    use serde_json;
+
+    fn sample_underlying(id: &str) -> RcInstrument {
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        RcInstrument::new(Qrc::new(Arc::new(sample_equity(currency, id, 2))))
+    }
+
+    fn sample_expiry() -> DateTime {
+        DateTime::new(Date::from_ymd(2018, 06, 01), TimeOfDay::Close)
+    }
+
+    /// A rainbow on two underlyings with identical market data, but with
+    /// strikes of 100 and 110
+    fn sample_rainbow(rainbow_type: RainbowType) -> RainbowOption {
+        let underlyings = vec![(100.0, sample_underlying("BP.L")),
+            (110.0, sample_underlying("BP2.L"))];
+        let settlement = underlyings[0].1.settlement().clone();
+        RainbowOption::new("SampleRainbow", "LSE", underlyings, settlement,
+            sample_expiry(), rainbow_type, PutOrCall::Call).unwrap()
+    }
+
+    fn sample_rainbow_market_data(correlation: f64) -> MarketData {
+        sample_basket_market_data(&["BP.L", "BP2.L"],
+            vec![vec![1.0, correlation], vec![correlation, 1.0]])
+    }
+
+    fn sample_european_on(id: &str, strike: f64, market_data: &MarketData) -> f64 {
+        let underlying = sample_underlying(id);
+        let settlement = underlying.settlement().clone();
+        let european = SpotStartingEuropean::new("SampleEuropean", "LSE",
+            underlying, settlement, sample_expiry(), strike, PutOrCall::Call,
+            OptionSettlement::Cash).unwrap();
+        let val_date = DateTime::new(market_data.spot_date(), TimeOfDay::Open);
+        european.price(market_data, val_date).unwrap()
+    }
+
+    fn mc_price_with_stderr(option: RainbowOption, market_data: &MarketData)
+        -> (f64, f64) {
+        mc_pricer(option, market_data).price_with_stderr().unwrap()
+    }
+
+    #[test]
+    fn perfectly_correlated_worst_of_matches_european() {
+
+        // With correlation one, the two identical underlyings move together,
+        // so the underlying with the higher strike always performs worse,
+        // and the worst-of is a European on it. Similarly the best-of is a
+        // European on the one with the lower strike.
+        let market_data = sample_rainbow_market_data(1.0);
+        let worse = sample_european_on("BP2.L", 110.0, &market_data);
+        let (price, stderr) = mc_price_with_stderr(
+            sample_rainbow(RainbowType::WorstOf), &market_data);
+        assert!((price - worse).abs() < 3.0 * stderr,
+            "price={} european={} stderr={}", price, worse, stderr);
+
+        let better = sample_european_on("BP.L", 100.0, &market_data);
+        let (price, stderr) = mc_price_with_stderr(
+            sample_rainbow(RainbowType::BestOf), &market_data);
+        assert!((price - better).abs() < 3.0 * stderr,
+            "price={} european={} stderr={}", price, better, stderr);
+
+        // with less correlation, the worst-of is worth less, as the other
+        // underlying sometimes performs worse
+        let uncorrelated_data = sample_rainbow_market_data(0.0);
+        let (uncorrelated, stderr) = mc_price_with_stderr(
+            sample_rainbow(RainbowType::WorstOf), &uncorrelated_data);
+        assert!(uncorrelated < worse - 10.0 * stderr,
+            "uncorrelated={} european={} stderr={}", uncorrelated, worse, stderr);
+    }
+
+    fn mc_pricer(option: RainbowOption, market_data: &MarketData) -> Box<Pricer> {
+        let instrument = RcInstrument::new(Qrc::new(Arc::new(option)));
+        Box::new(black_diffusion_mc_pricer(instrument, market_data, 0.01, 20000).unwrap())
+    }
+
+    /// The cega to the correlation between the two underlyings, and any
+    /// warnings from the report
+    fn cega(pricer: &mut Pricer, bumpsize: f64) -> (f64, Vec<String>) {
+        let unbumped = pricer.price().unwrap();
+        let mut save = pricer.as_bumpable().new_saveable();
+        let report = CegaReportGenerator::new(bumpsize)
+            .generate(pricer, &mut *save, unbumped).unwrap();
+
+        // the pricer is restored, including the correlated paths
+        assert_eq!(pricer.price().unwrap(), unbumped);
+        let report = report.as_any().downcast_ref::<CegaReport>().unwrap();
+        let cega = report.cega("BP2.L", "BP.L").unwrap();
+        assert_eq!(report.cega("BP.L", "BP2.L"), Some(cega));
+        (cega, report.warnings().to_vec())
+    }
+
+    #[test]
+    fn worst_of_gains_value_with_correlation() {
+
+        // The higher the correlation, the less likely it is that one of the
+        // underlyings does badly while the other does well, so a worst-of
+        // call gains value and a best-of call loses it. Bumping reuses the
+        // same random draws, so the differences are not swamped by noise.
+        let market_data = sample_rainbow_market_data(0.5);
+        let mut pricer = mc_pricer(sample_rainbow(RainbowType::WorstOf), &market_data);
+        let unbumped = pricer.price().unwrap();
+
+        let mut save = pricer.as_bumpable().new_saveable();
+        let bump = Bump::new_correlation(BumpCorrelation::new_pairwise(
+            "BP2.L", "BP.L", 0.1));
+        assert!(pricer.as_mut_bumpable().bump(&bump, Some(&mut *save)).unwrap());
+        let bumped = pricer.price().unwrap();
+        assert!(bumped > unbumped, "bumped={} unbumped={}", bumped, unbumped);
+        pricer.as_mut_bumpable().restore(&*save).unwrap();
+        assert_eq!(pricer.price().unwrap(), unbumped);
+
+        let (worst_of_cega, warnings) = cega(&mut *pricer, 0.01);
+        assert!(worst_of_cega > 0.0, "cega={}", worst_of_cega);
+        assert!(warnings.is_empty(), "warnings={:?}", warnings);
+
+        let mut best_of = mc_pricer(sample_rainbow(RainbowType::BestOf), &market_data);
+        let (best_of_cega, _) = cega(&mut *best_of, 0.01);
+        assert!(best_of_cega < 0.0, "cega={}", best_of_cega);
+
+        // near perfect correlation, the up bump is clamped, with a warning
+        let market_data = sample_rainbow_market_data(0.995);
+        let mut pricer = mc_pricer(sample_rainbow(RainbowType::WorstOf), &market_data);
+        let (clamped_cega, warnings) = cega(&mut *pricer, 0.01);
+        assert!(clamped_cega > 0.0, "cega={}", clamped_cega);
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("'BP2.L'"), "warnings={:?}", warnings);
+    }
+
+    #[test]
+    fn rainbow_fix_breaks_ties_by_order() {
+
+        // both underlyings are ten percent above their strikes, so both the
+        // worst-of and the best-of select the first
+        let fixings = |bp: f64, bp2: f64| FixingTable::from_fixings(
+            Date::from_ymd(2018, 06, 02), &[
+            ("BP.L", &[(sample_expiry(), bp)]),
+            ("BP2.L", &[(sample_expiry(), bp2)])]).unwrap();
+        for &rainbow_type in [RainbowType::WorstOf, RainbowType::BestOf].iter() {
+            let option = sample_rainbow(rainbow_type);
+            let decomp = option.fix(&fixings(110.0, 121.0)).unwrap().unwrap();
+            assert_eq!(decomp.len(), 1);
+            assert!(approx_eq(decomp[0].0, 10.0, 1e-12), "payment={}", decomp[0].0);
+            assert_eq!(decomp[0].1.id(), "SampleRainbow:Expiry");
+        }
+
+        // otherwise, the worst-of pays on the worse performer
+        let option = sample_rainbow(RainbowType::WorstOf);
+        let decomp = option.fix(&fixings(120.0, 121.0)).unwrap().unwrap();
+        assert!(approx_eq(decomp[0].0, 11.0, 1e-12), "payment={}", decomp[0].0);
+        let decomp = option.fix(&fixings(120.0, 100.0)).unwrap().unwrap();
+        assert!(decomp.is_empty());
+
+        let partial = FixingTable::from_fixings(Date::from_ymd(2018, 06, 02), &[
+            ("BP.L", &[(sample_expiry(), 110.0)])]).unwrap();
+        assert!(option.fix(&partial).is_err());
+    }
+
+    #[test]
+    fn rainbow_rejects_bad_strikes() {
+        let underlyings = vec![(100.0, sample_underlying("BP.L")),
+            (0.0, sample_underlying("BP2.L"))];
+        let settlement = underlyings[0].1.settlement().clone();
+        assert!(RainbowOption::new("Bad", "LSE", underlyings, settlement,
+            sample_expiry(), RainbowType::WorstOf, PutOrCall::Call).is_err());
+    }
+
+    #[test]
+    fn rainbow_option_serde() {
+        let option = sample_rainbow(RainbowType::WorstOf);
+        let serialized = serde_json::to_string(&option).unwrap();
+        let deserialized: RainbowOption = serde_json::from_str(&serialized).unwrap();
+        let reserialized = serde_json::to_string(&deserialized).unwrap();
+        assert_eq!(serialized, reserialized);
+    }
+}
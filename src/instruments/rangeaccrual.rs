@@ -0,0 +1,460 @@
+use std::sync::Arc;
+use instruments::Instrument;
+use instruments::RcInstrument;
+use instruments::DependencyContext;
+use instruments::SpotRequirement;
+use instruments::MonteCarloPriceable;
+use instruments::MonteCarloDependencies;
+use instruments::ObservationKind;
+use instruments::MonteCarloContext;
+use instruments::assets::Currency;
+use instruments::assets::RcCurrency;
+use instruments::bonds::ZeroCoupon;
+use data::fixings::FixingTable;
+use dates::Date;
+use dates::calendar::RcCalendar;
+use dates::rules::RcDateRule;
+use dates::datetime::DateTime;
+use dates::datetime::DateDayFraction;
+use core::qm;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use core::dedup::InstanceId;
+use ndarray::Axis;
+use ndarray::Array2;
+use erased_serde as esd;
+use serde::Deserialize;
+
+/// Whether an observation exactly on the lower or upper bound of the
+/// corridor counts as inside it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CorridorBoundary { Inclusive, Exclusive }
+
+/// A range accrual pays a coupon in proportion to the number of observation
+/// dates, normally every business day, on which the underlying lies within a
+/// corridor between a lower and upper bound. If the underlying is inside the
+/// corridor on every observation date, the full coupon is paid. The payment
+/// is made at the settlement date following the last observation date.
+///
+/// Once some of the observation dates are in the past, fixing the range
+/// accrual removes them from the observation dates and records how many
+/// there were, and how many of them were inside the corridor.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct RangeAccrual {
+    id: String,
+    credit_id: String,
+    underlying: RcInstrument,
+    settlement: RcDateRule,
+    observation_dates: Vec<DateTime>,
+    #[serde(default)]
+    past_observations: usize,
+    #[serde(default)]
+    past_accrued: usize,
+    lower: f64,
+    upper: f64,
+    boundary: CorridorBoundary,
+    coupon: f64,
+
+    // fields precomputed for performance and simplicity
+    observation_times: Vec<DateDayFraction>,
+    pay_date: Date
+}
+
+impl TypeId for RangeAccrual {
+    fn get_type_id(&self) -> &'static str { "RangeAccrual" }
+}
+
+impl InstanceId for RangeAccrual {
+    fn id(&self) -> &str { &self.id }
+}
+
+impl RangeAccrual {
+    /// Creates a range accrual observed daily, on every business day in the
+    /// calendar from start to end inclusive. All the observations are at
+    /// the same time of day as the end.
+    pub fn new(
+        id: &str,
+        credit_id: &str,
+        underlying: RcInstrument,
+        settlement: RcDateRule,
+        calendar: &RcCalendar,
+        start: Date,
+        end: DateTime,
+        lower: f64,
+        upper: f64,
+        boundary: CorridorBoundary,
+        coupon: f64)
+        -> Result<RangeAccrual, qm::Error> {
+
+        let mut observation_dates = Vec::new();
+        let mut date = calendar.step(start, 0, true);
+        while date <= end.date() {
+            observation_dates.push(DateTime::new(date, end.time_of_day()));
+            date = calendar.step(date, 1, true);
+        }
+
+        RangeAccrual::from_dates(id, credit_id, underlying, settlement,
+            &observation_dates, lower, upper, boundary, coupon)
+    }
+
+    /// Creates a range accrual with an arbitrary set of observation dates,
+    /// which must be supplied in strictly increasing order.
+    pub fn from_dates(
+        id: &str,
+        credit_id: &str,
+        underlying: RcInstrument,
+        settlement: RcDateRule,
+        observation_dates: &[DateTime],
+        lower: f64,
+        upper: f64,
+        boundary: CorridorBoundary,
+        coupon: f64)
+        -> Result<RangeAccrual, qm::Error> {
+
+        RangeAccrual::with_past_accrual(id, credit_id, underlying, settlement,
+            observation_dates, 0, 0, lower, upper, boundary, coupon)
+    }
+
+    /// Creates a range accrual where some of the observations have already
+    /// happened, of which past_accrued were inside the corridor.
+    fn with_past_accrual(
+        id: &str,
+        credit_id: &str,
+        underlying: RcInstrument,
+        settlement: RcDateRule,
+        observation_dates: &[DateTime],
+        past_observations: usize,
+        past_accrued: usize,
+        lower: f64,
+        upper: f64,
+        boundary: CorridorBoundary,
+        coupon: f64)
+        -> Result<RangeAccrual, qm::Error> {
+
+        if lower >= upper {
+            return Err(qm::Error::new(
+                "The lower bound of the corridor must be below the upper bound"))
+        }
+        if past_accrued > past_observations {
+            return Err(qm::Error::new(
+                "Cannot have accrued on more than the past observations"))
+        }
+
+        let last = observation_dates.last().ok_or_else(|| qm::Error::new(
+            "A range accrual must have at least one observation date"))?;
+        for pair in observation_dates.windows(2) {
+            if pair[0] >= pair[1] {
+                return Err(qm::Error::new(
+                    "Observation dates must be in strictly increasing order"))
+            }
+        }
+
+        let pay_date = settlement.apply(last.date());
+        let mut observation_times = Vec::with_capacity(observation_dates.len());
+        for date in observation_dates.iter() {
+            observation_times.push(underlying.time_to_day_fraction(*date)?);
+        }
+
+        Ok(RangeAccrual {
+            id: id.to_string(),
+            credit_id: credit_id.to_string(),
+            underlying: underlying,
+            settlement: settlement,
+            observation_dates: observation_dates.to_vec(),
+            past_observations: past_observations,
+            past_accrued: past_accrued,
+            lower: lower,
+            upper: upper,
+            boundary: boundary,
+            coupon: coupon,
+            observation_times: observation_times,
+            pay_date: pay_date })
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<Instrument>, esd::Error> {
+        Ok(Qrc::new(Arc::new(RangeAccrual::deserialize(de)?)))
+    }
+
+    fn expiry(&self) -> DateTime {
+        *self.observation_dates.last().unwrap()
+    }
+
+    /// The total number of observations, past and future
+    fn n_observations(&self) -> f64 {
+        (self.past_observations + self.observation_dates.len()) as f64
+    }
+
+    /// Is the given value of the underlying inside the corridor?
+    fn in_range(&self, spot: f64) -> bool {
+        match self.boundary {
+            CorridorBoundary::Inclusive => spot >= self.lower && spot <= self.upper,
+            CorridorBoundary::Exclusive => spot > self.lower && spot < self.upper }
+    }
+
+    /// The cash payment at the pay date
+    fn payment(&self) -> RcInstrument {
+        // TODO this feels inefficient and ugly
+        let currency = RcCurrency::new(Arc::new(self.payoff_currency().clone()));
+        RcInstrument::new(Qrc::new(Arc::new(
+            ZeroCoupon::new(&format!("{}:Expiry", self.id),
+            &self.credit_id, currency, self.expiry(), self.pay_date,
+            self.settlement.clone()))))
+    }
+}
+
+impl Instrument for RangeAccrual {
+    fn payoff_currency(&self) -> &Currency { self.underlying.payoff_currency() }
+    fn credit_id(&self) -> &str { &self.credit_id }
+    fn settlement(&self) -> &RcDateRule { &self.settlement }
+    fn as_mc_priceable(&self) -> Option<&MonteCarloPriceable> { Some(self) }
+
+    fn dependencies(&self, context: &mut DependencyContext)
+        -> SpotRequirement {
+
+        // one fixing on each observation date
+        for date in self.observation_dates.iter() {
+            context.fixing(self.underlying.id(), *date);
+        }
+
+        let expiry_date = self.expiry().date();
+        context.yield_curve(self.credit_id(), self.pay_date);
+        context.forward_curve(&self.underlying, expiry_date);
+        context.vol_surface(&self.underlying, expiry_date);
+
+        SpotRequirement::NotRequired
+    }
+
+    fn fix(&self, fixing_table: &FixingTable)
+        -> Result<Option<Vec<(f64, RcInstrument)>>, qm::Error> {
+
+        // Count the observation dates that have fixed, and how many of them
+        // were in the corridor (the fetch errors if a fixing in the past is
+        // missing). These must all come before the dates that have not fixed.
+        let mut n_fixed = 0;
+        let mut accrued = self.past_accrued;
+        let mut unfixed = false;
+        for date in self.observation_dates.iter() {
+            match fixing_table.get(self.underlying.id(), *date)? {
+                Some(_) if unfixed => return Err(qm::Error::new(&format!(
+                    "Range accrual {} has a fixing after an unfixed \
+                    observation date", self.id))),
+                Some(fixing) => {
+                    n_fixed += 1;
+                    if self.in_range(fixing) {
+                        accrued += 1;
+                    }
+                },
+                None => unfixed = true
+            }
+        }
+        if n_fixed == 0 {
+            return Ok(None)
+        }
+
+        // If all dates have fixed, the range accrual becomes a cash payment
+        if n_fixed == self.observation_dates.len() {
+            let payment = self.coupon * accrued as f64 / self.n_observations();
+            let mut decomp = Vec::new();
+            if payment != 0.0 {
+                decomp.push((payment, self.payment()));
+            }
+            return Ok(Some(decomp))
+        }
+
+        let fixed = RangeAccrual::with_past_accrual(&self.id, &self.credit_id,
+            self.underlying.clone(), self.settlement.clone(),
+            &self.observation_dates[n_fixed..], self.past_observations + n_fixed,
+            accrued, self.lower, self.upper, self.boundary, self.coupon)?;
+        Ok(Some(vec!((1.0, RcInstrument::new(Qrc::new(Arc::new(fixed)))))))
+    }
+}
+
+impl MonteCarloPriceable for RangeAccrual {
+    fn as_instrument(&self) -> &Instrument { self }
+
+    fn mc_dependencies(&self, _dates: &[DateDayFraction],
+        output: &mut MonteCarloDependencies) -> Result<(), qm::Error> {
+
+        // one observation for each accrual date
+        for time in self.observation_times.iter() {
+            output.observation(&self.underlying, *time, ObservationKind::Fixing);
+        }
+
+        // a single cash payment at the pay date
+        output.flow(&self.payment());
+        Ok(())
+    }
+
+    fn start_date(&self) -> Option<DateDayFraction> {
+        None
+    }
+
+    fn mc_price(&self, context: &MonteCarloContext)
+        -> Result<f64, qm::Error> {
+
+        let ref paths = context.paths(&self.underlying)?;
+        let n_paths = paths.shape()[0];
+        let n_obs = paths.shape()[1];
+        assert_eq!(n_obs, self.observation_times.len());
+
+        let coupon_per_observation = self.coupon / self.n_observations();
+        let mut quantities = Array2::zeros((n_paths, 1));
+        {
+            let ref mut flow_column = quantities.subview_mut(Axis(1), 0);
+            for (path, flow) in paths.axis_iter(Axis(0)).zip(flow_column.iter_mut()) {
+                let accrued = self.past_accrued
+                    + path.iter().filter(|spot| self.in_range(**spot)).count();
+                *flow = coupon_per_observation * accrued as f64;
+            }
+        }
+
+        context.evaluate_flows(quantities.view())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pricers::montecarlo::tests::black_diffusion_price_with_stderr;
+    use math::numerics::approx_eq;
+    use dates::datetime::TimeOfDay;
+    use dates::calendar::WeekdayCalendar;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::marketdata::tests::sample_currency;
+    use risk::marketdata::tests::sample_settlement;
+    use risk::marketdata::tests::sample_equity;
+    use instruments::PricingContext;
+    use serde_json;
+
+    /// Observed daily for the first three months of 2017, paying a coupon
+    /// of ten if BP.L stays in the corridor throughout
+    fn sample_range_accrual(lower: f64, upper: f64, boundary: CorridorBoundary)
+        -> RangeAccrual {
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        let equity = RcInstrument::new(Qrc::new(Arc::new(sample_equity(currency, 2))));
+        let calendar = RcCalendar::new(Arc::new(WeekdayCalendar::new()));
+        RangeAccrual::new("SampleRangeAccrual", "OPT", equity, sample_settlement(2),
+            &calendar, Date::from_ymd(2017, 01, 03),
+            DateTime::new(Date::from_ymd(2017, 03, 31), TimeOfDay::Close),
+            lower, upper, boundary, 10.0).unwrap()
+    }
+
+    fn mc_price_with_stderr(range_accrual: RangeAccrual) -> (f64, f64) {
+        let instrument = RcInstrument::new(Qrc::new(Arc::new(range_accrual)));
+        black_diffusion_price_with_stderr(instrument, &sample_market_data(), 0.01, 10000)
+            .unwrap()
+    }
+
+    /// The value of the full coupon, paid at the pay date
+    fn full_coupon(range_accrual: &RangeAccrual) -> f64 {
+        let market_data = sample_market_data();
+        let val_date = DateTime::new(market_data.spot_date(), TimeOfDay::Open);
+        let payment = range_accrual.payment();
+        range_accrual.coupon * payment.as_priceable().unwrap()
+            .price(&market_data, val_date).unwrap()
+    }
+
+    #[test]
+    fn range_accrual_observes_every_business_day() {
+        let range_accrual = sample_range_accrual(80.0, 120.0, CorridorBoundary::Inclusive);
+        assert_eq!(range_accrual.observation_dates.len(), 64);
+        assert_eq!(range_accrual.observation_dates[0].date(), Date::from_ymd(2017, 01, 03));
+        assert_eq!(range_accrual.expiry().date(), Date::from_ymd(2017, 03, 31));
+    }
+
+    #[test]
+    fn range_accrual_wide_corridor_approaches_full_accrual() {
+
+        // with a very wide corridor, every observation accrues on every path
+        let widest = sample_range_accrual(0.0, 1e6, CorridorBoundary::Exclusive);
+        let full = full_coupon(&widest);
+        let (price, stderr) = mc_price_with_stderr(widest);
+        assert_approx(price, full, 1e-10);
+        assert_approx(stderr, 0.0, 1e-10);
+
+        // As the corridor narrows, a little of the coupon is lost. Over
+        // three months with a vol of 30%, 50 to 200 is more than three
+        // standard deviations either side.
+        let wide = sample_range_accrual(50.0, 200.0, CorridorBoundary::Inclusive);
+        let (price, _) = mc_price_with_stderr(wide);
+        assert!(price <= full && price > 0.999 * full, "price={} full={}", price, full);
+
+        // a narrow corridor loses much more
+        let narrow = sample_range_accrual(95.0, 105.0, CorridorBoundary::Inclusive);
+        let (price, _) = mc_price_with_stderr(narrow);
+        assert!(price < 0.7 * full && price > 0.0, "price={} full={}", price, full);
+    }
+
+    #[test]
+    fn range_accrual_fix_with_boundary_observations() {
+
+        // the first four observations fix, two of them exactly on the bounds
+        let dates: Vec<DateTime> = (3..7).map(|day| DateTime::new(
+            Date::from_ymd(2017, 01, day), TimeOfDay::Close)).collect();
+        let fixings: Vec<(DateTime, f64)> = dates.iter().cloned()
+            .zip([80.0, 100.0, 120.0, 130.0].iter().cloned()).collect();
+        let today = Date::from_ymd(2017, 01, 09);
+        let fixing_table = FixingTable::from_fixings(today, &[("BP.L", &fixings)]).unwrap();
+
+        for &(boundary, expected) in [(CorridorBoundary::Inclusive, 3),
+            (CorridorBoundary::Exclusive, 1)].iter() {
+            let range_accrual = sample_range_accrual(80.0, 120.0, boundary);
+            let decomp = range_accrual.fix(&fixing_table).unwrap().unwrap();
+            assert_eq!(decomp.len(), 1);
+            let (weight, ref fixed) = decomp[0];
+            assert_approx(weight, 1.0, 1e-12);
+            let value = serde_json::to_value(fixed).unwrap();
+            let fixed = &value["RangeAccrual"];
+            assert_eq!(fixed["past_observations"], 4);
+            assert_eq!(fixed["past_accrued"], expected);
+            assert_eq!(fixed["observation_dates"].as_array().unwrap().len(), 60);
+        }
+    }
+
+    #[test]
+    fn range_accrual_fix_when_fully_fixed() {
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        let equity = RcInstrument::new(Qrc::new(Arc::new(sample_equity(currency, 2))));
+        let dates: Vec<DateTime> = (3..7).map(|day| DateTime::new(
+            Date::from_ymd(2017, 01, day), TimeOfDay::Close)).collect();
+        let range_accrual = RangeAccrual::from_dates("SampleRangeAccrual", "OPT",
+            equity, sample_settlement(2), &dates, 80.0, 120.0,
+            CorridorBoundary::Inclusive, 10.0).unwrap();
+
+        let fixings: Vec<(DateTime, f64)> = dates.iter().cloned()
+            .zip([80.0, 100.0, 120.0, 130.0].iter().cloned()).collect();
+        let today = Date::from_ymd(2017, 01, 09);
+        let fixing_table = FixingTable::from_fixings(today, &[("BP.L", &fixings)]).unwrap();
+        let decomp = range_accrual.fix(&fixing_table).unwrap().unwrap();
+        assert_eq!(decomp.len(), 1);
+        assert_approx(decomp[0].0, 7.5, 1e-12);
+        assert_eq!(decomp[0].1.id(), "SampleRangeAccrual:Expiry");
+    }
+
+    #[test]
+    fn range_accrual_rejects_bad_corridor() {
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        let equity = RcInstrument::new(Qrc::new(Arc::new(sample_equity(currency, 2))));
+        let dates = [DateTime::new(Date::from_ymd(2017, 01, 03), TimeOfDay::Close)];
+        assert!(RangeAccrual::from_dates("Bad", "OPT", equity.clone(),
+            sample_settlement(2), &dates, 120.0, 80.0,
+            CorridorBoundary::Inclusive, 10.0).is_err());
+        assert!(RangeAccrual::from_dates("Bad", "OPT", equity,
+            sample_settlement(2), &[], 80.0, 120.0,
+            CorridorBoundary::Inclusive, 10.0).is_err());
+    }
+
+    #[test]
+    fn range_accrual_serde() {
+        let range_accrual = sample_range_accrual(80.0, 120.0, CorridorBoundary::Exclusive);
+        let serialized = serde_json::to_string(&range_accrual).unwrap();
+        let deserialized: RangeAccrual = serde_json::from_str(&serialized).unwrap();
+        let reserialized = serde_json::to_string(&deserialized).unwrap();
+        assert_eq!(serialized, reserialized);
+    }
+
+    fn assert_approx(value: f64, expected: f64, tolerance: f64) {
+        assert!(approx_eq(value, expected, tolerance),
+            "value={} expected={}", value, expected);
+    }
+}
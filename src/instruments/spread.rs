@@ -0,0 +1,420 @@
+use std::sync::Arc;
+use instruments::Instrument;
+use instruments::RcInstrument;
+use instruments::Priceable;
+use instruments::PricingContext;
+use instruments::DependencyContext;
+use instruments::SpotRequirement;
+use instruments::MonteCarloPriceable;
+use instruments::MonteCarloDependencies;
+use instruments::ObservationKind;
+use instruments::MonteCarloContext;
+use instruments::options::PutOrCall;
+use instruments::bonds::ZeroCoupon;
+use instruments::assets::Currency;
+use instruments::assets::RcCurrency;
+use math::optionpricing::Black76;
+use dates::Date;
+use dates::rules::RcDateRule;
+use dates::datetime::DateTime;
+use dates::datetime::DateDayFraction;
+use data::fixings::FixingTable;
+use core::qm;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use core::dedup::InstanceId;
+use ndarray::Array2;
+use erased_serde as esd;
+use serde::Deserialize;
+
+/// A spread option is a European option on the difference between two
+/// underlyings at expiry. A call pays (S1 - S2 - K).max(0) and a put pays
+/// (K - S1 + S2).max(0), where S1 is the long underlying and S2 the short
+/// one, in cash at the settlement date following the expiry. With a zero
+/// strike, it is an option to exchange one underlying for the other.
+///
+/// It is priced by Monte-Carlo, which needs the correlation between the two
+/// underlyings. It can also be valued analytically using Kirk's
+/// approximation, which treats S2 + K as lognormal. This is exact for a zero
+/// strike, where it is Margrabe's formula for an exchange option, and is
+/// useful for reconciling the Monte-Carlo price otherwise.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct SpreadOption {
+    id: String,
+    credit_id: String,
+    long: RcInstrument,
+    short: RcInstrument,
+    settlement: RcDateRule,
+    expiry: DateTime,
+    strike: f64,
+    put_or_call: PutOrCall,
+
+    // fields precomputed for performance and simplicity
+    long_expiry_time: DateDayFraction,
+    short_expiry_time: DateDayFraction,
+    pay_date: Date
+}
+
+impl TypeId for SpreadOption {
+    fn get_type_id(&self) -> &'static str { "SpreadOption" }
+}
+
+impl InstanceId for SpreadOption {
+    fn id(&self) -> &str { &self.id }
+}
+
+impl SpreadOption {
+    /// Creates a spread option on the long underlying less the short one,
+    /// which must be in the same currency. The strike may be any finite
+    /// value, though Kirk's approximation needs the forward of the short
+    /// underlying plus the strike to be positive.
+    pub fn new(
+        id: &str,
+        credit_id: &str,
+        long: RcInstrument,
+        short: RcInstrument,
+        settlement: RcDateRule,
+        expiry: DateTime,
+        strike: f64,
+        put_or_call: PutOrCall) -> Result<SpreadOption, qm::Error> {
+
+        if !strike.is_finite() {
+            return Err(qm::Error::new("The strike of a spread option must be finite"))
+        }
+        if long.payoff_currency() != short.payoff_currency() {
+            return Err(qm::Error::new(
+                "The underlyings of a spread option must be in the same currency"))
+        }
+
+        let long_expiry_time = long.time_to_day_fraction(expiry)?;
+        let short_expiry_time = short.time_to_day_fraction(expiry)?;
+        let pay_date = settlement.apply(expiry.date());
+        Ok(SpreadOption {
+            id: id.to_string(),
+            credit_id: credit_id.to_string(),
+            long: long,
+            short: short,
+            settlement: settlement,
+            expiry: expiry,
+            strike: strike,
+            put_or_call: put_or_call,
+            long_expiry_time: long_expiry_time,
+            short_expiry_time: short_expiry_time,
+            pay_date: pay_date })
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<Instrument>, esd::Error> {
+        Ok(Qrc::new(Arc::new(SpreadOption::deserialize(de)?)))
+    }
+
+    /// The payoff, given the values of the long and short underlyings at
+    /// expiry
+    fn intrinsic(&self, long: f64, short: f64) -> f64 {
+        let spread = long - short;
+        match self.put_or_call {
+            PutOrCall::Call => (spread - self.strike).max(0.0),
+            PutOrCall::Put => (self.strike - spread).max(0.0) }
+    }
+
+    /// The cash payment at the pay date
+    fn payment(&self) -> RcInstrument {
+        let currency = RcCurrency::new(Arc::new(self.payoff_currency().clone()));
+        RcInstrument::new(Qrc::new(Arc::new(
+            ZeroCoupon::new(&format!("{}:Expiry", self.id),
+            &self.credit_id, currency, self.expiry, self.pay_date,
+            self.settlement.clone()))))
+    }
+
+    /// The forward of an underlying at expiry, and its variance from the
+    /// given date to expiry, at the money
+    fn forward_and_variance(&self, context: &PricingContext,
+        underlying: &RcInstrument, expiry_time: DateDayFraction, date: DateTime)
+        -> Result<(f64, f64), qm::Error> {
+
+        let expiry_date = self.expiry.date();
+        let priceable = underlying.as_priceable().ok_or_else(|| qm::Error::new(
+            "The underlyings of a spread option must themselves be priceable"))?;
+        let forward = priceable.price(context, self.expiry)?;
+        let vol = context.vol_surface(&**underlying, expiry_date,
+            &|| context.forward_curve(&**underlying, expiry_date))?;
+        if vol.displacement(expiry_date)? != 0.0 {
+            return Err(qm::Error::new(&format!("Kirk's approximation does not \
+                support the displaced vol surface of {}", underlying.id())))
+        }
+
+        let val_time = underlying.time_to_day_fraction(date)?;
+        let variance = vol.forward_variance(val_time.min(expiry_time),
+            expiry_time, forward)?;
+        if variance < 0.0 {
+            return Err(qm::Error::new("Negative variance"))
+        }
+        Ok((forward, variance))
+    }
+}
+
+impl Instrument for SpreadOption {
+    fn payoff_currency(&self) -> &Currency { self.long.payoff_currency() }
+    fn credit_id(&self) -> &str { &self.credit_id }
+    fn settlement(&self) -> &RcDateRule { &self.settlement }
+    fn as_priceable(&self) -> Option<&Priceable> { Some(self) }
+    fn as_mc_priceable(&self) -> Option<&MonteCarloPriceable> { Some(self) }
+
+    // Kirk's formula is only an approximation
+    fn as_analytic_priceable(&self) -> Option<&Priceable> { None }
+
+    fn dependencies(&self, context: &mut DependencyContext)
+        -> SpotRequirement {
+
+        // one fixing of each underlying, at expiry
+        let expiry_date = self.expiry.date();
+        for underlying in [&self.long, &self.short].iter() {
+            context.fixing(underlying.id(), self.expiry);
+            context.forward_curve(underlying, expiry_date);
+            context.vol_surface(underlying, expiry_date);
+        }
+        context.yield_curve(self.credit_id(), self.pay_date);
+
+        SpotRequirement::NotRequired
+    }
+
+    fn fix(&self, fixing_table: &FixingTable)
+        -> Result<Option<Vec<(f64, RcInstrument)>>, qm::Error> {
+
+        // Both underlyings fix at expiry, after which the option becomes a
+        // cash payment, or nothing if it expired out of the money.
+        let long = fixing_table.get(self.long.id(), self.expiry)?;
+        let short = fixing_table.get(self.short.id(), self.expiry)?;
+        match (long, short) {
+            (None, None) => Ok(None),
+            (Some(long), Some(short)) => {
+                let payment = self.intrinsic(long, short);
+                let mut decomp = Vec::new();
+                if payment > 0.0 {
+                    decomp.push((payment, self.payment()));
+                }
+                Ok(Some(decomp))
+            },
+            _ => Err(qm::Error::new(&format!(
+                "Spread option {} has only one of its underlyings fixed", self.id)))
+        }
+    }
+}
+
+impl Priceable for SpreadOption {
+    fn as_instrument(&self) -> &Instrument { self }
+
+    /// Kirk's approximation, which prices the option with the Black-76
+    /// formula, as an option to exchange S2 + K for S1, where S2 + K is
+    /// lognormal with a vol scaled by F2 / (F2 + K). The vols are taken at
+    /// the money.
+    fn prices(&self, context: &PricingContext, dates: &[DateTime], out: &mut [f64])
+        -> Result<(), qm::Error> {
+        assert_eq!(dates.len(), out.len());
+
+        let yc = context.yield_curve(&self.credit_id, self.pay_date)?;
+        let correlation = context.correlation(&*self.long, &*self.short)?;
+        let black76 = Black76::new()?;
+
+        for (date, output) in dates.iter().zip(out.iter_mut()) {
+            if *date > self.expiry {
+                *output = 0.0;
+                continue;
+            }
+
+            let (f1, v1) = self.forward_and_variance(context, &self.long,
+                self.long_expiry_time, *date)?;
+            let (f2, v2) = self.forward_and_variance(context, &self.short,
+                self.short_expiry_time, *date)?;
+            let shifted = f2 + self.strike;
+            if !(shifted > 0.0) {
+                return Err(qm::Error::new(&format!("Kirk's approximation needs \
+                    the forward {} plus the strike {} to be positive", f2, self.strike)))
+            }
+
+            let weight = f2 / shifted;
+            let variance = v1 - 2.0 * correlation * weight * (v1 * v2).sqrt()
+                + weight * weight * v2;
+            let sqrt_var = variance.max(0.0).sqrt();
+
+            let df = yc.df(self.pay_date, self.settlement.apply(date.date()))?;
+            *output = if sqrt_var > 0.0 {
+                match self.put_or_call {
+                    PutOrCall::Call => black76.call_price(df, f1, shifted, sqrt_var),
+                    PutOrCall::Put => black76.put_price(df, f1, shifted, sqrt_var) }
+            } else {
+                df * self.intrinsic(f1, f2)
+            };
+        }
+
+        Ok(())
+    }
+}
+
+impl MonteCarloPriceable for SpreadOption {
+    fn as_instrument(&self) -> &Instrument { self }
+
+    fn mc_dependencies(&self, _dates: &[DateDayFraction],
+        output: &mut MonteCarloDependencies) -> Result<(), qm::Error> {
+
+        // one observation of each underlying, at expiry, and one cash flow
+        output.observation(&self.long, self.long_expiry_time,
+            ObservationKind::Fixing);
+        output.observation(&self.short, self.short_expiry_time,
+            ObservationKind::Fixing);
+        output.flow(&self.payment());
+        Ok(())
+    }
+
+    fn start_date(&self) -> Option<DateDayFraction> {
+        None
+    }
+
+    fn mc_price(&self, context: &MonteCarloContext)
+        -> Result<f64, qm::Error> {
+
+        let long_paths = context.paths(&self.long)?;
+        let short_paths = context.paths(&self.short)?;
+        assert_eq!(long_paths.shape()[1], 1);
+        assert_eq!(short_paths.shape()[1], 1);
+
+        let n_paths = long_paths.shape()[0];
+        let mut quantities = Array2::zeros((n_paths, 1));
+        for (p, flow) in quantities.iter_mut().enumerate() {
+            *flow = self.intrinsic(long_paths[[p, 0]], short_paths[[p, 0]]);
+        }
+
+        context.evaluate_flows(quantities.view())
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use pricers::montecarlo::tests::black_diffusion_price_with_stderr;
+    use math::numerics::approx_eq;
+    use dates::datetime::TimeOfDay;
+    use instruments::assets::tests::sample_currency;
+    use instruments::assets::tests::sample_equity;
+    use instruments::basket::tests::sample_basket_market_data;
+    use risk::marketdata::MarketData;
+
+    fn sample_underlying(id: &str) -> RcInstrument {
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        RcInstrument::new(Qrc::new(Arc::new(sample_equity(currency, id, 2))))
+    }
+
+    fn sample_expiry() -> DateTime {
+        DateTime::new(Date::from_ymd(2018, 06, 01), TimeOfDay::Close)
+    }
+
+    pub fn sample_spread(strike: f64, put_or_call: PutOrCall) -> SpreadOption {
+        let long = sample_underlying("BP.L");
+        let settlement = long.settlement().clone();
+        SpreadOption::new("SampleSpread", "LSE", long, sample_underlying("BP2.L"),
+            settlement, sample_expiry(), strike, put_or_call).unwrap()
+    }
+
+    pub fn sample_spread_market_data(correlation: f64) -> MarketData {
+        sample_basket_market_data(&["BP.L", "BP2.L"],
+            vec![vec![1.0, correlation], vec![correlation, 1.0]])
+    }
+
+    /// The discount factor from the pay date of the sample spread to the
+    /// settlement of the spot date
+    fn sample_df(market_data: &MarketData) -> f64 {
+        let pay_date = Date::from_ymd(2018, 06, 05);
+        market_data.yield_curve("LSE", pay_date).unwrap()
+            .df(pay_date, Date::from_ymd(2017, 01, 04)).unwrap()
+    }
+
+    fn mc_price_with_stderr(option: SpreadOption, market_data: &MarketData)
+        -> (f64, f64) {
+        let instrument = RcInstrument::new(Qrc::new(Arc::new(option)));
+        black_diffusion_price_with_stderr(instrument, market_data, 0.01, 20000).unwrap()
+    }
+
+    #[test]
+    fn zero_strike_spread_matches_margrabe() {
+
+        // Margrabe's formula for an option to exchange BP2.L for BP.L. Both
+        // have the same market data, so the same forward and variance.
+        let correlation = 0.5;
+        let market_data = sample_spread_market_data(correlation);
+        let underlying = sample_underlying("BP.L");
+        let expiry = sample_expiry();
+        let expiry_date = expiry.date();
+        let forward = market_data.forward_curve(&*underlying, expiry_date).unwrap()
+            .forward(expiry_date).unwrap();
+        let variance = market_data.vol_surface(&*underlying, expiry_date,
+            &|| market_data.forward_curve(&*underlying, expiry_date)).unwrap()
+            .variance(underlying.time_to_day_fraction(expiry).unwrap(), forward).unwrap();
+        let df = sample_df(&market_data);
+        let sqrt_var = (2.0 * (1.0 - correlation) * variance).sqrt();
+        let margrabe = Black76::new().unwrap()
+            .call_price(df, forward, forward, sqrt_var);
+
+        // Kirk's approximation is exact here
+        let option = sample_spread(0.0, PutOrCall::Call);
+        let val_date = DateTime::new(market_data.spot_date(), TimeOfDay::Open);
+        let kirk = option.price(&market_data, val_date).unwrap();
+        assert!(approx_eq(kirk, margrabe, 1e-10), "kirk={} margrabe={}", kirk, margrabe);
+
+        let (price, stderr) = mc_price_with_stderr(option, &market_data);
+        assert!((price - margrabe).abs() < 3.0 * stderr,
+            "price={} margrabe={} stderr={}", price, margrabe, stderr);
+    }
+
+    #[test]
+    fn kirk_reconciles_with_monte_carlo() {
+
+        // the spread is worth more the less correlated the legs are, and
+        // with a strike, Kirk is close to but not exactly the simulated price
+        let val_date = DateTime::new(Date::from_ymd(2017, 01, 02), TimeOfDay::Open);
+        let mut previous = None;
+        for &correlation in [0.9, 0.5, 0.0].iter() {
+            let market_data = sample_spread_market_data(correlation);
+            let option = sample_spread(5.0, PutOrCall::Call);
+            let kirk = option.price(&market_data, val_date).unwrap();
+            let (price, stderr) = mc_price_with_stderr(option, &market_data);
+            assert!((price - kirk).abs() < 3.0 * stderr + 0.01 * kirk,
+                "correlation={} price={} kirk={} stderr={}",
+                correlation, price, kirk, stderr);
+            if let Some(previous) = previous {
+                assert!(kirk > previous, "kirk={} previous={}", kirk, previous);
+            }
+            previous = Some(kirk);
+
+            // put/call parity, where the two forwards are the same
+            let put = sample_spread(5.0, PutOrCall::Put).price(&market_data, val_date).unwrap();
+            assert!(approx_eq(kirk - put, -5.0 * sample_df(&market_data), 1e-10),
+                "call={} put={}", kirk, put);
+        }
+    }
+
+    #[test]
+    fn spread_fix_needs_both_underlyings() {
+        let fixings = |long: Option<f64>, short: Option<f64>| {
+            let mut curves = Vec::new();
+            if let Some(long) = long {
+                curves.push(("BP.L", vec![(sample_expiry(), long)]));
+            }
+            if let Some(short) = short {
+                curves.push(("BP2.L", vec![(sample_expiry(), short)]));
+            }
+            FixingTable::from_iter_known_until(Date::from_ymd(2018, 06, 02),
+                curves.into_iter()).unwrap()
+        };
+
+        let option = sample_spread(5.0, PutOrCall::Call);
+        let decomp = option.fix(&fixings(Some(120.0), Some(100.0))).unwrap().unwrap();
+        assert_eq!(decomp.len(), 1);
+        assert!(approx_eq(decomp[0].0, 15.0, 1e-12), "payment={}", decomp[0].0);
+        assert_eq!(decomp[0].1.id(), "SampleSpread:Expiry");
+        assert!(option.fix(&fixings(Some(100.0), Some(100.0))).unwrap().unwrap().is_empty());
+        assert!(option.fix(&fixings(Some(100.0), None)).is_err());
+
+        let put = sample_spread(5.0, PutOrCall::Put);
+        let decomp = put.fix(&fixings(Some(100.0), Some(100.0))).unwrap().unwrap();
+        assert!(approx_eq(decomp[0].0, 5.0, 1e-12), "payment={}", decomp[0].0);
+    }
+}
@@ -0,0 +1,237 @@
+use std::sync::Arc;
+use std::fmt::Display;
+use std::fmt;
+use instruments::fix_all;
+use instruments::Instrument;
+use instruments::RcInstrument;
+use instruments::Priceable;
+use instruments::PricingContext;
+use instruments::DependencyContext;
+use instruments::SpotRequirement;
+use instruments::assets::Currency;
+use instruments::assets::RcCurrency;
+use dates::rules::RcDateRule;
+use dates::datetime::DateTime;
+use data::fixings::FixingTable;
+use core::qm;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use core::dedup::InstanceId;
+use erased_serde as esd;
+use serde::Deserialize;
+
+/// A structured note, made up of weighted legs such as a zero-coupon bond
+/// for the capital plus an embedded option for the participation.
+///
+/// The note is never priced as a whole by the pricers. Its fix method always
+/// decomposes it into its legs, each with any fixings applied, so the pricer
+/// values the legs as a weighted sum, each by whatever means suits it. The
+/// note is also priceable in its own right if all its legs are, which is
+/// useful for checking the decomposition.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct StructuredNote {
+    id: String,
+    credit_id: String,
+    currency: RcCurrency,
+    settlement: RcDateRule,
+    legs: Vec<(f64, RcInstrument)>
+}
+
+impl TypeId for StructuredNote {
+    fn get_type_id(&self) -> &'static str { "StructuredNote" }
+}
+
+impl InstanceId for StructuredNote {
+    fn id(&self) -> &str { &self.id }
+}
+
+impl StructuredNote {
+    /// Creates a note from its weighted legs, which must all pay in the
+    /// currency of the note.
+    pub fn new(id: &str, credit_id: &str, currency: RcCurrency,
+        settlement: RcDateRule, legs: Vec<(f64, RcInstrument)>)
+        -> Result<StructuredNote, qm::Error> {
+
+        if legs.is_empty() {
+            return Err(qm::Error::new("A structured note must have at least one leg"))
+        }
+        for &(_, ref leg) in legs.iter() {
+            if *leg.payoff_currency() != *currency {
+                return Err(qm::Error::new(&format!("Leg {} of note {} does not \
+                    pay in the currency of the note", leg.id(), id)))
+            }
+        }
+
+        Ok(StructuredNote { id: id.to_string(), credit_id: credit_id.to_string(),
+            currency: currency, settlement: settlement, legs: legs })
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<Instrument>, esd::Error> {
+        Ok(Qrc::new(Arc::new(StructuredNote::deserialize(de)?)))
+    }
+
+    pub fn legs(&self) -> &[(f64, RcInstrument)] { &self.legs }
+}
+
+impl Instrument for StructuredNote {
+    fn payoff_currency(&self) -> &Currency { &*self.currency }
+    fn credit_id(&self) -> &str { &self.credit_id }
+    fn settlement(&self) -> &RcDateRule { &self.settlement }
+
+    fn dependencies(&self, context: &mut DependencyContext)
+        -> SpotRequirement {
+        for &(_, ref leg) in self.legs.iter() {
+            match leg.dependencies(context) {
+                SpotRequirement::NotRequired => {},
+                _ => context.spot(leg)
+            };
+        }
+        SpotRequirement::NotRequired
+    }
+
+    fn is_pure_rates(&self) -> bool {
+        self.legs.iter().all(|&(_, ref leg)| leg.is_pure_rates())
+    }
+
+    fn as_priceable(&self) -> Option<&Priceable> {
+        if self.legs.iter().all(|&(_, ref leg)| leg.as_priceable().is_some()) {
+            Some(self)
+        } else {
+            None
+        }
+    }
+
+    /// Always decomposes the note into its legs, applying the fixings to
+    /// each, so that a leg may itself be replaced by several instruments.
+    fn fix(&self, fixing_table: &FixingTable)
+        -> Result<Option<Vec<(f64, RcInstrument)>>, qm::Error> {
+
+        match fix_all(&self.legs, fixing_table)? {
+            Some(fixed) => Ok(Some(fixed)),
+            None => Ok(Some(self.legs.clone()))
+        }
+    }
+}
+
+impl Display for StructuredNote {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.id.fmt(f)
+    }
+}
+
+impl Priceable for StructuredNote {
+    fn as_instrument(&self) -> &Instrument { self }
+
+    /// The weighted sum of the prices of the legs
+    fn prices(&self, context: &PricingContext, dates: &[DateTime], out: &mut [f64])
+        -> Result<(), qm::Error> {
+        assert_eq!(dates.len(), out.len());
+
+        for output in out.iter_mut() {
+            *output = 0.0;
+        }
+
+        let mut leg_prices = vec!(0.0; dates.len());
+        for &(weight, ref leg) in self.legs.iter() {
+            let priceable = leg.as_priceable().ok_or_else(|| qm::Error::new(
+                &format!("Leg {} of note {} is not priceable", leg.id(), self.id)))?;
+            priceable.prices(context, dates, &mut leg_prices)?;
+            for (output, price) in out.iter_mut().zip(leg_prices.iter()) {
+                *output += weight * price;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+    use dates::Date;
+    use dates::datetime::TimeOfDay;
+    use data::fixings::RcFixingTable;
+    use instruments::bonds::ZeroCoupon;
+    use instruments::options::SpotStartingEuropean;
+    use instruments::options::PutOrCall;
+    use instruments::options::OptionSettlement;
+    use risk::marketdata::RcMarketData;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::marketdata::tests::sample_currency;
+    use risk::marketdata::tests::sample_settlement;
+    use risk::marketdata::tests::sample_equity;
+    use pricers::PricerFactory;
+    use risk::Pricer;
+    use pricers::selfpricer::SelfPricerFactory;
+
+    /// A capital-protected note, paying back 100 at maturity in June 2018
+    /// plus 0.8 of a call on BP.L struck at 100 and expiring on the given
+    /// date
+    fn sample_note(expiry: Date) -> StructuredNote {
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        let settlement = sample_settlement(2);
+        let equity = RcInstrument::new(Qrc::new(Arc::new(
+            sample_equity(currency.clone(), 2))));
+        let expiry = DateTime::new(expiry, TimeOfDay::Close);
+        let maturity = DateTime::new(Date::from_ymd(2018, 06, 01), TimeOfDay::Close);
+
+        let bond = RcInstrument::new(Qrc::new(Arc::new(ZeroCoupon::new(
+            "NoteCapital", "OPT", currency.clone(), maturity,
+            Date::from_ymd(2018, 06, 05), settlement.clone()))));
+        let option = RcInstrument::new(Qrc::new(Arc::new(SpotStartingEuropean::new(
+            "NoteOption", "OPT", equity, settlement.clone(), expiry, 100.0,
+            PutOrCall::Call, OptionSettlement::Cash).unwrap())));
+
+        StructuredNote::new("Note", "OPT", currency, settlement,
+            vec!((100.0, bond), (0.8, option))).unwrap()
+    }
+
+    #[test]
+    fn decomposed_note_matches_direct_valuation() {
+        let market_data = sample_market_data();
+        let note = sample_note(Date::from_ymd(2018, 06, 01));
+        let val_date = DateTime::new(Date::from_ymd(2017, 01, 02), TimeOfDay::Open);
+        let direct = note.price(&market_data, val_date).unwrap();
+
+        // with no fixings, the legs come back unchanged
+        let fixings = FixingTable::new(Date::from_ymd(2017, 01, 02));
+        let legs = note.fix(&fixings).unwrap().unwrap();
+        assert_eq!(legs.len(), 2);
+        assert_eq!(legs[0].0, 100.0);
+        assert_eq!(legs[1].0, 0.8);
+
+        // the pricer values the legs as a weighted sum
+        let pricer = SelfPricerFactory::new().new(
+            RcInstrument::new(Qrc::new(Arc::new(note))),
+            RcFixingTable::new(Arc::new(fixings)),
+            RcMarketData::new(Arc::new(market_data))).unwrap();
+        let decomposed = pricer.price().unwrap();
+        assert!(approx_eq(decomposed, direct, 1e-12),
+            "decomposed={} direct={}", decomposed, direct);
+    }
+
+    #[test]
+    fn note_propagates_fixings_into_legs() {
+
+        // the option expired in the money before the spot date, so it
+        // becomes a cash payment of 0.8 * (110 - 100)
+        let expiry = Date::from_ymd(2016, 12, 30);
+        let note = sample_note(expiry);
+        let fixings = FixingTable::from_fixings(Date::from_ymd(2017, 01, 02), &[
+            ("BP.L", &[(DateTime::new(expiry, TimeOfDay::Close), 110.0)])]).unwrap();
+        let legs = note.fix(&fixings).unwrap().unwrap();
+        assert_eq!(legs.len(), 2);
+        assert_eq!(legs[0].0, 100.0);
+        assert_eq!(legs[0].1.id(), "NoteCapital");
+        assert!(approx_eq(legs[1].0, 8.0, 1e-12), "weight={}", legs[1].0);
+        assert!(legs[1].1.is_pure_rates());
+    }
+
+    #[test]
+    fn note_validates_legs() {
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        assert!(StructuredNote::new("Note", "OPT", currency, sample_settlement(2),
+            Vec::new()).is_err());
+    }
+}
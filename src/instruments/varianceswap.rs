@@ -0,0 +1,632 @@
+use std::sync::Arc;
+use instruments::Instrument;
+use instruments::RcInstrument;
+use instruments::Priceable;
+use instruments::PricingContext;
+use instruments::DependencyContext;
+use instruments::SpotRequirement;
+use instruments::MonteCarloPriceable;
+use instruments::MonteCarloDependencies;
+use instruments::ObservationKind;
+use instruments::MonteCarloContext;
+use instruments::assets::Currency;
+use instruments::assets::RcCurrency;
+use instruments::bonds::ZeroCoupon;
+use data::fixings::FixingTable;
+use data::volsurface::VolSurface;
+use dates::Date;
+use dates::rules::RcDateRule;
+use dates::datetime::DateTime;
+use dates::datetime::DateDayFraction;
+use math::optionpricing::Black76;
+use core::qm;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use core::dedup::InstanceId;
+use ndarray::Axis;
+use ndarray::Array2;
+use erased_serde as esd;
+use serde::Deserialize;
+
+/// The number of standard deviations either side of the forward, measured
+/// in log strike, over which the replication integral is evaluated. Out of
+/// the money options beyond this are worthless to double precision.
+const REPLICATION_STD_DEVS: f64 = 10.0;
+
+/// The number of standard deviations either side of the forward beyond
+/// which the vol surface is extrapolated flat in strike for the replication
+/// integral. Many smile parameterisations are unreliable so far out, and
+/// some give vols that grow without bound.
+const WING_STD_DEVS: f64 = 4.0;
+
+/// The number of intervals in the replication integral. This must be a
+/// multiple of four, so the kink at the forward, where the integrand
+/// switches from puts to calls, falls on a boundary of Simpson's rule.
+const REPLICATION_STEPS: usize = 400;
+
+/// A variance swap pays the notional times the difference between the
+/// realised variance of the underlying and the variance strike, at the
+/// settlement date following the last observation. Realised variance is the
+/// annualisation factor, typically 252, times the mean of the squared log
+/// returns between consecutive observation dates. The returns are not
+/// adjusted for dividends, and there is no mean subtraction.
+///
+/// The notional is a variance notional. The variance strike is normally
+/// quoted as the square of a vol, so a strike of 0.04 is 20 vol.
+///
+/// Before any observations have fixed, the swap is priced analytically by
+/// static replication from the vol surface. Once some observations are in
+/// the past, fixing the swap records the sum of the squared returns so far
+/// and the most recent fixing, and the swap must then be priced by
+/// Monte-Carlo. The Monte-Carlo valuation is also useful for validating the
+/// replication.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct VarianceSwap {
+    id: String,
+    credit_id: String,
+    underlying: RcInstrument,
+    settlement: RcDateRule,
+    observation_dates: Vec<DateTime>,
+    variance_strike: f64,
+    notional: f64,
+    annualisation: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_fixing: Option<f64>,
+    #[serde(default)]
+    past_sum_of_squares: f64,
+    #[serde(default)]
+    past_returns: usize,
+
+    // fields precomputed for performance and simplicity
+    observation_times: Vec<DateDayFraction>,
+    pay_date: Date
+}
+
+impl TypeId for VarianceSwap {
+    fn get_type_id(&self) -> &'static str { "VarianceSwap" }
+}
+
+impl InstanceId for VarianceSwap {
+    fn id(&self) -> &str { &self.id }
+}
+
+impl VarianceSwap {
+    /// Creates a variance swap. The observation dates, normally every
+    /// business day from the start to the end of the swap, must be supplied
+    /// in strictly increasing order, and there must be at least two of them,
+    /// to make at least one return.
+    pub fn new(
+        id: &str,
+        credit_id: &str,
+        underlying: RcInstrument,
+        settlement: RcDateRule,
+        observation_dates: &[DateTime],
+        variance_strike: f64,
+        notional: f64,
+        annualisation: f64)
+        -> Result<VarianceSwap, qm::Error> {
+
+        if observation_dates.len() < 2 {
+            return Err(qm::Error::new(
+                "A variance swap must have at least two observation dates"))
+        }
+
+        VarianceSwap::with_past_fixings(id, credit_id, underlying, settlement,
+            observation_dates, variance_strike, notional, annualisation,
+            None, 0.0, 0)
+    }
+
+    /// Creates a variance swap where some of the observations are in the
+    /// past. The last fixing starts the return ending on the first of the
+    /// observation dates, and the past returns are the number of returns
+    /// that make up the past sum of squares.
+    fn with_past_fixings(
+        id: &str,
+        credit_id: &str,
+        underlying: RcInstrument,
+        settlement: RcDateRule,
+        observation_dates: &[DateTime],
+        variance_strike: f64,
+        notional: f64,
+        annualisation: f64,
+        last_fixing: Option<f64>,
+        past_sum_of_squares: f64,
+        past_returns: usize)
+        -> Result<VarianceSwap, qm::Error> {
+
+        if !(annualisation > 0.0) {
+            return Err(qm::Error::new("Annualisation factor must be positive"))
+        }
+        if let Some(fixing) = last_fixing {
+            if !(fixing > 0.0) {
+                return Err(qm::Error::new("Non-positive past fixing"))
+            }
+        }
+
+        let last = observation_dates.last().ok_or_else(|| qm::Error::new(
+            "A variance swap must have at least one observation date"))?;
+        for pair in observation_dates.windows(2) {
+            if pair[0] >= pair[1] {
+                return Err(qm::Error::new(
+                    "Observation dates must be in strictly increasing order"))
+            }
+        }
+
+        let pay_date = settlement.apply(last.date());
+        let mut observation_times = Vec::with_capacity(observation_dates.len());
+        for date in observation_dates.iter() {
+            observation_times.push(underlying.time_to_day_fraction(*date)?);
+        }
+
+        Ok(VarianceSwap {
+            id: id.to_string(),
+            credit_id: credit_id.to_string(),
+            underlying: underlying,
+            settlement: settlement,
+            observation_dates: observation_dates.to_vec(),
+            variance_strike: variance_strike,
+            notional: notional,
+            annualisation: annualisation,
+            last_fixing: last_fixing,
+            past_sum_of_squares: past_sum_of_squares,
+            past_returns: past_returns,
+            observation_times: observation_times,
+            pay_date: pay_date })
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<Instrument>, esd::Error> {
+        Ok(Qrc::new(Arc::new(VarianceSwap::deserialize(de)?)))
+    }
+
+    fn expiry(&self) -> DateTime {
+        *self.observation_dates.last().unwrap()
+    }
+
+    /// The total number of returns over the life of the swap, including
+    /// any that are in the past
+    fn total_returns(&self) -> usize {
+        let first_return = if self.last_fixing.is_some() { 0 } else { 1 };
+        self.past_returns + self.observation_dates.len() - first_return
+    }
+
+    /// The payoff given the sum of the squared log returns over the life of
+    /// the swap
+    fn payoff(&self, sum_of_squares: f64) -> f64 {
+        let realised = self.annualisation * sum_of_squares / self.total_returns() as f64;
+        self.notional * (realised - self.variance_strike)
+    }
+
+    /// The cash payment at the pay date
+    fn payment(&self) -> RcInstrument {
+        // TODO this feels inefficient and ugly
+        let currency = RcCurrency::new(Arc::new(self.payoff_currency().clone()));
+        RcInstrument::new(Qrc::new(Arc::new(
+            ZeroCoupon::new(&format!("{}:Expiry", self.id),
+            &self.credit_id, currency, self.expiry(), self.pay_date,
+            self.settlement.clone()))))
+    }
+
+    /// The fair variance strike, which is the expected realised variance
+    /// under the pricing measure, found by static replication from the vol
+    /// surface. The expected sum of squared log returns between the first
+    /// and last observations is twice the difference in value between log
+    /// contracts expiring on those dates. This ignores the drift in the
+    /// returns, whose square is negligible for daily observations.
+    ///
+    /// The replication is only available before any observations have
+    /// fixed, as it does not account for the return from the last fixing.
+    pub fn fair_variance(&self, context: &PricingContext) -> Result<f64, qm::Error> {
+
+        if self.last_fixing.is_some() {
+            return Err(qm::Error::new(&format!("Variance swap {} has started, \
+                so cannot be priced by replication", self.id)))
+        }
+
+        let expiry_date = self.expiry().date();
+        let vol = context.vol_surface(&*self.underlying, expiry_date,
+            &|| context.forward_curve(&*self.underlying, expiry_date))?;
+        let underlying = self.underlying.as_priceable().ok_or_else(|| qm::Error::new(
+            "The underlying of a variance swap must itself be priceable"))?;
+
+        let start = self.observation_dates[0];
+        let end = self.expiry();
+        let start_value = log_contract(&*vol, self.observation_times[0],
+            start.date(), underlying.price(context, start)?)?;
+        let end_value = log_contract(&*vol, *self.observation_times.last().unwrap(),
+            end.date(), underlying.price(context, end)?)?;
+        let variance = 2.0 * (end_value - start_value);
+        if variance < 0.0 {
+            return Err(qm::Error::new("Negative forward variance"))
+        }
+
+        Ok(self.annualisation * variance / self.total_returns() as f64)
+    }
+}
+
+/// The value of a log contract, -E[ln(S/F)], where S is the value of the
+/// underlying at the given time and F its forward, found by static
+/// replication. The log contract is a strip of out of the money puts and
+/// calls weighted by one over the strike squared. Expectations are taken
+/// in the forward measure, so the options are undiscounted.
+///
+/// The integral is evaluated by Simpson's rule in log strike. Beyond a few
+/// standard deviations, the vol surface is extrapolated flat in strike.
+fn log_contract(vol: &VolSurface, time: DateDayFraction, date: Date, forward: f64)
+    -> Result<f64, qm::Error> {
+
+    // no variance before the base date of the vol surface
+    let atm_variance = vol.variance(time, forward)?;
+    if atm_variance < 0.0 {
+        return Err(qm::Error::new("Negative variance"))
+    }
+    if atm_variance == 0.0 {
+        return Ok(0.0)
+    }
+
+    // For some div assumptions, we must displace the forward and strike
+    let displacement = vol.displacement(date)?;
+    let f = forward - displacement;
+    if f < 0.0 {
+        return Err(qm::Error::new("Negative forward"));
+    }
+
+    // Work out the strikes for the integral, and the strikes at which to
+    // look up vols, which are limited to the wings
+    let std_dev = atm_variance.sqrt();
+    let half_width = REPLICATION_STD_DEVS * std_dev;
+    let wing = WING_STD_DEVS * std_dev;
+    let dx = 2.0 * half_width / REPLICATION_STEPS as f64;
+    let log_strikes: Vec<f64> = (0..REPLICATION_STEPS + 1)
+        .map(|i| -half_width + i as f64 * dx).collect();
+    let lookup_strikes: Vec<f64> = log_strikes.iter()
+        .map(|x| forward * x.max(-wing).min(wing).exp()).collect();
+    let mut variances = vec![0.0; log_strikes.len()];
+    vol.variances(time, &lookup_strikes, &mut variances)?;
+
+    // With k = ln(K/F), the weight 1/K^2 dK becomes 1/K dk
+    let black76 = Black76::new()?;
+    let mut sum = 0.0;
+    for (i, (x, variance)) in log_strikes.iter().zip(variances.iter()).enumerate() {
+        if *variance < 0.0 {
+            return Err(qm::Error::new("Negative variance"))
+        }
+        let strike = forward * x.exp();
+        let sqrt_var = variance.sqrt();
+        let price = if strike < forward {
+            black76.put_price(1.0, f, strike + displacement, sqrt_var)
+        } else {
+            black76.call_price(1.0, f, strike + displacement, sqrt_var)
+        };
+        let weight = if i == 0 || i == REPLICATION_STEPS {
+            1.0
+        } else if i % 2 == 1 {
+            4.0
+        } else {
+            2.0
+        };
+        sum += weight * price / strike;
+    }
+
+    Ok(sum * dx / 3.0)
+}
+
+impl Instrument for VarianceSwap {
+    fn payoff_currency(&self) -> &Currency { self.underlying.payoff_currency() }
+    fn credit_id(&self) -> &str { &self.credit_id }
+    fn settlement(&self) -> &RcDateRule { &self.settlement }
+    fn as_mc_priceable(&self) -> Option<&MonteCarloPriceable> { Some(self) }
+
+    /// Only a variance swap with no past fixings can be replicated
+    fn as_priceable(&self) -> Option<&Priceable> {
+        match self.last_fixing {
+            Some(_) => None,
+            None => Some(self) }
+    }
+
+    fn dependencies(&self, context: &mut DependencyContext)
+        -> SpotRequirement {
+
+        // one fixing on each observation date
+        for date in self.observation_dates.iter() {
+            context.fixing(self.underlying.id(), *date);
+        }
+
+        let expiry_date = self.expiry().date();
+        context.yield_curve(self.credit_id(), self.pay_date);
+        context.forward_curve(&self.underlying, expiry_date);
+        context.vol_surface(&self.underlying, expiry_date);
+
+        SpotRequirement::NotRequired
+    }
+
+    fn fix(&self, fixing_table: &FixingTable)
+        -> Result<Option<Vec<(f64, RcInstrument)>>, qm::Error> {
+
+        // Find the observation dates that have fixed (the fetch errors if a
+        // fixing in the past is missing). These must all come before the
+        // dates that have not fixed. Each fixing completes the return
+        // started by the one before.
+        let mut last_fixing = self.last_fixing;
+        let mut sum_of_squares = self.past_sum_of_squares;
+        let mut past_returns = self.past_returns;
+        let mut n_fixed = 0;
+        let mut unfixed = false;
+        for date in self.observation_dates.iter() {
+            match fixing_table.get(self.underlying.id(), *date)? {
+                Some(_) if unfixed => return Err(qm::Error::new(&format!(
+                    "Variance swap {} has a fixing after an unfixed \
+                    observation date", self.id))),
+                Some(fixing) => {
+                    if !(fixing > 0.0) {
+                        return Err(qm::Error::new(&format!(
+                            "Variance swap {} has a non-positive fixing",
+                            self.id)))
+                    }
+                    if let Some(start) = last_fixing {
+                        let log_return = (fixing / start).ln();
+                        sum_of_squares += log_return * log_return;
+                        past_returns += 1;
+                    }
+                    last_fixing = Some(fixing);
+                    n_fixed += 1;
+                },
+                None => unfixed = true
+            }
+        }
+        if n_fixed == 0 {
+            return Ok(None)
+        }
+
+        // If all dates have fixed, the swap becomes a cash payment
+        if n_fixed == self.observation_dates.len() {
+            let payment = self.payoff(sum_of_squares);
+            let mut decomp = Vec::new();
+            if payment != 0.0 {
+                decomp.push((payment, self.payment()));
+            }
+            return Ok(Some(decomp))
+        }
+
+        let fixed = VarianceSwap::with_past_fixings(&self.id, &self.credit_id,
+            self.underlying.clone(), self.settlement.clone(),
+            &self.observation_dates[n_fixed..], self.variance_strike,
+            self.notional, self.annualisation, last_fixing, sum_of_squares,
+            past_returns)?;
+        Ok(Some(vec!((1.0, RcInstrument::new(Qrc::new(Arc::new(fixed)))))))
+    }
+}
+
+impl Priceable for VarianceSwap {
+    fn as_instrument(&self) -> &Instrument { self }
+
+    /// Values the variance swap from the fair variance, found by static
+    /// replication. As for European options, the value on a forward date
+    /// ignores any change in the fair variance between now and then.
+    fn prices(&self, context: &PricingContext, dates: &[DateTime], out: &mut [f64])
+        -> Result<(), qm::Error> {
+
+        assert_eq!(dates.len(), out.len());
+        if dates.is_empty() {
+            return Ok(())  // nothing to do
+        }
+
+        let yc = context.yield_curve(&self.credit_id, self.pay_date)?;
+        let df_from_base = (-yc.rt(self.pay_date)?).exp();
+        let value = self.notional * (self.fair_variance(context)? - self.variance_strike);
+
+        // We assume the swap goes ex just after its last observation
+        let ex_date = self.expiry();
+        for (date, output) in dates.iter().zip(out.iter_mut()) {
+            *output = if *date <= ex_date {
+                let settlement_date = self.settlement.apply(date.date());
+                let df = df_from_base * yc.rt(settlement_date)?.exp();
+                value * df
+            } else {
+                0.0
+            };
+        }
+
+        Ok(())
+    }
+}
+
+impl MonteCarloPriceable for VarianceSwap {
+    fn as_instrument(&self) -> &Instrument { self }
+
+    fn mc_dependencies(&self, _dates: &[DateDayFraction],
+        output: &mut MonteCarloDependencies) -> Result<(), qm::Error> {
+
+        // an observation on every observation date
+        for time in self.observation_times.iter() {
+            output.observation(&self.underlying, *time, ObservationKind::Fixing);
+        }
+
+        // a single cash payment at the pay date
+        output.flow(&self.payment());
+        Ok(())
+    }
+
+    /// Until the first observation has fixed, the swap is forward-starting
+    fn start_date(&self) -> Option<DateDayFraction> {
+        match self.last_fixing {
+            Some(_) => None,
+            None => Some(self.observation_times[0]) }
+    }
+
+    fn mc_price(&self, context: &MonteCarloContext)
+        -> Result<f64, qm::Error> {
+
+        let ref paths = context.paths(&self.underlying)?;
+        let n_paths = paths.shape()[0];
+        let n_obs = paths.shape()[1];
+        assert_eq!(n_obs, self.observation_times.len());
+
+        // If the first observation has not fixed, it starts the first
+        // return, rather than ending one
+        let first_end = if self.last_fixing.is_some() { 0 } else { 1 };
+
+        let mut quantities = Array2::zeros((n_paths, 1));
+        {
+            let ref mut flow_column = quantities.subview_mut(Axis(1), 0);
+            for (path, flow) in paths.axis_iter(Axis(0)).zip(flow_column.iter_mut()) {
+                let mut start = self.last_fixing.unwrap_or(path[0]);
+                let mut sum_of_squares = self.past_sum_of_squares;
+                for end in path.iter().skip(first_end) {
+                    let log_return = (*end / start).ln();
+                    sum_of_squares += log_return * log_return;
+                    start = *end;
+                }
+                *flow = self.payoff(sum_of_squares);
+            }
+        }
+
+        context.evaluate_flows(quantities.view())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pricers::montecarlo::tests::black_diffusion_price_with_stderr;
+    use math::numerics::approx_eq;
+    use dates::datetime::TimeOfDay;
+    use risk::marketdata::MarketData;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::marketdata::tests::sample_currency;
+    use risk::marketdata::tests::sample_settlement;
+    use risk::marketdata::tests::sample_equity;
+    use risk::Pricer;
+    use pricers::selfpricer::SelfPricer;
+    use serde_json;
+
+    fn sample_underlying() -> RcInstrument {
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        RcInstrument::new(Qrc::new(Arc::new(sample_equity(currency, 2))))
+    }
+
+    /// Every weekday close from February to June 2017. There are no
+    /// dividends going ex in this window, so realised variance comes only
+    /// from diffusion.
+    fn observation_dates() -> Vec<DateTime> {
+        let mut dates = Vec::new();
+        let mut date = Date::from_ymd(2017, 02, 01);
+        while date <= Date::from_ymd(2017, 07, 03) {
+            if date.day_of_week() < 5 {
+                dates.push(DateTime::new(date, TimeOfDay::Close));
+            }
+            date += 1;
+        }
+        dates
+    }
+
+    fn sample_variance_swap(variance_strike: f64) -> VarianceSwap {
+        VarianceSwap::new("SampleVarSwap", "OPT", sample_underlying(),
+            sample_settlement(2), &observation_dates(), variance_strike,
+            1000.0, 252.0).unwrap()
+    }
+
+    fn mc_price_with_stderr(instrument: RcInstrument, market_data: &MarketData)
+        -> (f64, f64) {
+        black_diffusion_price_with_stderr(instrument, market_data, 0.002, 20000).unwrap()
+    }
+
+    #[test]
+    fn fair_variance_on_flat_surface_is_vol_squared() {
+
+        // The sample vol surface is flat at 30%, with vol time measured in
+        // weekdays over 252, so the fair strike is 0.09 whatever the forward
+        let market_data = sample_market_data();
+        let swap = sample_variance_swap(0.0);
+        let fair = swap.fair_variance(&market_data).unwrap();
+        assert_approx(fair, 0.09, 1e-8);
+
+        // and a swap struck at the fair variance is worth nothing
+        let val_date = DateTime::new(market_data.spot_date(), TimeOfDay::Open);
+        let at_fair = sample_variance_swap(fair);
+        assert_approx(at_fair.price(&market_data, val_date).unwrap(), 0.0, 1e-9);
+    }
+
+    #[test]
+    fn replication_matches_monte_carlo_realised_variance() {
+
+        let market_data = sample_market_data();
+        let instrument = RcInstrument::new(Qrc::new(Arc::new(sample_variance_swap(0.05))));
+        assert!(instrument.as_analytic_priceable().is_some());
+        let analytic = SelfPricer::new(vec!((1.0, instrument.clone())), &market_data)
+            .unwrap().price().unwrap();
+        assert!(analytic > 30.0, "analytic={}", analytic);
+
+        let (mc, stderr) = mc_price_with_stderr(instrument, &market_data);
+        assert!((mc - analytic).abs() < 4.0 * stderr,
+            "mc={} analytic={} stderr={}", mc, analytic, stderr);
+    }
+
+    fn past_fixings(values: &[f64], today: Date) -> FixingTable {
+        let fixings: Vec<(DateTime, f64)> = observation_dates().iter()
+            .cloned().zip(values.iter().cloned()).collect();
+        FixingTable::from_fixings(today, &[("BP.L", &fixings)]).unwrap()
+    }
+
+    #[test]
+    fn variance_swap_fix_with_past_observations() {
+        let swap = sample_variance_swap(0.04);
+        let today = Date::from_ymd(2017, 02, 03);
+        let decomp = swap.fix(&past_fixings(&[100.0, 102.0, 99.0], today))
+            .unwrap().unwrap();
+        assert_eq!(decomp.len(), 1);
+        let fixed = &decomp[0].1;
+        assert!(fixed.as_priceable().is_none());
+        assert!(fixed.as_mc_priceable().is_some());
+
+        let value = serde_json::to_value(fixed).unwrap();
+        assert_eq!(value["VarianceSwap"]["last_fixing"], 99.0);
+        assert_eq!(value["VarianceSwap"]["past_returns"], 2);
+        let expected = (1.02f64).ln().powi(2) + (99.0f64 / 102.0).ln().powi(2);
+        let sum_of_squares = value["VarianceSwap"]["past_sum_of_squares"].as_f64().unwrap();
+        assert_approx(sum_of_squares, expected, 1e-15);
+        let remaining = value["VarianceSwap"]["observation_dates"].as_array().unwrap().len();
+        assert_eq!(remaining, observation_dates().len() - 3);
+    }
+
+    #[test]
+    fn variance_swap_fix_when_fully_fixed() {
+
+        // alternate between 100 and 101, so every return is the same size
+        let dates = observation_dates();
+        let values: Vec<f64> = (0..dates.len())
+            .map(|i| if i % 2 == 0 { 100.0 } else { 101.0 }).collect();
+        let swap = sample_variance_swap(0.04);
+        let decomp = swap.fix(&past_fixings(&values, Date::from_ymd(2017, 07, 04)))
+            .unwrap().unwrap();
+        assert_eq!(decomp.len(), 1);
+        let realised = 252.0 * (1.01f64).ln().powi(2);
+        assert_approx(decomp[0].0, 1000.0 * (realised - 0.04), 1e-9);
+        assert_eq!(decomp[0].1.id(), "SampleVarSwap:Expiry");
+    }
+
+    #[test]
+    fn variance_swap_rejects_bad_inputs() {
+        let dates = observation_dates();
+        assert!(VarianceSwap::new("Bad", "OPT", sample_underlying(),
+            sample_settlement(2), &dates[..1], 0.04, 1000.0, 252.0).is_err());
+        let unordered = [dates[1], dates[0]];
+        assert!(VarianceSwap::new("Bad", "OPT", sample_underlying(),
+            sample_settlement(2), &unordered, 0.04, 1000.0, 252.0).is_err());
+        assert!(VarianceSwap::new("Bad", "OPT", sample_underlying(),
+            sample_settlement(2), &dates, 0.04, 1000.0, 0.0).is_err());
+    }
+
+    #[test]
+    fn variance_swap_serde() {
+        let swap = sample_variance_swap(0.04);
+        let serialized = serde_json::to_string(&swap).unwrap();
+        let deserialized: VarianceSwap = serde_json::from_str(&serialized).unwrap();
+        let reserialized = serde_json::to_string(&deserialized).unwrap();
+        assert_eq!(serialized, reserialized);
+    }
+
+    fn assert_approx(value: f64, expected: f64, tolerance: f64) {
+        assert!(approx_eq(value, expected, tolerance),
+            "value={} expected={}", value, expected);
+    }
+}
@@ -15,6 +15,7 @@ extern crate serde_json;
 extern crate lazy_static;
 extern crate void;
 extern crate libc;
+extern crate rayon;
 
 // listed in dependency order, though this is not essential for compilation
 pub mod core;
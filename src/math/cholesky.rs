@@ -0,0 +1,108 @@
+use ndarray::Array2;
+use core::qm;
+
+/// Cholesky decomposition of a symmetric positive semi-definite matrix,
+/// returning the lower triangular root L such that L L' is the matrix.
+///
+/// Unlike the standard decomposition, this copes with singular matrices such
+/// as correlation matrices where two assets are perfectly correlated. Where
+/// a pivot is zero to within the tolerance, the corresponding column of the
+/// root is set to zero, which is only consistent if the rest of the column
+/// is also zero. Otherwise, or if any pivot is significantly negative, the
+/// matrix is not positive semi-definite and an error is returned.
+pub fn cholesky_psd(matrix: &Array2<f64>, tolerance: f64)
+    -> Result<Array2<f64>, qm::Error> {
+
+    let n = matrix.shape()[0];
+    if matrix.shape()[1] != n {
+        return Err(qm::Error::new("Cholesky decomposition requires a square matrix"))
+    }
+
+    let mut root = Array2::<f64>::zeros((n, n));
+    for j in 0..n {
+        let mut pivot = matrix[(j, j)];
+        for k in 0..j {
+            pivot -= root[(j, k)] * root[(j, k)];
+        }
+        if pivot < -tolerance || !pivot.is_finite() {
+            return Err(qm::Error::new(&format!("Matrix is not positive \
+                semi-definite: pivot {} at row {} is negative", pivot, j)))
+        }
+
+        let degenerate = pivot <= tolerance;
+        let diagonal = if degenerate { 0.0 } else { pivot.sqrt() };
+        root[(j, j)] = diagonal;
+
+        for i in (j + 1)..n {
+            let mut value = matrix[(i, j)];
+            for k in 0..j {
+                value -= root[(i, k)] * root[(j, k)];
+            }
+            if degenerate {
+                if value.abs() > tolerance {
+                    return Err(qm::Error::new(&format!("Matrix is not \
+                        positive semi-definite: row {} is inconsistent with \
+                        the zero pivot at row {}", i, j)))
+                }
+            } else {
+                root[(i, j)] = value / diagonal;
+            }
+        }
+    }
+
+    Ok(root)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+
+    #[test]
+    fn positive_definite_two_by_two() {
+        let correl = Array2::from_shape_vec((2, 2), vec![1.0, 0.6, 0.6, 1.0]).unwrap();
+        let root = cholesky_psd(&correl, 1e-12).unwrap();
+        assert_approx(root[(0, 0)], 1.0);
+        assert_approx(root[(0, 1)], 0.0);
+        assert_approx(root[(1, 0)], 0.6);
+        assert_approx(root[(1, 1)], 0.8);
+        assert_reconstructs(&correl, &root);
+    }
+
+    #[test]
+    fn perfectly_correlated_is_accepted() {
+        let correl = Array2::from_shape_vec((3, 3), vec![
+            1.0, 1.0, 0.5,
+            1.0, 1.0, 0.5,
+            0.5, 0.5, 1.0]).unwrap();
+        let root = cholesky_psd(&correl, 1e-12).unwrap();
+        assert_approx(root[(1, 1)], 0.0);
+        assert_reconstructs(&correl, &root);
+    }
+
+    #[test]
+    fn not_positive_semi_definite_is_rejected() {
+        // pairwise correlations that cannot all hold at once
+        let correl = Array2::from_shape_vec((3, 3), vec![
+            1.0, 0.9, -0.9,
+            0.9, 1.0, 0.9,
+            -0.9, 0.9, 1.0]).unwrap();
+        assert!(cholesky_psd(&correl, 1e-12).is_err());
+
+        // a zero pivot with a nonzero entry below it
+        let correl = Array2::from_shape_vec((2, 2), vec![0.0, 0.5, 0.5, 1.0]).unwrap();
+        assert!(cholesky_psd(&correl, 1e-12).is_err());
+    }
+
+    fn assert_reconstructs(matrix: &Array2<f64>, root: &Array2<f64>) {
+        let product = root.dot(&root.t());
+        for (value, expected) in product.iter().zip(matrix.iter()) {
+            assert_approx(*value, *expected);
+        }
+    }
+
+    fn assert_approx(value: f64, expected: f64) {
+        assert!(approx_eq(value, expected, 1e-12),
+            "value={} expected={}", value, expected);
+    }
+}
@@ -244,6 +244,10 @@ impl<T : Interpolable<T> + Copy> Linear<T> {
         validate_abscissae(&points)?;
         Ok(Linear { left: left, right: right, points: points.to_vec() })
     }
+
+    pub fn points(&self) -> &[(T, f64)] { &self.points }
+    pub fn left(&self) -> Extrap { self.left }
+    pub fn right(&self) -> Extrap { self.right }
 }
 
 /// Cubic spline interpolation is continuous up to the second derivative.
@@ -253,6 +257,12 @@ impl<T : Interpolable<T> + Copy> Linear<T> {
 ///
 /// We preprocess the points in the constructor to find the second derivative
 /// at each of the pillar points.
+///
+/// By default, the boundary conditions at the ends depend on the
+/// extrapolation: natural (zero second derivative) if the extrapolation is
+/// natural, otherwise zero first derivative, to join smoothly onto flat
+/// extrapolation. A natural spline has zero second derivatives at both ends,
+/// whatever the extrapolation.
 #[derive(Debug, Clone)]
 pub struct CubicSpline<T> where T : Interpolable<T> {
     inputs: CubicSplineInputs<T>,
@@ -270,7 +280,25 @@ impl<T> Serialize for CubicSpline<T> where T : Interpolable<T> + Serialize {
 struct CubicSplineInputs<T> where T : Interpolable<T> {
     left: Extrap,
     right: Extrap,
-    points: Vec<(T, f64)>
+    points: Vec<(T, f64)>,
+    #[serde(default, skip_serializing_if = "is_false")]
+    natural: bool
+}
+
+fn is_false(value: &bool) -> bool { !*value }
+
+impl<T : Interpolable<T> + Copy> CubicSplineInputs<T> {
+    /// Calculates the second derivatives at each of the pillars
+    fn second_derivatives(&self) -> Vec<f64> {
+        let mut second_deriv = vec![0.0; self.points.len()];
+        let natural_0 = self.natural || self.left.is_natural();
+        let natural_n = self.natural || self.right.is_natural();
+        let deriv_0 = if natural_0 { INFINITY } else { 0.0 };
+        let deriv_n = if natural_n { INFINITY } else { 0.0 };
+
+        nr_spline(&self.points, deriv_0, deriv_n, &mut second_deriv);
+        second_deriv
+    }
 }
 
 impl<T : Interpolable<T> + Copy> Interpolate<T> for CubicSpline<T> {
@@ -318,21 +346,68 @@ impl<T : Interpolable<T> + Copy> CubicSpline<T> {
     /// left and right, plus the points to interpolate.
     pub fn new(points: &[(T, f64)], left: Extrap, right: Extrap)
         -> Result<CubicSpline<T>, qm::Error> {
+        CubicSpline::with_boundaries(points, left, right, false)
+    }
 
-        validate_abscissae(&points)?;
+    /// Construct a natural cubic spline, with zero second derivative at
+    /// both ends, and the given rules for extrapolation.
+    pub fn natural(points: &[(T, f64)], left: Extrap, right: Extrap)
+        -> Result<CubicSpline<T>, qm::Error> {
+        CubicSpline::with_boundaries(points, left, right, true)
+    }
 
-        let mut second_deriv = vec![0.0; points.len()];
-        let deriv_0 = if left.is_natural() { INFINITY } else { 0.0 };
-        let deriv_n = if right.is_natural() { INFINITY } else { 0.0 };
+    fn with_boundaries(points: &[(T, f64)], left: Extrap, right: Extrap,
+        natural: bool) -> Result<CubicSpline<T>, qm::Error> {
+
+        validate_abscissae(&points)?;
+        if points.len() < 2 {
+            return Err(qm::Error::new("Cubic spline interpolator requires \
+                at least 2 points"))
+        }
 
-        nr_spline(points, deriv_0, deriv_n, &mut second_deriv);
+        let inputs = CubicSplineInputs {
+            left: left,
+            right: right,
+            points: points.to_vec(),
+            natural: natural };
+        let second_deriv = inputs.second_derivatives();
+        Ok(CubicSpline { inputs: inputs, second_deriv: second_deriv })
+    }
+
+    /// The first and last pillar abscissae, between which the curve is
+    /// interpolated rather than extrapolated.
+    pub fn bounds(&self) -> (T, T) {
+        let points = &self.inputs.points;
+        (points[0].0, points[points.len() - 1].0)
+    }
+
+    /// Returns the first derivative of the interpolated curve, which is
+    /// continuous everywhere within the pillars. Outside the pillars, the
+    /// derivative follows the extrapolation, so it is zero for flat
+    /// extrapolation.
+    pub fn derivative(&self, x: T) -> Result<f64, qm::Error> {
+
+        let points = &self.inputs.points;
+        let n = points.len();
+        let found = points.binary_search_by(|p| p.0.interp_cmp(x));
+        let segment = match found {
+            // at a pillar, either neighbouring segment gives the same result
+            Ok(i) => if i + 1 < n { i } else { i - 1 },
+            Err(0) => if self.inputs.left.is_natural() {
+                0
+            } else {
+                return self.inputs.left.extrapolate(0.0)
+            },
+            Err(i) if i >= n => if self.inputs.right.is_natural() {
+                n - 2
+            } else {
+                return self.inputs.right.extrapolate(0.0)
+            },
+            Err(i) => i - 1
+        };
 
-        Ok(CubicSpline {
-            inputs: CubicSplineInputs {
-                left: left,
-                right: right,
-                points: points.to_vec() },
-            second_deriv: second_deriv })
+        nr_splint_derivative(points[segment], points[segment + 1],
+            self.second_deriv[segment], self.second_deriv[segment + 1], x)
     }
 }
 
@@ -346,13 +421,7 @@ where T : Interpolable<T> + Deserialize<'de> + Copy {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where D: Deserializer<'de> {
         let inputs = CubicSplineInputs::deserialize(deserializer)?;
-
-        let mut second_deriv = vec![0.0; inputs.points.len()];
-        let deriv_0 = if inputs.left.is_natural() { INFINITY } else { 0.0 };
-        let deriv_n = if inputs.right.is_natural() { INFINITY } else { 0.0 };
-
-        nr_spline(&inputs.points, deriv_0, deriv_n, &mut second_deriv);
-
+        let second_deriv = inputs.second_derivatives();
         Ok(CubicSpline { inputs, second_deriv })
     }
 }
@@ -430,6 +499,24 @@ fn nr_splint<T : Interpolable<T> + Copy>(
     Ok(y)
 }
 
+/// The first derivative of the cubic calculated by nr_splint. This is also
+/// adapted from Numerical Recipes in C.
+fn nr_splint_derivative<T : Interpolable<T> + Copy>(
+    lo: (T, f64), hi: (T, f64), y2_lo: f64, y2_hi: f64, x: T)
+    -> Result<f64, qm::Error> {
+
+    let h = lo.0.interp_diff(hi.0);
+    if h == 0.0 {
+        return Err(qm::Error::new("Bad input to cubic spline interpolator"))
+    }
+    let a = x.interp_diff(hi.0) / h;
+    let b = lo.0.interp_diff(x) / h;
+    let dy = (hi.1 - lo.1) / h
+        - (3.0 * a * a - 1.0) * h * y2_lo / 6.0
+        + (3.0 * b * b - 1.0) * h * y2_hi / 6.0;
+    Ok(dy)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -540,6 +627,62 @@ mod tests {
         assert_2nd_diff(&cs, 7.0, cs.second_deriv[4], 0.0);
     }
 
+    #[test]
+    fn natural_cubic_spline_with_flat_extrapolation() {
+
+        // Same points as above. The natural spline matches the one with
+        // natural extrapolation inside the pillars, but extrapolates flat.
+        let points = [(0.0, 0.0), (2.0, 3.0), (4.0, 8.0), (6.0, 9.0),
+            (7.0, 10.0)];
+        let cs = CubicSpline::<f64>::natural(&points,
+            Extrap::Flat, Extrap::Flat).unwrap();
+
+        assert_match(cs.interpolate(-1.0), 0.0);
+        assert_match(cs.interpolate(1.0), 1.1798780487804879);
+        assert_match(cs.interpolate(5.0), 8.728658536585366);
+        assert_match(cs.interpolate(8.0), 10.0);
+        assert_match(cs.derivative(-1.0), 0.0);
+        assert_match(cs.derivative(8.0), 0.0);
+
+        // the natural flag survives a round trip
+        let serialized = serde_json::to_string(&cs).unwrap();
+        let deserialized: CubicSpline<f64> = serde_json::from_str(&serialized).unwrap();
+        assert_match(deserialized.interpolate(5.0), 8.728658536585366);
+    }
+
+    #[test]
+    fn cubic_spline_first_derivative() {
+        let points = [(0.0, 0.0), (2.0, 3.0), (4.0, 8.0), (6.0, 9.0),
+            (7.0, 10.0)];
+        let cs = CubicSpline::<f64>::new(&points,
+            Extrap::Natural, Extrap::Natural).unwrap();
+
+        // matches a finite difference, inside and outside the pillars
+        let epsilon = 1e-6;
+        for &x in [-1.0, 0.5, 1.0, 3.0, 5.5, 6.5, 8.0].iter() {
+            let up = cs.interpolate(x + epsilon).unwrap();
+            let down = cs.interpolate(x - epsilon).unwrap();
+            let fd = (up - down) / (2.0 * epsilon);
+            let deriv = cs.derivative(x).unwrap();
+            assert!(approx_eq(deriv, fd, 1e-6), "x={} deriv={} fd={}", x, deriv, fd);
+        }
+
+        // continuous across the pillars
+        for &(x, _) in points.iter() {
+            let left = cs.derivative(x - 1e-9).unwrap();
+            let right = cs.derivative(x + 1e-9).unwrap();
+            let at = cs.derivative(x).unwrap();
+            assert!(approx_eq(left, at, 1e-6) && approx_eq(right, at, 1e-6),
+                "x={} left={} at={} right={}", x, left, at, right);
+        }
+    }
+
+    #[test]
+    fn cubic_spline_needs_two_points() {
+        assert!(CubicSpline::<f64>::new(&[(1.0, 2.0)],
+            Extrap::Flat, Extrap::Flat).is_err());
+    }
+
     fn assert_match(result: Result<f64, qm::Error>, expected: f64) {
         let v = result.unwrap();
         assert!(approx_eq(v, expected, 1e-12),
@@ -1,4 +1,7 @@
 pub mod brent;
+pub mod cholesky;
 pub mod interpolation;
+pub mod moments;
 pub mod numerics;
 pub mod optionpricing;
+pub mod regression;
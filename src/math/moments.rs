@@ -0,0 +1,154 @@
+/// Accumulates the mean and variance of a stream of samples in constant
+/// memory, using Welford's algorithm. This avoids the cancellation error
+/// of summing the values and their squares, which matters for the very
+/// large sample counts seen in Monte-Carlo.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RunningMoments {
+    count: usize,
+    mean: f64,
+    m2: f64
+}
+
+impl RunningMoments {
+    pub fn new() -> RunningMoments {
+        RunningMoments::default()
+    }
+
+    /// Adds a single sample
+    pub fn add(&mut self, value: f64) {
+        self.count += 1;
+        let delta = value - self.mean;
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (value - self.mean);
+    }
+
+    /// The number of samples added so far
+    pub fn count(&self) -> usize { self.count }
+
+    /// The mean of the samples, or zero if there are none
+    pub fn mean(&self) -> f64 { self.mean }
+
+    /// The unbiased sample variance, or zero if there are fewer than two
+    /// samples
+    pub fn variance(&self) -> f64 {
+        if self.count < 2 {
+            0.0
+        } else {
+            self.m2 / (self.count - 1) as f64
+        }
+    }
+
+    /// The standard error of the mean, or zero if there are fewer than two
+    /// samples
+    pub fn stderr(&self) -> f64 {
+        if self.count < 2 {
+            0.0
+        } else {
+            (self.variance() / self.count as f64).sqrt()
+        }
+    }
+
+    /// Adds all the samples that went into the other moments, as if they
+    /// had been added one by one. See Chan, Golub and LeVeque (1979).
+    pub fn merge(&mut self, other: &RunningMoments) {
+        if other.count == 0 {
+            return
+        }
+        let count = self.count + other.count;
+        let delta = other.mean - self.mean;
+        let weight = other.count as f64 / count as f64;
+        self.mean += delta * weight;
+        self.m2 += other.m2 + delta * delta * self.count as f64 * weight;
+        self.count = count;
+    }
+
+    /// The same moments for samples that are all shifted by the given
+    /// amount. Only the mean changes.
+    pub fn shifted(&self, offset: f64) -> RunningMoments {
+        RunningMoments { count: self.count, mean: self.mean + offset, m2: self.m2 }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+
+    #[test]
+    fn running_moments_match_naive_sums() {
+
+        // a million samples with a large mean relative to their spread,
+        // which is where naive sums of squares lose accuracy
+        let n = 1000000;
+        let mut moments = RunningMoments::new();
+        let mut seed: u64 = 12345;
+        let mut values = Vec::with_capacity(n);
+        for _ in 0..n {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            let uniform = (seed >> 11) as f64 / (1u64 << 53) as f64;
+            let value = 1000.0 + uniform;
+            moments.add(value);
+            values.push(value);
+        }
+        assert_eq!(moments.count(), n);
+
+        let mean = values.iter().sum::<f64>() / n as f64;
+        assert_approx(moments.mean(), mean, 1e-10);
+
+        // uniform samples have a variance of one twelfth
+        let variance = values.iter().map(|x| (x - mean) * (x - mean))
+            .sum::<f64>() / (n - 1) as f64;
+        assert_approx(moments.variance(), variance, 1e-10);
+        assert_approx(moments.variance(), 1.0 / 12.0, 1e-3);
+        assert_approx(moments.stderr(), (variance / n as f64).sqrt(), 1e-12);
+
+        // shifting changes only the mean
+        let shifted = moments.shifted(-1000.0);
+        assert_approx(shifted.mean(), mean - 1000.0, 1e-10);
+        assert_eq!(shifted.variance(), moments.variance());
+    }
+
+    #[test]
+    fn merged_moments_match_adding_every_sample() {
+        let values = [3.0, 5.0, 4.5, -1.0, 10.0, 7.25, 2.0];
+        let mut all = RunningMoments::new();
+        for value in values.iter() {
+            all.add(*value);
+        }
+
+        for split in 0..values.len() + 1 {
+            let mut first = RunningMoments::new();
+            let mut second = RunningMoments::new();
+            for value in values[..split].iter() {
+                first.add(*value);
+            }
+            for value in values[split..].iter() {
+                second.add(*value);
+            }
+            first.merge(&second);
+            assert_eq!(first.count(), all.count());
+            assert_approx(first.mean(), all.mean(), 1e-12);
+            assert_approx(first.variance(), all.variance(), 1e-12);
+        }
+    }
+
+    #[test]
+    fn running_moments_with_few_samples() {
+        let mut moments = RunningMoments::new();
+        assert_eq!(moments.mean(), 0.0);
+        assert_eq!(moments.stderr(), 0.0);
+
+        moments.add(3.0);
+        assert_eq!(moments.mean(), 3.0);
+        assert_eq!(moments.variance(), 0.0);
+
+        moments.add(5.0);
+        assert_eq!(moments.mean(), 4.0);
+        assert_eq!(moments.variance(), 2.0);
+    }
+
+    fn assert_approx(value: f64, expected: f64, tolerance: f64) {
+        assert!(approx_eq(value, expected, tolerance),
+            "value={} expected={}", value, expected);
+    }
+}
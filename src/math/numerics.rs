@@ -1,5 +1,6 @@
 
 use std::fmt;
+use core::qm;
 
 /// Compares two floating point numbers for equality, with margin for error
 pub fn approx_eq(first: f64, second: f64, tolerance: f64) -> bool {
@@ -7,6 +8,20 @@ pub fn approx_eq(first: f64, second: f64, tolerance: f64) -> bool {
     diff.abs() < tolerance
 }
 
+/// Returns the value if it is finite. Otherwise, returns an error naming the
+/// stage of the calculation and describing the input that produced it, so a
+/// bad input such as a NaN vol gives an error saying where it went wrong,
+/// rather than a NaN price. The description is only built on error.
+pub fn check_finite<F>(value: f64, stage: &str, input: F) -> Result<f64, qm::Error>
+    where F: FnOnce() -> String {
+
+    if value.is_finite() {
+        Ok(value)
+    } else {
+        Err(qm::Error::new(&format!("Non-finite {} {} for {}", stage, value, input())))
+    }
+}
+
 /// Are two objects approximately equal? The two objects could be floating
 /// point numbers, or risk reports. Returns true if the objects are sufficiently
 /// close or false if they are not. The meaning of the tolerance parameters means
@@ -1,6 +1,16 @@
 use statrs::distribution::Normal;
 use statrs::distribution::Univariate;
 use core::qm;
+use std::f64::consts::PI;
+use std::f64::EPSILON;
+
+/// The relative accuracy in price, as a fraction of the discounted forward,
+/// to which implied vols are solved
+const IMPLIED_VOL_TOLERANCE: f64 = 1e-13;
+
+/// The maximum number of Newton or bisection steps used to solve for an
+/// implied vol
+const IMPLIED_VOL_MAX_ITER: u32 = 200;
 
 /// The 1976 reformulation of the Black-Scholes formula, where the price of
 /// a European option is expressed in terms of the Forward and the Strike.
@@ -42,6 +52,207 @@ impl Black76 {
     pub fn cdf(&self, x: f64) -> f64 {
         self.normal.cdf(x)
     }
+
+    /// Calculates the sensitivities of a European call option under Black
+    /// Scholes, in closed form. At expiry or with zero vol, the option is
+    /// worth its discounted intrinsic value, so the delta is a step at the
+    /// strike, with one half exactly at it, and the gamma is zero.
+    pub fn call_greeks(&self, df: f64, forward: f64, strike: f64,
+        sqrt_variance: f64) -> Black76Greeks {
+
+        if !(sqrt_variance > 0.0) {
+            let delta = if forward > strike {
+                df
+            } else if forward < strike {
+                0.0
+            } else {
+                0.5 * df
+            };
+            let vega = if forward == strike {
+                df * forward / (2.0 * PI).sqrt()
+            } else {
+                0.0
+            };
+            return Black76Greeks { delta: delta, gamma: 0.0, vega: vega }
+        }
+
+        let log_moneyness = (forward / strike).ln();
+        let (d_plus, _) = d_plus_minus(log_moneyness, sqrt_variance);
+        let density = (-0.5 * d_plus * d_plus).exp() / (2.0 * PI).sqrt();
+        Black76Greeks {
+            delta: df * self.cdf(d_plus),
+            gamma: df * density / (forward * sqrt_variance),
+            vega: df * forward * density }
+    }
+
+    /// Calculates the sensitivities of a European put option under Black
+    /// Scholes, in closed form. These follow from those of the call by
+    /// put/call parity.
+    pub fn put_greeks(&self, df: f64, forward: f64, strike: f64,
+        sqrt_variance: f64) -> Black76Greeks {
+
+        let call = self.call_greeks(df, forward, strike, sqrt_variance);
+        Black76Greeks { delta: call.delta - df, ..call }
+    }
+
+    /// Finds the volatility that gives a European call option the target
+    /// PV under Black Scholes. The expiry is the time to expiry in years,
+    /// measured in the same way as for the vol surface. (Put prices can be
+    /// converted to call prices first, using put/call parity.)
+    ///
+    /// The solver is Newton-Raphson, starting from the Brenner-Subrahmanyam
+    /// approximation. We keep track of a range that brackets the solution,
+    /// and fall back to bisection whenever a Newton step would leave it, as
+    /// happens far from the money where the vega is tiny.
+    pub fn implied_vol(&self, df: f64, forward: f64, strike: f64, expiry: f64,
+        price: f64) -> Result<f64, qm::Error> {
+
+        if !(expiry > 0.0) {
+            return Err(qm::Error::new("Implied vol requires a positive time to expiry"))
+        }
+        if !(df > 0.0 && forward > 0.0 && strike > 0.0) {
+            return Err(qm::Error::new(
+                "Implied vol requires a positive discount factor, forward and strike"))
+        }
+
+        // The price of a call lies between intrinsic value, at zero vol, and
+        // the discounted forward, at infinite vol.
+        let intrinsic = df * (forward - strike).max(0.0);
+        let upper_bound = df * forward;
+        if price < intrinsic {
+            return Err(qm::Error::new(&format!("Target price {} is below \
+                the intrinsic value {}, so there is no implied vol",
+                price, intrinsic)))
+        }
+        if price >= upper_bound {
+            return Err(qm::Error::new(&format!("Target price {} is not below \
+                the discounted forward {}, so there is no implied vol",
+                price, upper_bound)))
+        }
+        if price == intrinsic {
+            return Ok(0.0)
+        }
+
+        // We work in terms of the square root of the variance. Find an upper
+        // limit by doubling, which must terminate as the price is below the
+        // discounted forward.
+        let mut lower = 0.0;
+        let mut upper = 1.0;
+        let mut iter = 0;
+        while self.call_price(df, forward, strike, upper) < price {
+            lower = upper;
+            upper *= 2.0;
+            iter += 1;
+            if iter > IMPLIED_VOL_MAX_ITER {
+                return Err(qm::Error::new(&format!("Target price {} is too \
+                    close to the discounted forward {} to find an implied vol",
+                    price, upper_bound)))
+            }
+        }
+
+        // Brenner-Subrahmanyam is exact to first order at the money
+        let mut sqrt_variance = (2.0 * PI).sqrt() * price / upper_bound;
+        if !(sqrt_variance > lower && sqrt_variance < upper) {
+            sqrt_variance = 0.5 * (lower + upper);
+        }
+
+        let tolerance = IMPLIED_VOL_TOLERANCE * upper_bound;
+        let log_moneyness = (forward / strike).ln();
+        for _ in 0..IMPLIED_VOL_MAX_ITER {
+            let diff = self.call_price(df, forward, strike, sqrt_variance) - price;
+            if diff.abs() <= tolerance || upper - lower <= EPSILON * upper {
+                return Ok(sqrt_variance / expiry.sqrt())
+            }
+
+            // narrow the bracket, as the price increases with vol
+            if diff > 0.0 {
+                upper = sqrt_variance;
+            } else {
+                lower = sqrt_variance;
+            }
+
+            // take a Newton step if it stays within the bracket, otherwise
+            // bisect
+            let (d_plus, _) = d_plus_minus(log_moneyness, sqrt_variance);
+            let vega = upper_bound * (-0.5 * d_plus * d_plus).exp() / (2.0 * PI).sqrt();
+            let next = sqrt_variance - diff / vega;
+            if next > lower && next < upper {
+
+                // rounding in the price may stop us reaching the tolerance,
+                // so also stop if the step is negligible
+                if (next - sqrt_variance).abs() <= 4.0 * EPSILON * sqrt_variance {
+                    return Ok(next / expiry.sqrt())
+                }
+                sqrt_variance = next;
+            } else {
+                sqrt_variance = 0.5 * (lower + upper);
+            }
+        }
+
+        Err(qm::Error::new(&format!("Implied vol failed to converge for \
+            target price {}", price)))
+    }
+}
+
+/// The sensitivities of a Black76 price to its inputs
+#[derive(Clone, Copy, Debug)]
+pub struct Black76Greeks {
+    delta: f64,
+    gamma: f64,
+    vega: f64
+}
+
+impl Black76Greeks {
+    /// The first derivative of the price with respect to the forward
+    pub fn delta(&self) -> f64 { self.delta }
+
+    /// The second derivative of the price with respect to the forward
+    pub fn gamma(&self) -> f64 { self.gamma }
+
+    /// The derivative of the price with respect to the square root of the
+    /// variance, that is the vol times the square root of the time
+    pub fn vega(&self) -> f64 { self.vega }
+}
+
+/// The Bachelier formula, where the underlying is normally rather than
+/// lognormally distributed at expiry. It is used for rates, where forwards
+/// and strikes may be zero or negative. The sqrt_variance is the normal
+/// vol, in the same units as the forward, times the square root of the
+/// time to expiry.
+pub struct Bachelier {
+    normal: Normal
+}
+
+impl Bachelier {
+    pub fn new() -> Result<Bachelier, qm::Error> {
+        match Normal::new(0.0, 1.0) {
+            Ok(normal) => Ok(Bachelier { normal: normal }),
+            Err(e) => Err(qm::Error::new(&format!("RSStat error: {}", e)))
+        }
+    }
+
+    /// Calculates the PV of a European call option under normal dynamics.
+    /// With zero variance, this is the discounted intrinsic value.
+    pub fn call_price(&self, df: f64, forward: f64, strike: f64,
+        sqrt_variance: f64) -> f64 {
+
+        if !(sqrt_variance > 0.0) {
+            return df * (forward - strike).max(0.0)
+        }
+
+        let d = (forward - strike) / sqrt_variance;
+        let density = (-0.5 * d * d).exp() / (2.0 * PI).sqrt();
+        df * ((forward - strike) * self.normal.cdf(d) + sqrt_variance * density)
+    }
+
+    /// Calculates the PV of a European put option under normal dynamics,
+    /// using put/call parity
+    pub fn put_price(&self, df: f64, forward: f64, strike: f64,
+        sqrt_variance: f64) -> f64 {
+
+        self.call_price(df, forward, strike, sqrt_variance)
+            - df * (forward - strike)
+    }
 }
 
 /// Calculates the internal d_plus and d_minus values needed for many of the
@@ -94,6 +305,118 @@ mod tests {
         }
     }
 
+    #[test]
+    fn bachelier_price() {
+
+        // negative forwards and strikes are fine under normal dynamics
+        let df = 0.99;
+        let sqrt_var = 0.01;
+        let bachelier = Bachelier::new().unwrap();
+        for &forward in [-0.005, 0.0, 0.02].iter() {
+            for &strike in [-0.01, -0.005, 0.0, 0.01].iter() {
+                let call_price = bachelier.call_price(df, forward, strike, sqrt_var);
+                let put_price = bachelier.put_price(df, forward, strike, sqrt_var);
+                assert!(call_price >= df * (forward - strike).max(0.0));
+                assert!(put_price >= df * (strike - forward).max(0.0));
+            }
+
+            // at the money, the option is worth the discounted mean
+            // absolute deviation of the forward, halved
+            let atm = bachelier.call_price(df, forward, forward, sqrt_var);
+            assert_approx(atm, df * sqrt_var / (2.0 * PI).sqrt(), 1e-15, "atm");
+        }
+    }
+
+    #[test]
+    fn black76_implied_vol_round_trip() {
+
+        let forward = 100.0;
+        let df = 0.99;
+        let expiry = 2.0;
+        let black76 = Black76::new().unwrap();
+
+        for vol in [0.01, 0.2, 0.5, 1.5].iter() {
+            let sqrt_var = *vol * expiry.sqrt();
+            for strike in [50.0, 70.0, 90.0, 100.0, 110.0, 130.0, 160.0].iter() {
+                let price = black76.call_price(df, forward, *strike, sqrt_var);
+
+                // Far from the money the price is almost all intrinsic, and
+                // the vol is not well determined, so skip those
+                if price - df * (forward - *strike).max(0.0) < 1e-4 {
+                    continue;
+                }
+
+                let implied = black76.implied_vol(df, forward, *strike, expiry, price).unwrap();
+                assert_approx(implied, *vol, 1e-8, "implied vol");
+            }
+        }
+    }
+
+    #[test]
+    fn black76_implied_vol_errors_outside_bounds() {
+
+        let forward = 100.0;
+        let df = 0.99;
+        let black76 = Black76::new().unwrap();
+
+        // below intrinsic value
+        let err = black76.implied_vol(df, forward, 90.0, 1.0, 9.0).unwrap_err();
+        assert!(format!("{}", err).contains("below the intrinsic value"),
+            "unexpected error: {}", err);
+
+        // at or above the discounted forward
+        assert!(black76.implied_vol(df, forward, 90.0, 1.0, 99.0).is_err());
+        assert!(black76.implied_vol(df, forward, 90.0, 1.0, 120.0).is_err());
+
+        // exactly intrinsic value means zero vol
+        let intrinsic = df * (forward - 90.0);
+        let zero = black76.implied_vol(df, forward, 90.0, 1.0, intrinsic).unwrap();
+        assert_eq!(zero, 0.0);
+    }
+
+    #[test]
+    fn black76_greeks_match_finite_differences() {
+
+        let forward = 100.0;
+        let df = 0.99;
+        let sqrt_var = 0.3;
+        let h = 1e-4;
+        let black76 = Black76::new().unwrap();
+
+        for strike in [70.0, 90.0, 100.0, 110.0, 130.0].iter() {
+            let call = |f: f64, v: f64| black76.call_price(df, f, *strike, v);
+            let put = |f: f64, v: f64| black76.put_price(df, f, *strike, v);
+            for &(greeks, price) in [
+                (black76.call_greeks(df, forward, *strike, sqrt_var), &call as &Fn(f64, f64) -> f64),
+                (black76.put_greeks(df, forward, *strike, sqrt_var), &put as &Fn(f64, f64) -> f64)].iter() {
+
+                let up = price(forward + h, sqrt_var);
+                let down = price(forward - h, sqrt_var);
+                let mid = price(forward, sqrt_var);
+                assert_approx(greeks.delta(), (up - down) / (2.0 * h), 1e-8, "delta");
+                assert_approx(greeks.gamma(), (up + down - 2.0 * mid) / (h * h), 1e-5, "gamma");
+                let vega = (price(forward, sqrt_var + h) - price(forward, sqrt_var - h)) / (2.0 * h);
+                assert_approx(greeks.vega(), vega, 1e-6, "vega");
+            }
+        }
+    }
+
+    #[test]
+    fn black76_greeks_at_expiry() {
+
+        let df = 0.99;
+        let black76 = Black76::new().unwrap();
+        let itm = black76.call_greeks(df, 100.0, 90.0, 0.0);
+        assert_eq!(itm.delta(), df);
+        assert_eq!(itm.gamma(), 0.0);
+        assert_eq!(itm.vega(), 0.0);
+        let otm = black76.put_greeks(df, 100.0, 90.0, 0.0);
+        assert_eq!(otm.delta(), 0.0);
+        let atm = black76.call_greeks(df, 100.0, 100.0, 0.0);
+        assert_eq!(atm.delta(), 0.5 * df);
+        assert!(atm.vega().is_finite() && atm.vega() > 0.0);
+    }
+
     fn assert_approx(value: f64, expected: f64, tolerance: f64, message: &str) {
         assert!(approx_eq(value, expected, tolerance),
             "{}: value={} expected={}", message, value, expected);
@@ -0,0 +1,79 @@
+use nalgebra::base::DMatrix;
+use nalgebra::base::DVector;
+use nalgebra::linalg::Cholesky;
+
+/// Least-squares fit of a polynomial of the given degree to the points
+/// (x, y). Returns the coefficients, starting with the constant term, or
+/// None if there are too few points or the problem is too badly
+/// conditioned to solve.
+///
+/// The fit is done by solving the normal equations with a Cholesky
+/// decomposition. This is fast, but squares the condition number of the
+/// problem, so it is best to scale the x values to be of order one.
+pub fn polynomial_fit(x: &[f64], y: &[f64], degree: usize) -> Option<Vec<f64>> {
+    assert_eq!(x.len(), y.len());
+    let n_basis = degree + 1;
+    if x.len() < n_basis {
+        return None
+    }
+
+    // accumulate the normal equations A'A beta = A'y, where A is the
+    // Vandermonde matrix of the x values
+    let mut ata = DMatrix::<f64>::zeros(n_basis, n_basis);
+    let mut aty = DVector::<f64>::zeros(n_basis);
+    let mut powers = vec![0.0; n_basis];
+    for (xi, yi) in x.iter().zip(y.iter()) {
+        let mut power = 1.0;
+        for p in powers.iter_mut() {
+            *p = power;
+            power *= xi;
+        }
+        for i in 0..n_basis {
+            aty[i] += powers[i] * yi;
+            for j in 0..n_basis {
+                ata[(i, j)] += powers[i] * powers[j];
+            }
+        }
+    }
+
+    let cholesky = Cholesky::new(ata)?;
+    let beta = cholesky.solve(&aty);
+    if beta.iter().any(|b| !b.is_finite()) {
+        return None
+    }
+    Some(beta.iter().cloned().collect())
+}
+
+/// Evaluates a polynomial, given its coefficients starting with the
+/// constant term.
+pub fn polynomial_value(coefficients: &[f64], x: f64) -> f64 {
+    coefficients.iter().rev().fold(0.0, |acc, c| acc * x + c)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+
+    #[test]
+    fn fit_exact_quadratic() {
+        let x = [0.5, 0.8, 1.0, 1.2, 1.5];
+        let y: Vec<f64> = x.iter().map(|x| 2.0 - 3.0 * x + 0.5 * x * x).collect();
+        let beta = polynomial_fit(&x, &y, 2).unwrap();
+        assert!(approx_eq(beta[0], 2.0, 1e-9), "beta={:?}", beta);
+        assert!(approx_eq(beta[1], -3.0, 1e-9), "beta={:?}", beta);
+        assert!(approx_eq(beta[2], 0.5, 1e-9), "beta={:?}", beta);
+        assert!(approx_eq(polynomial_value(&beta, 2.0), -2.0, 1e-9));
+    }
+
+    #[test]
+    fn fit_too_few_points() {
+        assert!(polynomial_fit(&[1.0, 2.0], &[1.0, 2.0], 2).is_none());
+    }
+
+    #[test]
+    fn fit_degenerate_points() {
+        // all the x values the same, so no unique fit exists
+        assert!(polynomial_fit(&[1.0, 1.0, 1.0], &[1.0, 2.0, 3.0], 2).is_none());
+    }
+}
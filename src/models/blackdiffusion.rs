@@ -1,21 +1,20 @@
 use std::any::Any;
+use std::cell::Cell;
 use std::collections::HashMap;
 use std::ops::Deref;
 use std::sync::Arc;
-use rand;
-use rand::StdRng;
-use nalgebra::linalg::Cholesky;
-use nalgebra::base::DMatrix;
-use statrs::distribution::Distribution;
-use statrs::distribution::Normal;
-use ndarray::Array;
 use ndarray::Array1;
 use ndarray::Array2;
 use ndarray::Array3;
 use ndarray::ArrayView2;
+use ndarray::ArrayView3;
 use ndarray::ArrayViewMut2;
+use ndarray::ArrayViewMut3;
 use ndarray::Axis;
+use ndarray::stack;
 use core::qm;
+use math::cholesky::cholesky_psd;
+use math::numerics::check_finite;
 use instruments::Instrument;
 use instruments::MonteCarloContext;
 use instruments::PricingContext;
@@ -23,12 +22,29 @@ use instruments::RcInstrument;
 use risk::BumpablePricingContext;
 use risk::Bumpable;
 use risk::Saveable;
+use risk::CompositeSaveable;
 use risk::marketdata::MarketData;
 use risk::dependencies::DependencyCollector;
 use data::bump::Bump;
 use models::MonteCarloModel;
 use models::MonteCarloTimeline;
 use models::MonteCarloModelFactory;
+use models::VarianceReduction;
+use models::ConvergenceTarget;
+use models::StochasticRates;
+use models::DiscretizationScheme;
+use models::PathAccumulator;
+use math::moments::RunningMoments;
+use models::ProgressCallback;
+use models::progress_batch_size;
+use models::random::RandomSource;
+use models::random::RandomSourceType;
+use models::random::RngAlgorithm;
+use models::random::stratify_terminal;
+use models::random::entropy_seed;
+use rayon;
+use rayon::prelude::*;
+use dates::Date;
 use dates::datetime::DateDayFraction;
 use dates::datetime::DateTime;
 use dates::datetime::TimeOfDay;
@@ -40,23 +56,200 @@ use erased_serde as esd;
 /// The BlackDiffusionFactory is able to create a BlackDiffusion model, given
 /// the timeline of the product(s) to value, and the market data to value it
 /// with. The factory itself just needs the parameters of the BlackDiffusion
-/// itself, and there are only two: the time-stepping to use when converting
-/// local correlations from the market data to the integrated correlations
-/// needed by the model, and the number of paths.
+/// itself: the time-stepping to use when converting local correlations from
+/// the market data to the integrated correlations needed by the model, the
+/// number of paths, and any variance reduction technique to apply.
+///
+/// Optionally, the factory can also be told how many threads to use when
+/// generating the random numbers for the paths. They are generated with a
+/// rayon thread pool of that size. This does not affect the results, only
+/// the wall-clock time. It can also be told to use a Sobol
+/// sequence rather than pseudo-random numbers, and which scheme to use when
+/// stepping the paths. Rates are deterministic unless the factory is given
+/// a Hull-White short rate to diffuse with the underlyings. Simulated spots
+/// are not floored unless the factory is given a spot floor.
+///
+/// If a seed is supplied, the pseudo-random numbers are seeded with it, so a
+/// given set of inputs always gives exactly the same price. Otherwise the
+/// seed is drawn from the operating system's entropy, so prices vary from run
+/// to run. It is drawn once, when the factory is created or deserialized, so
+/// all the models the factory builds, for example for time bumps, share the
+/// same paths.
+///
+/// If a convergence target is set, the number of paths is the size of each
+/// batch, and the pricer adds batches until the target is met.
+///
+/// The two substep parameters control the time-stepping. See new for their
+/// definitions, and recommended_substeps for values to start from.
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct BlackDiffusionFactory {
-    /// Substep size in business days for correlation calculation
     correlation_substep: usize,
     path_substep: f64,
-    number_of_paths: usize
+    number_of_paths: usize,
+    #[serde(default)]
+    variance_reduction: VarianceReduction,
+    #[serde(default)]
+    random_source: RandomSourceType,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    seed: Option<u64>,
+    #[serde(default)]
+    rng_algorithm: RngAlgorithm,
+    #[serde(default)]
+    discretization: DiscretizationScheme,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    spot_floor: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    convergence: Option<ConvergenceTarget>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    stochastic_rates: Option<StochasticRates>,
+    #[serde(default = "default_threads")]
+    threads: usize,
+    #[serde(skip, default = "entropy_seed")]
+    default_seed: u64,
+    #[serde(skip)]
+    progress: Option<ProgressCallback>
 }
 
+pub fn default_threads() -> usize { 1 }
+
 impl BlackDiffusionFactory {
+    /// Creates a factory for BlackDiffusion models.
+    ///
+    /// The correlation_substep is the length in days of the steps taken
+    /// when integrating the local correlations in the market data into the
+    /// correlation over each step of the paths. It must be at least one. At
+    /// present the correlations are constant in time, so it does not affect
+    /// the price.
+    ///
+    /// The path_substep is the largest forward variance, that is the square
+    /// of the vol times the time in years, that the paths may cover in a
+    /// single step. Any step between observations with more variance than
+    /// this, for any asset, is split into equal substeps. It must be
+    /// positive, and smaller values reduce the discretization bias at the
+    /// cost of time.
+    ///
+    /// The number_of_paths is the number of paths, or the size of each
+    /// batch if there is a convergence target. If a seed is supplied, it
+    /// selects the pseudo-random stream. Otherwise one is drawn from the
+    /// operating system's entropy.
     pub fn new(correlation_substep: usize, path_substep: f64,
-        number_of_paths: usize) -> BlackDiffusionFactory {
+        number_of_paths: usize, variance_reduction: VarianceReduction,
+        seed: Option<u64>) -> Result<BlackDiffusionFactory, qm::Error> {
+
+        validate_substeps(correlation_substep, path_substep)?;
+        Ok(BlackDiffusionFactory { correlation_substep: correlation_substep,
+            path_substep: path_substep, number_of_paths: number_of_paths,
+            variance_reduction: variance_reduction,
+            random_source: RandomSourceType::default(),
+            seed: seed,
+            rng_algorithm: RngAlgorithm::default(),
+            discretization: DiscretizationScheme::default(),
+            spot_floor: None,
+            convergence: None,
+            stochastic_rates: None,
+            threads: default_threads(),
+            default_seed: entropy_seed(),
+            progress: None })
+    }
+
+    /// Suggests a correlation_substep and path_substep for a timeline,
+    /// scaled to the time from the spot date to its last observation. The
+    /// correlations are integrated in RECOMMENDED_CORRELATION_STEPS steps,
+    /// and the path_substep is such that an asset with a vol of 100% takes
+    /// RECOMMENDED_PATH_STEPS steps. Assets with lower vols take fewer. A
+    /// timeline with no observations after the spot date is treated as
+    /// spanning one day.
+    pub fn recommended_substeps(timeline: &MonteCarloTimeline) -> (usize, f64) {
+        let spot_date = timeline.spot_date();
+        let last = timeline.observations().values()
+            .filter_map(|obs| obs.last()).map(|obs| obs.date())
+            .max().unwrap_or(spot_date);
+        let days = (last - spot_date).max(1);
+
+        let correlation_substep = (days as usize / RECOMMENDED_CORRELATION_STEPS).max(1);
+        let path_substep = days as f64 / 365.0 / RECOMMENDED_PATH_STEPS as f64;
+        (correlation_substep, path_substep)
+    }
+
+    /// Selects the source of random numbers for generating paths. Sobol
+    /// sequences converge much faster for smooth payoffs with few
+    /// dimensions. They are limited to 53 dimensions, counting every
+    /// substep of every asset, so use them with few path substeps, and to
+    /// MAX_SOBOL_POINTS paths. Exceeding either gives an error when the
+    /// model is built.
+    pub fn with_random_source(mut self, random_source: RandomSourceType)
+        -> BlackDiffusionFactory {
+        self.random_source = random_source;
+        self
+    }
+
+    /// Selects the pseudo-random generator algorithm. The default is the
+    /// standard library generator. This has no effect on Sobol sequences.
+    pub fn with_rng_algorithm(mut self, rng_algorithm: RngAlgorithm)
+        -> BlackDiffusionFactory {
+        self.rng_algorithm = rng_algorithm;
+        self
+    }
+
+    /// Selects the scheme for stepping the paths. The default is Euler.
+    /// Only the exact scheme is unbiased when the steps are coarse.
+    pub fn with_discretization(mut self, discretization: DiscretizationScheme)
+        -> BlackDiffusionFactory {
+        self.discretization = discretization;
+        self
+    }
+
+    /// Floors each simulated spot, less any displacement, at the given
+    /// fraction of its forward, so that in long-dated high-vol simulations
+    /// spots do not underflow to denormalized numbers or zero, which would
+    /// make log returns infinite. It is applied after every step. The floor
+    /// must be positive and much less than one, such as DEFAULT_SPOT_FLOOR,
+    /// so that it does not measurably affect prices. By default there is no
+    /// floor.
+    pub fn with_spot_floor(mut self, spot_floor: f64) -> BlackDiffusionFactory {
+        self.spot_floor = Some(spot_floor);
+        self
+    }
+
+    /// Runs the paths in batches of number_of_paths, stopping once the
+    /// relative standard error of the price meets the target, or there are
+    /// max_paths paths. Like price_with_stderr, this is only meaningful for
+    /// pseudo-random numbers. With antithetic paths, the batch size should
+    /// be even, so that no pair is split between batches.
+    pub fn with_convergence_target(mut self, target: ConvergenceTarget)
+        -> BlackDiffusionFactory {
+        self.convergence = Some(target);
+        self
+    }
+
+    /// Diffuses a Hull-White short rate with the underlyings, rather than
+    /// assuming deterministic rates, which matters for long-dated options.
+    /// The short rate is always driven by pseudo-random numbers, from a
+    /// stream of its own, so the draws driving the underlyings are the same
+    /// as with deterministic rates.
+    pub fn with_stochastic_rates(mut self, stochastic_rates: StochasticRates)
+        -> BlackDiffusionFactory {
+        self.stochastic_rates = Some(stochastic_rates);
+        self
+    }
+
+    /// Sets the number of threads in the rayon pool used for generating
+    /// paths. The paths are generated in fixed-size chunks, each with its
+    /// own deterministic random number stream, so the results are the same
+    /// however many threads are used. A thread count of zero is treated as
+    /// one.
+    pub fn with_threads(mut self, threads: usize) -> BlackDiffusionFactory {
+        self.threads = threads.max(1);
+        self
+    }
 
-        BlackDiffusionFactory { correlation_substep: correlation_substep,
-            path_substep: path_substep, number_of_paths: number_of_paths }
+    /// Supplies a callback to report progress as the paths are evolved,
+    /// both initially and when they are regenerated for bumped repricings.
+    /// The callback is not serialized.
+    pub fn with_progress(mut self, progress: ProgressCallback)
+        -> BlackDiffusionFactory {
+        self.progress = Some(progress);
+        self
     }
 
     pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<MonteCarloModelFactory>, esd::Error> {
@@ -74,10 +267,43 @@ impl MonteCarloModelFactory for BlackDiffusionFactory {
         context: Box<BumpablePricingContext>)
         -> Result<Box<MonteCarloModel>, qm::Error> {
 
+        // a deserialized factory has not been validated
+        validate_substeps(self.correlation_substep, self.path_substep)?;
+        validate_spot_floor(self.spot_floor)?;
+
+        // the first batch must not exceed the cap on the number of paths
+        let n_paths = match self.convergence {
+            Some(target) => self.number_of_paths.min(target.max_paths),
+            None => self.number_of_paths
+        };
+
+        // nor may the paths need more points than the random source can
+        // supply. This is checked again, exactly, whenever a source is
+        // built for a batch, along with its dimensions, which depend on the
+        // timeline.
+        let max_paths = self.convergence.map_or(n_paths, |target| target.max_paths);
+        let max_points = if self.variance_reduction == VarianceReduction::Antithetic {
+            max_paths.div_ceil(2)
+        } else {
+            max_paths
+        };
+        self.random_source.validate_points(max_points)?;
+
         let model = BlackDiffusion::new(timeline, context,
-            self.correlation_substep, self.path_substep, self.number_of_paths)?;
+            self.correlation_substep, self.path_substep, n_paths,
+            self.variance_reduction, self.random_source,
+            Some(self.seed.unwrap_or(self.default_seed)), self.rng_algorithm, self.discretization, self.spot_floor,
+            self.stochastic_rates, self.threads, self.progress.clone())?;
         Ok(Box::new(model))
     }
+
+    fn threads(&self) -> usize {
+        self.threads
+    }
+
+    fn convergence_target(&self) -> Option<ConvergenceTarget> {
+        self.convergence
+    }
 }
 
 /// A Black Diffusion model represents the SDE:
@@ -102,6 +328,15 @@ impl MonteCarloModelFactory for BlackDiffusionFactory {
 /// the t parameter altogether, except in fetching the variance over a time
 /// step.
 ///
+/// The term structure of vol is honoured by treating the variance as
+/// piecewise constant between observations. Each step between observations
+/// uses the forward variance, the difference between the at-the-money
+/// total variances at either end, split evenly across any substeps. The
+/// simulated variance to each observation therefore sums to the total
+/// variance in the surface. Observations that lie between vol pillars take
+/// their total variance from the surface's own interpolation, which for
+/// VolByProbability is linear in total variance.
+///
 /// A note on the ordering of dimensions in the paths array (and the correlated
 /// gaussians array, which is kept the same for simplicity). The most natural
 /// ordering for constructing the paths is the one we currently use:
@@ -129,9 +364,55 @@ pub struct BlackDiffusion {
     context: Box<BumpablePricingContext>,
     key: HashMap<String, usize>,
     instruments: Vec<RcInstrument>,
+    quantos: Vec<Option<String>>,
     substepping: Vec<usize>,
+    discretization: DiscretizationScheme,
+    spot_floor: Option<f64>,
     correlated_gaussians: Array3<f64>,
-    paths: Array3<f64>
+    paths: Array3<f64>,
+    variance_reduction: VarianceReduction,
+    rates: Option<RatePaths>,
+    batches: PathBatches,
+    accumulator: PathAccumulator,
+    progress: Option<ProgressCallback>
+}
+
+/// What a BlackDiffusion needs to generate further batches of paths after
+/// the first. Each batch starts at a fresh chunk of random numbers, so the
+/// batches never share a random number stream. The paths from latest_start
+/// onwards are the latest batch, which may be valued on its own.
+#[derive(Clone)]
+struct PathBatches {
+    correlation_substep: usize,
+    batch_size: usize,
+    random_source: RandomSourceType,
+    seed: Option<u64>,
+    rng_algorithm: RngAlgorithm,
+    n_threads: usize,
+    next_chunk: usize,
+    latest_start: usize,
+    latest_only: Cell<bool>
+}
+
+/// The paths of a Hull-White short rate. The ratios are indexed by path then
+/// observation, and are the ratio of the money-market account on the path
+/// to the deterministic one implied by the yield curve. The spots on each
+/// path are scaled up by this ratio, and each flow is discounted by it, at
+/// the last observation on or before the flow pays.
+#[derive(Clone)]
+struct RatePaths {
+    parameters: StochasticRates,
+    ratios: Array2<f64>,
+    flow_observations: Vec<Option<usize>>
+}
+
+impl RatePaths {
+    /// Scales the paths of every asset by the ratios
+    fn apply(&self, mut paths: ArrayViewMut3<f64>) {
+        for mut path in paths.axis_iter_mut(Axis(2)) {
+            path *= &self.ratios;
+        }
+    }
 }
 
 impl BlackDiffusion {
@@ -142,18 +423,52 @@ impl BlackDiffusion {
     ///
     /// The correlation_substep parameter is a count of days, and is used for
     /// walking through the local correlations to create the terminal
-    /// correlation required by BlackDiffusion.
+    /// correlation required by BlackDiffusion. It must be at least one.
+    ///
+    /// The path_substep parameter is the maximum forward variance covered
+    /// by a single step, and must be positive. As volatilities increase, it
+    /// becomes necessary to take smaller steps in time, to converge on the
+    /// correct drift and variance. See BlackDiffusionFactory::new.
     ///
-    /// The path_substep parameter is a measure of the maximum sqrt_variance
-    /// step size. As volatilities increase, it becomes necessary to take
-    /// smaller steps in time, to converge on the correct drift and variance.
+    /// The variance_reduction parameter selects, for example, antithetic
+    /// paths, and the random_source selects pseudo-random or Sobol numbers.
+    /// The seed, if supplied, selects the pseudo-random stream, and the
+    /// rng_algorithm selects the pseudo-random generator. The discretization
+    /// selects the scheme for stepping the paths, and any spot_floor is the
+    /// smallest fraction of the forward a spot may fall to. If stochastic_rates is
+    /// supplied, a Hull-White short rate is diffused with the underlyings,
+    /// rather than using deterministic rates. The n_threads
+    /// parameter controls how many threads are used to
+    /// generate the correlated gaussians. The progress callback, if
+    /// supplied, is told as batches of paths are evolved.
     pub fn new(timeline: &MonteCarloTimeline,
         context: Box<BumpablePricingContext>,
         correlation_substep: usize,
         path_substep: f64,
-        n_paths: usize)
+        n_paths: usize,
+        variance_reduction: VarianceReduction,
+        random_source: RandomSourceType,
+        seed: Option<u64>,
+        rng_algorithm: RngAlgorithm,
+        discretization: DiscretizationScheme,
+        spot_floor: Option<f64>,
+        stochastic_rates: Option<StochasticRates>,
+        n_threads: usize,
+        progress: Option<ProgressCallback>)
         -> Result<BlackDiffusion, qm::Error> {
 
+        validate_substeps(correlation_substep, path_substep)?;
+        validate_spot_floor(spot_floor)?;
+        if let Some(rates) = stochastic_rates {
+            if !(rates.mean_reversion >= 0.0) || !(rates.volatility >= 0.0)
+                || !(rates.correlation.abs() <= 1.0) {
+                return Err(qm::Error::new(&format!("Invalid stochastic rates: \
+                    mean reversion {} and volatility {} must not be negative, \
+                    and correlation {} must be between -1 and 1",
+                    rates.mean_reversion, rates.volatility, rates.correlation)))
+            }
+        }
+
         // key to all observations and all instruments
         let mut observations = Vec::new();
         let mut key = HashMap::new();
@@ -173,21 +488,41 @@ impl BlackDiffusion {
             instruments.push(asset.clone());
         }
 
+        // the currency each asset is diffused in, if not its own
+        let quantos = instruments.iter().map(|asset| timeline.quanto_currency(asset))
+            .collect::<Result<Vec<_>, qm::Error>>()?;
+
         // Calculate the substepping required, given the path_substep
-        // constraint. This should be done only once, for all risks.
-        let substepping = calculate_substepping(&observations,
+        // constraint and any spacing or refinement of the timeline. This
+        // should be done only once, for all risks.
+        let mut substepping = calculate_substepping(&observations,
             context.as_pricing_context(), &instruments, path_substep)?;
+        timeline.adjust_substepping(&observations, &mut substepping);
 
         // Populate the correlated gaussians. (Really, this should be redone
         // whenever any forward or vol changes, but that would slow all 
         // risks down, and it is only a second order effect.)
         let correlated_gaussians = fetch_correlated_gaussians(
             context.as_pricing_context(), &instruments,
-            correlation_substep, &substepping, n_paths)?;
-
-        let paths = fetch_paths(&observations, &correlated_gaussians,
-            context.as_pricing_context(), &instruments, 
-            &substepping, n_paths)?;
+            correlation_substep, &substepping, n_paths, variance_reduction,
+            random_source, seed, rng_algorithm,
+            stochastic_rates.map(|r| r.correlation), 0, n_threads)?;
+
+        // Any short rate is driven by the gaussians after those of the assets
+        let spot_date = context.as_pricing_context().spot_date();
+        let rates = stochastic_rates.map(|parameters| RatePaths {
+            parameters: parameters,
+            ratios: fetch_rate_ratios(&parameters, &observations, spot_date,
+                &substepping, correlated_gaussians.subview(Axis(2), instruments.len())),
+            flow_observations: timeline.flows().iter().map(|flow|
+                flow_observation(flow, &observations, spot_date)).collect() });
+
+        let mut paths = fetch_paths(&observations, &correlated_gaussians,
+            context.as_pricing_context(), &instruments, &quantos,
+            &substepping, discretization, spot_floor, n_paths, progress.as_ref())?;
+        if let Some(ref rates) = rates {
+            rates.apply(paths.view_mut());
+        }
 
         // create the model with these paths and gaussians
         Ok(BlackDiffusion { 
@@ -196,9 +531,26 @@ impl BlackDiffusion {
             context: context,
             key: key,
             instruments: instruments,
+            quantos: quantos,
             substepping: substepping,
+            discretization: discretization,
+            spot_floor: spot_floor,
             correlated_gaussians: correlated_gaussians,
-            paths: paths })
+            paths: paths,
+            variance_reduction: variance_reduction,
+            rates: rates,
+            batches: PathBatches {
+                correlation_substep: correlation_substep,
+                batch_size: n_paths,
+                random_source: random_source,
+                seed: seed,
+                rng_algorithm: rng_algorithm,
+                n_threads: n_threads,
+                next_chunk: n_paths.div_ceil(PATHS_PER_CHUNK),
+                latest_start: 0,
+                latest_only: Cell::new(false) },
+            accumulator: PathAccumulator::new(),
+            progress: progress })
     }
 
     /// Refetch a single asset
@@ -213,16 +565,21 @@ impl BlackDiffusion {
         let id_string = id.to_string();
         if let Some(asset) = self.key.get(&id_string) {
 
-            // save the old path then replace it
+            // save the old path (unless already saved) then replace it
             let path = self.paths.subview_mut(Axis(2), *asset);
             if let Some(s) = saved_paths {
-                s.insert(*asset, path.to_owned());
+                s.entry(*asset).or_insert_with(|| path.to_owned());
             }
-            fetch_path(self.instruments[*asset].deref(), 
-                self.context.as_pricing_context(), &self.observations,
+            fetch_path_with_progress(self.instruments[*asset].deref(), 
+                self.context.as_pricing_context(),
+                self.quantos[*asset].as_ref().map(|c| c.as_str()), &self.observations,
                 self.correlated_gaussians.subview(Axis(2), *asset),
-                &self.substepping,
-                path)?;
+                &self.substepping, self.discretization, self.spot_floor,
+                path, self.progress.as_ref())?;
+            if let Some(ref rates) = self.rates {
+                let mut path = self.paths.subview_mut(Axis(2), *asset);
+                path *= &rates.ratios;
+            }
 
         } else {
             return Err(qm::Error::new("Failed to find asset"))
@@ -231,20 +588,149 @@ impl BlackDiffusion {
         Ok(true)
     }
 
+    /// Regenerate the correlated gaussians from the correlations in the
+    /// context, re-running the Cholesky decomposition, then refetch all the
+    /// paths. The gaussians are regenerated in the same batches as before,
+    /// so they are built from exactly the same random draws, and only the
+    /// correlation between them changes. Everything replaced is saved first,
+    /// unless already saved.
+    pub fn recorrelate(&mut self, bumped: bool,
+        saved: Option<&mut CompositeSaveable>) -> Result<bool, qm::Error> {
+
+        // if nothing was bumped, there is nothing to do
+        if !bumped {
+            return Ok(false)
+        }
+
+        if let Some(saved) = saved {
+            {
+                let saved_paths = saved.typed_child_mut(SAVED_PATHS, SavedPaths::new)?;
+                for asset in 0..self.instruments.len() {
+                    saved_paths.paths.entry(asset).or_insert_with(||
+                        self.paths.subview(Axis(2), asset).to_owned());
+                }
+            }
+            {
+                let saved_gaussians = saved.typed_child_mut(SAVED_GAUSSIANS,
+                    SavedGaussians::new)?;
+                if saved_gaussians.gaussians.is_none() {
+                    saved_gaussians.gaussians = Some(self.correlated_gaussians.clone());
+                }
+            }
+            if let Some(ref rates) = self.rates {
+                let saved_rates = saved.typed_child_mut(SAVED_RATES, SavedRates::new)?;
+                if saved_rates.ratios.is_none() {
+                    saved_rates.ratios = Some(rates.ratios.clone());
+                }
+            }
+        }
+
+        let n_total = self.paths.shape()[0];
+        let mut batches = Vec::new();
+        let mut first_chunk = 0;
+        let mut n_done = 0;
+        while n_done < n_total {
+            let n_paths = self.batches.batch_size.min(n_total - n_done);
+            batches.push(fetch_correlated_gaussians(
+                self.context.as_pricing_context(), &self.instruments,
+                self.batches.correlation_substep, &self.substepping, n_paths,
+                self.variance_reduction, self.batches.random_source,
+                self.batches.seed, self.batches.rng_algorithm,
+                self.rates.as_ref().map(|r| r.parameters.correlation),
+                first_chunk, self.batches.n_threads)?);
+            first_chunk += n_paths.div_ceil(PATHS_PER_CHUNK);
+            n_done += n_paths;
+        }
+        let views: Vec<ArrayView3<f64>> = batches.iter().map(|b| b.view()).collect();
+        self.correlated_gaussians = stack(Axis(0), &views)
+            .map_err(|e| qm::Error::new(&format!("Cannot recorrelate paths: {}", e)))?;
+
+        // any short rate is correlated with the assets, so it changes too
+        if let Some(ref mut rates) = self.rates {
+            let spot_date = self.context.as_pricing_context().spot_date();
+            rates.ratios = fetch_rate_ratios(&rates.parameters,
+                &self.observations, spot_date, &self.substepping,
+                self.correlated_gaussians.subview(Axis(2), self.instruments.len()));
+        }
+
+        self.refetch_all()?;
+        Ok(true)
+    }
+
     /// Refetch all paths for all assets. Note that this does not refetch the
-    /// correlated gaussians, so does not work for a correlation bump. It also
-    /// assumes the form of the instrument(s) being priced is unchanged.
+    /// correlated gaussians, so does not work for a correlation bump (see
+    /// recorrelate). It also assumes the form of the instrument(s) being
+    /// priced is unchanged.
     pub fn refetch_all(&mut self) -> Result<(), qm::Error> {
 
         let n_paths = self.paths.shape()[0];
 
         self.paths = fetch_paths(&self.observations, &self.correlated_gaussians,
-            self.context.as_pricing_context(), &self.instruments,
-            &self.substepping, n_paths)?;
+            self.context.as_pricing_context(), &self.instruments, &self.quantos,
+            &self.substepping, self.discretization, self.spot_floor, n_paths,
+            self.progress.as_ref())?;
+        if let Some(ref rates) = self.rates {
+            rates.apply(self.paths.view_mut());
+        }
         Ok(())
     }
 }
 
+impl BlackDiffusion {
+    /// The parameters the paths of the given underlying are evolved with,
+    /// and the index of its last observation on the given date.
+    fn diagnostic_parameters(&self, underlying: &RcInstrument, date: Date)
+        -> Result<(PathParameters, usize), qm::Error> {
+
+        let asset = *self.key.get(underlying.id()).ok_or_else(|| qm::Error::new(
+            &format!("BlackDiffusion does not know about '{}'", underlying.id())))?;
+        let obs = self.observations.iter().rposition(|o| o.date() == date)
+            .ok_or_else(|| qm::Error::new(&format!(
+                "No observation on {} in the timeline", date)))?;
+        let params = PathParameters::new(underlying.deref(),
+            self.context.as_pricing_context(),
+            self.quantos[asset].as_ref().map(|c| c.as_str()), &self.observations,
+            &self.substepping)?;
+        Ok((params, obs))
+    }
+}
+
+/// The number of steps over the timeline suggested for integrating the
+/// correlations. See BlackDiffusionFactory::recommended_substeps.
+const RECOMMENDED_CORRELATION_STEPS: usize = 25;
+
+/// The number of path steps over the timeline suggested for an asset with a
+/// vol of 100%. See BlackDiffusionFactory::recommended_substeps.
+const RECOMMENDED_PATH_STEPS: usize = 100;
+
+/// Checks the substep parameters of a BlackDiffusion. See
+/// BlackDiffusionFactory::new for their meaning.
+fn validate_substeps(correlation_substep: usize, path_substep: f64)
+    -> Result<(), qm::Error> {
+
+    if correlation_substep < 1 {
+        return Err(qm::Error::new("The correlation substep must be at least one day"))
+    }
+    if !(path_substep > 0.0) || !path_substep.is_finite() {
+        return Err(qm::Error::new(&format!("The path substep {} must be a \
+            positive and finite variance", path_substep)))
+    }
+    Ok(())
+}
+
+/// Checks that any spot floor is a positive fraction of the forward, well
+/// below one.
+fn validate_spot_floor(spot_floor: Option<f64>) -> Result<(), qm::Error> {
+    if let Some(floor) = spot_floor {
+        if !(floor > 0.0) || !(floor <= MAX_SPOT_FLOOR) {
+            return Err(qm::Error::new(&format!("The spot floor {} must be a \
+                positive fraction of the forward, no more than {}",
+                floor, MAX_SPOT_FLOOR)))
+        }
+    }
+    Ok(())
+}
+
 /// Work out how to step along the timeline. We need steps at each observation,
 /// but we may well need intermediate steps. This method calculates how many
 /// intermediate steps for each observation.
@@ -273,12 +759,14 @@ pub fn calculate_substepping(
         let mut prev_var = 0.0;
 
         for (obs, substep) in observations.iter().zip(substepping.iter_mut()) {
-            let atm = fwd.forward(obs.date())?;
-            let var = surface.variance(*obs, atm)?;
+            let atm = check_finite(fwd.forward(obs.date())?, "forward",
+                || format!("{} on {}", instr.id(), obs.date()))?;
+            let var = check_finite(surface.variance(*obs, atm)?, "variance",
+                || format!("{} on {} at strike {}", instr.id(), obs.date(), atm))?;
             let fwd_var = var - prev_var;
             prev_var = var;
             if fwd_var < 0.0 {
-                return Err(qm::Error::new("Negative forward variance"))
+                return Err(negative_forward_variance(instr, fwd_var, *obs))
             }
 
             // if the forward variance is too big, chop it up
@@ -290,33 +778,91 @@ pub fn calculate_substepping(
     Ok(substepping)
 }
 
+/// The error when the total variance of an asset falls in the step to an
+/// observation, meaning its vol surface has calendar arbitrage
+fn negative_forward_variance(instrument: &Instrument, fwd_var: f64,
+    obs: DateDayFraction) -> qm::Error {
+    qm::Error::new(&format!("Negative forward variance {} for {} in the \
+        step to {}", fwd_var, instrument.id(), obs.date()))
+}
+
+/// Number of paths in each chunk of correlated gaussians. Each chunk has its
+/// own random number stream, so this must not change with the number of
+/// threads, or results would not be reproducible. It must be even, so that
+/// antithetic pairs of paths never straddle two chunks.
+const PATHS_PER_CHUNK: usize = 1024;
+
+/// Offset added to the seed for the pseudo-random stream driving any short
+/// rate, so that it is independent of the streams driving the assets
+const RATE_SEED: u64 = 0x4a7e;
+
+/// A spot floor, as a fraction of the forward, small enough not to affect
+/// prices measurably, but far above the denormalized numbers.
+pub const DEFAULT_SPOT_FLOOR: f64 = 1e-100;
+
+/// The largest spot floor allowed, as a fraction of the forward. Anything
+/// larger would noticeably bias prices.
+const MAX_SPOT_FLOOR: f64 = 1e-6;
+
+/// Tolerance used when decomposing correlation matrices, below which a pivot
+/// is treated as zero, meaning the asset is a combination of the others.
+const CORRELATION_TOLERANCE: f64 = 1e-10;
+
 /// Fetch the correlated gaussians. In other words, a set of random
 /// numbers weighted by a gaussian distribution with correlations defined
 /// by the correlation matrix in the pricing context.
+///
+/// The paths are split into chunks of PATHS_PER_CHUNK, each of which draws
+/// deterministically from the random source. The chunks are filled in
+/// parallel by a rayon pool of n_threads threads, so the result is
+/// independent of the number of threads. The chunks are numbered from first_chunk, so later
+/// batches of paths can draw from streams not used by earlier ones.
+///
+/// If antithetic variance reduction is requested, paths are interleaved in
+/// pairs, with each odd path using the negated gaussians of the path before.
+/// The correlation is applied to the draws before negation, so both paths of
+/// a pair see the same correlation structure at every substep.
+///
+/// If stratified sampling is requested, the terminal value of each asset's
+/// independent draws is stratified within each chunk, before correlation.
+/// See stratify_terminal. The terminal value is the sum over all substeps,
+/// so the stratification is exact for a payoff observed once at the end of
+/// steps of equal variance, and weaker otherwise.
+///
+/// If a rate_correlation is supplied, there is an extra gaussian after those
+/// of the assets, to drive a short rate, with that correlation to each of
+/// the assets. See correlated_gaussians.
 pub fn fetch_correlated_gaussians(
     context: &PricingContext,
     instruments: &Vec<RcInstrument>,
     _correlation_substep: usize,
     substepping: &[usize],
-    n_paths: usize) -> Result<Array3<f64>, qm::Error> {
-
-    // calculate how many substeps we need altogether
-    let n_steps = substepping.iter().sum();
-    assert!(n_steps > 0);
-
-    // create a 3d tensor indexed by path, then observation, then asset
-    let n_assets = instruments.len();
-    assert!(n_assets > 0);
-    assert!(n_paths > 0);
-    let mut result = Array3::<f64>::zeros((n_paths, n_steps, n_assets));
+    n_paths: usize,
+    variance_reduction: VarianceReduction,
+    random_source: RandomSourceType,
+    seed: Option<u64>,
+    rng_algorithm: RngAlgorithm,
+    rate_correlation: Option<f64>,
+    first_chunk: usize,
+    n_threads: usize) -> Result<Array3<f64>, qm::Error> {
 
     // TODO we currently just use the raw correlations, but we ought to
     // calculate correlations between the timeline points. If there is a
     // term structure to vol, this is likely to be different, even with
     // flat correlation structure.
+    let correl = fetch_correlation_matrix(context, instruments)?;
+    correlated_gaussians(&correl, substepping, n_paths, variance_reduction,
+        random_source, seed, rng_algorithm, rate_correlation, first_chunk,
+        n_threads)
+}
 
-    // Create a correlation matrix. Starting with an identity matrix (eye)
-    // fills in the diagonals.
+/// Create a correlation matrix between the given instruments, using the
+/// correlations in the pricing context.
+pub fn fetch_correlation_matrix(context: &PricingContext,
+    instruments: &Vec<RcInstrument>) -> Result<Array2<f64>, qm::Error> {
+
+    // Starting with an identity matrix (eye) fills in the diagonals.
+    let n_assets = instruments.len();
     let mut correl = Array2::<f64>::eye(n_assets);
     for i in 0..n_assets {
         let first = instruments[i].deref();
@@ -327,62 +873,278 @@ pub fn fetch_correlated_gaussians(
             correl[(j, i)] = c;
         }
     }
+    Ok(correl)
+}
+
+/// Generate gaussians with the given correlation matrix, one set for each
+/// path and substep. The result is indexed by path, then substep, then by
+/// the rows of the correlation matrix. See fetch_correlated_gaussians for
+/// the way paths are chunked and shared between threads.
+///
+/// If a rate_correlation is supplied, each step has an extra gaussian after
+/// the rows of the matrix, with that correlation to each of them. It is
+/// built from the independent draws behind the others, plus a draw from a
+/// pseudo-random stream of its own, so the other gaussians are exactly as
+/// they would be without it.
+pub fn correlated_gaussians(
+    correl: &Array2<f64>,
+    substepping: &[usize],
+    n_paths: usize,
+    variance_reduction: VarianceReduction,
+    random_source: RandomSourceType,
+    seed: Option<u64>,
+    rng_algorithm: RngAlgorithm,
+    rate_correlation: Option<f64>,
+    first_chunk: usize,
+    n_threads: usize) -> Result<Array3<f64>, qm::Error> {
+
+    // calculate how many substeps we need altogether
+    let n_steps = substepping.iter().sum();
+    assert!(n_steps > 0);
+    if variance_reduction == (VarianceReduction::Stratified { strata: 0 }) {
+        return Err(qm::Error::new("Stratified sampling needs at least one stratum"))
+    }
+
+    // create a 3d tensor indexed by path, then observation, then asset
+    let n_assets = correl.shape()[0];
+    assert!(n_assets > 0);
+    assert!(n_paths > 0);
+    let n_factors = if rate_correlation.is_some() { n_assets + 1 } else { n_assets };
+    let mut result = Array3::<f64>::zeros((n_paths, n_steps, n_factors));
 
     // Use Cholesky decomposition to create a matrix to use for generating
     // correlated gaussians. (There are alternative ways of producing
     // copulae. This should be user-settable.)
 
-    // This is what it would look like if we could use ndarray_linalg
-    // if let Some(cholesky) = correl.cholesky(UPLO::Lower) {
-
-    // convert to a DMatrix
-    let slice = correl.as_slice().ok_or_else(|| qm::Error::new(
-        "Correlation cannot be accessed as a slice"))?;
-    let correld = DMatrix::from_column_slice(n_assets, n_assets, slice);
-    let rootd = Cholesky::new(correld).ok_or_else(|| qm::Error::new(
-        "Correlation matrix is not positive semi-definite"))?;
+    // The decomposition must allow singular matrices, for example where two
+    // assets are perfectly correlated.
+    let root = cholesky_psd(correl, CORRELATION_TOLERANCE).map_err(|e|
+        qm::Error::new(&format!("Correlation matrix is not positive \
+        semi-definite: {}", e)))?;
+
+    // The random source generates independent gaussians for all the steps
+    // of a path at once, so it can use a Brownian bridge where appropriate.
+    // It must supply a point for every path up to the end of this batch,
+    // or for every pair of antithetic paths.
+    let first_path = first_chunk * PATHS_PER_CHUNK;
+    let n_points = if variance_reduction == VarianceReduction::Antithetic {
+        (first_path + n_paths).div_ceil(2)
+    } else {
+        first_path + n_paths
+    };
+    let source = random_source.create(n_steps, n_assets, n_points, seed,
+        rng_algorithm)?;
+
+    // The short rate takes its weights on the independent draws from the
+    // last row of the decomposition of the matrix extended by the rate
+    let rate = match rate_correlation {
+        Some(rho) => {
+            let mut extended = Array2::<f64>::eye(n_factors);
+            for i in 0..n_assets {
+                for j in 0..n_assets {
+                    extended[(i, j)] = correl[(i, j)];
+                }
+                extended[(i, n_assets)] = rho;
+                extended[(n_assets, i)] = rho;
+            }
+            let extended_root = cholesky_psd(&extended, CORRELATION_TOLERANCE)
+                .map_err(|e| qm::Error::new(&format!("Correlation matrix is \
+                not positive semi-definite with a rate correlation of {}: {}",
+                rho, e)))?;
+            let weights = extended_root.subview(Axis(0), n_assets).to_owned();
+            let rate_seed = seed.unwrap_or(0).wrapping_add(RATE_SEED);
+            let rate_source = RandomSourceType::Pseudo.create(n_steps, 1,
+                n_points, Some(rate_seed), rng_algorithm)?;
+            Some((weights, rate_source))
+        },
+        None => None
+    };
+
+    // Fill the chunks of paths, in parallel unless there is only one thread.
+    // Each chunk has its own stream, so it does not matter which thread
+    // fills it.
+    let root = &root;
+    let source = &*source;
+    let rate = rate.as_ref().map(|&(ref weights, ref source)| (weights, &**source));
+    {
+        let chunks: Vec<(usize, ArrayViewMut3<f64>)> = result
+            .axis_chunks_iter_mut(Axis(0), PATHS_PER_CHUNK).enumerate()
+            .map(|(chunk, paths)| (first_chunk + chunk, paths)).collect();
+        let fill = |(chunk, paths): (usize, ArrayViewMut3<f64>)|
+            fill_correlated_gaussians(root, source, rate, chunk,
+                variance_reduction, paths);
+
+        if n_threads <= 1 {
+            chunks.into_iter().for_each(fill);
+        } else {
+            let pool = rayon::ThreadPoolBuilder::new().num_threads(n_threads)
+                .build().map_err(|e| qm::Error::new(&format!("Cannot create \
+                a pool of {} threads for generating paths: {}", n_threads, e)))?;
+            pool.install(|| chunks.into_par_iter().for_each(fill));
+        }
+    }
 
-    // convert back to an Array2
-    let root_slice = rootd.unpack().as_slice().to_vec();
-    let root = Array::from_shape_vec((n_assets, n_assets), root_slice)?;
+    Ok(result)
+}
 
-    // Use the standard library random number generator for now. (Look
-    // at better generators such as Mersenne Twister, or better still
-    // Sobol sequences -- this should be user-settable.)
-    let mut rand = rand::StdRng::new().unwrap();
+/// Fills one chunk of paths with correlated gaussians, using the independent
+/// gaussians generated by the random source for this chunk. If there is a
+/// short rate, its gaussians are weighted sums of the independent gaussians
+/// and a draw from its own source.
+fn fill_correlated_gaussians(root: &Array2<f64>, source: &RandomSource,
+    rate: Option<(&Array1<f64>, &RandomSource)>,
+    chunk: usize, variance_reduction: VarianceReduction,
+    mut paths: ArrayViewMut3<f64>) {
+
+    let shape = paths.shape().to_vec();
+    let (n_paths, n_steps) = (shape[0], shape[1]);
+    let n_assets = root.shape()[0];
+
+    // in the antithetic case, we only need draws for the even paths
+    let antithetic = variance_reduction == VarianceReduction::Antithetic;
+    let (n_draws, first_path) = if antithetic {
+        (n_paths.div_ceil(2), chunk * PATHS_PER_CHUNK / 2)
+    } else {
+        (n_paths, chunk * PATHS_PER_CHUNK)
+    };
+    let mut draws = Array2::zeros((n_draws, n_steps * n_assets));
+    source.fill_gaussians(chunk, first_path, draws.view_mut());
+    if let VarianceReduction::Stratified { strata } = variance_reduction {
+        stratify_terminal(draws.view_mut(), n_steps, n_assets, strata, chunk);
+    }
+    let rate_draws = rate.map(|(_, rate_source)| {
+        let mut rate_draws = Array2::zeros((n_draws, n_steps));
+        rate_source.fill_gaussians(chunk, first_path, rate_draws.view_mut());
+        rate_draws
+    });
+
+    for i in 0..n_paths {
+
+        // in the antithetic case, every odd path mirrors the one before,
+        // unless it is the last path of an odd number
+        if antithetic && i % 2 == 1 {
+            let mirror = paths.subview(Axis(0), i - 1).mapv(|x| -x);
+            paths.subview_mut(Axis(0), i).assign(&mirror);
+            continue;
+        }
 
-    // Use the normal statrs package for turning the random numbers into
-    // gaussians for now. Internally it uses Box-Mueller, which is a
-    // lossy algorithm, so it cannot be used for low-discrepancy
-    // sequences like Sobol.
-    let normal = Normal::new(0.0, 1.0).unwrap();
+        let draw = if antithetic { i / 2 } else { i };
+        let path_draws = draws.subview(Axis(0), draw)
+            .into_shape((n_steps, n_assets)).unwrap();
+        let mut path = paths.subview_mut(Axis(0), i);
+        for (k, (mut step, step_draws)) in path.outer_iter_mut()
+            .zip(path_draws.outer_iter()).enumerate() {
 
-    let mut draws = Array1::zeros(n_assets);
+            // turn them into correlated gaussians. TODO ensure that this
+            // multiplication does not result in an allocation.
+            let correlated = root.dot(&step_draws);
+            if let (Some((weights, _)), &Some(ref rate_draws)) = (rate, &rate_draws) {
+                for (j, value) in correlated.iter().enumerate() {
+                    step[j] = *value;
+                }
+                step[n_assets] = weights.iter().zip(step_draws.iter())
+                    .map(|(w, d)| w * d).sum::<f64>()
+                    + weights[n_assets] * rate_draws[(draw, k)];
+            } else {
+                step.assign(&correlated);
+            }
+        }
+    }
+}
 
-    for mut path in result.outer_iter_mut() {
-        for mut step in path.outer_iter_mut() {
+/// Simulates the Hull-White factor x of a short rate, driven by the given
+/// gaussians, indexed by path then substep, and returns the ratio of the
+/// money-market account to the deterministic one at each observation,
+/// indexed by path then observation. Time is measured in calendar years
+/// from the spot date, split evenly between the substeps.
+///
+/// The factor is stepped exactly, and its integral by the trapezium rule.
+/// Both are gaussian, so we track the variance of the integral under this
+/// scheme, V, and the ratio is exp(integral + V / 2). Its reciprocal, the
+/// discount relative to the yield curve, then has an expectation of exactly
+/// one on every date.
+fn fetch_rate_ratios(parameters: &StochasticRates,
+    observations: &[DateDayFraction], spot_date: Date, substepping: &[usize],
+    gaussians: ArrayView2<f64>) -> Array2<f64> {
+
+    let n_paths = gaussians.shape()[0];
+    let n_obs = observations.len();
+    let a = parameters.mean_reversion;
+    let sigma = parameters.volatility;
+
+    // the step sizes and the deterministic moments of the factor and its
+    // integral at each observation
+    let mut steps = Vec::with_capacity(n_obs);
+    let mut half_variances = Vec::with_capacity(n_obs);
+    let (mut var_x, mut cov_xi, mut var_i) = (0.0, 0.0, 0.0);
+    let mut prev_time = 0.0;
+    for (obs, substeps) in observations.iter().zip(substepping.iter()) {
+        let time = ((obs.date() - spot_date) as f64 / 365.0).max(prev_time);
+        let dt = (time - prev_time) / *substeps as f64;
+        prev_time = time;
+
+        let decay = (-a * dt).exp();
+        let sd = if a * dt > 1e-12 {
+            sigma * ((1.0 - decay * decay) / (2.0 * a)).sqrt()
+        } else {
+            sigma * dt.sqrt()
+        };
+        let weight = 0.5 * (1.0 + decay) * dt;
+        for _ in 0..*substeps {
+            var_i += weight * weight * var_x + 0.25 * sd * sd * dt * dt
+                + 2.0 * weight * cov_xi;
+            cov_xi = decay * weight * var_x + decay * cov_xi + 0.5 * sd * sd * dt;
+            var_x = decay * decay * var_x + sd * sd;
+        }
+        steps.push((decay, sd, dt));
+        half_variances.push(0.5 * var_i);
+    }
 
-            // create uncorrelated gaussians
-            for draw in draws.iter_mut() {
-                *draw = normal.sample::<StdRng>(&mut rand);
+    let mut ratios = Array2::zeros((n_paths, n_obs));
+    for (path_gaussians, mut path_ratios) in gaussians.outer_iter()
+        .zip(ratios.outer_iter_mut()) {
+
+        let (mut x, mut integral) = (0.0, 0.0);
+        let mut gaussian = path_gaussians.iter();
+        for (obs, substeps) in substepping.iter().enumerate() {
+            let (decay, sd, dt) = steps[obs];
+            for _ in 0..*substeps {
+                let next = decay * x + sd * gaussian.next().unwrap();
+                integral += 0.5 * (x + next) * dt;
+                x = next;
             }
-
-            // turn them into correlated gaussians. TODO ensure that this
-            // multiplication does not result in an allocation.
-            step.assign(&root.dot(&draws));
+            path_ratios[obs] = (integral + half_variances[obs]).exp();
         }
     }
+    ratios
+}
 
-    Ok(result)
+/// The index of the last observation on or before the date the flow pays,
+/// or None if it pays before the first observation
+fn flow_observation(flow: &RcInstrument, observations: &[DateDayFraction],
+    spot_date: Date) -> Option<usize> {
+
+    let mut collector = DependencyCollector::new(spot_date);
+    collector.spot(flow);
+    let pay_date = collector.yield_curve_hwm(flow.credit_id()).unwrap_or(spot_date);
+    observations.iter().rposition(|o| o.date() <= pay_date)
 }
 
+/// Evolves the paths of all the assets. Each asset is diffused under the
+/// measure of the corresponding quanto currency, if any, or otherwise its
+/// own currency. If there is a progress callback, the paths are evolved in
+/// batches, reporting progress after each batch.
 pub fn fetch_paths(
     observations: &[DateDayFraction],
     correlated_gaussians: &Array3<f64>,
     context: &PricingContext,
     instruments: &Vec<RcInstrument>,
+    quantos: &[Option<String>],
     substepping: &[usize],
-    n_paths: usize) -> Result<Array3<f64>, qm::Error> {
+    discretization: DiscretizationScheme,
+    spot_floor: Option<f64>,
+    n_paths: usize,
+    progress: Option<&ProgressCallback>) -> Result<Array3<f64>, qm::Error> {
 
     // create a 3d tensor indexed by path, then observation, then asset
     let n_assets = instruments.len();
@@ -392,96 +1154,290 @@ pub fn fetch_paths(
     assert!(n_paths > 0);
     let mut paths = Array3::<f64>::zeros((n_paths, n_obs, n_assets));
 
-    for ((asset, gaussians), path) in
-        instruments.iter().zip(
-        correlated_gaussians.axis_iter(Axis(2))).zip(
-        paths.axis_iter_mut(Axis(2))) {
-
+    // fetch the market data for each asset once, before any evolution
+    assert_eq!(quantos.len(), n_assets);
+    let mut parameters = Vec::with_capacity(n_assets);
+    for (asset, quanto) in instruments.iter().zip(quantos.iter()) {
         let instr: &Instrument = asset.deref();
-        fetch_path(instr, context, &observations, gaussians,
-            substepping, path)?;
+        parameters.push(PathParameters::new(instr, context,
+            quanto.as_ref().map(|c| c.as_str()), observations, substepping)?);
+    }
+
+    let batch_size = progress_batch_size(progress, n_paths);
+    if let Some(progress) = progress {
+        progress.start(n_paths);
+    }
+    for (gaussians, mut batch) in
+        correlated_gaussians.axis_chunks_iter(Axis(0), batch_size).zip(
+        paths.axis_chunks_iter_mut(Axis(0), batch_size)) {
+
+        let batch_paths = batch.shape()[0];
+        for ((params, asset_gaussians), path) in
+            parameters.iter().zip(
+            gaussians.axis_iter(Axis(2))).zip(
+            batch.axis_iter_mut(Axis(2))) {
+
+            params.evolve(asset_gaussians, substepping, discretization,
+                spot_floor, path);
+        }
+
+        if let Some(progress) = progress {
+            progress.report(batch_paths);
+        }
     }
 
     Ok(paths)
 }
 
 pub fn fetch_path(instrument: &Instrument, context: &PricingContext,
+    quanto: Option<&str>,
     observations: &[DateDayFraction], correlated_gaussians: ArrayView2<f64>,
-    substepping: &[usize],
-    mut path: ArrayViewMut2<f64>) -> Result<(), qm::Error> {
+    substepping: &[usize], discretization: DiscretizationScheme,
+    spot_floor: Option<f64>, path: ArrayViewMut2<f64>) -> Result<(), qm::Error> {
+
+    fetch_path_with_progress(instrument, context, quanto, observations,
+        correlated_gaussians, substepping, discretization, spot_floor, path, None)
+}
+
+/// Evolves the paths of a single asset, in batches if there is a progress
+/// callback.
+pub fn fetch_path_with_progress(instrument: &Instrument,
+    context: &PricingContext, quanto: Option<&str>,
+    observations: &[DateDayFraction], correlated_gaussians: ArrayView2<f64>,
+    substepping: &[usize], discretization: DiscretizationScheme,
+    spot_floor: Option<f64>, mut path: ArrayViewMut2<f64>,
+    progress: Option<&ProgressCallback>) -> Result<(), qm::Error> {
 
-    let n_obs = observations.len();
-    assert!(n_obs > 0);  // otherwise we should not be evolving this asset
     let shape = correlated_gaussians.shape();
     assert_eq!(shape.len(), 2);
     assert_eq!(path.shape()[0], shape[0]);
-    assert!(shape[1] >= n_obs);
+    assert!(shape[1] >= observations.len());
 
-    // Fetch the market data we need
-    let hwm = observations.last().unwrap().date();
-    let forward_curve = context.forward_curve(instrument, hwm)?;
-    let vol_surface = context.vol_surface(instrument, hwm, &|| Ok(forward_curve.clone()))?;
-    
-    // Fetch the forwards and variances on each observation date
-    // We use the at the forward variances, using the live forward curve
-    // (consider optionally using the forwards in the vol surface).
-    let mut forwards = Vec::with_capacity(n_obs);
-    let mut variances = Vec::with_capacity(n_obs);
-    let mut displacements = Vec::with_capacity(n_obs);
-    for obs in observations.iter() {
-        let fwd = forward_curve.forward(obs.date())?;
-        variances.push(vol_surface.variance(*obs, fwd)?);
-        let displacement = vol_surface.displacement(obs.date())?;
-        displacements.push(displacement);
-        forwards.push(fwd - displacement);
-    }
-
-    // The sigma dW term should be treated as a finite step, since our
-    // observations are widely spaced. To ensure we integrate to the correct
-    // overall variances, we use the sqrt of the forward variance over each
-    // step. (No need to use the forward_variance method here, as we are only
-    // looking along the forward, so smile is irrelevant.)
-    let mut sigmas = Vec::with_capacity(n_obs);
-    let mut prev_var = 0.0;
-    for (var, substep) in variances.iter().zip(substepping.iter()) {
-        let fwd_var = (var - prev_var) / (*substep as f64);
-        if fwd_var < 0.0 {
-            return Err(qm::Error::new("Negative forward variance")) 
-        }
-        sigmas.push(fwd_var.sqrt());
-        prev_var = *var;
-    }
-
-    // for each of the paths
-    for (ref gaussians, ref mut one_path) in 
-        correlated_gaussians.outer_iter().zip(path.outer_iter_mut()) {
-
-        // walk along each path
-        let mut point = 1.0;
-        let mut g = 0;	// index into the gaussians
-        for i in 0..n_obs {
-            let sigma = sigmas[i];
-            for _ in 0..substepping[i] {
-                point *= 1.0 + gaussians[g] * sigma;
-                g += 1;
-            }
-                
-            one_path[i] = point * forwards[i] + displacements[i];
-        }
+    let params = PathParameters::new(instrument, context, quanto, observations,
+        substepping)?;
+
+    let n_paths = shape[0];
+    let batch_size = progress_batch_size(progress, n_paths);
+    if let Some(progress) = progress {
+        progress.start(n_paths);
     }
+    for (gaussians, batch) in
+        correlated_gaussians.axis_chunks_iter(Axis(0), batch_size).zip(
+        path.axis_chunks_iter_mut(Axis(0), batch_size)) {
+
+        let batch_paths = gaussians.shape()[0];
+        params.evolve(gaussians, substepping, discretization, spot_floor, batch);
 
-    //println!("BlackDiffusion: sigma={:?}, forwards={:?}, displacements={:?}",
-    //    sigma, forwards, displacements);
+        if let Some(progress) = progress {
+            progress.report(batch_paths);
+        }
+    }
 
     Ok(())
 }
 
+/// The market data needed to evolve the paths of one asset: the forwards
+/// (less any displacement), displacements and forward sigmas at each
+/// observation. For an asset diffused under the measure of another
+/// currency, the forwards include the quanto drift correction.
+struct PathParameters {
+    forwards: Vec<f64>,
+    displacements: Vec<f64>,
+    sigmas: Vec<f64>
+}
+
+impl PathParameters {
+    fn new(instrument: &Instrument, context: &PricingContext,
+        quanto: Option<&str>, observations: &[DateDayFraction],
+        substepping: &[usize]) -> Result<PathParameters, qm::Error> {
+
+        let n_obs = observations.len();
+        assert!(n_obs > 0);  // otherwise we should not be evolving this asset
+
+        // Fetch the market data we need
+        let hwm = observations.last().unwrap().date();
+        let forward_curve = context.forward_curve(instrument, hwm)?;
+        let vol_surface = context.vol_surface(instrument, hwm, &|| Ok(forward_curve.clone()))?;
+        
+        // Fetch the forwards and variances on each observation date
+        // We use the at the forward variances, using the live forward curve
+        // (consider optionally using the forwards in the vol surface).
+        let mut forwards = Vec::with_capacity(n_obs);
+        let mut variances = Vec::with_capacity(n_obs);
+        let mut displacements = Vec::with_capacity(n_obs);
+        for obs in observations.iter() {
+            let fwd = check_finite(forward_curve.forward(obs.date())?, "forward",
+                || format!("{} on {}", instrument.id(), obs.date()))?;
+            variances.push(check_finite(vol_surface.variance(*obs, fwd)?,
+                "variance", || format!("{} on {} at strike {}",
+                instrument.id(), obs.date(), fwd))?);
+            let displacement = check_finite(vol_surface.displacement(obs.date())?,
+                "displacement", || format!("{} on {}", instrument.id(), obs.date()))?;
+            displacements.push(displacement);
+            forwards.push(fwd - displacement);
+        }
+
+        // The sigma dW term should be treated as a finite step, since our
+        // observations are widely spaced. To ensure we integrate to the correct
+        // overall variances, we use the sqrt of the forward variance over each
+        // step. (No need to use the forward_variance method here, as we are only
+        // looking along the forward, so smile is irrelevant.)
+        let mut sigmas = Vec::with_capacity(n_obs);
+        let mut prev_var = 0.0;
+        for ((var, substep), obs) in variances.iter().zip(substepping.iter())
+            .zip(observations.iter()) {
+            let fwd_var = (var - prev_var) / (*substep as f64);
+            if fwd_var < 0.0 {
+                return Err(negative_forward_variance(instrument, var - prev_var, *obs))
+            }
+            sigmas.push(fwd_var.sqrt());
+            prev_var = *var;
+        }
+
+        // Under the measure of another currency, the drift of the asset is
+        // corrected by the quanto drift times its instantaneous vol. As the
+        // vol is deterministic, this integrates to a scaling of the forwards
+        // by the drift times the sum of sqrt(dt dV) over each step.
+        if let Some(currency) = quanto {
+            let drift = context.quanto_drift(instrument, currency)?;
+            let mut integral = 0.0;
+            let mut prev_time = 0.0;
+            let mut prev_var = 0.0;
+            for ((var, obs), forward) in variances.iter().zip(observations.iter())
+                .zip(forwards.iter_mut()) {
+                let time = vol_surface.vol_time(*obs)?;
+                integral += ((time - prev_time) * (var - prev_var)).max(0.0).sqrt();
+                *forward *= (drift * integral).exp();
+                prev_time = time;
+                prev_var = *var;
+            }
+        }
+
+        Ok(PathParameters { forwards: forwards, displacements: displacements,
+            sigmas: sigmas })
+    }
+
+    /// Evolves each path from the given gaussians using the given scheme,
+    /// writing the value at each observation. Any floor is applied to the
+    /// path relative to the forward after each step.
+    fn evolve(&self, correlated_gaussians: ArrayView2<f64>,
+        substepping: &[usize], discretization: DiscretizationScheme,
+        spot_floor: Option<f64>, mut path: ArrayViewMut2<f64>) {
+
+        let n_obs = self.forwards.len();
+
+        // for each of the paths
+        for (ref gaussians, ref mut one_path) in 
+            correlated_gaussians.outer_iter().zip(path.outer_iter_mut()) {
+
+            // walk along each path
+            let mut point = 1.0;
+            let mut g = 0;	// index into the gaussians
+            for i in 0..n_obs {
+                let sigma = self.sigmas[i];
+                for _ in 0..substepping[i] {
+                    point *= discretization.growth(sigma, gaussians[g]);
+                    if let Some(floor) = spot_floor {
+                        point = point.max(floor);
+                    }
+                    g += 1;
+                }
+                    
+                one_path[i] = point * self.forwards[i] + self.displacements[i];
+            }
+        }
+    }
+}
+
 impl MonteCarloModel for BlackDiffusion {
 
     fn as_mc_context(&self) -> &MonteCarloContext { self }
     fn as_bumpable(&self) -> &Bumpable { self }
     fn as_mut_bumpable(&mut self) -> &mut Bumpable { self }
     fn raw_market_data(&self) -> &MarketData { self.context.raw_market_data() }
+    fn bumpable_context(&self) -> &BumpablePricingContext { &*self.context }
+
+    fn diagnostic_forward(&self, underlying: &RcInstrument, date: Date)
+        -> Result<f64, qm::Error> {
+        let (params, obs) = self.diagnostic_parameters(underlying, date)?;
+        Ok(params.forwards[obs] + params.displacements[obs])
+    }
+
+    fn diagnostic_total_variance(&self, underlying: &RcInstrument, date: Date)
+        -> Result<f64, qm::Error> {
+        let (params, obs) = self.diagnostic_parameters(underlying, date)?;
+        Ok(params.sigmas.iter().zip(self.substepping.iter()).take(obs + 1)
+            .map(|(sigma, steps)| sigma * sigma * *steps as f64).sum())
+    }
+
+    fn accumulate_paths(&self, weight: Option<f64>) {
+        self.accumulator.set_weight(weight);
+    }
+
+    fn take_path_values(&self) -> Option<Array1<f64>> {
+        self.accumulator.take(self.variance_reduction == VarianceReduction::Antithetic)
+    }
+
+    fn stream_path_moments(&self) {
+        self.accumulator.stream_moments(self.variance_reduction == VarianceReduction::Antithetic)
+    }
+
+    fn take_path_moments(&self) -> Option<RunningMoments> {
+        self.accumulator.take_moments(self.variance_reduction == VarianceReduction::Antithetic)
+    }
+
+    fn add_path_batch(&mut self, max_paths: usize) -> Result<bool, qm::Error> {
+        let existing = self.paths.shape()[0];
+        let n_paths = self.batches.batch_size.min(max_paths.saturating_sub(existing));
+        if n_paths == 0 {
+            return Ok(false)
+        }
+
+        let gaussians = fetch_correlated_gaussians(
+            self.context.as_pricing_context(), &self.instruments,
+            self.batches.correlation_substep, &self.substepping, n_paths,
+            self.variance_reduction, self.batches.random_source,
+            self.batches.seed, self.batches.rng_algorithm,
+            self.rates.as_ref().map(|r| r.parameters.correlation),
+            self.batches.next_chunk, self.batches.n_threads)?;
+        let mut paths = fetch_paths(&self.observations, &gaussians,
+            self.context.as_pricing_context(), &self.instruments, &self.quantos,
+            &self.substepping, self.discretization, self.spot_floor, n_paths,
+            self.progress.as_ref())?;
+
+        if let Some(ref mut rates) = self.rates {
+            let spot_date = self.context.as_pricing_context().spot_date();
+            let ratios = fetch_rate_ratios(&rates.parameters,
+                &self.observations, spot_date, &self.substepping,
+                gaussians.subview(Axis(2), self.instruments.len()));
+            for mut path in paths.axis_iter_mut(Axis(2)) {
+                path *= &ratios;
+            }
+            rates.ratios = stack(Axis(0), &[rates.ratios.view(), ratios.view()])
+                .map_err(|e| qm::Error::new(&format!("Cannot add paths: {}", e)))?;
+        }
+
+        self.correlated_gaussians = stack(Axis(0),
+            &[self.correlated_gaussians.view(), gaussians.view()])
+            .map_err(|e| qm::Error::new(&format!("Cannot add paths: {}", e)))?;
+        self.paths = stack(Axis(0), &[self.paths.view(), paths.view()])
+            .map_err(|e| qm::Error::new(&format!("Cannot add paths: {}", e)))?;
+        self.batches.next_chunk += n_paths.div_ceil(PATHS_PER_CHUNK);
+        self.batches.latest_start = existing;
+        Ok(true)
+    }
+
+    fn value_latest_batch_only(&self, latest_only: bool) {
+        self.batches.latest_only.set(latest_only);
+    }
+}
+
+impl BlackDiffusion {
+
+    /// The index of the first path to value. See value_latest_batch_only.
+    fn first_valued_path(&self) -> usize {
+        if self.batches.latest_only.get() { self.batches.latest_start } else { 0 }
+    }
 }
 
 impl MonteCarloContext for BlackDiffusion {
@@ -492,7 +1448,8 @@ impl MonteCarloContext for BlackDiffusion {
         let id = instrument.id().to_string();
         let asset = self.key.get(&id).ok_or_else(|| qm::Error::new(
             &format!("BlackDiffusion does not know about '{}'", id)))?;
-        Ok(self.paths.subview(Axis(2), *asset))
+        Ok(self.paths.subview(Axis(2), *asset)
+            .split_at(Axis(0), self.first_valued_path()).1)
     }
 
     fn evaluate_flows(&self, quantities: ArrayView2<f64>)
@@ -500,8 +1457,8 @@ impl MonteCarloContext for BlackDiffusion {
 
         let flows_shape = quantities.shape();
         let paths_shape = self.paths.shape();
-        let n_paths = paths_shape[0];
-        let n_paths_f64: f64 = n_paths as f64;
+        let first = self.first_valued_path();
+        let n_paths = paths_shape[0] - first;
         assert_eq!(flows_shape[0], n_paths);
         assert_eq!(flows_shape[1], self.flows.len());
 
@@ -509,38 +1466,76 @@ impl MonteCarloContext for BlackDiffusion {
         // restriction later, by passing a slice of date-times into the method.)
         let val_date = DateTime::new(self.context.as_pricing_context().spot_date(), TimeOfDay::Open);
 
-        // weighted sum of all of the flows
-        let mut total = 0.0;
-        for (flow, quantity) in self.flows.iter().zip(
-            quantities.axis_iter(Axis(1))) {
+        // With stochastic rates, each path discounts its flows by its own
+        // money-market account, relative to the deterministic one
+        if let Some(ref rates) = self.rates {
+            let mut deflated = quantities.to_owned();
+            for (mut quantity, obs) in deflated.axis_iter_mut(Axis(1))
+                .zip(rates.flow_observations.iter()) {
+                if let Some(obs) = *obs {
+                    quantity /= &rates.ratios.subview(Axis(1), obs)
+                        .split_at(Axis(0), first).1;
+                }
+            }
+            return evaluate_pure_rates_flows(&self.flows,
+                self.context.as_pricing_context(), val_date, deflated.view(),
+                &self.accumulator)
+        }
 
-            // BlackDiffusion is a non-stochastic-rate model, so we can
-            // save time by evaluating the pure rate flows using Priceable
-            if flow.is_pure_rates() {
+        evaluate_pure_rates_flows(&self.flows, self.context.as_pricing_context(),
+            val_date, quantities, &self.accumulator)
+    }
 
-                // value of the instrument times the average quantity
-                let average = quantity.scalar_sum() / n_paths_f64;
-                let pricer = flow.as_priceable().ok_or_else(|| qm::Error::new(
-                    "All pure-rates flows must be priceable"))?;
-                let value = pricer.price(self.context.as_pricing_context(), val_date)?;
-                total += average * value;
+    fn pricing_context(&self) -> &PricingContext {
+        &*self.context.as_pricing_context()
+    }
+}
 
-                //println!("BlackDiffusion::evaluate_flows value={} average={} \
-                //    total={}", value, average, total);
+/// Evaluates flows for a model where rates are not stochastic, so each
+/// flow can be valued using Priceable, weighted by the average quantity
+/// across the paths. The quantities are indexed by path, then flow.
+///
+/// If the accumulator is active, the value of each path is also added to
+/// it, so the standard error can be calculated.
+pub fn evaluate_pure_rates_flows(flows: &[RcInstrument], context: &PricingContext,
+    val_date: DateTime, quantities: ArrayView2<f64>, accumulator: &PathAccumulator)
+    -> Result<f64, qm::Error> {
+
+    let n_paths_f64 = quantities.shape()[0] as f64;
+
+    // weighted sum of all of the flows
+    let mut total = 0.0;
+    let mut values = Vec::with_capacity(flows.len());
+    for (flow, quantity) in flows.iter().zip(
+        quantities.axis_iter(Axis(1))) {
+
+        // Non-stochastic-rate models can save time by evaluating the pure
+        // rate flows using Priceable
+        if flow.is_pure_rates() {
+
+            // value of the instrument times the average quantity
+            let average = check_finite(quantity.scalar_sum() / n_paths_f64,
+                "average quantity", || format!("flow {}", flow.id()))?;
+            let pricer = flow.as_priceable().ok_or_else(|| qm::Error::new(
+                "All pure-rates flows must be priceable"))?;
+            let value = check_finite(pricer.price(context, val_date)?,
+                "discounted value", || format!("flow {}", flow.id()))?;
+            total += average * value;
+            values.push(value);
+
+            //println!("evaluate_pure_rates_flows value={} average={} \
+            //    total={}", value, average, total);
 
-            } else {
+        } else {
 
-                // otherwise we must price by Monte-Carlo over each path
-                // TODO how do we pass in the weights?
-                return Err(qm::Error::new("not implemented"))
-            }
+            // otherwise we must price by Monte-Carlo over each path
+            // TODO how do we pass in the weights?
+            return Err(qm::Error::new("not implemented"))
         }
-        Ok(total)
     }
 
-    fn pricing_context(&self) -> &PricingContext {
-        &*self.context.as_pricing_context()
-    }
+    accumulator.add_flows(quantities, &values);
+    Ok(total)
 }
 
 impl Bumpable for BlackDiffusion {
@@ -548,26 +1543,29 @@ impl Bumpable for BlackDiffusion {
     fn bump(&mut self, bump: &Bump, any_saved: Option<&mut Saveable>)
         -> Result<bool, qm::Error> {
 
-        // we have to unpack the option<saveable> into options on all its
-        // components all at the same time, to avoid problems with borrowing.
-        let saved = to_saved(any_saved)?;
-        let (saved_data, saved_paths) 
-            : (Option<&mut Saveable>, Option<&mut HashMap<usize, Array2<f64>>>)
-            = if let Some(s) = saved {
-            (Some(&mut *s.saved_data), Some(&mut s.paths))
-        } else {
-            (None, None)
-        };
+        // Each sub-model saves its state in its own child of the save space,
+        // created only when the bump touches it
+        let mut saved = to_saved(any_saved)?;
 
         // bump the underlying market data (and prefetched content if any)
-        let bumped = self.context.as_mut_bumpable().bump(bump, saved_data)?;
+        let bumped = {
+            let saved_data = match saved {
+                Some(ref mut s) => Some(s.child_mut(SAVED_MARKET_DATA,
+                    || self.context.as_bumpable().new_saveable())),
+                None => None };
+            self.context.as_mut_bumpable().bump(bump, saved_data)?
+        };
 
         // refetch any paths that may have changed
         match bump {
-            &Bump::Spot(ref id, _) => self.refetch(&id, bumped, saved_paths),
-            &Bump::Divs(ref id, _) => self.refetch(&id, bumped, saved_paths),
-            &Bump::Borrow(ref id, _) => self.refetch(&id, bumped, saved_paths),
-            &Bump::Vol(ref id, _) => self.refetch(&id, bumped, saved_paths),
+            &Bump::Spot(ref id, _) => self.refetch(&id, bumped,
+                saved_paths(&mut saved, bumped)?),
+            &Bump::Divs(ref id, _) => self.refetch(&id, bumped,
+                saved_paths(&mut saved, bumped)?),
+            &Bump::Borrow(ref id, _) => self.refetch(&id, bumped,
+                saved_paths(&mut saved, bumped)?),
+            &Bump::Vol(ref id, _) => self.refetch(&id, bumped,
+                saved_paths(&mut saved, bumped)?),
             &Bump::Yield(ref credit_id, _) => {
                 // we have to copy these ids to avoid a tangle with borrowing
                 let v = self.dependencies()?
@@ -575,7 +1573,7 @@ impl Bumpable for BlackDiffusion {
 
                 // we also have to unpack then repack saved_paths to clarify borrowing
                 // (is this something the Rust compiler could be cleverer about?)
-                if let Some(s) = saved_paths {
+                if let Some(s) = saved_paths(&mut saved, bumped)? {
                     for id in v.iter() {
                         self.refetch(&id, bumped, Some(s))?;
                     }
@@ -586,6 +1584,8 @@ impl Bumpable for BlackDiffusion {
                 }
                 Ok(bumped)
             },
+            // discounting does not affect the paths
+            &Bump::Discount(_, _) => Ok(bumped),
             &Bump::SpotDate(_) => {
                 if bumped {
                     // Theta bumping in Monte-Carlo is a difficult compromise. We want to
@@ -594,16 +1594,22 @@ impl Bumpable for BlackDiffusion {
                     // first step, and more seriously we ignore changes to the form of the
                     // instruments. For example, fixings may have been passed. We need to
                     // spot this case and handle it.
+                    if let Some(s) = saved_paths(&mut saved, bumped)? {
+                        for asset in 0..self.instruments.len() {
+                            let path = self.paths.subview(Axis(2), asset);
+                            s.entry(asset).or_insert_with(|| path.to_owned());
+                        }
+                    }
                     self.refetch_all()?;
                 }
                 Ok(bumped)
-            }
+            },
+            &Bump::Correlation(_) => self.recorrelate(bumped, saved)
         }
     }
 
     fn new_saveable(&self) -> Box<Saveable> {
-        Box::new(SavedBlackDiffusion::new(
-            self.context.as_bumpable().new_saveable()))
+        Box::new(CompositeSaveable::new())
     }
 
     fn dependencies(&self) -> Result<&DependencyCollector, qm::Error> {
@@ -616,16 +1622,36 @@ impl Bumpable for BlackDiffusion {
 
     fn restore(&mut self, any_saved: &Saveable) -> Result<(), qm::Error> {
 
+        // Only the sub-models that were touched by the bump have any saved
+        // state, so each is restored only if it is there
         if let Some(saved) 
-            = any_saved.as_any().downcast_ref::<SavedBlackDiffusion>()  {
+            = any_saved.as_any().downcast_ref::<CompositeSaveable>()  {
 
             // first restore the underlying market data and cached curves
-            self.context.as_mut_bumpable().restore(&*saved.saved_data)?;
+            if let Some(saved_data) = saved.child(SAVED_MARKET_DATA) {
+                self.context.as_mut_bumpable().restore(saved_data)?;
+            }
+
+            // now restore any gaussians and short rates regenerated by a
+            // correlation bump
+            if let Some(saved_gaussians) = saved.typed_child::<SavedGaussians>(SAVED_GAUSSIANS)? {
+                if let Some(ref gaussians) = saved_gaussians.gaussians {
+                    self.correlated_gaussians = gaussians.clone();
+                }
+            }
+            if let (&mut Some(ref mut rates), Some(saved_rates))
+                = (&mut self.rates, saved.typed_child::<SavedRates>(SAVED_RATES)?) {
+                if let Some(ref ratios) = saved_rates.ratios {
+                    rates.ratios = ratios.clone();
+                }
+            }
 
-            // now restore any cached paths
-            for (asset, paths) in saved.paths.iter() {
-                let mut dest = self.paths.subview_mut(Axis(2), *asset);
-                dest.assign(paths);
+            // and any cached paths
+            if let Some(saved_paths) = saved.typed_child::<SavedPaths>(SAVED_PATHS)? {
+                for (asset, paths) in saved_paths.paths.iter() {
+                    let mut dest = self.paths.subview_mut(Axis(2), *asset);
+                    dest.assign(paths);
+                }
             }
             Ok(())
 
@@ -633,13 +1659,32 @@ impl Bumpable for BlackDiffusion {
             Err(qm::Error::new("Mismatching save space for restore"))
         }
     }
+
+    fn checkpoint(&self) -> Result<Vec<u8>, qm::Error> {
+        self.context.as_bumpable().checkpoint()
+    }
+
+    fn restore_checkpoint(&mut self, checkpoint: &[u8]) -> Result<(), qm::Error> {
+
+        // the gaussians only need regenerating if the correlations changed
+        let correlations = fetch_correlation_matrix(
+            self.context.as_pricing_context(), &self.instruments)?;
+        self.context.as_mut_bumpable().restore_checkpoint(checkpoint)?;
+        if fetch_correlation_matrix(self.context.as_pricing_context(),
+            &self.instruments)? != correlations {
+            self.recorrelate(true, None)?;
+            Ok(())
+        } else {
+            self.refetch_all()
+        }
+    }
 }
 
 fn to_saved(opt_saveable: Option<&mut Saveable>) 
-    -> Result<Option<&mut SavedBlackDiffusion>, qm::Error> {
+    -> Result<Option<&mut CompositeSaveable>, qm::Error> {
 
     if let Some(saveable) = opt_saveable {
-        if let Some(saved) = saveable.as_mut_any().downcast_mut::<SavedBlackDiffusion>() {
+        if let Some(saved) = saveable.as_mut_any().downcast_mut::<CompositeSaveable>() {
             Ok(Some(saved))
         } else {
             Err(qm::Error::new("Mismatching save space for black diffusion"))
@@ -649,29 +1694,289 @@ fn to_saved(opt_saveable: Option<&mut Saveable>)
     }
 }
 
-/// Save space for BlackDiffusion to use during bumping
-pub struct SavedBlackDiffusion {
-    saved_data: Box<Saveable>,
-    paths: HashMap<usize, Array2<f64>>
+/// The save space for the paths, if we are saving and the bump changed
+/// anything
+fn saved_paths<'a>(saved: &'a mut Option<&mut CompositeSaveable>, bumped: bool)
+    -> Result<Option<&'a mut HashMap<usize, Array2<f64>>>, qm::Error> {
+
+    match *saved {
+        Some(ref mut s) if bumped =>
+            Ok(Some(&mut s.typed_child_mut(SAVED_PATHS, SavedPaths::new)?.paths)),
+        _ => Ok(None)
+    }
 }
 
-impl SavedBlackDiffusion {
+/// The names of the sub-models of BlackDiffusion that save their state
+/// separately during bumping. See CompositeSaveable.
+pub const SAVED_MARKET_DATA: &str = "market data";
+pub const SAVED_PATHS: &str = "paths";
+pub const SAVED_GAUSSIANS: &str = "gaussians";
+pub const SAVED_RATES: &str = "rates";
+
+/// The paths of each asset from before a bump, keyed by the index of the
+/// asset
+pub struct SavedPaths {
+    paths: HashMap<usize, Array2<f64>>
+}
 
-    /// Creates an empty set of paths, which can be used for saving state
-    /// so it can be restored after a bump
-    pub fn new(saved_data: Box<Saveable>) -> SavedBlackDiffusion {
-        SavedBlackDiffusion {
-            saved_data: saved_data,
-            paths: HashMap::new() }
+impl SavedPaths {
+    pub fn new() -> SavedPaths {
+        SavedPaths { paths: HashMap::new() }
     }
 }
 
-impl Saveable for SavedBlackDiffusion {
+impl Saveable for SavedPaths {
     fn as_any(&self) -> &Any { self }
     fn as_mut_any(&mut self) -> &mut Any { self }
 
     fn clear(&mut self) {
-        self.saved_data.clear();
         self.paths.clear();
     }
 }
+
+/// The correlated gaussians from before a correlation bump
+pub struct SavedGaussians {
+    gaussians: Option<Array3<f64>>
+}
+
+impl SavedGaussians {
+    pub fn new() -> SavedGaussians {
+        SavedGaussians { gaussians: None }
+    }
+}
+
+impl Saveable for SavedGaussians {
+    fn as_any(&self) -> &Any { self }
+    fn as_mut_any(&mut self) -> &mut Any { self }
+
+    fn clear(&mut self) {
+        self.gaussians = None;
+    }
+}
+
+/// The ratios that any short rate paths apply to the assets, from before a
+/// correlation bump
+pub struct SavedRates {
+    ratios: Option<Array2<f64>>
+}
+
+impl SavedRates {
+    pub fn new() -> SavedRates {
+        SavedRates { ratios: None }
+    }
+}
+
+impl Saveable for SavedRates {
+    fn as_any(&self) -> &Any { self }
+    fn as_mut_any(&mut self) -> &mut Any { self }
+
+    fn clear(&mut self) {
+        self.ratios = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use models::random::BASE_SEED;
+    use math::numerics::approx_eq;
+    use instruments::DependencyContext;
+    use risk::cache::PricingContextPrefetch;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::marketdata::tests::sample_european;
+    use risk::marketdata::tests::sample_currency;
+    use risk::marketdata::tests::sample_equity;
+    use instruments::MonteCarloDependencies;
+    use instruments::ObservationKind;
+    use instruments::assets::RcCurrency;
+    use data::bumpvol::BumpVol;
+    use data::bumpspot::BumpSpot;
+    use data::bumpyield::BumpYield;
+    use risk::Pricer;
+    use models::RcMonteCarloModelFactory;
+    use pricers::montecarlo::MonteCarloPricer;
+    use std::f64::NAN;
+
+    #[test]
+    fn diagnostics_match_pricing_context() {
+
+        let market_data = sample_market_data();
+        let spot_date = market_data.spot_date();
+        let european = RcInstrument::new(Qrc::new(sample_european()));
+        let mut dependencies = DependencyCollector::new(spot_date);
+        dependencies.spot(&european);
+        let mut timeline = MonteCarloTimeline::new(spot_date);
+        european.as_mc_priceable().unwrap()
+            .mc_dependencies(&[], &mut timeline).unwrap();
+        timeline.collate().unwrap();
+        let context = PricingContextPrefetch::new(&market_data,
+            Arc::new(dependencies)).unwrap();
+        let factory = BlackDiffusionFactory::new(20, 0.01, 100,
+            VarianceReduction::None, Some(BASE_SEED)).unwrap();
+        let model = factory.factory(&timeline, Box::new(context)).unwrap();
+
+        // the European observes only at expiry, which the model reaches in
+        // many substeps
+        let (underlying, observations) = timeline.observations().iter().next().unwrap();
+        let expiry = *observations.last().unwrap();
+        let instr: &Instrument = underlying.deref();
+        let forward_curve = market_data.forward_curve(instr, expiry.date()).unwrap();
+        let forward = forward_curve.forward(expiry.date()).unwrap();
+        let vol_surface = market_data.vol_surface(instr, expiry.date(),
+            &|| Ok(forward_curve.clone())).unwrap();
+
+        let model_forward = model.diagnostic_forward(underlying, expiry.date()).unwrap();
+        assert!(approx_eq(model_forward, forward, 1e-12),
+            "model={} context={}", model_forward, forward);
+
+        // the sample vol surface is flat at 30%
+        let variance = model.diagnostic_total_variance(underlying, expiry.date()).unwrap();
+        let expected = 0.3 * 0.3 * vol_surface.vol_time(expiry).unwrap();
+        assert!(approx_eq(variance, expected, 1e-12),
+            "model={} expected={}", variance, expected);
+        assert!(approx_eq(variance, vol_surface.variance(expiry, forward).unwrap(), 1e-12));
+
+        // dates that are not in the timeline are an error
+        assert!(model.diagnostic_forward(underlying, spot_date + 7).is_err());
+    }
+
+    #[test]
+    fn spot_floor_keeps_log_returns_finite() {
+
+        // At a vol of 4000% to an expiry about 17 months away, the variance
+        // is over 2000, so most of the paths underflow to zero. A single
+        // step is exact, and avoids hundreds of thousands of substeps.
+        let mut market_data = sample_market_data();
+        let bump = Bump::new_vol("BP.L", BumpVol::new_replace(40.0));
+        assert!(market_data.bump(&bump, None).unwrap());
+        let european = RcInstrument::new(Qrc::new(sample_european()));
+        let unfloored = BlackDiffusionFactory::new(20, 1e4, 2000,
+            VarianceReduction::None, Some(BASE_SEED)).unwrap()
+            .with_discretization(DiscretizationScheme::Exact);
+        let floored = unfloored.clone().with_spot_floor(DEFAULT_SPOT_FLOOR);
+
+        let log_returns = |factory: &BlackDiffusionFactory| {
+            let spot_date = market_data.spot_date();
+            let mut dependencies = DependencyCollector::new(spot_date);
+            dependencies.spot(&european);
+            let mut timeline = MonteCarloTimeline::new(spot_date);
+            european.as_mc_priceable().unwrap()
+                .mc_dependencies(&[], &mut timeline).unwrap();
+            timeline.collate().unwrap();
+            let context = PricingContextPrefetch::new(&market_data,
+                Arc::new(dependencies)).unwrap();
+            let model = factory.factory(&timeline, Box::new(context)).unwrap();
+            let (underlying, _) = timeline.observations().iter().next().unwrap();
+            let paths = model.paths(underlying).unwrap();
+            paths.iter().map(|spot| (spot / 100.0).ln()).collect::<Vec<f64>>()
+        };
+
+        let unfloored_returns = log_returns(&unfloored);
+        assert!(unfloored_returns.iter().any(|r| !r.is_finite()));
+        let floored_returns = log_returns(&floored);
+        assert!(floored_returns.iter().all(|r| r.is_finite()));
+
+        // the floor only affects paths far below the strike, so the
+        // prices agree
+        let price = |factory: BlackDiffusionFactory| {
+            let model_factory = RcMonteCarloModelFactory::new(Arc::new(factory));
+            MonteCarloPricer::new(vec!((1.0, european.clone())), model_factory,
+                &market_data).unwrap().price_with_stderr().unwrap()
+        };
+        let (unfloored_price, stderr) = price(unfloored);
+        let (floored_price, _) = price(floored);
+        assert!(floored_price.is_finite() && unfloored_price.is_finite());
+        assert!((floored_price - unfloored_price).abs() <= 3.0 * stderr,
+            "floored={} unfloored={} stderr={}", floored_price, unfloored_price, stderr);
+
+        // floors that are not tiny positive fractions are rejected
+        for &floor in [0.0, -1e-100, 0.01, NAN].iter() {
+            let factory = BlackDiffusionFactory::new(20, 1e4, 100,
+                VarianceReduction::None, Some(BASE_SEED)).unwrap().with_spot_floor(floor);
+            let model_factory = RcMonteCarloModelFactory::new(Arc::new(factory));
+            assert!(MonteCarloPricer::new(vec!((1.0, european.clone())),
+                model_factory, &market_data).is_err(), "floor={}", floor);
+        }
+    }
+
+    #[test]
+    fn substeps_are_validated_and_recommended() {
+
+        assert!(BlackDiffusionFactory::new(0, 0.01, 100,
+            VarianceReduction::None, Some(BASE_SEED)).is_err());
+        for &path_substep in [0.0, -0.01, NAN].iter() {
+            assert!(BlackDiffusionFactory::new(20, path_substep, 100,
+                VarianceReduction::None, Some(BASE_SEED)).is_err());
+        }
+
+        // the recommendations scale with the span of the timeline
+        let spot_date = Date::from_ymd(2017, 01, 02);
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        let equity = RcInstrument::new(Qrc::new(Arc::new(sample_equity(currency, 2))));
+        let recommended = |days: i32| {
+            let mut timeline = MonteCarloTimeline::new(spot_date);
+            timeline.observation(&equity, DateDayFraction::new(spot_date + days, 0.8),
+                ObservationKind::Fixing);
+            timeline.collate().unwrap();
+            BlackDiffusionFactory::recommended_substeps(&timeline)
+        };
+        let (short_correlation, short_path) = recommended(100);
+        let (long_correlation, long_path) = recommended(500);
+        assert_eq!(short_correlation, 4);
+        assert_eq!(long_correlation, 20);
+        assert!(approx_eq(long_path, 5.0 * short_path, 1e-15),
+            "short={} long={}", short_path, long_path);
+
+        // and are themselves valid
+        assert!(BlackDiffusionFactory::new(short_correlation, short_path, 100,
+            VarianceReduction::None, Some(BASE_SEED)).is_ok());
+        let empty = {
+            let mut timeline = MonteCarloTimeline::new(spot_date);
+            timeline.collate().unwrap();
+            BlackDiffusionFactory::recommended_substeps(&timeline)
+        };
+        assert_eq!(empty.0, 1);
+        assert!(empty.1 > 0.0);
+    }
+
+    #[test]
+    fn stochastic_rates_save_only_bumped_state() {
+
+        let market_data = sample_market_data();
+        let european = RcInstrument::new(Qrc::new(sample_european()));
+        let factory = BlackDiffusionFactory::new(20, 0.01, 2000,
+            VarianceReduction::None, Some(BASE_SEED)).unwrap()
+            .with_stochastic_rates(StochasticRates {
+                mean_reversion: 0.1, volatility: 0.02, correlation: 0.5 });
+        let model_factory = RcMonteCarloModelFactory::new(Arc::new(factory));
+        let mut pricer = MonteCarloPricer::new(vec!((1.0, european)),
+            model_factory, &market_data).unwrap();
+        let unbumped = pricer.price().unwrap();
+
+        // a spot bump touches the market data and the paths of the one
+        // asset, but not the gaussians or the short rate
+        let mut saveable = pricer.new_saveable();
+        let bump = Bump::new_spot("BP.L", BumpSpot::new_relative(0.01));
+        assert!(pricer.bump(&bump, Some(&mut *saveable)).unwrap());
+        assert!(pricer.price().unwrap() > unbumped);
+        {
+            let saved = saveable.as_any().downcast_ref::<CompositeSaveable>().unwrap();
+            assert_eq!(saved.keys(), vec!(SAVED_MARKET_DATA, SAVED_PATHS));
+            let paths = saved.typed_child::<SavedPaths>(SAVED_PATHS).unwrap().unwrap();
+            assert_eq!(paths.paths.keys().cloned().collect::<Vec<_>>(), vec!(0));
+        }
+
+        // a yield bump on top, saved in the same space, restores with it
+        let bump = Bump::new_yield("LSE", BumpYield::new_flat_annualised(0.01));
+        assert!(pricer.bump(&bump, Some(&mut *saveable)).unwrap());
+        pricer.restore(&*saveable).unwrap();
+        assert_eq!(pricer.price().unwrap(), unbumped);
+
+        // once cleared, restoring touches nothing
+        saveable.clear();
+        assert!(saveable.as_any().downcast_ref::<CompositeSaveable>().unwrap()
+            .keys().is_empty());
+        pricer.restore(&*saveable).unwrap();
+        assert_eq!(pricer.price().unwrap(), unbumped);
+    }
+}
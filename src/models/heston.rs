@@ -0,0 +1,899 @@
+use std::any::Any;
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::sync::Arc;
+use ndarray::Array1;
+use ndarray::Array2;
+use ndarray::Array3;
+use ndarray::ArrayView2;
+use ndarray::ArrayViewMut2;
+use ndarray::Axis;
+use core::qm;
+use math::numerics::check_finite;
+use instruments::Instrument;
+use instruments::MonteCarloContext;
+use instruments::PricingContext;
+use instruments::RcInstrument;
+use risk::BumpablePricingContext;
+use risk::Bumpable;
+use risk::Saveable;
+use risk::marketdata::MarketData;
+use risk::dependencies::DependencyCollector;
+use data::bump::Bump;
+use models::MonteCarloModel;
+use models::MonteCarloTimeline;
+use models::MonteCarloModelFactory;
+use models::VarianceReduction;
+use models::PathAccumulator;
+use models::ProgressCallback;
+use models::progress_batch_size;
+use math::moments::RunningMoments;
+use models::random::RandomSourceType;
+use models::random::RngAlgorithm;
+use models::random::entropy_seed;
+use models::blackdiffusion::default_threads;
+use models::blackdiffusion::fetch_correlation_matrix;
+use models::blackdiffusion::correlated_gaussians;
+use models::blackdiffusion::evaluate_pure_rates_flows;
+use dates::datetime::DateDayFraction;
+use dates::datetime::DateTime;
+use dates::datetime::TimeOfDay;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use serde::Deserialize;
+use erased_serde as esd;
+
+/// The HestonFactory creates a Heston stochastic volatility model, given
+/// the timeline of the product(s) to value, and the market data to value it
+/// with. The factory holds the five Heston parameters, which are applied to
+/// every asset, as well as the correlation substep and the number of paths.
+///
+/// As for the BlackDiffusionFactory, the paths are seeded with any seed
+/// supplied, or otherwise with one drawn from the operating system's entropy
+/// when the factory is created, and their random numbers can be generated
+/// on several threads without affecting the results.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct HestonFactory {
+    /// Substep size in business days for correlation calculation
+    correlation_substep: usize,
+    number_of_paths: usize,
+    parameters: HestonParameters,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    seed: Option<u64>,
+    #[serde(default = "default_threads")]
+    threads: usize,
+    #[serde(skip, default = "entropy_seed")]
+    default_seed: u64,
+    #[serde(skip)]
+    progress: Option<ProgressCallback>
+}
+
+/// The parameters of the Heston model. The variance process is
+///
+///  dv = kappa (theta - v) dt + sigma sqrt(v) dZ
+///
+/// starting at v0, where dZ has correlation rho with the Brownian motion
+/// driving the spot.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct HestonParameters {
+    pub v0: f64,
+    pub kappa: f64,
+    pub theta: f64,
+    pub sigma: f64,
+    pub rho: f64
+}
+
+impl HestonParameters {
+    /// Validates that the parameters make sense
+    pub fn validate(&self) -> Result<(), qm::Error> {
+        if self.v0 < 0.0 || self.theta < 0.0 {
+            return Err(qm::Error::new("Heston variances must not be negative"))
+        }
+        if self.kappa < 0.0 || self.sigma < 0.0 {
+            return Err(qm::Error::new("Heston mean reversion and vol of vol \
+                must not be negative"))
+        }
+        if self.rho < -1.0 || self.rho > 1.0 {
+            return Err(qm::Error::new("Heston correlation must be between \
+                -1 and 1"))
+        }
+        Ok(())
+    }
+}
+
+impl HestonFactory {
+    pub fn new(correlation_substep: usize, number_of_paths: usize,
+        v0: f64, kappa: f64, theta: f64, sigma: f64, rho: f64)
+        -> HestonFactory {
+
+        HestonFactory { correlation_substep, number_of_paths,
+            parameters: HestonParameters { v0, kappa, theta, sigma, rho },
+            seed: None, threads: default_threads(), default_seed: entropy_seed(),
+            progress: None }
+    }
+
+    /// Selects the pseudo-random stream, so that a given set of inputs
+    /// always gives exactly the same price
+    pub fn with_seed(mut self, seed: u64) -> HestonFactory {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// Sets the number of threads used for generating the random numbers
+    /// for the paths. See BlackDiffusionFactory::with_threads.
+    pub fn with_threads(mut self, threads: usize) -> HestonFactory {
+        self.threads = threads.max(1);
+        self
+    }
+
+    /// Supplies a callback to report progress as the paths are evolved,
+    /// both initially and when they are regenerated for bumped repricings.
+    /// The callback is not serialized.
+    pub fn with_progress(mut self, progress: ProgressCallback) -> HestonFactory {
+        self.progress = Some(progress);
+        self
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<MonteCarloModelFactory>, esd::Error> {
+        Ok(Qrc::new(Arc::new(HestonFactory::deserialize(de)?)))
+    }
+}
+
+impl TypeId for HestonFactory {
+    fn get_type_id(&self) -> &'static str { "HestonFactory" }
+}
+
+impl MonteCarloModelFactory for HestonFactory {
+
+    fn factory(&self, timeline: &MonteCarloTimeline,
+        context: Box<BumpablePricingContext>)
+        -> Result<Box<MonteCarloModel>, qm::Error> {
+
+        let model = Heston::new(timeline, context, self.correlation_substep,
+            self.number_of_paths, self.parameters,
+            self.seed.unwrap_or(self.default_seed), self.threads,
+            self.progress.clone())?;
+        Ok(Box::new(model))
+    }
+
+    fn threads(&self) -> usize {
+        self.threads
+    }
+}
+
+/// The largest step in vol time that the Heston model takes along a path.
+/// The Euler scheme for the variance is only accurate for small steps.
+const MAX_TIME_STEP: f64 = 1.0 / 52.0;
+
+/// A Heston model represents the SDEs:
+///
+///  dS/S = mu(t) dt + sqrt(v) dW
+///  dv = kappa (theta - v) dt + sigma sqrt(v) dZ
+///
+/// where dW and dZ have correlation rho. Spot/spot correlations between
+/// assets are taken from the pricing context, as for BlackDiffusion. The
+/// correlation between one asset's spot and another asset's variance is rho
+/// times the spot/spot correlation, which keeps the full correlation matrix
+/// positive semi-definite.
+///
+/// As with BlackDiffusion, we work with an underlier scaled such that
+/// mu(t) = 0, so the forward curve only scales the paths. An asset diffused
+/// under the measure of another currency has the quanto drift correction
+/// -rho_X sigma_X sqrt(v) added to mu(t), which depends on the path, so it
+/// is applied when the martingales are evolved. Time is measured
+/// in vol time, as defined by the vol surface of each asset. The vol surface
+/// itself is only used for its measure of time and its displacement; the
+/// volatility comes entirely from the Heston parameters.
+///
+/// The variance is evolved with a full-truncation Euler scheme, where the
+/// variance may go negative, but is floored at zero wherever it is used for
+/// the drift or diffusion. The spot is evolved in log space, so it always
+/// stays positive.
+///
+/// The martingale paths only depend on the gaussians and the times of the
+/// observations, so they are generated once. Bumps to forwards are handled
+/// by rescaling them.
+#[derive(Clone)]
+pub struct Heston {
+    observations: Vec<DateDayFraction>,
+    flows: Vec<RcInstrument>,
+    context: Box<BumpablePricingContext>,
+    key: HashMap<String, usize>,
+    instruments: Vec<RcInstrument>,
+    martingales: Array3<f64>,
+    paths: Array3<f64>,
+    accumulator: PathAccumulator,
+    progress: Option<ProgressCallback>
+}
+
+impl Heston {
+
+    /// Create a new Heston model, given a timeline to define the
+    /// instrument(s) we want to price, a context to define the market data,
+    /// a count of paths and the Heston parameters. The seed selects the
+    /// pseudo-random stream, and n_threads is the number of threads used
+    /// to generate it. The progress callback, if any, reports as the paths
+    /// are evolved.
+    pub fn new(timeline: &MonteCarloTimeline,
+        context: Box<BumpablePricingContext>,
+        _correlation_substep: usize,
+        n_paths: usize,
+        parameters: HestonParameters,
+        seed: u64,
+        n_threads: usize,
+        progress: Option<ProgressCallback>)
+        -> Result<Heston, qm::Error> {
+
+        parameters.validate()?;
+
+        // key to all observations and all instruments
+        let mut observations = Vec::new();
+        let mut key = HashMap::new();
+        let mut instruments = Vec::new();
+        for (asset, obs) in timeline.observations().iter() {
+
+            // at present, we just insist that all observations are the same
+            if observations.is_empty() {
+                observations = obs.to_vec();
+            }
+
+            // store the assets in the order we are told about them
+            key.insert(asset.id().to_string(), instruments.len());
+            instruments.push(asset.clone());
+        }
+
+        // Fetch the vol times of the observations for each asset, and use
+        // them to decide how many substeps we need, then space and refine
+        // them if the timeline asks
+        let times = fetch_times(&observations, context.as_pricing_context(),
+            &instruments)?;
+        let mut substepping = vec!(1_usize; observations.len());
+        for asset_times in times.iter() {
+            let mut prev_time = 0.0;
+            for (time, substep) in asset_times.iter().zip(substepping.iter_mut()) {
+                let steps = ((time - prev_time) / MAX_TIME_STEP).ceil() as usize;
+                *substep = (*substep).max(steps);
+                prev_time = *time;
+            }
+        }
+        timeline.adjust_substepping(&observations, &mut substepping);
+
+        // Generate gaussians for the spots then the variances of all the
+        // assets, using the spot correlations from the context combined with
+        // the Heston spot/vol correlation
+        let spot_correl = fetch_correlation_matrix(
+            context.as_pricing_context(), &instruments)?;
+        let n_assets = instruments.len();
+        let mut correl = Array2::<f64>::zeros((2 * n_assets, 2 * n_assets));
+        for i in 0..n_assets {
+            for j in 0..n_assets {
+                let c = spot_correl[(i, j)];
+                correl[(i, j)] = c;
+                correl[(n_assets + i, n_assets + j)] = c;
+                correl[(i, n_assets + j)] = c * parameters.rho;
+                correl[(n_assets + i, j)] = c * parameters.rho;
+            }
+        }
+        let gaussians = correlated_gaussians(&correl, &substepping, n_paths,
+            VarianceReduction::None, RandomSourceType::Pseudo, Some(seed),
+            RngAlgorithm::default(), None, 0, n_threads)?;
+
+        // Evolve the martingales, with any quanto drift, then scale them by
+        // the forwards
+        let n_obs = observations.len();
+        let mut martingales = Array3::<f64>::zeros((n_paths, n_obs, n_assets));
+        for (asset, asset_times) in times.iter().enumerate() {
+            let quanto_drift = match timeline.quanto_currency(&instruments[asset])? {
+                Some(currency) => context.as_pricing_context().quanto_drift(
+                    instruments[asset].deref(), &currency)?,
+                None => 0.0
+            };
+            evolve_martingale(&parameters, quanto_drift, asset_times, &substepping,
+                gaussians.subview(Axis(2), asset),
+                gaussians.subview(Axis(2), n_assets + asset),
+                martingales.subview_mut(Axis(2), asset), progress.as_ref());
+        }
+
+        // scaling by the forwards is cheap, so is not reported here
+        let mut paths = Array3::<f64>::zeros((n_paths, n_obs, n_assets));
+        for (asset, instrument) in instruments.iter().enumerate() {
+            fetch_path(instrument.deref(), context.as_pricing_context(),
+                &observations, martingales.subview(Axis(2), asset),
+                paths.subview_mut(Axis(2), asset), None)?;
+        }
+
+        Ok(Heston {
+            observations,
+            flows: timeline.flows().to_vec(),
+            context,
+            key,
+            instruments,
+            martingales,
+            paths,
+            accumulator: PathAccumulator::new(),
+            progress })
+    }
+
+    /// Refetch a single asset
+    pub fn refetch(&mut self, id: &str, bumped: bool,
+        saved_paths: Option<&mut SavedPaths>) -> Result<bool, qm::Error> {
+
+        // if nothing was bumped, there is nothing to do
+        if !bumped {
+            return Ok(false)
+        }
+
+        let id_string = id.to_string();
+        if let Some(asset) = self.key.get(&id_string) {
+
+            // save the old path (unless already saved) then replace it
+            let path = self.paths.subview_mut(Axis(2), *asset);
+            if let Some(s) = saved_paths {
+                s.entry(*asset).or_insert_with(|| path.to_owned());
+            }
+            fetch_path(self.instruments[*asset].deref(),
+                self.context.as_pricing_context(), &self.observations,
+                self.martingales.subview(Axis(2), *asset), path,
+                self.progress.as_ref())?;
+
+        } else {
+            return Err(qm::Error::new("Failed to find asset"))
+        }
+
+        Ok(true)
+    }
+
+    /// Refetch all paths for all assets. Note that this does not regenerate
+    /// the martingales, so ignores any change in vol times.
+    pub fn refetch_all(&mut self) -> Result<(), qm::Error> {
+        for (asset, instrument) in self.instruments.iter().enumerate() {
+            fetch_path(instrument.deref(), self.context.as_pricing_context(),
+                &self.observations, self.martingales.subview(Axis(2), asset),
+                self.paths.subview_mut(Axis(2), asset), self.progress.as_ref())?;
+        }
+        Ok(())
+    }
+}
+
+/// Fetch the vol times of each observation for each asset, indexed by asset
+/// then observation
+fn fetch_times(observations: &[DateDayFraction], context: &PricingContext,
+    instruments: &[RcInstrument]) -> Result<Vec<Vec<f64>>, qm::Error> {
+
+    if observations.is_empty() {
+        return Err(qm::Error::new("No observations"))
+    }
+    let hwm = observations.last().unwrap().date();
+
+    let mut times = Vec::with_capacity(instruments.len());
+    for instrument in instruments.iter() {
+        let instr: &Instrument = instrument.deref();
+        let fwd = context.forward_curve(instr, hwm)?;
+        let surface = context.vol_surface(instr, hwm, &|| Ok(fwd.clone()))?;
+
+        let mut asset_times = Vec::with_capacity(observations.len());
+        let mut prev_time = 0.0;
+        for obs in observations.iter() {
+            let time = surface.vol_time(*obs)?;
+            if time < prev_time {
+                return Err(qm::Error::new("Observations must be in order"))
+            }
+            asset_times.push(time);
+            prev_time = time;
+        }
+        times.push(asset_times);
+    }
+    Ok(times)
+}
+
+/// Evolve the martingale paths of a single asset, given the gaussians that
+/// drive the spot and the variance. Paths start at one at vol time zero.
+/// The quanto drift is multiplied by the instantaneous vol and added to the
+/// drift of the log spot, so the paths are only martingales if it is zero.
+/// If there is a progress callback, the paths are evolved in batches,
+/// reporting progress after each batch.
+fn evolve_martingale(parameters: &HestonParameters, quanto_drift: f64, times: &[f64],
+    substepping: &[usize], spot_gaussians: ArrayView2<f64>,
+    vol_gaussians: ArrayView2<f64>, mut martingales: ArrayViewMut2<f64>,
+    progress: Option<&ProgressCallback>) {
+
+    let n_paths = martingales.shape()[0];
+    let batch_size = progress_batch_size(progress, n_paths);
+    if let Some(progress) = progress {
+        progress.start(n_paths);
+    }
+    for ((spot_batch, vol_batch), mut batch) in spot_gaussians
+        .axis_chunks_iter(Axis(0), batch_size)
+        .zip(vol_gaussians.axis_chunks_iter(Axis(0), batch_size))
+        .zip(martingales.axis_chunks_iter_mut(Axis(0), batch_size)) {
+
+        let batch_paths = batch.shape()[0];
+        for ((spot_draws, vol_draws), mut path) in spot_batch.outer_iter()
+            .zip(vol_batch.outer_iter()).zip(batch.outer_iter_mut()) {
+
+            let mut log_spot = 0.0;
+            let mut variance = parameters.v0;
+            let mut prev_time = 0.0;
+            let mut g = 0;  // index into the gaussians
+            for (i, time) in times.iter().enumerate() {
+                let dt = (time - prev_time) / substepping[i] as f64;
+                let sqrt_dt = dt.sqrt();
+                for _ in 0..substepping[i] {
+
+                    // full truncation: floor the variance at zero wherever
+                    // it is used, but let the state itself go negative
+                    let v = variance.max(0.0);
+                    let sqrt_v = v.sqrt();
+                    log_spot += -0.5 * v * dt + quanto_drift * sqrt_v * dt
+                        + sqrt_v * sqrt_dt * spot_draws[g];
+                    variance += parameters.kappa * (parameters.theta - v) * dt
+                        + parameters.sigma * sqrt_v * sqrt_dt * vol_draws[g];
+                    g += 1;
+                }
+                path[i] = log_spot.exp();
+                prev_time = *time;
+            }
+        }
+
+        if let Some(progress) = progress {
+            progress.report(batch_paths);
+        }
+    }
+}
+
+/// Scale the martingale paths of a single asset by its forwards
+fn fetch_path(instrument: &Instrument, context: &PricingContext,
+    observations: &[DateDayFraction], martingales: ArrayView2<f64>,
+    mut path: ArrayViewMut2<f64>, progress: Option<&ProgressCallback>)
+    -> Result<(), qm::Error> {
+
+    let n_obs = observations.len();
+    assert!(n_obs > 0);  // otherwise we should not be evolving this asset
+    let hwm = observations.last().unwrap().date();
+    let forward_curve = context.forward_curve(instrument, hwm)?;
+    let vol_surface = context.vol_surface(instrument, hwm, &|| Ok(forward_curve.clone()))?;
+
+    let mut forwards = Vec::with_capacity(n_obs);
+    let mut displacements = Vec::with_capacity(n_obs);
+    for obs in observations.iter() {
+        let displacement = check_finite(vol_surface.displacement(obs.date())?,
+            "displacement", || format!("{} on {}", instrument.id(), obs.date()))?;
+        let forward = check_finite(forward_curve.forward(obs.date())?, "forward",
+            || format!("{} on {}", instrument.id(), obs.date()))?;
+        displacements.push(displacement);
+        forwards.push(forward - displacement);
+    }
+
+    let n_paths = martingales.shape()[0];
+    let batch_size = progress_batch_size(progress, n_paths);
+    if let Some(progress) = progress {
+        progress.start(n_paths);
+    }
+    for (martingale_batch, mut batch) in martingales.axis_chunks_iter(Axis(0), batch_size)
+        .zip(path.axis_chunks_iter_mut(Axis(0), batch_size)) {
+
+        let batch_paths = batch.shape()[0];
+        for (martingale, mut one_path) in martingale_batch.outer_iter()
+            .zip(batch.outer_iter_mut()) {
+            for i in 0..n_obs {
+                one_path[i] = martingale[i] * forwards[i] + displacements[i];
+            }
+        }
+
+        if let Some(progress) = progress {
+            progress.report(batch_paths);
+        }
+    }
+    Ok(())
+}
+
+impl MonteCarloModel for Heston {
+
+    fn as_mc_context(&self) -> &MonteCarloContext { self }
+    fn as_bumpable(&self) -> &Bumpable { self }
+    fn as_mut_bumpable(&mut self) -> &mut Bumpable { self }
+    fn raw_market_data(&self) -> &MarketData { self.context.raw_market_data() }
+    fn bumpable_context(&self) -> &BumpablePricingContext { &*self.context }
+
+    fn accumulate_paths(&self, weight: Option<f64>) {
+        self.accumulator.set_weight(weight);
+    }
+
+    fn take_path_values(&self) -> Option<Array1<f64>> {
+        self.accumulator.take(false)
+    }
+
+    fn stream_path_moments(&self) {
+        self.accumulator.stream_moments(false)
+    }
+
+    fn take_path_moments(&self) -> Option<RunningMoments> {
+        self.accumulator.take_moments(false)
+    }
+}
+
+impl MonteCarloContext for Heston {
+
+    fn paths(&self, instrument: &RcInstrument)
+        -> Result<ArrayView2<f64>, qm::Error> {
+
+        let id = instrument.id().to_string();
+        let asset = self.key.get(&id).ok_or_else(|| qm::Error::new(
+            &format!("Heston does not know about '{}'", id)))?;
+        Ok(self.paths.subview(Axis(2), *asset))
+    }
+
+    fn evaluate_flows(&self, quantities: ArrayView2<f64>)
+        -> Result<f64, qm::Error> {
+
+        let flows_shape = quantities.shape();
+        assert_eq!(flows_shape[0], self.paths.shape()[0]);
+        assert_eq!(flows_shape[1], self.flows.len());
+
+        // For now, always value as of the spot date at the open
+        let val_date = DateTime::new(self.context.as_pricing_context().spot_date(), TimeOfDay::Open);
+
+        // Heston has deterministic rates, so we can value flows analytically
+        evaluate_pure_rates_flows(&self.flows, self.context.as_pricing_context(),
+            val_date, quantities, &self.accumulator)
+    }
+
+    fn pricing_context(&self) -> &PricingContext {
+        self.context.as_pricing_context()
+    }
+}
+
+impl Bumpable for Heston {
+
+    fn bump(&mut self, bump: &Bump, any_saved: Option<&mut Saveable>)
+        -> Result<bool, qm::Error> {
+
+        // unpack the option<saveable> into its components
+        let saved = to_saved(any_saved)?;
+        let (saved_data, saved_paths)
+            : (Option<&mut Saveable>, Option<&mut SavedPaths>)
+            = if let Some(s) = saved {
+            (Some(&mut *s.saved_data), Some(&mut s.paths))
+        } else {
+            (None, None)
+        };
+
+        // The correlations are baked into the paths when they are generated,
+        // so a correlation bump is ignored, without touching the market data.
+        // Reports such as cega then skip the pair.
+        if let Bump::Correlation(_) = *bump {
+            return Ok(false)
+        }
+
+        // bump the underlying market data (and prefetched content if any)
+        let bumped = self.context.as_mut_bumpable().bump(bump, saved_data)?;
+
+        // refetch any paths that may have changed
+        match *bump {
+            Bump::Spot(ref id, _) => self.refetch(id, bumped, saved_paths),
+            Bump::Divs(ref id, _) => self.refetch(id, bumped, saved_paths),
+            Bump::Borrow(ref id, _) => self.refetch(id, bumped, saved_paths),
+            Bump::Vol(ref id, _) => self.refetch(id, bumped, saved_paths),
+            Bump::Yield(ref credit_id, _) => {
+                let v = self.dependencies()?
+                    .forward_id_by_credit_id(credit_id).to_vec();
+                if let Some(s) = saved_paths {
+                    for id in v.iter() {
+                        self.refetch(id, bumped, Some(s))?;
+                    }
+                } else {
+                    for id in v.iter() {
+                        self.refetch(id, bumped, None)?;
+                    }
+                }
+                Ok(bumped)
+            },
+            // discounting does not affect the paths
+            Bump::Discount(_, _) => Ok(bumped),
+            Bump::SpotDate(_) => {
+                if bumped {
+                    if let Some(s) = saved_paths {
+                        for asset in 0..self.instruments.len() {
+                            let path = self.paths.subview(Axis(2), asset);
+                            s.entry(asset).or_insert_with(|| path.to_owned());
+                        }
+                    }
+                    self.refetch_all()?;
+                }
+                Ok(bumped)
+            },
+            // rejected above
+            Bump::Correlation(_) => Ok(bumped)
+        }
+    }
+
+    fn new_saveable(&self) -> Box<Saveable> {
+        Box::new(SavedHeston::new(
+            self.context.as_bumpable().new_saveable()))
+    }
+
+    fn dependencies(&self) -> Result<&DependencyCollector, qm::Error> {
+        self.context.dependencies()
+    }
+
+    fn context(&self) -> &PricingContext {
+        self.context.as_pricing_context()
+    }
+
+    fn restore(&mut self, any_saved: &Saveable) -> Result<(), qm::Error> {
+
+        if let Some(saved) = any_saved.as_any().downcast_ref::<SavedHeston>() {
+
+            // first restore the underlying market data and cached curves
+            self.context.as_mut_bumpable().restore(&*saved.saved_data)?;
+
+            // now restore any cached paths
+            for (asset, paths) in saved.paths.iter() {
+                let mut dest = self.paths.subview_mut(Axis(2), *asset);
+                dest.assign(paths);
+            }
+            Ok(())
+
+        } else {
+            Err(qm::Error::new("Mismatching save space for restore"))
+        }
+    }
+
+    fn checkpoint(&self) -> Result<Vec<u8>, qm::Error> {
+        self.context.as_bumpable().checkpoint()
+    }
+
+    fn restore_checkpoint(&mut self, checkpoint: &[u8]) -> Result<(), qm::Error> {
+        self.context.as_mut_bumpable().restore_checkpoint(checkpoint)?;
+        self.refetch_all()
+    }
+}
+
+fn to_saved(opt_saveable: Option<&mut Saveable>)
+    -> Result<Option<&mut SavedHeston>, qm::Error> {
+
+    if let Some(saveable) = opt_saveable {
+        if let Some(saved) = saveable.as_mut_any().downcast_mut::<SavedHeston>() {
+            Ok(Some(saved))
+        } else {
+            Err(qm::Error::new("Mismatching save space for Heston"))
+        }
+    } else {
+        Ok(None)
+    }
+}
+
+/// Paths of individual assets, keyed by their index in the model
+type SavedPaths = HashMap<usize, Array2<f64>>;
+
+/// Save space for Heston to use during bumping
+pub struct SavedHeston {
+    saved_data: Box<Saveable>,
+    paths: SavedPaths
+}
+
+impl SavedHeston {
+
+    /// Creates an empty set of paths, which can be used for saving state
+    /// so it can be restored after a bump
+    pub fn new(saved_data: Box<Saveable>) -> SavedHeston {
+        SavedHeston {
+            saved_data,
+            paths: HashMap::new() }
+    }
+}
+
+impl Saveable for SavedHeston {
+    fn as_any(&self) -> &Any { self }
+    fn as_mut_any(&mut self) -> &mut Any { self }
+
+    fn clear(&mut self) {
+        self.saved_data.clear();
+        self.paths.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use models::random::BASE_SEED;
+    use std::sync::Mutex;
+    use math::numerics::approx_eq;
+    use data::bumpspot::BumpSpot;
+    use data::fixings::FixingTable;
+    use data::fixings::RcFixingTable;
+    use dates::Date;
+    use risk::marketdata::RcMarketData;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::marketdata::tests::sample_european;
+    use pricers::PricerFactory;
+    use pricers::montecarlo::MonteCarloPricerFactory;
+    use models::RcMonteCarloModelFactory;
+    use risk::Pricer;
+    use serde_json;
+    use ndarray::arr2;
+    use std::f64::NAN;
+    use data::bumpcorrelation::BumpCorrelation;
+
+    fn sample_fixings() -> FixingTable {
+        let today = Date::from_ymd(2017, 01, 02);
+        FixingTable::from_fixings(today, &[
+            ("BP.L", &[
+            (DateTime::new(today - 7, TimeOfDay::Close), 102.0)])]).unwrap()
+    }
+
+    fn heston_pricer(n_paths: usize, v0: f64, kappa: f64, theta: f64,
+        sigma: f64, rho: f64) -> Box<Pricer> {
+
+        let market_data = RcMarketData::new(Arc::new(sample_market_data()));
+        let instrument = RcInstrument::new(Qrc::new(sample_european()));
+        let fixings = RcFixingTable::new(Arc::new(sample_fixings()));
+
+        let model_factory = RcMonteCarloModelFactory::new(Arc::new(
+            HestonFactory::new(20, n_paths, v0, kappa, theta, sigma, rho).with_seed(BASE_SEED)));
+        let factory = MonteCarloPricerFactory::new(model_factory);
+        factory.new(instrument, fixings, market_data).unwrap()
+    }
+
+    #[test]
+    fn heston_with_no_vol_of_vol_matches_black_scholes() {
+
+        // The sample vol surface is flat at 30%. With the variance starting
+        // at and reverting to 0.09 and a tiny vol of vol, Heston collapses
+        // to Black-Scholes, so we should match the analytic price within the
+        // Monte-Carlo error.
+        let pricer = heston_pricer(100000, 0.09, 1.0, 0.09, 1e-6, -0.5);
+        let price = pricer.price().unwrap();
+        assert_approx(price, 16.710717400832973, 0.15);
+    }
+
+    #[test]
+    fn heston_price_is_independent_of_threads() {
+        let market_data = RcMarketData::new(Arc::new(sample_market_data()));
+        let instrument = RcInstrument::new(Qrc::new(sample_european()));
+        let fixings = RcFixingTable::new(Arc::new(sample_fixings()));
+
+        let mut prices = Vec::new();
+        for &threads in [1, 4].iter() {
+            let factory = HestonFactory::new(20, 5000, 0.09, 1.0, 0.09, 0.3, -0.5)
+                .with_seed(BASE_SEED).with_threads(threads);
+            assert_eq!(factory.threads(), threads);
+            let factory = MonteCarloPricerFactory::new(
+                RcMonteCarloModelFactory::new(Arc::new(factory)));
+            let pricer = factory.new(instrument.clone(), fixings.clone(),
+                market_data.clone()).unwrap();
+            prices.push(pricer.price().unwrap());
+        }
+        assert_approx(prices[1], prices[0], 1e-9);
+    }
+
+    #[test]
+    fn heston_reports_progress_over_bumps() {
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let recorder = reports.clone();
+        let progress = ProgressCallback::new(250, move |completed, total|
+            recorder.lock().unwrap().push((completed, total)));
+
+        let market_data = RcMarketData::new(Arc::new(sample_market_data()));
+        let instrument = RcInstrument::new(Qrc::new(sample_european()));
+        let fixings = RcFixingTable::new(Arc::new(sample_fixings()));
+        let model_factory = RcMonteCarloModelFactory::new(Arc::new(
+            HestonFactory::new(20, 1000, 0.09, 1.0, 0.09, 0.3, -0.5).with_seed(BASE_SEED)
+            .with_progress(progress)));
+        let factory = MonteCarloPricerFactory::new(model_factory);
+        let mut pricer = factory.new(instrument, fixings, market_data).unwrap();
+        let expected: Vec<(usize, usize)> = (1..5).map(|i| (i * 250, 1000)).collect();
+        assert_eq!(*reports.lock().unwrap(), expected);
+
+        // rescaling the paths for a bump carries on the count
+        let mut save = pricer.as_bumpable().new_saveable();
+        let bump = Bump::new_spot("BP.L", BumpSpot::new_relative(0.01));
+        assert!(pricer.as_mut_bumpable().bump(&bump, Some(&mut *save)).unwrap());
+        let expected: Vec<(usize, usize)> = (1..5).map(|i| (i * 250, 1000))
+            .chain((5..9).map(|i| (i * 250, 2000))).collect();
+        assert_eq!(*reports.lock().unwrap(), expected);
+    }
+
+    #[test]
+    fn heston_delta() {
+        let mut pricer = heston_pricer(10000, 0.09, 1.0, 0.09, 1e-6, -0.5);
+        let unbumped_price = pricer.price().unwrap();
+
+        let mut save = pricer.as_bumpable().new_saveable();
+        let bump = Bump::new_spot("BP.L", BumpSpot::new_relative(0.01));
+        let bumped = pricer.as_mut_bumpable().bump(&bump, Some(&mut *save)).unwrap();
+        assert!(bumped);
+        let delta = pricer.price().unwrap() - unbumped_price;
+        assert_approx(delta, 0.633187905501792, 0.02);
+
+        pricer.as_mut_bumpable().restore(&*save).unwrap();
+        assert_approx(pricer.price().unwrap(), unbumped_price, 1e-12);
+    }
+
+    #[test]
+    fn heston_ignores_correlation_bumps() {
+        let mut pricer = heston_pricer(1000, 0.09, 1.0, 0.09, 0.3, -0.5);
+        let unbumped = pricer.price().unwrap();
+        let bump = Bump::new_correlation(BumpCorrelation::new_pairwise(
+            "BP.L", "GSK.L", 0.1));
+        assert!(!pricer.as_mut_bumpable().bump(&bump, None).unwrap());
+        assert_eq!(pricer.price().unwrap(), unbumped);
+    }
+
+    #[test]
+    fn heston_bad_spot_gives_stage_error() {
+        let mut pricer = heston_pricer(1000, 0.09, 1.0, 0.09, 0.3, -0.5);
+        let bump = Bump::new_spot("BP.L", BumpSpot::new_replace(NAN));
+        let err = pricer.as_mut_bumpable().bump(&bump, None).unwrap_err();
+        let message = format!("{}", err);
+        assert!(message.contains("Non-finite forward NaN for BP.L"),
+            "unexpected error: {}", message);
+    }
+
+    #[test]
+    fn heston_negative_variance_is_truncated() {
+
+        // A huge vol of vol with a low long-term variance drives the Euler
+        // variance negative on many paths. Full truncation must keep the
+        // price finite and sensible.
+        let pricer = heston_pricer(10000, 0.09, 0.5, 0.01, 3.0, -0.9);
+        let price = pricer.price().unwrap();
+        assert!(price.is_finite(), "price={}", price);
+        assert!(price > 0.0 && price < 100.0, "price={}", price);
+    }
+
+    #[test]
+    fn heston_rejects_bad_parameters() {
+        let parameters = HestonParameters { v0: 0.09, kappa: 1.0,
+            theta: 0.09, sigma: 0.3, rho: -1.5 };
+        assert!(parameters.validate().is_err());
+    }
+
+    #[test]
+    fn correlated_gaussians_use_the_lower_cholesky_factor() {
+
+        // With a correlation of rho between two factors, the first factor
+        // must be the independent draw itself, and the second must mix in
+        // rho of the first. Reading the factor the wrong way round would
+        // transpose it, giving the first factor a variance of 1 + rho^2.
+        let rho = -0.7;
+        let correl = arr2(&[[1.0, rho], [rho, 1.0]]);
+        let independent = correlated_gaussians(&Array2::eye(2), &[3], 10,
+            VarianceReduction::None, RandomSourceType::Pseudo, None,
+            RngAlgorithm::default(), None, 0, 1).unwrap();
+        let correlated = correlated_gaussians(&correl, &[3], 10,
+            VarianceReduction::None, RandomSourceType::Pseudo, None,
+            RngAlgorithm::default(), None, 0, 1).unwrap();
+
+        let scale = (1.0 - rho * rho).sqrt();
+        for path in 0..10 {
+            for step in 0..3 {
+                let first = independent[(path, step, 0)];
+                let second = independent[(path, step, 1)];
+                assert_approx(correlated[(path, step, 0)], first, 1e-12);
+                assert_approx(correlated[(path, step, 1)],
+                    rho * first + scale * second, 1e-12);
+            }
+        }
+    }
+
+    #[test]
+    fn serde_heston_factory_roundtrip() {
+        let factory = RcMonteCarloModelFactory::new(Arc::new(
+            HestonFactory::new(20, 1000, 0.04, 1.5, 0.05, 0.4, -0.7).with_seed(BASE_SEED)));
+
+        let serialized = serde_json::to_string_pretty(&factory).unwrap();
+        print!("serialized: {}\n", serialized);
+        let deserialized: RcMonteCarloModelFactory = serde_json::from_str(&serialized).unwrap();
+
+        // an unseeded factory draws a fresh default seed when deserialized,
+        // so compare everything that is serialized rather than the debug
+        // representation
+        assert_eq!(serde_json::to_string_pretty(&deserialized).unwrap(), serialized);
+    }
+
+    fn assert_approx(value: f64, expected: f64, tolerance: f64) {
+        assert!(approx_eq(value, expected, tolerance),
+            "value={} expected={}", value, expected);
+    }
+}
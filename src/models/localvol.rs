@@ -0,0 +1,940 @@
+use std::any::Any;
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::sync::Arc;
+use ndarray::Array1;
+use ndarray::Array2;
+use ndarray::Array3;
+use ndarray::ArrayView2;
+use ndarray::ArrayViewMut2;
+use ndarray::Axis;
+use core::qm;
+use math::numerics::check_finite;
+use instruments::Instrument;
+use instruments::MonteCarloContext;
+use instruments::PricingContext;
+use instruments::RcInstrument;
+use risk::BumpablePricingContext;
+use risk::Bumpable;
+use risk::Saveable;
+use risk::marketdata::MarketData;
+use risk::dependencies::DependencyCollector;
+use data::bump::Bump;
+use data::volsurface::RcVolSurface;
+use models::MonteCarloModel;
+use models::MonteCarloTimeline;
+use models::MonteCarloModelFactory;
+use models::VarianceReduction;
+use models::PathAccumulator;
+use models::ProgressCallback;
+use models::progress_batch_size;
+use math::moments::RunningMoments;
+use models::random::RandomSourceType;
+use models::random::RngAlgorithm;
+use models::random::entropy_seed;
+use models::blackdiffusion::default_threads;
+use models::blackdiffusion::fetch_correlation_matrix;
+use models::blackdiffusion::correlated_gaussians;
+use models::blackdiffusion::evaluate_pure_rates_flows;
+use dates::datetime::DateDayFraction;
+use dates::datetime::DateTime;
+use dates::datetime::TimeOfDay;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use serde::Deserialize;
+use erased_serde as esd;
+
+/// The LocalVolFactory creates a Dupire local volatility model, given the
+/// timeline of the product(s) to value, and the market data to value it
+/// with. The local volatility is calibrated from the implied vol surface
+/// of each asset in the market data.
+///
+/// As for the BlackDiffusionFactory, the paths are seeded with any seed
+/// supplied, or otherwise with one drawn from the operating system's entropy
+/// when the factory is created, and their random numbers can be generated
+/// on several threads without affecting the results.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LocalVolFactory {
+    number_of_paths: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    seed: Option<u64>,
+    #[serde(default = "default_threads")]
+    threads: usize,
+    #[serde(skip, default = "entropy_seed")]
+    default_seed: u64,
+    #[serde(skip)]
+    progress: Option<ProgressCallback>
+}
+
+impl LocalVolFactory {
+    /// Creates a factory for local vol models with the given number of
+    /// paths. There is no correlation substep, as the correlations are
+    /// constant in time, so the gaussians are correlated once per step.
+    pub fn new(number_of_paths: usize) -> LocalVolFactory {
+        LocalVolFactory { number_of_paths, seed: None,
+            threads: default_threads(), default_seed: entropy_seed(),
+            progress: None }
+    }
+
+    /// Selects the pseudo-random stream, so that a given set of inputs
+    /// always gives exactly the same price
+    pub fn with_seed(mut self, seed: u64) -> LocalVolFactory {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// Sets the number of threads used for generating the random numbers
+    /// for the paths. See BlackDiffusionFactory::with_threads.
+    pub fn with_threads(mut self, threads: usize) -> LocalVolFactory {
+        self.threads = threads.max(1);
+        self
+    }
+
+    /// Supplies a callback to report progress as the paths are evolved,
+    /// both initially and when they are regenerated for bumped repricings.
+    /// The callback is not serialized.
+    pub fn with_progress(mut self, progress: ProgressCallback) -> LocalVolFactory {
+        self.progress = Some(progress);
+        self
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<MonteCarloModelFactory>, esd::Error> {
+        Ok(Qrc::new(Arc::new(LocalVolFactory::deserialize(de)?)))
+    }
+}
+
+impl TypeId for LocalVolFactory {
+    fn get_type_id(&self) -> &'static str { "LocalVolFactory" }
+}
+
+impl MonteCarloModelFactory for LocalVolFactory {
+
+    fn factory(&self, timeline: &MonteCarloTimeline,
+        context: Box<BumpablePricingContext>)
+        -> Result<Box<MonteCarloModel>, qm::Error> {
+
+        let model = LocalVol::new(timeline, context, self.number_of_paths,
+            self.seed.unwrap_or(self.default_seed), self.threads,
+            self.progress.clone())?;
+        Ok(Box::new(model))
+    }
+
+    fn threads(&self) -> usize {
+        self.threads
+    }
+}
+
+/// The largest step in vol time that the local vol model takes along a
+/// path. The vol depends on the spot, so the Euler scheme is only accurate
+/// for small steps.
+const MAX_TIME_STEP: f64 = 1.0 / 52.0;
+
+/// The number of points in the log-moneyness grid of local variances
+const GRID_POINTS: usize = 101;
+
+/// The half-width of the log-moneyness grid, in standard deviations of the
+/// at-the-money implied variance at the last observation
+const GRID_STANDARD_DEVIATIONS: f64 = 6.0;
+
+/// Local variances are floored at this level. The floor is only hit if the
+/// input surface has calendar or butterfly arbitrage.
+const LOCAL_VARIANCE_FLOOR: f64 = 1e-6;
+
+/// A LocalVol model represents the SDE:
+///
+///  dS/S = mu(t) dt + sigma(S, t) dW
+///
+/// where the local volatility sigma(S, t) is given by the Dupire formula
+/// applied to the implied vol surface. Correlations between assets are
+/// taken from the pricing context, as for BlackDiffusion.
+///
+/// As with BlackDiffusion, we work with an underlier scaled such that
+/// mu(t) = 0, and time measured in vol time. If the surface is displaced,
+/// the displaced underlier is diffused. The local variance is calculated
+/// on a grid of log-moneyness at each observation date of the timeline,
+/// and is held constant in time between observations. This reproduces the
+/// implied variances at the observation dates, which is where the paths are
+/// used. Within each observation, the spot is evolved in log space with an
+/// Euler scheme, interpolating linearly in the grid. An asset diffused
+/// under the measure of another currency has the quanto drift correction
+/// -rho_X sigma_X sigma(S, t) added to mu(t).
+///
+/// Unlike BlackDiffusion or Heston, the shape of the paths depends on the
+/// forwards and vols, so any bump regenerates the paths from the stored
+/// gaussians.
+#[derive(Clone)]
+pub struct LocalVol {
+    observations: Vec<DateDayFraction>,
+    flows: Vec<RcInstrument>,
+    context: Box<BumpablePricingContext>,
+    key: HashMap<String, usize>,
+    instruments: Vec<RcInstrument>,
+    quantos: Vec<Option<String>>,
+    substepping: Vec<usize>,
+    gaussians: Array3<f64>,
+    paths: Array3<f64>,
+    warnings: Vec<Option<String>>,
+    accumulator: PathAccumulator,
+    progress: Option<ProgressCallback>
+}
+
+impl LocalVol {
+
+    /// Create a new LocalVol model, given a timeline to define the
+    /// instrument(s) we want to price, a context to define the market data
+    /// and a count of paths. The seed selects the pseudo-random stream, and
+    /// n_threads is the number of threads used to generate it. The progress
+    /// callback, if any, reports as the paths are evolved.
+    pub fn new(timeline: &MonteCarloTimeline,
+        context: Box<BumpablePricingContext>,
+        n_paths: usize,
+        seed: u64,
+        n_threads: usize,
+        progress: Option<ProgressCallback>)
+        -> Result<LocalVol, qm::Error> {
+
+        // key to all observations and all instruments
+        let mut observations = Vec::new();
+        let mut key = HashMap::new();
+        let mut instruments = Vec::new();
+        for (asset, obs) in timeline.observations().iter() {
+
+            // at present, we just insist that all observations are the same
+            if observations.is_empty() {
+                observations = obs.to_vec();
+            }
+
+            // store the assets in the order we are told about them
+            key.insert(asset.id().to_string(), instruments.len());
+            instruments.push(asset.clone());
+        }
+
+        if observations.is_empty() {
+            return Err(qm::Error::new("No observations"))
+        }
+
+        // the currency each asset is diffused in, if not its own
+        let quantos = instruments.iter().map(|asset| timeline.quanto_currency(asset))
+            .collect::<Result<Vec<_>, qm::Error>>()?;
+
+        // Use the vol times of the observations for each asset to decide
+        // how many substeps we need, then space and refine them if the
+        // timeline asks
+        let mut substepping = vec!(1_usize; observations.len());
+        for instrument in instruments.iter() {
+            let (slices, _) = fetch_slices(instrument.deref(),
+                context.as_pricing_context(), &observations)?;
+            let mut prev_time = 0.0;
+            for (slice, substep) in slices.iter().zip(substepping.iter_mut()) {
+                let steps = ((slice.time - prev_time) / MAX_TIME_STEP).ceil() as usize;
+                *substep = (*substep).max(steps);
+                prev_time = slice.time;
+            }
+        }
+        timeline.adjust_substepping(&observations, &mut substepping);
+
+        let correl = fetch_correlation_matrix(
+            context.as_pricing_context(), &instruments)?;
+        let gaussians = correlated_gaussians(&correl, &substepping, n_paths,
+            VarianceReduction::None, RandomSourceType::Pseudo, Some(seed),
+            RngAlgorithm::default(), None, 0, n_threads)?;
+
+        let n_obs = observations.len();
+        let n_assets = instruments.len();
+        let mut paths = Array3::<f64>::zeros((n_paths, n_obs, n_assets));
+        let mut warnings = Vec::with_capacity(n_assets);
+        for (asset, instrument) in instruments.iter().enumerate() {
+            warnings.push(fetch_path(instrument.deref(),
+                context.as_pricing_context(), quanto_str(&quantos[asset]),
+                &observations, &substepping,
+                gaussians.subview(Axis(2), asset),
+                paths.subview_mut(Axis(2), asset), progress.as_ref())?);
+        }
+
+        Ok(LocalVol {
+            observations,
+            flows: timeline.flows().to_vec(),
+            context,
+            key,
+            instruments,
+            quantos,
+            substepping,
+            gaussians,
+            paths,
+            warnings,
+            accumulator: PathAccumulator::new(),
+            progress })
+    }
+
+    /// Refetch a single asset
+    pub fn refetch(&mut self, id: &str, bumped: bool,
+        saved_paths: Option<&mut SavedPaths>) -> Result<bool, qm::Error> {
+
+        // if nothing was bumped, there is nothing to do
+        if !bumped {
+            return Ok(false)
+        }
+
+        let id_string = id.to_string();
+        if let Some(asset) = self.key.get(&id_string) {
+
+            // save the old path and warning (unless already saved) then
+            // replace them
+            let path = self.paths.subview_mut(Axis(2), *asset);
+            if let Some(s) = saved_paths {
+                let warning = &self.warnings[*asset];
+                s.entry(*asset).or_insert_with(|| (path.to_owned(), warning.clone()));
+            }
+            self.warnings[*asset] = fetch_path(self.instruments[*asset].deref(),
+                self.context.as_pricing_context(), quanto_str(&self.quantos[*asset]),
+                &self.observations,
+                &self.substepping, self.gaussians.subview(Axis(2), *asset), path,
+                self.progress.as_ref())?;
+
+        } else {
+            return Err(qm::Error::new("Failed to find asset"))
+        }
+
+        Ok(true)
+    }
+
+    /// Refetch all paths for all assets. Note that this does not change the
+    /// substepping, so it may be coarser than ideal if vol times change.
+    pub fn refetch_all(&mut self) -> Result<(), qm::Error> {
+        for (asset, instrument) in self.instruments.iter().enumerate() {
+            self.warnings[asset] = fetch_path(instrument.deref(),
+                self.context.as_pricing_context(), quanto_str(&self.quantos[asset]),
+                &self.observations,
+                &self.substepping, self.gaussians.subview(Axis(2), asset),
+                self.paths.subview_mut(Axis(2), asset), self.progress.as_ref())?;
+        }
+        Ok(())
+    }
+}
+
+/// The market data for one asset at one observation
+struct Slice {
+    date: DateDayFraction,
+    time: f64,
+    forward: f64,
+    displacement: f64
+}
+
+impl Slice {
+    /// Returns the implied variances at each of the given log-moneynesses
+    /// of the displaced forward.
+    fn variances(&self, surface: &RcVolSurface, log_moneyness: &[f64])
+        -> Result<Vec<f64>, qm::Error> {
+
+        let strikes: Vec<f64> = log_moneyness.iter()
+            .map(|y| self.forward * y.exp() + self.displacement).collect();
+        let mut variances = vec!(0.0; strikes.len());
+        surface.variances(self.date, &strikes, &mut variances)?;
+        for (variance, strike) in variances.iter().zip(strikes.iter()) {
+            check_finite(*variance, "variance", || format!("{} at strike {}",
+                self.date.date(), strike))?;
+        }
+        Ok(variances)
+    }
+}
+
+/// Fetch the vol time, displaced forward and displacement of each
+/// observation of a single asset, together with its vol surface
+fn fetch_slices(instrument: &Instrument, context: &PricingContext,
+    observations: &[DateDayFraction])
+    -> Result<(Vec<Slice>, RcVolSurface), qm::Error> {
+
+    assert!(!observations.is_empty());
+    let hwm = observations.last().unwrap().date();
+    let forward_curve = context.forward_curve(instrument, hwm)?;
+    let surface = context.vol_surface(instrument, hwm, &|| Ok(forward_curve.clone()))?;
+
+    let mut slices = Vec::with_capacity(observations.len());
+    let mut prev_time = 0.0;
+    for obs in observations.iter() {
+        let time = surface.vol_time(*obs)?;
+        if time < prev_time {
+            return Err(qm::Error::new("Observations must be in order"))
+        }
+        prev_time = time;
+        let displacement = check_finite(surface.displacement(obs.date())?,
+            "displacement", || format!("{} on {}", instrument.id(), obs.date()))?;
+        let forward = check_finite(forward_curve.forward(obs.date())?, "forward",
+            || format!("{} on {}", instrument.id(), obs.date()))? - displacement;
+        if forward <= 0.0 {
+            return Err(qm::Error::new("Local vol requires positive displaced forwards"))
+        }
+        slices.push(Slice { date: *obs, time, forward, displacement });
+    }
+    Ok((slices, surface))
+}
+
+/// The local variance of a single asset over one observation period, as a
+/// function of log-moneyness on a uniform grid.
+struct LocalVarianceGrid {
+    start: f64,
+    step: f64,
+    variances: Vec<f64>
+}
+
+impl LocalVarianceGrid {
+    /// Interpolates linearly in the grid, extrapolating flat
+    fn interpolate(&self, y: f64) -> f64 {
+        let n = self.variances.len();
+        let x = (y - self.start) / self.step;
+        if x <= 0.0 {
+            return self.variances[0]
+        }
+        let i = x.floor() as usize;
+        if i >= n - 1 {
+            return self.variances[n - 1]
+        }
+        let f = x - i as f64;
+        self.variances[i] * (1.0 - f) + self.variances[i + 1] * f
+    }
+}
+
+/// Builds the local variance grids for each observation period, using the
+/// Dupire formula in terms of total implied variance w(y, t), where y is
+/// the log-moneyness:
+///
+///  sigma^2 = (dw/dt) / (1 - (y/w) dw/dy
+///      + (-1/4 - 1/w + y^2/w^2) (dw/dy)^2 / 4 + (d2w/dy2) / 2)
+///
+/// The time derivative is the difference between the implied variances at
+/// consecutive observations. Returns the grids and the number of grid points
+/// where the local variance was not positive and had to be floored.
+fn local_variance_grids(surface: &RcVolSurface, slices: &[Slice])
+    -> Result<(Vec<LocalVarianceGrid>, usize), qm::Error> {
+
+    // centre the grid on the forward, scaled by the longest at-the-money
+    // implied variance
+    let last = slices.last().unwrap();
+    let atm_variance = last.variances(surface, &[0.0])?[0];
+    let half_width = GRID_STANDARD_DEVIATIONS * atm_variance.max(0.01).sqrt();
+    let step = 2.0 * half_width / (GRID_POINTS - 1) as f64;
+    let ys: Vec<f64> = (0..GRID_POINTS).map(|i| i as f64 * step - half_width).collect();
+
+    let mut grids = Vec::with_capacity(slices.len());
+    let mut floored = 0;
+    let mut prev_variances = vec!(0.0; GRID_POINTS);
+    let mut prev_time = 0.0;
+    for slice in slices.iter() {
+        let w = slice.variances(surface, &ys)?;
+        let dt = slice.time - prev_time;
+
+        let mut variances = vec!(LOCAL_VARIANCE_FLOOR; GRID_POINTS);
+        if dt > 0.0 {
+            for i in 0..GRID_POINTS {
+                // finite differences in log-moneyness, one-sided at the ends
+                let lo = if i == 0 { 0 } else { i - 1 };
+                let hi = if i == GRID_POINTS - 1 { i } else { i + 1 };
+                let dw_dy = (w[hi] - w[lo]) / ((hi - lo) as f64 * step);
+                let d2w_dy2 = if lo < i && i < hi {
+                    (w[hi] - 2.0 * w[i] + w[lo]) / (step * step)
+                } else {
+                    0.0
+                };
+
+                let y = ys[i];
+                let wi = w[i];
+                let denominator = 1.0 - y / wi * dw_dy
+                    + 0.25 * (-0.25 - 1.0 / wi + y * y / (wi * wi)) * dw_dy * dw_dy
+                    + 0.5 * d2w_dy2;
+                let local_variance = (wi - prev_variances[i]) / dt / denominator;
+
+                // this test also catches NaNs from zero implied variance
+                if local_variance > LOCAL_VARIANCE_FLOOR {
+                    variances[i] = local_variance;
+                } else {
+                    floored += 1;
+                }
+            }
+        }
+
+        grids.push(LocalVarianceGrid { start: -half_width, step, variances });
+        prev_variances = w;
+        prev_time = slice.time;
+    }
+
+    Ok((grids, floored))
+}
+
+fn quanto_str(quanto: &Option<String>) -> Option<&str> {
+    quanto.as_ref().map(|currency| currency.as_str())
+}
+
+/// Evolve the paths of a single asset using local vol, given the gaussians
+/// that drive it, under the measure of the quanto currency if any. Returns
+/// a warning if the local variance had to be floored anywhere.
+fn fetch_path(instrument: &Instrument, context: &PricingContext,
+    quanto: Option<&str>, observations: &[DateDayFraction], substepping: &[usize],
+    gaussians: ArrayView2<f64>, mut path: ArrayViewMut2<f64>,
+    progress: Option<&ProgressCallback>)
+    -> Result<Option<String>, qm::Error> {
+
+    let (slices, surface) = fetch_slices(instrument, context, observations)?;
+    let (grids, floored) = local_variance_grids(&surface, &slices)?;
+    let quanto_drift = match quanto {
+        Some(currency) => context.quanto_drift(instrument, currency)?,
+        None => 0.0
+    };
+
+    // evolve in batches if there is a progress callback to report to
+    let n_paths = gaussians.shape()[0];
+    let batch_size = progress_batch_size(progress, n_paths);
+    if let Some(progress) = progress {
+        progress.start(n_paths);
+    }
+    for (draws_batch, mut batch) in gaussians.axis_chunks_iter(Axis(0), batch_size)
+        .zip(path.axis_chunks_iter_mut(Axis(0), batch_size)) {
+
+        let batch_paths = batch.shape()[0];
+        for (draws, mut one_path) in draws_batch.outer_iter().zip(batch.outer_iter_mut()) {
+            let mut log_moneyness = 0.0;
+            let mut prev_time = 0.0;
+            let mut g = 0;  // index into the gaussians
+            for (i, (slice, grid)) in slices.iter().zip(grids.iter()).enumerate() {
+                let dt = (slice.time - prev_time) / substepping[i] as f64;
+                let sqrt_dt = dt.sqrt();
+                for _ in 0..substepping[i] {
+                    let v = grid.interpolate(log_moneyness);
+                    let sqrt_v = v.sqrt();
+                    log_moneyness += -0.5 * v * dt + quanto_drift * sqrt_v * dt
+                        + sqrt_v * sqrt_dt * draws[g];
+                    g += 1;
+                }
+                one_path[i] = log_moneyness.exp() * slice.forward + slice.displacement;
+                prev_time = slice.time;
+            }
+        }
+
+        if let Some(progress) = progress {
+            progress.report(batch_paths);
+        }
+    }
+
+    if floored > 0 {
+        Ok(Some(format!("Local variance for '{}' was not positive at {} of {} \
+            grid points, so was floored at {}. The implied vol surface may \
+            have arbitrage.", instrument.id(), floored,
+            GRID_POINTS * slices.len(), LOCAL_VARIANCE_FLOOR)))
+    } else {
+        Ok(None)
+    }
+}
+
+impl MonteCarloModel for LocalVol {
+
+    fn as_mc_context(&self) -> &MonteCarloContext { self }
+    fn as_bumpable(&self) -> &Bumpable { self }
+    fn as_mut_bumpable(&mut self) -> &mut Bumpable { self }
+    fn raw_market_data(&self) -> &MarketData { self.context.raw_market_data() }
+    fn bumpable_context(&self) -> &BumpablePricingContext { &*self.context }
+
+    fn accumulate_paths(&self, weight: Option<f64>) {
+        self.accumulator.set_weight(weight);
+    }
+
+    fn take_path_values(&self) -> Option<Array1<f64>> {
+        self.accumulator.take(false)
+    }
+
+    fn stream_path_moments(&self) {
+        self.accumulator.stream_moments(false)
+    }
+
+    fn take_path_moments(&self) -> Option<RunningMoments> {
+        self.accumulator.take_moments(false)
+    }
+
+    fn warnings(&self) -> Vec<String> {
+        self.warnings.iter().filter_map(|w| w.clone()).collect()
+    }
+}
+
+impl MonteCarloContext for LocalVol {
+
+    fn paths(&self, instrument: &RcInstrument)
+        -> Result<ArrayView2<f64>, qm::Error> {
+
+        let id = instrument.id().to_string();
+        let asset = self.key.get(&id).ok_or_else(|| qm::Error::new(
+            &format!("LocalVol does not know about '{}'", id)))?;
+        Ok(self.paths.subview(Axis(2), *asset))
+    }
+
+    fn evaluate_flows(&self, quantities: ArrayView2<f64>)
+        -> Result<f64, qm::Error> {
+
+        let flows_shape = quantities.shape();
+        assert_eq!(flows_shape[0], self.paths.shape()[0]);
+        assert_eq!(flows_shape[1], self.flows.len());
+
+        // For now, always value as of the spot date at the open
+        let val_date = DateTime::new(self.context.as_pricing_context().spot_date(), TimeOfDay::Open);
+
+        // LocalVol has deterministic rates, so we can value flows analytically
+        evaluate_pure_rates_flows(&self.flows, self.context.as_pricing_context(),
+            val_date, quantities, &self.accumulator)
+    }
+
+    fn pricing_context(&self) -> &PricingContext {
+        self.context.as_pricing_context()
+    }
+}
+
+impl Bumpable for LocalVol {
+
+    fn bump(&mut self, bump: &Bump, any_saved: Option<&mut Saveable>)
+        -> Result<bool, qm::Error> {
+
+        // unpack the option<saveable> into its components
+        let saved = to_saved(any_saved)?;
+        let (saved_data, saved_paths)
+            : (Option<&mut Saveable>, Option<&mut SavedPaths>)
+            = if let Some(s) = saved {
+            (Some(&mut *s.saved_data), Some(&mut s.paths))
+        } else {
+            (None, None)
+        };
+
+        // The correlations are baked into the paths when they are generated,
+        // so a correlation bump is ignored, without touching the market data.
+        // Reports such as cega then skip the pair.
+        if let Bump::Correlation(_) = *bump {
+            return Ok(false)
+        }
+
+        // bump the underlying market data (and prefetched content if any)
+        let bumped = self.context.as_mut_bumpable().bump(bump, saved_data)?;
+
+        // refetch any paths that may have changed
+        match *bump {
+            Bump::Spot(ref id, _) => self.refetch(id, bumped, saved_paths),
+            Bump::Divs(ref id, _) => self.refetch(id, bumped, saved_paths),
+            Bump::Borrow(ref id, _) => self.refetch(id, bumped, saved_paths),
+            Bump::Vol(ref id, _) => self.refetch(id, bumped, saved_paths),
+            Bump::Yield(ref credit_id, _) => {
+                let v = self.dependencies()?
+                    .forward_id_by_credit_id(credit_id).to_vec();
+                if let Some(s) = saved_paths {
+                    for id in v.iter() {
+                        self.refetch(id, bumped, Some(s))?;
+                    }
+                } else {
+                    for id in v.iter() {
+                        self.refetch(id, bumped, None)?;
+                    }
+                }
+                Ok(bumped)
+            },
+            // discounting does not affect the paths
+            Bump::Discount(_, _) => Ok(bumped),
+            Bump::SpotDate(_) => {
+                if bumped {
+                    if let Some(s) = saved_paths {
+                        for asset in 0..self.instruments.len() {
+                            let path = self.paths.subview(Axis(2), asset);
+                            let warning = &self.warnings[asset];
+                            s.entry(asset).or_insert_with(|| (path.to_owned(), warning.clone()));
+                        }
+                    }
+                    self.refetch_all()?;
+                }
+                Ok(bumped)
+            },
+            // rejected above
+            Bump::Correlation(_) => Ok(bumped)
+        }
+    }
+
+    fn new_saveable(&self) -> Box<Saveable> {
+        Box::new(SavedLocalVol::new(
+            self.context.as_bumpable().new_saveable()))
+    }
+
+    fn dependencies(&self) -> Result<&DependencyCollector, qm::Error> {
+        self.context.dependencies()
+    }
+
+    fn context(&self) -> &PricingContext {
+        self.context.as_pricing_context()
+    }
+
+    fn restore(&mut self, any_saved: &Saveable) -> Result<(), qm::Error> {
+
+        if let Some(saved) = any_saved.as_any().downcast_ref::<SavedLocalVol>() {
+
+            // first restore the underlying market data and cached curves
+            self.context.as_mut_bumpable().restore(&*saved.saved_data)?;
+
+            // now restore any cached paths and their warnings
+            for (asset, (paths, warning)) in saved.paths.iter() {
+                let mut dest = self.paths.subview_mut(Axis(2), *asset);
+                dest.assign(paths);
+                self.warnings[*asset] = warning.clone();
+            }
+            Ok(())
+
+        } else {
+            Err(qm::Error::new("Mismatching save space for restore"))
+        }
+    }
+
+    fn checkpoint(&self) -> Result<Vec<u8>, qm::Error> {
+        self.context.as_bumpable().checkpoint()
+    }
+
+    fn restore_checkpoint(&mut self, checkpoint: &[u8]) -> Result<(), qm::Error> {
+        self.context.as_mut_bumpable().restore_checkpoint(checkpoint)?;
+        self.refetch_all()
+    }
+}
+
+fn to_saved(opt_saveable: Option<&mut Saveable>)
+    -> Result<Option<&mut SavedLocalVol>, qm::Error> {
+
+    if let Some(saveable) = opt_saveable {
+        if let Some(saved) = saveable.as_mut_any().downcast_mut::<SavedLocalVol>() {
+            Ok(Some(saved))
+        } else {
+            Err(qm::Error::new("Mismatching save space for LocalVol"))
+        }
+    } else {
+        Ok(None)
+    }
+}
+
+/// Paths and warnings of individual assets, keyed by their index in the model
+type SavedPaths = HashMap<usize, (Array2<f64>, Option<String>)>;
+
+/// Save space for LocalVol to use during bumping
+pub struct SavedLocalVol {
+    saved_data: Box<Saveable>,
+    paths: SavedPaths
+}
+
+impl SavedLocalVol {
+
+    /// Creates an empty set of paths, which can be used for saving state
+    /// so it can be restored after a bump
+    pub fn new(saved_data: Box<Saveable>) -> SavedLocalVol {
+        SavedLocalVol {
+            saved_data,
+            paths: HashMap::new() }
+    }
+}
+
+impl Saveable for SavedLocalVol {
+    fn as_any(&self) -> &Any { self }
+    fn as_mut_any(&mut self) -> &mut Any { self }
+
+    fn clear(&mut self) {
+        self.saved_data.clear();
+        self.paths.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use models::random::BASE_SEED;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+    use math::numerics::approx_eq;
+    use math::interpolation::Linear;
+    use math::interpolation::Extrap;
+    use data::bumpspot::BumpSpot;
+    use data::bumpvol::BumpVol;
+    use data::volsmile::CubicSplineSmile;
+    use data::volsurface::VolByProbabilityCubicSplineSmile;
+    use data::volsurface::DivAssumptions;
+    use dates::Date;
+    use dates::calendar::RcCalendar;
+    use dates::calendar::WeekdayCalendar;
+    use instruments::assets::RcCurrency;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::marketdata::tests::sample_european;
+    use risk::marketdata::tests::sample_currency;
+    use risk::marketdata::tests::sample_equity;
+    use risk::marketdata::tests::create_sample_rate;
+    use risk::marketdata::tests::create_sample_borrow;
+    use risk::marketdata::tests::create_sample_divstream;
+    use pricers::montecarlo::MonteCarloPricer;
+    use models::RcMonteCarloModelFactory;
+    use risk::Pricer;
+    use serde_json;
+    use std::f64::NAN;
+    use data::bumpcorrelation::BumpCorrelation;
+
+    fn local_vol_pricer(n_paths: usize, market_data: &MarketData) -> MonteCarloPricer {
+        let instrument = RcInstrument::new(Qrc::new(sample_european()));
+        let model_factory = RcMonteCarloModelFactory::new(Arc::new(
+            LocalVolFactory::new(n_paths).with_seed(BASE_SEED)));
+        MonteCarloPricer::new(vec!((1.0, instrument)), model_factory,
+            market_data).unwrap()
+    }
+
+    #[test]
+    fn flat_local_vol_matches_black_scholes() {
+
+        // The sample vol surface is flat at 30%, so the local vol is flat
+        // at 30% too, and we should match the analytic price within the
+        // Monte-Carlo error.
+        let pricer = local_vol_pricer(100000, &sample_market_data());
+        let (price, stderr) = pricer.price_with_stderr().unwrap();
+        let analytic = 16.710717400832973;
+        assert!((price - analytic).abs() < 3.0 * stderr,
+            "price={} analytic={} stderr={}", price, analytic, stderr);
+        assert!(pricer.warnings().is_empty());
+    }
+
+    #[test]
+    fn local_vol_bumps_and_restores() {
+        let mut pricer = local_vol_pricer(10000, &sample_market_data());
+        let unbumped_price = pricer.price().unwrap();
+        let mut save = pricer.as_bumpable().new_saveable();
+
+        // delta is close to the Black-Scholes delta
+        let bump = Bump::new_spot("BP.L", BumpSpot::new_relative(0.01));
+        assert!(pricer.as_mut_bumpable().bump(&bump, Some(&mut *save)).unwrap());
+        let delta = pricer.price().unwrap() - unbumped_price;
+        assert_approx(delta, 0.633187905501792, 0.02);
+        pricer.as_mut_bumpable().restore(&*save).unwrap();
+        save.clear();
+        assert_approx(pricer.price().unwrap(), unbumped_price, 1e-12);
+
+        // a vol bump changes the local vol, so regenerates the paths
+        let bump = Bump::new_vol("BP.L", BumpVol::new_flat_additive(0.01));
+        assert!(pricer.as_mut_bumpable().bump(&bump, Some(&mut *save)).unwrap());
+        let vega = pricer.price().unwrap() - unbumped_price;
+        assert_approx(vega, 0.42483019575, 0.02);
+        pricer.as_mut_bumpable().restore(&*save).unwrap();
+        assert_approx(pricer.price().unwrap(), unbumped_price, 1e-12);
+    }
+
+    #[test]
+    fn local_vol_ignores_correlation_bumps() {
+        let mut pricer = local_vol_pricer(1000, &sample_market_data());
+        let unbumped = pricer.price().unwrap();
+        let bump = Bump::new_correlation(BumpCorrelation::new_pairwise(
+            "BP.L", "GSK.L", 0.1));
+        assert!(!pricer.as_mut_bumpable().bump(&bump, None).unwrap());
+        assert_eq!(pricer.price().unwrap(), unbumped);
+    }
+
+    #[test]
+    fn local_vol_bad_inputs_give_stage_errors() {
+        let bump_error = |bump: Bump| -> String {
+            let mut pricer = local_vol_pricer(1000, &sample_market_data());
+            let err = pricer.as_mut_bumpable().bump(&bump, None).unwrap_err();
+            format!("{}", err)
+        };
+
+        let message = bump_error(Bump::new_spot("BP.L", BumpSpot::new_replace(NAN)));
+        assert!(message.contains("Non-finite forward NaN for BP.L"),
+            "unexpected error: {}", message);
+
+        let message = bump_error(Bump::new_vol("BP.L", BumpVol::new_replace(NAN)));
+        assert!(message.contains("Non-finite variance NaN"),
+            "unexpected error: {}", message);
+    }
+
+    #[test]
+    fn local_vol_reports_progress_over_bumps() {
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let recorder = reports.clone();
+        let progress = ProgressCallback::new(250, move |completed, total|
+            recorder.lock().unwrap().push((completed, total)));
+
+        let instrument = RcInstrument::new(Qrc::new(sample_european()));
+        let model_factory = RcMonteCarloModelFactory::new(Arc::new(
+            LocalVolFactory::new(1000).with_seed(BASE_SEED).with_progress(progress)));
+        let mut pricer = MonteCarloPricer::new(vec!((1.0, instrument)),
+            model_factory, &sample_market_data()).unwrap();
+        let expected: Vec<(usize, usize)> = (1..5).map(|i| (i * 250, 1000)).collect();
+        assert_eq!(*reports.lock().unwrap(), expected);
+
+        // regenerating the paths carries on the count
+        let mut save = pricer.as_bumpable().new_saveable();
+        let bump = Bump::new_spot("BP.L", BumpSpot::new_relative(0.01));
+        assert!(pricer.as_mut_bumpable().bump(&bump, Some(&mut *save)).unwrap());
+        let expected: Vec<(usize, usize)> = (1..5).map(|i| (i * 250, 1000))
+            .chain((5..9).map(|i| (i * 250, 2000))).collect();
+        assert_eq!(*reports.lock().unwrap(), expected);
+    }
+
+    /// Market data where the smile of BP.L has a sharp peak at the money,
+    /// which is a butterfly arbitrage
+    fn arbitrage_market_data() -> MarketData {
+
+        let market_data = sample_market_data();
+        let spot_date = market_data.spot_date();
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        let equity = sample_equity(currency, 2);
+        let forward = market_data.forward_curve(&equity, spot_date + 1500).unwrap();
+
+        let d = spot_date;
+        let dates = [d, d + 365, d + 730, d + 1460];
+        let points: Vec<(Date, f64)> = dates.iter()
+            .map(|&date| (date, forward.forward(date).unwrap())).collect();
+        let fwd = Linear::new(&points, Extrap::Natural, Extrap::Natural).unwrap();
+        let divs = Linear::new(&[(d, 0.0)], Extrap::Flat, Extrap::Flat).unwrap();
+
+        let points = [(60.0, 0.2), (80.0, 0.2), (95.0, 0.6), (100.0, 0.8),
+            (105.0, 0.6), (120.0, 0.2), (140.0, 0.2)];
+        let smiles = [(DateDayFraction::new(d + 517, 0.7),
+            CubicSplineSmile::new(&points).unwrap())];
+        let calendar = RcCalendar::new(Arc::new(WeekdayCalendar()));
+        let base = DateDayFraction::new(spot_date, 0.0);
+        let surface = VolByProbabilityCubicSplineSmile::new(&smiles, calendar,
+            base, fwd, divs, DivAssumptions::NoCashDivs).unwrap();
+
+        let mut spots = HashMap::new();
+        spots.insert("BP.L".to_string(), 100.0);
+        let mut dividends = HashMap::new();
+        dividends.insert("BP.L".to_string(), create_sample_divstream());
+        let mut yield_curves = HashMap::new();
+        yield_curves.insert("OPT".to_string(), create_sample_rate());
+        yield_curves.insert("LSE".to_string(), create_sample_rate());
+        let mut borrow_curves = HashMap::new();
+        borrow_curves.insert("BP.L".to_string(), create_sample_borrow());
+        let mut vol_surfaces = HashMap::new();
+        vol_surfaces.insert("BP.L".to_string(), RcVolSurface::new(Arc::new(surface)));
+
+        MarketData::new(spot_date, spots, yield_curves, borrow_curves,
+            dividends, vol_surfaces)
+    }
+
+    #[test]
+    fn negative_local_variance_is_floored_with_warning() {
+        let pricer = local_vol_pricer(10000, &arbitrage_market_data());
+        let price = pricer.price().unwrap();
+        assert!(price.is_finite() && price > 0.0, "price={}", price);
+
+        let warnings = pricer.warnings();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("'BP.L'"), "warning={}", warnings[0]);
+    }
+
+    #[test]
+    fn serde_local_vol_factory_roundtrip() {
+        let factory = RcMonteCarloModelFactory::new(Arc::new(
+            LocalVolFactory::new(1000).with_seed(BASE_SEED)));
+
+        let serialized = serde_json::to_string_pretty(&factory).unwrap();
+        print!("serialized: {}\n", serialized);
+        let deserialized: RcMonteCarloModelFactory = serde_json::from_str(&serialized).unwrap();
+
+        // an unseeded factory draws a fresh default seed when deserialized,
+        // so compare everything that is serialized rather than the debug
+        // representation
+        assert_eq!(serde_json::to_string_pretty(&deserialized).unwrap(), serialized);
+    }
+
+    fn assert_approx(value: f64, expected: f64, tolerance: f64) {
+        assert!(approx_eq(value, expected, tolerance),
+            "value={} expected={}", value, expected);
+    }
+}
@@ -1,9 +1,16 @@
 pub mod blackdiffusion;
+pub mod heston;
+pub mod localvol;
+pub mod random;
 
 use models::blackdiffusion::BlackDiffusionFactory;
+use models::heston::HestonFactory;
+use models::localvol::LocalVolFactory;
 use core::qm;
+use math::moments::RunningMoments;
 use instruments::RcInstrument;
 use instruments::MonteCarloDependencies;
+use instruments::ObservationKind;
 use instruments::MonteCarloContext;
 use risk::Bumpable;
 use risk::BumpablePricingContext;
@@ -12,12 +19,23 @@ use dates::Date;
 use dates::datetime::DateDayFraction;
 use core::factories::{TypeId, Qrc, Registry};
 use std::collections::HashMap;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
 use std::clone::Clone;
+use std::cell::Cell;
+use std::cell::RefCell;
+use ndarray::Array1;
+use ndarray::ArrayView1;
+use ndarray::ArrayView2;
 use erased_serde as esd;
 use serde as sd;
 use serde_tagged as sdt;
 use serde_tagged::de::BoxFnSeed;
 use std::fmt::Debug;
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
 
 /// Interface that must be implemented by a model factory in order to support
 /// Monte-Carlo pricing.
@@ -28,6 +46,18 @@ pub trait MonteCarloModelFactory : esd::Serialize + TypeId + Sync + Send + Debug
     fn factory(&self, timeline: &MonteCarloTimeline, 
         context: Box<BumpablePricingContext>)
         -> Result<Box<MonteCarloModel>, qm::Error>;
+
+    /// If the factory asks for paths to be run in batches until the price
+    /// converges, returns the target. See ConvergenceTarget.
+    fn convergence_target(&self) -> Option<ConvergenceTarget> {
+        None
+    }
+
+    /// The number of threads the models use when generating their paths.
+    /// This does not affect the results, only the wall-clock time.
+    fn threads(&self) -> usize {
+        1
+    }
 }
 
 // Get serialization to work recursively for instruments by using the
@@ -50,6 +80,8 @@ pub fn get_registry() -> &'static TypeRegistry {
         static ref REG: TypeRegistry = {
             let mut reg = TypeRegistry::new();
             reg.insert("BlackDiffusionFactory", BoxFnSeed::new(BlackDiffusionFactory::from_serial));
+            reg.insert("HestonFactory", BoxFnSeed::new(HestonFactory::from_serial));
+            reg.insert("LocalVolFactory", BoxFnSeed::new(LocalVolFactory::from_serial));
             reg
         };
     }
@@ -58,9 +90,104 @@ pub fn get_registry() -> &'static TypeRegistry {
 
 pub type RcMonteCarloModelFactory = Qrc<MonteCarloModelFactory>;
 
+/// Techniques for reducing the variance of a Monte-Carlo simulation, so that
+/// fewer paths are needed for a given accuracy.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum VarianceReduction {
+    /// Plain Monte-Carlo, with every path driven by independent draws
+    None,
+
+    /// Paths are generated in pairs, where the second path of each pair is
+    /// driven by the negated draws of the first. This works well for payoffs
+    /// that are close to linear in the underlying.
+    Antithetic,
+
+    /// Latin hypercube sampling of the terminal value of the Brownian motion
+    /// driving each asset. Each run of the given number of consecutive paths
+    /// has exactly one path in each of that many equal-probability strata,
+    /// independently permuted for each asset, while the increments leading
+    /// up to the terminal value keep their correct conditional distribution.
+    /// This works well for payoffs that depend mainly on the final values.
+    /// The paths within a run are not independent, so the standard error
+    /// estimated from the spread of the paths overstates the true error.
+    Stratified { strata: usize }
+}
+
+impl Default for VarianceReduction {
+    fn default() -> VarianceReduction { VarianceReduction::None }
+}
+
+/// Rather than running a fixed number of paths, paths may be added in
+/// batches until the standard error of the price, relative to the price,
+/// is no more than rel_error, or until there are max_paths paths. Each batch
+/// has its own deterministic random numbers, so a given seed always runs
+/// the same sequence of batches and gives the same price.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct ConvergenceTarget {
+    pub rel_error: f64,
+    pub max_paths: usize
+}
+
+/// Parameters of a Hull-White short rate, r(t) = x(t) + phi(t), diffused
+/// alongside the underlyings:
+///
+///  dx = -mean_reversion x dt + volatility dW_r,  x(0) = 0
+///
+/// where phi(t) is chosen so the expected discount factor to every date
+/// matches the yield curve. The driver dW_r has the given correlation with
+/// the driver of every underlying. Spots grow, and flows are discounted, at
+/// the realized short rate of each path, so the money-market account is the
+/// numeraire. The volatility is in absolute rate terms, per annum.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct StochasticRates {
+    pub mean_reversion: f64,
+    pub volatility: f64,
+    pub correlation: f64
+}
+
+/// Schemes for stepping a diffusion along its timeline. Each step is
+/// expressed as the factor by which a driftless lognormal process grows over
+/// the step, given the square root of the variance over the step and a
+/// standard gaussian draw. Euler and Milstein are biased for large steps,
+/// converging as the steps shrink, whereas the exact scheme is unbiased at
+/// any step size, so comparing them measures the discretization bias.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum DiscretizationScheme {
+    /// First order in the step: dS = S sigma dW
+    Euler,
+
+    /// Euler plus the Ito correction to the diffusion term, which is
+    /// S sigma^2 (dW^2 - dt) / 2 for a lognormal process
+    Milstein,
+
+    /// Steps the log of the underlying, which is exact for a lognormal
+    /// process whose vol does not depend on the level
+    Exact
+}
+
+impl DiscretizationScheme {
+    /// The factor by which the process grows over a step with the given
+    /// sqrt variance, driven by the given gaussian
+    pub fn growth(&self, sqrt_variance: f64, gaussian: f64) -> f64 {
+        let diffusion = sqrt_variance * gaussian;
+        match *self {
+            DiscretizationScheme::Euler => 1.0 + diffusion,
+            DiscretizationScheme::Milstein => 1.0 + diffusion
+                + 0.5 * sqrt_variance * sqrt_variance * (gaussian * gaussian - 1.0),
+            DiscretizationScheme::Exact =>
+                (diffusion - 0.5 * sqrt_variance * sqrt_variance).exp()
+        }
+    }
+}
+
+impl Default for DiscretizationScheme {
+    fn default() -> DiscretizationScheme { DiscretizationScheme::Euler }
+}
+
 /// Interface that must be implemented by a model in order to support
-/// Monte-Carlo pricing.
-pub trait MonteCarloModel : MonteCarloContext + Bumpable + MonteCarloModelClone {
+/// Monte-Carlo pricing. Models must be Send, so that the pricers that own
+/// them can be cloned onto other threads.
+pub trait MonteCarloModel : MonteCarloContext + Bumpable + MonteCarloModelClone + Send {
 
     /// Converts this model to a MonteCarloContext that can be used for pricing
     fn as_mc_context(&self) -> &MonteCarloContext;
@@ -70,6 +197,284 @@ pub trait MonteCarloModel : MonteCarloContext + Bumpable + MonteCarloModelClone
     fn as_mut_bumpable(&mut self) -> &mut Bumpable;
 
     fn raw_market_data(&self) -> &MarketData;
+
+    /// The pricing context the model was built with, including any bumps.
+    /// This allows a model to be rebuilt from a modified copy of it.
+    fn bumpable_context(&self) -> &BumpablePricingContext;
+
+    /// Starts accumulating the value of each path as flows are evaluated,
+    /// multiplied by the given weight, or stops accumulating if the weight
+    /// is None. The weight can be changed between instruments.
+    fn accumulate_paths(&self, weight: Option<f64>);
+
+    /// Returns the accumulated value of each independent sample, and clears
+    /// the accumulator. Where paths are not independent, for example the
+    /// pairs of antithetic paths, they are averaged into a single sample.
+    /// Returns None if no flows were evaluated while accumulating.
+    fn take_path_values(&self) -> Option<Array1<f64>>;
+
+    /// Asks the model to add the value of each independent sample straight
+    /// into the moments returned by take_path_moments, rather than keeping
+    /// the value of every path. This is only possible if each path has a
+    /// single weighted contribution, so a pricer must only ask when it is
+    /// valuing just one instrument by simulation. It lasts until the
+    /// accumulator is next cleared. By default, models keep the values.
+    fn stream_path_moments(&self) {}
+
+    /// Returns the mean and variance of the accumulated value of each
+    /// independent sample, and clears the accumulator. This is the same as
+    /// take_path_values, but avoids creating any further vectors of paths.
+    fn take_path_moments(&self) -> Option<RunningMoments> {
+        self.take_path_values().map(|values| {
+            let mut moments = RunningMoments::new();
+            for value in values.iter() {
+                moments.add(*value);
+            }
+            moments
+        })
+    }
+
+    /// Any warnings about the market data raised while building or bumping
+    /// the model, for example where it had to be adjusted to be usable.
+    fn warnings(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// The forward that the model diffuses the given underlying with, at
+    /// its observation on the given date, which must be in the timeline.
+    /// This is purely for checking the model against the pricing context,
+    /// and has no effect on pricing. Models that cannot supply it give an
+    /// error.
+    fn diagnostic_forward(&self, _underlying: &RcInstrument, _date: Date)
+        -> Result<f64, qm::Error> {
+        Err(qm::Error::new("This model does not supply diagnostic forwards"))
+    }
+
+    /// The total at-the-money variance that the model diffuses the given
+    /// underlying with, from the spot date to its observation on the given
+    /// date, summed over all the steps. See diagnostic_forward.
+    fn diagnostic_total_variance(&self, _underlying: &RcInstrument, _date: Date)
+        -> Result<f64, qm::Error> {
+        Err(qm::Error::new("This model does not supply diagnostic variances"))
+    }
+
+    /// Adds another batch of paths, the same size as the first, after those
+    /// already in the model, but truncated so there are no more than
+    /// max_paths in all. Returns false if there are already max_paths paths.
+    /// This must not be done while the model is bumped, as the saved state
+    /// would not cover the new paths. Models that cannot add paths give an
+    /// error.
+    fn add_path_batch(&mut self, _max_paths: usize) -> Result<bool, qm::Error> {
+        Err(qm::Error::new("This model cannot add batches of paths"))
+    }
+
+    /// Restricts valuation to the paths of the batch most recently added by
+    /// add_path_batch, or lifts the restriction. A pricer adding batches
+    /// until the price converges uses this to value each batch just once.
+    /// Models that cannot add batches have only the one, so do nothing.
+    fn value_latest_batch_only(&self, _latest_only: bool) {}
+}
+
+/// Accumulates the weighted value of each path as flows are evaluated, so
+/// that a pricer can estimate the standard error of a Monte-Carlo price.
+/// Models hold one of these, and add to it from within evaluate_flows.
+///
+/// Where several instruments contribute to each path, the value of every
+/// path must be kept until they have all been added. Where there is only
+/// one contribution, the accumulator can instead stream the values into
+/// running moments as they are added. See stream_moments.
+#[derive(Clone, Debug, Default)]
+pub struct PathAccumulator {
+    weight: Cell<Option<f64>>,
+    values: RefCell<Option<Array1<f64>>>,
+    streaming: Cell<Option<bool>>,
+    moments: RefCell<Option<RunningMoments>>
+}
+
+impl PathAccumulator {
+    pub fn new() -> PathAccumulator {
+        PathAccumulator::default()
+    }
+
+    /// Sets the weight for subsequent values, or stops accumulating if None
+    pub fn set_weight(&self, weight: Option<f64>) {
+        self.weight.set(weight);
+    }
+
+    /// Returns true if values are currently being accumulated
+    pub fn is_active(&self) -> bool {
+        self.weight.get().is_some()
+    }
+
+    /// Streams the values subsequently added by add_flows into running
+    /// moments, rather than keeping them, until the moments are taken. If
+    /// antithetic is true, each pair of paths is averaged into a single
+    /// sample, as for take. Only one set of flows may be added while
+    /// streaming.
+    pub fn stream_moments(&self, antithetic: bool) {
+        self.streaming.set(Some(antithetic));
+    }
+
+    /// Adds the value of each path, given the quantity of each flow on each
+    /// path, indexed by path then flow, and the value of each flow. If
+    /// streaming, each path value goes into the moments as it is found.
+    /// Does nothing if we are not accumulating.
+    pub fn add_flows(&self, quantities: ArrayView2<f64>, flow_values: &[f64]) {
+        let weight = match self.weight.get() {
+            Some(weight) => weight,
+            None => return
+        };
+        let antithetic = match self.streaming.get() {
+            Some(antithetic) => antithetic,
+            None => {
+                self.add(quantities.dot(&ArrayView1::from(flow_values)).view());
+                return
+            }
+        };
+
+        let mut moments = self.moments.borrow_mut();
+        assert!(moments.is_none(), "Only one contribution per path can be streamed");
+        let mut streamed = RunningMoments::new();
+        let path_value = |row: ArrayView1<f64>| weight * row.iter()
+            .zip(flow_values.iter()).map(|(q, v)| q * v).sum::<f64>();
+        let mut rows = quantities.outer_iter();
+        while let Some(first) = rows.next() {
+            let value = path_value(first);
+            if !antithetic {
+                streamed.add(value);
+            } else if let Some(second) = rows.next() {
+                streamed.add(0.5 * (value + path_value(second)));
+            } else {
+                streamed.add(value);
+            }
+        }
+        *moments = Some(streamed);
+    }
+
+    /// Adds the value of each path, multiplied by the current weight. Does
+    /// nothing if we are not accumulating. Values cannot be streamed this
+    /// way. See add_flows.
+    pub fn add(&self, path_values: ArrayView1<f64>) {
+        if let Some(weight) = self.weight.get() {
+            assert!(self.streaming.get().is_none(),
+                "Streamed values must be added with add_flows");
+            let mut values = self.values.borrow_mut();
+            if let Some(ref mut v) = *values {
+                v.scaled_add(weight, &path_values);
+                return
+            }
+            *values = Some(path_values.mapv(|x| x * weight));
+        }
+    }
+
+    /// Returns the accumulated values and clears them. If antithetic is
+    /// true, each pair of paths is averaged into a single sample.
+    pub fn take(&self, antithetic: bool) -> Option<Array1<f64>> {
+        self.stop_streaming();
+        let values = self.values.borrow_mut().take()?;
+        if !antithetic {
+            return Some(values)
+        }
+
+        let n = values.len();
+        Some((0..n.div_ceil(2)).map(|i| paired_value(&values, i)).collect())
+    }
+
+    /// Returns the mean and variance of the accumulated values, and clears
+    /// them. Antithetic pairs are averaged as for take. If the values were
+    /// streamed, these are the moments already found. Otherwise they are
+    /// found in a single pass, without any intermediate vector.
+    pub fn take_moments(&self, antithetic: bool) -> Option<RunningMoments> {
+        if let Some(streamed) = self.stop_streaming() {
+            return Some(streamed)
+        }
+
+        let values = self.values.borrow_mut().take()?;
+        let mut moments = RunningMoments::new();
+        if antithetic {
+            for i in 0..values.len().div_ceil(2) {
+                moments.add(paired_value(&values, i));
+            }
+        } else {
+            for value in values.iter() {
+                moments.add(*value);
+            }
+        }
+        Some(moments)
+    }
+
+    /// Stops streaming, returning any moments streamed
+    fn stop_streaming(&self) -> Option<RunningMoments> {
+        self.streaming.set(None);
+        self.moments.borrow_mut().take()
+    }
+}
+
+/// The average of the i'th pair of values, or the last value if it has no
+/// pair
+fn paired_value(values: &Array1<f64>, i: usize) -> f64 {
+    let first = values[2 * i];
+    if 2 * i + 1 < values.len() { 0.5 * (first + values[2 * i + 1]) } else { first }
+}
+
+/// Callback reporting the progress of a long Monte-Carlo run. Paths are
+/// evolved in batches of the given size, and after each batch the callback
+/// is invoked with the number of paths completed so far and the total number
+/// of paths requested so far. Paths are evolved when they are first
+/// generated, and again whenever they are regenerated for a bumped
+/// repricing. The counts are cumulative over all of these, and are shared
+/// with any clones of the callback, so a risk sweep reports its overall
+/// progress rather than starting again for each repricing.
+///
+/// Without a callback, all paths are evolved in a single batch, so there is
+/// no overhead.
+#[derive(Clone)]
+pub struct ProgressCallback {
+    batch_size: usize,
+    callback: Arc<Fn(usize, usize) + Send + Sync>,
+    completed: Arc<AtomicUsize>,
+    total: Arc<AtomicUsize>
+}
+
+impl ProgressCallback {
+    /// Creates a callback invoked every batch_size paths. A batch size of
+    /// zero is treated as one.
+    pub fn new<F>(batch_size: usize, callback: F) -> ProgressCallback
+        where F: Fn(usize, usize) + Send + Sync + 'static {
+        ProgressCallback {
+            batch_size: batch_size.max(1),
+            callback: Arc::new(callback),
+            completed: Arc::new(AtomicUsize::new(0)),
+            total: Arc::new(AtomicUsize::new(0)) }
+    }
+
+    pub fn batch_size(&self) -> usize { self.batch_size }
+
+    /// Adds n_paths to the total, before they are evolved
+    pub fn start(&self, n_paths: usize) {
+        self.total.fetch_add(n_paths, Ordering::SeqCst);
+    }
+
+    /// Reports that a batch of n_paths has been evolved
+    pub fn report(&self, n_paths: usize) {
+        let completed = self.completed.fetch_add(n_paths, Ordering::SeqCst) + n_paths;
+        (self.callback)(completed, self.total.load(Ordering::SeqCst))
+    }
+}
+
+impl Debug for ProgressCallback {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ProgressCallback {{ batch_size: {} }}", self.batch_size)
+    }
+}
+
+/// Returns the number of paths to evolve at a time, which is all of them
+/// unless there is a progress callback.
+pub fn progress_batch_size(progress: Option<&ProgressCallback>, n_paths: usize) -> usize {
+    match progress {
+        Some(progress) => progress.batch_size(),
+        None => n_paths.max(1)
+    }
 }
 
 pub trait MonteCarloModelClone {
@@ -90,12 +495,26 @@ impl Clone for Box<MonteCarloModel> {
     }
 }
 
+/// The distinct observations of an underlying in time order, each tagged
+/// with all the reasons it was requested.
+pub type TaggedObservations = BTreeMap<DateDayFraction, BTreeSet<ObservationKind>>;
+
 /// Timeline, which collects the information about an instrument that a model
 /// needs to generate paths for valuing it.
+///
+/// As well as the observations in the order they were requested, which
+/// define the layout of the paths, the timeline keeps the union of the
+/// requested dates for each underlying, tagged with why they are needed.
+#[derive(Clone)]
 pub struct MonteCarloTimeline {
-    _spot_date: Date,
+    spot_date: Date,
     observations: HashMap<RcInstrument, Vec<DateDayFraction>>,
+    tagged: HashMap<RcInstrument, TaggedObservations>,
     flows: Vec<RcInstrument>,
+    payment_currency: Option<String>,
+    measures: HashMap<RcInstrument, BTreeSet<String>>,
+    refinement: usize,
+    min_spacing: Option<u32>,
     collated: bool
 }
 
@@ -105,9 +524,63 @@ impl MonteCarloTimeline {
     /// wish to value. Finally, invoke collate to ensure the timeline is
     /// sorted correctly.
     pub fn new(spot_date: Date) -> MonteCarloTimeline {
-        MonteCarloTimeline { _spot_date: spot_date, 
-            observations: HashMap::new(), flows: Vec::new(),
-            collated: false }
+        MonteCarloTimeline { spot_date: spot_date, 
+            observations: HashMap::new(), tagged: HashMap::new(), flows: Vec::new(),
+            payment_currency: None, measures: HashMap::new(),
+            refinement: 1, min_spacing: None, collated: false }
+    }
+
+    /// Sets the currency paid by the instrument whose dependencies are about
+    /// to be collected. Each underlying it observes is then diffused under
+    /// the measure of that currency, with a quanto drift correction if the
+    /// underlying is in a different currency. If this is never invoked, all
+    /// underlyings are diffused in their own currencies. Must be invoked
+    /// before collate.
+    pub fn set_payment_currency(&mut self, currency: &str) {
+        assert!(!self.collated);
+        self.payment_currency = Some(currency.to_string());
+    }
+
+    /// Adds the payment currencies recorded for each underlying in another
+    /// timeline, for example where the observations of several instruments
+    /// are collected separately and then merged. Must be invoked before
+    /// collate.
+    pub fn merge_payment_currencies(&mut self, other: &MonteCarloTimeline) {
+        assert!(!self.collated);
+        for (instrument, currencies) in other.measures.iter() {
+            self.measures.entry(instrument.clone()).or_insert_with(BTreeSet::new)
+                .extend(currencies.iter().cloned());
+        }
+    }
+
+    /// Asks the model to insert intermediate diffusion steps, so that no
+    /// two consecutive steps are more than the given number of days apart,
+    /// however sparse the observations. This improves the accuracy of
+    /// barriers and other path-dependent payoffs, without the instruments
+    /// having to observe on dense dates. The observations themselves are
+    /// unchanged. Must be invoked before collate.
+    pub fn with_min_spacing(mut self, days: u32) -> MonteCarloTimeline {
+        assert!(!self.collated);
+        self.min_spacing = Some(days);
+        self
+    }
+
+    /// Asks the model to take refinement times as many substeps between
+    /// observations as it otherwise would, for example to estimate the
+    /// discretization error. Must be invoked before collate.
+    pub fn refine(&mut self, refinement: usize) {
+        assert!(!self.collated);
+        self.refinement = refinement;
+    }
+
+    /// Adds a further reason for an observation of an underlying, without
+    /// adding the observation to the paths again. Must be invoked before
+    /// collate.
+    pub fn tag(&mut self, instrument: &RcInstrument, date_time: DateDayFraction,
+        kind: ObservationKind) {
+        assert!(!self.collated);
+        self.tagged.entry(instrument.clone()).or_insert_with(BTreeMap::new)
+            .entry(date_time).or_insert_with(BTreeSet::new).insert(kind);
     }
 
     pub fn collate(&mut self) -> Result<(), qm::Error> {
@@ -119,30 +592,131 @@ impl MonteCarloTimeline {
 
         // validate that the flows all make sense and all fix in the future
 
+        // The tagged observations are already the sorted union of the
+        // requested dates, merging the kinds requested for each.
+
+        if self.refinement == 0 {
+            return Err(qm::Error::new("Timeline refinement must be at least one"))
+        }
+        if self.min_spacing == Some(0) {
+            return Err(qm::Error::new("Timeline minimum spacing must be at least one day"))
+        }
+
         self.collated = true;
         Ok(())
     }
 
+    pub fn spot_date(&self) -> Date {
+        self.spot_date
+    }
+
     pub fn observations(&self) -> &HashMap<RcInstrument, Vec<DateDayFraction>> {
         assert!(self.collated);
         &self.observations
     }
 
+    /// The distinct observations of the given underlying, in time order,
+    /// with the kinds of observation requested on each date. Where several
+    /// instruments observe the underlying on the same date, the kinds are
+    /// merged.
+    pub fn tagged_observations(&self, instrument: &RcInstrument)
+        -> Option<&TaggedObservations> {
+        assert!(self.collated);
+        self.tagged.get(instrument)
+    }
+
+    /// The distinct observations of the given underlying that were requested
+    /// with the given kind, in time order. A date may also have been
+    /// requested for other reasons.
+    pub fn observations_of_kind(&self, instrument: &RcInstrument,
+        kind: ObservationKind) -> Vec<DateDayFraction> {
+        self.tagged_observations(instrument).map_or(Vec::new(), |tagged|
+            tagged.iter().filter(|&(_, kinds)| kinds.contains(&kind))
+                .map(|(date, _)| *date).collect())
+    }
+
     pub fn flows(&self) -> &[RcInstrument] {
         assert!(self.collated);
         &self.flows
     }
+
+    /// The currency whose measure the given underlying must be diffused
+    /// under, if it is not the underlying's own currency. It is an error if
+    /// instruments paying in more than one currency observe the underlying,
+    /// as its paths can only be diffused under one measure.
+    pub fn quanto_currency(&self, instrument: &RcInstrument)
+        -> Result<Option<String>, qm::Error> {
+        assert!(self.collated);
+        let currencies = match self.measures.get(instrument) {
+            Some(currencies) => currencies,
+            None => return Ok(None)
+        };
+        if currencies.len() > 1 {
+            return Err(qm::Error::new(&format!("'{}' is observed by \
+                instruments paying in {:?}, but can only be diffused under \
+                the measure of one currency", instrument.id(), currencies)))
+        }
+        let own = instrument.payoff_currency().id();
+        match currencies.iter().next() {
+            Some(currency) if currency.as_str() != own => Ok(Some(currency.clone())),
+            _ => Ok(None)
+        }
+    }
+
+    pub fn refinement(&self) -> usize {
+        assert!(self.collated);
+        self.refinement
+    }
+
+    pub fn min_spacing(&self) -> Option<u32> {
+        assert!(self.collated);
+        self.min_spacing
+    }
+
+    /// Adjusts the number of substeps a model has chosen to take up to each
+    /// of the given observations, so that no substep spans more than the
+    /// minimum spacing, measured from the spot date for the first
+    /// observation, then applies any refinement.
+    pub fn adjust_substepping(&self, observations: &[DateDayFraction],
+        substepping: &mut [usize]) {
+        assert!(self.collated);
+        assert_eq!(observations.len(), substepping.len());
+
+        if let Some(days) = self.min_spacing {
+            let mut prev = DateDayFraction::new(self.spot_date, 0.0);
+            for (obs, substep) in observations.iter().zip(substepping.iter_mut()) {
+                let gap = (obs.date() - prev.date()) as f64
+                    + obs.day_fraction() - prev.day_fraction();
+                let steps = (gap / days as f64).ceil() as usize;
+                *substep = (*substep).max(steps);
+                prev = *obs;
+            }
+        }
+
+        for substep in substepping.iter_mut() {
+            *substep *= self.refinement;
+        }
+    }
 }
 
 impl MonteCarloDependencies for MonteCarloTimeline {
 
     fn observation(&mut self, instrument: &RcInstrument,
-        date_time: DateDayFraction) {
+        date_time: DateDayFraction, kind: ObservationKind) {
 
         // Record the observations in the order the client specifies them
         // for any one instrument
         self.observations.entry(instrument.clone())
             .or_insert(Vec::<DateDayFraction>::new()).push(date_time);
+
+        // Also keep the union of the dates, with every reason for each
+        self.tag(instrument, date_time, kind);
+
+        // and the currency whose measure the underlying is needed under
+        if let Some(ref currency) = self.payment_currency {
+            self.measures.entry(instrument.clone()).or_insert_with(BTreeSet::new)
+                .insert(currency.clone());
+        }
     }
 
     fn flow(&mut self, instrument: &RcInstrument) {
@@ -152,3 +726,175 @@ impl MonteCarloDependencies for MonteCarloTimeline {
         self.flows.push(instrument.clone());
     }
 } 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::arr1;
+    use ndarray::arr2;
+    use math::numerics::approx_eq;
+    use instruments::assets::RcCurrency;
+    use risk::marketdata::tests::sample_currency;
+    use risk::marketdata::tests::sample_equity;
+    use risk::marketdata::tests::sample_settlement;
+    use instruments::MonteCarloPriceable;
+    use instruments::asian::tests::sample_asian;
+    use instruments::barrier::BarrierOption;
+    use instruments::barrier::BarrierDirection;
+    use instruments::barrier::KnockType;
+    use instruments::options::PutOrCall;
+    use dates::datetime::DateTime;
+    use dates::datetime::TimeOfDay;
+
+    #[test]
+    fn path_accumulator_weights_and_pairs() {
+        let accumulator = PathAccumulator::new();
+
+        // nothing is accumulated until a weight is set
+        accumulator.add(arr1(&[1.0, 2.0, 3.0]).view());
+        assert!(accumulator.take(false).is_none());
+
+        accumulator.set_weight(Some(2.0));
+        accumulator.add(arr1(&[1.0, 2.0, 3.0]).view());
+        accumulator.set_weight(Some(-1.0));
+        accumulator.add(arr1(&[1.0, 1.0, 1.0]).view());
+        accumulator.set_weight(None);
+        assert_eq!(accumulator.take(true).unwrap(), arr1(&[2.0, 5.0]));
+        assert!(accumulator.take(true).is_none());
+
+        // the moments are those of the paired values
+        accumulator.set_weight(Some(1.0));
+        accumulator.add(arr1(&[1.0, 3.0, 4.0, 6.0, 8.0]).view());
+        accumulator.set_weight(None);
+        let moments = accumulator.take_moments(true).unwrap();
+        assert_eq!(moments.count(), 3);
+        assert_eq!(moments.mean(), 5.0);
+        assert_eq!(moments.variance(), 9.0);
+        assert!(accumulator.take_moments(true).is_none());
+    }
+
+    #[test]
+    fn path_accumulator_streams_the_same_moments_as_it_keeps() {
+        let quantities = arr2(&[[1.0, 0.0], [1.0, 2.0], [0.0, 2.0], [3.0, 1.0], [2.0, 2.0]]);
+        let flows = [1.0, 1.5];
+
+        for &antithetic in [false, true].iter() {
+            let accumulator = PathAccumulator::new();
+            accumulator.set_weight(Some(2.0));
+            accumulator.add_flows(quantities.view(), &flows);
+            accumulator.set_weight(None);
+            let kept = accumulator.take_moments(antithetic).unwrap();
+
+            accumulator.stream_moments(antithetic);
+            accumulator.set_weight(Some(2.0));
+            accumulator.add_flows(quantities.view(), &flows);
+            accumulator.set_weight(None);
+            let streamed = accumulator.take_moments(antithetic).unwrap();
+
+            assert_eq!(streamed.count(), kept.count());
+            assert!(approx_eq(streamed.mean(), kept.mean(), 1e-12));
+            assert!(approx_eq(streamed.variance(), kept.variance(), 1e-12));
+
+            // taking the moments clears them, and stops streaming
+            assert!(accumulator.take_moments(antithetic).is_none());
+            accumulator.set_weight(Some(1.0));
+            accumulator.add(arr1(&[1.0, 2.0]).view());
+            assert_eq!(accumulator.take(false).unwrap(), arr1(&[1.0, 2.0]));
+        }
+    }
+
+    #[test]
+    fn timeline_min_spacing_densifies_sparse_observations() {
+
+        let spot_date = Date::from_ymd(2017, 01, 02);
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        let equity = RcInstrument::new(Qrc::new(Arc::new(sample_equity(currency, 2))));
+        let dates = [DateDayFraction::new(spot_date + 30, 0.8),
+            DateDayFraction::new(spot_date + 365, 0.8)];
+
+        let mut timeline = MonteCarloTimeline::new(spot_date).with_min_spacing(7);
+        for date in dates.iter() {
+            timeline.observation(&equity, *date, ObservationKind::Fixing);
+        }
+        timeline.collate().unwrap();
+
+        // the observations are exactly those requested
+        assert_eq!(timeline.observations().len(), 1);
+        assert_eq!(timeline.observations().get(&equity).unwrap().as_slice(), &dates[..]);
+
+        // 30.8 days from spot needs five steps of at most seven days, and the
+        // 335 days between the observations needs 48. Finer stepping chosen
+        // by the model is kept.
+        let mut substepping = vec![1, 1];
+        timeline.adjust_substepping(&dates, &mut substepping);
+        assert_eq!(substepping, vec![5, 48]);
+        let mut substepping = vec![10, 1];
+        timeline.adjust_substepping(&dates, &mut substepping);
+        assert_eq!(substepping, vec![10, 48]);
+
+        // refinement applies after the spacing
+        let mut timeline = MonteCarloTimeline::new(spot_date).with_min_spacing(7);
+        timeline.refine(2);
+        timeline.collate().unwrap();
+        let mut substepping = vec![1, 1];
+        timeline.adjust_substepping(&dates, &mut substepping);
+        assert_eq!(substepping, vec![10, 96]);
+
+        // without a minimum spacing, the stepping is unchanged
+        let mut timeline = MonteCarloTimeline::new(spot_date);
+        timeline.collate().unwrap();
+        let mut substepping = vec![1, 3];
+        timeline.adjust_substepping(&dates, &mut substepping);
+        assert_eq!(substepping, vec![1, 3]);
+
+        // a spacing of zero days is meaningless
+        let mut timeline = MonteCarloTimeline::new(spot_date).with_min_spacing(0);
+        assert!(timeline.collate().is_err());
+    }
+
+    #[test]
+    fn timeline_tags_and_merges_observations_of_barrier_and_asian() {
+
+        // An Asian averaging on the first of each month in 2017, and a
+        // barrier monitored daily from the first averaging date to the
+        // third, on the same underlying
+        let spot_date = Date::from_ymd(2017, 01, 02);
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        let equity = RcInstrument::new(Qrc::new(Arc::new(sample_equity(currency, 2))));
+        let asian = sample_asian();
+        let barrier = BarrierOption::new("SampleBarrier", "OPT", equity.clone(),
+            sample_settlement(2),
+            DateTime::new(Date::from_ymd(2017, 01, 01), TimeOfDay::Close),
+            DateTime::new(Date::from_ymd(2017, 03, 01), TimeOfDay::Close),
+            59, 100.0, PutOrCall::Call, 90.0, BarrierDirection::Down,
+            KnockType::Out).unwrap();
+
+        let mut timeline = MonteCarloTimeline::new(spot_date);
+        asian.mc_dependencies(&[], &mut timeline).unwrap();
+        barrier.mc_dependencies(&[], &mut timeline).unwrap();
+        timeline.collate().unwrap();
+
+        // the observations are still all those requested, in order
+        assert_eq!(timeline.observations().get(&equity).unwrap().len(), 12 + 60);
+
+        // the tagged observations are their union, in time order
+        let tagged = timeline.tagged_observations(&equity).unwrap();
+        assert_eq!(tagged.len(), 60 + 9);
+        let dates: Vec<_> = tagged.keys().cloned().collect();
+        let mut sorted = dates.clone();
+        sorted.sort();
+        sorted.dedup();
+        assert_eq!(dates, sorted);
+
+        // dates requested by both are tagged with both kinds
+        let averaging = timeline.observations_of_kind(&equity, ObservationKind::Averaging);
+        let monitoring = timeline.observations_of_kind(&equity, ObservationKind::Barrier);
+        assert_eq!(averaging.len(), 12);
+        assert_eq!(monitoring.len(), 60);
+        let both: Vec<_> = tagged.iter().filter(|&(_, kinds)| kinds.len() == 2)
+            .map(|(date, _)| date.date()).collect();
+        assert_eq!(both, vec!(Date::from_ymd(2017, 01, 01),
+            Date::from_ymd(2017, 02, 01), Date::from_ymd(2017, 03, 01)));
+        assert!(timeline.observations_of_kind(&equity, ObservationKind::Exercise).is_empty());
+    }
+}
@@ -0,0 +1,994 @@
+use core::qm;
+use std::f64::consts::SQRT_2;
+use rand;
+use rand::StdRng;
+use rand::SeedableRng;
+use statrs::distribution::Distribution;
+use statrs::distribution::Normal;
+use statrs::function::erf::erfc;
+use statrs::function::erf::erfc_inv;
+use ndarray::ArrayViewMut2;
+use ndarray::Axis;
+
+/// A source of independent standard gaussians, used to drive Monte-Carlo
+/// paths. The gaussians are generated in blocks of paths, so that they can
+/// be shared between threads. For any one path, the dimensions are ordered
+/// by time step, then by asset.
+///
+/// Implementations must be deterministic given the block and path indices,
+/// so that results do not depend on how the blocks are shared between
+/// threads.
+pub trait RandomSource : Sync {
+
+    /// Fills a block of gaussians, indexed by path then dimension. The
+    /// block index identifies the block, and first_path is the index of its
+    /// first path within the whole simulation.
+    fn fill_gaussians(&self, block: usize, first_path: usize,
+        gaussians: ArrayViewMut2<f64>);
+}
+
+/// Selects which random source to use for generating paths.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum RandomSourceType {
+    /// Pseudo-random numbers, with each block seeded separately
+    Pseudo,
+
+    /// Sobol quasi-random sequence, with Brownian bridge construction
+    Sobol
+}
+
+impl Default for RandomSourceType {
+    fn default() -> RandomSourceType { RandomSourceType::Pseudo }
+}
+
+impl RandomSourceType {
+    /// Creates a random source, given the number of time steps and the
+    /// number of assets (or other correlated factors) at each step, and the
+    /// number of points, or paths of draws, it must be able to supply. The
+    /// seed selects the pseudo-random stream, or the default stream if it is
+    /// not supplied, and the algorithm selects the pseudo-random generator.
+    /// Sobol sequences are not random, so they ignore both, but they are
+    /// limited in their dimensions and points, which are checked here.
+    pub fn create(&self, n_steps: usize, n_assets: usize, n_points: usize,
+        seed: Option<u64>, algorithm: RngAlgorithm)
+        -> Result<Box<RandomSource>, qm::Error> {
+        match *self {
+            RandomSourceType::Pseudo => Ok(Box::new(match seed {
+                Some(seed) => PseudoRandom::with_seed(seed),
+                None => PseudoRandom::new() }.with_algorithm(algorithm))),
+            RandomSourceType::Sobol => Ok(Box::new(
+                SobolRandom::new(n_steps, n_assets, n_points)?))
+        }
+    }
+
+    /// Checks that the source can supply the given number of points. This
+    /// lets a factory reject too many paths before building anything.
+    /// Pseudo-random sources have no limit.
+    pub fn validate_points(&self, n_points: usize) -> Result<(), qm::Error> {
+        match *self {
+            RandomSourceType::Pseudo => Ok(()),
+            RandomSourceType::Sobol => validate_sobol_points(n_points)
+        }
+    }
+}
+
+/// Selects the algorithm used to generate pseudo-random numbers. The
+/// default is the standard library generator, which the crate has always
+/// used, so existing prices are unchanged unless another is chosen.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum RngAlgorithm {
+    /// The standard library generator, with gaussians by Box-Muller
+    Std,
+
+    /// Mersenne Twister MT19937, with each block seeded by init_by_array
+    Mt19937,
+
+    /// PCG XSL RR 128/64, with each block using its own stream
+    Pcg64,
+
+    /// xoshiro256**, with each block one jump further along the stream
+    Xoshiro256
+}
+
+impl Default for RngAlgorithm {
+    fn default() -> RngAlgorithm { RngAlgorithm::Std }
+}
+
+/// Draws a seed for the pseudo-random number streams from the operating
+/// system's entropy. Model factories that are not given a seed use this, so
+/// their paths differ from run to run.
+pub fn entropy_seed() -> u64 {
+    rand::random()
+}
+
+/// Default seed for the pseudo-random number streams. Each block of paths is
+/// seeded with this (or a user-supplied seed) and the index of the block.
+pub const BASE_SEED: u64 = 0x5eed;
+
+/// Pseudo-random gaussians, seeded from the seed and the index of the block.
+/// Given the same seed and algorithm, the gaussians are identical from run
+/// to run.
+pub struct PseudoRandom {
+    seed: u64,
+    algorithm: RngAlgorithm
+}
+
+impl Default for PseudoRandom {
+    fn default() -> PseudoRandom { PseudoRandom::new() }
+}
+
+impl PseudoRandom {
+    /// Creates a pseudo-random source using the default seed
+    pub fn new() -> PseudoRandom {
+        PseudoRandom::with_seed(BASE_SEED)
+    }
+
+    /// Creates a pseudo-random source with the given seed
+    pub fn with_seed(seed: u64) -> PseudoRandom {
+        PseudoRandom { seed: seed, algorithm: RngAlgorithm::default() }
+    }
+
+    /// Selects the generator algorithm
+    pub fn with_algorithm(mut self, algorithm: RngAlgorithm) -> PseudoRandom {
+        self.algorithm = algorithm;
+        self
+    }
+}
+
+impl RandomSource for PseudoRandom {
+    fn fill_gaussians(&self, block: usize, _first_path: usize,
+        mut gaussians: ArrayViewMut2<f64>) {
+
+        let seed_lo = self.seed & 0xffff_ffff;
+        let seed_hi = self.seed >> 32;
+        match self.algorithm {
+            RngAlgorithm::Std => {
+                // The high half of the seed goes last, so that seeds that
+                // fit in 32 bits give the same streams as seeding with just
+                // the low half.
+                let seed: &[usize] = &[seed_lo as usize, block,
+                    seed_hi as usize];
+                let mut rand = StdRng::from_seed(seed);
+
+                // The statrs normal uses Box-Mueller internally, which is a
+                // lossy algorithm, so it cannot be used for low-discrepancy
+                // sequences like Sobol.
+                let normal = Normal::new(0.0, 1.0).unwrap();
+                for draw in gaussians.iter_mut() {
+                    *draw = normal.sample::<StdRng>(&mut rand);
+                }
+            }
+            RngAlgorithm::Mt19937 => {
+                let key = [seed_lo as u32, block as u32, seed_hi as u32];
+                fill_by_inversion(&mut Mt19937::from_key(&key), gaussians);
+            }
+            RngAlgorithm::Pcg64 => {
+                fill_by_inversion(&mut Pcg64::new(self.seed as u128,
+                    block as u128), gaussians);
+            }
+            RngAlgorithm::Xoshiro256 => {
+                let mut rand = Xoshiro256::new(self.seed);
+                for _ in 0..block {
+                    rand.jump();
+                }
+                fill_by_inversion(&mut rand, gaussians);
+            }
+        }
+    }
+}
+
+/// A generator of uniform deviates in the open interval (0, 1)
+trait UniformGenerator {
+    fn next_uniform(&mut self) -> f64;
+}
+
+/// Fills the gaussians from a uniform generator, using the inverse
+/// cumulative normal, so each gaussian takes exactly one uniform draw.
+fn fill_by_inversion(uniforms: &mut UniformGenerator,
+    mut gaussians: ArrayViewMut2<f64>) {
+    for draw in gaussians.iter_mut() {
+        *draw = inverse_cumulative_normal(uniforms.next_uniform());
+    }
+}
+
+const MT_N: usize = 624;
+const MT_M: usize = 397;
+
+/// The Mersenne Twister MT19937 generator of Matsumoto and Nishimura, giving
+/// the same 32-bit outputs as their reference implementation mt19937ar.c.
+pub struct Mt19937 {
+    state: [u32; MT_N],
+    index: usize
+}
+
+impl Mt19937 {
+    /// Seeds the generator as init_genrand in the reference implementation
+    pub fn new(seed: u32) -> Mt19937 {
+        let mut state = [0_u32; MT_N];
+        state[0] = seed;
+        for i in 1..MT_N {
+            let prev = state[i - 1];
+            state[i] = 1_812_433_253_u32.wrapping_mul(prev ^ (prev >> 30))
+                .wrapping_add(i as u32);
+        }
+        Mt19937 { state: state, index: MT_N }
+    }
+
+    /// Seeds the generator from an array of keys, as init_by_array in the
+    /// reference implementation
+    pub fn from_key(key: &[u32]) -> Mt19937 {
+        let mut mt = Mt19937::new(19_650_218);
+        let state = &mut mt.state;
+        let mut i = 1;
+        let mut j = 0;
+        for _ in 0..MT_N.max(key.len()) {
+            let prev = state[i - 1];
+            state[i] = (state[i] ^ (prev ^ (prev >> 30)).wrapping_mul(1_664_525))
+                .wrapping_add(key.get(j).cloned().unwrap_or(0))
+                .wrapping_add(j as u32);
+            i += 1;
+            j += 1;
+            if i >= MT_N {
+                state[0] = state[MT_N - 1];
+                i = 1;
+            }
+            if j >= key.len() {
+                j = 0;
+            }
+        }
+        for _ in 0..(MT_N - 1) {
+            let prev = state[i - 1];
+            state[i] = (state[i] ^ (prev ^ (prev >> 30)).wrapping_mul(1_566_083_941))
+                .wrapping_sub(i as u32);
+            i += 1;
+            if i >= MT_N {
+                state[0] = state[MT_N - 1];
+                i = 1;
+            }
+        }
+        state[0] = 0x8000_0000;
+        mt
+    }
+
+    /// The next 32-bit output, as genrand_int32 in the reference
+    /// implementation
+    pub fn next_u32(&mut self) -> u32 {
+        if self.index >= MT_N {
+            for k in 0..MT_N {
+                let y = (self.state[k] & 0x8000_0000)
+                    | (self.state[(k + 1) % MT_N] & 0x7fff_ffff);
+                let mag = if y & 1 == 1 { 0x9908_b0df } else { 0 };
+                self.state[k] = self.state[(k + MT_M) % MT_N] ^ (y >> 1) ^ mag;
+            }
+            self.index = 0;
+        }
+
+        let mut y = self.state[self.index];
+        self.index += 1;
+        y ^= y >> 11;
+        y ^= (y << 7) & 0x9d2c_5680;
+        y ^= (y << 15) & 0xefc6_0000;
+        y ^ (y >> 18)
+    }
+}
+
+impl UniformGenerator for Mt19937 {
+    fn next_uniform(&mut self) -> f64 {
+        (f64::from(self.next_u32()) + 0.5) / 4_294_967_296.0
+    }
+}
+
+const PCG_MULTIPLIER: u128 = 0x2360_ed05_1fc6_5da4_4385_df64_9fcc_f645;
+
+/// The PCG XSL RR 128/64 generator of O'Neill, with a 128-bit state and a
+/// selectable stream, giving the same outputs as pcg64 in the reference C
+/// implementation.
+pub struct Pcg64 {
+    state: u128,
+    increment: u128
+}
+
+impl Pcg64 {
+    /// Seeds the generator with an initial state and stream, as
+    /// pcg64_srandom_r in the reference implementation
+    pub fn new(seed: u128, stream: u128) -> Pcg64 {
+        let mut pcg = Pcg64 { state: 0, increment: (stream << 1) | 1 };
+        pcg.step();
+        pcg.state = pcg.state.wrapping_add(seed);
+        pcg.step();
+        pcg
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.step();
+        let state = self.state;
+        let rotation = (state >> 122) as u32;
+        (((state >> 64) ^ state) as u64).rotate_right(rotation)
+    }
+
+    fn step(&mut self) {
+        self.state = self.state.wrapping_mul(PCG_MULTIPLIER)
+            .wrapping_add(self.increment);
+    }
+}
+
+impl UniformGenerator for Pcg64 {
+    fn next_uniform(&mut self) -> f64 {
+        uniform_from_u64(self.next_u64())
+    }
+}
+
+const XOSHIRO_JUMP: [u64; 4] = [0x180e_c6d3_3cfd_0aba, 0xd5a6_1266_f0c9_392c,
+    0xa958_2618_e03f_c9aa, 0x39ab_dc45_29b1_661c];
+
+/// The xoshiro256** generator of Blackman and Vigna. The state is filled
+/// from the seed using splitmix64, as its authors recommend.
+pub struct Xoshiro256 {
+    state: [u64; 4]
+}
+
+impl Xoshiro256 {
+    pub fn new(seed: u64) -> Xoshiro256 {
+        let mut x = seed;
+        let mut splitmix64 = || {
+            x = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut z = x;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            z ^ (z >> 31)
+        };
+        let state = [splitmix64(), splitmix64(), splitmix64(), splitmix64()];
+        Xoshiro256 { state: state }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        let s = &mut self.state;
+        let result = s[1].wrapping_mul(5).rotate_left(7).wrapping_mul(9);
+        let t = s[1] << 17;
+        s[2] ^= s[0];
+        s[3] ^= s[1];
+        s[1] ^= s[2];
+        s[0] ^= s[3];
+        s[2] ^= t;
+        s[3] = s[3].rotate_left(45);
+        result
+    }
+
+    /// Advances the generator by 2^128 outputs, so that successive jumps
+    /// give non-overlapping streams
+    pub fn jump(&mut self) {
+        let mut jumped = [0_u64; 4];
+        for word in XOSHIRO_JUMP.iter() {
+            for bit in 0..64 {
+                if (*word >> bit) & 1 == 1 {
+                    for (j, s) in jumped.iter_mut().zip(self.state.iter()) {
+                        *j ^= *s;
+                    }
+                }
+                self.next_u64();
+            }
+        }
+        self.state = jumped;
+    }
+}
+
+impl UniformGenerator for Xoshiro256 {
+    fn next_uniform(&mut self) -> f64 {
+        uniform_from_u64(self.next_u64())
+    }
+}
+
+/// Maps the top 53 bits of a 64-bit output to the centre of one of 2^53
+/// equal subintervals of (0, 1), so it is never zero or one.
+fn uniform_from_u64(x: u64) -> f64 {
+    ((x >> 11) as f64 + 0.5) / 9_007_199_254_740_992.0
+}
+
+/// Number of bits in each Sobol coordinate, which also limits the number of
+/// points we can generate to 2^BITS - 1.
+const BITS: usize = 32;
+
+/// The most points a Sobol sequence can supply, skipping the origin
+pub const MAX_SOBOL_POINTS: u64 = (1 << BITS) - 1;
+
+/// Initial direction numbers for the Sobol dimensions after the first, from
+/// the new-joe-kuo-6.21201 table of S. Joe and F. Y. Kuo, "Constructing
+/// Sobol sequences with better two-dimensional projections", SIAM J. Sci.
+/// Comput. 30 (2008). Each entry is the degree s of a primitive polynomial,
+/// its interior coefficients a, and the initial direction numbers m_1 to
+/// m_s. Only the leading rows of the published table are included, which
+/// limits the number of dimensions.
+const JOE_KUO: &[(u32, u32, &[u32])] = &[
+    (1, 0, &[1]),
+    (2, 1, &[1, 3]),
+    (3, 1, &[1, 3, 1]),
+    (3, 2, &[1, 1, 1]),
+    (4, 1, &[1, 1, 3, 3]),
+    (4, 4, &[1, 3, 5, 13]),
+    (5, 2, &[1, 1, 5, 5, 17]),
+    (5, 4, &[1, 1, 5, 5, 5]),
+    (5, 7, &[1, 1, 7, 11, 19]),
+    (5, 11, &[1, 1, 5, 1, 1]),
+    (5, 13, &[1, 1, 1, 3, 11]),
+    (5, 14, &[1, 3, 5, 5, 31]),
+    (6, 1, &[1, 3, 3, 9, 7, 49]),
+    (6, 13, &[1, 1, 1, 15, 21, 21]),
+    (6, 16, &[1, 3, 1, 13, 27, 49]),
+    (6, 19, &[1, 1, 1, 15, 7, 5]),
+    (6, 22, &[1, 3, 1, 15, 13, 25]),
+    (6, 25, &[1, 1, 5, 5, 19, 61]),
+    (7, 1, &[1, 3, 7, 11, 23, 15, 103]),
+    (7, 4, &[1, 3, 7, 13, 13, 15, 69]),
+    (7, 7, &[1, 1, 3, 13, 7, 35, 63]),
+    (7, 8, &[1, 3, 5, 9, 1, 25, 53]),
+    (7, 14, &[1, 3, 1, 13, 9, 35, 107]),
+    (7, 19, &[1, 3, 1, 5, 27, 61, 31]),
+    (7, 21, &[1, 1, 5, 11, 19, 41, 61]),
+    (7, 28, &[1, 3, 5, 3, 3, 13, 69]),
+    (7, 31, &[1, 1, 7, 13, 1, 19, 1]),
+    (7, 32, &[1, 3, 7, 5, 13, 19, 59]),
+    (7, 37, &[1, 1, 3, 9, 25, 29, 41]),
+    (7, 41, &[1, 3, 5, 13, 23, 1, 55]),
+    (7, 42, &[1, 3, 7, 3, 13, 59, 17]),
+    (7, 50, &[1, 3, 1, 3, 5, 53, 69]),
+    (7, 55, &[1, 1, 5, 5, 23, 33, 13]),
+    (7, 56, &[1, 1, 7, 7, 1, 61, 123]),
+    (7, 59, &[1, 1, 7, 9, 13, 61, 49]),
+    (7, 62, &[1, 3, 3, 5, 3, 55, 33]),
+    (8, 14, &[1, 3, 1, 15, 31, 13, 49, 245]),
+    (8, 21, &[1, 3, 5, 15, 31, 59, 63, 97]),
+    (8, 22, &[1, 3, 1, 11, 11, 11, 77, 249]),
+    (8, 38, &[1, 3, 1, 11, 27, 43, 71, 9]),
+    (8, 47, &[1, 1, 7, 15, 21, 11, 81, 45]),
+    (8, 49, &[1, 3, 7, 3, 25, 31, 65, 79]),
+    (8, 50, &[1, 3, 1, 1, 19, 11, 3, 205]),
+    (8, 52, &[1, 1, 5, 9, 19, 21, 29, 157]),
+    (8, 56, &[1, 3, 7, 11, 1, 33, 89, 185]),
+    (8, 67, &[1, 3, 3, 3, 15, 9, 79, 71]),
+    (8, 70, &[1, 3, 7, 11, 15, 39, 119, 27]),
+    (8, 84, &[1, 1, 3, 1, 11, 31, 97, 225]),
+    (8, 97, &[1, 1, 1, 3, 23, 43, 57, 177]),
+    (8, 103, &[1, 3, 7, 7, 17, 17, 37, 71]),
+    (8, 115, &[1, 3, 1, 5, 27, 63, 123, 213]),
+    (8, 122, &[1, 1, 3, 5, 11, 43, 53, 133]),
+];
+
+/// Sobol quasi-random gaussians. Each path takes one point of the sequence,
+/// skipping the origin, and the uniform coordinates are mapped to gaussians
+/// by the inverse cumulative normal.
+///
+/// Sobol sequences have much better uniformity in their first few
+/// coordinates than their later ones, so we use a Brownian bridge to assign
+/// the first coordinates to the terminal value of each asset's Brownian
+/// motion, then the midpoints, and so on. The bridge treats every time step
+/// as a unit of time, so the gaussians it returns are independent and
+/// identically distributed, exactly as for the pseudo-random source.
+///
+/// The direction numbers come from the table of Joe and Kuo, so the points
+/// are the same as other implementations that use it. The number of
+/// dimensions, which is the number of steps times the number of assets, is
+/// limited by the size of the table, and the number of points to
+/// MAX_SOBOL_POINTS.
+pub struct SobolRandom {
+    n_steps: usize,
+    n_assets: usize,
+    directions: Vec<[u32; BITS]>,
+    bridge: BrownianBridge
+}
+
+impl SobolRandom {
+    /// Creates a Sobol source for the given number of steps and assets,
+    /// which must be able to supply n_points points.
+    pub fn new(n_steps: usize, n_assets: usize, n_points: usize)
+        -> Result<SobolRandom, qm::Error> {
+        let dimensions = n_steps * n_assets;
+        if dimensions == 0 {
+            return Err(qm::Error::new("Sobol sequence needs at least one dimension"))
+        }
+        validate_sobol_points(n_points)?;
+
+        Ok(SobolRandom {
+            n_steps,
+            n_assets,
+            directions: direction_numbers(dimensions)?,
+            bridge: BrownianBridge::new(n_steps) })
+    }
+}
+
+impl RandomSource for SobolRandom {
+    fn fill_gaussians(&self, _block: usize, first_path: usize,
+        mut gaussians: ArrayViewMut2<f64>) {
+
+        let dimensions = self.directions.len();
+        assert_eq!(gaussians.shape()[1], dimensions);
+
+        // Jump directly to the first point of the block, skipping the origin,
+        // by xoring the direction numbers selected by its Gray code
+        let mut index = first_path + 1;
+        let gray = index ^ (index >> 1);
+        let mut point = vec![0_u32; dimensions];
+        for bit in 0..BITS {
+            if (gray >> bit) & 1 == 1 {
+                for (p, v) in point.iter_mut().zip(self.directions.iter()) {
+                    *p ^= v[bit];
+                }
+            }
+        }
+
+        let scale = 1.0 / (1_u64 << BITS) as f64;
+        let mut bridge_points = vec![0.0; self.n_steps];
+        let mut increments = vec![0.0; self.n_steps];
+        let n_paths = gaussians.shape()[0];
+        for (i, mut path) in gaussians.outer_iter_mut().enumerate() {
+
+            // Run a bridge for each asset, using the coordinates for that
+            // asset, which are interleaved so the terminal values of all the
+            // assets get the earliest coordinates.
+            for asset in 0..self.n_assets {
+                for (j, z) in bridge_points.iter_mut().enumerate() {
+                    let u = point[j * self.n_assets + asset] as f64 * scale;
+                    *z = inverse_cumulative_normal(u);
+                }
+                self.bridge.increments(&bridge_points, &mut increments);
+                for (step, dw) in increments.iter().enumerate() {
+                    path[step * self.n_assets + asset] = *dw;
+                }
+            }
+
+            // step to the next point using its Gray code, if there is one.
+            // The number of points was validated when the source was built.
+            if i + 1 == n_paths {
+                break
+            }
+            index += 1;
+            let bit = index.trailing_zeros() as usize;
+            debug_assert!(bit < BITS, "Too many points for Sobol sequence");
+            for (p, v) in point.iter_mut().zip(self.directions.iter()) {
+                *p ^= v[bit];
+            }
+        }
+    }
+}
+
+/// Seed for the permutations of strata in stratify_terminal. Each block of
+/// paths is permuted by a stream seeded from this and the block index.
+const STRATIFICATION_SEED: u64 = 0x57a7;
+
+/// Stratifies the terminal value of the Brownian motion driving each asset,
+/// for Latin hypercube sampling. The gaussians are indexed by path, then
+/// dimension, with the dimensions ordered by step, then asset, as they come
+/// from a RandomSource.
+///
+/// For each asset, the sum of the gaussians over the steps is the terminal
+/// value, and is independent of the deviations of the gaussians from their
+/// mean. We map the terminal value to a uniform, squash that into a stratum
+/// and map it back, then shift the gaussians so they sum to the new value,
+/// leaving the deviations alone. Each run of strata consecutive paths covers
+/// every stratum once, in an order permuted independently for each asset,
+/// so every path on its own still has the distribution of independent draws.
+pub fn stratify_terminal(mut gaussians: ArrayViewMut2<f64>, n_steps: usize,
+    n_assets: usize, strata: usize, block: usize) {
+
+    assert!(strata > 0);
+    assert!(n_steps > 0);
+    assert_eq!(gaussians.shape()[1], n_steps * n_assets);
+
+    let n_paths = gaussians.shape()[0];
+    let root_n = (n_steps as f64).sqrt();
+    let mut rng = Xoshiro256::new(STRATIFICATION_SEED.wrapping_add(block as u64));
+    let mut permutation: Vec<usize> = (0..strata).collect();
+    for first in (0..n_paths).step_by(strata) {
+        let end = n_paths.min(first + strata);
+        for asset in 0..n_assets {
+
+            // Fisher-Yates shuffle of the strata
+            for i in (1..strata).rev() {
+                let j = (rng.next_u64() % (i as u64 + 1)) as usize;
+                permutation.swap(i, j);
+            }
+
+            for (path, stratum) in (first..end).zip(permutation.iter()) {
+                let mut row = gaussians.subview_mut(Axis(0), path);
+                let mean = (0..n_steps).map(|step| row[step * n_assets + asset])
+                    .sum::<f64>() / n_steps as f64;
+                let u = cumulative_normal(mean * root_n)
+                    .max(STRATUM_EDGE).min(1.0 - STRATUM_EDGE);
+                let terminal = inverse_cumulative_normal(
+                    (*stratum as f64 + u) / strata as f64);
+                let shift = terminal / root_n - mean;
+                for step in 0..n_steps {
+                    row[step * n_assets + asset] += shift;
+                }
+            }
+        }
+    }
+}
+
+/// Uniforms are kept this far inside (0, 1) when stratifying, so that the
+/// outermost strata never map to an infinite gaussian
+const STRATUM_EDGE: f64 = 1e-16;
+
+/// The cumulative normal distribution
+fn cumulative_normal(x: f64) -> f64 {
+    0.5 * erfc(-x / SQRT_2)
+}
+
+/// The inverse of the cumulative normal distribution
+fn inverse_cumulative_normal(u: f64) -> f64 {
+    -SQRT_2 * erfc_inv(2.0 * u)
+}
+
+/// Checks that a Sobol sequence can supply the given number of points
+fn validate_sobol_points(n_points: usize) -> Result<(), qm::Error> {
+    if n_points as u64 > MAX_SOBOL_POINTS {
+        return Err(qm::Error::new(&format!("Sobol sequence supports at most \
+            {} points, but {} are needed. Try fewer paths.",
+            MAX_SOBOL_POINTS, n_points)))
+    }
+    Ok(())
+}
+
+/// Calculates the direction numbers for the given number of dimensions. The
+/// first dimension is the van der Corput sequence. The others are taken from
+/// the table of Joe and Kuo.
+fn direction_numbers(dimensions: usize) -> Result<Vec<[u32; BITS]>, qm::Error> {
+
+    if dimensions > JOE_KUO.len() + 1 {
+        return Err(qm::Error::new(&format!("Sobol sequence supports at most \
+            {} dimensions, but {} are needed. Try fewer path substeps.",
+            JOE_KUO.len() + 1, dimensions)))
+    }
+
+    let mut directions = Vec::with_capacity(dimensions);
+
+    // van der Corput, which has all initial direction numbers one
+    let mut first = [0_u32; BITS];
+    for (k, v) in first.iter_mut().enumerate() {
+        *v = 1 << (BITS - 1 - k);
+    }
+    directions.push(first);
+
+    for &(degree, a, m) in JOE_KUO.iter().take(dimensions - 1) {
+
+        // the initial direction numbers m_k are odd and less than 2^k
+        let s = degree as usize;
+        let mut v = [0_u32; BITS];
+        for (k, vk) in v.iter_mut().enumerate().take(s) {
+            *vk = m[k] << (BITS - 1 - k);
+        }
+
+        // the rest follow from the recurrence defined by the polynomial,
+        // whose interior coefficients are the bits of a, most significant
+        // first
+        for k in s..BITS {
+            let mut value = v[k - s] ^ (v[k - s] >> s);
+            for j in 1..s {
+                if (a >> (s - 1 - j)) & 1 == 1 {
+                    value ^= v[k - j];
+                }
+            }
+            v[k] = value;
+        }
+        directions.push(v);
+    }
+
+    Ok(directions)
+}
+
+/// A Brownian bridge over a number of unit time steps, which builds the
+/// Brownian motion from its terminal value inwards, by successive bisection.
+struct BrownianBridge {
+    // for each gaussian in turn: the point it builds, the points either side
+    // and the weights of those points and of the gaussian
+    steps: Vec<(usize, usize, usize, f64, f64, f64)>
+}
+
+impl BrownianBridge {
+    fn new(n_steps: usize) -> BrownianBridge {
+        let mut steps = Vec::with_capacity(n_steps);
+        if n_steps == 0 {
+            return BrownianBridge { steps }
+        }
+
+        // the terminal value has variance n_steps
+        steps.push((n_steps, 0, n_steps, 0.0, 0.0, (n_steps as f64).sqrt()));
+
+        // breadth-first bisection, so the coarsest points come first
+        let mut intervals = ::std::collections::VecDeque::new();
+        intervals.push_back((0, n_steps));
+        while let Some((left, right)) = intervals.pop_front() {
+            if right - left < 2 {
+                continue;
+            }
+            let mid = (left + right) / 2;
+            let width = (right - left) as f64;
+            let to_left = (mid - left) as f64;
+            let to_right = (right - mid) as f64;
+            steps.push((mid, left, right, to_right / width, to_left / width,
+                (to_left * to_right / width).sqrt()));
+            intervals.push_back((left, mid));
+            intervals.push_back((mid, right));
+        }
+        assert_eq!(steps.len(), n_steps);
+        BrownianBridge { steps }
+    }
+
+    /// Converts gaussians, in order of importance, into the increments of
+    /// the Brownian motion over each step
+    fn increments(&self, gaussians: &[f64], increments: &mut [f64]) {
+        let n = self.steps.len();
+        assert_eq!(gaussians.len(), n);
+        assert_eq!(increments.len(), n);
+
+        let mut w = vec![0.0; n + 1];
+        for (&(point, left, right, left_weight, right_weight, sd), z)
+            in self.steps.iter().zip(gaussians.iter()) {
+            w[point] = left_weight * w[left] + right_weight * w[right] + sd * z;
+        }
+        for (i, dw) in increments.iter_mut().enumerate() {
+            *dw = w[i + 1] - w[i];
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::Array2;
+    use math::numerics::approx_eq;
+
+    /// Finds the next primitive polynomial over GF(2) of the given degree,
+    /// after the given one (or the first if zero). Polynomials are bit
+    /// patterns, with the leading and constant terms always set.
+    fn next_primitive_polynomial(degree: u32, after: u32) -> Option<u32> {
+        let first = (1 << degree) | 1;
+        let start = if after < first { first } else { after + 2 };
+        let end = 1 << (degree + 1);
+        (start..end).step_by(2).find(|&p| is_primitive(p, degree))
+    }
+
+    /// A polynomial of degree s is primitive if the multiplicative order of
+    /// x modulo the polynomial is 2^s - 1.
+    fn is_primitive(polynomial: u32, degree: u32) -> bool {
+        let period = (1_u32 << degree) - 1;
+        let mut r = 1_u32;
+        for i in 1..=period {
+            // multiply by x, reducing modulo the polynomial
+            r <<= 1;
+            if r & (1 << degree) != 0 {
+                r ^= polynomial;
+            }
+            if r == 1 {
+                return i == period
+            }
+        }
+        false
+    }
+
+    #[test]
+    fn primitive_polynomials() {
+        // there are 2, 2 and 6 primitive polynomials of degree 3, 4 and 5
+        let count = |degree| {
+            let mut n = 0;
+            let mut p = 0;
+            while let Some(next) = next_primitive_polynomial(degree, p) {
+                n += 1;
+                p = next;
+            }
+            n
+        };
+        assert_eq!(count(3), 2);
+        assert_eq!(count(4), 2);
+        assert_eq!(count(5), 6);
+        assert_eq!(next_primitive_polynomial(3, 0), Some(0b1011));
+    }
+
+    #[test]
+    fn joe_kuo_table_is_well_formed() {
+        // the table lists every primitive polynomial of each degree in
+        // turn, and the initial direction numbers m_k are odd and less
+        // than 2^k
+        let mut expected = 0;
+        let mut previous_degree = 0;
+        for &(degree, a, m) in JOE_KUO.iter() {
+            if degree != previous_degree {
+                assert_eq!(expected, 0, "degree {} is incomplete", previous_degree);
+                expected = 0;
+                previous_degree = degree;
+            }
+            let polynomial = (1 << degree) | (a << 1) | 1;
+            assert_eq!(next_primitive_polynomial(degree, expected), Some(polynomial));
+            expected = next_primitive_polynomial(degree, polynomial).unwrap_or(0);
+
+            assert_eq!(m.len(), degree as usize);
+            for (k, mk) in m.iter().enumerate() {
+                assert!(mk % 2 == 1 && *mk < 1 << (k + 1), "a={} m={:?}", a, m);
+            }
+        }
+    }
+
+    #[test]
+    fn sobol_matches_published_points() {
+        // the first points of the three-dimensional sequence, as given by
+        // other implementations of the Joe and Kuo directions
+        let directions = direction_numbers(3).unwrap();
+        let expected = [[0.5, 0.5, 0.5], [0.75, 0.25, 0.25], [0.25, 0.75, 0.75],
+            [0.375, 0.375, 0.625], [0.875, 0.875, 0.125], [0.625, 0.125, 0.875],
+            [0.125, 0.625, 0.375]];
+        let mut point = [0_u32; 3];
+        for (i, e) in expected.iter().enumerate() {
+            let bit = (i + 1).trailing_zeros() as usize;
+            for (p, v) in point.iter_mut().zip(directions.iter()) {
+                *p ^= v[bit];
+            }
+            for (p, u) in point.iter().zip(e.iter()) {
+                assert_eq!(*p as f64 / (1_u64 << BITS) as f64, *u);
+            }
+        }
+
+        // there are no more dimensions than rows in the table
+        assert!(direction_numbers(JOE_KUO.len() + 1).is_ok());
+        assert!(direction_numbers(JOE_KUO.len() + 2).is_err());
+    }
+
+    #[test]
+    fn pseudo_random_seeding() {
+        let fill = |source: &PseudoRandom, block| {
+            let mut gaussians = Array2::<f64>::zeros((4, 3));
+            source.fill_gaussians(block, 0, gaussians.view_mut());
+            gaussians
+        };
+
+        // the default seed gives the same stream as seeding with just the
+        // base seed and block, as before seeds could be supplied
+        let mut rand = StdRng::from_seed(&[BASE_SEED as usize, 3][..]);
+        let normal = Normal::new(0.0, 1.0).unwrap();
+        let expected: Vec<f64> = (0..12).map(|_| normal.sample::<StdRng>(&mut rand)).collect();
+        let default = fill(&PseudoRandom::new(), 3);
+        assert_eq!(default.iter().cloned().collect::<Vec<f64>>(), expected);
+
+        // the same seed and block give the same gaussians, and changing
+        // either of them gives different ones
+        let seeded = fill(&PseudoRandom::with_seed(42), 3);
+        assert_eq!(fill(&PseudoRandom::with_seed(42), 3), seeded);
+        assert!(fill(&PseudoRandom::with_seed(42), 4) != seeded);
+        assert!(fill(&PseudoRandom::with_seed(42 + (1 << 32)), 3) != seeded);
+    }
+
+    #[test]
+    fn mersenne_twister_matches_reference() {
+        // first outputs of mt19937ar.c, seeded with init_genrand(5489) and
+        // with init_by_array({0x123, 0x234, 0x345, 0x456})
+        let mut mt = Mt19937::new(5489);
+        let outputs: Vec<u32> = (0..5).map(|_| mt.next_u32()).collect();
+        assert_eq!(outputs, vec![3499211612, 581869302, 3890346734,
+            3586334585, 545404204]);
+        let mut mt = Mt19937::from_key(&[0x123, 0x234, 0x345, 0x456]);
+        let outputs: Vec<u32> = (0..5).map(|_| mt.next_u32()).collect();
+        assert_eq!(outputs, vec![1067595299, 955945823, 477289528,
+            4107218783, 4228976476]);
+    }
+
+    #[test]
+    fn mersenne_twister_gaussians_match_reference() {
+        // Block 0 with seed 5489 is seeded with init_by_array({5489, 0, 0}),
+        // whose first outputs are 657351208, 3996833783, 221645825,
+        // 1680429941, 2621729491 and 3274382267. Each gaussian is the
+        // inverse normal of (x + 0.5) / 2^32.
+        let source = PseudoRandom::with_seed(5489)
+            .with_algorithm(RngAlgorithm::Mt19937);
+        let mut gaussians = Array2::<f64>::zeros((2, 3));
+        source.fill_gaussians(0, 0, gaussians.view_mut());
+        let expected = [-1.02343332432098, 1.4801649369289596,
+            -1.6294779433579438, -0.27604803842224607, 0.280411167707853,
+            0.7139680467771949];
+        for (g, e) in gaussians.iter().zip(expected.iter()) {
+            assert!(approx_eq(*g, *e, 1e-12), "g={} e={}", g, e);
+        }
+    }
+
+    #[test]
+    fn pcg_matches_reference() {
+        // first outputs of pcg64 in the reference C implementation, seeded
+        // with initstate 42 and stream 54
+        let mut pcg = Pcg64::new(42, 54);
+        assert_eq!(pcg.next_u64(), 0x86b1da1d72062b68);
+        assert_eq!(pcg.next_u64(), 0x1304aa46c9853d39);
+        assert_eq!(pcg.next_u64(), 0xa3670e9e0dd50358);
+    }
+
+    #[test]
+    fn rng_algorithms_give_independent_unit_gaussians() {
+        let algorithms = [RngAlgorithm::Std, RngAlgorithm::Mt19937,
+            RngAlgorithm::Pcg64, RngAlgorithm::Xoshiro256];
+        let n = 20000;
+        for algorithm in algorithms.iter() {
+            let source = PseudoRandom::new().with_algorithm(*algorithm);
+            let mut first = Array2::<f64>::zeros((n, 2));
+            source.fill_gaussians(0, 0, first.view_mut());
+            let mut second = Array2::<f64>::zeros((n, 2));
+            source.fill_gaussians(1, n, second.view_mut());
+            assert!(first != second, "{:?}", algorithm);
+
+            let mut repeat = Array2::<f64>::zeros((n, 2));
+            source.fill_gaussians(1, n, repeat.view_mut());
+            assert_eq!(second, repeat);
+
+            let mean = first.scalar_sum() / (2 * n) as f64;
+            let var = first.iter().map(|x| x * x).sum::<f64>() / (2 * n) as f64;
+            assert!(mean.abs() < 0.03, "{:?} mean={}", algorithm, mean);
+            assert!((var - 1.0).abs() < 0.05, "{:?} var={}", algorithm, var);
+        }
+
+        // the default algorithm is the standard library generator
+        assert_eq!(RngAlgorithm::default(), RngAlgorithm::Std);
+    }
+
+    #[test]
+    fn sobol_first_dimension_is_van_der_corput() {
+        let sobol = SobolRandom::new(1, 1, 3).unwrap();
+        let mut gaussians = Array2::<f64>::zeros((3, 1));
+        sobol.fill_gaussians(0, 0, gaussians.view_mut());
+        let expected = [0.5, 0.75, 0.25];
+        for (g, u) in gaussians.iter().zip(expected.iter()) {
+            assert!(approx_eq(*g, inverse_cumulative_normal(*u), 1e-14),
+                "g={} u={}", g, u);
+        }
+    }
+
+    #[test]
+    fn sobol_blocks_match_single_run() {
+        let sobol = SobolRandom::new(5, 2, 8).unwrap();
+        let mut whole = Array2::<f64>::zeros((8, 10));
+        sobol.fill_gaussians(0, 0, whole.view_mut());
+        let mut second = Array2::<f64>::zeros((4, 10));
+        sobol.fill_gaussians(1, 4, second.view_mut());
+        for (a, b) in whole.outer_iter().skip(4).zip(second.outer_iter()) {
+            assert_eq!(a, b);
+        }
+    }
+
+    #[test]
+    fn sobol_rejects_too_many_points_or_dimensions() {
+        let max = MAX_SOBOL_POINTS as usize;
+        assert!(SobolRandom::new(1, 1, max + 1).is_err());
+        assert!(RandomSourceType::Sobol.validate_points(max + 1).is_err());
+        assert!(RandomSourceType::Pseudo.validate_points(max + 1).is_ok());
+        assert!(SobolRandom::new(JOE_KUO.len() + 2, 1, 1).is_err());
+        assert!(RandomSourceType::Sobol.create(JOE_KUO.len() + 2, 1, 1, None,
+            RngAlgorithm::default()).is_err());
+
+        // the very last points can be generated without running out
+        let sobol = SobolRandom::new(1, 1, max).unwrap();
+        let mut gaussians = Array2::<f64>::zeros((2, 1));
+        sobol.fill_gaussians(0, max - 2, gaussians.view_mut());
+        assert!(gaussians.iter().all(|g| g.is_finite()));
+    }
+
+    #[test]
+    fn sobol_gaussians_have_unit_variance() {
+        let n = 4095;
+        let sobol = SobolRandom::new(7, 3, n).unwrap();
+        let mut gaussians = Array2::<f64>::zeros((n, 21));
+        sobol.fill_gaussians(0, 0, gaussians.view_mut());
+        for column in gaussians.gencolumns() {
+            let mean = column.scalar_sum() / n as f64;
+            let var = column.iter().map(|x| x * x).sum::<f64>() / n as f64;
+            assert!(approx_eq(mean, 0.0, 0.01), "mean={}", mean);
+            assert!(approx_eq(var, 1.0, 0.02), "var={}", var);
+        }
+    }
+
+    #[test]
+    fn brownian_bridge_terminal_value() {
+        let bridge = BrownianBridge::new(5);
+        let gaussians = [1.0, 0.0, 0.0, 0.0, 0.0];
+        let mut increments = [0.0; 5];
+        bridge.increments(&gaussians, &mut increments);
+        let total: f64 = increments.iter().sum();
+        assert!(approx_eq(total, 5.0_f64.sqrt(), 1e-14));
+        for dw in increments.iter() {
+            assert!(approx_eq(*dw, 5.0_f64.sqrt() / 5.0, 1e-14));
+        }
+    }
+}
@@ -0,0 +1,139 @@
+use core::qm;
+use std::sync::Arc;
+use instruments::RcInstrument;
+use risk::Pricer;
+use pricers::PricerFactory;
+use pricers::selfpricer::SelfPricer;
+use pricers::montecarlo::MonteCarloPricer;
+use data::fixings::RcFixingTable;
+use risk::marketdata::RcMarketData;
+use models::RcMonteCarloModelFactory;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use serde::Deserialize;
+use erased_serde as esd;
+
+/// The AutoPricerFactory chooses the pricer for each instrument. If the
+/// instrument, once fixed, can be valued exactly by an analytic formula, it
+/// gets a SelfPricer, which avoids any Monte-Carlo noise. Otherwise, it gets
+/// a MonteCarloPricer using the supplied model. This makes it suitable for
+/// pricing a mixed book of vanillas and exotics.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AutoPricerFactory {
+    model_factory: RcMonteCarloModelFactory
+}
+
+impl AutoPricerFactory {
+    /// Constructs a factory, given the model to use for any instruments that
+    /// need to be priced by Monte-Carlo. The model is only built when such
+    /// an instrument is priced.
+    pub fn new(model_factory: RcMonteCarloModelFactory) -> AutoPricerFactory {
+        AutoPricerFactory { model_factory: model_factory }
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<PricerFactory>, esd::Error> {
+        Ok(Qrc::new(Arc::new(AutoPricerFactory::deserialize(de)?)))
+    }
+}
+
+impl TypeId for AutoPricerFactory {
+    fn get_type_id(&self) -> &'static str { "AutoPricerFactory" }
+}
+
+impl PricerFactory for AutoPricerFactory {
+    fn new(&self, instrument: RcInstrument, fixing_table: RcFixingTable,
+        market_data: RcMarketData) -> Result<Box<Pricer>, qm::Error> {
+
+        // Apply the fixings to the instrument. (This is the last time we need
+        // the fixings.)
+        let instruments = match instrument.fix(&*fixing_table)? {
+            Some(fixed) => fixed,
+            None => vec!((1.0, instrument))
+        };
+
+        if instruments.iter().all(|&(_, ref i)| i.as_analytic_priceable().is_some()) {
+            let pricer = SelfPricer::new(instruments, &*market_data)?;
+            return Ok(Box::new(pricer))
+        }
+
+        let pricer = MonteCarloPricer::new(instruments, self.model_factory.clone(), &*market_data)?;
+        Ok(Box::new(pricer))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use models::random::BASE_SEED;
+    use math::numerics::approx_eq;
+    use dates::Date;
+    use data::fixings::FixingTable;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::marketdata::tests::sample_european;
+    use instruments::asian::tests::sample_asian;
+    use instruments::options::PutOrCall;
+    use instruments::spread::tests::sample_spread;
+    use instruments::spread::tests::sample_spread_market_data;
+    use models::VarianceReduction;
+    use models::blackdiffusion::BlackDiffusionFactory;
+    use pricers::montecarlo::MonteCarloPricerFactory;
+
+    fn sample_model_factory() -> RcMonteCarloModelFactory {
+        RcMonteCarloModelFactory::new(Arc::new(BlackDiffusionFactory::new(
+            20, 0.01, 2000, VarianceReduction::None, Some(BASE_SEED)).unwrap()))
+    }
+
+    #[test]
+    fn auto_pricer_prices_european_analytically() {
+        let market_data = RcMarketData::new(Arc::new(sample_market_data()));
+        let instrument = RcInstrument::new(Qrc::new(sample_european()));
+        let fixings = RcFixingTable::new(Arc::new(FixingTable::new(Date::from_ymd(2017, 01, 02))));
+
+        // With only 2000 paths, Monte-Carlo would be out by cents, so this
+        // is only exact if the pricer is analytic
+        let factory = AutoPricerFactory::new(sample_model_factory());
+        let pricer = factory.new(instrument, fixings, market_data).unwrap();
+        assert_approx(pricer.price().unwrap(), 16.710717400832973, 1e-9);
+    }
+
+    #[test]
+    fn auto_pricer_falls_back_to_monte_carlo_for_asian() {
+        let market_data = RcMarketData::new(Arc::new(sample_market_data()));
+        let instrument = RcInstrument::new(Qrc::new(sample_asian()));
+        let fixings = RcFixingTable::new(Arc::new(FixingTable::new(Date::from_ymd(2017, 01, 02))));
+        assert!(instrument.as_analytic_priceable().is_none());
+
+        // The Asian gets exactly the same price as from a Monte-Carlo pricer
+        // with the same model
+        let factory = AutoPricerFactory::new(sample_model_factory());
+        let pricer = factory.new(instrument.clone(), fixings.clone(),
+            market_data.clone()).unwrap();
+        let mc_factory = MonteCarloPricerFactory::new(sample_model_factory());
+        let mc_pricer = mc_factory.new(instrument, fixings, market_data).unwrap();
+        assert_eq!(pricer.price().unwrap(), mc_pricer.price().unwrap());
+    }
+
+    #[test]
+    fn auto_pricer_falls_back_to_monte_carlo_for_spread() {
+        let market_data = RcMarketData::new(Arc::new(sample_spread_market_data(0.5)));
+        let instrument = RcInstrument::new(Qrc::new(Arc::new(
+            sample_spread(5.0, PutOrCall::Call))));
+        let fixings = RcFixingTable::new(Arc::new(FixingTable::new(Date::from_ymd(2017, 01, 02))));
+
+        // Kirk's formula is only approximate, so the spread is simulated
+        // even though it is priceable
+        assert!(instrument.as_priceable().is_some());
+        assert!(instrument.as_analytic_priceable().is_none());
+        let factory = AutoPricerFactory::new(sample_model_factory());
+        let pricer = factory.new(instrument.clone(), fixings.clone(),
+            market_data.clone()).unwrap();
+        let mc_factory = MonteCarloPricerFactory::new(sample_model_factory());
+        let mc_pricer = mc_factory.new(instrument, fixings, market_data).unwrap();
+        assert_eq!(pricer.price().unwrap(), mc_pricer.price().unwrap());
+    }
+
+    fn assert_approx(value: f64, expected: f64, tolerance: f64) {
+        assert!(approx_eq(value, expected, tolerance),
+            "value={} expected={}", value, expected);
+    }
+}
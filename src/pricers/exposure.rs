@@ -0,0 +1,320 @@
+use std::collections::HashMap;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::cell::RefCell;
+use std::sync::Arc;
+use core::qm;
+use instruments::RcInstrument;
+use instruments::PricingContext;
+use instruments::DependencyContext;
+use instruments::MonteCarloContext;
+use instruments::MonteCarloDependencies;
+use instruments::ObservationKind;
+use models::MonteCarloModel;
+use models::MonteCarloTimeline;
+use models::TaggedObservations;
+use models::RcMonteCarloModelFactory;
+use pricers::montecarlo::add_path_batches;
+use risk::cache::PricingContextPrefetch;
+use risk::dependencies::DependencyCollector;
+use risk::marketdata::MarketData;
+use math::regression::polynomial_fit;
+use math::regression::polynomial_value;
+use dates::Date;
+use dates::datetime::DateTime;
+use dates::datetime::DateDayFraction;
+use dates::datetime::TimeOfDay;
+use ndarray::Axis;
+use ndarray::Array1;
+use ndarray::Array2;
+use ndarray::ArrayView2;
+
+/// The degree of the polynomial in the underlyings used to estimate the
+/// mark-to-market on each path
+const EXPOSURE_BASIS_DEGREE: usize = 3;
+
+/// Calculates the expected positive exposure of a set of weighted
+/// instruments at each of the given dates. See
+/// MonteCarloPricer::exposure_profile.
+///
+/// The underlyings are simulated with extra observations on the exposure
+/// dates, up to the last date on which the instruments observe them. Each
+/// Monte-Carlo instrument is valued on these paths, seeing only its own
+/// observations, and the quantity of each of its flows on each path is
+/// recorded. The flows paying after an exposure date make up the remaining
+/// payoff, whose mark-to-market on each path is estimated by least-squares
+/// regression against the underlyings observed on or before that date, as
+/// in Longstaff-Schwartz. Instruments valued analytically add the same
+/// value to every path.
+///
+/// If the model factory has a convergence target, the model adds the given
+/// number of batches of paths, as found when converging the price, rather
+/// than simulating just the first batch.
+pub fn exposure_profile(instruments: &[(f64, RcInstrument)],
+    model_factory: &RcMonteCarloModelFactory, market_data: &MarketData,
+    min_spacing: Option<u32>, path_batches: usize, dates: &[Date])
+    -> Result<Vec<f64>, qm::Error> {
+
+    let spot_date = market_data.spot_date();
+
+    // Record the observations and flows of each Monte-Carlo instrument on
+    // its own. Anything else has been validated as analytic.
+    let mut dependencies = DependencyCollector::new(spot_date);
+    let mut simulated = Vec::new();
+    let mut analytic = Vec::new();
+    for &(weight, ref instrument) in instruments.iter() {
+        dependencies.spot(instrument);
+        if let Some(mc) = instrument.as_mc_priceable() {
+            let mut own = MonteCarloTimeline::new(spot_date);
+            own.set_payment_currency(instrument.payoff_currency().id());
+            mc.mc_dependencies(&[], &mut own)?;
+            own.collate()?;
+            simulated.push((weight, instrument.clone(), own));
+        } else {
+            analytic.push((weight, instrument.clone()));
+        }
+    }
+
+    let context = PricingContextPrefetch::new(market_data, Arc::new(dependencies))?;
+    let mut analytic_values = Vec::with_capacity(dates.len());
+    for date in dates.iter() {
+        analytic_values.push(analytic_exposure(&analytic, &context, *date)?);
+    }
+
+    // With nothing to simulate, the exposure is deterministic
+    if simulated.is_empty() {
+        return Ok(analytic_values.iter().map(|v| v.max(0.0)).collect())
+    }
+
+    // Merge the observations of each underlying with the exposure dates,
+    // in time order, keeping the reasons for each
+    let mut tagged: HashMap<RcInstrument, TaggedObservations> = HashMap::new();
+    for &(_, _, ref own) in simulated.iter() {
+        for underlying in own.observations().keys() {
+            let merged = tagged.entry(underlying.clone()).or_insert_with(BTreeMap::new);
+            let observations = own.tagged_observations(underlying)
+                .ok_or_else(|| qm::Error::new(&format!("No tagged observations \
+                    of '{}' in the timeline of an exposure", underlying.id())))?;
+            for (observation, kinds) in observations {
+                merged.entry(*observation).or_insert_with(BTreeSet::new)
+                    .extend(kinds.iter().cloned());
+            }
+        }
+    }
+    for (underlying, observations) in tagged.iter_mut() {
+        let last = observations.keys().map(|o| o.date()).max().unwrap_or(spot_date);
+        for date in dates.iter().filter(|d| **d > spot_date && **d <= last) {
+            let observation = underlying.time_to_day_fraction(
+                DateTime::new(*date, TimeOfDay::Close))?;
+            observations.entry(observation).or_insert_with(BTreeSet::new)
+                .insert(ObservationKind::Exposure);
+        }
+    }
+    let merged: HashMap<RcInstrument, Vec<DateDayFraction>> = tagged.iter()
+        .map(|(underlying, observations)|
+            (underlying.clone(), observations.keys().cloned().collect()))
+        .collect();
+
+    let mut timeline = MonteCarloTimeline::new(spot_date);
+    if let Some(days) = min_spacing {
+        timeline = timeline.with_min_spacing(days);
+    }
+    for (underlying, observations) in tagged.iter() {
+        for (observation, kinds) in observations.iter() {
+            let mut kinds = kinds.iter();
+            if let Some(kind) = kinds.next() {
+                timeline.observation(underlying, *observation, *kind);
+            }
+            for kind in kinds {
+                timeline.tag(underlying, *observation, *kind);
+            }
+        }
+    }
+    for &(_, _, ref own) in simulated.iter() {
+        for flow in own.flows().iter() {
+            timeline.flow(flow);
+        }
+        timeline.merge_payment_currencies(own);
+    }
+    timeline.collate()?;
+    let mut model = model_factory.factory(&timeline, Box::new(context))?;
+    if let Some(target) = model_factory.convergence_target() {
+        add_path_batches(&mut *model, path_batches, target.max_paths)?;
+    }
+
+    // Value each instrument, accumulating the present value on each path
+    // of the flows paying after each exposure date
+    let n_paths = {
+        let first = merged.keys().next().unwrap();
+        model.as_mc_context().paths(first)?.shape()[0]
+    };
+    let mut remaining: Array2<f64> = Array2::zeros((dates.len(), n_paths));
+    for &(weight, ref instrument, ref own) in simulated.iter() {
+        let exposure_context = ExposureContext::new(&*model, own, &merged)?;
+        instrument.as_mc_priceable().unwrap().mc_price(&exposure_context)?;
+        let quantities = exposure_context.quantities.borrow_mut().take()
+            .ok_or_else(|| qm::Error::new(&format!(
+                "Instrument {} did not evaluate its flows", instrument.id())))?;
+
+        for ((flow, value), quantity) in own.flows().iter()
+            .zip(exposure_context.flow_values.iter())
+            .zip(quantities.axis_iter(Axis(1))) {
+            let pay_date = payment_date(flow, spot_date);
+            for (date, mut row) in dates.iter().zip(remaining.axis_iter_mut(Axis(0))) {
+                if pay_date > *date {
+                    row.scaled_add(weight * value, &quantity);
+                }
+            }
+        }
+    }
+
+    // Estimate the mark-to-market on each path by regression, and average
+    // its positive part
+    let mut profile = Vec::with_capacity(dates.len());
+    for ((date, row), analytic_value) in dates.iter()
+        .zip(remaining.axis_iter(Axis(0))).zip(analytic_values.iter()) {
+
+        let y = row.to_vec();
+        let mean = y.iter().sum::<f64>() / n_paths as f64;
+        let x = regressors(&*model, &merged, spot_date, *date, n_paths)?;
+        let beta = x.as_ref().and_then(|x| polynomial_fit(x, &y, EXPOSURE_BASIS_DEGREE));
+        let positive: f64 = match (x, beta) {
+            (Some(x), Some(beta)) => x.iter().map(|xp|
+                (polynomial_value(&beta, *xp) + analytic_value).max(0.0)).sum(),
+            _ => n_paths as f64 * (mean + analytic_value).max(0.0)
+        };
+        profile.push(positive / n_paths as f64);
+    }
+
+    Ok(profile)
+}
+
+/// The present value of the instruments valued analytically, as of the
+/// given date. Before the spot date, this is just their price.
+fn analytic_exposure(analytic: &[(f64, RcInstrument)], context: &PricingContext,
+    date: Date) -> Result<f64, qm::Error> {
+
+    let spot_date = context.spot_date();
+    let val_date = DateTime::new(date.max(spot_date), TimeOfDay::Open);
+    let mut total = 0.0;
+    for &(weight, ref instrument) in analytic.iter() {
+        let priceable = instrument.as_priceable().unwrap();
+        let value = priceable.price(context, val_date)?;
+        if value != 0.0 && date > spot_date {
+            let yc = context.yield_curve(instrument.credit_id(), date)?;
+            total += weight * value * yc.df(date, spot_date)?;
+        } else {
+            total += weight * value;
+        }
+    }
+    Ok(total)
+}
+
+/// The date on which a flow pays, which is the last date on which it needs
+/// discounting
+fn payment_date(flow: &RcInstrument, spot_date: Date) -> Date {
+    let mut collector = DependencyCollector::new(spot_date);
+    collector.spot(flow);
+    collector.yield_curve_hwm(flow.credit_id()).unwrap_or(spot_date)
+}
+
+/// The variable to regress against on each path, which is the sum of the
+/// latest observation on or before the date of each underlying, each
+/// divided by its mean across the paths. Returns None if no underlying has
+/// been observed by then.
+fn regressors(model: &MonteCarloModel,
+    merged: &HashMap<RcInstrument, Vec<DateDayFraction>>,
+    spot_date: Date, date: Date, n_paths: usize)
+    -> Result<Option<Vec<f64>>, qm::Error> {
+
+    if date <= spot_date {
+        return Ok(None)
+    }
+
+    let mut x = vec![0.0; n_paths];
+    let mut observed = false;
+    for (underlying, observations) in merged.iter() {
+        if let Some(column) = observations.iter().rposition(|o| o.date() <= date) {
+            let paths = model.as_mc_context().paths(underlying)?;
+            let spots = paths.subview(Axis(1), column);
+            let mean = spots.scalar_sum() / n_paths as f64;
+            if mean > 0.0 {
+                for (xp, spot) in x.iter_mut().zip(spots.iter()) {
+                    *xp += spot / mean;
+                }
+                observed = true;
+            }
+        }
+    }
+    Ok(if observed { Some(x) } else { None })
+}
+
+/// Monte-Carlo context that gives an instrument only the columns of the
+/// paths that it observes, and records the quantities of its flows rather
+/// than just their total value.
+struct ExposureContext<'a> {
+    model: &'a MonteCarloModel,
+    paths: HashMap<String, Array2<f64>>,
+    flow_values: Vec<f64>,
+    quantities: RefCell<Option<Array2<f64>>>
+}
+
+impl<'a> ExposureContext<'a> {
+    fn new(model: &'a MonteCarloModel, own: &MonteCarloTimeline,
+        merged: &HashMap<RcInstrument, Vec<DateDayFraction>>)
+        -> Result<ExposureContext<'a>, qm::Error> {
+
+        let context = model.as_mc_context();
+        let mut paths = HashMap::new();
+        for (underlying, observations) in own.observations().iter() {
+            let all = &merged[underlying];
+            let columns: Vec<usize> = observations.iter()
+                .map(|o| all.binary_search(o).unwrap()).collect();
+            let underlying_paths = context.paths(underlying)?;
+            paths.insert(underlying.id().to_string(),
+                underlying_paths.select(Axis(1), &columns));
+        }
+
+        // the value of each flow, as in evaluate_pure_rates_flows
+        let pricing_context = context.pricing_context();
+        let val_date = DateTime::new(pricing_context.spot_date(), TimeOfDay::Open);
+        let mut flow_values = Vec::with_capacity(own.flows().len());
+        for flow in own.flows().iter() {
+            let priceable = flow.as_priceable().ok_or_else(|| qm::Error::new(
+                "All pure-rates flows must be priceable"))?;
+            flow_values.push(priceable.price(pricing_context, val_date)?);
+        }
+
+        Ok(ExposureContext { model, paths, flow_values,
+            quantities: RefCell::new(None) })
+    }
+}
+
+impl<'a> MonteCarloContext for ExposureContext<'a> {
+    fn paths(&self, instrument: &RcInstrument)
+        -> Result<ArrayView2<f64>, qm::Error> {
+        self.paths.get(instrument.id()).map(|p| p.view()).ok_or_else(||
+            qm::Error::new(&format!("No paths for '{}'", instrument.id())))
+    }
+
+    fn evaluate_flows(&self, quantities: ArrayView2<f64>)
+        -> Result<f64, qm::Error> {
+
+        assert_eq!(quantities.shape()[1], self.flow_values.len());
+        let n_paths = quantities.shape()[0] as f64;
+        let values = Array1::from_vec(self.flow_values.clone());
+        let total = quantities.dot(&values).scalar_sum() / n_paths;
+
+        let mut stored = self.quantities.borrow_mut();
+        if let Some(ref mut existing) = *stored {
+            *existing += &quantities;
+            return Ok(total)
+        }
+        *stored = Some(quantities.to_owned());
+        Ok(total)
+    }
+
+    fn pricing_context(&self) -> &PricingContext {
+        self.model.as_mc_context().pricing_context()
+    }
+}
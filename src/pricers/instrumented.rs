@@ -0,0 +1,305 @@
+use core::qm;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+use std::collections::BTreeMap;
+use instruments::RcInstrument;
+use instruments::PricingContext;
+use risk::Pricer;
+use risk::PricerClone;
+use risk::pricereport::PriceReport;
+use risk::dependencies::DependencyCollector;
+use risk::Bumpable;
+use risk::TimeBumpable;
+use risk::Saveable;
+use pricers::PricerFactory;
+use pricers::RcPricerFactory;
+use data::fixings::RcFixingTable;
+use data::bump::Bump;
+use risk::bumptime::BumpTime;
+use risk::marketdata::RcMarketData;
+use risk::marketdata::MarketData;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use serde::Deserialize;
+use erased_serde as esd;
+
+/// The stages of constructing and using a pricer that are timed by the
+/// InstrumentedPricerFactory. Only the MonteCarloPricerFactory reports the
+/// stages of construction. Other factories have all of it timed as
+/// Construction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum PricerStage {
+    /// Applying the fixings to the instrument
+    Fixing,
+    /// Finding the market data and observation dates the instruments need
+    Dependencies,
+    /// Fetching the market data into a pricing context
+    Prefetch,
+    /// Building the model, including any paths generated up front
+    ModelBuild,
+    /// Construction of a pricer by a factory that does not break it down
+    Construction,
+    /// Each call to price or price_report
+    Pricing
+}
+
+/// The total time spent in each stage, and the number of times it was
+/// entered.
+#[derive(Debug, Clone, Default)]
+pub struct StageTimings {
+    timings: BTreeMap<PricerStage, (usize, Duration)>
+}
+
+impl StageTimings {
+    pub fn new() -> StageTimings {
+        StageTimings { timings: BTreeMap::new() }
+    }
+
+    /// Runs the given closure, recording the time it takes against the
+    /// stage, whether or not it succeeds.
+    pub fn time<T, F>(&mut self, stage: PricerStage, f: F) -> T
+        where F: FnOnce() -> T {
+
+        let start = Instant::now();
+        let result = f();
+        self.record(stage, start.elapsed());
+        result
+    }
+
+    /// Adds one entry to the stage, taking the given time
+    pub fn record(&mut self, stage: PricerStage, elapsed: Duration) {
+        let entry = self.timings.entry(stage).or_insert((0, Duration::default()));
+        entry.0 += 1;
+        entry.1 += elapsed;
+    }
+
+    /// Adds all the entries of another set of timings to this one
+    pub fn merge(&mut self, other: &StageTimings) {
+        for (&stage, &(count, elapsed)) in other.timings.iter() {
+            let entry = self.timings.entry(stage).or_insert((0, Duration::default()));
+            entry.0 += count;
+            entry.1 += elapsed;
+        }
+    }
+
+    /// The number of times the stage was entered
+    pub fn count(&self, stage: PricerStage) -> usize {
+        self.timings.get(&stage).map_or(0, |&(count, _)| count)
+    }
+
+    /// The total time spent in the stage
+    pub fn total(&self, stage: PricerStage) -> Duration {
+        self.timings.get(&stage).map_or(Duration::default(), |&(_, elapsed)| elapsed)
+    }
+
+    /// The stages that were entered at least once, in the order they happen
+    pub fn stages(&self) -> Vec<PricerStage> {
+        self.timings.keys().cloned().collect()
+    }
+}
+
+/// A decorator for any pricer factory, which times each stage of creating
+/// a pricer and each call to price the pricers it creates. The pricers and
+/// their prices are exactly those of the wrapped factory. All the pricers
+/// created by the factory, and any clones of them, add to the same timings,
+/// which are not serialized.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct InstrumentedPricerFactory {
+    factory: RcPricerFactory,
+    #[serde(skip)]
+    timings: Arc<Mutex<StageTimings>>
+}
+
+impl InstrumentedPricerFactory {
+    pub fn new(factory: RcPricerFactory) -> InstrumentedPricerFactory {
+        InstrumentedPricerFactory { factory: factory,
+            timings: Arc::new(Mutex::new(StageTimings::new())) }
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<PricerFactory>, esd::Error> {
+        Ok(Qrc::new(Arc::new(InstrumentedPricerFactory::deserialize(de)?)))
+    }
+
+    /// A snapshot of the timings so far
+    pub fn timings(&self) -> StageTimings {
+        match self.timings.lock() {
+            Ok(timings) => timings.clone(),
+            Err(poisoned) => poisoned.into_inner().clone()
+        }
+    }
+
+    /// Clears the timings so far
+    pub fn reset_timings(&self) {
+        merge_into(&self.timings, None);
+    }
+}
+
+/// Adds timings to shared timings, or clears them if there are none. A
+/// panic while another thread held the lock cannot leave the timings in an
+/// invalid state, so we ignore poisoning.
+fn merge_into(shared: &Mutex<StageTimings>, timings: Option<&StageTimings>) {
+    let mut guard = match shared.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner()
+    };
+    match timings {
+        Some(timings) => guard.merge(timings),
+        None => *guard = StageTimings::new()
+    }
+}
+
+impl TypeId for InstrumentedPricerFactory {
+    fn get_type_id(&self) -> &'static str { "InstrumentedPricerFactory" }
+}
+
+impl PricerFactory for InstrumentedPricerFactory {
+    fn new(&self, instrument: RcInstrument, fixings: RcFixingTable,
+        market_data: RcMarketData) -> Result<Box<Pricer>, qm::Error> {
+
+        let mut timings = StageTimings::new();
+        let pricer = self.factory.new_timed(instrument, fixings, market_data,
+            &mut timings);
+        merge_into(&self.timings, Some(&timings));
+
+        Ok(Box::new(InstrumentedPricer { pricer: pricer?,
+            timings: self.timings.clone() }))
+    }
+}
+
+/// A pricer that delegates everything to the pricer it contains, timing
+/// each call to price or price_report.
+pub struct InstrumentedPricer {
+    pricer: Box<Pricer>,
+    timings: Arc<Mutex<StageTimings>>
+}
+
+impl InstrumentedPricer {
+    fn timed<T, F>(&self, f: F) -> T where F: FnOnce(&Pricer) -> T {
+        let start = Instant::now();
+        let result = f(&*self.pricer);
+        let mut timings = StageTimings::new();
+        timings.record(PricerStage::Pricing, start.elapsed());
+        merge_into(&self.timings, Some(&timings));
+        result
+    }
+}
+
+impl Pricer for InstrumentedPricer {
+    fn as_bumpable(&self) -> &Bumpable { self }
+    fn as_mut_bumpable(&mut self) -> &mut Bumpable { self }
+    fn as_mut_time_bumpable(&mut self) -> &mut TimeBumpable { self }
+
+    fn price(&self) -> Result<f64, qm::Error> {
+        self.timed(|pricer| pricer.price())
+    }
+
+    fn price_report(&self) -> Result<PriceReport, qm::Error> {
+        self.timed(|pricer| pricer.price_report())
+    }
+
+    fn refresh_market_data(&mut self, market_data: &MarketData)
+        -> Result<(), qm::Error> {
+        self.pricer.refresh_market_data(market_data)
+    }
+}
+
+impl PricerClone for InstrumentedPricer {
+    fn clone_box(&self) -> Box<Pricer> {
+        Box::new(InstrumentedPricer { pricer: self.pricer.clone_box(),
+            timings: self.timings.clone() })
+    }
+}
+
+impl Bumpable for InstrumentedPricer {
+    fn bump(&mut self, bump: &Bump, save: Option<&mut Saveable>)
+        -> Result<bool, qm::Error> {
+        self.pricer.bump(bump, save)
+    }
+
+    fn dependencies(&self) -> Result<&DependencyCollector, qm::Error> {
+        self.pricer.as_bumpable().dependencies()
+    }
+
+    fn context(&self) -> &PricingContext {
+        self.pricer.as_bumpable().context()
+    }
+
+    fn new_saveable(&self) -> Box<Saveable> {
+        self.pricer.new_saveable()
+    }
+
+    fn restore(&mut self, saved: &Saveable) -> Result<(), qm::Error> {
+        self.pricer.restore(saved)
+    }
+
+    fn checkpoint(&self) -> Result<Vec<u8>, qm::Error> {
+        self.pricer.checkpoint()
+    }
+
+    fn restore_checkpoint(&mut self, checkpoint: &[u8]) -> Result<(), qm::Error> {
+        self.pricer.restore_checkpoint(checkpoint)
+    }
+}
+
+impl TimeBumpable for InstrumentedPricer {
+    fn bump_time(&mut self, bump: &BumpTime) -> Result<(), qm::Error> {
+        self.pricer.bump_time(bump)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use models::random::BASE_SEED;
+    use dates::Date;
+    use data::fixings::FixingTable;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::marketdata::tests::sample_european;
+    use models::VarianceReduction;
+    use models::RcMonteCarloModelFactory;
+    use models::blackdiffusion::BlackDiffusionFactory;
+    use pricers::montecarlo::MonteCarloPricerFactory;
+
+    #[test]
+    fn instrumented_monte_carlo_times_each_stage() {
+        let market_data = RcMarketData::new(Arc::new(sample_market_data()));
+        let instrument = RcInstrument::new(Qrc::new(sample_european()));
+        let fixings = RcFixingTable::new(Arc::new(FixingTable::new(Date::from_ymd(2017, 01, 02))));
+        let model_factory = RcMonteCarloModelFactory::new(Arc::new(BlackDiffusionFactory::new(
+            20, 0.01, 2000, VarianceReduction::None, Some(BASE_SEED)).unwrap()));
+        let mc_factory = RcPricerFactory::new(Arc::new(
+            MonteCarloPricerFactory::new(model_factory)));
+
+        let unwrapped = mc_factory.new(instrument.clone(), fixings.clone(),
+            market_data.clone()).unwrap().price().unwrap();
+
+        let start = Instant::now();
+        let factory = InstrumentedPricerFactory::new(mc_factory);
+        let pricer = factory.new(instrument, fixings, market_data).unwrap();
+        assert_eq!(pricer.price().unwrap(), unwrapped);
+        let elapsed = start.elapsed();
+
+        let timings = factory.timings();
+        assert_eq!(timings.stages(), vec!(PricerStage::Fixing,
+            PricerStage::Dependencies, PricerStage::Prefetch,
+            PricerStage::ModelBuild, PricerStage::Pricing));
+        // a coarse clock may time a quick stage as zero, but the stages
+        // cannot add up to more than the time taken overall
+        let mut total = Duration::default();
+        for stage in timings.stages() {
+            assert_eq!(timings.count(stage), 1, "stage={:?}", stage);
+            total += timings.total(stage);
+        }
+        assert!(total <= elapsed, "total={:?} elapsed={:?}", total, elapsed);
+
+        // clones of the pricer add to the same timings
+        let clone = pricer.clone_box();
+        assert_eq!(clone.price().unwrap(), unwrapped);
+        assert_eq!(factory.timings().count(PricerStage::Pricing), 2);
+
+        factory.reset_timings();
+        assert!(factory.timings().stages().is_empty());
+    }
+}
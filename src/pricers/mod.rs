@@ -1,7 +1,16 @@
+pub mod auto;
+pub mod exposure;
+pub mod instrumented;
 pub mod montecarlo;
+pub mod pde;
 pub mod selfpricer;
 
+use pricers::auto::AutoPricerFactory;
+use pricers::instrumented::InstrumentedPricerFactory;
+use pricers::instrumented::PricerStage;
+use pricers::instrumented::StageTimings;
 use pricers::montecarlo::MonteCarloPricerFactory;
+use pricers::pde::PdePricerFactory;
 use pricers::selfpricer::SelfPricerFactory;
 use core::qm;
 use core::factories::{TypeId, Qrc, Registry};
@@ -24,6 +33,16 @@ pub trait PricerFactory : esd::Serialize + TypeId + Sync + Send + Debug {
     /// than Rc, allowing multithreaded use across different pricers.)
     fn new(&self, instrument: RcInstrument, fixings: RcFixingTable, 
         market_data: RcMarketData) -> Result<Box<Pricer>, qm::Error>;
+
+    /// Creates a pricer exactly as new does, adding the time spent in each
+    /// stage of construction to the timings. By default, the whole of the
+    /// construction is timed as a single stage.
+    fn new_timed(&self, instrument: RcInstrument, fixings: RcFixingTable,
+        market_data: RcMarketData, timings: &mut StageTimings)
+        -> Result<Box<Pricer>, qm::Error> {
+        timings.time(PricerStage::Construction,
+            || self.new(instrument, fixings, market_data))
+    }
 }
 
 // Get serialization to work recursively for instruments by using the
@@ -45,7 +64,10 @@ pub fn get_registry() -> &'static TypeRegistry {
     lazy_static! {
         static ref REG: TypeRegistry = {
             let mut reg = TypeRegistry::new();
+            reg.insert("AutoPricerFactory", BoxFnSeed::new(AutoPricerFactory::from_serial));
+            reg.insert("InstrumentedPricerFactory", BoxFnSeed::new(InstrumentedPricerFactory::from_serial));
             reg.insert("MonteCarloPricerFactory", BoxFnSeed::new(MonteCarloPricerFactory::from_serial));
+            reg.insert("PdePricerFactory", BoxFnSeed::new(PdePricerFactory::from_serial));
             reg.insert("SelfPricerFactory", BoxFnSeed::new(SelfPricerFactory::from_serial));
             reg
         };
@@ -1,22 +1,36 @@
 use core::qm;
 use std::sync::Arc;
+use std::cell::Ref;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
 use instruments::RcInstrument;
 use instruments::PricingContext;
 use instruments::DependencyContext;
 use risk::cache::PricingContextPrefetch;
 use risk::Pricer;
 use risk::PricerClone;
+use risk::pricereport::PriceReport;
 use risk::dependencies::DependencyCollector;
 use risk::Bumpable;
 use risk::TimeBumpable;
 use risk::Saveable;
+use risk::BumpablePricingContext;
 use pricers::PricerFactory;
+use pricers::instrumented::PricerStage;
+use pricers::instrumented::StageTimings;
+use pricers::selfpricer::SelfPricer;
+use pricers::exposure::exposure_profile;
 use data::fixings::RcFixingTable;
 use data::bump::Bump;
 use risk::bumptime::BumpTime;
 use risk::marketdata::RcMarketData;
 use risk::marketdata::MarketData;
+use dates::Date;
+use dates::datetime::DateTime;
+use dates::datetime::TimeOfDay;
 use models::MonteCarloModel;
+use models::ConvergenceTarget;
+use math::moments::RunningMoments;
 use models::RcMonteCarloModelFactory;
 use models::MonteCarloTimeline;
 use core::factories::TypeId;
@@ -27,19 +41,40 @@ use erased_serde as esd;
 /// The MonteCarlo calculator uses the MonteCarloPriceable interface of an
 /// instrument to evaluate the instrument . It then exposes this
 /// interface as a Pricer, allowing bumping for risk calculation.
+///
+/// Instruments that are priced purely off the yield curve, such as streams
+/// of known cashflows, need no simulation. If they are not Monte-Carlo
+/// priceable, they are valued analytically instead.
 #[derive(Clone)]
 pub struct MonteCarloPricer {
     model_factory: RcMonteCarloModelFactory,
     instruments: Vec<(f64, RcInstrument)>,
-    model: Box<MonteCarloModel>
+    model: Box<MonteCarloModel>,
+    timeline: MonteCarloTimeline,
+    min_spacing: Option<u32>,
+
+    // batches of paths added to the first while converging, if the model
+    // factory has a convergence target. Rebuilt models add the same number,
+    // so bumped prices use as many paths as the unbumped one.
+    path_batches: usize,
+
+    // diagnostics, only populated if retain_path_payoffs is set
+    retain_path_payoffs: bool,
+    path_payoffs: RefCell<Option<Vec<f64>>>
 }
 
 /// The MonteCarloPricerFactory is used to construct MonteCarloPricer pricers.
 /// It means that the interface for constructing pricers is independent of
 /// what sort of pricer it is.
+///
+/// If nothing needs simulating, because the instruments are analytic or
+/// their values are deterministic, such as options expiring on the spot
+/// date, the factory returns a SelfPricer instead.
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct MonteCarloPricerFactory {
-    model_factory: RcMonteCarloModelFactory
+    model_factory: RcMonteCarloModelFactory,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    min_spacing: Option<u32>
 }
 
 impl MonteCarloPricerFactory {
@@ -52,7 +87,15 @@ impl MonteCarloPricerFactory {
     pub fn new(model_factory: RcMonteCarloModelFactory)
         -> MonteCarloPricerFactory {
 
-        MonteCarloPricerFactory { model_factory: model_factory }
+        MonteCarloPricerFactory { model_factory: model_factory, min_spacing: None }
+    }
+
+    /// Asks the model to take diffusion steps no more than the given number
+    /// of days apart, however sparse the observations of the instruments.
+    /// See MonteCarloTimeline::with_min_spacing.
+    pub fn with_min_spacing(mut self, days: u32) -> MonteCarloPricerFactory {
+        self.min_spacing = Some(days);
+        self
     }
 
     pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<PricerFactory>, esd::Error> {
@@ -67,76 +110,512 @@ impl TypeId for MonteCarloPricerFactory {
 impl PricerFactory for MonteCarloPricerFactory {
     fn new(&self, instrument: RcInstrument, fixing_table: RcFixingTable, 
         market_data: RcMarketData) -> Result<Box<Pricer>, qm::Error> {
+        self.new_timed(instrument, fixing_table, market_data,
+            &mut StageTimings::new())
+    }
+
+    fn new_timed(&self, instrument: RcInstrument, fixing_table: RcFixingTable,
+        market_data: RcMarketData, timings: &mut StageTimings)
+        -> Result<Box<Pricer>, qm::Error> {
 
         // Apply the fixings to the instrument. (This is the last time we need
         // the fixings.)
-        let instruments = match instrument.fix(&*fixing_table)? {
+        let instruments = timings.time(PricerStage::Fixing,
+            || instrument.fix(&*fixing_table))?;
+        let instruments = match instruments {
             Some(fixed) => fixed,
             None => vec!((1.0, instrument))
         };
+        let spot_date = market_data.spot_date();
+
+        // If nothing needs simulating, there is no need for a model at all
+        if instruments.iter().all(|&(_, ref i)| i.as_mc_priceable().is_none()) {
+            let dependencies = timings.time(PricerStage::Dependencies,
+                || SelfPricer::collect_dependencies(&instruments, spot_date))?;
+            let context = timings.time(PricerStage::Prefetch,
+                || PricingContextPrefetch::new(&*market_data, Arc::new(dependencies)))?;
+            let pricer = SelfPricer::from_context(instruments, context)?;
+            return Ok(Box::new(pricer))
+        }
+
+        let (context, timeline) = prefetch(&instruments, &*market_data,
+            self.min_spacing, timings)?;
 
-        let pricer = MonteCarloPricer::new(instruments, self.model_factory.clone(), &*market_data)?;
+        // If the values are all known without simulation, for example for
+        // options expiring today, simulating would only add noise
+        if SelfPricer::all_deterministic(&instruments, &context)? {
+            return Ok(Box::new(SelfPricer::from_deterministic(instruments, context)?))
+        }
+
+        let pricer = timings.time(PricerStage::ModelBuild,
+            || MonteCarloPricer::from_context(instruments, self.model_factory.clone(),
+                &timeline, Box::new(context), self.min_spacing, None))?;
         Ok(Box::new(pricer))
     }
 }
 
+impl MonteCarloPricerFactory {
+
+    /// Creates a pricer for each of a batch of instruments, all priced off
+    /// the same fixings and market data. The result is the same as invoking
+    /// new for each instrument in turn, but the forwards and vol surfaces
+    /// are only fetched once for the whole batch, which is much faster if
+    /// many instruments share underlyings. Each pricer still has its own
+    /// copy of the market data for bumping, and reports risk only to its
+    /// own dependencies.
+    pub fn new_batch(&self, instruments: &[RcInstrument],
+        fixing_table: RcFixingTable, market_data: RcMarketData)
+        -> Result<Vec<Box<Pricer>>, qm::Error> {
+
+        // Apply the fixings to each instrument
+        let mut fixed = Vec::with_capacity(instruments.len());
+        for instrument in instruments.iter() {
+            fixed.push(match instrument.fix(&*fixing_table)? {
+                Some(fixed) => fixed,
+                None => vec!((1.0, instrument.clone()))
+            });
+        }
+
+        // Prefetch the union of the dependencies of all the instruments
+        let mut dependencies = DependencyCollector::new(market_data.spot_date());
+        for components in fixed.iter() {
+            for &(_, ref instr) in components.iter() {
+                dependencies.spot(instr);
+            }
+        }
+        let shared = PricingContextPrefetch::new(&*market_data,
+            Arc::new(dependencies))?;
+
+        // Each pricer gets a context restricted to its own dependencies
+        let mut pricers = Vec::with_capacity(fixed.len());
+        for components in fixed.into_iter() {
+            let (dependencies, timeline) = collect_dependencies(
+                &components, market_data.spot_date(), 1, self.min_spacing)?;
+            let context = shared.subset(Arc::new(dependencies))?;
+
+            let pricer : Box<Pricer> = if components.iter().all(
+                |&(_, ref i)| i.as_mc_priceable().is_none()) {
+                Box::new(SelfPricer::from_context(components, context)?)
+            } else if SelfPricer::all_deterministic(&components, &context)? {
+                Box::new(SelfPricer::from_deterministic(components, context)?)
+            } else {
+                Box::new(MonteCarloPricer::from_context(components,
+                    self.model_factory.clone(), &timeline, Box::new(context),
+                    self.min_spacing, None)?)
+            };
+            pricers.push(pricer);
+        }
+
+        Ok(pricers)
+    }
+}
+
 impl MonteCarloPricer {
     pub fn new(instruments:  Vec<(f64, RcInstrument)>,
         model_factory: RcMonteCarloModelFactory, market_data: &MarketData)
         -> Result<MonteCarloPricer, qm::Error> {
+        MonteCarloPricer::build(instruments, model_factory, market_data, None,
+            None, &mut StageTimings::new())
+    }
 
-        // Find the dependencies of the resulting vector of instruments,
-        // also validate that all instruments are priceable by Monte-Carlo
-        // and fetch the timeline.
-        let spot_date = market_data.spot_date();
-        let mut dependencies = DependencyCollector::new(spot_date);
-        let mut timeline: MonteCarloTimeline 
-            = MonteCarloTimeline::new(spot_date);
-        let dates_to_value = Vec::new();
-        for &(_, ref instr) in instruments.iter() {
-            dependencies.spot(instr);
-            if let Some(mc) = instr.as_mc_priceable() {
-               mc.mc_dependencies(&dates_to_value, &mut timeline)?;
-            } else {
-                return Err(qm::Error::new(&format!("Instrument {} is not \
-                    priceable by MonteCarlo", instr.id())))
-            } 
-        }
-        timeline.collate()?;
+    /// Creates a pricer whose model takes diffusion steps no more than
+    /// min_spacing days apart, if it is supplied, adding the time spent in
+    /// each stage to the timings. See from_context for path_batches.
+    fn build(instruments:  Vec<(f64, RcInstrument)>,
+        model_factory: RcMonteCarloModelFactory, market_data: &MarketData,
+        min_spacing: Option<u32>, path_batches: Option<usize>,
+        timings: &mut StageTimings) -> Result<MonteCarloPricer, qm::Error> {
 
-        // Create a cached pricing context, prefetching the data to price them
-        let context = Box::new(PricingContextPrefetch::new(market_data,
-            Arc::new(dependencies))?);
+        let (context, timeline) = prefetch(&instruments, market_data,
+            min_spacing, timings)?;
+        timings.time(PricerStage::ModelBuild,
+            || MonteCarloPricer::from_context(instruments, model_factory,
+                &timeline, Box::new(context), min_spacing, path_batches))
+    }
+
+    /// Creates a pricer given a context that has already prefetched the
+    /// dependencies of the instruments, and the timeline they need. If the
+    /// model factory has a convergence target, batches of paths are added
+    /// until the price converges, unless path_batches gives the number of
+    /// batches to add, as found when converging an earlier pricer.
+    fn from_context(instruments: Vec<(f64, RcInstrument)>,
+        model_factory: RcMonteCarloModelFactory, timeline: &MonteCarloTimeline,
+        context: Box<BumpablePricingContext>, min_spacing: Option<u32>,
+        path_batches: Option<usize>) -> Result<MonteCarloPricer, qm::Error> {
 
         // Create a Monte-Carlo model
-        let model = model_factory.factory(&timeline, context)?;
+        let model = model_factory.factory(timeline, context)?;
+        let target = model_factory.convergence_target();
 
-        Ok(MonteCarloPricer { model_factory, instruments, model })
+        let mut pricer = MonteCarloPricer { model_factory, instruments, model,
+            timeline: timeline.clone(), min_spacing, path_batches: 0,
+            retain_path_payoffs: false, path_payoffs: RefCell::new(None) };
+        if let Some(target) = target {
+            let batches = match path_batches {
+                Some(batches) => add_path_batches(&mut *pricer.model,
+                    batches, target.max_paths)?,
+                None => pricer.converge(target)?
+            };
+            pricer.path_batches = batches;
+        }
+        Ok(pricer)
     }
-}
 
-impl Pricer for MonteCarloPricer {
-    fn as_bumpable(&self) -> &Bumpable { self }
-    fn as_mut_bumpable(&mut self) -> &mut Bumpable { self }
-    fn as_mut_time_bumpable(&mut self) -> &mut TimeBumpable { self }
+    /// Adds batches of paths to the model until the standard error of the
+    /// price is within the target relative to the price, or the model has
+    /// target.max_paths paths. Only the batch just added is valued each
+    /// time, and its moments merged with those of the earlier batches, so
+    /// the cost is linear in the number of paths. This is done once, before
+    /// any bumping, so all risks use the same paths. Returns the number of
+    /// batches added after the first.
+    fn converge(&mut self, target: ConvergenceTarget) -> Result<usize, qm::Error> {
+
+        let mut moments = RunningMoments::new();
+        let mut batches = 0;
+        loop {
+            self.model.value_latest_batch_only(true);
+            let latest = self.report_with_path_moments();
+            self.model.value_latest_batch_only(false);
 
-    fn price(&self) -> Result<f64, qm::Error> {
+            // with nothing simulated, there is no error to reduce
+            match latest?.1 {
+                Some(latest) => moments.merge(&latest),
+                None => return Ok(batches)
+            }
+
+            if moments.stderr() <= target.rel_error * moments.mean().abs()
+                || !self.model.add_path_batch(target.max_paths)? {
+                return Ok(batches)
+            }
+            batches += 1;
+        }
+    }
+
+    /// Turns on a diagnostic mode, where every valuation retains the
+    /// discounted payoff of each path, summed over the instruments. This
+    /// costs memory proportional to the number of paths, so it is off by
+    /// default.
+    pub fn with_path_payoffs(mut self, retain: bool) -> MonteCarloPricer {
+        self.retain_path_payoffs = retain;
+        if !retain {
+            self.path_payoffs = RefCell::new(None);
+        }
+        self
+    }
+
+    /// Returns the discounted payoffs of each path from the most recent
+    /// valuation, if the pricer was built with_path_payoffs. Their mean is
+    /// the price. Antithetic pairs of paths are averaged into a single value.
+    /// Returns None if path payoffs are not being retained, or nothing has
+    /// been valued yet.
+    pub fn last_path_payoffs(&self) -> Option<Ref<Vec<f64>>> {
+        let payoffs = self.path_payoffs.borrow();
+        if payoffs.is_none() {
+            return None
+        }
+        Some(Ref::map(payoffs, |p| p.as_ref().unwrap()))
+    }
+
+    /// Returns the Monte-Carlo price and its standard error, estimated
+    /// from the spread of the values of the individual paths. If there are
+    /// no paths, for example because all flows have already fixed, the
+    /// standard error is zero.
+    ///
+    /// The standard error is only meaningful for pseudo-random numbers. It
+    /// is not a valid error estimate for quasi-random sequences such as
+    /// Sobol.
+    pub fn price_with_stderr(&self) -> Result<(f64, f64), qm::Error> {
+        let (price, moments) = self.price_with_moments()?;
+        Ok((price, moments.stderr()))
+    }
+
+    /// Returns the Monte-Carlo price and the mean and variance of the values
+    /// of the individual paths. The moments are accumulated in a single pass
+    /// with constant memory, so they are cheap even for very large numbers of
+    /// paths. If there are no paths, the moments are empty.
+    pub fn price_with_moments(&self) -> Result<(f64, RunningMoments), qm::Error> {
+        let (report, moments) = self.report_with_path_moments()?;
+        Ok((report.total(), moments.unwrap_or_default()))
+    }
+
+    /// Any warnings raised by the model about the market data, for example
+    /// arbitrage in a vol surface that the model had to correct for.
+    pub fn warnings(&self) -> Vec<String> {
+        self.model.warnings()
+    }
+
+    /// Estimates the price in the limit of infinitely fine time-stepping,
+    /// using Richardson extrapolation. The price is calculated as normal,
+    /// then again with the model rebuilt to take twice as many substeps
+    /// along the timeline. Assuming the discretization error is first order
+    /// in the step size, the extrapolated price is 2 P(2N) - P(N).
+    ///
+    /// If the two prices are statistically indistinguishable, their
+    /// difference is mostly Monte-Carlo noise, which extrapolating would only
+    /// amplify. In that case, the finer price is returned with a warning.
+    pub fn extrapolated_price(&self) -> Result<(f64, Option<String>), qm::Error> {
+        let (coarse, coarse_stderr) = self.price_with_stderr()?;
+        let (fine, fine_stderr) = self.refined(2)?.price_with_stderr()?;
+
+        let stderr = (coarse_stderr * coarse_stderr
+            + fine_stderr * fine_stderr).sqrt();
+        if (fine - coarse).abs() <= INDISTINGUISHABLE_STDERRS * stderr {
+            return Ok((fine, Some(format!("Prices with N and 2N substeps ({} \
+                and {}) are within the Monte-Carlo error ({}), so the finer \
+                price is not extrapolated", coarse, fine, stderr))))
+        }
+        Ok((2.0 * fine - coarse, None))
+    }
+
+    /// Returns the expected positive exposure of the instruments at each of
+    /// the given dates, for use in xVA calculations. This is the mean across
+    /// the paths of max(V(t), 0), where V(t) is the mark-to-market at date t
+    /// of the flows still to pay, discounted to today. Exposures are zero
+    /// once everything has paid.
+    ///
+    /// The paths are simulated afresh, with extra observations at the
+    /// exposure dates, and the mark-to-market on each path is estimated by
+    /// regression against the underlyings. See exposure::exposure_profile.
+    pub fn exposure_profile(&self, dates: &[Date]) -> Result<Vec<f64>, qm::Error> {
+        exposure_profile(&self.instruments, &self.model_factory,
+            self.model.raw_market_data(), self.min_spacing, self.path_batches,
+            dates)
+    }
+
+    /// Returns the expected cashflows of the Monte-Carlo instruments before
+    /// discounting, weighted and summed by payment date, in order of date.
+    /// See MonteCarloPriceable::mc_cashflows. Instruments valued
+    /// analytically are not included, and nor is any correction from a
+    /// control variate, so for instruments with a control the discounted
+    /// cashflows differ from the price by the error in the control.
+    pub fn expected_cashflows(&self) -> Result<Vec<(Date, f64)>, qm::Error> {
+        let mut cashflows = BTreeMap::new();
+        let context = self.model.as_mc_context();
+        for &(weight, ref instrument) in self.instruments.iter() {
+            if let Some(mc) = instrument.as_mc_priceable() {
+                for (date, amount) in mc.mc_cashflows(context)? {
+                    *cashflows.entry(date).or_insert(0.0) += weight * amount;
+                }
+            }
+        }
+        Ok(cashflows.into_iter().collect())
+    }
+
+    /// Creates a pricer for the same instruments and market data, with the
+    /// model rebuilt from a timeline refined by the given factor.
+    fn refined(&self, refinement: usize) -> Result<MonteCarloPricer, qm::Error> {
+        let market_data = self.model.raw_market_data();
+        let (dependencies, timeline) = collect_dependencies(&self.instruments,
+            market_data.spot_date(), refinement, self.min_spacing)?;
+        let context = PricingContextPrefetch::new(market_data,
+            Arc::new(dependencies))?;
+        MonteCarloPricer::from_context(self.instruments.clone(),
+            self.model_factory.clone(), &timeline, Box::new(context),
+            self.min_spacing, Some(self.path_batches))
+    }
+
+    /// Runs the Monte-Carlo simulation for each instrument, accumulating the
+    /// weighted value of each independent sample. Instruments valued
+    /// analytically are worth the same on every path. Returns the mean and
+    /// variance of the path values. Only if the pricer is retaining path
+    /// payoffs are the values themselves kept, for diagnostics.
+    fn report_with_path_moments(&self)
+        -> Result<(PriceReport, Option<RunningMoments>), qm::Error> {
+
+        let mut report = PriceReport::new();
+        let result = self.accumulate_path_values(&mut report);
+
+        // always clear the accumulated values, even if there was an error
+        if !self.retain_path_payoffs {
+            let moments = self.model.take_path_moments();
+            let analytic = result?;
+            return Ok((report, moments.map(|m| m.shifted(analytic))))
+        }
+
+        let values = self.model.take_path_values();
+        let analytic = result?;
+        let payoffs = values.map(|v| v.iter().map(|x| x + analytic).collect::<Vec<f64>>());
+        let moments = payoffs.as_ref().map(|p| {
+            let mut moments = RunningMoments::new();
+            for payoff in p.iter() {
+                moments.add(*payoff);
+            }
+            moments
+        });
+        *self.path_payoffs.borrow_mut() = payoffs;
+        Ok((report, moments))
+    }
+
+    /// Adds the value of each instrument to the report, accumulating the
+    /// weighted value of each path in the model, then stops accumulating.
+    /// With a single contribution to each path, and no payoffs retained,
+    /// the model streams the path values into running moments rather than
+    /// keeping them all. Returns the weighted total of the instruments
+    /// valued analytically.
+    fn accumulate_path_values(&self, report: &mut PriceReport)
+        -> Result<f64, qm::Error> {
+        if !self.retain_path_payoffs && self.path_contributions() == 1 {
+            self.model.stream_path_moments();
+        }
+
+        // always stop accumulating, even if there was an error
+        let result = self.add_to_report(true, report);
+        self.model.accumulate_paths(None);
+        result
+    }
+
+    /// The number of weighted contributions made to the value of each path:
+    /// one for each instrument valued by simulation, and one more for each
+    /// control variate.
+    fn path_contributions(&self) -> usize {
+        self.instruments.iter()
+            .filter(|&&(_, ref instrument)| instrument.as_mc_priceable().is_some())
+            .map(|&(_, ref instrument)|
+                if instrument.as_control_variate().is_some() { 2 } else { 1 })
+            .sum()
+    }
+
+    /// Adds the value of each instrument to the report, optionally
+    /// accumulating the weighted value of each path in the model. Returns
+    /// the weighted total of the instruments valued analytically.
+    fn add_to_report(&self, accumulate: bool, report: &mut PriceReport)
+        -> Result<f64, qm::Error> {
+
+        let mut analytic = 0.0;
 
         // Run a Monte-Carlo simulation to generate a matrix of cashflows
         // per path. Note that we have already verified that the instruments
-        // are all mc priceable, so just skip them if they aren't
-        let mut total = 0.0;
+        // are all mc priceable or analytic, so just skip them if neither.
         for &(weight, ref instrument) in self.instruments.iter() {
+
             if let Some(mc) = instrument.as_mc_priceable() {
                let context = self.model.as_mc_context();
-               total += weight * mc.mc_price(context)?;
+               if accumulate {
+                   self.model.accumulate_paths(Some(weight));
+               }
+               let mut price = mc.mc_price(context)?;
+
+               // If the instrument supplies a control variate, correct the
+               // price by the error in the simulated value of the control.
+               // The per-path values of the control are subtracted too.
+               if let Some(control) = instrument.as_control_variate() {
+                   if accumulate {
+                       self.model.accumulate_paths(Some(-weight));
+                   }
+                   price += control.control_value(context.pricing_context())?
+                       - control.mc_control_price(context)?;
+               }
+
+               report.add(instrument.id(), weight * price);
+
+            } else if is_analytic(instrument) {
+                // Value as of the spot date at the open, like the SelfPricer
+                let context = self.model.as_bumpable().context();
+                let val_date = DateTime::new(context.spot_date(), TimeOfDay::Open);
+                let priceable = instrument.as_priceable().unwrap();
+                let value = weight * priceable.price(context, val_date)?;
+                report.add(instrument.id(), value);
+                analytic += value;
             }
         }
+        Ok(analytic)
+    }
+}
 
-        // Return a weighted sum of the individual prices. (TODO consider
-        // returning some data structure that shows the components as well as
-        // the weighted sum.)
-        Ok(total)
+/// Finds the dependencies of a vector of instruments, also validating that
+/// all instruments are priceable by Monte-Carlo or analytically off the yield
+/// curve, and fetches the timeline, refined by the given factor and with
+/// any minimum spacing of its diffusion steps.
+fn collect_dependencies(instruments: &[(f64, RcInstrument)], spot_date: Date,
+    refinement: usize, min_spacing: Option<u32>)
+    -> Result<(DependencyCollector, MonteCarloTimeline), qm::Error> {
+
+    let mut dependencies = DependencyCollector::new(spot_date);
+    let mut timeline: MonteCarloTimeline 
+        = MonteCarloTimeline::new(spot_date);
+    if let Some(days) = min_spacing {
+        timeline = timeline.with_min_spacing(days);
+    }
+    timeline.refine(refinement);
+    let dates_to_value = Vec::new();
+    for &(_, ref instr) in instruments.iter() {
+        dependencies.spot(instr);
+        if let Some(mc) = instr.as_mc_priceable() {
+           timeline.set_payment_currency(instr.payoff_currency().id());
+           mc.mc_dependencies(&dates_to_value, &mut timeline)?;
+        } else if !is_analytic(instr) {
+            return Err(qm::Error::new(&format!("Instrument {} is not \
+                priceable by MonteCarlo", instr.id())))
+        } 
+    }
+    timeline.collate()?;
+    Ok((dependencies, timeline))
+}
+
+/// Finds the dependencies and timeline of the instruments, and prefetches
+/// the market data they need into a pricing context, adding the time spent
+/// in each stage to the timings.
+fn prefetch(instruments: &[(f64, RcInstrument)], market_data: &MarketData,
+    min_spacing: Option<u32>, timings: &mut StageTimings)
+    -> Result<(PricingContextPrefetch, MonteCarloTimeline), qm::Error> {
+
+    let (dependencies, timeline) = timings.time(PricerStage::Dependencies,
+        || collect_dependencies(instruments, market_data.spot_date(), 1, min_spacing))?;
+    let context = timings.time(PricerStage::Prefetch,
+        || PricingContextPrefetch::new(market_data, Arc::new(dependencies)))?;
+    Ok((context, timeline))
+}
+
+/// The number of standard errors within which extrapolated_price treats the
+/// prices with N and 2N substeps as indistinguishable
+const INDISTINGUISHABLE_STDERRS: f64 = 2.0;
+
+/// Adds up to the given number of batches of paths to a model, stopping
+/// early if it reaches max_paths. Returns the number of batches added.
+pub fn add_path_batches(model: &mut MonteCarloModel, batches: usize,
+    max_paths: usize) -> Result<usize, qm::Error> {
+    for added in 0..batches {
+        if !model.add_path_batch(max_paths)? {
+            return Ok(added)
+        }
+    }
+    Ok(batches)
+}
+
+/// Whether an instrument can be valued analytically off the yield curve,
+/// without simulation.
+fn is_analytic(instrument: &RcInstrument) -> bool {
+    instrument.is_pure_rates() && instrument.as_priceable().is_some()
+}
+
+impl Pricer for MonteCarloPricer {
+    fn as_bumpable(&self) -> &Bumpable { self }
+    fn as_mut_bumpable(&mut self) -> &mut Bumpable { self }
+    fn as_mut_time_bumpable(&mut self) -> &mut TimeBumpable { self }
+
+    fn price_report(&self) -> Result<PriceReport, qm::Error> {
+        if self.retain_path_payoffs {
+            return Ok(self.report_with_path_moments()?.0)
+        }
+
+        let mut report = PriceReport::new();
+        self.add_to_report(false, &mut report)?;
+        Ok(report)
+    }
+
+    /// Rebuilds the model from the same timeline, with a copy of its pricing
+    /// context refreshed with the new market data. The paths are regenerated, as
+    /// they depend on the market data.
+    fn refresh_market_data(&mut self, market_data: &MarketData)
+        -> Result<(), qm::Error> {
+
+        let mut context = self.model.bumpable_context().clone_box();
+        context.refresh_market_data(market_data)?;
+        let pricer = MonteCarloPricer::from_context(self.instruments.clone(),
+            self.model_factory.clone(), &self.timeline, context, self.min_spacing,
+            Some(self.path_batches))?;
+        *self = pricer.with_path_payoffs(self.retain_path_payoffs);
+        Ok(())
     }
 }
 
@@ -165,22 +644,38 @@ impl Bumpable for MonteCarloPricer {
     fn restore(&mut self, saved: &Saveable) -> Result<(), qm::Error> {
         self.model.restore(saved)
     }
+
+    fn checkpoint(&self) -> Result<Vec<u8>, qm::Error> {
+        self.model.checkpoint()
+    }
+
+    fn restore_checkpoint(&mut self, checkpoint: &[u8]) -> Result<(), qm::Error> {
+        self.model.restore_checkpoint(checkpoint)
+    }
 }
 
 impl TimeBumpable for MonteCarloPricer {
     fn bump_time(&mut self, bump: &BumpTime) -> Result<(), qm::Error> {
         if bump.apply(&mut self.instruments, self.model.as_mut_bumpable())? {
             // if the instruments have changed, we need to rebuild the pricer
-            *self = MonteCarloPricer::new(self.instruments.clone(), self.model_factory.clone(),
-                self.model.raw_market_data())?
+            *self = MonteCarloPricer::build(self.instruments.clone(), self.model_factory.clone(),
+                self.model.raw_market_data(), self.min_spacing, Some(self.path_batches),
+                &mut StageTimings::new())?
+                .with_path_payoffs(self.retain_path_payoffs)
         }
         Ok(())
     }
+
+    fn bump_time_saved(&mut self, bump: &BumpTime, save: &mut Saveable)
+        -> Result<bool, qm::Error> {
+        bump.apply_saved(&self.instruments, self.model.as_mut_bumpable(), save)
+    }
 }
 
 #[cfg(test)]
-mod tests {
+pub mod tests {
     use super::*;
+    use models::random::BASE_SEED;
     use std::sync::Arc;
     use dates::Date;
     use dates::datetime::DateTime;
@@ -195,9 +690,73 @@ mod tests {
     use risk::marketdata::tests::sample_market_data;
     use risk::marketdata::tests::sample_european;
     use risk::marketdata::tests::sample_forward_european;
+    use risk::marketdata::tests::sample_currency;
+    use risk::marketdata::tests::sample_settlement;
+    use risk::marketdata::tests::sample_equity;
+    use instruments::assets::RcCurrency;
+    use instruments::options::ForwardStartingEuropean;
+    use instruments::options::SpotStartingEuropean;
+    use instruments::assets::Equity;
+    use instruments::options::PutOrCall;
+    use instruments::options::OptionSettlement;
+    use instruments::asian::tests::sample_asian;
+    use instruments::asian::tests::sample_fixed_asian;
+    use instruments::asian::tests::moment_matched_price;
     use models::blackdiffusion::BlackDiffusionFactory;
+    use models::VarianceReduction;
+    use models::DiscretizationScheme;
+    use models::StochasticRates;
+    use data::bumpvol::VolTenor;
+    use std::f64::NAN;
+    use models::ProgressCallback;
+    use models::random::RandomSourceType;
+    use models::random::MAX_SOBOL_POINTS;
+    use std::sync::Mutex;
+    use instruments::bonds::Cashflow;
+    use instruments::bonds::CashflowStream;
+    use risk::marketdata::tests::create_sample_rate;
+    use risk::marketdata::tests::create_sample_borrow;
+    use risk::marketdata::tests::create_sample_divstream;
+    use std::collections::HashMap;
+    use dates::calendar::WeekdayCalendar;
+    use dates::calendar::RcCalendar;
+    use dates::datetime::DateDayFraction;
+    use math::interpolation::Linear;
+    use math::interpolation::Extrap;
+    use data::volsmile::FlatSmile;
+    use data::volsurface::VolSurface;
+    use data::volsurface::RcVolSurface;
+    use data::volsurface::VolByProbabilityFlatSmile;
+    use data::volsurface::DivAssumptions;
+    use risk::rho::rho;
+    use risk::bumped_price;
+    use risk::marketdata::tests::create_sample_flat_vol;
+    use math::optionpricing::Black76;
+    use instruments::Instrument;
+    use instruments::Priceable;
+    use dates::calendar::Calendar;
+    use std::thread;
     use core::factories::Qrc;
 
+    /// A Monte-Carlo pricer for a single instrument, using a black diffusion
+    /// with the given path substep and number of paths, and no variance
+    /// reduction. Shared by the tests of the individual instruments.
+    pub fn black_diffusion_mc_pricer(instrument: RcInstrument, market_data: &MarketData,
+        path_substep: f64, n_paths: usize) -> Result<MonteCarloPricer, qm::Error> {
+        let model_factory = RcMonteCarloModelFactory::new(Arc::new(BlackDiffusionFactory::new(
+            20, path_substep, n_paths, VarianceReduction::None, Some(BASE_SEED))?));
+        MonteCarloPricer::new(vec!((1.0, instrument)), model_factory, market_data)
+    }
+
+    /// The price and standard error of a single instrument, priced as in
+    /// black_diffusion_mc_pricer
+    pub fn black_diffusion_price_with_stderr(instrument: RcInstrument,
+        market_data: &MarketData, path_substep: f64, n_paths: usize)
+        -> Result<(f64, f64), qm::Error> {
+        black_diffusion_mc_pricer(instrument, market_data, path_substep, n_paths)?
+            .price_with_stderr()
+    }
+
     fn sample_fixings() -> FixingTable {
         let today = Date::from_ymd(2017, 01, 02);
         FixingTable::from_fixings(today, &[
@@ -205,6 +764,269 @@ mod tests {
             (DateTime::new(today - 7, TimeOfDay::Close), 102.0)])]).unwrap()
     }
 
+    fn sample_cashflow_stream() -> (CashflowStream, Vec<(Date, f64)>) {
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        let flows = vec![(Date::from_ymd(2017, 07, 04), 5.0),
+            (Date::from_ymd(2018, 01, 04), 5.0), (Date::from_ymd(2018, 07, 04), 105.0)];
+        let cashflows = flows.iter().map(|&(pay, amount)| Cashflow::new(
+            DateTime::new(pay - 2, TimeOfDay::Open), pay, amount)).collect();
+        let stream = CashflowStream::new("SampleStream", "LSE", currency,
+            sample_settlement(2), cashflows).unwrap();
+        (stream, flows)
+    }
+
+    #[test]
+    fn monte_carlo_factory_prices_cashflow_stream_analytically() {
+
+        // The stream needs no simulation, so the factory gives a pricer that
+        // values it analytically, rather than an error
+        let market_data = RcMarketData::new(Arc::new(sample_market_data()));
+        let (stream, flows) = sample_cashflow_stream();
+        let stream = RcInstrument::new(Qrc::new(Arc::new(stream)));
+        let fixings = RcFixingTable::new(Arc::new(sample_fixings()));
+        let model_factory = RcMonteCarloModelFactory::new(Arc::new(BlackDiffusionFactory::new(
+            20, 0.01, 2000, VarianceReduction::None, Some(BASE_SEED)).unwrap()));
+        let factory = MonteCarloPricerFactory::new(model_factory.clone());
+        let mut pricer = factory.new(stream.clone(), fixings, market_data.clone()).unwrap();
+
+        // the price is the sum of the cashflows, discounted to settlement
+        let curve = create_sample_rate();
+        let settlement_date = Date::from_ymd(2017, 01, 04);
+        let (_, t_settle) = curve.r_and_t(settlement_date).unwrap();
+        let mut expected = 0.0;
+        let mut expected_rho = 0.0;
+        for &(pay, amount) in flows.iter() {
+            let pv = amount * curve.df(pay, settlement_date).unwrap();
+            let (_, t_pay) = curve.r_and_t(pay).unwrap();
+            expected += pv;
+            expected_rho -= pv * (t_pay - t_settle);
+        }
+        let unbumped = pricer.price().unwrap();
+        assert_approx(unbumped, expected, 1e-12);
+
+        // The stream is priced purely off the yield curve, so a yield bump
+        // and a discount bump give the same rho
+        let mut save = pricer.as_bumpable().new_saveable();
+        let bumpsize = 0.0001;
+        let mut bumped = Vec::new();
+        for size in [bumpsize, -bumpsize].iter() {
+            let bump = Bump::new_yield("LSE", BumpYield::new_flat_continuously_compounded(*size));
+            assert!(pricer.as_mut_bumpable().bump(&bump, Some(&mut *save)).unwrap());
+            bumped.push(pricer.price().unwrap());
+            pricer.as_mut_bumpable().restore(&*save).unwrap();
+            save.clear();
+        }
+        let yield_rho = (bumped[0] - bumped[1]) / (2.0 * bumpsize);
+        assert_approx(yield_rho, expected_rho, 1e-6);
+        assert_approx(rho(&mut *pricer, "LSE", bumpsize).unwrap(), yield_rho, 1e-9);
+        assert_eq!(pricer.price().unwrap(), unbumped);
+
+        // Alongside an instrument that needs simulation, the stream is still
+        // valued analytically, adding exactly its value to the price
+        let european = RcInstrument::new(Qrc::new(sample_european()));
+        let alone = MonteCarloPricer::new(vec!((1.0, european.clone())),
+            model_factory.clone(), &*market_data).unwrap();
+        let together = MonteCarloPricer::new(vec!((1.0, european), (2.0, stream)),
+            model_factory, &*market_data).unwrap();
+        assert_approx(together.price().unwrap(),
+            alone.price().unwrap() + 2.0 * expected, 1e-9);
+    }
+
+    #[test]
+    fn monte_carlo_retains_path_payoffs() {
+
+        let market_data = sample_market_data();
+        let european = RcInstrument::new(Qrc::new(sample_european()));
+        let model_factory = RcMonteCarloModelFactory::new(Arc::new(BlackDiffusionFactory::new(
+            20, 0.01, 2000, VarianceReduction::None, Some(BASE_SEED)).unwrap()));
+
+        // by default, nothing is retained
+        let pricer = MonteCarloPricer::new(vec!((1.0, european.clone())),
+            model_factory.clone(), &market_data).unwrap();
+        let unretained = pricer.price().unwrap();
+        assert!(pricer.last_path_payoffs().is_none());
+
+        // in diagnostic mode, the price is unchanged, and is the mean of the
+        // retained payoffs, which include any analytically valued instruments
+        let (stream, _) = sample_cashflow_stream();
+        let stream = RcInstrument::new(Qrc::new(Arc::new(stream)));
+        for &(ref instruments, expected) in [
+            (vec!((1.0, european.clone())), Some(unretained)),
+            (vec!((2.0, european.clone()), (0.5, stream)), None)].iter() {
+
+            let pricer = MonteCarloPricer::new(instruments.clone(),
+                model_factory.clone(), &market_data).unwrap().with_path_payoffs(true);
+            assert!(pricer.last_path_payoffs().is_none());
+            let price = pricer.price().unwrap();
+            if let Some(expected) = expected {
+                assert_eq!(price, expected);
+            }
+
+            let payoffs = pricer.last_path_payoffs().unwrap();
+            assert_eq!(payoffs.len(), 2000);
+            let mean = payoffs.iter().sum::<f64>() / payoffs.len() as f64;
+            assert_approx(mean, price, 1e-9);
+        }
+    }
+
+    #[test]
+    fn monte_carlo_streams_moments_of_a_single_instrument() {
+
+        let market_data = sample_market_data();
+        let european = RcInstrument::new(Qrc::new(sample_european()));
+        let model_factory = RcMonteCarloModelFactory::new(Arc::new(BlackDiffusionFactory::new(
+            20, 0.01, 2000, VarianceReduction::None, Some(BASE_SEED)).unwrap()));
+
+        // the value of each path is only kept where there is more than one
+        // contribution to combine
+        for &(ref instruments, streamed) in [
+            (vec!((1.0, european.clone())), true),
+            (vec!((1.0, european.clone()), (2.0, european.clone())), false)].iter() {
+
+            let pricer = MonteCarloPricer::new(instruments.clone(),
+                model_factory.clone(), &market_data).unwrap();
+            pricer.accumulate_path_values(&mut PriceReport::new()).unwrap();
+            assert_eq!(pricer.model.take_path_moments().unwrap().count(), 2000);
+
+            pricer.accumulate_path_values(&mut PriceReport::new()).unwrap();
+            assert_eq!(pricer.model.take_path_values().is_some(), !streamed);
+        }
+    }
+
+    #[test]
+    fn monte_carlo_reports_progress() {
+
+        // record each progress report
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let recorder = reports.clone();
+        let progress = ProgressCallback::new(100, move |completed, total|
+            recorder.lock().unwrap().push((completed, total)));
+
+        let market_data = sample_market_data();
+        let european = RcInstrument::new(Qrc::new(sample_european()));
+        let model_factory = RcMonteCarloModelFactory::new(Arc::new(BlackDiffusionFactory::new(
+            20, 0.01, 1000, VarianceReduction::None, Some(BASE_SEED)).unwrap().with_progress(progress)));
+        let mut pricer = MonteCarloPricer::new(vec!((1.0, european)),
+            model_factory, &market_data).unwrap();
+        let unbumped = pricer.price().unwrap();
+
+        // one report for each batch of 100 paths
+        let expected: Vec<(usize, usize)> = (1..11).map(|i| (i * 100, 1000)).collect();
+        assert_eq!(*reports.lock().unwrap(), expected);
+
+        // bumping spot regenerates the paths, and the progress carries on
+        // from where it left off, so it covers the whole sweep
+        let mut save = pricer.as_bumpable().new_saveable();
+        let bump = Bump::new_spot("BP.L", BumpSpot::new_relative(0.01));
+        assert!(pricer.as_mut_bumpable().bump(&bump, Some(&mut *save)).unwrap());
+        let expected: Vec<(usize, usize)> = (1..11).map(|i| (i * 100, 1000))
+            .chain((11..21).map(|i| (i * 100, 2000))).collect();
+        assert_eq!(*reports.lock().unwrap(), expected);
+
+        // progress reporting does not affect the price
+        pricer.as_mut_bumpable().restore(&*save).unwrap();
+        let model_factory = RcMonteCarloModelFactory::new(Arc::new(BlackDiffusionFactory::new(
+            20, 0.01, 1000, VarianceReduction::None, Some(BASE_SEED)).unwrap()));
+        let european = RcInstrument::new(Qrc::new(sample_european()));
+        let quiet = MonteCarloPricer::new(vec!((1.0, european)),
+            model_factory, &market_data).unwrap();
+        assert_eq!(quiet.price().unwrap(), unbumped);
+        assert_eq!(pricer.price().unwrap(), unbumped);
+    }
+
+    #[test]
+    fn breakeven_vol_of_sample_european() {
+
+        let market_data = sample_market_data();
+        let european = sample_european();
+        let val_date = DateTime::new(Date::from_ymd(2017, 01, 02), TimeOfDay::Open);
+
+        // the analytic price gives back exactly the flat market vol
+        let analytic = european.price(&market_data, val_date).unwrap();
+        let vol = european.breakeven_vol(&market_data, val_date, analytic).unwrap();
+        assert_approx(vol, 0.3, 1e-10);
+
+        // the Monte-Carlo price gives the market vol to within its noise
+        let model_factory = RcMonteCarloModelFactory::new(Arc::new(BlackDiffusionFactory::new(
+            20, 0.01, 10000, VarianceReduction::Antithetic, Some(BASE_SEED)).unwrap()));
+        let instrument = RcInstrument::new(Qrc::new(european.clone()));
+        let pricer = MonteCarloPricer::new(vec!((1.0, instrument)),
+            model_factory, &market_data).unwrap();
+        let (price, stderr) = pricer.price_with_stderr().unwrap();
+        let low = european.breakeven_vol(&market_data, val_date, price - 4.0 * stderr).unwrap();
+        let high = european.breakeven_vol(&market_data, val_date, price + 4.0 * stderr).unwrap();
+        assert!(low < 0.3 && 0.3 < high, "low={} high={}", low, high);
+
+        // prices that no vol can reach are errors
+        assert!(european.breakeven_vol(&market_data, val_date, -1.0).is_err());
+        assert!(european.breakeven_vol(&market_data, val_date, 1000.0).is_err());
+    }
+
+    #[test]
+    fn monte_carlo_refresh_market_data_matches_fresh_pricer() {
+
+        let market_data = sample_market_data();
+        let instrument = RcInstrument::new(Qrc::new(sample_european()));
+        let fixings = RcFixingTable::new(Arc::new(sample_fixings()));
+        let model_factory = RcMonteCarloModelFactory::new(Arc::new(BlackDiffusionFactory::new(
+            20, 0.01, 2000, VarianceReduction::None, Some(BASE_SEED)).unwrap()));
+        let factory = MonteCarloPricerFactory::new(model_factory);
+        let mut pricer = factory.new(instrument.clone(), fixings.clone(),
+            RcMarketData::new(Arc::new(market_data.clone()))).unwrap();
+        let unbumped = pricer.price().unwrap();
+
+        // refreshing with a market where spot has moved gives exactly the price
+        // of a pricer built afresh on that market
+        let mut moved = market_data.clone();
+        let bump = Bump::new_spot("BP.L", BumpSpot::new_relative(0.05));
+        assert!(moved.bump(&bump, None).unwrap());
+        let fresh = factory.new(instrument, fixings,
+            RcMarketData::new(Arc::new(moved.clone()))).unwrap().price().unwrap();
+        pricer.refresh_market_data(&moved).unwrap();
+        assert_eq!(pricer.price().unwrap(), fresh);
+        assert!(fresh > unbumped);
+
+        // any bumps are discarded by a refresh
+        let bump = Bump::new_vol("BP.L", BumpVol::new_flat_additive(0.01));
+        assert!(pricer.as_mut_bumpable().bump(&bump, None).unwrap());
+        pricer.refresh_market_data(&market_data).unwrap();
+        assert_eq!(pricer.price().unwrap(), unbumped);
+
+        // market data that does not satisfy the dependencies, or has a
+        // different spot date, is an error, and leaves the pricer unchanged
+        let spot_date = market_data.spot_date();
+        let empty = |date: Date| MarketData::new(date, HashMap::new(),
+            HashMap::new(), HashMap::new(), HashMap::new(), HashMap::new());
+        assert!(pricer.refresh_market_data(&empty(spot_date)).is_err());
+        let err = pricer.refresh_market_data(&empty(spot_date + 1)).unwrap_err();
+        assert!(format!("{}", err).contains("spot date"), "{}", err);
+        assert_eq!(pricer.price().unwrap(), unbumped);
+    }
+
+    #[test]
+    fn monte_carlo_checkpoint_refetches_paths() {
+
+        let market_data = RcMarketData::new(Arc::new(sample_market_data()));
+        let instrument = RcInstrument::new(Qrc::new(sample_european()));
+        let fixings = RcFixingTable::new(Arc::new(sample_fixings()));
+        let model_factory = RcMonteCarloModelFactory::new(Arc::new(BlackDiffusionFactory::new(
+            20, 0.01, 10000, VarianceReduction::None, Some(BASE_SEED)).unwrap()));
+        let factory = MonteCarloPricerFactory::new(model_factory);
+        let mut pricer = factory.new(instrument.clone(), fixings.clone(),
+            market_data.clone()).unwrap();
+
+        let bump = Bump::new_spot("BP.L", BumpSpot::new_relative(0.01));
+        assert!(pricer.as_mut_bumpable().bump(&bump, None).unwrap());
+        let bumped = pricer.price().unwrap();
+        let checkpoint = pricer.checkpoint().unwrap();
+
+        // the paths are regenerated from the same random draws, so the
+        // reloaded pricer matches the bumped one, not just to within noise
+        let mut reloaded = factory.new(instrument, fixings, market_data).unwrap();
+        reloaded.restore_checkpoint(&checkpoint).unwrap();
+        assert_approx(reloaded.price().unwrap(), bumped, 1e-12);
+    }
+
     #[test]
     fn monte_carlo_price_european_bumped_price() {
 
@@ -221,7 +1043,7 @@ mod tests {
         let correlation_substep = 20;
         let path_substep = 0.01;
         let model_factory = RcMonteCarloModelFactory::new(Arc::new(BlackDiffusionFactory::new(
-            correlation_substep, path_substep, n_paths)));
+            correlation_substep, path_substep, n_paths, VarianceReduction::None, Some(BASE_SEED)).unwrap()));
         let factory = MonteCarloPricerFactory::new(model_factory);
         let mut pricer = factory.new(instrument, fixings, market_data).unwrap();
         let mut save = pricer.as_bumpable().new_saveable();
@@ -299,6 +1121,235 @@ mod tests {
         assert_approx(price, unbumped_price, 1e-12);
     }
 
+    #[test]
+    fn monte_carlo_price_european_multithreaded() {
+
+        // The paths are generated in chunks with deterministic seeds, so
+        // the number of threads should make no difference to the price.
+        let market_data = RcMarketData::new(Arc::new(sample_market_data()));
+        let instrument = RcInstrument::new(Qrc::new(sample_european()));
+        let fixings = RcFixingTable::new(Arc::new(sample_fixings()));
+
+        let n_paths = 10000;
+        let correlation_substep = 20;
+        let path_substep = 0.01;
+
+        let mut prices = Vec::new();
+        for &threads in [1, 4].iter() {
+            let model_factory = RcMonteCarloModelFactory::new(Arc::new(BlackDiffusionFactory::new(
+                correlation_substep, path_substep, n_paths, VarianceReduction::None, Some(BASE_SEED)).unwrap().with_threads(threads)));
+            let factory = MonteCarloPricerFactory::new(model_factory);
+            let pricer = factory.new(instrument.clone(), fixings.clone(),
+                market_data.clone()).unwrap();
+            prices.push(pricer.price().unwrap());
+        }
+
+        assert_approx(prices[0], 16.710717400832973, 1.0);
+        assert_approx(prices[1], prices[0], 1e-9);
+    }
+
+    #[test]
+    fn monte_carlo_price_european_seeded() {
+
+        let market_data = RcMarketData::new(Arc::new(sample_market_data()));
+        let instrument = RcInstrument::new(Qrc::new(sample_european()));
+        let fixings = RcFixingTable::new(Arc::new(sample_fixings()));
+
+        let price = |seed| {
+            let model_factory = RcMonteCarloModelFactory::new(Arc::new(
+                BlackDiffusionFactory::new(20, 0.01, 2000, VarianceReduction::None, seed).unwrap()));
+            let factory = MonteCarloPricerFactory::new(model_factory);
+            let pricer = factory.new(instrument.clone(), fixings.clone(),
+                market_data.clone()).unwrap();
+            pricer.price().unwrap()
+        };
+
+        // the same seed gives exactly the same price, and a different seed
+        // gives a different one
+        let seeded = price(Some(42));
+        assert_eq!(price(Some(42)), seeded);
+        assert!(price(Some(43)) != seeded, "seeded={}", seeded);
+        assert_approx(seeded, 16.710717400832973, 1.5);
+
+        // the high bits of the seed matter too
+        assert!(price(Some(42 + (1 << 32))) != seeded, "seeded={}", seeded);
+
+        // with no seed, each factory draws its own from entropy, so the
+        // prices differ from factory to factory, but every pricer from the
+        // same factory has the same paths
+        assert!(price(None) != price(None));
+        let model_factory = RcMonteCarloModelFactory::new(Arc::new(
+            BlackDiffusionFactory::new(20, 0.01, 2000, VarianceReduction::None, None).unwrap()));
+        let factory = MonteCarloPricerFactory::new(model_factory);
+        let first = factory.new(instrument.clone(), fixings.clone(),
+            market_data.clone()).unwrap();
+        let second = factory.new(instrument.clone(), fixings.clone(),
+            market_data.clone()).unwrap();
+        assert_eq!(first.price().unwrap(), second.price().unwrap());
+    }
+
+    #[test]
+    fn monte_carlo_price_european_antithetic() {
+
+        // With antithetic paths, 50,000 pairs are enough to get within a
+        // much tighter tolerance than the plain run above. The paths needed
+        // for a given error scale with the variance, so a plain run with
+        // the same number of paths would need more paths to match it.
+        let market_data = sample_market_data();
+        let instrument = RcInstrument::new(Qrc::new(sample_european()));
+
+        let n_paths = 100000;
+        let correlation_substep = 20;
+        let path_substep = 0.01;
+        let price_with_stderr = |variance_reduction| {
+            let model_factory = RcMonteCarloModelFactory::new(Arc::new(BlackDiffusionFactory::new(
+                correlation_substep, path_substep, n_paths, variance_reduction, Some(BASE_SEED)).unwrap()));
+            let pricer = MonteCarloPricer::new(vec!((1.0, instrument.clone())),
+                model_factory, &market_data).unwrap();
+            pricer.price_with_stderr().unwrap()
+        };
+
+        let (price, stderr) = price_with_stderr(VarianceReduction::Antithetic);
+        assert_approx(price, 16.710717400832973, 0.05);
+
+        let (_, plain_stderr) = price_with_stderr(VarianceReduction::None);
+        let plain_paths_needed = n_paths as f64 * (plain_stderr / stderr).powi(2);
+        assert!(plain_paths_needed > 1.2 * n_paths as f64,
+            "plain_paths_needed={} stderr={} plain_stderr={}",
+            plain_paths_needed, stderr, plain_stderr);
+    }
+
+    #[test]
+    fn monte_carlo_price_european_sobol() {
+
+        // Sobol converges to within 0.05 with 4095 paths, whereas plain
+        // pseudo-random numbers have a standard error well outside that
+        // with four times as many. Sobol sequences are limited to the
+        // dimensions in the table of direction numbers, so we keep the
+        // number of path substeps down.
+        let market_data = sample_market_data();
+        let instrument = RcInstrument::new(Qrc::new(sample_european()));
+        let pricer = |n_paths, random_source| {
+            let model_factory = RcMonteCarloModelFactory::new(Arc::new(
+                BlackDiffusionFactory::new(20, 0.01, n_paths, VarianceReduction::None, Some(BASE_SEED)).unwrap()
+                .with_random_source(random_source)));
+            MonteCarloPricer::new(vec!((1.0, instrument.clone())),
+                model_factory, &market_data).unwrap()
+        };
+
+        let expected = 16.710717400832973;
+        let tolerance = 0.05;
+        let sobol = pricer(4095, RandomSourceType::Sobol).price().unwrap();
+        assert_approx(sobol, expected, tolerance);
+
+        let (_, stderr) = pricer(16383, RandomSourceType::Pseudo)
+            .price_with_stderr().unwrap();
+        assert!(stderr > 2.0 * tolerance, "stderr={}", stderr);
+        assert!((sobol - expected).abs() < stderr,
+            "sobol={} stderr={}", sobol, stderr);
+
+        // too many paths or dimensions for the sequence give errors rather
+        // than panics
+        let sobol_pricer = |n_paths, path_substep| {
+            let model_factory = RcMonteCarloModelFactory::new(Arc::new(
+                BlackDiffusionFactory::new(20, path_substep, n_paths,
+                    VarianceReduction::None, None).unwrap()
+                .with_random_source(RandomSourceType::Sobol)));
+            MonteCarloPricer::new(vec!((1.0, instrument.clone())),
+                model_factory, &market_data)
+        };
+        assert!(sobol_pricer(MAX_SOBOL_POINTS as usize + 1, 0.01).is_err());
+        assert!(sobol_pricer(1000, 0.0001).is_err());
+    }
+
+    #[test]
+    fn monte_carlo_price_asian_control_variate() {
+
+        // Price an Asian option with few paths, with and without the
+        // geometric control variate on the same paths, and compare with a
+        // high-path run that does not use the control.
+        let market_data = sample_market_data();
+        let instrument = RcInstrument::new(Qrc::new(sample_asian()));
+        let correlation_substep = 20;
+        let path_substep = 0.01;
+
+        let (reference, reference_stderr) = {
+            let model_factory = RcMonteCarloModelFactory::new(Arc::new(BlackDiffusionFactory::new(
+                correlation_substep, path_substep, 100000, VarianceReduction::None, Some(BASE_SEED)).unwrap()));
+            let pricer = MonteCarloPricer::new(vec!((1.0, instrument.clone())),
+                model_factory, &market_data).unwrap();
+            plain_price_with_stderr(&pricer, &instrument)
+        };
+
+        let model_factory = RcMonteCarloModelFactory::new(Arc::new(BlackDiffusionFactory::new(
+            correlation_substep, path_substep, 2000, VarianceReduction::None, Some(BASE_SEED)).unwrap()));
+        let pricer = MonteCarloPricer::new(vec!((1.0, instrument.clone())),
+            model_factory, &market_data).unwrap();
+        let (with_control, stderr) = pricer.price_with_stderr().unwrap();
+        let (_, plain_stderr) = plain_price_with_stderr(&pricer, &instrument);
+
+        let control_error = (with_control - reference).abs();
+        assert!(control_error < 3.0 * (stderr + reference_stderr),
+            "control_error={} stderr={} reference_stderr={}",
+            control_error, stderr, reference_stderr);
+        assert!(stderr * 10.0 < plain_stderr,
+            "stderr={} plain_stderr={}", stderr, plain_stderr);
+    }
+
+    /// Prices the instrument by itself on the paths of the pricer, without
+    /// any control variate, returning the price and its standard error
+    fn plain_price_with_stderr(pricer: &MonteCarloPricer, instrument: &RcInstrument)
+        -> (f64, f64) {
+
+        pricer.model.accumulate_paths(Some(1.0));
+        let mc = instrument.as_mc_priceable().unwrap();
+        let price = mc.mc_price(pricer.model.as_mc_context()).unwrap();
+        pricer.model.accumulate_paths(None);
+        let moments = pricer.model.take_path_moments().unwrap();
+        (price, moments.stderr())
+    }
+
+    /// An at-the-money forward-starting European, struck at today's open
+    fn forward_european_struck_today() -> RcInstrument {
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        let equity = RcInstrument::new(Qrc::new(Arc::new(sample_equity(currency, 2))));
+        let strike_date = DateTime::new(Date::from_ymd(2017, 01, 02), TimeOfDay::Open);
+        let expiry = DateTime::new(Date::from_ymd(2018, 06, 01), TimeOfDay::Close);
+        RcInstrument::new(Qrc::new(Arc::new(ForwardStartingEuropean::new(
+            "SampleForwardEuropean", "OPT", equity, sample_settlement(2), expiry,
+            1.0, strike_date, PutOrCall::Call, OptionSettlement::Cash).unwrap())))
+    }
+
+    #[test]
+    fn monte_carlo_price_forward_european_struck_today() {
+
+        // The strike date is today but not yet fixed, so the strike is set
+        // from the simulated spot at today's open, which is the spot. This
+        // should match the analytic price of the plain European.
+        let market_data = sample_market_data();
+        let model_factory = RcMonteCarloModelFactory::new(Arc::new(BlackDiffusionFactory::new(
+            20, 0.01, 100000, VarianceReduction::None, Some(BASE_SEED)).unwrap()));
+        let pricer = MonteCarloPricer::new(vec!((1.0, forward_european_struck_today())),
+            model_factory.clone(), &market_data).unwrap();
+        let (price, stderr) = pricer.price_with_stderr().unwrap();
+        let analytic = 16.710717400832973;
+        assert!((price - analytic).abs() < 3.0 * stderr,
+            "price={} analytic={} stderr={}", price, analytic, stderr);
+
+        // If the strike has fixed at the spot, it fixes into the plain
+        // European, and prices identically.
+        let today = Date::from_ymd(2017, 01, 02);
+        let fixings = RcFixingTable::new(Arc::new(FixingTable::from_fixings(today + 1, &[
+            ("BP.L", &[(DateTime::new(today, TimeOfDay::Open), 100.0)])]).unwrap()));
+        let market_data = RcMarketData::new(Arc::new(market_data));
+        let factory = MonteCarloPricerFactory::new(model_factory);
+        let forward_pricer = factory.new(forward_european_struck_today(),
+            fixings.clone(), market_data.clone()).unwrap();
+        let european = RcInstrument::new(Qrc::new(sample_european()));
+        let european_pricer = factory.new(european, fixings, market_data).unwrap();
+        assert_eq!(forward_pricer.price().unwrap(), european_pricer.price().unwrap());
+    }
+
     #[test]
     fn monte_carlo_price_forward_european_time_bumped() {
 
@@ -315,7 +1366,7 @@ mod tests {
         let correlation_substep = 20;
         let path_substep = 0.01;
         let model_factory = RcMonteCarloModelFactory::new(Arc::new(BlackDiffusionFactory::new(
-            correlation_substep, path_substep, n_paths)));
+            correlation_substep, path_substep, n_paths, VarianceReduction::None, Some(BASE_SEED)).unwrap()));
         let factory = MonteCarloPricerFactory::new(model_factory);
         let mut pricer = factory.new(instrument, fixings, market_data).unwrap();
 
@@ -370,6 +1421,623 @@ mod tests {
         assert_approx(bumped_price, 12.219583564604477, 0.1);
     }
 
+    #[test]
+    fn monte_carlo_price_european_with_stderr() {
+
+        // The analytic price should lie within three standard errors of the
+        // Monte-Carlo mean
+        let market_data = sample_market_data();
+        let instrument = RcInstrument::new(Qrc::new(sample_european()));
+        let stderr_for = |variance_reduction| {
+            let model_factory = RcMonteCarloModelFactory::new(Arc::new(
+                BlackDiffusionFactory::new(20, 0.01, 10000, variance_reduction, Some(BASE_SEED)).unwrap()));
+            let pricer = MonteCarloPricer::new(vec!((1.0, instrument.clone())),
+                model_factory, &market_data).unwrap();
+            let (price, stderr) = pricer.price_with_stderr().unwrap();
+
+            // the mean must be exactly the same as the normal price, and
+            // also the mean of the paths
+            assert_approx(price, pricer.price().unwrap(), 1e-12);
+            let (_, moments) = pricer.price_with_moments().unwrap();
+            assert_approx(moments.mean(), price, 1e-10);
+            assert_eq!(moments.stderr(), stderr);
+            (price, stderr)
+        };
+
+        let (price, stderr) = stderr_for(VarianceReduction::None);
+        assert!(stderr > 0.1 && stderr < 0.5, "stderr={}", stderr);
+        assert!((price - 16.710717400832973).abs() < 3.0 * stderr,
+            "price={} stderr={}", price, stderr);
+
+        // antithetic paths should reduce the error
+        let (antithetic_price, antithetic_stderr) = stderr_for(VarianceReduction::Antithetic);
+        assert!(antithetic_stderr < stderr,
+            "antithetic_stderr={} stderr={}", antithetic_stderr, stderr);
+        assert!((antithetic_price - 16.710717400832973).abs() < 3.0 * antithetic_stderr,
+            "price={} stderr={}", antithetic_price, antithetic_stderr);
+    }
+
+    #[test]
+    fn monte_carlo_price_asian_control_variate_stderr() {
+
+        // The control variate should dramatically reduce the standard error
+        let market_data = sample_market_data();
+        let instrument = RcInstrument::new(Qrc::new(sample_asian()));
+        let model_factory = RcMonteCarloModelFactory::new(Arc::new(BlackDiffusionFactory::new(
+            20, 0.01, 2000, VarianceReduction::None, Some(BASE_SEED)).unwrap()));
+        let pricer = MonteCarloPricer::new(vec!((1.0, instrument.clone())),
+            model_factory, &market_data).unwrap();
+        let (_, stderr) = pricer.price_with_stderr().unwrap();
+
+        // the plain error, accumulating the instrument alone
+        let (_, plain_stderr) = plain_price_with_stderr(&pricer, &instrument);
+
+        assert!(stderr * 10.0 < plain_stderr,
+            "stderr={} plain_stderr={}", stderr, plain_stderr);
+    }
+
+    #[test]
+    fn monte_carlo_price_asian_arithmetic_benchmark() {
+
+        // Compare against the moment-matched approximation, allowing for
+        // both the Monte-Carlo error and the error in the approximation,
+        // which overprices at-the-money options by up to about one percent.
+        // The second option has some averaging dates in the past.
+        let market_data = sample_market_data();
+        for asian in [sample_asian(), sample_fixed_asian()].iter() {
+            let benchmark = moment_matched_price(asian, &market_data);
+            let instrument = RcInstrument::new(Qrc::new(asian.clone()));
+            let model_factory = RcMonteCarloModelFactory::new(Arc::new(BlackDiffusionFactory::new(
+                20, 0.01, 20000, VarianceReduction::None, Some(BASE_SEED)).unwrap()));
+            let pricer = MonteCarloPricer::new(vec!((1.0, instrument)),
+                model_factory, &market_data).unwrap();
+            let (price, stderr) = pricer.price_with_stderr().unwrap();
+            assert!((price - benchmark).abs() < 3.0 * stderr + 0.01 * benchmark,
+                "price={} stderr={} benchmark={}", price, stderr, benchmark);
+        }
+    }
+
+    #[test]
+    fn monte_carlo_batch_matches_individual_pricers() {
+
+        // Two Europeans on different underlyings, with different expiries,
+        // so the batch must fetch the union of their dependencies
+        let market_data = RcMarketData::new(Arc::new(sample_market_data()));
+        let call = RcInstrument::new(Qrc::new(sample_european()));
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        let equity = RcInstrument::new(Qrc::new(Arc::new(Equity::new(
+            "GSK.L", "LSE", currency, sample_settlement(2)))));
+        let expiry = DateTime::new(Date::from_ymd(2017, 12, 01), TimeOfDay::Close);
+        let put = RcInstrument::new(Qrc::new(Arc::new(SpotStartingEuropean::new(
+            "SampleSpotPut", "OPT", equity, sample_settlement(2), expiry,
+            190.0, PutOrCall::Put, OptionSettlement::Cash).unwrap())));
+        let instruments = vec![call, put];
+
+        let fixings = RcFixingTable::new(Arc::new(sample_fixings()));
+        let model_factory = RcMonteCarloModelFactory::new(Arc::new(BlackDiffusionFactory::new(
+            20, 0.01, 2000, VarianceReduction::None, Some(BASE_SEED)).unwrap()));
+        let factory = MonteCarloPricerFactory::new(model_factory);
+
+        let batch = factory.new_batch(&instruments, fixings.clone(),
+            market_data.clone()).unwrap();
+        assert_eq!(batch.len(), instruments.len());
+        for (instrument, batched) in instruments.iter().zip(batch.iter()) {
+            let single = factory.new(instrument.clone(), fixings.clone(),
+                market_data.clone()).unwrap();
+            assert_approx(batched.price().unwrap(), single.price().unwrap(), 1e-12);
+        }
+    }
+
+    /// Sample market data, but with BP.L having a term structure of vol:
+    /// 20% to the first pillar and 30% to the second, with flat smiles.
+    fn term_structure_market_data(first: Date, second: Date) -> MarketData {
+
+        let market_data = sample_market_data();
+        let spot_date = market_data.spot_date();
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        let equity = sample_equity(currency, 2);
+        let forward = market_data.forward_curve(&equity, second).unwrap();
+
+        let d = spot_date;
+        let points: Vec<(Date, f64)> = [d, first, second].iter()
+            .map(|&date| (date, forward.forward(date).unwrap())).collect();
+        let fwd = Linear::new(&points, Extrap::Natural, Extrap::Natural).unwrap();
+        let divs = Linear::new(&[(d, 0.0)], Extrap::Flat, Extrap::Flat).unwrap();
+        let smiles = [
+            (DateDayFraction::new(first, 0.8), FlatSmile::new(0.2).unwrap()),
+            (DateDayFraction::new(second, 0.8), FlatSmile::new(0.3).unwrap())];
+        let calendar = RcCalendar::new(Arc::new(WeekdayCalendar()));
+        let base = DateDayFraction::new(spot_date, 0.0);
+        let surface = VolByProbabilityFlatSmile::new(&smiles, calendar,
+            base, fwd, divs, DivAssumptions::NoCashDivs).unwrap();
+
+        let mut spots = HashMap::new();
+        spots.insert("BP.L".to_string(), 100.0);
+        let mut yield_curves = HashMap::new();
+        yield_curves.insert("OPT".to_string(), create_sample_rate());
+        yield_curves.insert("LSE".to_string(), create_sample_rate());
+        let mut borrow_curves = HashMap::new();
+        borrow_curves.insert("BP.L".to_string(), create_sample_borrow());
+        let mut dividends = HashMap::new();
+        dividends.insert("BP.L".to_string(), create_sample_divstream());
+        let mut vol_surfaces = HashMap::new();
+        vol_surfaces.insert("BP.L".to_string(), RcVolSurface::new(Arc::new(surface)));
+
+        MarketData::new(spot_date, spots, yield_curves, borrow_curves,
+            dividends, vol_surfaces)
+    }
+
+    #[test]
+    fn monte_carlo_price_europeans_on_vol_term_structure() {
+
+        // Europeans expiring on each vol pillar, and one in between
+        let first = Date::from_ymd(2017, 07, 03);
+        let middle = Date::from_ymd(2017, 12, 01);
+        let second = Date::from_ymd(2018, 06, 01);
+        let market_data = term_structure_market_data(first, second);
+
+        // Between the pillars, the surface is linear in total variance
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        let equity = sample_equity(currency.clone(), 2);
+        let forward = market_data.forward_curve(&equity, second).unwrap();
+        let surface = market_data.vol_surface(&equity, second,
+            &|| Ok(forward.clone())).unwrap();
+        let first_time = surface.vol_time(DateDayFraction::new(first, 0.8)).unwrap();
+        let second_time = surface.vol_time(DateDayFraction::new(second, 0.8)).unwrap();
+        let middle_date = DateDayFraction::new(middle, 0.8);
+        let middle_time = surface.vol_time(middle_date).unwrap();
+        let fraction = (middle_time - first_time) / (second_time - first_time);
+        let first_var = 0.2 * 0.2 * first_time;
+        let second_var = 0.3 * 0.3 * second_time;
+        assert_approx(surface.variance(middle_date, 100.0).unwrap(),
+            first_var + fraction * (second_var - first_var), 1e-12);
+
+        let equity = RcInstrument::new(Qrc::new(Arc::new(equity)));
+        let europeans: Vec<RcInstrument> = [first, middle, second].iter().enumerate()
+            .map(|(i, &expiry)| RcInstrument::new(Qrc::new(Arc::new(
+                SpotStartingEuropean::new(&format!("TermStructureEuropean{}", i),
+                "OPT", equity.clone(), sample_settlement(2),
+                DateTime::new(expiry, TimeOfDay::Close), 100.0,
+                PutOrCall::Call, OptionSettlement::Cash).unwrap())))).collect();
+
+        // Price all three off the same paths, so the timeline has a step
+        // within each tenor. Each European should match its analytic price,
+        // which we test by putting all the weight on one of them at a time.
+        let model_factory = RcMonteCarloModelFactory::new(Arc::new(BlackDiffusionFactory::new(
+            20, 0.002, 20000, VarianceReduction::None, Some(BASE_SEED)).unwrap()));
+        for i in 0..europeans.len() {
+            let weighted: Vec<(f64, RcInstrument)> = europeans.iter().enumerate()
+                .map(|(j, e)| (if i == j { 1.0 } else { 0.0 }, e.clone())).collect();
+            let pricer = MonteCarloPricer::new(weighted, model_factory.clone(),
+                &market_data).unwrap();
+            let (price, stderr) = pricer.price_with_stderr().unwrap();
+
+            let analytic = SelfPricer::new(vec!((1.0, europeans[i].clone())),
+                &market_data).unwrap().price().unwrap();
+            assert!((price - analytic).abs() < 4.0 * stderr,
+                "expiry={} price={} analytic={} stderr={}", i, price, analytic, stderr);
+        }
+    }
+
+    #[test]
+    fn monte_carlo_price_is_discounted_to_spot_date() {
+
+        // The Black76 inputs for the sample European, which pays two business
+        // days after its expiry, discounted to the settlement of the spot date
+        let market_data = sample_market_data();
+        let european = sample_european();
+        let spot_date = market_data.spot_date();
+        let expiry = Date::from_ymd(2018, 06, 01);
+        let pay_date = Date::from_ymd(2018, 06, 05);
+        let settlement = european.settlement().apply(spot_date);
+        let df = market_data.yield_curve("OPT", pay_date).unwrap()
+            .df(pay_date, settlement).unwrap();
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        let equity = sample_equity(currency, 2);
+        let forward = market_data.forward_curve(&equity, expiry).unwrap()
+            .forward(expiry).unwrap();
+        let vol = create_sample_flat_vol();
+        let time = vol.calendar().year_fraction(
+            DateDayFraction::new(spot_date, 0.0),
+            DateDayFraction::new(expiry, 0.8));
+
+        // The analytic price is the discounted expected payoff
+        let black76 = Black76::new().unwrap();
+        let undiscounted = black76.call_price(1.0, forward, 100.0, 0.3 * time.sqrt());
+        let val_date = DateTime::new(spot_date, TimeOfDay::Open);
+        let analytic = european.price(&market_data, val_date).unwrap();
+        assert_approx(analytic, df * undiscounted, 1e-10);
+        assert!(df < 0.9, "df={}", df);
+
+        // Monte-Carlo prices are present values too. They must match the
+        // analytic price, and be far from the undiscounted expectation.
+        let instrument = RcInstrument::new(Qrc::new(european));
+        let model_factory = RcMonteCarloModelFactory::new(Arc::new(BlackDiffusionFactory::new(
+            20, 0.01, 100000, VarianceReduction::None, Some(BASE_SEED)).unwrap()));
+        let pricer = MonteCarloPricer::new(vec!((1.0, instrument)),
+            model_factory, &market_data).unwrap();
+        let (price, stderr) = pricer.price_with_stderr().unwrap();
+        assert!((price - analytic).abs() < 3.0 * stderr,
+            "price={} analytic={} stderr={}", price, analytic, stderr);
+        assert!((price - undiscounted).abs() > 10.0 * stderr,
+            "price={} undiscounted={} stderr={}", price, undiscounted, stderr);
+    }
+
+    #[test]
+    fn monte_carlo_cashflows_discount_to_price() {
+
+        // the sample European pays once, two business days after expiry
+        let market_data = sample_market_data();
+        let european = sample_european();
+        let spot_date = market_data.spot_date();
+        let pay_date = Date::from_ymd(2018, 06, 05);
+        let settlement = european.settlement().apply(spot_date);
+        let df = market_data.yield_curve("OPT", pay_date).unwrap()
+            .df(pay_date, settlement).unwrap();
+
+        let instrument = RcInstrument::new(Qrc::new(european));
+        let model_factory = RcMonteCarloModelFactory::new(Arc::new(BlackDiffusionFactory::new(
+            20, 0.01, 10000, VarianceReduction::None, Some(BASE_SEED)).unwrap()));
+        let pricer = MonteCarloPricer::new(vec!((2.0, instrument)),
+            model_factory, &market_data).unwrap();
+        let price = pricer.price().unwrap();
+        let cashflows = pricer.expected_cashflows().unwrap();
+        assert_eq!(cashflows.len(), 1);
+        assert_eq!(cashflows[0].0, pay_date);
+        assert!(cashflows[0].1 > price, "cashflows={:?} price={}", cashflows, price);
+        assert_approx(cashflows[0].1 * df, price, 1e-12);
+    }
+
+    /// Calculates one of a selection of greeks, leaving the pricer unchanged
+    fn greek(pricer: &mut Pricer, which: usize) -> f64 {
+        let bump = match which {
+            0 => Bump::new_spot("BP.L", BumpSpot::new_relative(0.01)),
+            1 => Bump::new_vol("BP.L", BumpVol::new_flat_additive(0.01)),
+            2 => Bump::new_divs("BP.L", BumpDivs::new_all_relative(0.01)),
+            3 => return pricer.gamma("BP.L", 0.01).unwrap(),
+            _ => return rho(pricer, "LSE", 0.0001).unwrap() };
+
+        let unbumped = pricer.price().unwrap();
+        let mut save = pricer.as_bumpable().new_saveable();
+        let bumped = bumped_price(&bump, pricer, Some(&mut *save), unbumped).unwrap();
+        pricer.as_mut_bumpable().restore(&*save).unwrap();
+        bumped - unbumped
+    }
+
+    #[test]
+    fn monte_carlo_greeks_on_parallel_clones_match_sequential() {
+
+        let market_data = RcMarketData::new(Arc::new(sample_market_data()));
+        let instrument = RcInstrument::new(Qrc::new(sample_european()));
+        let fixings = RcFixingTable::new(Arc::new(sample_fixings()));
+        let model_factory = RcMonteCarloModelFactory::new(Arc::new(BlackDiffusionFactory::new(
+            20, 0.01, 2000, VarianceReduction::None, Some(BASE_SEED)).unwrap()));
+        let factory = MonteCarloPricerFactory::new(model_factory);
+        let mut pricer = factory.new(instrument, fixings, market_data).unwrap();
+        let unbumped = pricer.price().unwrap();
+
+        let n_greeks = 5;
+        let sequential: Vec<f64> = (0..n_greeks).map(|g| greek(&mut *pricer, g)).collect();
+
+        // each thread bumps its own clone, so they do not interfere with
+        // each other or with the original
+        let handles: Vec<_> = (0..n_greeks).map(|g| {
+            let mut clone = pricer.clone_box();
+            thread::spawn(move || greek(&mut *clone, g))
+        }).collect();
+        let parallel: Vec<f64> = handles.into_iter()
+            .map(|handle| handle.join().unwrap()).collect();
+
+        for (p, s) in parallel.iter().zip(sequential.iter()) {
+            assert_approx(*p, *s, 1e-12);
+        }
+        assert_eq!(pricer.price().unwrap(), unbumped);
+    }
+
+    #[test]
+    fn monte_carlo_exposure_profile_european() {
+
+        let market_data = sample_market_data();
+        let european = RcInstrument::new(Qrc::new(sample_european()));
+        let model_factory = RcMonteCarloModelFactory::new(Arc::new(BlackDiffusionFactory::new(
+            20, 0.01, 20000, VarianceReduction::None, Some(BASE_SEED)).unwrap()));
+        let pricer = MonteCarloPricer::new(vec!((1.0, european.clone())),
+            model_factory.clone(), &market_data).unwrap();
+        let (price, stderr) = pricer.price_with_stderr().unwrap();
+
+        // the option expires on 2018-06-01 and pays on 2018-06-05
+        let dates = [Date::from_ymd(2017, 01, 02), Date::from_ymd(2017, 06, 01),
+            Date::from_ymd(2017, 12, 01), Date::from_ymd(2018, 03, 01),
+            Date::from_ymd(2018, 06, 01), Date::from_ymd(2018, 06, 04),
+            Date::from_ymd(2018, 06, 05), Date::from_ymd(2018, 09, 01)];
+        let profile = pricer.exposure_profile(&dates).unwrap();
+
+        // A long option is never worth less than zero, so until it pays its
+        // discounted exposure stays at its price, rather than peaking
+        assert_approx(profile[0], price, 4.0 * stderr);
+        for epe in profile[1..6].iter() {
+            assert!(*epe >= profile[0] - 1e-9, "profile={:?}", profile);
+            assert_approx(*epe, profile[0], 0.02 * price);
+        }
+        for epe in profile[6..].iter() {
+            assert_eq!(*epe, 0.0, "profile={:?}", profile);
+        }
+
+        // Hedged with a fixed payment of about its price, the position starts
+        // near zero and its exposure grows as the spot moves away
+        let pay = Date::from_ymd(2018, 06, 05);
+        let settlement_date = Date::from_ymd(2017, 01, 04);
+        let amount = price / create_sample_rate().df(pay, settlement_date).unwrap();
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        let premium = RcInstrument::new(Qrc::new(Arc::new(CashflowStream::new(
+            "Premium", "LSE", currency, sample_settlement(2),
+            vec![Cashflow::new(DateTime::new(pay - 2, TimeOfDay::Open), pay, amount)])
+            .unwrap())));
+        let hedged = MonteCarloPricer::new(vec!((1.0, european), (-1.0, premium)),
+            model_factory, &market_data).unwrap();
+        let profile = hedged.exposure_profile(&dates[..5]).unwrap();
+        assert!(profile[0] < 1.0, "profile={:?}", profile);
+        for pair in profile.windows(2) {
+            assert!(pair[1] > pair[0], "profile={:?}", profile);
+        }
+        assert!(profile[3] > 3.0, "profile={:?}", profile);
+    }
+
+    #[test]
+    fn monte_carlo_discretization_bias() {
+
+        // A deep out of the money call depends on the tail of the
+        // distribution, which coarse Euler steps get badly wrong
+        let market_data = sample_market_data();
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        let equity = RcInstrument::new(Qrc::new(Arc::new(sample_equity(currency, 2))));
+        let expiry = DateTime::new(Date::from_ymd(2018, 06, 01), TimeOfDay::Close);
+        let call = RcInstrument::new(Qrc::new(Arc::new(SpotStartingEuropean::new(
+            "OutOfTheMoneyCall", "OPT", equity, sample_settlement(2), expiry,
+            180.0, PutOrCall::Call, OptionSettlement::Cash).unwrap())));
+
+        // a path_substep of 1.0 takes a single step to expiry, whereas 0.001
+        // takes over a hundred
+        let price = |scheme: DiscretizationScheme, path_substep: f64| -> (f64, f64) {
+            let model_factory = RcMonteCarloModelFactory::new(Arc::new(
+                BlackDiffusionFactory::new(20, path_substep, 20000,
+                VarianceReduction::None, Some(BASE_SEED)).unwrap().with_discretization(scheme)));
+            MonteCarloPricer::new(vec!((1.0, call.clone())), model_factory,
+                &market_data).unwrap().price_with_stderr().unwrap()
+        };
+
+        // exact stepping is unbiased whatever the step size
+        let (exact_coarse, coarse_err) = price(DiscretizationScheme::Exact, 1.0);
+        let (exact_fine, fine_err) = price(DiscretizationScheme::Exact, 0.001);
+        let stderr = (coarse_err * coarse_err + fine_err * fine_err).sqrt();
+        assert!(exact_coarse > 0.0);
+        assert_approx(exact_coarse, exact_fine, 4.0 * stderr);
+
+        // a single Euler step is normal rather than lognormal, so has far too
+        // thin a tail, whereas Milstein recovers much of the skew. All
+        // schemes converge as the steps get finer.
+        let (euler_coarse, _) = price(DiscretizationScheme::Euler, 1.0);
+        let (milstein_coarse, _) = price(DiscretizationScheme::Milstein, 1.0);
+        let (euler_fine, _) = price(DiscretizationScheme::Euler, 0.001);
+        let (milstein_fine, _) = price(DiscretizationScheme::Milstein, 0.001);
+        assert!(exact_coarse - euler_coarse > 10.0 * stderr,
+            "euler={} exact={} stderr={}", euler_coarse, exact_coarse, stderr);
+        assert!((exact_coarse - milstein_coarse).abs() < exact_coarse - euler_coarse,
+            "milstein={} euler={} exact={}", milstein_coarse, euler_coarse, exact_coarse);
+        assert_approx(euler_fine, exact_fine, 4.0 * stderr);
+        assert_approx(milstein_fine, exact_fine, 4.0 * stderr);
+    }
+
+    #[test]
+    fn monte_carlo_stratified_sampling_beats_plain() {
+
+        // With exact stepping the only error is Monte-Carlo noise, so we
+        // compare the root mean square errors against the analytic price
+        // over a number of seeds, at the same number of paths
+        let market_data = sample_market_data();
+        let european = sample_european();
+        let val_date = DateTime::new(Date::from_ymd(2017, 01, 02), TimeOfDay::Open);
+        let analytic = european.price(&market_data, val_date).unwrap();
+        let european = RcInstrument::new(Qrc::new(european));
+
+        let rms_error = |variance_reduction: VarianceReduction| -> f64 {
+            let n_seeds = 16;
+            let total: f64 = (0..n_seeds).map(|seed| {
+                let model_factory = RcMonteCarloModelFactory::new(Arc::new(
+                    BlackDiffusionFactory::new(20, 0.01, 4096, variance_reduction,
+                    Some(seed)).unwrap().with_discretization(DiscretizationScheme::Exact)));
+                let pricer = MonteCarloPricer::new(vec!((1.0, european.clone())),
+                    model_factory, &market_data).unwrap();
+                let error = pricer.price().unwrap() - analytic;
+                error * error
+            }).sum();
+            (total / n_seeds as f64).sqrt()
+        };
+
+        let plain = rms_error(VarianceReduction::None);
+        let stratified = rms_error(VarianceReduction::Stratified { strata: 64 });
+        assert!(stratified < 0.25 * plain, "stratified={} plain={}", stratified, plain);
+
+        // a single stratum is no stratification at all, and zero is an error
+        let one = rms_error(VarianceReduction::Stratified { strata: 1 });
+        assert!(one > stratified, "one={} stratified={}", one, stratified);
+        let model_factory = RcMonteCarloModelFactory::new(Arc::new(
+            BlackDiffusionFactory::new(20, 0.01, 4096,
+            VarianceReduction::Stratified { strata: 0 }, Some(BASE_SEED)).unwrap()));
+        assert!(MonteCarloPricer::new(vec!((1.0, european)), model_factory,
+            &market_data).is_err());
+    }
+
+    #[test]
+    fn monte_carlo_convergence_target_stops_early() {
+
+        // batches of 1000 paths do not fill whole chunks of random numbers,
+        // so later batches skip to fresh chunks
+        let market_data = sample_market_data();
+        let european = RcInstrument::new(Qrc::new(sample_european()));
+        let converged_pricer = |rel_error: f64| -> MonteCarloPricer {
+            let target = ConvergenceTarget { rel_error, max_paths: 20000 };
+            let model_factory = RcMonteCarloModelFactory::new(Arc::new(
+                BlackDiffusionFactory::new(20, 0.01, 1000, VarianceReduction::None,
+                Some(1234)).unwrap().with_convergence_target(target)));
+            MonteCarloPricer::new(vec!((1.0, european.clone())),
+                model_factory, &market_data).unwrap()
+        };
+
+        // a loose target is met well before the cap
+        let loose = converged_pricer(0.1);
+        let (price, moments) = loose.price_with_moments().unwrap();
+        assert!(moments.count() < 5000, "count={}", moments.count());
+        assert!(moments.stderr() <= 0.1 * price, "price={} stderr={}",
+            price, moments.stderr());
+        assert_approx(price, 16.710717400832973, 4.0 * moments.stderr());
+
+        // a tight target runs all the way to the cap
+        let tight = converged_pricer(0.001);
+        let (price, moments) = tight.price_with_moments().unwrap();
+        assert_eq!(moments.count(), 20000);
+        assert!(moments.stderr() > 0.001 * price);
+        assert_approx(price, 16.710717400832973, 4.0 * moments.stderr());
+
+        // the same seed runs the same batches, giving the same price
+        assert_eq!(converged_pricer(0.001).price().unwrap(), price);
+        assert_eq!(converged_pricer(0.1).price().unwrap(), loose.price().unwrap());
+
+        // rebuilding off bumped market data keeps the converged path count,
+        // rather than converging again
+        let (_, loose_moments) = loose.price_with_moments().unwrap();
+        let mut bumped_data = sample_market_data();
+        assert!(bumped_data.bump(&Bump::new_vol("BP.L",
+            BumpVol::new_flat_additive(0.2)), None).unwrap());
+        let mut rebuilt = loose.clone();
+        rebuilt.refresh_market_data(&bumped_data).unwrap();
+        let (_, rebuilt_moments) = rebuilt.price_with_moments().unwrap();
+        assert_eq!(rebuilt_moments.count(), loose_moments.count());
+    }
+
+    #[test]
+    fn monte_carlo_stochastic_rates() {
+
+        let market_data = sample_market_data();
+        let european = RcInstrument::new(Qrc::new(sample_european()));
+        let price = |rates: Option<StochasticRates>| -> Result<f64, qm::Error> {
+            let mut factory = BlackDiffusionFactory::new(20, 0.01, 10000,
+                VarianceReduction::Antithetic, Some(4321)).unwrap();
+            if let Some(rates) = rates {
+                factory = factory.with_stochastic_rates(rates);
+            }
+            let model_factory = RcMonteCarloModelFactory::new(Arc::new(factory));
+            MonteCarloPricer::new(vec!((1.0, european.clone())), model_factory,
+                &market_data)?.price()
+        };
+        let rates = |volatility: f64, correlation: f64| Some(StochasticRates {
+            mean_reversion: 0.1, volatility, correlation });
+
+        // with no rate vol, the paths and discounting are exactly as with
+        // deterministic rates, whatever the correlation
+        let deterministic = price(None).unwrap();
+        assert_approx(deterministic, 16.710717400832973, 0.5);
+        assert_eq!(price(rates(0.0, 0.5)).unwrap(), deterministic);
+        assert_eq!(price(rates(0.0, -0.5)).unwrap(), deterministic);
+
+        // rates correlated with the equity add to the vol of the forward
+        let positive = price(rates(0.02, 0.5)).unwrap();
+        let negative = price(rates(0.02, -0.5)).unwrap();
+        assert!(positive > negative, "positive={} negative={}", positive, negative);
+        assert_approx(positive, deterministic, 1.0);
+        assert_approx(negative, deterministic, 1.0);
+
+        assert!(price(rates(-0.01, 0.0)).is_err());
+        assert!(price(rates(0.01, 1.5)).is_err());
+    }
+
+    #[test]
+    fn monte_carlo_bad_inputs_give_stage_errors() {
+
+        // an Asian observes monthly, so the model steps between many dates
+        let pricer = || MonteCarloPricer::new(
+            vec!((1.0, RcInstrument::new(Qrc::new(sample_asian())))),
+            RcMonteCarloModelFactory::new(Arc::new(BlackDiffusionFactory::new(
+                20, 0.01, 1000, VarianceReduction::None, Some(BASE_SEED)).unwrap())),
+            &sample_market_data()).unwrap();
+        let bump_error = |bump: Bump| -> String {
+            let mut pricer = pricer();
+            let err = pricer.as_mut_bumpable().bump(&bump, None).unwrap_err();
+            format!("{}", err)
+        };
+
+        let message = bump_error(Bump::new_vol("BP.L", BumpVol::new_replace(NAN)));
+        assert!(message.contains("Non-finite variance NaN for BP.L"),
+            "unexpected error: {}", message);
+
+        let message = bump_error(Bump::new_spot("BP.L", BumpSpot::new_replace(NAN)));
+        assert!(message.contains("Non-finite forward NaN for BP.L"),
+            "unexpected error: {}", message);
+
+        // knocking out the vol from half a year on makes the total variance
+        // fall in the step after
+        let tenor = VolTenor::ladder(&[0.3, 0.5])[1];
+        let message = bump_error(Bump::new_vol("BP.L",
+            BumpVol::new_tenor_additive(tenor, -1.0)));
+        assert!(message.contains("Negative forward variance"),
+            "unexpected error: {}", message);
+        assert!(message.contains("for BP.L in the step to 2017-0"),
+            "unexpected error: {}", message);
+
+        // the same surface is rejected when the model is first built
+        let market_data = sample_market_data();
+        let mut bumped = market_data.clone();
+        bumped.bump(&Bump::new_vol("BP.L", BumpVol::new_replace(NAN)), None).unwrap();
+        let err = MonteCarloPricer::new(
+            vec!((1.0, RcInstrument::new(Qrc::new(sample_asian())))),
+            RcMonteCarloModelFactory::new(Arc::new(BlackDiffusionFactory::new(
+                20, 0.01, 1000, VarianceReduction::None, Some(BASE_SEED)).unwrap())),
+            &bumped).err().unwrap();
+        assert!(format!("{}", err).contains("Non-finite variance"), "{}", err);
+    }
+
+    #[test]
+    fn same_day_expiry_is_exact_intrinsic() {
+
+        let market_data = RcMarketData::new(Arc::new(sample_market_data()));
+        let fixings = RcFixingTable::new(Arc::new(sample_fixings()));
+        let european = |expiry: Date| {
+            let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+            let equity = RcInstrument::new(Qrc::new(Arc::new(sample_equity(currency, 2))));
+            RcInstrument::new(Qrc::new(Arc::new(SpotStartingEuropean::new(
+                "SameDayEuropean", "OPT", equity, sample_settlement(2),
+                DateTime::new(expiry, TimeOfDay::Close), 90.0, PutOrCall::Call,
+                OptionSettlement::Cash).unwrap())))
+        };
+        let pricer_for = |instrument: &RcInstrument, n_paths: usize| {
+            let model_factory = RcMonteCarloModelFactory::new(Arc::new(
+                BlackDiffusionFactory::new(20, 0.01, n_paths,
+                VarianceReduction::None, Some(BASE_SEED)).unwrap()));
+            let factory = MonteCarloPricerFactory::new(model_factory);
+            factory.new(instrument.clone(), fixings.clone(),
+                market_data.clone()).unwrap()
+        };
+
+        // An option expiring today is worth exactly its intrinsic value
+        // against spot, with no noise however many paths are asked for. It
+        // pays on the settlement date, so there is no discounting.
+        let today = european(Date::from_ymd(2017, 01, 02));
+        let mut pricer = pricer_for(&today, 1000);
+        assert_approx(pricer.price().unwrap(), 10.0, 1e-12);
+        assert_eq!(pricer_for(&today, 5000).price().unwrap(), pricer.price().unwrap());
+
+        // and is still bumpable, with a delta of one
+        let bump = Bump::new_spot("BP.L", BumpSpot::new_relative(0.01));
+        assert!(pricer.as_mut_bumpable().bump(&bump, None).unwrap());
+        assert_approx(pricer.price().unwrap(), 11.0, 1e-12);
+
+        // An option expiring tomorrow is still simulated
+        let tomorrow = european(Date::from_ymd(2017, 01, 03));
+        let coarse = pricer_for(&tomorrow, 1000).price().unwrap();
+        let fine = pricer_for(&tomorrow, 5000).price().unwrap();
+        assert!(coarse != fine, "coarse={} fine={}", coarse, fine);
+    }
+
     fn assert_approx(value: f64, expected: f64, tolerance: f64) {
         assert!(approx_eq(value, expected, tolerance),
             "value={} expected={}", value, expected);
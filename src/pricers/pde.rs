@@ -0,0 +1,420 @@
+use core::qm;
+use std::sync::Arc;
+use instruments::RcInstrument;
+use instruments::PricingContext;
+use instruments::PdePriceable;
+use instruments::DependencyContext;
+use risk::cache::PricingContextPrefetch;
+use risk::Pricer;
+use risk::PricerClone;
+use risk::pricereport::PriceReport;
+use risk::dependencies::DependencyCollector;
+use risk::Bumpable;
+use risk::TimeBumpable;
+use risk::Saveable;
+use risk::BumpablePricingContext;
+use pricers::PricerFactory;
+use data::fixings::RcFixingTable;
+use data::bump::Bump;
+use risk::bumptime::BumpTime;
+use risk::marketdata::RcMarketData;
+use risk::marketdata::MarketData;
+use dates::datetime::DateTime;
+use dates::datetime::TimeOfDay;
+use core::factories::TypeId;
+use core::factories::Qrc;
+use serde::Deserialize;
+use erased_serde as esd;
+
+/// The PdePricer values instruments by solving the pricing PDE backwards
+/// from expiry on a one-dimensional grid in log spot, using Crank-Nicolson
+/// time stepping. It is an alternative to the MonteCarloPricer for
+/// instruments with a single underlying, and exposes the same Bumpable and
+/// TimeBumpable interfaces, so it can be used for risk calculation.
+///
+/// Instruments that do not support the PdePriceable interface, such as
+/// the cash flows an option fixes into after expiry, are valued using their
+/// Priceable interface if they have one.
+#[derive(Clone)]
+pub struct PdePricer {
+    instruments: Vec<(f64, RcInstrument)>,
+    context: PricingContextPrefetch,
+    space_steps: usize,
+    time_steps: usize
+}
+
+/// The PdePricerFactory is used to construct PdePricer pricers. It is
+/// parameterised by the number of steps in the spatial grid and in time.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PdePricerFactory {
+    space_steps: usize,
+    time_steps: usize
+}
+
+/// The half-width of the grid, in standard deviations of log spot at expiry
+const GRID_STANDARD_DEVIATIONS: f64 = 6.0;
+
+/// The number of fully implicit steps taken at the start of the solve, to
+/// damp the oscillations that Crank-Nicolson suffers from when the payoff
+/// has a kink (Rannacher smoothing).
+const IMPLICIT_STEPS: usize = 2;
+
+impl PdePricerFactory {
+    /// Creates a factory for PDE pricers. The number of space steps is
+    /// rounded up to an even number, so that the forward is a grid node.
+    pub fn new(space_steps: usize, time_steps: usize) -> PdePricerFactory {
+        PdePricerFactory { space_steps, time_steps }
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<PricerFactory>, esd::Error> {
+        Ok(Qrc::new(Arc::new(PdePricerFactory::deserialize(de)?)))
+    }
+}
+
+impl TypeId for PdePricerFactory {
+    fn get_type_id(&self) -> &'static str { "PdePricerFactory" }
+}
+
+impl PricerFactory for PdePricerFactory {
+    fn new(&self, instrument: RcInstrument, fixing_table: RcFixingTable,
+        market_data: RcMarketData) -> Result<Box<Pricer>, qm::Error> {
+
+        // Apply the fixings to the instrument. (This is the last time we need
+        // the fixings.)
+        let instruments = match instrument.fix(&fixing_table)? {
+            Some(fixed) => fixed,
+            None => vec!((1.0, instrument))
+        };
+
+        let pricer = PdePricer::new(instruments, &market_data,
+            self.space_steps, self.time_steps)?;
+        Ok(Box::new(pricer))
+    }
+}
+
+impl PdePricer {
+    pub fn new(instruments: Vec<(f64, RcInstrument)>, market_data: &MarketData,
+        space_steps: usize, time_steps: usize) -> Result<PdePricer, qm::Error> {
+
+        if space_steps < 2 || time_steps < 1 {
+            return Err(qm::Error::new("PDE pricer needs at least two space \
+                steps and one time step"))
+        }
+
+        // Find the dependencies of the resulting vector of instruments
+        // also validate that all instruments can be priced
+        let mut dependencies = DependencyCollector::new(
+            market_data.spot_date());
+        for (_, instr) in instruments.iter() {
+            dependencies.spot(instr);
+            if let Some(pde) = instr.as_pde_priceable() {
+                dependencies.spot(&pde.pde_payment());
+            } else if instr.as_priceable().is_none() {
+                return Err(qm::Error::new(&format!("Instrument {} cannot \
+                    be priced by PDE", instr.id())))
+            }
+        }
+
+        // Create a cached pricing context, prefetching the data to price them
+        let context = PricingContextPrefetch::new(market_data,
+            Arc::new(dependencies))?;
+
+        // round the space steps up to an even number, so the forward is a node
+        let space_steps = space_steps + space_steps % 2;
+
+        Ok(PdePricer { instruments, context, space_steps, time_steps })
+    }
+
+    /// Values a single PDE-priceable instrument, as of the open on the spot
+    /// date.
+    fn pde_price(&self, pde: &PdePriceable) -> Result<f64, qm::Error> {
+
+        let context = self.context.as_pricing_context();
+        let val_date = DateTime::new(context.spot_date(), TimeOfDay::Open);
+
+        // fetch the forward, displacement and variance to expiry. We use
+        // the variance at the forward, consistent with the Black diffusion
+        let underlying = pde.pde_underlying();
+        let expiry = pde.pde_expiry();
+        let expiry_date = expiry.date();
+        let fwd = context.forward_curve(&**underlying, expiry_date)?;
+        let vol = context.vol_surface(&**underlying, expiry_date,
+            &|| Ok(fwd.clone()))?;
+        let forward = fwd.forward(expiry_date)?;
+        let displacement = vol.displacement(expiry_date)?;
+        let f = forward - displacement;
+        if f < 0.0 {
+            return Err(qm::Error::new("Negative forward"));
+        }
+        let val_time = underlying.time_to_day_fraction(val_date)?;
+        let variance = if val_time < expiry {
+            vol.forward_variance(val_time, expiry, forward)?
+        } else {
+            0.0
+        };
+        if variance < 0.0 {
+            return Err(qm::Error::new("Negative variance"));
+        }
+
+        // the payoff is expressed as a quantity of the payment
+        let payment = pde.pde_payment();
+        let priceable = payment.as_priceable().ok_or_else(|| qm::Error::new(
+            "The payment of a PDE-priceable instrument must be priceable"))?;
+        let df = priceable.price(context, val_date)?;
+
+        if variance == 0.0 {
+            return Ok(df * pde.pde_payoff(forward))
+        }
+
+        // set up a uniform grid in log of the displaced forward, centred on
+        // the forward, and initialise it with the payoff at expiry
+        let n = self.space_steps;
+        let half_width = GRID_STANDARD_DEVIATIONS * variance.sqrt();
+        let dy = 2.0 * half_width / n as f64;
+        let mut values: Vec<f64> = (0..n + 1).map(|i|
+            pde.pde_payoff(f * (dy * i as f64 - half_width).exp() + displacement))
+            .collect();
+
+        // step backwards, in units of variance rather than time
+        let dv = variance / self.time_steps as f64;
+        for step in 0..self.time_steps {
+            let theta = if step < IMPLICIT_STEPS { 1.0 } else { 0.5 };
+            crank_nicolson_step(&mut values, dv, dy, theta);
+        }
+
+        Ok(df * values[n / 2])
+    }
+}
+
+/// Takes one step backwards in variance of the PDE v_t = v_yy / 2 - v_y / 2,
+/// which is the Black PDE for a martingale in log space. The theta parameter
+/// is 0.5 for Crank-Nicolson and 1.0 for fully implicit stepping. The
+/// boundary values are left unchanged (Dirichlet boundary conditions), which
+/// is accurate enough if the grid is wide.
+fn crank_nicolson_step(values: &mut [f64], dv: f64, dy: f64, theta: f64) {
+    let n = values.len();
+    assert!(n >= 3);
+
+    // the spatial operator L is a tridiagonal matrix with constant bands
+    let a = dv * (0.5 / (dy * dy) + 0.25 / dy);    // coefficient of v[i-1]
+    let c = dv * (0.5 / (dy * dy) - 0.25 / dy);    // coefficient of v[i+1]
+    let b = -dv / (dy * dy);                        // coefficient of v[i]
+
+    // right hand side: (I + (1 - theta) L) v
+    let explicit = 1.0 - theta;
+    let mut rhs = values.to_vec();
+    for i in 1..n - 1 {
+        rhs[i] = values[i] + explicit * (a * values[i - 1] + b * values[i]
+            + c * values[i + 1]);
+    }
+
+    // solve (I - theta L) v' = rhs with the Thomas algorithm, folding the
+    // fixed boundary values into the right hand side
+    let lower = -theta * a;
+    let diag = 1.0 - theta * b;
+    let upper = -theta * c;
+    rhs[1] -= lower * values[0];
+    rhs[n - 2] -= upper * values[n - 1];
+
+    let m = n - 2;
+    let mut c_prime = vec![0.0; m];
+    let mut d_prime = vec![0.0; m];
+    c_prime[0] = upper / diag;
+    d_prime[0] = rhs[1] / diag;
+    for i in 1..m {
+        let denom = diag - lower * c_prime[i - 1];
+        c_prime[i] = upper / denom;
+        d_prime[i] = (rhs[i + 1] - lower * d_prime[i - 1]) / denom;
+    }
+    values[m] = d_prime[m - 1];
+    for i in (0..m - 1).rev() {
+        values[i + 1] = d_prime[i] - c_prime[i] * values[i + 2];
+    }
+}
+
+impl Pricer for PdePricer {
+    fn as_bumpable(&self) -> &Bumpable { self }
+    fn as_mut_bumpable(&mut self) -> &mut Bumpable { self }
+    fn as_mut_time_bumpable(&mut self) -> &mut TimeBumpable { self }
+
+    fn price_report(&self) -> Result<PriceReport, qm::Error> {
+        // Note that we have already verified that all components are priceable
+        // so here we simply skip any that are not.
+
+        // for now, always value as of the spot date at the open
+        let val_date = DateTime::new(self.context.as_pricing_context().spot_date(), TimeOfDay::Open);
+
+        let mut report = PriceReport::new();
+        for &(weight, ref instrument) in self.instruments.iter() {
+            if let Some(pde) = instrument.as_pde_priceable() {
+                report.add(instrument.id(), weight * self.pde_price(pde)?);
+            } else if let Some(priceable) = instrument.as_priceable() {
+                report.add(instrument.id(), weight * priceable.price(&self.context, val_date)?);
+            }
+        }
+        Ok(report)
+    }
+}
+
+impl PricerClone for PdePricer {
+    fn clone_box(&self) -> Box<Pricer> { Box::new(self.clone()) }
+}
+
+impl Bumpable for PdePricer {
+    fn bump(&mut self, bump: &Bump, save: Option<&mut Saveable>)
+        -> Result<bool, qm::Error> {
+        self.context.bump(bump, save)
+    }
+
+    fn dependencies(&self) -> Result<&DependencyCollector, qm::Error> {
+        self.context.dependencies()
+    }
+
+    fn context(&self) -> &PricingContext {
+        self.context.as_pricing_context()
+    }
+
+    fn new_saveable(&self) -> Box<Saveable> {
+        self.context.new_saveable()
+    }
+
+    fn restore(&mut self, saved: &Saveable) -> Result<(), qm::Error> {
+        self.context.restore(saved)
+    }
+
+    fn checkpoint(&self) -> Result<Vec<u8>, qm::Error> {
+        self.context.checkpoint()
+    }
+
+    fn restore_checkpoint(&mut self, checkpoint: &[u8]) -> Result<(), qm::Error> {
+        self.context.restore_checkpoint(checkpoint)
+    }
+}
+
+impl TimeBumpable for PdePricer {
+    fn bump_time(&mut self, bump: &BumpTime) -> Result<(), qm::Error> {
+        if bump.apply(&mut self.instruments, &mut self.context)? {
+            // if the instruments have changed, we need to rebuild the pricer
+            *self = PdePricer::new(self.instruments.clone(),
+                self.context.raw_market_data(), self.space_steps, self.time_steps)?
+        }
+        Ok(())
+   }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use dates::Date;
+    use math::numerics::approx_eq;
+    use data::bumpspot::BumpSpot;
+    use data::bumpvol::BumpVol;
+    use data::bumpspotdate::SpotDynamics;
+    use data::fixings::FixingTable;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::marketdata::tests::sample_european;
+    use pricers::RcPricerFactory;
+    use pricers::selfpricer::SelfPricerFactory;
+    use core::factories::Qrc;
+    use core::factories::tests::assert_debug_eq;
+    use serde_json;
+
+    fn sample_fixings() -> FixingTable {
+        let today = Date::from_ymd(2017, 01, 02);
+        FixingTable::from_fixings(today, &[
+            ("BP.L", &[
+            (DateTime::new(today - 7, TimeOfDay::Close), 102.0)])]).unwrap()
+    }
+
+    fn analytic_pricer() -> Box<Pricer> {
+        let market_data = RcMarketData::new(Arc::new(sample_market_data()));
+        let instrument = RcInstrument::new(Qrc::new(sample_european()));
+        let fixings = RcFixingTable::new(Arc::new(sample_fixings()));
+        SelfPricerFactory::new().new(instrument, fixings, market_data).unwrap()
+    }
+
+    fn pde_pricer() -> Box<Pricer> {
+        let market_data = RcMarketData::new(Arc::new(sample_market_data()));
+        let instrument = RcInstrument::new(Qrc::new(sample_european()));
+        let fixings = RcFixingTable::new(Arc::new(sample_fixings()));
+        PdePricerFactory::new(400, 100).new(instrument, fixings, market_data).unwrap()
+    }
+
+    #[test]
+    fn pde_price_european_against_analytic() {
+        let analytic = analytic_pricer().price().unwrap();
+        let pde = pde_pricer().price().unwrap();
+        assert_approx(pde, analytic, 1e-3);
+    }
+
+    #[test]
+    fn pde_price_european_bumped_price() {
+        let mut analytic = analytic_pricer();
+        let mut pricer = pde_pricer();
+        let mut save = pricer.as_bumpable().new_saveable();
+        let unbumped_price = pricer.price().unwrap();
+        let analytic_unbumped = analytic.price().unwrap();
+
+        // delta from a 1% spot bump should match the analytic delta
+        let bump = Bump::new_spot("BP.L", BumpSpot::new_relative(0.01));
+        let bumped = pricer.as_mut_bumpable().bump(&bump, Some(&mut *save)).unwrap();
+        assert!(bumped);
+        let delta = pricer.price().unwrap() - unbumped_price;
+        analytic.as_mut_bumpable().bump(&bump, None).unwrap();
+        let analytic_delta = analytic.price().unwrap() - analytic_unbumped;
+        assert_approx(delta, analytic_delta, 1e-3);
+
+        // when we restore, it should take the price back
+        pricer.as_mut_bumpable().restore(&*save).unwrap();
+        save.clear();
+        assert_approx(pricer.price().unwrap(), unbumped_price, 1e-12);
+
+        // a vol bump should increase the price of an atm option
+        let bump = Bump::new_vol("BP.L", BumpVol::new_flat_additive(0.01));
+        let bumped = pricer.as_mut_bumpable().bump(&bump, Some(&mut *save)).unwrap();
+        assert!(bumped);
+        assert!(pricer.price().unwrap() > unbumped_price);
+        pricer.as_mut_bumpable().restore(&*save).unwrap();
+        save.clear();
+        assert_approx(pricer.price().unwrap(), unbumped_price, 1e-12);
+    }
+
+    #[test]
+    fn pde_price_european_time_bumped() {
+        let mut analytic = analytic_pricer();
+        let mut pricer = pde_pricer();
+
+        // a time bump past expiry fixes the option into a cash flow, which
+        // we value directly
+        let spot_date = Date::from_ymd(2017, 01, 02);
+        let expiry_date = Date::from_ymd(2018, 06, 01);
+        let dynamics = SpotDynamics::StickyForward;
+        for &date in [spot_date + 1, expiry_date + 1].iter() {
+            let time_bump = BumpTime::new(date, spot_date, dynamics);
+            pricer.as_mut_time_bumpable().bump_time(&time_bump).unwrap();
+            analytic.as_mut_time_bumpable().bump_time(&time_bump).unwrap();
+            assert_approx(pricer.price().unwrap(), analytic.price().unwrap(), 1e-3);
+        }
+    }
+
+    #[test]
+    fn serde_pde_pricer_roundtrip() {
+
+        // create some sample data
+        let factory = RcPricerFactory::new(Arc::new(PdePricerFactory::new(200, 50)));
+
+        // round trip it via JSON
+        let serialized = serde_json::to_string_pretty(&factory).unwrap();
+        print!("serialized: {}\n", serialized);
+        let deserialized: RcPricerFactory = serde_json::from_str(&serialized).unwrap();
+
+        // check that they match, at least in debug representation
+        assert_debug_eq(&factory, &deserialized);
+    }
+
+    fn assert_approx(value: f64, expected: f64, tolerance: f64) {
+        assert!(approx_eq(value, expected, tolerance),
+            "value={} expected={}", value, expected);
+    }
+}
@@ -1,11 +1,14 @@
 use core::qm;
+use math::numerics::check_finite;
 use std::sync::Arc;
 use instruments::RcInstrument;
 use instruments::PricingContext;
 use instruments::DependencyContext;
+use instruments::Greeks;
 use risk::cache::PricingContextPrefetch;
 use risk::Pricer;
 use risk::PricerClone;
+use risk::pricereport::PriceReport;
 use risk::dependencies::DependencyCollector;
 use risk::Bumpable;
 use risk::TimeBumpable;
@@ -17,20 +20,28 @@ use data::bump::Bump;
 use risk::bumptime::BumpTime;
 use risk::marketdata::RcMarketData;
 use risk::marketdata::MarketData;
+use dates::Date;
 use dates::datetime::DateTime;
 use dates::datetime::TimeOfDay;
 use core::factories::TypeId;
 use core::factories::Qrc;
 use serde::Deserialize;
 use erased_serde as esd;
+use std::collections::HashMap;
 
 /// The SelfPricer calculator uses the Priceable interface of an
 /// instrument to evaluate the instrument . It then exposes this
 /// interface as a Pricer, allowing bumping for risk calculation.
+///
+/// It is also used in place of Monte-Carlo when every instrument's value is
+/// deterministic, in which case it uses the deterministic value of each
+/// instrument in preference to its Priceable interface. See
+/// MonteCarloPriceable::deterministic_value.
 #[derive(Clone)]
 pub struct SelfPricer {
     instruments: Vec<(f64, RcInstrument)>,
-    context: PricingContextPrefetch
+    context: PricingContextPrefetch,
+    deterministic: bool
 }
 
 /// The SelfPricerFactory is used to construct SelfPricer pricers.
@@ -76,9 +87,22 @@ impl SelfPricer {
         market_data: &MarketData) -> Result<SelfPricer, qm::Error> {
 
         // Find the dependencies of the resulting vector of instruments
-        // also validate that all instruments are self-priceable
-        let mut dependencies = DependencyCollector::new(
-            market_data.spot_date());
+        let dependencies = SelfPricer::collect_dependencies(&instruments,
+            market_data.spot_date())?;
+
+        // Create a cached pricing context, prefetching the data to price them
+        let context = PricingContextPrefetch::new(&*market_data,
+            Arc::new(dependencies))?;
+
+        SelfPricer::from_context(instruments, context)
+    }
+
+    /// Finds the dependencies of a vector of instruments, also validating
+    /// that all instruments are self-priceable
+    pub fn collect_dependencies(instruments: &[(f64, RcInstrument)],
+        spot_date: Date) -> Result<DependencyCollector, qm::Error> {
+
+        let mut dependencies = DependencyCollector::new(spot_date);
         for &(_, ref instr) in instruments.iter() {
             dependencies.spot(instr);
             if let None = instr.as_priceable() {
@@ -86,12 +110,75 @@ impl SelfPricer {
                     priceable", instr.id())))
             } 
         }
+        Ok(dependencies)
+    }
 
-        // Create a cached pricing context, prefetching the data to price them
-        let context = PricingContextPrefetch::new(&*market_data,
-            Arc::new(dependencies))?;
+    /// Creates a pricer given a context that has already prefetched the
+    /// dependencies of the instruments.
+    pub fn from_context(instruments: Vec<(f64, RcInstrument)>,
+        context: PricingContextPrefetch) -> Result<SelfPricer, qm::Error> {
+
+        for &(_, ref instr) in instruments.iter() {
+            if instr.as_priceable().is_none() {
+                return Err(qm::Error::new(&format!("Instrument {} is not \
+                    priceable", instr.id())))
+            }
+        }
+
+        Ok(SelfPricer { instruments: instruments, context: context,
+            deterministic: false })
+    }
+
+    /// Creates a pricer for instruments that would otherwise be valued by
+    /// Monte-Carlo, but whose values are all deterministic. Instruments with
+    /// no deterministic value must be priceable.
+    pub fn from_deterministic(instruments: Vec<(f64, RcInstrument)>,
+        context: PricingContextPrefetch) -> Result<SelfPricer, qm::Error> {
+
+        for &(_, ref instr) in instruments.iter() {
+            if deterministic_value(instr, &context)?.is_none()
+                && instr.as_priceable().is_none() {
+                return Err(qm::Error::new(&format!("Instrument {} is neither \
+                    deterministic nor priceable", instr.id())))
+            }
+        }
+
+        Ok(SelfPricer { instruments: instruments, context: context,
+            deterministic: true })
+    }
+
+    /// Whether every instrument is either deterministic when valued by
+    /// Monte-Carlo, or not valued by Monte-Carlo at all
+    pub fn all_deterministic(instruments: &[(f64, RcInstrument)],
+        context: &PricingContext) -> Result<bool, qm::Error> {
+        for &(_, ref instr) in instruments.iter() {
+            if instr.as_mc_priceable().is_some()
+                && deterministic_value(instr, context)?.is_none() {
+                return Ok(false)
+            }
+        }
+        Ok(true)
+    }
+
+    /// Calculates the closed-form greeks of the instruments, as of the same
+    /// val date as the price, weighted and summed by underlying id. This is
+    /// an error if any instrument does not have closed-form greeks.
+    pub fn analytic_greeks(&self) -> Result<HashMap<String, Greeks>, qm::Error> {
 
-        Ok(SelfPricer { instruments: instruments, context: context })
+        let context = self.context.as_pricing_context();
+        let val_date = DateTime::new(context.spot_date(), TimeOfDay::Open);
+
+        let mut results = HashMap::new();
+        for &(weight, ref instrument) in self.instruments.iter() {
+            let closed_form = instrument.as_closed_form_greeks().ok_or_else(||
+                qm::Error::new(&format!("Instrument {} does not have \
+                    closed-form greeks", instrument.id())))?;
+            let greeks = closed_form.analytic_greeks(context, val_date)?;
+            results.entry(closed_form.greeks_underlying().to_string())
+                .or_insert_with(Greeks::default)
+                .add_weighted(weight, &greeks);
+        }
+        Ok(results)
     }
 }
 
@@ -100,24 +187,35 @@ impl Pricer for SelfPricer {
     fn as_mut_bumpable(&mut self) -> &mut Bumpable { self }
     fn as_mut_time_bumpable(&mut self) -> &mut TimeBumpable { self }
 
-    fn price(&self) -> Result<f64, qm::Error> {
-        // Return a weighted sum of the individual prices. (TODO consider
-        // returning some data structure that shows the components as well as
-        // the weighted sum.)
-
+    fn price_report(&self) -> Result<PriceReport, qm::Error> {
         // Note that we have already verified that all components are priceable
         // so here we simply skip any that are not.
 
         // for now, always value as of the spot date at the open
         let val_date = DateTime::new(self.context.as_pricing_context().spot_date(), TimeOfDay::Open);
 
-        let mut total = 0.0;
+        let mut report = PriceReport::new();
         for &(weight, ref instrument) in self.instruments.iter() {
+            if self.deterministic {
+                if let Some(value) = deterministic_value(instrument, &self.context)? {
+                    let value = check_finite(value, "value",
+                        || instrument.id().to_string())?;
+                    report.add(instrument.id(), weight * value);
+                    continue
+                }
+            }
             if let Some(priceable) = instrument.as_priceable() {
-                total += weight * priceable.price(&self.context, val_date)?;
+                let value = check_finite(priceable.price(&self.context, val_date)?,
+                    "value", || instrument.id().to_string())?;
+                report.add(instrument.id(), weight * value);
             }
         }
-        Ok(total)
+        Ok(report)
+    }
+
+    fn refresh_market_data(&mut self, market_data: &MarketData)
+        -> Result<(), qm::Error> {
+        self.context.refresh_market_data(market_data)
     }
 }
 
@@ -146,21 +244,47 @@ impl Bumpable for SelfPricer {
     fn restore(&mut self, saved: &Saveable) -> Result<(), qm::Error> {
         self.context.restore(saved)
     }
+
+    fn checkpoint(&self) -> Result<Vec<u8>, qm::Error> {
+        self.context.checkpoint()
+    }
+
+    fn restore_checkpoint(&mut self, checkpoint: &[u8]) -> Result<(), qm::Error> {
+        self.context.restore_checkpoint(checkpoint)
+    }
 }
 
 impl TimeBumpable for SelfPricer {
     fn bump_time(&mut self, bump: &BumpTime) -> Result<(), qm::Error> {
         if bump.apply(&mut self.instruments, &mut self.context)? {
             // if the instruments have changed, we need to rebuild the pricer
-            *self = SelfPricer::new(self.instruments.clone(), self.context.raw_market_data())?
+            let deterministic = self.deterministic;
+            *self = SelfPricer::new(self.instruments.clone(), self.context.raw_market_data())?;
+            self.deterministic = deterministic;
         }
         Ok(())
-   }
+    }
+
+    fn bump_time_saved(&mut self, bump: &BumpTime, save: &mut Saveable)
+        -> Result<bool, qm::Error> {
+        bump.apply_saved(&self.instruments, &mut self.context, save)
+    }
+}
+
+/// The deterministic value of an instrument valued by Monte-Carlo, if it
+/// has one
+fn deterministic_value(instrument: &RcInstrument, context: &PricingContext)
+    -> Result<Option<f64>, qm::Error> {
+    match instrument.as_mc_priceable() {
+        Some(mc) => mc.deterministic_value(context),
+        None => Ok(None)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use models::random::BASE_SEED;
     use std::sync::Arc;
     use dates::Date;
     use dates::datetime::DateTime;
@@ -178,7 +302,19 @@ mod tests {
     use pricers::RcPricerFactory;
     use core::factories::Qrc;
     use core::factories::tests::assert_debug_eq;
+    use risk::ReportGenerator;
+    use risk::deltagamma::DeltaGammaReportGenerator;
+    use risk::deltagamma::DeltaGammaReport;
+    use pricers::montecarlo::MonteCarloPricer;
+    use models::RcMonteCarloModelFactory;
+    use models::VarianceReduction;
+    use models::blackdiffusion::BlackDiffusionFactory;
+    use instruments::bonds::ZeroCoupon;
+    use instruments::assets::RcCurrency;
+    use risk::marketdata::tests::sample_currency;
+    use risk::marketdata::tests::sample_settlement;
     use serde_json;
+    use std::f64::NAN;
 
     fn sample_fixings() -> FixingTable {
         let today = Date::from_ymd(2017, 01, 02);
@@ -187,6 +323,64 @@ mod tests {
             (DateTime::new(today - 7, TimeOfDay::Close), 102.0)])]).unwrap()
     }
 
+    #[test]
+    fn self_price_bad_vol_gives_stage_error() {
+
+        let market_data = RcMarketData::new(Arc::new(sample_market_data()));
+        let instrument = RcInstrument::new(Qrc::new(sample_european()));
+        let fixings = RcFixingTable::new(Arc::new(sample_fixings()));
+        let mut pricer = SelfPricerFactory::new().new(instrument, fixings,
+            market_data).unwrap();
+
+        let bump = Bump::new_vol("BP.L", BumpVol::new_replace(NAN));
+        assert!(pricer.as_mut_bumpable().bump(&bump, None).unwrap());
+        let message = format!("{}", pricer.price().unwrap_err());
+        assert!(message.contains("Non-finite value NaN for"),
+            "unexpected error: {}", message);
+    }
+
+    #[test]
+    fn self_price_european_checkpoint() {
+
+        let market_data = RcMarketData::new(Arc::new(sample_market_data()));
+        let instrument = RcInstrument::new(Qrc::new(sample_european()));
+        let fixings = RcFixingTable::new(Arc::new(sample_fixings()));
+
+        let factory = SelfPricerFactory::new();
+        let mut pricer = factory.new(instrument.clone(), fixings.clone(),
+            market_data.clone()).unwrap();
+        let unbumped = pricer.price().unwrap();
+
+        // checkpoint after a spot bump, and a discount bump which is not
+        // part of the serialized market data
+        let bumps = [Bump::new_spot("BP.L", BumpSpot::new_relative(0.01)),
+            Bump::new_discount("LSE", BumpYield::new_flat_annualised(0.01))];
+        for bump in bumps.iter() {
+            assert!(pricer.as_mut_bumpable().bump(bump, None).unwrap());
+        }
+        let bumped = pricer.price().unwrap();
+        let checkpoint = pricer.checkpoint().unwrap();
+
+        // a fresh pricer reloaded from the checkpoint prices identically
+        let mut reloaded = factory.new(instrument, fixings, market_data).unwrap();
+        assert_eq!(reloaded.price().unwrap(), unbumped);
+        reloaded.restore_checkpoint(&checkpoint).unwrap();
+        assert_eq!(reloaded.price().unwrap(), bumped);
+
+        // as does the original, whatever has been bumped since
+        let bump = Bump::new_vol("BP.L", BumpVol::new_flat_additive(0.01));
+        assert!(pricer.as_mut_bumpable().bump(&bump, None).unwrap());
+        assert!(pricer.price().unwrap() != bumped);
+        pricer.restore_checkpoint(&checkpoint).unwrap();
+        assert_eq!(pricer.price().unwrap(), bumped);
+
+        // a checkpoint cannot be restored on a different spot date
+        let mut rolled = pricer.clone_box();
+        let date = Date::from_ymd(2017, 01, 03);
+        rolled.bump_time(&BumpTime::new(date, date, SpotDynamics::StickySpot)).unwrap();
+        assert!(rolled.restore_checkpoint(&checkpoint).is_err());
+    }
+
     #[test]
     fn self_price_european_bumped_price() {
 
@@ -271,6 +465,35 @@ mod tests {
         assert_approx(price, unbumped_price, 1e-12);
     }
 
+    #[test]
+    fn self_price_european_price_report() {
+
+        let market_data = RcMarketData::new(Arc::new(sample_market_data()));
+        let instrument = RcInstrument::new(Qrc::new(sample_european()));
+        let fixings = RcFixingTable::new(Arc::new(sample_fixings()));
+
+        let factory = SelfPricerFactory::new();
+        let pricer = factory.new(instrument, fixings, market_data).unwrap();
+
+        let report = pricer.price_report().unwrap();
+        assert_approx(report.total(), 16.710717400832973, 1e-12);
+        assert_eq!(report.components().len(), 1);
+        assert_eq!(report.components()[0].0, "SampleSpotEuropean");
+        assert_approx(report.components()[0].1, report.total(), 1e-12);
+        assert_approx(pricer.price().unwrap(), report.total(), 1e-12);
+    }
+
+    #[test]
+    fn self_price_no_instruments() {
+        let market_data = sample_market_data();
+        let pricer = SelfPricer::new(Vec::new(), &market_data).unwrap();
+
+        let report = pricer.price_report().unwrap();
+        assert_eq!(report.total(), 0.0);
+        assert!(report.components().is_empty());
+        assert_eq!(pricer.price().unwrap(), 0.0);
+    }
+
     #[test]
     fn self_price_forward_european_time_bumped() {
 
@@ -331,6 +554,70 @@ mod tests {
         assert_approx(bumped_price, 12.219583564604477, 1e-12);
     }
 
+    fn bumped_delta_gamma(pricer: &mut Pricer, bumpsize: f64) -> (f64, f64) {
+        let unbumped = pricer.price().unwrap();
+        let mut save = pricer.as_bumpable().new_saveable();
+        let generator = DeltaGammaReportGenerator::new(bumpsize);
+        let report = generator.generate(pricer, &mut *save, unbumped).unwrap();
+        let results = report.as_any().downcast_ref::<DeltaGammaReport>()
+            .unwrap().results();
+        let delta_gamma = results.get("BP.L").unwrap();
+        (delta_gamma.delta(), delta_gamma.gamma())
+    }
+
+    #[test]
+    fn self_price_european_analytic_greeks() {
+
+        let market_data = sample_market_data();
+        let instrument = RcInstrument::new(Qrc::new(sample_european()));
+        let mut pricer = SelfPricer::new(vec![(2.0, instrument.clone())],
+            &market_data).unwrap();
+        let greeks = pricer.analytic_greeks().unwrap();
+        assert_eq!(greeks.len(), 1);
+        let greeks = greeks.get("BP.L").unwrap();
+
+        // the closed-form delta and gamma agree with a small bump of the
+        // self-pricer, up to the second order error in the bump
+        let (delta, gamma) = bumped_delta_gamma(&mut pricer, 0.0001);
+        assert_approx(greeks.delta(), delta, 1e-6);
+        assert_approx(greeks.gamma(), gamma, 1e-6);
+        assert_approx(greeks.delta(), 2.0 * 0.6281335819139144, 1e-6);
+
+        // vega is close to a one percent flat vol bump, and theta and rho
+        // have the expected signs for a call
+        let mut save = pricer.as_bumpable().new_saveable();
+        let unbumped = pricer.price().unwrap();
+        let bump = Bump::new_vol("BP.L", BumpVol::new_flat_additive(0.0001));
+        assert!(pricer.as_mut_bumpable().bump(&bump, Some(&mut *save)).unwrap());
+        let bumped_vega = (pricer.price().unwrap() - unbumped) / 0.0001;
+        assert_approx(greeks.vega(), bumped_vega, 0.01);
+        assert!(greeks.theta() < 0.0, "theta={}", greeks.theta());
+        assert!(greeks.rho() > 0.0, "rho={}", greeks.rho());
+
+        // the closed-form delta matches the Monte-Carlo bumped delta, which
+        // is noisy but uses the same paths up and down
+        let model_factory = RcMonteCarloModelFactory::new(Arc::new(
+            BlackDiffusionFactory::new(20, 0.01, 20000,
+            VarianceReduction::Antithetic, Some(BASE_SEED)).unwrap()));
+        let mut mc_pricer = MonteCarloPricer::new(vec![(2.0, instrument)],
+            model_factory, &market_data).unwrap();
+        let (mc_delta, _) = bumped_delta_gamma(&mut mc_pricer, 0.01);
+        assert_approx(mc_delta, greeks.delta(), 0.04);
+    }
+
+    #[test]
+    fn self_price_analytic_greeks_need_closed_form() {
+
+        let market_data = sample_market_data();
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        let pay_date = Date::from_ymd(2018, 06, 05);
+        let bond = RcInstrument::new(Qrc::new(Arc::new(ZeroCoupon::new("ZC",
+            "OPT", currency, DateTime::new(pay_date, TimeOfDay::Open), pay_date,
+            sample_settlement(2)))));
+        let pricer = SelfPricer::new(vec![(1.0, bond)], &market_data).unwrap();
+        assert!(pricer.analytic_greeks().is_err());
+    }
+
     #[test]
     fn serde_self_pricer_roundtrip() {
 
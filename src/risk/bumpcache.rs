@@ -0,0 +1,460 @@
+use core::qm;
+use data::bump::Bump;
+use risk::Pricer;
+use risk::Saveable;
+use risk::restore_and_verify;
+
+/// Memoizes bumped prices within a single report session, so that bumped
+/// states needed by more than one report generator are only priced once.
+/// For example, the vol bump up used for vega is also one of the legs of
+/// volga in the vanna-volga report.
+///
+/// Prices are keyed on the full sequence of bumps applied, in order, to the
+/// unbumped pricer, where bumps are compared by value. A state reached by
+/// bumping up then down is therefore distinct from one reached by a single
+/// bump, even if they should be the same to within rounding.
+///
+/// The cached prices are only valid while the pricer returns exactly to the
+/// unbumped state between bumps. An incomplete restore cannot in general be
+/// detected, so the cache is emptied whenever the pricer is restored or
+/// rebumped by anything other than the cached functions in this module, or
+/// when a bump or restore fails. A session should start with an empty
+/// cache.
+#[derive(Default)]
+pub struct BumpedPriceCache {
+    entries: Vec<(Vec<Bump>, f64)>
+}
+
+impl BumpedPriceCache {
+    pub fn new() -> BumpedPriceCache {
+        BumpedPriceCache::default()
+    }
+
+    /// Returns the cached price for the given sequence of bumps applied to
+    /// the unbumped state, if there is one.
+    pub fn get(&self, bumps: &[Bump]) -> Option<f64> {
+        self.entries.iter()
+            .find(|&&(ref key, _)| key.as_slice() == bumps)
+            .map(|&(_, price)| price)
+    }
+
+    /// Stores the price for the given sequence of bumps applied to the
+    /// unbumped state, replacing any price already stored for them.
+    pub fn insert(&mut self, bumps: &[Bump], price: f64) {
+        if let Some(entry) = self.entries.iter_mut().find(|entry| entry.0.as_slice() == bumps) {
+            entry.1 = price;
+            return
+        }
+        self.entries.push((bumps.to_vec(), price));
+    }
+
+    /// Empties the cache. This must be called whenever the pricer may have
+    /// been left in a state other than the unbumped one.
+    pub fn invalidate(&mut self) {
+        self.entries.clear();
+    }
+
+    /// The number of bumped prices in the cache
+    pub fn len(&self) -> usize { self.entries.len() }
+
+    pub fn is_empty(&self) -> bool { self.entries.is_empty() }
+}
+
+/// Restores a pricer after a bump, as restore_and_verify, then clears the
+/// saveable. If the restore fails, the pricer may be left in a state other
+/// than the one the cached prices were calculated for, so the cache is
+/// invalidated.
+pub fn restore_cached(pricer: &mut Pricer, saveable: &mut Saveable, unbumped: f64,
+    cache: &mut BumpedPriceCache) -> Result<(), qm::Error> {
+
+    if let Err(e) = restore_and_verify(pricer, saveable, unbumped) {
+        cache.invalidate();
+        return Err(e)
+    }
+    saveable.clear();
+    Ok(())
+}
+
+/// Applies a set of bumps, saving the state before any of them, reprices,
+/// then restores the pricer and clears the saveable ready for the next bump.
+/// If the same bumps have already been priced in this session, the cached
+/// price is returned without bumping or repricing. If none of the bumps had
+/// any effect, returns the unbumped price.
+pub fn cached_bumped_price(pricer: &mut Pricer, saveable: &mut Saveable, bumps: &[Bump],
+    unbumped: f64, cache: &mut BumpedPriceCache) -> Result<f64, qm::Error> {
+
+    if let Some(price) = cache.get(bumps) {
+        return Ok(price)
+    }
+
+    let mut applied = Ok(false);
+    for bump in bumps.iter() {
+        match pricer.as_mut_bumpable().bump(bump, Some(&mut *saveable)) {
+            Ok(bumped) => applied = applied.map(|a| a | bumped),
+            Err(e) => { applied = Err(e); break }
+        }
+    }
+
+    let price = match applied {
+        Ok(true) => pricer.price(),
+        Ok(false) => Ok(unbumped),
+        Err(e) => Err(e)
+    };
+
+    // restore even if the bumping or pricing failed, so the pricer is left
+    // unchanged
+    finish_cached(pricer, saveable, unbumped, cache, price.is_ok())?;
+    let price = price?;
+    cache.insert(bumps, price);
+    Ok(price)
+}
+
+/// Applies a sequence of bumps one after another, repricing after each, then
+/// restores the pricer and clears the saveable. Only the state before the
+/// first bump is saved, so this is cheaper than restoring between the bumps.
+/// Returns the price after each bump, where the price after the i'th bump
+/// is cached under the first i bumps of the sequence. If every one of these
+/// is already cached, nothing is bumped or repriced. A bump that has no
+/// effect leaves the price unchanged.
+pub fn cached_bump_sequence(pricer: &mut Pricer, saveable: &mut Saveable, bumps: &[Bump],
+    unbumped: f64, cache: &mut BumpedPriceCache) -> Result<Vec<f64>, qm::Error> {
+
+    let cached: Option<Vec<f64>> = (1..bumps.len() + 1)
+        .map(|i| cache.get(&bumps[..i])).collect();
+    if let Some(prices) = cached {
+        return Ok(prices)
+    }
+
+    let mut prices = Vec::with_capacity(bumps.len());
+    let mut error = None;
+    let mut price = unbumped;
+    for (i, bump) in bumps.iter().enumerate() {
+        let save = if i == 0 { Some(&mut *saveable) } else { None };
+        let result = match pricer.as_mut_bumpable().bump(bump, save) {
+            Ok(true) => pricer.price(),
+            Ok(false) => Ok(price),
+            Err(e) => Err(e)
+        };
+        match result {
+            Ok(bumped) => { price = bumped; prices.push(price) },
+            Err(e) => { error = Some(e); break }
+        }
+    }
+
+    // restore even if the bumping or pricing failed, so the pricer is left
+    // unchanged
+    finish_cached(pricer, saveable, unbumped, cache, error.is_none())?;
+    if let Some(e) = error {
+        return Err(e)
+    }
+    for (i, &price) in prices.iter().enumerate() {
+        cache.insert(&bumps[..i + 1], price);
+    }
+    Ok(prices)
+}
+
+/// Restores the pricer after cached bumps. If the bumps failed, the
+/// restored state cannot be trusted, so the cache is invalidated.
+fn finish_cached(pricer: &mut Pricer, saveable: &mut Saveable, unbumped: f64,
+    cache: &mut BumpedPriceCache, succeeded: bool) -> Result<(), qm::Error> {
+
+    restore_cached(pricer, saveable, unbumped, cache)?;
+    if !succeeded {
+        cache.invalidate();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::sync::Arc;
+    use math::numerics::approx_eq;
+    use risk::deltagamma::tests::sample_pricer;
+    use risk::deltagamma::{DeltaGammaReportGenerator, DeltaGammaReport};
+    use risk::vegavolga::{VegaVolgaReportGenerator, VegaVolgaReport};
+    use risk::vannavolga::{VannaVolgaReportGenerator, VannaVolgaReport};
+    use risk::Bumpable;
+    use risk::TimeBumpable;
+    use risk::PricerClone;
+    use risk::RcReportGenerator;
+    use risk::BoxReport;
+    use risk::generate_reports;
+    use risk::bumptime::BumpTime;
+    use risk::pricereport::PriceReport;
+    use risk::dependencies::DependencyCollector;
+    use instruments::PricingContext;
+    use data::bumpspot::BumpSpot;
+    use data::bumpvol::BumpVol;
+    use data::bumpspot::VolDynamics;
+    use data::bumpspotdate::SpotDynamics;
+    use risk::timebumped::TimeBumpedReportGenerator;
+
+    // a pricer that counts the number of times it is priced, and can be
+    // made to fail once it has been priced a given number of times
+    struct CountingPricer {
+        pricer: Box<Pricer>,
+        count: Cell<usize>,
+        fail_after: Cell<Option<usize>>
+    }
+
+    impl CountingPricer {
+        fn new() -> CountingPricer {
+            CountingPricer { pricer: sample_pricer(), count: Cell::new(0),
+                fail_after: Cell::new(None) }
+        }
+    }
+
+    impl Pricer for CountingPricer {
+        fn as_bumpable(&self) -> &Bumpable { self }
+        fn as_mut_bumpable(&mut self) -> &mut Bumpable { self }
+        fn as_mut_time_bumpable(&mut self) -> &mut TimeBumpable { self }
+        fn price_report(&self) -> Result<PriceReport, qm::Error> {
+            if let Some(limit) = self.fail_after.get() {
+                if self.count.get() >= limit {
+                    return Err(qm::Error::new("CountingPricer failed as requested"))
+                }
+            }
+            self.count.set(self.count.get() + 1);
+            self.pricer.price_report()
+        }
+    }
+
+    impl PricerClone for CountingPricer {
+        fn clone_box(&self) -> Box<Pricer> {
+            Box::new(CountingPricer { pricer: self.pricer.clone_box(), count: Cell::new(0),
+                fail_after: Cell::new(self.fail_after.get()) })
+        }
+    }
+
+    impl Bumpable for CountingPricer {
+        fn bump(&mut self, bump: &Bump, save: Option<&mut Saveable>) -> Result<bool, qm::Error> {
+            self.pricer.as_mut_bumpable().bump(bump, save)
+        }
+        fn dependencies(&self) -> Result<&DependencyCollector, qm::Error> {
+            self.pricer.as_bumpable().dependencies()
+        }
+        fn context(&self) -> &PricingContext {
+            self.pricer.as_bumpable().context()
+        }
+        fn new_saveable(&self) -> Box<Saveable> {
+            self.pricer.as_bumpable().new_saveable()
+        }
+        fn restore(&mut self, saved: &Saveable) -> Result<(), qm::Error> {
+            self.pricer.as_mut_bumpable().restore(saved)
+        }
+    }
+
+    impl TimeBumpable for CountingPricer {
+        fn bump_time(&mut self, bump: &BumpTime) -> Result<(), qm::Error> {
+            self.pricer.bump_time(bump)
+        }
+    }
+
+    fn greek_generators() -> Vec<RcReportGenerator> {
+        vec![
+            RcReportGenerator::new(Arc::new(DeltaGammaReportGenerator::new(0.01))),
+            RcReportGenerator::new(Arc::new(VegaVolgaReportGenerator::new(
+                BumpVol::new_flat_additive(0.01)))),
+            RcReportGenerator::new(Arc::new(VannaVolgaReportGenerator::new(0.01, 0.01)))]
+    }
+
+    #[test]
+    fn combined_greek_report_reuses_bumped_prices() {
+
+        // each generator on its own, without sharing a cache
+        let mut pricer = CountingPricer::new();
+        let unbumped = pricer.price().unwrap();
+        let mut save = pricer.as_bumpable().new_saveable();
+        let mut separate = Vec::new();
+        pricer.count.set(0);
+        for generator in greek_generators().iter() {
+            separate.push(generator.generate(&mut pricer, &mut *save, unbumped).unwrap());
+        }
+
+        // delta-gamma and vega-volga take two repricings each, vanna-volga six
+        assert_eq!(pricer.count.get(), 10);
+
+        // in a single session, both vol bumps for vega are reused by volga
+        pricer.count.set(0);
+        let combined = generate_reports(&mut pricer, &greek_generators(), unbumped).unwrap();
+        assert_eq!(pricer.count.get(), 8);
+        assert_eq!(pricer.price().unwrap(), unbumped);
+
+        // and the results are the same
+        assert_eq!(combined.len(), separate.len());
+        let delta_gamma = |report: &BoxReport| report.as_any()
+            .downcast_ref::<DeltaGammaReport>().unwrap().results()
+            .get("BP.L").unwrap().delta();
+        let vega = |report: &BoxReport| report.as_any()
+            .downcast_ref::<VegaVolgaReport>().unwrap().results()
+            .get("BP.L").unwrap().vega();
+        let volga = |report: &BoxReport| report.as_any()
+            .downcast_ref::<VannaVolgaReport>().unwrap().results()
+            .get("BP.L").unwrap().volga();
+        assert_eq!(delta_gamma(&combined[0]), delta_gamma(&separate[0]));
+        assert_eq!(vega(&combined[1]), vega(&separate[1]));
+        assert_approx(volga(&combined[2]), volga(&separate[2]), 1e-12);
+    }
+
+    #[test]
+    fn repeated_bumps_are_priced_once() {
+
+        let mut pricer = CountingPricer::new();
+        let unbumped = pricer.price().unwrap();
+        let mut save = pricer.as_bumpable().new_saveable();
+        let mut cache = BumpedPriceCache::new();
+        let bumps = [Bump::new_spot("BP.L", BumpSpot::new_relative(0.01)),
+            Bump::new_vol("BP.L", BumpVol::new_flat_additive(0.01))];
+
+        pricer.count.set(0);
+        let first = cached_bumped_price(&mut pricer, &mut *save, &bumps, unbumped, &mut cache).unwrap();
+        let second = cached_bumped_price(&mut pricer, &mut *save, &bumps, unbumped, &mut cache).unwrap();
+        assert_eq!(pricer.count.get(), 1);
+        assert_eq!(first, second);
+        assert!(first != unbumped);
+        assert_eq!(pricer.price().unwrap(), unbumped);
+
+        // the same bumps in a different order are a different key
+        let reversed = [bumps[1].clone(), bumps[0].clone()];
+        assert!(cache.get(&reversed).is_none());
+        assert_eq!(cache.get(&bumps), Some(first));
+        assert_eq!(cache.len(), 1);
+
+        // invalidating empties the cache
+        cache.invalidate();
+        assert!(cache.get(&bumps).is_none());
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn bump_sequences_are_keyed_on_every_bump() {
+
+        let mut pricer = CountingPricer::new();
+        let unbumped = pricer.price().unwrap();
+        let mut save = pricer.as_bumpable().new_saveable();
+        let mut cache = BumpedPriceCache::new();
+        let up = Bump::new_spot("BP.L", BumpSpot::new_relative(0.01));
+        let down = Bump::new_spot("BP.L", BumpSpot::new_relative(1.0 / 1.01 - 1.0));
+
+        pricer.count.set(0);
+        let prices = cached_bump_sequence(&mut pricer, &mut *save,
+            &[up.clone(), down.clone()], unbumped, &mut cache).unwrap();
+        assert_eq!(pricer.count.get(), 2);
+        assert_eq!(pricer.price().unwrap(), unbumped);
+
+        // the up state is shared with a single bump, but the state after
+        // both bumps is not the same as a single bump down, nor is it the
+        // unbumped state, even if the spot is the same to within rounding
+        assert_eq!(cache.get(&[up.clone()]), Some(prices[0]));
+        assert_eq!(cache.get(&[up.clone(), down.clone()]), Some(prices[1]));
+        assert!(cache.get(&[down.clone()]).is_none());
+        let single = cached_bumped_price(&mut pricer, &mut *save, &[up.clone()],
+            unbumped, &mut cache).unwrap();
+        assert_eq!(single, prices[0]);
+        assert_eq!(pricer.count.get(), 2);
+
+        // a spot bump with different vol dynamics is a different key
+        let sticky_delta = Bump::new_spot("BP.L", BumpSpot::new_relative_with_dynamics(
+            1.0 / 1.01 - 1.0, VolDynamics::StickyDelta));
+        assert!(cache.get(&[up.clone(), sticky_delta]).is_none());
+    }
+
+    #[test]
+    fn repeated_reports_in_a_session_are_not_repriced() {
+
+        let mut pricer = CountingPricer::new();
+        let unbumped = pricer.price().unwrap();
+        let delta_gamma = RcReportGenerator::new(Arc::new(DeltaGammaReportGenerator::new(0.01)));
+        let vega_volga = RcReportGenerator::new(Arc::new(VegaVolgaReportGenerator::new(
+            BumpVol::new_flat_additive(0.01))));
+
+        // delta-gamma costs two repricings the first time, and none the second
+        pricer.count.set(0);
+        let reports = generate_reports(&mut pricer,
+            &[delta_gamma.clone(), delta_gamma.clone()], unbumped).unwrap();
+        assert_eq!(pricer.count.get(), 2);
+        let delta = |report: &BoxReport| report.as_any()
+            .downcast_ref::<DeltaGammaReport>().unwrap().results()
+            .get("BP.L").unwrap().delta();
+        assert_eq!(delta(&reports[0]), delta(&reports[1]));
+
+        // and likewise vega-volga
+        pricer.count.set(0);
+        let reports = generate_reports(&mut pricer,
+            &[vega_volga.clone(), vega_volga.clone()], unbumped).unwrap();
+        assert_eq!(pricer.count.get(), 2);
+        let volga = |report: &BoxReport| report.as_any()
+            .downcast_ref::<VegaVolgaReport>().unwrap().results()
+            .get("BP.L").unwrap().volga();
+        assert_eq!(volga(&reports[0]), volga(&reports[1]));
+        assert_eq!(pricer.price().unwrap(), unbumped);
+    }
+
+    #[test]
+    fn reports_that_do_not_use_the_cache_invalidate_it() {
+
+        let mut pricer = CountingPricer::new();
+        let unbumped = pricer.price().unwrap();
+        let mut save = pricer.as_bumpable().new_saveable();
+        let mut cache = BumpedPriceCache::new();
+        let bump = [Bump::new_spot("BP.L", BumpSpot::new_relative(0.01))];
+        cached_bumped_price(&mut pricer, &mut *save, &bump, unbumped, &mut cache).unwrap();
+        assert_eq!(cache.len(), 1);
+
+        // the time-bumped report does not use the cache, so it cannot know
+        // what the report did to the pricer
+        let spot_date = pricer.as_bumpable().context().spot_date();
+        let generator = TimeBumpedReportGenerator::new(BumpTime::new(
+            spot_date + 1, spot_date + 1, SpotDynamics::StickyForward));
+        generator.generate_cached(&mut pricer, &mut *save, unbumped, &mut cache).unwrap();
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn failed_restore_invalidates_cache() {
+
+        let mut pricer = sample_pricer();
+        let unbumped = pricer.price().unwrap();
+        let mut save = pricer.as_bumpable().new_saveable();
+        let mut cache = BumpedPriceCache::new();
+        let bump = [Bump::new_spot("BP.L", BumpSpot::new_relative(0.01))];
+        cached_bumped_price(&mut *pricer, &mut *save, &bump, unbumped, &mut cache).unwrap();
+        assert_eq!(cache.len(), 1);
+
+        // corrupt the saved state, so the restore leaves the pricer bumped
+        assert!(pricer.as_mut_bumpable().bump(&bump[0], Some(&mut *save)).unwrap());
+        save.clear();
+        let result = restore_cached(&mut *pricer, &mut *save, unbumped, &mut cache);
+        assert_eq!(result.is_err(), cfg!(feature = "restore-check"));
+        assert_eq!(cache.is_empty(), cfg!(feature = "restore-check"));
+    }
+
+    #[test]
+    fn failed_repricing_invalidates_cache() {
+
+        let mut pricer = CountingPricer::new();
+        let unbumped = pricer.price().unwrap();
+        let mut save = pricer.as_bumpable().new_saveable();
+        let mut cache = BumpedPriceCache::new();
+        let up = Bump::new_spot("BP.L", BumpSpot::new_relative(0.01));
+        let down = Bump::new_spot("BP.L", BumpSpot::new_relative(-0.02));
+        cached_bumped_price(&mut pricer, &mut *save, &[up.clone()], unbumped, &mut cache).unwrap();
+        assert_eq!(cache.len(), 1);
+
+        // a repricing that fails part way through a sequence leaves the
+        // pricer restored, but the cache is emptied
+        pricer.fail_after.set(Some(pricer.count.get()));
+        assert!(cached_bump_sequence(&mut pricer, &mut *save, &[down, up],
+            unbumped, &mut cache).is_err());
+        assert!(cache.is_empty());
+        pricer.fail_after.set(None);
+        assert_eq!(pricer.price().unwrap(), unbumped);
+    }
+
+    fn assert_approx(value: f64, expected: f64, tolerance: f64) {
+        assert!(approx_eq(value, expected, tolerance),
+            "value={} expected={}", value, expected);
+    }
+}
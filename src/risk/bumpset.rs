@@ -0,0 +1,144 @@
+use risk::Bumpable;
+use risk::Saveable;
+use data::bump::Bump;
+use core::qm;
+
+/// A sequence of bumps to be applied together, for example the factors of
+/// a multi-factor scenario. The set is built up fluently, by chaining calls
+/// to add, and can be used standalone or underneath a Scenario.
+///
+/// The bumps are applied in the order they were added, each saving into its
+/// own save area, and are restored explicitly in the reverse order. Bumps
+/// need not commute, for example replacing the spot then scaling it, so the
+/// set unwinds them one at a time rather than relying on a single save area
+/// to hold the state from before the first.
+#[derive(Clone, Default)]
+pub struct BumpSet {
+    bumps: Vec<Bump>
+}
+
+/// The state saved by each bump of a BumpSet that was applied, in the order
+/// they were applied. Returned by apply_all, and consumed by restore_all.
+pub struct SavedBumps {
+    saves: Vec<Box<Saveable>>,
+    bumped: bool
+}
+
+impl SavedBumps {
+    /// Whether any of the bumps changed anything
+    pub fn bumped(&self) -> bool { self.bumped }
+}
+
+impl BumpSet {
+    pub fn new() -> BumpSet {
+        BumpSet { bumps: Vec::new() }
+    }
+
+    /// Adds a bump, to be applied after all those already in the set
+    pub fn add(mut self, bump: Bump) -> BumpSet {
+        self.bumps.push(bump);
+        self
+    }
+
+    pub fn bumps(&self) -> &[Bump] { &self.bumps }
+    pub fn is_empty(&self) -> bool { self.bumps.is_empty() }
+
+    /// Applies all the bumps in order, returning the state saved by each, so
+    /// they can be undone by restore_all. If any bump fails, the ones
+    /// already applied are restored and the error is returned.
+    pub fn apply_all(&self, bumpable: &mut Bumpable) -> Result<SavedBumps, qm::Error> {
+
+        let mut saved = SavedBumps { saves: Vec::with_capacity(self.bumps.len()),
+            bumped: false };
+        for bump in self.bumps.iter() {
+            let mut save = bumpable.new_saveable();
+            match bumpable.bump(bump, Some(&mut *save)) {
+                Ok(b) => {
+                    saved.bumped |= b;
+                    saved.saves.push(save);
+                },
+                Err(e) => {
+                    // the failed bump may have saved some state before failing
+                    saved.saves.push(save);
+                    self.restore_all(bumpable, saved)?;
+                    return Err(e)
+                }
+            }
+        }
+        Ok(saved)
+    }
+
+    /// Undoes the bumps applied by apply_all, restoring the state saved by
+    /// each in the reverse order to that in which they were applied, so the
+    /// bumpable is left as it was before them. Every bump is restored even
+    /// if one fails, in which case the first error is returned.
+    pub fn restore_all(&self, bumpable: &mut Bumpable, saved: SavedBumps)
+        -> Result<(), qm::Error> {
+
+        let mut result = Ok(());
+        for save in saved.saves.iter().rev() {
+            let restored = bumpable.restore(&**save);
+            if result.is_ok() {
+                result = restored;
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use risk::Pricer;
+    use risk::deltagamma::tests::sample_pricer;
+    use data::bumpspot::BumpSpot;
+    use data::bumpvol::BumpVol;
+
+    #[test]
+    fn spot_and_vol_bump_set_matches_manual_bumps() {
+
+        let spot = Bump::new_spot("BP.L", BumpSpot::new_relative(-0.1));
+        let vol = Bump::new_vol("BP.L", BumpVol::new_flat_additive(0.05));
+        let set = BumpSet::new().add(spot.clone()).add(vol.clone());
+        assert_eq!(set.bumps().len(), 2);
+
+        let mut pricer = sample_pricer();
+        let unbumped = pricer.price().unwrap();
+        let saved = set.apply_all(pricer.as_mut_bumpable()).unwrap();
+        assert!(saved.bumped());
+        let bumped = pricer.price().unwrap();
+        set.restore_all(pricer.as_mut_bumpable(), saved).unwrap();
+        assert_eq!(pricer.price().unwrap(), unbumped);
+
+        // the same bumps applied one at a time
+        let mut save = pricer.as_bumpable().new_saveable();
+        assert!(pricer.as_mut_bumpable().bump(&spot, Some(&mut *save)).unwrap());
+        assert!(pricer.as_mut_bumpable().bump(&vol, Some(&mut *save)).unwrap());
+        assert_eq!(pricer.price().unwrap(), bumped);
+        pricer.as_mut_bumpable().restore(&*save).unwrap();
+        save.clear();
+        assert_eq!(pricer.price().unwrap(), unbumped);
+        assert!(bumped != unbumped);
+    }
+
+    #[test]
+    fn bump_set_preserves_order_and_unwinds() {
+
+        // replacing then scaling the spot differs from scaling then replacing
+        let replace = Bump::new_spot("BP.L", BumpSpot::new_replace(90.0));
+        let scale = Bump::new_spot("BP.L", BumpSpot::new_relative(-0.1));
+        let mut pricer = sample_pricer();
+
+        let set = BumpSet::new().add(replace.clone()).add(scale.clone());
+        let saved = set.apply_all(pricer.as_mut_bumpable()).unwrap();
+        assert_eq!(pricer.as_bumpable().context().spot("BP.L").unwrap(), 81.0);
+        set.restore_all(pricer.as_mut_bumpable(), saved).unwrap();
+        assert_eq!(pricer.as_bumpable().context().spot("BP.L").unwrap(), 100.0);
+
+        let set = BumpSet::new().add(scale).add(replace);
+        let saved = set.apply_all(pricer.as_mut_bumpable()).unwrap();
+        assert_eq!(pricer.as_bumpable().context().spot("BP.L").unwrap(), 90.0);
+        set.restore_all(pricer.as_mut_bumpable(), saved).unwrap();
+        assert_eq!(pricer.as_bumpable().context().spot("BP.L").unwrap(), 100.0);
+    }
+}
@@ -1,4 +1,5 @@
 use risk::Bumpable;
+use risk::Saveable;
 use dates::Date;
 use dates::datetime::DateTime;
 use core::qm;
@@ -51,6 +52,24 @@ impl BumpTime {
         Ok(modified)
     }
 
+    /// Applies the bump to the model, saving its state so that it can be restored, so
+    /// long as the list of instruments would not change. If any fixings between the
+    /// old and new spot dates would change the instruments, the model would need
+    /// rebuilding, which cannot be undone by a restore. In that case, nothing is
+    /// bumped and the method returns false.
+    pub fn apply_saved(&self, instruments: &[(f64, RcInstrument)],
+        bumpable: &mut Bumpable, save: &mut Saveable) -> Result<bool, qm::Error> {
+
+        let mut fixed = instruments.to_vec();
+        if self.update_instruments(&mut fixed, bumpable.context(), bumpable.dependencies()?)? {
+            return Ok(false)
+        }
+
+        let bump = Bump::new_spot_date(self.spot_date_bump.clone());
+        bumpable.bump(&bump, Some(save))?;
+        Ok(true)
+    }
+
     /// Creates a fixing table representing any fixings between the old and new spot dates, and
     /// applies it to the instruments, modifying the vector if necessary. If any have changed,
     /// returns true.
@@ -3,8 +3,11 @@ use std::collections::HashMap;
 use std::any::Any;
 use std::ops::Deref;
 use data::volsurface::RcVolSurface;
+use data::volsurface::VolForwardDynamics;
+use data::volsurface::VolForwardDynamics::StickyStrike;
 use data::forward::Forward;
 use data::curves::RcRateCurve;
+use data::fx::FxRate;
 use data::bump::Bump;
 use dates::Date;
 use instruments::Instrument;
@@ -15,6 +18,8 @@ use risk::marketdata::SavedData;
 use risk::marketdata::copy_from_saved;
 use risk::Bumpable;
 use risk::Saveable;
+use risk::write_checkpoint;
+use risk::read_checkpoint;
 use risk::BumpablePricingContext;
 use core::qm;
 
@@ -55,6 +60,126 @@ impl PricingContextPrefetch {
         })
     }
 
+    /// Creates a context for a subset of the dependencies of this one, for
+    /// example for one of a batch of pricers sharing the same market data.
+    /// Forwards and vol surfaces that were prefetched with the same high
+    /// water marks are shared rather than fetched again. Anything else is
+    /// fetched afresh, so the result is the same as calling new with the
+    /// subset of dependencies, so long as this context has not been bumped.
+    pub fn subset(&self, dependencies: Arc<DependencyCollector>)
+        -> Result<PricingContextPrefetch, qm::Error> {
+
+        let mut forward_curves = HashMap::new();
+        let mut vol_surfaces = HashMap::new();
+        {
+            let vol_dependencies = dependencies.vol_surfaces();
+            for (rc_instrument, high_water_mark) in dependencies.forward_curves() {
+
+                // share the forward if it was fetched to the same date
+                let instrument : &Instrument = rc_instrument.deref();
+                let id = instrument.id().to_string();
+                let same_forward = self.dependencies.forward_curve_hwm(rc_instrument)
+                    == Some(*high_water_mark);
+                let forward = match self.forward_curves.get(&id) {
+                    Some(forward) if same_forward => forward.clone(),
+                    _ => self.context.forward_curve(instrument, *high_water_mark)?
+                };
+
+                // the vol surface may depend on the forward, so only share
+                // it if the forward is shared too
+                if let Some(vol_hwm) = vol_dependencies.get(rc_instrument) {
+                    let same_vol = same_forward
+                        && self.dependencies.vol_surface_hwm(rc_instrument) == Some(*vol_hwm);
+                    let vol = match self.vol_surfaces.get(&id) {
+                        Some(vol) if same_vol => vol.clone(),
+                        _ => self.context.vol_surface(instrument, *vol_hwm,
+                            &|| Ok(forward.clone()))?
+                    };
+                    vol_surfaces.insert(id.clone(), vol);
+                }
+
+                forward_curves.insert(id, forward);
+            }
+        }
+
+        Ok(PricingContextPrefetch {
+            context: self.context.clone(),
+            dependencies: dependencies,
+            forward_curves: forward_curves,
+            vol_surfaces: vol_surfaces
+        })
+    }
+
+    /// Replaces the market data with a fresh copy, reusing the dependencies
+    /// rather than collecting them again. Only the forwards and vol surfaces
+    /// whose inputs differ from the current, possibly bumped, market data
+    /// are fetched again, and any bumps are discarded. The new market data
+    /// must have the same spot date, as the dependencies depend on it, and
+    /// must supply everything the dependencies need. Otherwise this returns
+    /// an error, leaving the context unchanged.
+    pub fn refresh_market_data(&mut self, market_data: &MarketData)
+        -> Result<(), qm::Error> {
+
+        if market_data.spot_date() != self.context.spot_date() {
+            return Err(qm::Error::new(&format!("Cannot refresh the market data \
+                with spot date {}, as the dependencies were collected for {}",
+                market_data.spot_date(), self.context.spot_date())))
+        }
+
+        // yield curves and FX rates are not prefetched, so check them now
+        for (credit_id, high_water_mark) in self.dependencies.yield_curves() {
+            market_data.yield_curve(credit_id, *high_water_mark)?;
+        }
+        for id in self.dependencies.fx_rates().keys() {
+            let mut currencies = id.splitn(2, '/');
+            match (currencies.next(), currencies.next()) {
+                (Some(base), Some(quote)) => { market_data.fx_rate(base, quote)?; },
+                _ => return Err(qm::Error::new(&format!(
+                    "Badly formed FX rate dependency '{}'", id)))
+            }
+        }
+
+        // Reuse the forwards whose inputs are unchanged, and the vol surfaces
+        // that are unchanged and do not follow a changed forward
+        let diff = self.context.diff(market_data)?;
+        let mut forward_curves = HashMap::new();
+        let mut vol_surfaces = HashMap::new();
+        {
+            let vol_dependencies = self.dependencies.vol_surfaces();
+            for (rc_instrument, high_water_mark) in self.dependencies.forward_curves() {
+
+                let instrument : &Instrument = rc_instrument.deref();
+                let id = instrument.id().to_string();
+                let same_forward = !diff.dividend_model
+                    && !diff.spots.contains_key(&id)
+                    && !diff.dividends.contains_key(&id)
+                    && !diff.borrow_curves.contains_key(&id)
+                    && !diff.yield_curves.contains_key(instrument.credit_id());
+                let forward = match self.forward_curves.get(&id) {
+                    Some(forward) if same_forward => forward.clone(),
+                    _ => market_data.forward_curve(instrument, *high_water_mark)?
+                };
+
+                if let Some(vol_hwm) = vol_dependencies.get(rc_instrument) {
+                    let same_vol = same_forward && !diff.vol_surfaces.contains_key(&id);
+                    let vol = match self.vol_surfaces.get(&id) {
+                        Some(vol) if same_vol => vol.clone(),
+                        _ => market_data.vol_surface(instrument, *vol_hwm,
+                            &|| Ok(forward.clone()))?
+                    };
+                    vol_surfaces.insert(id.clone(), vol);
+                }
+
+                forward_curves.insert(id, forward);
+            }
+        }
+
+        self.context = market_data.clone();
+        self.forward_curves = forward_curves;
+        self.vol_surfaces = vol_surfaces;
+        Ok(())
+    }
+
     /// Refetch all of the cached data after some change that affects all
     /// dependencies, such as a theta bump
     pub fn refetch_all(&mut self) -> Result<(), qm::Error> {
@@ -65,11 +190,26 @@ impl PricingContextPrefetch {
             &mut self.forward_curves, &mut self.vol_surfaces)
     }
 
+    /// Refetch the forwards, leaving the vol surfaces unchanged. The forwards
+    /// are always built directly from the market data, whereas the vol
+    /// surfaces may have been modified to follow a bumped forward.
+    fn refetch_forwards(&mut self) -> Result<(), qm::Error> {
+        for (rc_instrument, high_water_mark) in self.dependencies.forward_curves() {
+            let instrument : &Instrument = rc_instrument.deref();
+            let forward = self.context.forward_curve(instrument, *high_water_mark)?;
+            self.forward_curves.insert(instrument.id().to_string(), forward);
+        }
+        Ok(())
+    }
+
     /// Refetch some of the cached data after a change that affects only the
-    /// forward or vol surface on one instrument, such as a delta bump
+    /// forward or vol surface on one instrument, such as a delta bump. If the
+    /// forward is bumped, the vol dynamics say how the vol surface should
+    /// follow it.
     pub fn refetch(&mut self, id: &str,
         bumped_forward: bool,
         bumped_vol: bool,
+        vol_dynamics: VolForwardDynamics,
         saved_forward_curves: Option<&mut HashMap<String, Arc<Forward>>>,
         saved_vol_surfaces: Option<&mut HashMap<String, RcVolSurface>>)
         -> Result<bool, qm::Error> {
@@ -90,7 +230,7 @@ impl PricingContextPrefetch {
                 // save the old forward if we are about to bump it
                 if bumped_forward {
                     if let Some(s) = saved_forward_curves {
-                        s.insert(id.to_string(), fwd.clone());
+                        s.entry(id.to_string()).or_insert_with(|| fwd.clone());
                     }
 
                     // Refetch forward: requires instrument and high water mark
@@ -102,22 +242,24 @@ impl PricingContextPrefetch {
                     }
                 }
 
-                // If we had vol surfaces such as sticky delta surfaces that
-                // needed to be updated when the forward was changed, we'd need
-                // the following test to be more complicated than just 
-                // looking at bumped_vol
+                // Vol surfaces such as sticky delta surfaces need to be
+                // updated when the forward is changed
+                let follow_forward = bumped_forward
+                    && vol_dynamics.depends_on_forward();
 
                 // save the old vol surface if we are about to bump it
-                if bumped_vol {
+                if bumped_vol || follow_forward {
                     if let Some(vol) = self.vol_surfaces.get_mut(&id_string) {
                         if let Some(s) = saved_vol_surfaces {
-                            s.insert(id_string, vol.clone());
+                            s.entry(id_string).or_insert_with(|| vol.clone());
                         }
 
                         // Refetch vol if required. If vol not found, it may
                         // not be an error if we are responding to a forward
                         // bump, but that code is not implemented yet.
-                        if let Some(vol_hwm) = 
+                        if !bumped_vol {
+                            vol_dynamics.modify(vol, &|| Ok(fwd.clone()))?;
+                        } else if let Some(vol_hwm) = 
                             self.dependencies.vol_surface_hwm(inst) {
                             *vol = self.context.vol_surface(instrument, vol_hwm,
                                 &|| Ok(fwd.clone()))?;
@@ -203,6 +345,16 @@ impl PricingContext for PricingContextPrefetch {
         -> Result<f64, qm::Error> {
         self.context.correlation(first, second)
     }
+
+    fn fx_rate(&self, base_currency: &str, quote_currency: &str)
+        -> Result<FxRate, qm::Error> {
+        self.context.fx_rate(base_currency, quote_currency)
+    }
+
+    fn fx_correlation(&self, base_currency: &str, quote_currency: &str,
+        instrument: &Instrument) -> Result<f64, qm::Error> {
+        self.context.fx_correlation(base_currency, quote_currency, instrument)
+    }
 }
 
 /// Look for market-data-derived objects in the cache. If they are not there,
@@ -231,8 +383,8 @@ impl Bumpable for PricingContextPrefetch {
         // we have to unpack the option<saveable> into options on all its
         // components all at the same time, to avoid problems with borrowing.
         let saved = to_saved(any_saved)?;
-        let (saved_data, saved_forward_curves, saved_vol_surfaces) 
-            : (Option<&mut Saveable>
+        let (mut saved_data, saved_forward_curves, saved_vol_surfaces) 
+            : (Option<&mut SavedData>
             , Option<&mut HashMap<String, Arc<Forward>>>
             , Option<&mut HashMap<String, RcVolSurface>>)
             = if let Some(s) = saved {
@@ -247,15 +399,16 @@ impl Bumpable for PricingContextPrefetch {
         let bumped = if let &Bump::SpotDate(ref bump) = bump {
             bump.spot_date() != self.spot_date()
         } else {
-            self.context.bump(bump, saved_data)?
+            self.context.bump(bump,
+                saved_data.as_mut().map(|s| &mut **s as &mut Saveable))?
         };
 
         // we may need to refetch some of the prefetched data
         match bump {
-            &Bump::Spot(ref id, _) => self.refetch(&id, bumped, false, saved_forward_curves, saved_vol_surfaces),
-            &Bump::Divs(ref id, _) => self.refetch(&id, bumped, false, saved_forward_curves, saved_vol_surfaces),
-            &Bump::Vol(ref id, _) => self.refetch(&id, false, bumped, saved_forward_curves, saved_vol_surfaces),
-            &Bump::Borrow(ref id, _) => self.refetch(&id, bumped, false, saved_forward_curves, saved_vol_surfaces),
+            &Bump::Spot(ref id, ref spot_bump) => self.refetch(&id, bumped, false, spot_bump.dynamics(), saved_forward_curves, saved_vol_surfaces),
+            &Bump::Divs(ref id, _) => self.refetch(&id, bumped, false, StickyStrike, saved_forward_curves, saved_vol_surfaces),
+            &Bump::Vol(ref id, _) => self.refetch(&id, false, bumped, StickyStrike, saved_forward_curves, saved_vol_surfaces),
+            &Bump::Borrow(ref id, _) => self.refetch(&id, bumped, false, StickyStrike, saved_forward_curves, saved_vol_surfaces),
             &Bump::Yield(ref credit_id, _) => {
                 // we have to copy these ids to avoid a tangle with borrowing
                 let v = self.dependencies
@@ -269,21 +422,32 @@ impl Bumpable for PricingContextPrefetch {
                     if let Some(svs) = saved_vol_surfaces {
                         done = true;
                         for id in v.iter() {
-                            self.refetch(&id, bumped, false, Some(sfc), Some(svs))?;
+                            self.refetch(&id, bumped, false, StickyStrike, Some(sfc), Some(svs))?;
                         }
                     }
                 }
                 
                 if !done {
                     for id in v.iter() {
-                        self.refetch(&id, bumped, false, None, None)?;
+                        self.refetch(&id, bumped, false, StickyStrike, None, None)?;
                     }
                 }
 
                 Ok(bumped) },
+            // discounting curves are not prefetched, so nothing to refetch
+            &Bump::Discount(_, _) => Ok(bumped),
+            // correlations are not prefetched either
+            &Bump::Correlation(_) => Ok(bumped),
             &Bump::SpotDate(ref bump) => {
                 if bumped {
-                    self.context.bump_spot_date(bump, &self.dependencies)?; 
+                    // everything cached is refetched, so save all of it
+                    if let Some(sfc) = saved_forward_curves {
+                        save_all_entries(&self.forward_curves, sfc);
+                    }
+                    if let Some(svs) = saved_vol_surfaces {
+                        save_all_entries(&self.vol_surfaces, svs);
+                    }
+                    self.context.bump_spot_date(bump, &self.dependencies, saved_data)?;
                     self.refetch_all()?;
                 } 
                 Ok(bumped) }
@@ -319,6 +483,23 @@ impl Bumpable for PricingContextPrefetch {
             Err(qm::Error::new("Mismatching save space for restore"))
         }
     }
+
+    /// The forwards cannot be serialized, so they are refetched from the
+    /// restored market data. Vol surfaces that have followed a forward bump
+    /// with sticky delta dynamics contain the bumped forward, so cannot be
+    /// restored from a checkpoint.
+    fn checkpoint(&self) -> Result<Vec<u8>, qm::Error> {
+        write_checkpoint(self.spot_date(), &SavedPrefetch {
+            saved_data: self.context.save_all(),
+            forward_curves: HashMap::new(),
+            vol_surfaces: self.vol_surfaces.clone() })
+    }
+
+    fn restore_checkpoint(&mut self, checkpoint: &[u8]) -> Result<(), qm::Error> {
+        let saved: SavedPrefetch = read_checkpoint(self.spot_date(), checkpoint)?;
+        self.restore(&saved)?;
+        self.refetch_forwards()
+    }
 }
 
 impl BumpablePricingContext for PricingContextPrefetch {
@@ -326,6 +507,21 @@ impl BumpablePricingContext for PricingContextPrefetch {
     fn as_mut_bumpable(&mut self) -> &mut Bumpable { self }
     fn as_pricing_context(&self) -> &PricingContext { self }
     fn raw_market_data(&self) -> &MarketData { &self.context }
+
+    fn refresh_market_data(&mut self, market_data: &MarketData)
+        -> Result<(), qm::Error> {
+        PricingContextPrefetch::refresh_market_data(self, market_data)
+    }
+}
+
+/// Saves every entry of a cache, except any already saved by an earlier
+/// bump, so that a restore goes back to the state before all the bumps
+fn save_all_entries<T: Clone>(cache: &HashMap<String, T>,
+    save: &mut HashMap<String, T>) {
+
+    for (key, value) in cache.iter() {
+        save.entry(key.to_string()).or_insert_with(|| value.clone());
+    }
 }
 
 fn to_saved(opt_any_saved: Option<&mut Saveable>) 
@@ -343,9 +539,12 @@ fn to_saved(opt_any_saved: Option<&mut Saveable>)
 }
 
 /// Data structure for saving the prefetched content before a bump, so it
-/// can be restored later on.
+/// can be restored later on. The forwards are not serialized, as they are
+/// derived from the market data.
+#[derive(Serialize, Deserialize)]
 pub struct SavedPrefetch {
     saved_data: SavedData,
+    #[serde(skip)]
     forward_curves: HashMap<String, Arc<Forward>>,
     vol_surfaces: HashMap<String, RcVolSurface>
 }
@@ -387,11 +586,23 @@ pub mod tests {
     use risk::marketdata::tests::sample_market_data;
     use risk::marketdata::tests::sample_european;
     use data::bumpspot::BumpSpot;
+    use data::bumpspot::VolDynamics;
     use data::bumpdivs::BumpDivs;
     use data::bumpvol::BumpVol;
     use data::bumpyield::BumpYield;
+    use data::volsurface::VolByProbabilityCubicSplineSmile;
+    use data::volsurface::DivAssumptions;
+    use data::volsmile::CubicSplineSmile;
+    use instruments::assets::RcCurrency;
     use dates::datetime::DateTime;
     use dates::datetime::TimeOfDay;
+    use dates::datetime::DateDayFraction;
+    use dates::calendar::WeekdayCalendar;
+    use dates::calendar::RcCalendar;
+    use math::interpolation::Linear;
+    use math::interpolation::Extrap;
+    use risk::marketdata::tests::{sample_currency, sample_equity,
+        create_sample_rate, create_sample_borrow, create_sample_divstream};
     use core::factories::Qrc;
 
     pub fn create_dependencies(instrument: &RcInstrument, spot_date: Date)
@@ -491,6 +702,109 @@ pub mod tests {
         assert_approx(price, unbumped_price, 1e-12);
     }
 
+    /// Sample market data as above, but with a skewed vol surface on BP.L,
+    /// calibrated to today's forward.
+    fn skewed_market_data() -> MarketData {
+
+        let market_data = sample_market_data();
+        let spot_date = market_data.spot_date();
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        let equity = sample_equity(currency, 2);
+        let forward = market_data.forward_curve(&equity, spot_date + 1500).unwrap();
+
+        let d = spot_date;
+        let dates = [d, d + 30, d + 90, d + 180, d + 365, d + 730, d + 1460];
+        let points: Vec<(Date, f64)> = dates.iter()
+            .map(|&date| (date, forward.forward(date).unwrap())).collect();
+        let fwd = Linear::new(&points, Extrap::Natural, Extrap::Natural).unwrap();
+        let divs = Linear::new(&[(d, 0.0)], Extrap::Flat, Extrap::Flat).unwrap();
+
+        // downward sloping skew, with vols quoted by absolute strike
+        let mut smiles = Vec::new();
+        for &(expiry, scale) in [(d + 182, 1.0), (d + 517, 0.9), (d + 900, 0.85)].iter() {
+            let points = [(60.0, 0.45 * scale), (80.0, 0.37 * scale),
+                (100.0, 0.3 * scale), (120.0, 0.26 * scale), (140.0, 0.24 * scale)];
+            smiles.push((DateDayFraction::new(expiry, 0.7),
+                CubicSplineSmile::new(&points).unwrap()));
+        }
+
+        let calendar = RcCalendar::new(Arc::new(WeekdayCalendar()));
+        let base = DateDayFraction::new(spot_date, 0.0);
+        let surface = VolByProbabilityCubicSplineSmile::new(&smiles, calendar,
+            base, fwd, divs, DivAssumptions::NoCashDivs).unwrap();
+
+        let mut spots = HashMap::new();
+        spots.insert("BP.L".to_string(), 100.0);
+        let mut yield_curves = HashMap::new();
+        yield_curves.insert("OPT".to_string(), create_sample_rate());
+        yield_curves.insert("LSE".to_string(), create_sample_rate());
+        let mut borrow_curves = HashMap::new();
+        borrow_curves.insert("BP.L".to_string(), create_sample_borrow());
+        let mut dividends = HashMap::new();
+        dividends.insert("BP.L".to_string(), create_sample_divstream());
+        let mut vol_surfaces = HashMap::new();
+        vol_surfaces.insert("BP.L".to_string(), RcVolSurface::new(Arc::new(surface)));
+
+        MarketData::new(spot_date, spots, yield_curves, borrow_curves,
+            dividends, vol_surfaces)
+    }
+
+    fn central_delta(market_data: &MarketData, dynamics: Option<VolDynamics>)
+        -> f64 {
+
+        let european = sample_european();
+        let val_date = DateTime::new(market_data.spot_date(), TimeOfDay::Open);
+        let instrument = RcInstrument::new(Qrc::new(european.clone()));
+        let dependencies = create_dependencies(&instrument, market_data.spot_date());
+        let mut mut_data = PricingContextPrefetch::new(market_data,
+            dependencies).unwrap();
+        let mut save = SavedPrefetch::new();
+        let unbumped = european.price(&mut_data, val_date).unwrap();
+
+        let bumpsize = 0.01;
+        let mut prices = [0.0; 2];
+        for (price, size) in prices.iter_mut().zip([bumpsize, -bumpsize].iter()) {
+            let bump_spot = match dynamics {
+                None => BumpSpot::new_relative(*size),
+                Some(d) => BumpSpot::new_relative_with_dynamics(*size, d)
+            };
+            let bump = Bump::new_spot("BP.L", bump_spot);
+            assert!(mut_data.bump(&bump, Some(&mut save)).unwrap());
+            *price = european.price(&mut_data, val_date).unwrap();
+            mut_data.restore(&save).unwrap();
+            save.clear();
+
+            // restoring must also restore any re-anchored vol surface
+            let price = european.price(&mut_data, val_date).unwrap();
+            assert_approx(price, unbumped, 1e-12);
+        }
+
+        (prices[0] - prices[1]) / (2.0 * bumpsize * 100.0)
+    }
+
+    #[test]
+    fn spot_bump_vol_dynamics() {
+
+        // sticky strike is what a spot bump has always done
+        let skewed = skewed_market_data();
+        let default_delta = central_delta(&skewed, None);
+        let sticky_strike = central_delta(&skewed, Some(VolDynamics::StickyStrike));
+        assert_eq!(sticky_strike, default_delta);
+
+        // With a downward sloping skew, moving the smile up with the spot
+        // raises the vol at the strike when spot rises, so sticky delta
+        // gives a materially larger call delta.
+        let sticky_delta = central_delta(&skewed, Some(VolDynamics::StickyDelta));
+        assert!(sticky_delta > sticky_strike + 0.05,
+            "sticky_delta={} sticky_strike={}", sticky_delta, sticky_strike);
+
+        // a flat surface has no forward, so the dynamics make no difference
+        let flat = sample_market_data();
+        let flat_strike = central_delta(&flat, Some(VolDynamics::StickyStrike));
+        let flat_delta = central_delta(&flat, Some(VolDynamics::StickyDelta));
+        assert_eq!(flat_strike, flat_delta);
+    }
+
     fn assert_approx(value: f64, expected: f64, tolerance: f64) {
         assert!(approx_eq(value, expected, tolerance),
             "value={} expected={}", value, expected);
@@ -0,0 +1,233 @@
+use std::collections::HashMap;
+use std::any::Any;
+use std::sync::Arc;
+use std::ops::Deref;
+use std::fmt;
+use math::numerics::{ApproxEq, approx_eq};
+use instruments::RcInstrument;
+use risk::Report;
+use risk::BoxReport;
+use risk::ReportGenerator;
+use risk::Pricer;
+use risk::Saveable;
+use risk::restore_and_verify;
+use risk::ApproxEqReport;
+use risk::ReportTolerances;
+use data::bump::Bump;
+use data::bumpcorrelation::BumpCorrelation;
+use core::qm;
+use core::factories::TypeId;
+use core::factories::{Qrc, Qbox};
+use serde::Deserialize;
+use erased_serde as esd;
+
+/// Cega is the first derivative of price with respect to the correlation
+/// between two underlyings. This report shows the cega with respect to each
+/// pair of underlyings that affect the price and have a correlation in the
+/// market data. The results are keyed by the id of the first of the pair in
+/// alphabetical order, then by the second.
+///
+/// Where a shift had to be reduced, to keep the correlation within [-1, 1]
+/// or the matrix positive semi-definite, the cega is calculated from the
+/// shifts actually applied, and the report contains a warning.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CegaReport {
+    bumpsize: f64,
+    results: HashMap<String, HashMap<String, f64>>,
+    warnings: Vec<String>
+}
+
+impl Report for CegaReport {
+    fn as_any(&self) -> &Any { self }
+}
+
+impl TypeId for CegaReport {
+    fn get_type_id(&self) -> &'static str { "CegaReport" }
+}
+
+impl CegaReport {
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qbox<Report>, esd::Error> {
+        Ok(Qbox::new(Box::new(CegaReport::deserialize(de)?)))
+    }
+
+    pub fn results(&self) -> &HashMap<String, HashMap<String, f64>> { &self.results }
+    pub fn warnings(&self) -> &[String] { &self.warnings }
+
+    /// The cega with respect to the correlation between two underlyings,
+    /// given in either order, if it is in the report
+    pub fn cega(&self, first: &str, second: &str) -> Option<f64> {
+        let (first, second) = ordered(first, second);
+        self.results.get(first).and_then(|row| row.get(second)).cloned()
+    }
+
+    fn count(&self) -> usize {
+        self.results.values().map(|row| row.len()).sum()
+    }
+}
+
+impl<'v> ApproxEq<ReportTolerances, &'v CegaReport> for &'v CegaReport {
+    fn validate(self, other: &'v CegaReport, tol: &ReportTolerances,
+        _msg: &str, diffs: &mut fmt::Formatter) -> fmt::Result {
+
+        if self.count() != other.count() {
+            write!(diffs, "CegaReport: number of reports {} != {}", self.count(), other.count())?;
+        }
+
+        // Cega is based on diffs, so should use the currency risk tolerance.
+        let tolerance = tol.currency_risk() / self.bumpsize;
+        for (first, row) in &self.results {
+            for (second, cega) in row {
+                if let Some(other_cega) = other.cega(first, second) {
+                    if !approx_eq(*cega, other_cega, tolerance) {
+                        writeln!(diffs, "CegaReport: {}/{} cega {} != {} tol={}",
+                            first, second, cega, other_cega, tolerance)?;
+                    }
+                } else {
+                    write!(diffs, "CegaReport: {}/{} is missing", first, second)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl ApproxEqReport for CegaReport {
+    fn validate_report(&self, other: &Report, tol: &ReportTolerances,
+        msg: &str, diffs: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(other_report) = other.as_any().downcast_ref::<CegaReport>() {
+            self.validate(other_report, tol, msg, diffs)
+        } else {
+            write!(diffs, "CegaReport: mismatching report {} != {}", self.get_type_id(), other.get_type_id())?;
+            Ok(())
+        }
+    }
+}
+
+/// Calculator for cega by bumping. The bump size is specified as an
+/// absolute shift in correlation, and is applied up and down to give a
+/// central difference. Each bump is applied to a single pair of underlyings,
+/// and any model that generates correlated paths regenerates them.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CegaReportGenerator {
+    bumpsize: f64
+}
+
+impl CegaReportGenerator {
+    pub fn new(bumpsize: f64) -> CegaReportGenerator {
+        CegaReportGenerator { bumpsize: bumpsize }
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<ReportGenerator>, esd::Error> {
+        Ok(Qrc::new(Arc::new(CegaReportGenerator::deserialize(de)?)))
+    }
+}
+
+impl TypeId for CegaReportGenerator {
+    fn get_type_id(&self) -> &'static str { "CegaReportGenerator" }
+}
+
+impl ReportGenerator for CegaReportGenerator {
+    fn generate(&self, pricer: &mut Pricer, saveable: &mut Saveable, unbumped: f64)
+        -> Result<BoxReport, qm::Error> {
+
+        // Find the underlyings we should have cega to, in order of id. Note
+        // that we need to clone them, to avoid borrowing problems.
+        let mut underlyings: Vec<RcInstrument> = pricer.as_bumpable()
+            .dependencies()?.forward_curves().keys().cloned().collect();
+        underlyings.sort_by(|a, b| a.id().cmp(b.id()));
+
+        let mut results = HashMap::new();
+        let mut warnings = Vec::new();
+        for (i, first) in underlyings.iter().enumerate() {
+            for second in underlyings.iter().skip(i + 1) {
+
+                // pairs with no correlation cannot affect the price
+                let correlation = match fetch_correlation(pricer, first, second) {
+                    Ok(correlation) => correlation,
+                    Err(_) => continue
+                };
+
+                // Bump up and reprice, then down and reprice, noting the
+                // correlation actually achieved each time
+                let mut shifted = [(0.0, 0.0); 2];
+                let mut applied = true;
+                for (result, size) in shifted.iter_mut()
+                    .zip([self.bumpsize, -self.bumpsize].iter()) {
+
+                    let bump = Bump::new_correlation(BumpCorrelation::new_pairwise(
+                        first.id(), second.id(), *size));
+                    applied = pricer.as_mut_bumpable().bump(&bump, Some(&mut *saveable))?;
+                    if applied {
+                        *result = (fetch_correlation(pricer, first, second)?, pricer.price()?);
+                    }
+                    restore_and_verify(pricer, saveable, unbumped)?;
+                    saveable.clear();
+                    if !applied {
+                        break
+                    }
+                }
+                if !applied {
+                    continue
+                }
+
+                let (up_correlation, upbumped) = shifted[0];
+                let (down_correlation, downbumped) = shifted[1];
+                let shift = up_correlation - down_correlation;
+                if shift == 0.0 {
+                    warnings.push(format!("Correlation {} between '{}' and '{}' \
+                        cannot be shifted, so has no cega", correlation,
+                        first.id(), second.id()));
+                    continue
+                }
+                if !approx_eq(shift, 2.0 * self.bumpsize, 1e-12) {
+                    warnings.push(format!("Correlation {} between '{}' and '{}' \
+                        could only be shifted to {} and {}, to keep it within \
+                        [-1, 1] and the matrix positive semi-definite",
+                        correlation, first.id(), second.id(), up_correlation,
+                        down_correlation));
+                }
+
+                let cega = (upbumped - downbumped) / shift;
+                results.entry(first.id().to_string()).or_insert_with(HashMap::new)
+                    .insert(second.id().to_string(), cega);
+            }
+        }
+
+        Ok(Qbox::new(Box::new(CegaReport { bumpsize: self.bumpsize,
+            results: results, warnings: warnings })))
+    }
+}
+
+/// The correlation between two underlyings in the pricer's current context
+fn fetch_correlation(pricer: &Pricer, first: &RcInstrument, second: &RcInstrument)
+    -> Result<f64, qm::Error> {
+    pricer.as_bumpable().context().correlation(first.deref(), second.deref())
+}
+
+fn ordered<'a>(first: &'a str, second: &'a str) -> (&'a str, &'a str) {
+    if first <= second { (first, second) } else { (second, first) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use risk::RcReportGenerator;
+    use core::factories::tests::assert_debug_eq;
+    use serde_json;
+
+    #[test]
+    fn serde_cega_generator_roundtrip() {
+
+        // create some sample data
+        let generator = RcReportGenerator::new(Arc::new(CegaReportGenerator::new(0.01)));
+
+        // round trip it via JSON
+        let serialized = serde_json::to_string_pretty(&generator).unwrap();
+        print!("serialized: {}\n", serialized);
+        let deserialized: RcReportGenerator = serde_json::from_str(&serialized).unwrap();
+
+        // check that they match, at least in debug representation
+        assert_debug_eq(&generator, &deserialized);
+    }
+}
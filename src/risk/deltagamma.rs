@@ -8,7 +8,7 @@ use risk::BoxReport;
 use risk::ReportGenerator;
 use risk::Pricer;
 use risk::Saveable;
-use risk::bumped_price;
+use risk::bumpcache::{BumpedPriceCache, cached_bump_sequence};
 use risk::ApproxEqReport;
 use risk::ReportTolerances;
 use data::bump::Bump;
@@ -137,6 +137,11 @@ impl TypeId for DeltaGammaReportGenerator {
 impl ReportGenerator for DeltaGammaReportGenerator {
     fn generate(&self, pricer: &mut Pricer, saveable: &mut Saveable, unbumped: f64)
         -> Result<BoxReport, qm::Error> {
+        self.generate_cached(pricer, saveable, unbumped, &mut BumpedPriceCache::new())
+    }
+
+    fn generate_cached(&self, pricer: &mut Pricer, saveable: &mut Saveable,
+        unbumped: f64, cache: &mut BumpedPriceCache) -> Result<BoxReport, qm::Error> {
 
         // We first bump up by 1 + bumpsize, then down by (1 - bumpsize) / (1 + bumpsize)
         // so we cancel out the original up bump. This saves time compared
@@ -153,16 +158,11 @@ impl ReportGenerator for DeltaGammaReportGenerator {
 
             let spot = pricer.as_bumpable().context().spot(id)?;
 
-            // bump up and reprice
-            let bump = Bump::new_spot(id, up.clone());
-            let upbumped = bumped_price(&bump, pricer, Some(saveable), unbumped)?;
-            
-            // bump down and reprice (do not save the result from this)
-            let bump = Bump::new_spot(id, down.clone());
-            let downbumped = bumped_price(&bump, pricer, None, unbumped)?;
-
-            pricer.as_mut_bumpable().restore(saveable)?;
-            saveable.clear();
+            // bump up and reprice, then bump down and reprice, without
+            // restoring in between
+            let bumps = [Bump::new_spot(id, up.clone()), Bump::new_spot(id, down.clone())];
+            let prices = cached_bump_sequence(pricer, saveable, &bumps, unbumped, cache)?;
+            let (upbumped, downbumped) = (prices[0], prices[1]);
 
             // delta and gamma calculations
             let bumpsize = self.bumpsize * spot;
@@ -185,6 +185,7 @@ pub mod tests {
     use risk::PricerClone;
     use risk::TimeBumpable;
     use risk::bumptime::BumpTime;
+    use risk::pricereport::PriceReport;
     use risk::dependencies::DependencyCollector;
     use risk::cache::PricingContextPrefetch;
     use instruments::PricingContext;
@@ -227,11 +228,14 @@ pub mod tests {
         fn as_mut_bumpable(&mut self) -> &mut Bumpable { self }
         fn as_mut_time_bumpable(&mut self) -> &mut TimeBumpable { self }
 
-        fn price(&self) -> Result<f64, qm::Error> {
+        fn price_report(&self) -> Result<PriceReport, qm::Error> {
             assert!(self.instruments.len() == 1);
             let instrument = self.instruments[0].1.clone();
             let val_date = DateTime::new(self.context.spot_date(), TimeOfDay::Open);
-            instrument.as_priceable().unwrap().price(&self.context, val_date)
+            let mut report = PriceReport::new();
+            report.add(instrument.id(),
+                instrument.as_priceable().unwrap().price(&self.context, val_date)?);
+            Ok(report)
         }
     }
 
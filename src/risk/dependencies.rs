@@ -4,8 +4,25 @@ use dates::Date;
 use dates::datetime::DateTime;
 use instruments::RcInstrument;
 use instruments::SpotRequirement;
+use data::fx::fx_id;
 use std::collections::HashSet;
 use std::collections::HashMap;
+use std::collections::BTreeSet;
+
+/// An item of market data needed by the instruments whose dependencies were
+/// collected. Apart from spots, each item is needed over a range of dates,
+/// from the spot date to a high water mark. Forwards are broken down into
+/// the dividends and borrow they are built from, plus the spot and the
+/// yield curve of the underlying, which are listed separately.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum MarketDataRequirement {
+    Spot { id: String },
+    YieldCurve { credit_id: String, from: Date, to: Date },
+    Dividends { id: String, from: Date, to: Date },
+    Borrow { id: String, from: Date, to: Date },
+    VolSurface { id: String, from: Date, to: Date },
+    FxRate { id: String, from: Date, to: Date }
+}
 
 /// Collect the dependencies of an instrument
 pub struct DependencyCollector {
@@ -17,6 +34,8 @@ pub struct DependencyCollector {
     instruments: HashMap<String, RcInstrument>,
     forward_id_from_credit_id: HashMap<String, Vec<String>>,
     fixings: HashMap<String, Vec<DateTime>>,
+    fx_rates: HashMap<String, Date>,
+    value_dates: HashMap<String, Date>,
     empty: Vec<String>,
     empty_fixings: Vec<DateTime>
 }
@@ -32,6 +51,8 @@ impl DependencyCollector {
             instruments: HashMap::new(),
             forward_id_from_credit_id: HashMap::new(),
             fixings: HashMap::new(),
+            fx_rates: HashMap::new(),
+            value_dates: HashMap::new(),
             empty: Vec::<String>::new(),
             empty_fixings: Vec::<DateTime>::new()
         }
@@ -56,6 +77,27 @@ impl DependencyCollector {
         get_hwm(&self.vol_surfaces, instrument)
     }
 
+    pub fn fx_rate_hwm(&self, base_currency: &str, quote_currency: &str)
+        -> Option<Date> {
+        get_hwm_by_str(&self.fx_rates, &fx_id(base_currency, quote_currency))
+    }
+
+    /// The date that prices discounted on the given credit id are valued
+    /// at, which is the settlement date of the spot date for the first
+    /// instrument found with that credit id. Top-level instruments are seen
+    /// before any they depend on, so it is normally their settlement.
+    pub fn value_date(&self, credit_id: &str) -> Option<Date> {
+        self.value_dates.get(credit_id).cloned()
+    }
+
+    pub fn fx_rates(&self) -> &HashMap<String, Date> {
+        &self.fx_rates
+    }
+
+    pub fn yield_curves(&self) -> &HashMap<String, Date> {
+        &self.yield_curves
+    }
+
     pub fn forward_curves(&self) -> &HashMap<RcInstrument, Date> {
         &self.forward_curves
     }
@@ -89,11 +131,53 @@ impl DependencyCollector {
         }
     }
 
+    /// The date of the last fixing any instrument depends on, which is
+    /// normally the expiry of the longest-dated instrument.
+    pub fn last_fixing_date(&self) -> Option<Date> {
+        self.fixings.values()
+            .filter_map(|fixings| fixings.iter().map(|f| f.date()).max())
+            .max()
+    }
+
     fn add_instrument(&mut self, instrument: &RcInstrument) {
         self.instruments.insert(
             instrument.id().to_string(), instrument.clone());
     }
 
+    /// Lists all the market data that was asked for, in a stable order, with
+    /// each item appearing once. This is useful for finding out why an
+    /// instrument needs a particular item, or for assembling the minimal
+    /// market data to price it. Fixings are not included. See fixings.
+    pub fn requirements(&self) -> Vec<MarketDataRequirement> {
+        let from = self.spot_date;
+        let mut requirements = BTreeSet::new();
+        for instrument in self.spots.iter() {
+            requirements.insert(MarketDataRequirement::Spot {
+                id: instrument.id().to_string() });
+        }
+        for (credit_id, &to) in self.yield_curves.iter() {
+            requirements.insert(MarketDataRequirement::YieldCurve {
+                credit_id: credit_id.to_string(), from: from, to: to });
+        }
+        for (instrument, &to) in self.forward_curves.iter() {
+            let id = instrument.id().to_string();
+            requirements.insert(MarketDataRequirement::Spot { id: id.clone() });
+            requirements.insert(MarketDataRequirement::Dividends {
+                id: id.clone(), from: from, to: to });
+            requirements.insert(MarketDataRequirement::Borrow {
+                id: id, from: from, to: to });
+        }
+        for (instrument, &to) in self.vol_surfaces.iter() {
+            requirements.insert(MarketDataRequirement::VolSurface {
+                id: instrument.id().to_string(), from: from, to: to });
+        }
+        for (id, &to) in self.fx_rates.iter() {
+            requirements.insert(MarketDataRequirement::FxRate {
+                id: id.to_string(), from: from, to: to });
+        }
+        requirements.into_iter().collect()
+    }
+
     pub fn instruments_clone(&self) -> Vec<String> {
         // this rather unpleasant syntax forces the ids to be owned
         // by the resulting vector rather than the original hashmap
@@ -128,6 +212,12 @@ impl DependencyContext for DependencyCollector {
 
     fn spot(&mut self, instrument: &RcInstrument) {
 
+        // note the value date before recursing, so that the outermost
+        // instrument with each credit id takes precedence
+        let spot_date = self.spot_date;
+        self.value_dates.entry(instrument.credit_id().to_string())
+            .or_insert_with(|| instrument.settlement().apply(spot_date));
+
         // recurse into this instrument
         let spot_requirement = instrument.dependencies(self);
 
@@ -169,6 +259,12 @@ impl DependencyContext for DependencyCollector {
             .push(date)
     }
 
+    fn fx_rate(&mut self, base_currency: &str, quote_currency: &str,
+        high_water_mark: Date) {
+        set_hwm_by_str(&fx_id(base_currency, quote_currency), high_water_mark,
+            &mut self.fx_rates);
+    }
+
 }
 
 pub fn set_hwm_by_str(id: &str, high_water_mark: Date,
@@ -209,6 +305,7 @@ mod tests {
     use instruments::options::OptionSettlement;
     use dates::rules::RcDateRule;
     use core::factories::Qrc;
+    use risk::marketdata::tests::sample_european;
     use std::sync::Arc;
 
     fn sample_currency(step: u32) -> Currency {
@@ -264,4 +361,34 @@ mod tests {
         assert_eq!(c.yield_curve_hwm("OPT"), Some(d+212));
         assert_eq!(c.yield_curve_hwm("LSE"), Some(d+210));
     }
+
+    #[test]
+    fn sample_european_requirements() {
+
+        // The European needs its own discount curve to the payment date, and
+        // the forward and vol of BP.L to expiry. Its forward needs the spot,
+        // dividends and borrow of BP.L, and the LSE yield curve.
+        let european = RcInstrument::new(Qrc::new(sample_european()));
+        let d = Date::from_ymd(2017, 01, 02);
+        let expiry = Date::from_ymd(2018, 06, 01);
+        let mut c = DependencyCollector::new(d);
+        c.spot(&european);
+
+        assert_eq!(c.requirements(), vec![
+            MarketDataRequirement::Spot { id: "BP.L".to_string() },
+            MarketDataRequirement::YieldCurve { credit_id: "LSE".to_string(),
+                from: d, to: expiry },
+            MarketDataRequirement::YieldCurve { credit_id: "OPT".to_string(),
+                from: d, to: expiry + 4 },
+            MarketDataRequirement::Dividends { id: "BP.L".to_string(),
+                from: d, to: expiry },
+            MarketDataRequirement::Borrow { id: "BP.L".to_string(),
+                from: d, to: expiry },
+            MarketDataRequirement::VolSurface { id: "BP.L".to_string(),
+                from: d, to: expiry }]);
+
+        // collecting again adds nothing
+        c.spot(&european);
+        assert_eq!(c.requirements().len(), 6);
+    }
 }
@@ -4,26 +4,35 @@ use std::any::Any;
 use std::ops::Deref;
 use core::qm;
 use dates::Date;
+use dates::datetime::DateDayFraction;
 use data::curves::RcRateCurve;
 use data::divstream::RcDividendStream;
 use data::volsurface::RcVolSurface;
+use data::correlation::CorrelationMatrix;
+use data::fx::FxRate;
+use data::fx::fx_id;
 use data::forward::Forward;
 use data::forward::EquityForward;
+use data::forward::DividendModel;
 use data::bump::Bump;
 use data::bumpspot::BumpSpot;
 use data::bumpdivs::BumpDivs;
 use data::bumpyield::BumpYield;
 use data::bumpvol::BumpVol;
 use data::bumpspotdate::BumpSpotDate;
+use data::bumpcorrelation::BumpCorrelation;
 use data::bumpspotdate::SpotDynamics;
 use data::bump::Bumper;
 use instruments::Instrument;
 use instruments::PricingContext;
 use risk::Bumpable;
 use risk::Saveable;
+use risk::write_checkpoint;
+use risk::read_checkpoint;
 use risk::BumpablePricingContext;
 use risk::dependencies::DependencyCollector;
 use serde as sd;
+use serde_json;
 
 /// The market data struct contains all the market data supplied for a
 /// valuation. It has methods for building the analytics needed for valuation
@@ -44,7 +53,19 @@ pub struct MarketData {
     yield_curves: HashMap<String, RcRateCurve>,
     borrow_curves: HashMap<String, RcRateCurve>,
     dividends: HashMap<String, RcDividendStream>,
-    vol_surfaces: HashMap<String, RcVolSurface>
+    vol_surfaces: HashMap<String, RcVolSurface>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    correlations: Option<CorrelationMatrix>,
+    #[serde(default, skip_serializing_if = "DividendModel::is_discrete")]
+    dividend_model: DividendModel,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    fx_rates: HashMap<String, FxRate>,
+
+    // Yield curves that override the ones above, but only when used for
+    // discounting. These are created by discount bumps, and are not part of
+    // the supplied market data.
+    #[serde(skip)]
+    discount_curves: HashMap<String, RcRateCurve>
 }
 
 impl MarketData {
@@ -80,24 +101,240 @@ impl MarketData {
             yield_curves: yield_curves,
             borrow_curves: borrow_curves,
             dividends: dividends,
-            vol_surfaces: vol_surfaces }
+            vol_surfaces: vol_surfaces,
+            correlations: None,
+            dividend_model: DividendModel::Discrete,
+            fx_rates: HashMap::new(),
+            discount_curves: HashMap::new() }
     }
 
-    /// Bumps the spot date, for example during a Theta calculation
-    pub fn bump_spot_date(&mut self, bump: &BumpSpotDate, dependencies: &DependencyCollector)
-        -> Result<(), qm::Error> {
+    /// Supplies the correlations between assets, needed for valuing
+    /// instruments with more than one underlying.
+    pub fn set_correlations(&mut self, correlations: CorrelationMatrix) {
+        self.correlations = Some(correlations);
+    }
+
+    /// Chooses whether equity forwards model the dividends as discrete
+    /// payments, the default, or as an equivalent continuous yield.
+    pub fn set_dividend_model(&mut self, dividend_model: DividendModel) {
+        self.dividend_model = dividend_model;
+    }
+
+    pub fn dividend_model(&self) -> DividendModel { self.dividend_model }
+
+    /// Supplies the FX rate between two currencies, needed for valuing
+    /// instruments that pay in a different currency from their underlying.
+    /// The rate is keyed by the pair of currencies, and can be used either
+    /// way round. Correlations between the rate and assets are looked up in
+    /// the correlation matrix using the same key, as given by fx_id.
+    pub fn set_fx_rate(&mut self, base_currency: &str, quote_currency: &str,
+        rate: FxRate) {
+        self.fx_rates.insert(fx_id(base_currency, quote_currency), rate);
+    }
+
+    /// Reads market data from JSON, in the format written by to_json. All
+    /// the market data keyed by asset, such as dividends, borrow curves and
+    /// vol surfaces, must be for an asset that has a spot. Yield curves are
+    /// keyed by credit id, so are not checked.
+    pub fn from_json(json: &str) -> Result<MarketData, qm::Error> {
+        let market_data: MarketData = serde_json::from_str(json)?;
+        market_data.validate_asset_ids()?;
+        if let Some(ref correlations) = market_data.correlations {
+            correlations.validate()?;
+        }
+        Ok(market_data)
+    }
+
+    /// Writes market data as JSON. Dates and curve pillars are preserved
+    /// exactly by a round trip via from_json.
+    pub fn to_json(&self) -> Result<String, qm::Error> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    /// Describes how the given market data differs from this, taking this
+    /// as the old state and the other as the new. Items of data keyed by an
+    /// id that is only in one of them are reported as added or removed.
+    /// Items in both are compared by value, and reported with the size of
+    /// any change. See MarketDataDiff for how the size is measured.
+    pub fn diff(&self, other: &MarketData) -> Result<MarketDataDiff, qm::Error> {
+
+        let spot_date = if self.spot_date == other.spot_date {
+            None
+        } else {
+            Some((self.spot_date, other.spot_date))
+        };
+
+        let probes: Vec<Date> = DIFF_PROBE_DAYS.iter()
+            .map(|days| self.spot_date + *days).collect();
+        let curve_change = |_: &str, old: &RcRateCurve, new: &RcRateCurve|
+            -> Result<Option<f64>, qm::Error> {
+            if same_serialized(old, new)? {
+                return Ok(None)
+            }
+            let mut change: f64 = 0.0;
+            for date in probes.iter() {
+                let (old_rate, _) = old.r_and_t(*date)?;
+                let (new_rate, _) = new.r_and_t(*date)?;
+                change = change.max((new_rate - old_rate).abs());
+            }
+            Ok(Some(change))
+        };
+
+        Ok(MarketDataDiff {
+            spot_date: spot_date,
+            spots: diff_maps(&self.spots, &other.spots,
+                |_, old, new| Ok(if old == new { None } else { Some(new - old) }))?,
+            yield_curves: diff_maps(&self.yield_curves, &other.yield_curves,
+                &curve_change)?,
+            discount_curves: diff_maps(&self.discount_curves, &other.discount_curves,
+                &curve_change)?,
+            borrow_curves: diff_maps(&self.borrow_curves, &other.borrow_curves,
+                &curve_change)?,
+            dividends: diff_maps(&self.dividends, &other.dividends,
+                |_, old, new| {
+                    if same_serialized(old, new)? {
+                        return Ok(None)
+                    }
+                    let cash = |divs: &RcDividendStream| -> f64 {
+                        divs.dividends().iter().map(|d| d.cash()).sum() };
+                    Ok(Some(cash(new) - cash(old)))
+                })?,
+            vol_surfaces: diff_maps(&self.vol_surfaces, &other.vol_surfaces,
+                |id, old, new| {
+                    if same_serialized(old, new)? {
+                        return Ok(None)
+                    }
+                    // compare the vols struck at the old spot, if we have one
+                    let strike = [*self.spots.get(id).unwrap_or(&1.0)];
+                    let mut change: f64 = 0.0;
+                    for date in probes.iter() {
+                        let date_time = DateDayFraction::new(*date, 0.0);
+                        let mut old_vol = [0.0];
+                        let mut new_vol = [0.0];
+                        old.volatilities(date_time, &strike, &mut old_vol)?;
+                        new.volatilities(date_time, &strike, &mut new_vol)?;
+                        change = change.max((new_vol[0] - old_vol[0]).abs());
+                    }
+                    Ok(Some(change))
+                })?,
+            fx_rates: diff_maps(&self.fx_rates, &other.fx_rates,
+                |_, old, new| Ok(if old == new { None }
+                    else { Some(new.spot() - old.spot()) }))?,
+            correlations: !same_serialized(&self.correlations, &other.correlations)?,
+            dividend_model: self.dividend_model != other.dividend_model })
+    }
+
+    fn validate_asset_ids(&self) -> Result<(), qm::Error> {
+        let mut unknown = Vec::new();
+        unknown_ids("Dividends", &self.dividends, &self.spots, &mut unknown);
+        unknown_ids("Borrow curve", &self.borrow_curves, &self.spots, &mut unknown);
+        unknown_ids("Vol surface", &self.vol_surfaces, &self.spots, &mut unknown);
+        if unknown.is_empty() {
+            Ok(())
+        } else {
+            unknown.sort();
+            Err(qm::Error::new(&format!(
+                "Market data for unknown assets (no spot supplied): {}",
+                unknown.join(", "))))
+        }
+    }
+
+    /// Saves the whole of the state that can be bumped, rather than just the
+    /// parts changed by a bump, so that restoring from it returns to this
+    /// state whatever has been bumped since.
+    pub fn save_all(&self) -> SavedData {
+
+        // credit ids with no discount override are saved as None, so that
+        // any override added by a later bump is removed on restore
+        let mut discount_curves: HashMap<String, Option<RcRateCurve>> = self.yield_curves.keys()
+            .map(|credit_id| (credit_id.to_string(), None)).collect();
+        for (credit_id, curve) in self.discount_curves.iter() {
+            discount_curves.insert(credit_id.to_string(), Some(curve.clone()));
+        }
+
+        SavedData {
+            spots: self.spots.clone(),
+            yield_curves: self.yield_curves.clone(),
+            borrow_curves: self.borrow_curves.clone(),
+            dividends: self.dividends.clone(),
+            vol_surfaces: self.vol_surfaces.clone(),
+            discount_curves: discount_curves,
+            correlations: self.correlations.clone(),
+            spot_date: None }
+    }
+
+    /// Bumps the spot date, for example during a Theta calculation. If a
+    /// save area is supplied, the spot date and the spots are saved first,
+    /// unless already saved, so that restore undoes the bump.
+    pub fn bump_spot_date(&mut self, bump: &BumpSpotDate, dependencies: &DependencyCollector,
+        save: Option<&mut SavedData>) -> Result<(), qm::Error> {
 
         let new_spot_date = bump.spot_date();
+        if let Some(save) = save {
+            if save.spot_date.is_none() {
+                save.spot_date = Some(self.spot_date);
+            }
+            for (id, spot) in self.spots.iter() {
+                save.spots.entry(id.to_string()).or_insert(*spot);
+            }
+        }
   
         match bump.spot_dynamics() {
             SpotDynamics::StickyForward => self.sticky_forward_bump(new_spot_date, dependencies)?,
-            SpotDynamics::StickySpot => self.sticky_spot_bump(new_spot_date, dependencies)?
+            SpotDynamics::StickySpot => self.sticky_spot_bump(new_spot_date)?
         }
 
         self.spot_date = new_spot_date;
         Ok(())
     }
 
+    /// Bumps the yield curve for the given credit id, but only where it is
+    /// used for discounting. Forwards are still built from the unbumped
+    /// curve. Returns false if there is no such yield curve.
+    fn apply_discount_bump(&mut self, credit_id: &str, bump: &BumpYield,
+        save: Option<&mut HashMap<String, Option<RcRateCurve>>>)
+        -> Result<bool, qm::Error> {
+
+        let existing = self.discount_curves.get(credit_id).cloned();
+        let curve = match existing {
+            Some(ref curve) => curve.clone(),
+            None => match self.yield_curves.get(credit_id) {
+                Some(curve) => curve.clone(),
+                None => return Ok(false)
+            }
+        };
+
+        // only save the first time, so that restore goes back to the
+        // state before any of the bumps
+        if let Some(save) = save {
+            save.entry(credit_id.to_string()).or_insert(existing);
+        }
+
+        self.discount_curves.insert(credit_id.to_string(), bump.apply(curve));
+        Ok(true)
+    }
+
+    fn apply_correlation_bump(&mut self, bump: &BumpCorrelation,
+        save: Option<&mut Option<CorrelationMatrix>>) -> Result<bool, qm::Error> {
+
+        let bumped = match self.correlations {
+            Some(ref correlations) if bump.applies_to(correlations)
+                => bump.apply(correlations)?,
+            _ => return Ok(false)
+        };
+
+        // only save the first time, so that restore goes back to the
+        // state before any of the bumps
+        if let Some(save) = save {
+            if save.is_none() {
+                *save = self.correlations.clone();
+            }
+        }
+
+        self.correlations = Some(bumped);
+        Ok(true)
+    }
+
     fn sticky_forward_bump(&mut self, new_spot_date: Date, dependencies: &DependencyCollector)
         -> Result<(), qm::Error> {
         
@@ -126,14 +363,113 @@ impl MarketData {
         Ok(())
     }
 
-    fn sticky_spot_bump(&mut self, _new_spot_date: Date, _dependencies: &DependencyCollector)
-        -> Result<(), qm::Error> {
-        // for now, do nothing. Need to think about what to do about dividends
+    /// Spot stays where it is as the spot date moves forward, so the forwards
+    /// are recalculated from the new spot date and lose the carry over the
+    /// period. The exception is any dividends going ex in the period, which
+    /// are taken out of spot, just as they would drop out of the forwards.
+    fn sticky_spot_bump(&mut self, new_spot_date: Date) -> Result<(), qm::Error> {
+
+        let old_spot_date = self.spot_date;
+        for (id, spot) in self.spots.iter_mut() {
+            if let Some(stream) = self.dividends.get(id) {
+                for div in stream.dividends().iter() {
+                    let ex_date = div.ex_date();
+                    if ex_date > old_spot_date && ex_date <= new_spot_date {
+                        *spot -= div.cash() + div.relative() * *spot;
+                    }
+                }
+            }
+        }
+
         Ok(())
     }
 
 }
 
+/// Days after the spot date at which curves and vol surfaces are sampled
+/// to measure the size of a change in MarketData::diff
+const DIFF_PROBE_DAYS: [i32; 8] = [7, 30, 91, 182, 365, 730, 1826, 3652];
+
+/// How one item of market data differs between two states
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DataChange {
+    /// The item is only in the new state
+    Added,
+
+    /// The item is only in the old state
+    Removed,
+
+    /// The item is in both states, with a different value. The size of the
+    /// change is measured as described in MarketDataDiff.
+    Changed(f64)
+}
+
+/// The differences between two states of market data, as found by
+/// MarketData::diff. Each map contains only the items that differ, keyed by
+/// their id. The sizes of the changes are measured as:
+///
+/// * spots and FX rates: the new value less the old
+/// * yield, discount and borrow curves: the largest absolute change in the
+///   continuously compounded zero rate, sampled at a range of tenors
+/// * dividends: the total cash dividend, new less old
+/// * vol surfaces: the largest absolute change in vol struck at the old
+///   spot, sampled at the same tenors as the curves
+///
+/// A curve, dividend stream or vol surface is changed if anything in it
+/// differs, so it may be reported with a size of zero if the change is
+/// somewhere we do not sample.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MarketDataDiff {
+    pub spot_date: Option<(Date, Date)>,
+    pub spots: HashMap<String, DataChange>,
+    pub yield_curves: HashMap<String, DataChange>,
+    pub discount_curves: HashMap<String, DataChange>,
+    pub borrow_curves: HashMap<String, DataChange>,
+    pub dividends: HashMap<String, DataChange>,
+    pub vol_surfaces: HashMap<String, DataChange>,
+    pub fx_rates: HashMap<String, DataChange>,
+    pub correlations: bool,
+    pub dividend_model: bool
+}
+
+impl MarketDataDiff {
+    /// True if the two states of market data are the same
+    pub fn is_empty(&self) -> bool {
+        self.spot_date.is_none() && self.spots.is_empty()
+            && self.yield_curves.is_empty() && self.discount_curves.is_empty()
+            && self.borrow_curves.is_empty() && self.dividends.is_empty()
+            && self.vol_surfaces.is_empty() && self.fx_rates.is_empty()
+            && !self.correlations && !self.dividend_model
+    }
+}
+
+/// Compares two maps of market data, using the given function to compare
+/// the items in both, which returns the size of any change.
+fn diff_maps<T, F>(old: &HashMap<String, T>, new: &HashMap<String, T>, change: F)
+    -> Result<HashMap<String, DataChange>, qm::Error>
+    where F: Fn(&str, &T, &T) -> Result<Option<f64>, qm::Error> {
+
+    let mut changes = HashMap::new();
+    for (id, old_item) in old.iter() {
+        match new.get(id) {
+            None => { changes.insert(id.to_string(), DataChange::Removed); }
+            Some(new_item) => if let Some(size) = change(id, old_item, new_item)? {
+                changes.insert(id.to_string(), DataChange::Changed(size));
+            }
+        }
+    }
+    for id in new.keys().filter(|id| !old.contains_key(*id)) {
+        changes.insert(id.to_string(), DataChange::Added);
+    }
+    Ok(changes)
+}
+
+/// Whether two items of market data serialize identically, which is how we
+/// compare items such as curves that have no other notion of equality
+fn same_serialized<T: sd::Serialize>(first: &T, second: &T) -> Result<bool, qm::Error> {
+    Ok(serde_json::to_string(first)? == serde_json::to_string(second)?)
+}
+
 /// Create a new type for a Rc<MarketData> so we can implement serialize
 /// and deserialize functions for it.
 #[derive(Clone, Debug)]
@@ -178,6 +514,9 @@ impl PricingContext for MarketData {
 
     fn yield_curve(&self, credit_id: &str, _high_water_mark: Date)
             -> Result<RcRateCurve, qm::Error> {
+        if let Some(curve) = self.discount_curves.get(credit_id) {
+            return Ok(curve.clone())
+        }
         find_market_data(credit_id, &self.yield_curves, "Yield curve")
     }
 
@@ -205,7 +544,7 @@ impl PricingContext for MarketData {
         // bumps
         let settlement = instrument.settlement().clone();
         let forward = EquityForward::new(self.spot_date, spot, settlement,
-            yield_curve, borrow, &*divs, high_water_mark)?;
+            yield_curve, borrow, &*divs, high_water_mark, self.dividend_model)?;
         Ok(Arc::new(forward))
     }
 
@@ -225,9 +564,55 @@ impl PricingContext for MarketData {
         Ok(vol)
     }
 
-    fn correlation(&self, _first: &Instrument, _second: &Instrument)
+    fn correlation(&self, first: &Instrument, second: &Instrument)
         -> Result<f64, qm::Error> {
-        Err(qm::Error::new("Correlation not implemented"))
+        match self.correlations {
+            Some(ref correlations) => correlations.get(first.id(), second.id()),
+            None => Err(qm::Error::new("No correlations supplied"))
+        }
+    }
+
+    fn fx_rate(&self, base_currency: &str, quote_currency: &str)
+        -> Result<FxRate, qm::Error> {
+
+        if base_currency == quote_currency {
+            return Ok(FxRate::unity())
+        }
+        if let Some(rate) = self.fx_rates.get(&fx_id(base_currency, quote_currency)) {
+            return Ok(*rate)
+        }
+        match self.fx_rates.get(&fx_id(quote_currency, base_currency)) {
+            Some(rate) => Ok(rate.inverse()),
+            None => Err(qm::Error::new(&format!("FX rate not found: '{}'",
+                fx_id(base_currency, quote_currency))))
+        }
+    }
+
+    fn fx_correlation(&self, base_currency: &str, quote_currency: &str,
+        instrument: &Instrument) -> Result<f64, qm::Error> {
+
+        let correlations = match self.correlations {
+            Some(ref correlations) => correlations,
+            None => return Err(qm::Error::new("No correlations supplied"))
+        };
+
+        // if the rate is supplied the other way round, so is its correlation
+        let id = fx_id(base_currency, quote_currency);
+        if self.fx_rates.contains_key(&id) {
+            correlations.get(&id, instrument.id())
+        } else {
+            let inverse = fx_id(quote_currency, base_currency);
+            Ok(-correlations.get(&inverse, instrument.id())?)
+        }
+    }
+}
+
+fn unknown_ids<T>(item: &str, collection: &HashMap<String, T>,
+    spots: &HashMap<String, f64>, unknown: &mut Vec<String>) {
+    for id in collection.keys() {
+        if !spots.contains_key(id) {
+            unknown.push(format!("{} '{}'", item, id));
+        }
     }
 }
 
@@ -263,9 +648,14 @@ impl Bumpable for MarketData {
             &Bump::Yield(ref credit_id, ref bump) => apply_bump(&credit_id,
                 bump as &BumpYield, &mut self.yield_curves, 
                 saved.map_or(None, |s| Some(&mut s.yield_curves))),
+            &Bump::Discount(ref credit_id, ref bump) => self.apply_discount_bump(
+                &credit_id, bump as &BumpYield,
+                saved.map_or(None, |s| Some(&mut s.discount_curves))),
              &Bump::SpotDate(_) => Err(qm::Error::new("MarketData does not have \
                 enough information to handle spot date bumping on its own. It needs \
-                to be handled by a containing PricingContextPrefetch."))
+                to be handled by a containing PricingContextPrefetch.")),
+            &Bump::Correlation(ref bump) => self.apply_correlation_bump(bump,
+                saved.map_or(None, |s| Some(&mut s.correlations)))
         }
     }
 
@@ -289,12 +679,34 @@ impl Bumpable for MarketData {
             copy_from_saved(&mut self.borrow_curves, &saved.borrow_curves);
             copy_from_saved(&mut self.dividends, &saved.dividends);
             copy_from_saved(&mut self.vol_surfaces, &saved.vol_surfaces);
+            for (credit_id, curve) in saved.discount_curves.iter() {
+                match curve {
+                    &Some(ref curve) => self.discount_curves.insert(
+                        credit_id.to_string(), curve.clone()),
+                    &None => self.discount_curves.remove(credit_id)
+                };
+            }
+            if let Some(ref correlations) = saved.correlations {
+                self.correlations = Some(correlations.clone());
+            }
+            if let Some(spot_date) = saved.spot_date {
+                self.spot_date = spot_date;
+            }
             Ok(())
 
         } else {
             Err(qm::Error::new("Mismatching save space for restore"))
         }
     }
+
+    fn checkpoint(&self) -> Result<Vec<u8>, qm::Error> {
+        write_checkpoint(self.spot_date, &self.save_all())
+    }
+
+    fn restore_checkpoint(&mut self, checkpoint: &[u8]) -> Result<(), qm::Error> {
+        let saved: SavedData = read_checkpoint(self.spot_date, checkpoint)?;
+        self.restore(&saved)
+    }
 }
 
 impl BumpablePricingContext for MarketData {
@@ -302,6 +714,17 @@ impl BumpablePricingContext for MarketData {
     fn as_mut_bumpable(&mut self) -> &mut Bumpable { self }
     fn as_pricing_context(&self) -> &PricingContext { self }
     fn raw_market_data(&self) -> &MarketData { self }
+
+    fn refresh_market_data(&mut self, market_data: &MarketData)
+        -> Result<(), qm::Error> {
+        if market_data.spot_date != self.spot_date {
+            return Err(qm::Error::new(&format!("Cannot refresh the market data \
+                with spot date {} rather than {}", market_data.spot_date,
+                self.spot_date)))
+        }
+        *self = market_data.clone();
+        Ok(())
+    }
 }
 
 fn to_saved_data(opt_save: Option<&mut Saveable>) -> Result<Option<&mut SavedData>, qm::Error> {
@@ -325,9 +748,11 @@ fn apply_bump<T: Clone>(id: &str, bump: &Bumper<T>,
     let key = id.to_string();
     if let Some(entry) = to_bump.get_mut(&key) {
 
-        // save the old value if there is anywhere to save it
+        // save the old value if there is anywhere to save it, unless it
+        // was already saved by an earlier bump, so that a restore goes back
+        // to the state before all the bumps
         if let Some(save) = to_save {
-            save.insert(key, entry.clone());
+            save.entry(key).or_insert_with(|| entry.clone());
         }
 
         // update the new value and return true to say we changed it
@@ -348,12 +773,27 @@ pub fn copy_from_saved<T: Clone>(to_restore: &mut HashMap<String, T>,
     }
 }
 
+/// Data structure for saving the market data before a bump, so it can be
+/// restored later on. It is serializable, so that a copy of the whole
+/// state made by save_all can be used as a checkpoint.
+#[derive(Serialize, Deserialize)]
 pub struct SavedData {
     spots: HashMap<String, f64>,
     yield_curves: HashMap<String, RcRateCurve>,
     borrow_curves: HashMap<String, RcRateCurve>,
     dividends: HashMap<String, RcDividendStream>,
-    vol_surfaces: HashMap<String, RcVolSurface>
+    vol_surfaces: HashMap<String, RcVolSurface>,
+
+    // None means there was no discount override before the bump
+    discount_curves: HashMap<String, Option<RcRateCurve>>,
+
+    // None means the correlations were not bumped
+    #[serde(default)]
+    correlations: Option<CorrelationMatrix>,
+
+    // None means the spot date was not bumped
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    spot_date: Option<Date>
 }
 
 impl SavedData {
@@ -366,7 +806,10 @@ impl SavedData {
             yield_curves: HashMap::new(),
             borrow_curves: HashMap::new(),
             dividends: HashMap::new(),
-            vol_surfaces: HashMap::new() }
+            vol_surfaces: HashMap::new(),
+            discount_curves: HashMap::new(),
+            correlations: None,
+            spot_date: None }
     }
 }
 
@@ -380,6 +823,9 @@ impl Saveable for SavedData {
         self.borrow_curves.clear();
         self.dividends.clear();
         self.vol_surfaces.clear();
+        self.discount_curves.clear();
+        self.correlations = None;
+        self.spot_date = None;
     }
 }
 
@@ -404,16 +850,22 @@ pub mod tests {
     use data::divstream::DividendStream;
     use data::divstream::Dividend;
     use data::curves::RateCurveAct365;
+    use data::curves::ZeroRateCurve;
     use data::volsurface::RcVolSurface;
     use data::volsurface::FlatVolSurface;
+    use data::volsurface::VolSurface;
     use data::bumpspot::BumpSpot;
     use data::bumpdivs::BumpDivs;
     use data::bumpvol::BumpVol;
+    use data::forward::discount_with_borrow;
+    use data::forward::log_discount_with_borrow;
     use data::bumpyield::BumpYield;
     use dates::calendar::WeekdayCalendar;
     use dates::calendar::RcCalendar;
     use math::numerics::approx_eq;
     use math::interpolation::Extrap;
+    use math::optionpricing::Black76;
+    use dates::calendar::Calendar;
     use core::factories::Qrc;
     use serde_json;
 
@@ -548,6 +1000,310 @@ pub mod tests {
         assert_approx(price, 16.710717400832973, 1e-12);
     }
 
+    #[test]
+    fn forward_steps_down_by_dividend_on_ex_date() {
+
+        let market_data = sample_market_data();
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        let equity = sample_equity(currency, 2);
+        let settlement = equity.settlement().clone();
+        let d = market_data.spot_date();
+
+        // The forward is (S - D(t)) / df(settlement of t), where S is the
+        // spot discounted over its settlement period, D(t) is the NPV of the
+        // dividends going ex up to and including t, and the discounting
+        // includes the borrow. Undoing the growth either side of an ex date
+        // therefore leaves exactly the NPV of the dividend.
+        let rate = create_sample_rate();
+        let borrow = create_sample_borrow();
+        let base = log_discount_with_borrow(&*rate, &*borrow, d).unwrap();
+        let df = |date: Date| discount_with_borrow(&*rate, &*borrow, base, date).unwrap();
+        let ex_drop = |data: &MarketData, ex_date: Date| {
+            let before = data.forward(&equity, ex_date - 1).unwrap();
+            let after = data.forward(&equity, ex_date).unwrap();
+            assert!(after < before, "before={} after={}", before, after);
+            before * df(settlement.apply(ex_date - 1))
+                - after * df(settlement.apply(ex_date))
+        };
+
+        // a cash dividend of 1.2, going ex on d + 28 and paying on d + 30,
+        // then a mixed dividend of 0.8 cash plus 0.2% of the forward
+        let cash_pv = 1.2 * df(d + 30);
+        let mixed_pv = |spot: f64| {
+            let reference_spot = spot * df(settlement.apply(d));
+            let fwd = (reference_spot - cash_pv) / df(d + 210);
+            (0.8 + 0.002 * fwd) * df(d + 212)
+        };
+        assert_approx(ex_drop(&market_data, d + 28), cash_pv, 1e-12);
+        assert_approx(ex_drop(&market_data, d + 210), mixed_pv(100.0), 1e-12);
+
+        // a spot bump leaves the cash amounts unchanged, but the relative
+        // part of a dividend scales with the forward
+        let mut bumped = market_data.clone();
+        let bump = Bump::new_spot("BP.L", BumpSpot::new_relative(0.01));
+        assert!(bumped.bump(&bump, None).unwrap());
+        assert_approx(ex_drop(&bumped, d + 28), cash_pv, 1e-12);
+        assert_approx(ex_drop(&bumped, d + 210), mixed_pv(101.0), 1e-12);
+    }
+
+    #[test]
+    fn implied_vol_recovers_sample_european_vol() {
+
+        let market_data = sample_market_data();
+        let european = sample_european();
+        let spot_date = Date::from_ymd(2017, 01, 02);
+        let val_date = DateTime::new(spot_date, TimeOfDay::Open);
+        let price = european.price(&market_data, val_date).unwrap();
+
+        // Find the inputs the European uses for Black76. It pays two
+        // business days after its expiry, and is discounted to the settlement
+        // date of the spot date.
+        let expiry = Date::from_ymd(2018, 06, 01);
+        let pay_date = Date::from_ymd(2018, 06, 05);
+        let settlement = european.settlement().apply(spot_date);
+        let yc = market_data.yield_curve("LSE", pay_date).unwrap();
+        let df = yc.df(pay_date, settlement).unwrap();
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        let equity = sample_equity(currency, 2);
+        let forward = market_data.forward_curve(&equity, expiry).unwrap()
+            .forward(expiry).unwrap();
+
+        // The vol time runs from the val date to the close on the expiry date
+        let vol = create_sample_flat_vol();
+        let time = vol.calendar().year_fraction(
+            DateDayFraction::new(spot_date, 0.0),
+            DateDayFraction::new(expiry, 0.8));
+
+        let black76 = Black76::new().unwrap();
+        let implied = black76.implied_vol(df, forward, 100.0, time, price).unwrap();
+        assert_approx(implied, 0.3, 1e-10);
+
+        // a price below intrinsic value has no implied vol
+        let intrinsic = df * (forward - 80.0);
+        assert!(black76.implied_vol(df, forward, 80.0, time, 0.5 * intrinsic).is_err());
+    }
+
+    #[test]
+    fn european_price_with_continuous_dividend_yield() {
+
+        // The dividends are small, and the forward at the expiry of the
+        // option is unchanged, so the price should match the discrete model
+        let mut market_data = sample_market_data();
+        market_data.set_dividend_model(DividendModel::ContinuousYield);
+        let european = sample_european();
+        let val_date = DateTime::new(Date::from_ymd(2017, 01, 02), TimeOfDay::Open);
+        let price = european.price(&market_data, val_date).unwrap();
+        assert_approx(price, 16.710717400832973, 1e-10);
+
+        // the choice survives serialization, and the default is discrete
+        let json = market_data.to_json().unwrap();
+        let deserialized = MarketData::from_json(&json).unwrap();
+        assert_eq!(deserialized.dividend_model(), DividendModel::ContinuousYield);
+        let json = sample_market_data().to_json().unwrap();
+        assert!(!json.contains("dividend_model"));
+        let deserialized = MarketData::from_json(&json).unwrap();
+        assert_eq!(deserialized.dividend_model(), DividendModel::Discrete);
+    }
+
+    #[test]
+    fn european_price_with_dividend_yield_and_no_schedule() {
+
+        // Replace the discrete dividends with a flat continuous yield,
+        // chosen so the forward at the expiry of the option is unchanged
+        let discrete = sample_market_data();
+        let spot_date = discrete.spot_date();
+        let expiry = Date::from_ymd(2018, 06, 01);
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        let equity = sample_equity(currency, 2);
+        let with_yield = |q: f64| {
+            let curve = RateCurveAct365::new(spot_date,
+                &[(spot_date, q), (spot_date + 1000, q)],
+                Extrap::Flat, Extrap::Flat).unwrap();
+            let divs = DividendStream::from_yield(RcRateCurve::new(Arc::new(curve)));
+            let mut market_data = sample_market_data();
+            market_data.dividends.insert("BP.L".to_string(),
+                RcDividendStream::new(Arc::new(divs)));
+            market_data
+        };
+        let target = discrete.forward(&equity, expiry).unwrap();
+        let no_divs = with_yield(0.0).forward(&equity, expiry).unwrap();
+        let t = (expiry - spot_date) as f64 / 365.0;
+        let market_data = with_yield((no_divs / target).ln() / t);
+        assert!(market_data.dividends.get("BP.L").unwrap().dividends().is_empty());
+        assert_approx(market_data.forward(&equity, expiry).unwrap(), target, 1e-10);
+        assert_approx(market_data.forward(&equity, spot_date).unwrap(), 100.0, 1e-12);
+
+        let european = sample_european();
+        let val_date = DateTime::new(spot_date, TimeOfDay::Open);
+        let price = european.price(&market_data, val_date).unwrap();
+        assert_approx(price, 16.710717400832973, 1e-9);
+
+        // bumping the dividends bumps the yield, which lowers the forward
+        let mut bumped = market_data.clone();
+        let bump = Bump::new_divs("BP.L", BumpDivs::new_all_relative(0.1));
+        assert!(bumped.bump(&bump, None).unwrap());
+        let bumped_price = european.price(&bumped, val_date).unwrap();
+        assert!(bumped_price < price, "bumped={} unbumped={}", bumped_price, price);
+    }
+
+    #[test]
+    fn dividend_stream_with_no_schedule() {
+
+        // an index has only a continuous yield, and its schedule may be
+        // left out of the JSON
+        let json = r#"{"div_yield": {"ZeroRateCurve": {"base": "2017-01-02"}}}"#;
+        let divs: DividendStream = serde_json::from_str(json).unwrap();
+        assert!(divs.dividends().is_empty());
+
+        let spot_date = Date::from_ymd(2017, 01, 02);
+        let curve = RateCurveAct365::new(spot_date,
+            &[(spot_date, 0.02), (spot_date + 1000, 0.02)],
+            Extrap::Flat, Extrap::Flat).unwrap();
+        let divs = DividendStream::from_yield(RcRateCurve::new(Arc::new(curve)));
+        assert!(divs.dividends().is_empty());
+    }
+
+    #[test]
+    fn window_dividend_bump_only_moves_forward_from_window() {
+
+        // The sample dividends go ex 28, 210, 392 and 574 days after the
+        // spot date. The window is exactly from the first to the second, so
+        // both are bumped, as the boundaries are inclusive.
+        let market_data = sample_market_data();
+        let d = market_data.spot_date();
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        let equity = sample_equity(currency, 2);
+        let bumped_by = |bump: BumpDivs| {
+            let mut bumped = market_data.clone();
+            assert!(bumped.bump(&Bump::new_divs("BP.L", bump), None).unwrap());
+            bumped
+        };
+        let window = bumped_by(BumpDivs::new_window_relative(d + 28, d + 210, 0.1));
+        let all = bumped_by(BumpDivs::new_all_relative(0.1));
+
+        // before the window, the forward is unchanged. Within it, and up to
+        // the next dividend, it is bumped as if all dividends were. (The
+        // dividend yield only starts after two years.)
+        for &days in [0, 27].iter() {
+            assert_eq!(window.forward(&equity, d + days).unwrap(),
+                market_data.forward(&equity, d + days).unwrap());
+        }
+        for &days in [28, 100, 210, 391].iter() {
+            let bumped = window.forward(&equity, d + days).unwrap();
+            assert!(bumped < market_data.forward(&equity, d + days).unwrap());
+            assert_approx(bumped, all.forward(&equity, d + days).unwrap(), 1e-12);
+        }
+
+        // after the window, later dividends are unbumped, so the forward is
+        // between the unbumped one and that with all dividends bumped
+        let later = d + 392;
+        let bumped = window.forward(&equity, later).unwrap();
+        assert!(bumped < market_data.forward(&equity, later).unwrap());
+        assert!(bumped > all.forward(&equity, later).unwrap());
+
+        // the European expires after the window, so reprices in the same way
+        let european = sample_european();
+        let val_date = DateTime::new(d, TimeOfDay::Open);
+        let price = european.price(&market_data, val_date).unwrap();
+        let window_price = european.price(&window, val_date).unwrap();
+        let all_price = european.price(&all, val_date).unwrap();
+        assert!(window_price < price && window_price > all_price,
+            "window={} unbumped={} all={}", window_price, price, all_price);
+
+        // a window with no dividends in it changes nothing
+        let empty = bumped_by(BumpDivs::new_window_relative(d + 29, d + 209, 0.1));
+        assert_eq!(european.price(&empty, val_date).unwrap(), price);
+    }
+
+    #[test]
+    fn discount_to_value_date_matches_sample_curve() {
+
+        // The European pays two business days after its expiry on Friday
+        // 1st June 2018, and is valued two business days after the spot
+        // date. The sample curve is linear in the Act/365 rate, between
+        // pillars at 0 and 14 days from its base for the value date, and at
+        // 364 and 728 days for the payment.
+        let market_data = sample_market_data();
+        let base = Date::from_ymd(2016, 12, 30);
+        let spot_date = Date::from_ymd(2017, 01, 02);
+        let pay_date = Date::from_ymd(2018, 06, 05);
+        let european = sample_european();
+        let value_date = european.settlement().apply(spot_date);
+        assert_eq!(value_date, Date::from_ymd(2017, 01, 04));
+        let value_days = (value_date - base) as f64;
+        let pay_days = (pay_date - base) as f64;
+        let value_rate = 0.05 + (0.08 - 0.05) * value_days / 14.0;
+        let pay_rate = 0.085 + (0.082 - 0.085) * (pay_days - 364.0) / 364.0;
+        let expected = (value_rate * value_days / 365.0
+            - pay_rate * pay_days / 365.0).exp();
+
+        let df = market_data.discount_to_value_date("OPT", pay_date, value_date).unwrap();
+        assert_approx(df, expected, 1e-14);
+        assert_approx(market_data.discount_to_value_date("OPT", value_date,
+            value_date).unwrap(), 1.0, 1e-14);
+
+        // this is the discount factor the European actually applies
+        let yc = market_data.yield_curve("OPT", pay_date).unwrap();
+        assert_approx(df, yc.df(pay_date, value_date).unwrap(), 1e-14);
+
+        // dates before the spot date are an error, as is an unknown credit
+        assert!(market_data.discount_to_value_date("OPT", spot_date - 1,
+            value_date).is_err());
+        assert!(market_data.discount_to_value_date("Unknown", pay_date,
+            value_date).is_err());
+    }
+
+    #[test]
+    fn european_price_with_negative_rates() {
+
+        // A flat -0.5% yield curve, with no borrow or dividends, so the
+        // only carry is the negative rate
+        let spot_date = Date::from_ymd(2017, 01, 02);
+        let d = Date::from_ymd(2016, 12, 30);
+        let rate = RcRateCurve::new(Arc::new(RateCurveAct365::new(d,
+            &[(d, -0.005), (d + 728, -0.005)], Extrap::Flat, Extrap::Flat).unwrap()));
+        let no_divs = RcDividendStream::new(Arc::new(DividendStream::new(&[],
+            RcRateCurve::new(Arc::new(ZeroRateCurve::new(d))))));
+        let mut spots = HashMap::new();
+        spots.insert("BP.L".to_string(), 100.0);
+        let mut yield_curves = HashMap::new();
+        yield_curves.insert("OPT".to_string(), rate.clone());
+        yield_curves.insert("LSE".to_string(), rate.clone());
+        let mut borrow_curves = HashMap::new();
+        borrow_curves.insert("BP.L".to_string(),
+            RcRateCurve::new(Arc::new(ZeroRateCurve::new(d))));
+        let mut dividends = HashMap::new();
+        dividends.insert("BP.L".to_string(), no_divs);
+        let mut vol_surfaces = HashMap::new();
+        vol_surfaces.insert("BP.L".to_string(), create_sample_flat_vol());
+        let market_data = MarketData::new(spot_date, spots, yield_curves,
+            borrow_curves, dividends, vol_surfaces);
+
+        // The forward grows at the negative rate, so is below spot, and the
+        // discount factor is above one
+        let european = sample_european();
+        let expiry = Date::from_ymd(2018, 06, 01);
+        let pay_date = Date::from_ymd(2018, 06, 05);
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        let forward = market_data.forward_curve(&sample_equity(currency, 2), expiry)
+            .unwrap().forward(expiry).unwrap();
+        assert!(forward < 100.0 && forward > 99.0, "forward={}", forward);
+        let value_date = european.settlement().apply(spot_date);
+        let df = market_data.discount_to_value_date("OPT", pay_date, value_date).unwrap();
+        assert!(df > 1.0 && df < 1.01, "df={}", df);
+
+        // The price is finite, worth more than the discounted intrinsic
+        // value, and less than the discounted forward
+        let val_date = DateTime::new(spot_date, TimeOfDay::Open);
+        let price = european.price(&market_data, val_date).unwrap();
+        assert!(price.is_finite());
+        assert!(price > (forward - 100.0).max(0.0) && price < forward, "price={}", price);
+
+        // Compared with the positive rates of the sample data, a call with
+        // the same strike and vol is worth less, as the forward is lower
+        assert!(price < 16.710717400832973, "price={}", price);
+    }
+
     #[test]
     fn european_bumped_price() {
 
@@ -695,6 +1451,82 @@ pub mod tests {
         assert_approx(serde_price, price, 1e-12);
     }
 
+    #[test]
+    fn json_market_data_roundtrip() {
+
+        // use a spot that is not exactly representable in decimal
+        let mut market_data = sample_market_data();
+        market_data.spots.insert("BP.L".to_string(), 100.0 + 1.0 / 3.0);
+        let european = sample_european();
+        let val_date = DateTime::new(Date::from_ymd(2017, 01, 02), TimeOfDay::Open);
+        let price = european.price(&market_data, val_date).unwrap();
+
+        // the round trip should be exact, so writing it again gives the same
+        // JSON (other than the order of keys) and the same price
+        let json = market_data.to_json().unwrap();
+        let deserialized = MarketData::from_json(&json).unwrap();
+        assert_eq!(deserialized.spot_date, market_data.spot_date);
+        assert_eq!(deserialized.spots, market_data.spots);
+        let rejson = deserialized.to_json().unwrap();
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        let revalue: serde_json::Value = serde_json::from_str(&rejson).unwrap();
+        assert_eq!(revalue, value);
+        let json_price = european.price(&deserialized, val_date).unwrap();
+        assert_eq!(json_price, price);
+    }
+
+    #[test]
+    fn json_market_data_unknown_asset() {
+
+        // remove the spot for GSK.L, so its other market data is orphaned
+        let mut market_data = sample_market_data();
+        market_data.spots.remove("GSK.L");
+        let json = market_data.to_json().unwrap();
+        match MarketData::from_json(&json) {
+            Ok(_) => panic!("from_json should fail for an unknown asset"),
+            Err(e) => assert_eq!(e.to_string(), "rfin error: Market data for \
+                unknown assets (no spot supplied): Borrow curve 'GSK.L', \
+                Dividends 'GSK.L', Vol surface 'GSK.L'")
+        }
+    }
+
+    #[test]
+    fn diff_reports_only_bumped_spot() {
+
+        let market_data = sample_market_data();
+        assert!(market_data.diff(&market_data.clone()).unwrap().is_empty());
+
+        let mut bumped = market_data.clone();
+        let bump = Bump::new_spot("BP.L", BumpSpot::new_relative(0.01));
+        assert!(bumped.bump(&bump, None).unwrap());
+        let diff = market_data.diff(&bumped).unwrap();
+        assert_eq!(diff.spots.len(), 1);
+        match diff.spots["BP.L"] {
+            DataChange::Changed(size) => assert_approx(size, 1.0, 1e-12),
+            ref other => panic!("unexpected change {:?}", other)
+        }
+        let only_spots = MarketDataDiff { spots: diff.spots.clone(), ..Default::default() };
+        assert_eq!(diff, only_spots);
+
+        // a vol bump is measured in vol, and assets that are only on one
+        // side are added or removed rather than ignored
+        let mut other = market_data.clone();
+        let bump = Bump::new_vol("GSK.L", BumpVol::new_flat_additive(0.01));
+        assert!(other.bump(&bump, None).unwrap());
+        other.spots.remove("BP.L");
+        other.spots.insert("RDSA.L".to_string(), 2000.0);
+        let diff = market_data.diff(&other).unwrap();
+        assert_eq!(diff.spots.len(), 2);
+        assert_eq!(diff.spots["BP.L"], DataChange::Removed);
+        assert_eq!(diff.spots["RDSA.L"], DataChange::Added);
+        assert_eq!(diff.vol_surfaces.len(), 1);
+        match diff.vol_surfaces["GSK.L"] {
+            DataChange::Changed(size) => assert_approx(size, 0.01, 1e-12),
+            ref other => panic!("unexpected change {:?}", other)
+        }
+        assert!(diff.yield_curves.is_empty() && diff.dividends.is_empty());
+    }
+
     fn assert_approx(value: f64, expected: f64, tolerance: f64) {
         assert!(approx_eq(value, expected, tolerance),
             "value={} expected={}", value, expected);
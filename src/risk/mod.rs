@@ -5,23 +5,49 @@ pub mod bumptime;
 pub mod deltagamma;
 pub mod timebumped;
 pub mod vegavolga;
+pub mod vannavolga;
+pub mod vegaladder;
+pub mod pricereport;
+pub mod rho;
+pub mod cega;
+pub mod rholadder;
+pub mod scenario;
+pub mod bumpset;
+pub mod bumpcache;
+pub mod pnlexplain;
+pub mod portfolio;
+pub mod spotladder;
 
 use risk::timebumped::{TimeBumpedReportGenerator, TimeBumpedReport};
 use risk::deltagamma::{DeltaGammaReportGenerator, DeltaGammaReport};
 use risk::vegavolga::{VegaVolgaReportGenerator, VegaVolgaReport};
+use risk::vannavolga::{VannaVolgaReportGenerator, VannaVolgaReport};
+use risk::vegaladder::{VegaLadderReportGenerator, VegaLadderReport};
+use risk::rho::{RhoReportGenerator, RhoReport};
+use risk::cega::{CegaReportGenerator, CegaReport};
+use risk::rholadder::{RhoLadderReportGenerator, RhoLadderReport};
+use risk::spotladder::{SpotLadderReportGenerator, SpotLadderReport};
+use risk::pricereport::PriceReport;
+use risk::bumpcache::BumpedPriceCache;
 use core::qm;
 use core::factories::{Qrc, Qbox, TypeId, Registry};
 use data::bump::Bump;
+use data::bumpspot::BumpSpot;
 use risk::bumptime::BumpTime;
+use data::bumpspotdate::SpotDynamics;
+use dates::Date;
+use dates::calendar::RcCalendar;
 use risk::marketdata::MarketData;
 use instruments::PricingContext;
 use risk::dependencies::DependencyCollector;
 use erased_serde as esd;
 use serde as sd;
+use serde_json;
 use serde_tagged as sdt;
 use serde_tagged::de::BoxFnSeed;
 use std::fmt::Debug;
 use std::any::Any;
+use std::collections::BTreeMap;
 use std::fmt;
 use std::ops::Deref;
 use math::numerics::ApproxEq;
@@ -50,6 +76,27 @@ pub trait Bumpable {
 
     /// Restores the state to what it was before the bump
     fn restore(&mut self, saved: &Saveable) -> Result<(), qm::Error>;
+
+    /// Writes the whole of the current bumped state, so that it can be
+    /// persisted and later reloaded by restore_checkpoint, without rebuilding
+    /// the market data. Time bumps are not part of the state, as they may
+    /// change the instruments, so a checkpoint can only be restored into
+    /// an object with the same spot date.
+    ///
+    /// Only state that can be bumped needs to be written. For example, the
+    /// paths of a Monte-Carlo model are derived from its pricing context and
+    /// random draws, and the draws are not bumpable, so the model need only
+    /// checkpoint its context.
+    fn checkpoint(&self) -> Result<Vec<u8>, qm::Error> {
+        Err(qm::Error::new("Checkpointing is not supported"))
+    }
+
+    /// Returns to the state written by checkpoint, whatever has been bumped
+    /// since. The object must have been built from the same instruments and
+    /// market data as the one that wrote the checkpoint.
+    fn restore_checkpoint(&mut self, _checkpoint: &[u8]) -> Result<(), qm::Error> {
+        Err(qm::Error::new("Checkpointing is not supported"))
+    }
 }
 
 pub trait BumpablePricingContext: Bumpable + PricingContext + BumpablePricingContextClone {
@@ -57,6 +104,11 @@ pub trait BumpablePricingContext: Bumpable + PricingContext + BumpablePricingCon
     fn as_mut_bumpable(&mut self) -> &mut Bumpable;
     fn as_pricing_context(&self) -> &PricingContext;
     fn raw_market_data(&self) -> &MarketData;
+
+    /// Replaces the market data with a fresh copy with the same spot date,
+    /// discarding any bumps. See PricingContextPrefetch::refresh_market_data.
+    fn refresh_market_data(&mut self, market_data: &MarketData)
+        -> Result<(), qm::Error>;
 }
 
 pub trait BumpablePricingContextClone {
@@ -86,12 +138,25 @@ pub trait TimeBumpable {
     /// save and restore facility, so you probably need to deep_clone the
     /// object first.
     fn bump_time(&mut self, bump: &BumpTime) -> Result<(), qm::Error>;
+
+    /// Applies a time bump to this object, saving its state so that it can
+    /// be restored using Bumpable::restore. This is only possible if the
+    /// bump leaves the instruments unchanged. Otherwise nothing is bumped
+    /// and this returns false, and you need bump_time on a clone instead.
+    /// The default implementation never saves.
+    fn bump_time_saved(&mut self, _bump: &BumpTime, _save: &mut Saveable)
+        -> Result<bool, qm::Error> {
+        Ok(false)
+    }
 }
 
 /// The basic pricing interface for qm. Returns a price from a pricer or a
 /// priceable instrument. The point of this interface is that it is bumpable,
 /// so it can be used to calculate risks and scenarios.
-pub trait Pricer : Bumpable + TimeBumpable + PricerClone {
+///
+/// Pricers are Send, so risks can be calculated in parallel by moving a
+/// clone of the pricer to each thread. See PricerClone.
+pub trait Pricer : Bumpable + TimeBumpable + PricerClone + Send {
     fn as_bumpable(&self) -> &Bumpable;
     fn as_mut_bumpable(&mut self) -> &mut Bumpable;
     fn as_mut_time_bumpable(&mut self) -> &mut TimeBumpable;
@@ -106,7 +171,84 @@ pub trait Pricer : Bumpable + TimeBumpable + PricerClone {
     /// all underlyings have the same settlement date.
     /// 
     /// Discount date is currently disabled.
-    fn price(&self /*, discount_date: Option<Date>*/) -> Result<f64, qm::Error>;
+    fn price(&self /*, discount_date: Option<Date>*/) -> Result<f64, qm::Error> {
+        Ok(self.price_report()?.total())
+    }
+
+    /// Returns the present value, broken down into the weighted contribution
+    /// of each of the instruments being priced. The total of the report is
+    /// the value returned by price.
+    fn price_report(&self) -> Result<PriceReport, qm::Error>;
+
+    /// Returns the value compounded forward to a reference date on the
+    /// yield curve with the given credit id, for products quoted on an
+    /// undiscounted basis. The present value is a value on the value date,
+    /// so price is price_forward times the discount factor from
+    /// forward_discount_factor. If no reference date is supplied, it
+    /// defaults to the expiry, which is the date of the last fixing. For
+    /// instruments with no fixings, or if the last fixing is after anything
+    /// is discounted on the curve, it is the last date discounted from.
+    fn price_forward(&self, credit_id: &str, reference_date: Option<Date>)
+        -> Result<f64, qm::Error> {
+        let df = self.forward_discount_factor(credit_id, reference_date)?;
+        Ok(self.price()? / df)
+    }
+
+    /// Returns the discount factor from the reference date to the value
+    /// date on the yield curve with the given credit id, as used by
+    /// price_forward. The value date is the settlement date of the spot
+    /// date for the instruments discounted on that curve.
+    fn forward_discount_factor(&self, credit_id: &str,
+        reference_date: Option<Date>) -> Result<f64, qm::Error> {
+
+        let dependencies = self.as_bumpable().dependencies()?;
+        let no_default = || qm::Error::new(&format!("No default reference \
+            date for a forward price, as nothing is discounted on '{}'",
+            credit_id));
+        let date = match reference_date {
+            Some(date) => date,
+            None => {
+                let hwm = dependencies.yield_curve_hwm(credit_id)
+                    .ok_or_else(no_default)?;
+                match dependencies.last_fixing_date() {
+                    Some(expiry) if expiry <= hwm => expiry,
+                    _ => hwm
+                }
+            }
+        };
+        let value_date = dependencies.value_date(credit_id)
+            .ok_or_else(|| qm::Error::new(&format!("No value date for a \
+                forward price, as no instrument has credit id '{}'",
+                credit_id)))?;
+
+        let df = self.as_bumpable().context().discount_to_value_date(
+            credit_id, date, value_date)?;
+        if !(df > 0.0) {
+            return Err(qm::Error::new(&format!("Cannot compound forward to {} \
+                with a discount factor of {}", date, df)))
+        }
+        Ok(df)
+    }
+
+    /// Returns the gamma to the spot of the given underlying, calculated by
+    /// bumping spot up and down by the relative bumpsize. The pricer is left
+    /// unchanged. See second_order_bump.
+    fn gamma(&mut self, id: &str, bumpsize: f64) -> Result<f64, qm::Error> {
+        second_order_bump(self, id, bumpsize)
+    }
+
+    /// Reprices off fresh market data with the same spot date, such as an
+    /// intraday update, without collecting the dependencies of the
+    /// instruments again. Any bumps are discarded. It is an error if the
+    /// market data does not supply everything the instruments need, or has a
+    /// different spot date, in which case the pricer is unchanged. Rolling
+    /// to a new spot date can change the fixings and dependencies, so needs
+    /// a new pricer. By default, pricers do not support this, and must be
+    /// created afresh.
+    fn refresh_market_data(&mut self, _market_data: &MarketData)
+        -> Result<(), qm::Error> {
+        Err(qm::Error::new("This pricer cannot refresh its market data"))
+    }
 }
 
 /// For some reason that I do not understand, the rust compiler runs into an
@@ -114,6 +256,11 @@ pub trait Pricer : Bumpable + TimeBumpable + PricerClone {
 /// same as clone_box is implemented elsewhere. Thus you need to implement
 /// this manually in each pricer.
 pub trait PricerClone {
+    /// Makes an independent copy of the pricer, including any cached paths.
+    /// The copy shares only immutable market data with the original, as
+    /// bumps replace curves and surfaces rather than modifying them, and it
+    /// makes its own saveables for save and restore. It can therefore be
+    /// moved to another thread and bumped concurrently with the original.
     fn clone_box(&self) -> Box<Pricer>;
 }
 
@@ -130,6 +277,72 @@ pub trait Saveable : Any {
     fn clear(&mut self);
 }
 
+/// Save space for a bumpable built from several sub-models, such as a model
+/// with market data, paths of assets and a short rate. Each sub-model saves
+/// its state in a child saveable, keyed by the name of the sub-model. The
+/// children are only created when a bump first touches their sub-model, so
+/// the composite holds just the state that was affected, and a restore
+/// leaves the other sub-models alone.
+pub struct CompositeSaveable {
+    children: BTreeMap<String, Box<Saveable>>
+}
+
+impl CompositeSaveable {
+    pub fn new() -> CompositeSaveable {
+        CompositeSaveable { children: BTreeMap::new() }
+    }
+
+    /// The names of the sub-models that have saved state, in order
+    pub fn keys(&self) -> Vec<&str> {
+        self.children.keys().map(|key| key.as_str()).collect()
+    }
+
+    /// The saved state of the given sub-model, if a bump has touched it
+    pub fn child(&self, key: &str) -> Option<&Saveable> {
+        self.children.get(key).map(|child| &**child)
+    }
+
+    /// The saved state of the given sub-model, creating it if this is the
+    /// first bump to touch it
+    pub fn child_mut<F>(&mut self, key: &str, create: F) -> &mut Saveable
+        where F: FnOnce() -> Box<Saveable> {
+        &mut **self.children.entry(key.to_string()).or_insert_with(create)
+    }
+
+    /// As child, but converted to the concrete type of the sub-model's save
+    /// space. It is an error if the child is of a different type.
+    pub fn typed_child<T: Saveable>(&self, key: &str)
+        -> Result<Option<&T>, qm::Error> {
+        match self.child(key) {
+            Some(child) => child.as_any().downcast_ref::<T>()
+                .map(Some).ok_or_else(|| mismatching_child(key)),
+            None => Ok(None)
+        }
+    }
+
+    /// As child_mut, but converted to the concrete type of the sub-model's
+    /// save space. It is an error if the child is of a different type.
+    pub fn typed_child_mut<T: Saveable, F>(&mut self, key: &str, create: F)
+        -> Result<&mut T, qm::Error> where F: FnOnce() -> T {
+        self.child_mut(key, || Box::new(create()) as Box<Saveable>).as_mut_any()
+            .downcast_mut::<T>().ok_or_else(|| mismatching_child(key))
+    }
+}
+
+fn mismatching_child(key: &str) -> qm::Error {
+    qm::Error::new(&format!("Mismatching save space for '{}'", key))
+}
+
+impl Saveable for CompositeSaveable {
+    fn as_any(&self) -> &Any { self }
+    fn as_mut_any(&mut self) -> &mut Any { self }
+
+    /// Drops all the children, so that a restore touches no sub-model
+    fn clear(&mut self) {
+        self.children.clear();
+    }
+}
+
 /// A report is the result of a set of calculations, normally with bumped
 /// time and or market data. For example, a delta-gamma report shows the
 /// first and second differentials to all applicable underliers.
@@ -178,6 +391,37 @@ pub trait ReportGenerator : esd::Serialize + TypeId + Sync + Send + Debug {
     /// and is left empty on exit, unless documented otherwise.
     fn generate(&self, pricer: &mut Pricer, saveable: &mut Saveable, unbumped: f64)
         -> Result<BoxReport, qm::Error>;
+
+    /// As generate, but looking up and storing bumped prices in a cache
+    /// shared by all the generators in a report session, so that bumped
+    /// states needed by more than one report are only priced once. The
+    /// default implementation does not use the cache. As it bumps and
+    /// restores the pricer itself, the cache is invalidated afterwards.
+    fn generate_cached(&self, pricer: &mut Pricer, saveable: &mut Saveable,
+        unbumped: f64, cache: &mut BumpedPriceCache) -> Result<BoxReport, qm::Error> {
+        let report = self.generate(pricer, saveable, unbumped);
+        cache.invalidate();
+        report
+    }
+}
+
+/// Runs a set of report generators as a single session, returning the
+/// reports in the same order as the generators. The generators share a
+/// cache of bumped prices, so bumped states needed by more than one report,
+/// such as the vol bump up used by both vega and volga, are only priced
+/// once. The pricer must be in the state that gives the unbumped price, and
+/// is left in that state.
+pub fn generate_reports(pricer: &mut Pricer, report_generators: &[RcReportGenerator],
+    unbumped: f64) -> Result<Vec<BoxReport>, qm::Error> {
+
+    let mut saveable = pricer.as_bumpable().new_saveable();
+    let mut cache = BumpedPriceCache::new();
+    let mut reports = Vec::with_capacity(report_generators.len());
+    for report_generator in report_generators.iter() {
+        reports.push(report_generator.generate_cached(pricer, &mut *saveable,
+            unbumped, &mut cache)?);
+    }
+    Ok(reports)
 }
 
 // Get serialization to work recursively for report generators by using the
@@ -201,7 +445,13 @@ pub fn get_generator_registry() -> &'static GeneratorTypeRegistry {
             let mut reg = GeneratorTypeRegistry::new();
             reg.insert("DeltaGammaReportGenerator", BoxFnSeed::new(DeltaGammaReportGenerator::from_serial));
             reg.insert("VegaVolgaReportGenerator", BoxFnSeed::new(VegaVolgaReportGenerator::from_serial));
+            reg.insert("VannaVolgaReportGenerator", BoxFnSeed::new(VannaVolgaReportGenerator::from_serial));
+            reg.insert("VegaLadderReportGenerator", BoxFnSeed::new(VegaLadderReportGenerator::from_serial));
             reg.insert("TimeBumpedReportGenerator", BoxFnSeed::new(TimeBumpedReportGenerator::from_serial));
+            reg.insert("RhoReportGenerator", BoxFnSeed::new(RhoReportGenerator::from_serial));
+            reg.insert("CegaReportGenerator", BoxFnSeed::new(CegaReportGenerator::from_serial));
+            reg.insert("RhoLadderReportGenerator", BoxFnSeed::new(RhoLadderReportGenerator::from_serial));
+            reg.insert("SpotLadderReportGenerator", BoxFnSeed::new(SpotLadderReportGenerator::from_serial));
             reg
         };
     }
@@ -256,7 +506,13 @@ pub fn get_report_registry() -> &'static ReportTypeRegistry {
             let mut reg = ReportTypeRegistry::new();
             reg.insert("DeltaGammaReport", BoxFnSeed::new(DeltaGammaReport::from_serial));
             reg.insert("VegaVolgaReport", BoxFnSeed::new(VegaVolgaReport::from_serial));
+            reg.insert("VannaVolgaReport", BoxFnSeed::new(VannaVolgaReport::from_serial));
+            reg.insert("VegaLadderReport", BoxFnSeed::new(VegaLadderReport::from_serial));
             reg.insert("TimeBumpedReport", BoxFnSeed::new(TimeBumpedReport::from_serial));
+            reg.insert("RhoReport", BoxFnSeed::new(RhoReport::from_serial));
+            reg.insert("CegaReport", BoxFnSeed::new(CegaReport::from_serial));
+            reg.insert("RhoLadderReport", BoxFnSeed::new(RhoLadderReport::from_serial));
+            reg.insert("SpotLadderReport", BoxFnSeed::new(SpotLadderReport::from_serial));
             reg
         };
     }
@@ -273,4 +529,408 @@ pub fn bumped_price(bump: &Bump, pricer: &mut Pricer, saveable: Option<&mut Save
     } else {
         Ok(unbumped)
     }
-}
\ No newline at end of file
+}
+
+#[derive(Serialize)]
+struct CheckpointRef<'a, T: 'a> {
+    spot_date: Date,
+    state: &'a T
+}
+
+#[derive(Deserialize)]
+struct Checkpoint<T> {
+    spot_date: Date,
+    state: T
+}
+
+/// Serializes a state for Bumpable::checkpoint, tagged with the spot date
+/// it was taken on.
+pub fn write_checkpoint<T: sd::Serialize>(spot_date: Date, state: &T)
+    -> Result<Vec<u8>, qm::Error> {
+    Ok(serde_json::to_vec(&CheckpointRef { spot_date: spot_date, state: state })?)
+}
+
+/// Deserializes a state written by write_checkpoint. It is an error if it
+/// was taken on a different spot date.
+pub fn read_checkpoint<T: sd::de::DeserializeOwned>(spot_date: Date, checkpoint: &[u8])
+    -> Result<T, qm::Error> {
+
+    let checkpoint: Checkpoint<T> = serde_json::from_slice(checkpoint)?;
+    if checkpoint.spot_date != spot_date {
+        return Err(qm::Error::new(&format!("Checkpoint was taken on spot date {}, \
+            which does not match the spot date {}", checkpoint.spot_date, spot_date)))
+    }
+    Ok(checkpoint.state)
+}
+
+/// The relative tolerance within which a restored pricer must reproduce its
+/// unbumped price
+const RESTORE_TOLERANCE: f64 = 1e-12;
+
+/// Restores a pricer after a bump. If the crate is built with the
+/// `restore-check` feature, the pricer is then repriced, and it is an error
+/// if the price does not match the unbumped price. This catches incomplete
+/// restores, which would otherwise silently corrupt any subsequent risks. The
+/// check doubles the cost of restoring, so it is off by default.
+pub fn restore_and_verify<P: Pricer + ?Sized>(pricer: &mut P, saveable: &Saveable,
+    unbumped: f64) -> Result<(), qm::Error> {
+
+    pricer.as_mut_bumpable().restore(saveable)?;
+    if cfg!(feature = "restore-check") {
+        verify_restored(pricer, unbumped)?;
+    }
+    Ok(())
+}
+
+/// Reprices a pricer that should have been restored, returning an error if
+/// the price does not match the unbumped price.
+pub fn verify_restored<P: Pricer + ?Sized>(pricer: &P, unbumped: f64)
+    -> Result<(), qm::Error> {
+
+    let restored = pricer.price()?;
+    let tolerance = RESTORE_TOLERANCE * unbumped.abs().max(1.0);
+    if (restored - unbumped).abs() > tolerance {
+        return Err(qm::Error::new(&format!("Pricer was not fully restored \
+            after a bump: price {} does not match unbumped price {}",
+            restored, unbumped)))
+    }
+    Ok(())
+}
+
+/// Calculates gamma, the second differential of the price with respect to
+/// the spot of the given underlying, by central finite differences. Spot is
+/// bumped up and down by the same relative bumpsize, restoring the pricer
+/// after each bump, so that the pricer ends unchanged. It is an error if
+/// either bump has no effect, as the pricer does not depend on the spot.
+pub fn second_order_bump<P: Pricer + ?Sized>(pricer: &mut P, id: &str, bumpsize: f64)
+    -> Result<f64, qm::Error> {
+
+    if bumpsize <= 0.0 {
+        return Err(qm::Error::new("Gamma bumpsize must be positive"))
+    }
+
+    let unbumped = pricer.price()?;
+    let spot = pricer.as_bumpable().context().spot(id)?;
+    let mut save = pricer.as_bumpable().new_saveable();
+
+    let mut bumped = [0.0; 2];
+    for (bump_price, size) in bumped.iter_mut().zip([bumpsize, -bumpsize].iter()) {
+        let bump = Bump::new_spot(id, BumpSpot::new_relative(*size));
+        let applied = pricer.as_mut_bumpable().bump(&bump, Some(&mut *save))?;
+        let price = if applied { Some(pricer.price()) } else { None };
+        restore_and_verify(pricer, &*save, unbumped)?;
+        save.clear();
+        *bump_price = match price {
+            Some(price) => price?,
+            None => return Err(qm::Error::new(&format!(
+                "Spot bump of {} to {} had no effect", id, size)))
+        };
+    }
+
+    let absolute_bump = bumpsize * spot;
+    Ok((bumped[0] + bumped[1] - 2.0 * unbumped) / (absolute_bump * absolute_bump))
+}
+
+/// Calculates theta, the change in price from advancing the spot date by
+/// one business day in the given calendar. Fixings between the old and new
+/// spot dates are applied to the instruments, as for any BumpTime.
+///
+/// Under StickyForward dynamics, spot rolls up the forward, so all forwards
+/// after the new spot date are held fixed. The carry of the underlying is then
+/// already in the forwards and is not counted again through a move in spot,
+/// so theta is mainly the decay of optionality and the unwinding of
+/// discounting. Under StickySpot dynamics, spot is held fixed apart from any
+/// dividends going ex, so theta also includes the change in the forwards.
+///
+/// The pricer is bumped and restored via its save mechanism. If fixings
+/// over the day change the instruments, the pricer would need rebuilding,
+/// which cannot be restored, so in that case only the bump is applied to a
+/// clone. Either way, the pricer is left unchanged.
+pub fn theta<P: Pricer + ?Sized>(pricer: &mut P, calendar: &RcCalendar,
+    spot_dynamics: SpotDynamics) -> Result<f64, qm::Error> {
+
+    let unbumped = pricer.price()?;
+    let spot_date = pricer.as_bumpable().context().spot_date();
+    let theta_date = calendar.step(spot_date, 1, true);
+    let bump = BumpTime::new(theta_date, theta_date, spot_dynamics);
+
+    let mut save = pricer.as_bumpable().new_saveable();
+    if pricer.as_mut_time_bumpable().bump_time_saved(&bump, &mut *save)? {
+        let bumped = pricer.price();
+        restore_and_verify(pricer, &*save, unbumped)?;
+        return Ok(bumped? - unbumped)
+    }
+
+    let mut bumped = pricer.clone_box();
+    bumped.bump_time(&bump)?;
+    Ok(bumped.price()? - unbumped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::f64::consts::PI;
+    use risk::deltagamma::tests::sample_pricer;
+    use risk::marketdata::tests::sample_equity;
+    use risk::marketdata::tests::sample_currency;
+    use risk::marketdata::tests::create_sample_rate;
+    use instruments::Instrument;
+    use instruments::assets::RcCurrency;
+    use math::optionpricing::Black76;
+    use dates::Date;
+    use dates::datetime::DateDayFraction;
+    use dates::calendar::WeekdayCalendar;
+
+    #[test]
+    fn restore_guard_catches_incomplete_restore() {
+
+        let mut pricer = sample_pricer();
+        let unbumped = pricer.price().unwrap();
+        let bump = Bump::new_spot("BP.L", BumpSpot::new_relative(0.01));
+
+        // a proper restore passes the check
+        let mut save = pricer.as_bumpable().new_saveable();
+        assert!(pricer.as_mut_bumpable().bump(&bump, Some(&mut *save)).unwrap());
+        restore_and_verify(&mut *pricer, &*save, unbumped).unwrap();
+        verify_restored(&*pricer, unbumped).unwrap();
+
+        // Corrupt the saved state by clearing it after the bump, so the
+        // restore leaves the pricer bumped. The guard must catch this.
+        save.clear();
+        assert!(pricer.as_mut_bumpable().bump(&bump, Some(&mut *save)).unwrap());
+        save.clear();
+        let result = restore_and_verify(&mut *pricer, &*save, unbumped);
+        assert_eq!(result.is_err(), cfg!(feature = "restore-check"));
+        let err = verify_restored(&*pricer, unbumped).unwrap_err();
+        assert!(format!("{}", err).contains("not fully restored"),
+            "unexpected error: {}", err);
+    }
+
+    // A pricer whose saveable can be told to forget the state it saved,
+    // mocking a bumpable whose restore is incomplete
+    struct ForgetfulPricer {
+        pricer: Box<Pricer>
+    }
+
+    struct ForgetfulSaveable {
+        saved: Box<Saveable>,
+        forget: bool
+    }
+
+    impl Saveable for ForgetfulSaveable {
+        fn as_any(&self) -> &Any { self }
+        fn as_mut_any(&mut self) -> &mut Any { self }
+        fn clear(&mut self) { self.saved.clear(); }
+    }
+
+    impl Pricer for ForgetfulPricer {
+        fn as_bumpable(&self) -> &Bumpable { self }
+        fn as_mut_bumpable(&mut self) -> &mut Bumpable { self }
+        fn as_mut_time_bumpable(&mut self) -> &mut TimeBumpable { self }
+        fn price_report(&self) -> Result<PriceReport, qm::Error> {
+            self.pricer.price_report()
+        }
+    }
+
+    impl PricerClone for ForgetfulPricer {
+        fn clone_box(&self) -> Box<Pricer> {
+            Box::new(ForgetfulPricer { pricer: self.pricer.clone_box() })
+        }
+    }
+
+    impl Bumpable for ForgetfulPricer {
+        fn bump(&mut self, bump: &Bump, save: Option<&mut Saveable>) -> Result<bool, qm::Error> {
+            match save {
+                Some(save) => {
+                    let save = save.as_mut_any().downcast_mut::<ForgetfulSaveable>().unwrap();
+                    self.pricer.as_mut_bumpable().bump(bump, Some(&mut *save.saved))
+                },
+                None => self.pricer.as_mut_bumpable().bump(bump, None)
+            }
+        }
+        fn dependencies(&self) -> Result<&DependencyCollector, qm::Error> {
+            self.pricer.as_bumpable().dependencies()
+        }
+        fn context(&self) -> &PricingContext {
+            self.pricer.as_bumpable().context()
+        }
+        fn new_saveable(&self) -> Box<Saveable> {
+            Box::new(ForgetfulSaveable {
+                saved: self.pricer.as_bumpable().new_saveable(), forget: false })
+        }
+        fn restore(&mut self, saved: &Saveable) -> Result<(), qm::Error> {
+            let saved = saved.as_any().downcast_ref::<ForgetfulSaveable>().unwrap();
+            if saved.forget {
+                Ok(())
+            } else {
+                self.pricer.as_mut_bumpable().restore(&*saved.saved)
+            }
+        }
+    }
+
+    impl TimeBumpable for ForgetfulPricer {
+        fn bump_time(&mut self, bump: &BumpTime) -> Result<(), qm::Error> {
+            self.pricer.bump_time(bump)
+        }
+    }
+
+    #[test]
+    fn restore_guard_catches_mock_saveable_that_forgets() {
+
+        let mut pricer = ForgetfulPricer { pricer: sample_pricer() };
+        let unbumped = pricer.price().unwrap();
+        let bump = Bump::new_spot("BP.L", BumpSpot::new_relative(0.01));
+
+        // a saveable that remembers restores the pricer
+        let mut save = pricer.new_saveable();
+        assert!(pricer.bump(&bump, Some(&mut *save)).unwrap());
+        restore_and_verify(&mut pricer, &*save, unbumped).unwrap();
+        verify_restored(&pricer, unbumped).unwrap();
+
+        // one that forgets leaves the pricer bumped, which the guard catches
+        save.as_mut_any().downcast_mut::<ForgetfulSaveable>().unwrap().forget = true;
+        assert!(pricer.bump(&bump, Some(&mut *save)).unwrap());
+        let result = restore_and_verify(&mut pricer, &*save, unbumped);
+        assert_eq!(result.is_err(), cfg!(feature = "restore-check"));
+        let err = verify_restored(&pricer, unbumped).unwrap_err();
+        assert!(format!("{}", err).contains("not fully restored"),
+            "unexpected error: {}", err);
+    }
+
+    #[test]
+    fn forward_price_of_european() {
+
+        let pricer = sample_pricer();
+        let price = pricer.price().unwrap();
+
+        // compound forward to expiry on the option's own curve
+        let expiry = Date::from_ymd(2018, 06, 01);
+        let forward = pricer.price_forward("OPT", Some(expiry)).unwrap();
+        let df = pricer.forward_discount_factor("OPT", Some(expiry)).unwrap();
+        assert!(forward > price, "forward={} price={}", forward, price);
+        assert!((forward * df - price).abs() < 1e-12,
+            "forward={} df={} price={}", forward, df, price);
+        // discounted to the value date, two business days after spot
+        let value_date = Date::from_ymd(2017, 01, 04);
+        assert_eq!(pricer.as_bumpable().dependencies().unwrap()
+            .value_date("OPT"), Some(value_date));
+        let expected_df = create_sample_rate().df(expiry, value_date).unwrap();
+        assert!((df - expected_df).abs() < 1e-14, "df={} expected={}", df, expected_df);
+
+        // by default, compound to the expiry, not the payment date
+        let pay_date = Date::from_ymd(2018, 06, 05);
+        assert_eq!(pricer.price_forward("OPT", None).unwrap(), forward);
+        assert!(pricer.price_forward("OPT", Some(pay_date)).unwrap() != forward);
+
+        // the reference date must not be in the past, and there is no
+        // default for a curve that nothing discounts on
+        assert!(pricer.price_forward("OPT", Some(Date::from_ymd(2016, 12, 30))).is_err());
+        assert!(pricer.price_forward("GBP", None).is_err());
+    }
+
+    #[test]
+    fn gamma_european_matches_black_scholes() {
+
+        let mut pricer = sample_pricer();
+        let unbumped = pricer.price().unwrap();
+        let gamma = pricer.gamma("BP.L", 0.01).unwrap();
+        assert!(gamma > 0.0, "gamma={}", gamma);
+
+        // the pricer should be left unchanged
+        assert_eq!(pricer.price().unwrap(), unbumped);
+
+        // The sample European is priced by Black76 on the forward, which is
+        // linear in spot, though not with unit slope because of the
+        // dividends. Find the forward, its slope and the variance.
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        let equity = sample_equity(currency, 2);
+        let expiry = Date::from_ymd(2018, 06, 01);
+        let forward_at = |pricer: &Pricer| pricer.as_bumpable().context()
+            .forward_curve(&equity, expiry).unwrap().forward(expiry).unwrap();
+        let forward = forward_at(&*pricer);
+        let mut save = pricer.as_bumpable().new_saveable();
+        let bump = Bump::new_spot("BP.L", BumpSpot::new_relative(0.01));
+        assert!(pricer.as_mut_bumpable().bump(&bump, Some(&mut *save)).unwrap());
+        let slope = (forward_at(&*pricer) - forward) / (0.01 * 100.0);
+        pricer.as_mut_bumpable().restore(&*save).unwrap();
+
+        let context = pricer.as_bumpable().context();
+        let fwd = context.forward_curve(&equity, expiry).unwrap();
+        let vol = context.vol_surface(&equity, expiry, &|| Ok(fwd.clone())).unwrap();
+        let variance = vol.variance(DateDayFraction::new(expiry, 0.8), forward).unwrap();
+        let sqrt_variance = variance.sqrt();
+
+        // Back out the discount factor from the price, then apply the
+        // Black-Scholes gamma with respect to the forward
+        let strike = 100.0;
+        let df = unbumped / Black76::new().unwrap()
+            .call_price(1.0, forward, strike, sqrt_variance);
+        let d1 = (forward / strike).ln() / sqrt_variance + 0.5 * sqrt_variance;
+        let density = (-0.5 * d1 * d1).exp() / (2.0 * PI).sqrt();
+        let analytic = df * density / (forward * sqrt_variance) * slope * slope;
+        assert!((gamma - analytic).abs() < 1e-5,
+            "gamma={} analytic={}", gamma, analytic);
+
+        // the free function gives the same result with a smaller bump
+        let fine_gamma = second_order_bump(&mut *pricer, "BP.L", 0.001).unwrap();
+        assert!((fine_gamma - analytic).abs() < 1e-5,
+            "fine_gamma={} analytic={}", fine_gamma, analytic);
+    }
+
+    #[test]
+    fn gamma_errors_if_spot_not_used() {
+        let mut pricer = sample_pricer();
+        assert!(pricer.gamma("GSK.L", 0.01).is_err());
+        assert!(pricer.gamma("BP.L", 0.0).is_err());
+    }
+
+    #[test]
+    fn theta_european_sticky_forward() {
+        let mut pricer = sample_pricer();
+        let unbumped = pricer.price().unwrap();
+        let calendar = RcCalendar::new(Arc::new(WeekdayCalendar::new()));
+
+        // The spot date is a Monday, so this matches a one day time bump.
+        // Theta is small and negative, as the option loses time value.
+        let sticky_forward = theta(&mut *pricer, &calendar, SpotDynamics::StickyForward).unwrap();
+        assert!(sticky_forward < 0.0 && sticky_forward > -0.1, "theta={}", sticky_forward);
+        assert!((sticky_forward - -0.014051516972845235).abs() < 1e-12,
+            "theta={}", sticky_forward);
+
+        // sticky spot gives a different answer, as the forwards move
+        let sticky_spot = theta(&mut *pricer, &calendar, SpotDynamics::StickySpot).unwrap();
+        assert!((sticky_spot - sticky_forward).abs() > 1e-6,
+            "sticky_spot={} sticky_forward={}", sticky_spot, sticky_forward);
+
+        // the pricer should be restored to the original spot date
+        assert_eq!(pricer.price().unwrap(), unbumped);
+        assert_eq!(pricer.as_bumpable().context().spot_date(),
+            Date::from_ymd(2017, 01, 02));
+    }
+
+    #[test]
+    fn theta_saved_matches_theta_on_a_clone() {
+        let mut pricer = sample_pricer();
+        let unbumped = pricer.price().unwrap();
+        let calendar = RcCalendar::new(Arc::new(WeekdayCalendar::new()));
+        let theta_date = calendar.step(Date::from_ymd(2017, 01, 02), 1, true);
+        let bump = BumpTime::new(theta_date, theta_date, SpotDynamics::StickySpot);
+
+        let mut cloned = pricer.clone_box();
+        cloned.bump_time(&bump).unwrap();
+        let expected = cloned.price().unwrap() - unbumped;
+
+        // a spot bump saved before the time bump must be restored too
+        let spot = Bump::new_spot("BP.L", BumpSpot::new_relative(0.01));
+        let mut save = pricer.as_bumpable().new_saveable();
+        assert!(pricer.as_mut_bumpable().bump(&spot, Some(&mut *save)).unwrap());
+        assert!(pricer.as_mut_time_bumpable().bump_time_saved(&bump, &mut *save).unwrap());
+        pricer.as_mut_bumpable().restore(&*save).unwrap();
+        assert_eq!(pricer.price().unwrap(), unbumped);
+
+        let saved = theta(&mut *pricer, &calendar, SpotDynamics::StickySpot).unwrap();
+        assert_eq!(saved, expected);
+        assert_eq!(pricer.price().unwrap(), unbumped);
+    }
+}
@@ -0,0 +1,254 @@
+use core::qm;
+use instruments::RcInstrument;
+use instruments::PricingContext;
+use pricers::PricerFactory;
+use data::bump::Bump;
+use data::bumpvol::BumpVol;
+use data::bumpspotdate::SpotDynamics;
+use data::forward::Forward;
+use data::fixings::RcFixingTable;
+use dates::Date;
+use dates::datetime::DateTime;
+use dates::datetime::TimeOfDay;
+use risk::Pricer;
+use risk::ReportGenerator;
+use risk::restore_and_verify;
+use risk::deltagamma::{DeltaGammaReportGenerator, DeltaGammaReport};
+use risk::bumptime::BumpTime;
+use risk::marketdata::MarketData;
+use risk::marketdata::RcMarketData;
+
+/// Relative size of the spot bumps used for delta and gamma
+const SPOT_BUMPSIZE: f64 = 0.01;
+
+/// Additive size of the flat vol bumps used for vega
+const VOL_BUMPSIZE: f64 = 0.01;
+
+/// The change in price between two markets, broken down into the parts
+/// explained by a Taylor expansion in the greeks of the old market, and an
+/// unexplained residual.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PnlExplain {
+    old_price: f64,
+    new_price: f64,
+    delta: f64,
+    gamma: f64,
+    vega: f64,
+    theta: f64
+}
+
+impl PnlExplain {
+    pub fn old_price(&self) -> f64 { self.old_price }
+    pub fn new_price(&self) -> f64 { self.new_price }
+
+    /// The first order contribution of the spot moves, delta times the move
+    /// in spot, summed over all the underlyings
+    pub fn delta(&self) -> f64 { self.delta }
+
+    /// The second order contribution of the spot moves, half gamma times the
+    /// square of the move in spot, summed over all the underlyings
+    pub fn gamma(&self) -> f64 { self.gamma }
+
+    /// The contribution of the vol moves, vega times the move in at the money
+    /// vol, summed over all the underlyings
+    pub fn vega(&self) -> f64 { self.vega }
+
+    /// The change in price from moving to the new spot date, with all other
+    /// market data unchanged
+    pub fn theta(&self) -> f64 { self.theta }
+
+    /// The actual change in price
+    pub fn actual(&self) -> f64 { self.new_price - self.old_price }
+
+    /// The change in price explained by the greeks
+    pub fn explained(&self) -> f64 {
+        self.delta + self.gamma + self.vega + self.theta
+    }
+
+    /// The change in price that is not explained by the greeks, for example
+    /// because of rate moves, cross gammas or higher order terms
+    pub fn residual(&self) -> f64 { self.actual() - self.explained() }
+}
+
+/// Explains the change in price of an instrument between an old and a new
+/// market. The greeks are calculated in the old market, by bumping spot by
+/// one percent relative, as in the delta-gamma report, and vol by one
+/// percent flat, then multiplied by the moves between the markets: the
+/// change in spot of each underlying, and the change in its vol at the money
+/// forward at the high water mark of its vol surface. Theta is the change in price from rolling the old market to the
+/// spot date of the new one, with spot held fixed, so that it does not
+/// overlap with delta.
+pub fn pnl_explain(pricer_factory: &PricerFactory, instrument: &RcInstrument,
+    fixing_table: &RcFixingTable, old_market: &RcMarketData,
+    new_market: &RcMarketData) -> Result<PnlExplain, qm::Error> {
+
+    let new_price = pricer_factory.new(instrument.clone(), fixing_table.clone(),
+        new_market.clone())?.price()?;
+    let mut pricer = pricer_factory.new(instrument.clone(), fixing_table.clone(),
+        old_market.clone())?;
+    let old_price = pricer.price()?;
+
+    // find the underlyings and their vol surfaces before bumping anything
+    let (spots, vols) = {
+        let dependencies = pricer.as_bumpable().dependencies()?;
+        let spots: Vec<RcInstrument> = dependencies.instruments_iter()
+            .map(|(_, underlying)| underlying.clone())
+            .filter(|underlying| dependencies.has_spot(underlying))
+            .collect();
+        let vols: Vec<(RcInstrument, Date)> = dependencies.vol_surfaces().iter()
+            .map(|(underlying, hwm)| (underlying.clone(), *hwm))
+            .collect();
+        (spots, vols)
+    };
+
+    // delta and gamma come from the delta-gamma report, which is only
+    // calculated if any spot has moved
+    let mut spot_moves = Vec::new();
+    for underlying in spots.iter() {
+        let id = underlying.id();
+        let spot_move = new_market.spot(id)? - old_market.spot(id)?;
+        if spot_move != 0.0 {
+            spot_moves.push((id.to_string(), spot_move));
+        }
+    }
+
+    let mut delta = 0.0;
+    let mut gamma = 0.0;
+    if !spot_moves.is_empty() {
+        let mut save = pricer.as_bumpable().new_saveable();
+        let report = DeltaGammaReportGenerator::new(SPOT_BUMPSIZE)
+            .generate(&mut *pricer, &mut *save, old_price)?;
+        let results = report.as_any().downcast_ref::<DeltaGammaReport>()
+            .ok_or_else(|| qm::Error::new("Expected a delta-gamma report"))?
+            .results();
+        for &(ref id, spot_move) in spot_moves.iter() {
+            let greeks = results.get(id).ok_or_else(|| qm::Error::new(
+                &format!("No delta or gamma for '{}'", id)))?;
+            delta += spot_move * greeks.delta();
+            gamma += 0.5 * spot_move * spot_move * greeks.gamma();
+        }
+    }
+
+    let mut vega = 0.0;
+    for &(ref underlying, hwm) in vols.iter() {
+        let vol_move = atm_vol(new_market, underlying, hwm)?
+            - atm_vol(old_market, underlying, hwm)?;
+        if vol_move == 0.0 {
+            continue
+        }
+        let id = underlying.id();
+        let (up, down) = up_and_down(&mut *pricer, old_price,
+            &|size| Bump::new_vol(id, BumpVol::new_flat_additive(size)),
+            VOL_BUMPSIZE)?;
+        vega += vol_move * (up - down) / (2.0 * VOL_BUMPSIZE);
+    }
+
+    // A time bump cannot be restored, so apply it to a clone
+    let new_date = new_market.spot_date();
+    let theta = if new_date > old_market.spot_date() {
+        let mut rolled = pricer.clone_box();
+        rolled.bump_time(&BumpTime::new(new_date, new_date,
+            SpotDynamics::StickySpot))?;
+        rolled.price()? - old_price
+    } else {
+        0.0
+    };
+
+    Ok(PnlExplain { old_price, new_price, delta, gamma, vega, theta })
+}
+
+/// Prices with the bump created by the given function applied up and down
+/// by the given size, restoring the pricer after each
+fn up_and_down(pricer: &mut Pricer, unbumped: f64, bump: &Fn(f64) -> Bump,
+    size: f64) -> Result<(f64, f64), qm::Error> {
+
+    let mut save = pricer.as_bumpable().new_saveable();
+    let mut prices = [unbumped; 2];
+    for (price, size) in prices.iter_mut().zip([size, -size].iter()) {
+        if pricer.as_mut_bumpable().bump(&bump(*size), Some(&mut *save))? {
+            let bumped = pricer.price();
+            restore_and_verify(pricer, &*save, unbumped)?;
+            save.clear();
+            *price = bumped?;
+        }
+    }
+    Ok((prices[0], prices[1]))
+}
+
+/// The vol of the underlying at the close on the given date, struck at the
+/// forward on that date
+fn atm_vol(market: &MarketData, underlying: &RcInstrument, date: Date)
+    -> Result<f64, qm::Error> {
+
+    let forward = market.forward_curve(&**underlying, date)?;
+    let strike = forward.forward(date)?;
+    let vol = market.vol_surface(&**underlying, date, &|| Ok(forward.clone()))?;
+    let time = underlying.time_to_day_fraction(DateTime::new(date, TimeOfDay::Close))?;
+    let vol_time = vol.vol_time(time)?;
+    if vol_time <= 0.0 {
+        return Ok(0.0)
+    }
+    Ok((vol.variance(time, strike)? / vol_time).sqrt())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use math::numerics::approx_eq;
+    use core::factories::Qrc;
+    use data::fixings::FixingTable;
+    use risk::Bumpable;
+    use data::bumpspot::BumpSpot;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::marketdata::tests::sample_european;
+    use pricers::selfpricer::SelfPricerFactory;
+
+    fn explain(new_market: MarketData) -> PnlExplain {
+        let old_market = sample_market_data();
+        let instrument = RcInstrument::new(Qrc::new(sample_european()));
+        let fixings = RcFixingTable::new(Arc::new(FixingTable::new(old_market.spot_date())));
+        pnl_explain(&SelfPricerFactory::new(), &instrument, &fixings,
+            &RcMarketData::new(Arc::new(old_market)),
+            &RcMarketData::new(Arc::new(new_market))).unwrap()
+    }
+
+    #[test]
+    fn spot_move_is_explained_by_delta_and_gamma() {
+
+        let mut new_market = sample_market_data();
+        let bump = Bump::new_spot("BP.L", BumpSpot::new_relative(0.005));
+        assert!(new_market.bump(&bump, None).unwrap());
+        let pnl = explain(new_market);
+
+        assert_approx(pnl.old_price(), 16.710717400832973, 1e-12);
+        assert!(pnl.actual() > 0.0, "actual={}", pnl.actual());
+        assert!(pnl.delta() > 0.9 * pnl.actual(), "delta={} actual={}",
+            pnl.delta(), pnl.actual());
+        assert!(pnl.gamma() > 0.0, "gamma={}", pnl.gamma());
+        assert_eq!(pnl.vega(), 0.0);
+        assert_eq!(pnl.theta(), 0.0);
+        assert!(pnl.residual().abs() < 1e-3 * pnl.actual(),
+            "residual={} actual={}", pnl.residual(), pnl.actual());
+    }
+
+    #[test]
+    fn vol_move_is_explained_by_vega() {
+
+        let mut new_market = sample_market_data();
+        let bump = Bump::new_vol("BP.L", BumpVol::new_flat_additive(0.005));
+        assert!(new_market.bump(&bump, None).unwrap());
+        let pnl = explain(new_market);
+
+        assert_eq!(pnl.delta(), 0.0);
+        assert_eq!(pnl.gamma(), 0.0);
+        assert!(pnl.vega() > 0.0, "vega={}", pnl.vega());
+        assert!(pnl.residual().abs() < 1e-3 * pnl.actual(),
+            "residual={} actual={}", pnl.residual(), pnl.actual());
+    }
+
+    fn assert_approx(value: f64, expected: f64, tolerance: f64) {
+        assert!(approx_eq(value, expected, tolerance),
+            "value={} expected={}", value, expected);
+    }
+}
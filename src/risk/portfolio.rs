@@ -0,0 +1,360 @@
+use std::any::Any;
+use std::sync::Arc;
+use core::qm;
+use instruments::RcInstrument;
+use instruments::PricingContext;
+use pricers::PricerFactory;
+use data::bump::Bump;
+use data::fixings::RcFixingTable;
+use risk::Pricer;
+use risk::PricerClone;
+use risk::Bumpable;
+use risk::TimeBumpable;
+use risk::Saveable;
+use risk::BoxReport;
+use risk::RcReportGenerator;
+use risk::BumpablePricingContext;
+use risk::generate_reports;
+use risk::bumptime::BumpTime;
+use risk::cache::PricingContextPrefetch;
+use risk::dependencies::DependencyCollector;
+use risk::marketdata::RcMarketData;
+use risk::pricereport::PriceReport;
+
+/// A book of weighted positions, each valued by its own pricer, but bumped
+/// as a whole, so that every bump is applied consistently to all of them.
+/// The portfolio is itself a pricer, whose price report breaks the total
+/// down by position, so any report generator can be run on the whole book.
+/// Use reports to break the risks down by position as well.
+#[derive(Clone)]
+pub struct Portfolio {
+    positions: Vec<Position>,
+    context: PricingContextPrefetch
+}
+
+struct Position {
+    id: String,
+    weight: f64,
+    pricer: Box<Pricer>
+}
+
+impl Clone for Position {
+    fn clone(&self) -> Position {
+        Position { id: self.id.clone(), weight: self.weight,
+            pricer: self.pricer.clone_box() }
+    }
+}
+
+impl Portfolio {
+    /// Creates a portfolio of the given weighted instruments, each of which
+    /// is fixed and priced by a pricer from the given factory.
+    pub fn new(positions: &[(f64, RcInstrument)], pricer_factory: &PricerFactory,
+        fixing_table: RcFixingTable, market_data: RcMarketData)
+        -> Result<Portfolio, qm::Error> {
+
+        let mut dependencies = DependencyCollector::new(market_data.spot_date());
+        let mut priced = Vec::with_capacity(positions.len());
+        for &(weight, ref instrument) in positions.iter() {
+            dependencies.spot(instrument);
+            let pricer = pricer_factory.new(instrument.clone(),
+                fixing_table.clone(), market_data.clone())?;
+            priced.push(Position { id: instrument.id().to_string(), weight, pricer });
+        }
+
+        // The book has a context of its own, covering all the positions, so
+        // that bumps sized from the market data see the same data as the
+        // positions do
+        let context = PricingContextPrefetch::new(&*market_data,
+            Arc::new(dependencies))?;
+
+        Ok(Portfolio { positions: priced, context })
+    }
+
+    /// Runs the report generators on the book as a whole, then on each
+    /// position on its own. The prices and reports of the positions are per
+    /// unit, before weighting, so the risks of the book are the weighted
+    /// sums of those of the positions. The portfolio is left unbumped.
+    pub fn reports(&mut self, report_generators: &[RcReportGenerator])
+        -> Result<PortfolioReports, qm::Error> {
+
+        let price = self.price()?;
+        let total = generate_reports(self, report_generators, price)?;
+
+        let mut positions = Vec::with_capacity(self.positions.len());
+        for position in self.positions.iter_mut() {
+            let unbumped = position.pricer.price()?;
+            let reports = generate_reports(&mut *position.pricer,
+                report_generators, unbumped)?;
+            positions.push(PositionReports { id: position.id.clone(),
+                weight: position.weight, price: unbumped, reports });
+        }
+
+        Ok(PortfolioReports { price, total, positions })
+    }
+}
+
+/// The risks of a portfolio, both in total and by position
+pub struct PortfolioReports {
+    price: f64,
+    total: Vec<BoxReport>,
+    positions: Vec<PositionReports>
+}
+
+impl PortfolioReports {
+    /// The weighted price of the whole book
+    pub fn price(&self) -> f64 { self.price }
+
+    /// The reports for the whole book, in the order of the generators
+    pub fn total(&self) -> &[BoxReport] { &self.total }
+
+    /// The prices and reports of each position, in the order the positions
+    /// were supplied
+    pub fn positions(&self) -> &[PositionReports] { &self.positions }
+}
+
+/// The unweighted price and risks of a single position in a portfolio
+pub struct PositionReports {
+    id: String,
+    weight: f64,
+    price: f64,
+    reports: Vec<BoxReport>
+}
+
+impl PositionReports {
+    pub fn id(&self) -> &str { &self.id }
+    pub fn weight(&self) -> f64 { self.weight }
+
+    /// The price of one unit of the position
+    pub fn price(&self) -> f64 { self.price }
+
+    /// The reports for one unit of the position, in the order of the
+    /// generators
+    pub fn reports(&self) -> &[BoxReport] { &self.reports }
+}
+
+impl Pricer for Portfolio {
+    fn as_bumpable(&self) -> &Bumpable { self }
+    fn as_mut_bumpable(&mut self) -> &mut Bumpable { self }
+    fn as_mut_time_bumpable(&mut self) -> &mut TimeBumpable { self }
+
+    fn price_report(&self) -> Result<PriceReport, qm::Error> {
+        let mut report = PriceReport::new();
+        for position in self.positions.iter() {
+            report.add(&position.id, position.weight * position.pricer.price()?);
+        }
+        Ok(report)
+    }
+}
+
+impl PricerClone for Portfolio {
+    fn clone_box(&self) -> Box<Pricer> { Box::new(self.clone()) }
+}
+
+impl Bumpable for Portfolio {
+    fn bump(&mut self, bump: &Bump, any_saved: Option<&mut Saveable>)
+        -> Result<bool, qm::Error> {
+
+        // unpack the saveable into its components all at once, to avoid
+        // problems with borrowing
+        let (saved_context, saved_positions) = match to_saved(any_saved)? {
+            Some(s) => (Some(&mut *s.context), Some(&mut s.positions)),
+            None => (None, None)
+        };
+
+        // the book's own context is bumped to keep it in step, but it does
+        // not contribute to the price
+        self.context.bump(bump, saved_context)?;
+
+        let mut bumped = false;
+        if let Some(saves) = saved_positions {
+            for (position, save) in self.positions.iter_mut().zip(saves.iter_mut()) {
+                bumped |= position.pricer.as_mut_bumpable().bump(bump, Some(&mut **save))?;
+            }
+        } else {
+            for position in self.positions.iter_mut() {
+                bumped |= position.pricer.as_mut_bumpable().bump(bump, None)?;
+            }
+        }
+        Ok(bumped)
+    }
+
+    fn dependencies(&self) -> Result<&DependencyCollector, qm::Error> {
+        self.context.dependencies()
+    }
+
+    fn context(&self) -> &PricingContext {
+        self.context.as_pricing_context()
+    }
+
+    fn new_saveable(&self) -> Box<Saveable> {
+        Box::new(SavedPortfolio {
+            context: self.context.new_saveable(),
+            positions: self.positions.iter()
+                .map(|p| p.pricer.as_bumpable().new_saveable()).collect() })
+    }
+
+    fn restore(&mut self, any_saved: &Saveable) -> Result<(), qm::Error> {
+
+        if let Some(saved) = any_saved.as_any().downcast_ref::<SavedPortfolio>() {
+            self.context.restore(&*saved.context)?;
+            for (position, save) in self.positions.iter_mut().zip(saved.positions.iter()) {
+                position.pricer.as_mut_bumpable().restore(&**save)?;
+            }
+            Ok(())
+        } else {
+            Err(qm::Error::new("Mismatching save space for restore"))
+        }
+    }
+}
+
+impl TimeBumpable for Portfolio {
+    fn bump_time(&mut self, bump: &BumpTime) -> Result<(), qm::Error> {
+        for position in self.positions.iter_mut() {
+            position.pricer.bump_time(bump)?;
+        }
+
+        // the book's context prices no instruments, so only needs to move
+        // its spot date
+        bump.apply(&mut Vec::new(), &mut self.context)?;
+        Ok(())
+    }
+}
+
+/// Save space for a portfolio, with one saveable per position
+struct SavedPortfolio {
+    context: Box<Saveable>,
+    positions: Vec<Box<Saveable>>
+}
+
+impl Saveable for SavedPortfolio {
+    fn as_any(&self) -> &Any { self }
+    fn as_mut_any(&mut self) -> &mut Any { self }
+
+    fn clear(&mut self) {
+        self.context.clear();
+        for save in self.positions.iter_mut() {
+            save.clear();
+        }
+    }
+}
+
+fn to_saved(opt_saveable: Option<&mut Saveable>)
+    -> Result<Option<&mut SavedPortfolio>, qm::Error> {
+
+    if let Some(saveable) = opt_saveable {
+        if let Some(saved) = saveable.as_mut_any().downcast_mut::<SavedPortfolio>() {
+            Ok(Some(saved))
+        } else {
+            Err(qm::Error::new("Mismatching save space for portfolio"))
+        }
+    } else {
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+    use core::factories::Qrc;
+    use dates::Date;
+    use dates::datetime::DateTime;
+    use dates::datetime::TimeOfDay;
+    use data::bumpspot::BumpSpot;
+    use data::bumpvol::BumpVol;
+    use data::fixings::FixingTable;
+    use instruments::assets::RcCurrency;
+    use instruments::options::SpotStartingEuropean;
+    use instruments::options::PutOrCall;
+    use instruments::options::OptionSettlement;
+    use pricers::selfpricer::SelfPricerFactory;
+    use risk::deltagamma::DeltaGammaReportGenerator;
+    use risk::deltagamma::DeltaGammaReport;
+    use risk::vegavolga::VegaVolgaReportGenerator;
+    use risk::vegavolga::VegaVolgaReport;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::marketdata::tests::sample_european;
+    use risk::marketdata::tests::sample_currency;
+    use risk::marketdata::tests::sample_settlement;
+    use risk::marketdata::tests::sample_equity;
+
+    fn sample_portfolio() -> Portfolio {
+        let market_data = sample_market_data();
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        let equity = RcInstrument::new(Qrc::new(Arc::new(sample_equity(currency, 2))));
+        let put = SpotStartingEuropean::new("SamplePut", "OPT", equity,
+            sample_settlement(2), DateTime::new(Date::from_ymd(2018, 06, 01),
+            TimeOfDay::Close), 90.0, PutOrCall::Put, OptionSettlement::Cash).unwrap();
+        let positions = vec![
+            (2.0, RcInstrument::new(Qrc::new(sample_european()))),
+            (-3.0, RcInstrument::new(Qrc::new(Arc::new(put))))];
+
+        let fixings = RcFixingTable::new(Arc::new(FixingTable::new(market_data.spot_date())));
+        Portfolio::new(&positions, &SelfPricerFactory::new(), fixings,
+            RcMarketData::new(Arc::new(market_data))).unwrap()
+    }
+
+    #[test]
+    fn portfolio_greeks_are_weighted_sums_of_positions() {
+
+        let mut portfolio = sample_portfolio();
+        let generators = vec![
+            RcReportGenerator::new(Arc::new(DeltaGammaReportGenerator::new(0.01))),
+            RcReportGenerator::new(Arc::new(VegaVolgaReportGenerator::new(
+                BumpVol::new_flat_additive(0.01))))];
+        let reports = portfolio.reports(&generators).unwrap();
+
+        let delta = |reports: &[BoxReport]| reports[0].as_any()
+            .downcast_ref::<DeltaGammaReport>().unwrap().results()
+            .get("BP.L").unwrap().delta();
+        let vega = |reports: &[BoxReport]| reports[1].as_any()
+            .downcast_ref::<VegaVolgaReport>().unwrap().results()
+            .get("BP.L").unwrap().vega();
+
+        assert_eq!(reports.positions().len(), 2);
+        assert_eq!(reports.positions()[0].id(), "SampleSpotEuropean");
+        assert_eq!(reports.positions()[1].id(), "SamplePut");
+        assert_approx(reports.positions()[0].price(), 16.710717400832973, 1e-12);
+
+        let mut price = 0.0;
+        let mut weighted_delta = 0.0;
+        let mut weighted_vega = 0.0;
+        for position in reports.positions().iter() {
+            price += position.weight() * position.price();
+            weighted_delta += position.weight() * delta(position.reports());
+            weighted_vega += position.weight() * vega(position.reports());
+        }
+        assert_approx(reports.price(), price, 1e-12);
+        assert_approx(delta(reports.total()), weighted_delta, 1e-12);
+        assert_approx(vega(reports.total()), weighted_vega, 1e-12);
+
+        // the short put adds to the delta of the calls
+        assert!(delta(reports.positions()[1].reports()) < 0.0);
+        assert!(weighted_delta > delta(reports.positions()[0].reports()) * 2.0);
+
+        // the portfolio is left unbumped
+        assert_approx(portfolio.price().unwrap(), price, 1e-12);
+    }
+
+    #[test]
+    fn bumping_portfolio_bumps_every_position() {
+
+        let mut portfolio = sample_portfolio();
+        let unbumped = portfolio.price_report().unwrap();
+
+        let mut save = portfolio.new_saveable();
+        let bump = Bump::new_spot("BP.L", BumpSpot::new_relative(0.01));
+        assert!(portfolio.bump(&bump, Some(&mut *save)).unwrap());
+        let bumped = portfolio.price_report().unwrap();
+        assert!(bumped.components()[0].1 > unbumped.components()[0].1);
+        assert!(bumped.components()[1].1 > unbumped.components()[1].1);
+
+        portfolio.restore(&*save).unwrap();
+        assert_eq!(portfolio.price_report().unwrap(), unbumped);
+    }
+
+    fn assert_approx(value: f64, expected: f64, tolerance: f64) {
+        assert!(approx_eq(value, expected, tolerance),
+            "value={} expected={}", value, expected);
+    }
+}
@@ -0,0 +1,69 @@
+/// A price report shows the weighted total price of a pricer, together with
+/// the contribution of each of the instruments that make it up. For example,
+/// a structured product may fix into several legs, and it is useful to be
+/// able to see which leg contributes what, without repricing each leg
+/// separately.
+///
+/// Each contribution is the price of the component multiplied by its weight,
+/// so the components always sum to the total.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PriceReport {
+    total: f64,
+    components: Vec<(String, f64)>
+}
+
+impl PriceReport {
+    /// Creates an empty report, with a total of zero and no components.
+    pub fn new() -> PriceReport {
+        PriceReport { total: 0.0, components: Vec::new() }
+    }
+
+    /// Adds the contribution of a component instrument to the report. The
+    /// contribution should already have been multiplied by the weight.
+    pub fn add(&mut self, id: &str, contribution: f64) {
+        self.total += contribution;
+        self.components.push((id.to_string(), contribution));
+    }
+
+    /// The weighted sum of the prices of all the components
+    pub fn total(&self) -> f64 { self.total }
+
+    /// The id and weighted contribution of each component, in the order
+    /// they were added.
+    pub fn components(&self) -> &[(String, f64)] { &self.components }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+
+    #[test]
+    fn empty_price_report() {
+        let report = PriceReport::new();
+        assert_eq!(report.total(), 0.0);
+        assert!(report.components().is_empty());
+    }
+
+    #[test]
+    fn price_report_sums_components() {
+        let mut report = PriceReport::new();
+        report.add("Leg1", 10.0);
+        report.add("Leg2", -2.5);
+        assert_eq!(report.total(), 7.5);
+        assert_eq!(report.components(),
+            &[("Leg1".to_string(), 10.0), ("Leg2".to_string(), -2.5)]);
+    }
+
+    #[test]
+    fn price_report_serde_round_trip() {
+        let mut report = PriceReport::new();
+        report.add("Leg1", 10.0);
+
+        let serialized = serde_json::to_string(&report).unwrap();
+        assert_eq!(serialized, r#"{"total":10.0,"components":[["Leg1",10.0]]}"#);
+
+        let deserialized: PriceReport = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(deserialized, report);
+    }
+}
@@ -0,0 +1,253 @@
+use std::collections::HashMap;
+use std::any::Any;
+use std::sync::Arc;
+use std::fmt;
+use math::numerics::{ApproxEq, approx_eq};
+use risk::Report;
+use risk::BoxReport;
+use risk::ReportGenerator;
+use risk::Pricer;
+use risk::Saveable;
+use risk::bumped_price;
+use risk::restore_and_verify;
+use risk::ApproxEqReport;
+use risk::ReportTolerances;
+use data::bump::Bump;
+use data::bumpyield::BumpYield;
+use core::qm;
+use core::factories::TypeId;
+use core::factories::{Qrc, Qbox};
+use serde::Deserialize;
+use erased_serde as esd;
+
+/// Rho is the first derivative of price with respect to the discount rate.
+/// Yield curves are used both for discounting and for calculating the
+/// forwards of any assets in the same currency, so a plain yield bump
+/// mixes the two effects. Rho isolates the discounting effect, by bumping
+/// the yield curves only where they are used for discounting. This report
+/// shows the rho with respect to each of the yield curves, keyed by credit
+/// id, that affect the price.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RhoReport {
+    bumpsize: f64,
+    results: HashMap<String, f64>
+}
+
+impl Report for RhoReport {
+    fn as_any(&self) -> &Any { self }
+}
+
+impl TypeId for RhoReport {
+    fn get_type_id(&self) -> &'static str { "RhoReport" }
+}
+
+impl RhoReport {
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qbox<Report>, esd::Error> {
+        Ok(Qbox::new(Box::new(RhoReport::deserialize(de)?)))
+    }
+
+    pub fn results(&self) -> &HashMap<String, f64> { &self.results }
+}
+
+impl<'v> ApproxEq<ReportTolerances, &'v RhoReport> for &'v RhoReport {
+    fn validate(self, other: &'v RhoReport, tol: &ReportTolerances,
+        _msg: &str, diffs: &mut fmt::Formatter) -> fmt::Result {
+
+        if self.results.len() != other.results.len() {
+            write!(diffs, "RhoReport: number of reports {} != {}", self.results.len(), other.results.len())?;
+        }
+
+        // Rho is based on diffs, so should use the currency risk tolerance.
+        let tolerance = tol.currency_risk() / self.bumpsize;
+        for (id, rho) in &self.results {
+            if let Some(other_rho) = other.results.get(id) {
+                if !approx_eq(*rho, *other_rho, tolerance) {
+                    writeln!(diffs, "RhoReport: {} rho {} != {} tol={}", id, rho, other_rho, tolerance)?;
+                }
+            } else {
+                write!(diffs, "RhoReport: {} is missing", id)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl ApproxEqReport for RhoReport {
+    fn validate_report(&self, other: &Report, tol: &ReportTolerances,
+        msg: &str, diffs: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(other_report) = other.as_any().downcast_ref::<RhoReport>() {
+            self.validate(other_report, tol, msg, diffs)
+        } else {
+            write!(diffs, "RhoReport: mismatching report {} != {}", self.get_type_id(), other.get_type_id())?;
+            Ok(())
+        }
+    }
+}
+
+/// Calculator for rho by bumping. The bump size is specified as an absolute
+/// shift in the continuously compounded discount rate, and is applied up and
+/// down to give a central difference.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RhoReportGenerator {
+    bumpsize: f64
+}
+
+impl RhoReportGenerator {
+    pub fn new(bumpsize: f64) -> RhoReportGenerator {
+        RhoReportGenerator { bumpsize: bumpsize }
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<ReportGenerator>, esd::Error> {
+        Ok(Qrc::new(Arc::new(RhoReportGenerator::deserialize(de)?)))
+    }
+}
+
+impl TypeId for RhoReportGenerator {
+    fn get_type_id(&self) -> &'static str { "RhoReportGenerator" }
+}
+
+impl ReportGenerator for RhoReportGenerator {
+    fn generate(&self, pricer: &mut Pricer, saveable: &mut Saveable, unbumped: f64)
+        -> Result<BoxReport, qm::Error> {
+
+        // Find the yield curves we should have rho to. Note that we need to
+        // clone the list of credit ids, to avoid borrowing problems.
+        let credit_ids: Vec<String> = pricer.as_bumpable().dependencies()?
+            .yield_curves().keys().cloned().collect();
+        let mut results = HashMap::new();
+        for credit_id in credit_ids.iter() {
+
+            // Bump up and reprice, then down and reprice. The down bump is
+            // applied on top of the up bump, so it is twice the size.
+            let bump = Bump::new_discount(credit_id,
+                BumpYield::new_flat_continuously_compounded(self.bumpsize));
+            let upbumped = bumped_price(&bump, pricer, Some(saveable), unbumped)?;
+
+            let bump = Bump::new_discount(credit_id,
+                BumpYield::new_flat_continuously_compounded(-2.0 * self.bumpsize));
+            let downbumped = bumped_price(&bump, pricer, None, unbumped)?;
+
+            restore_and_verify(pricer, saveable, unbumped)?;
+            saveable.clear();
+
+            let rho = (upbumped - downbumped) / (2.0 * self.bumpsize);
+            results.insert(credit_id.to_string(), rho);
+        }
+
+        Ok(Qbox::new(Box::new(RhoReport { bumpsize: self.bumpsize, results: results })))
+    }
+}
+
+/// Calculates rho, the first differential of the price with respect to the
+/// continuously compounded rate of the discount curve with the given credit
+/// id, by central finite differences. The forwards of any assets are left
+/// unchanged. The pricer is restored after each bump, so that it ends
+/// unchanged. It is an error if there is no such discount curve.
+pub fn rho<P: Pricer + ?Sized>(pricer: &mut P, credit_id: &str, bumpsize: f64)
+    -> Result<f64, qm::Error> {
+
+    if bumpsize <= 0.0 {
+        return Err(qm::Error::new("Rho bumpsize must be positive"))
+    }
+
+    let mut save = pricer.as_bumpable().new_saveable();
+    let mut bumped = [0.0; 2];
+    for (bump_price, size) in bumped.iter_mut().zip([bumpsize, -bumpsize].iter()) {
+        let bump = Bump::new_discount(credit_id,
+            BumpYield::new_flat_continuously_compounded(*size));
+        let applied = pricer.as_mut_bumpable().bump(&bump, Some(&mut *save))?;
+        let price = if applied { Some(pricer.price()) } else { None };
+        pricer.as_mut_bumpable().restore(&*save)?;
+        save.clear();
+        *bump_price = match price {
+            Some(price) => price?,
+            None => return Err(qm::Error::new(&format!(
+                "No discount curve found for credit id '{}'", credit_id)))
+        };
+    }
+
+    Ok((bumped[0] - bumped[1]) / (2.0 * bumpsize))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use risk::deltagamma::tests::sample_pricer;
+    use risk::marketdata::tests::create_sample_rate;
+    use risk::RcReportGenerator;
+    use core::factories::tests::assert_debug_eq;
+    use dates::Date;
+    use serde_json;
+
+    #[test]
+    fn rho_european() {
+
+        // create a pricer for a european at the money call
+        let mut pricer = sample_pricer();
+        let unbumped = pricer.price().unwrap();
+        assert_approx(unbumped, 16.710717400832973, 1e-12);
+
+        // The sample European discounts on the yield curve of its underlying
+        // from the payment date, two business days after expiry, to the
+        // settlement date, two business days after today. Bumping only the
+        // discounting, rho is minus the price times the time between them,
+        // which is negative even for a call.
+        let curve = create_sample_rate();
+        let (_, t_pay) = curve.r_and_t(Date::from_ymd(2018, 06, 05)).unwrap();
+        let (_, t_settle) = curve.r_and_t(Date::from_ymd(2017, 01, 04)).unwrap();
+        let t = t_pay - t_settle;
+        let discount_rho = rho(&mut *pricer, "LSE", 0.0001).unwrap();
+        assert!(discount_rho < 0.0, "rho={}", discount_rho);
+        assert_approx(discount_rho, -t * unbumped, 1e-6);
+
+        // the pricer should be left unchanged
+        assert_eq!(pricer.price().unwrap(), unbumped);
+
+        // By contrast, a yield bump also raises the forward, which dominates
+        // for a call, so the overall rate sensitivity is positive
+        let mut save = pricer.as_bumpable().new_saveable();
+        let bump = Bump::new_yield("LSE", BumpYield::new_flat_continuously_compounded(0.0001));
+        let yield_bumped = bumped_price(&bump, &mut *pricer, Some(&mut *save), unbumped).unwrap();
+        pricer.as_mut_bumpable().restore(&*save).unwrap();
+        save.clear();
+        assert!(yield_bumped > unbumped, "yield_bumped={}", yield_bumped);
+
+        // The report gives the same rho. The option's own credit curve is
+        // a dependency, but it is not used for discounting this European.
+        let generator = RhoReportGenerator::new(0.0001);
+        let report = generator.generate(&mut *pricer, &mut *save, unbumped).unwrap();
+        let results = report.as_any().downcast_ref::<RhoReport>().unwrap().results();
+        assert_eq!(results.len(), 2);
+        assert_approx(*results.get("LSE").unwrap(), discount_rho, 1e-9);
+        assert_approx(*results.get("OPT").unwrap(), 0.0, 1e-12);
+        assert_eq!(pricer.price().unwrap(), unbumped);
+    }
+
+    #[test]
+    fn rho_errors_if_no_discount_curve() {
+        let mut pricer = sample_pricer();
+        assert!(rho(&mut *pricer, "GBP", 0.0001).is_err());
+        assert!(rho(&mut *pricer, "LSE", 0.0).is_err());
+    }
+
+    #[test]
+    fn serde_rho_generator_roundtrip() {
+
+        // create some sample data
+        let generator = RcReportGenerator::new(Arc::new(RhoReportGenerator::new(0.0001)));
+
+        // round trip it via JSON
+        let serialized = serde_json::to_string_pretty(&generator).unwrap();
+        print!("serialized: {}\n", serialized);
+        let deserialized: RcReportGenerator = serde_json::from_str(&serialized).unwrap();
+
+        // check that they match, at least in debug representation
+        assert_debug_eq(&generator, &deserialized);
+    }
+
+    fn assert_approx(value: f64, expected: f64, tolerance: f64) {
+        assert!(approx_eq(value, expected, tolerance),
+            "value={} expected={}", value, expected);
+    }
+}
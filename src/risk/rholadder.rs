@@ -0,0 +1,224 @@
+use std::collections::HashMap;
+use std::any::Any;
+use std::sync::Arc;
+use std::fmt;
+use math::numerics::{ApproxEq, approx_eq};
+use risk::Report;
+use risk::BoxReport;
+use risk::ReportGenerator;
+use risk::ReportTolerances;
+use risk::Pricer;
+use risk::Saveable;
+use risk::ApproxEqReport;
+use risk::bumpcache::{BumpedPriceCache, cached_bumped_price};
+use data::bump::Bump;
+use data::bumpyield::BumpYield;
+use data::bumpyield::RateTenor;
+use dates::Date;
+use core::qm;
+use core::factories::TypeId;
+use core::factories::{Qrc, Qbox};
+use serde::Deserialize;
+use erased_serde as esd;
+
+/// A rho ladder splits the rho to each yield curve by pillar, giving the
+/// key-rate sensitivities used for hedging rates by tenor. The buckets are
+/// defined by RateTenor, such that the rhos in a ladder add up to the rho
+/// to a flat annualised bump, other than the effects of nonlinearity. As
+/// with RhoReport, the curves are only bumped where they are used for
+/// discounting.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RhoLadderReport {
+    bumpsize: f64,
+    results: HashMap<String, Vec<(Date, f64)>>
+}
+
+impl Report for RhoLadderReport {
+    fn as_any(&self) -> &Any { self }
+}
+
+impl TypeId for RhoLadderReport {
+    fn get_type_id(&self) -> &'static str { "RhoLadderReport" }
+}
+
+impl RhoLadderReport {
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qbox<Report>, esd::Error> {
+        Ok(Qbox::new(Box::new(RhoLadderReport::deserialize(de)?)))
+    }
+
+    /// The ladder for each yield curve, keyed by credit id, as pairs of
+    /// pillar date and rho, in increasing order of date.
+    pub fn results(&self) -> &HashMap<String, Vec<(Date, f64)>> { &self.results }
+}
+
+impl<'v> ApproxEq<ReportTolerances, &'v RhoLadderReport> for &'v RhoLadderReport {
+    fn validate(self, other: &'v RhoLadderReport, tol: &ReportTolerances,
+        _msg: &str, diffs: &mut fmt::Formatter) -> fmt::Result {
+
+        if self.results.len() != other.results.len() {
+            write!(diffs, "RhoLadderReport: number of reports {} != {}", self.results.len(), other.results.len())?;
+        }
+
+        // Rho is based on diffs, so should use the currency risk tolerance.
+        let tolerance = tol.currency_risk() / self.bumpsize;
+
+        for (id, ladder) in &self.results {
+            if let Some(other_ladder) = other.results.get(id) {
+                if ladder.len() != other_ladder.len() {
+                    writeln!(diffs, "RhoLadderReport: {} number of pillars {} != {}",
+                        id, ladder.len(), other_ladder.len())?;
+                }
+                for (&(pillar, rho), &(other_pillar, other_rho)) in ladder.iter().zip(other_ladder.iter()) {
+                    if pillar != other_pillar {
+                        writeln!(diffs, "RhoLadderReport: {} pillar {} != {}", id, pillar, other_pillar)?;
+                    } else if !approx_eq(rho, other_rho, tolerance) {
+                        writeln!(diffs, "RhoLadderReport: {} pillar {} rho {} != {} tol={}",
+                            id, pillar, rho, other_rho, tolerance)?;
+                    }
+                }
+            } else {
+                write!(diffs, "RhoLadderReport: {} is missing", id)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl ApproxEqReport for RhoLadderReport {
+    fn validate_report(&self, other: &Report, tol: &ReportTolerances,
+        msg: &str, diffs: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(other_report) = other.as_any().downcast_ref::<RhoLadderReport>() {
+            self.validate(other_report, tol, msg, diffs)
+        } else {
+            write!(diffs, "RhoLadderReport: mismatching report {} != {}", self.get_type_id(), other.get_type_id())?;
+            Ok(())
+        }
+    }
+}
+
+/// Calculator for key-rate rho. Each pillar of each discount curve is
+/// bumped up and down independently by an additive bump in annualised
+/// yield. Pillars whose buckets lie entirely beyond the last date on which
+/// the curve is used have no effect on the price, so they are given zero
+/// rho without repricing. Curves with no pillars have empty ladders.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RhoLadderReportGenerator {
+    bumpsize: f64
+}
+
+impl RhoLadderReportGenerator {
+    pub fn new(bumpsize: f64) -> RhoLadderReportGenerator {
+        RhoLadderReportGenerator { bumpsize: bumpsize }
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<ReportGenerator>, esd::Error> {
+        Ok(Qrc::new(Arc::new(RhoLadderReportGenerator::deserialize(de)?)))
+    }
+}
+
+impl TypeId for RhoLadderReportGenerator {
+    fn get_type_id(&self) -> &'static str { "RhoLadderReportGenerator" }
+}
+
+impl ReportGenerator for RhoLadderReportGenerator {
+    fn generate(&self, pricer: &mut Pricer, saveable: &mut Saveable, unbumped: f64)
+        -> Result<BoxReport, qm::Error> {
+        self.generate_cached(pricer, saveable, unbumped, &mut BumpedPriceCache::new())
+    }
+
+    fn generate_cached(&self, pricer: &mut Pricer, saveable: &mut Saveable,
+        unbumped: f64, cache: &mut BumpedPriceCache) -> Result<BoxReport, qm::Error> {
+
+        // Find the yield curves we should have rho to, their high water
+        // marks and their pillars. Note that we need to clone these, to
+        // avoid borrowing problems.
+        let mut curves: Vec<(String, Date, Vec<Date>)> = Vec::new();
+        {
+            let dependencies = pricer.as_bumpable().dependencies()?;
+            let context = pricer.as_bumpable().context();
+            for (credit_id, hwm) in dependencies.yield_curves().iter() {
+                let curve = context.yield_curve(credit_id, *hwm)?;
+                curves.push((credit_id.to_string(), *hwm, curve.pillar_dates()));
+            }
+        }
+
+        let mut results = HashMap::new();
+        for &(ref credit_id, hwm, ref pillars) in curves.iter() {
+            let mut ladder = Vec::with_capacity(pillars.len());
+            for tenor in RateTenor::ladder(pillars).iter() {
+                let rho = if tenor.is_after(hwm) {
+                    0.0
+                } else {
+                    let up = [Bump::new_discount(credit_id,
+                        BumpYield::new_tenor_annualised(*tenor, self.bumpsize))];
+                    let upbumped = cached_bumped_price(pricer, saveable, &up, unbumped, cache)?;
+
+                    let down = [Bump::new_discount(credit_id,
+                        BumpYield::new_tenor_annualised(*tenor, -self.bumpsize))];
+                    let downbumped = cached_bumped_price(pricer, saveable, &down, unbumped, cache)?;
+
+                    (upbumped - downbumped) / (2.0 * self.bumpsize)
+                };
+                ladder.push((tenor.pillar(), rho));
+            }
+            results.insert(credit_id.to_string(), ladder);
+        }
+
+        Ok(Qbox::new(Box::new(RhoLadderReport { bumpsize: self.bumpsize, results })))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use risk::deltagamma::tests::sample_pricer;
+    use risk::bumped_price;
+
+    #[test]
+    fn rho_ladder_european() {
+
+        let mut pricer = sample_pricer();
+        let unbumped = pricer.price().unwrap();
+        let mut save = pricer.as_bumpable().new_saveable();
+
+        let generator = RhoLadderReportGenerator::new(0.0001);
+        let report = generator.generate(&mut *pricer, &mut *save, unbumped).unwrap();
+        let results = report.as_any().downcast_ref::<RhoLadderReport>().unwrap().results();
+        assert_eq!(results.len(), 2);
+
+        // The sample curve has pillars at 0, 14, 182, 364 and 728 days after
+        // 2016-12-30. The European pays on 2018-06-05, between the last two,
+        // so they carry almost all of its rho, the nearer one the most. The
+        // 182 day pillar touches neither the settlement nor the payment
+        // date, so has no rho at all.
+        let ladder = results.get("LSE").unwrap();
+        assert_eq!(ladder.len(), 5);
+        assert_eq!(ladder[3].0, Date::from_ymd(2017, 12, 29));
+        let rhos: Vec<f64> = ladder.iter().map(|&(_, rho)| rho).collect();
+        let total: f64 = rhos.iter().sum();
+        assert!(total < 0.0, "rhos={:?}", rhos);
+        assert!(rhos[3] < rhos[4] && rhos[4] < 0.0, "rhos={:?}", rhos);
+        assert!((rhos[3] + rhos[4]) / total > 0.99, "rhos={:?}", rhos);
+        assert_eq!(rhos[2], 0.0);
+
+        // the ladder should sum to the rho to a flat annualised bump
+        let mut flat = [0.0; 2];
+        for (price, size) in flat.iter_mut().zip([0.0001, -0.0001].iter()) {
+            let bump = Bump::new_discount("LSE", BumpYield::new_flat_annualised(*size));
+            *price = bumped_price(&bump, &mut *pricer, Some(&mut *save), unbumped).unwrap();
+            pricer.as_mut_bumpable().restore(&*save).unwrap();
+            save.clear();
+        }
+        let flat_rho = (flat[0] - flat[1]) / 0.0002;
+        assert!((total - flat_rho).abs() < 1e-6, "total={} flat_rho={}", total, flat_rho);
+
+        // the option's own credit curve is not used for discounting
+        for &(_, rho) in results.get("OPT").unwrap().iter() {
+            assert!(rho.abs() < 1e-12, "rho={}", rho);
+        }
+
+        // the pricer is left unchanged
+        assert_eq!(pricer.price().unwrap(), unbumped);
+    }
+}
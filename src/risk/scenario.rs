@@ -0,0 +1,251 @@
+use risk::Pricer;
+use risk::bumpset::BumpSet;
+use risk::bumptime::BumpTime;
+use data::bump::Bump;
+use core::qm;
+
+/// A scenario is a set of bumps that are applied together, for example for
+/// stress testing, where we want the price with spot down, vol up and rates
+/// up all at the same time. The bumps are applied in order, optionally after
+/// moving the pricer forward in time. See BumpSet.
+pub struct Scenario {
+    bumps: BumpSet,
+    time_bump: Option<BumpTime>
+}
+
+impl Scenario {
+    /// Creates a scenario. The time bump, if supplied, is applied before
+    /// any of the other bumps, so they are bumps to the market data as of
+    /// the new spot date.
+    pub fn new(bumps: Vec<Bump>, time_bump: Option<BumpTime>) -> Scenario {
+        let bumps = bumps.into_iter().fold(BumpSet::new(), |set, bump| set.add(bump));
+        Scenario { bumps: bumps, time_bump: time_bump }
+    }
+
+    pub fn bumps(&self) -> &[Bump] { self.bumps.bumps() }
+    pub fn time_bump(&self) -> Option<&BumpTime> { self.time_bump.as_ref() }
+
+    /// Applies all the bumps in the scenario and returns the bumped price.
+    /// The pricer is always left as it started, even if one of the bumps or
+    /// the pricing fails.
+    pub fn price(&self, pricer: &mut Pricer) -> Result<f64, qm::Error> {
+
+        if let Some(ref time_bump) = self.time_bump {
+            // The time bump irreversibly modifies the pricer, so bump a clone
+            // of it. There is then nothing to restore.
+            let mut pricer_clone = pricer.clone_box();
+            pricer_clone.bump_time(time_bump)?;
+            self.bump_and_price(&mut *pricer_clone)
+
+        } else {
+            self.bump_and_price(pricer)
+        }
+    }
+
+    /// Applies the bumps, prices, then undoes the bumps in reverse order.
+    /// A failure to apply the bumps has already been rolled back.
+    fn bump_and_price(&self, pricer: &mut Pricer) -> Result<f64, qm::Error> {
+
+        let saved = self.bumps.apply_all(pricer.as_mut_bumpable())?;
+        let result = pricer.price();
+        self.bumps.restore_all(pricer.as_mut_bumpable(), saved)?;
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+    use risk::Bumpable;
+    use risk::Saveable;
+    use risk::PricerClone;
+    use risk::TimeBumpable;
+    use risk::pricereport::PriceReport;
+    use risk::dependencies::DependencyCollector;
+    use risk::deltagamma::tests::sample_pricer;
+    use risk::marketdata::tests::sample_market_data;
+    use risk::marketdata::tests::sample_european;
+    use instruments::Priceable;
+    use instruments::PricingContext;
+    use data::bumpspot::BumpSpot;
+    use data::bumpvol::BumpVol;
+    use data::bumpyield::BumpYield;
+    use data::bumpdivs::BumpDivs;
+    use data::bumpspotdate::SpotDynamics;
+    use dates::Date;
+    use dates::datetime::DateTime;
+    use dates::datetime::TimeOfDay;
+
+    // a pricer that fails to apply any dividend bump, to test rollback
+    struct FailingDivsPricer {
+        pricer: Box<Pricer>
+    }
+
+    impl Pricer for FailingDivsPricer {
+        fn as_bumpable(&self) -> &Bumpable { self }
+        fn as_mut_bumpable(&mut self) -> &mut Bumpable { self }
+        fn as_mut_time_bumpable(&mut self) -> &mut TimeBumpable { self }
+        fn price_report(&self) -> Result<PriceReport, qm::Error> {
+            self.pricer.price_report()
+        }
+    }
+
+    impl PricerClone for FailingDivsPricer {
+        fn clone_box(&self) -> Box<Pricer> {
+            Box::new(FailingDivsPricer { pricer: self.pricer.clone_box() })
+        }
+    }
+
+    impl Bumpable for FailingDivsPricer {
+        fn bump(&mut self, bump: &Bump, save: Option<&mut Saveable>) -> Result<bool, qm::Error> {
+            if let &Bump::Divs(_, _) = bump {
+                return Err(qm::Error::new("Dividend bumps not supported"))
+            }
+            self.pricer.as_mut_bumpable().bump(bump, save)
+        }
+        fn dependencies(&self) -> Result<&DependencyCollector, qm::Error> {
+            self.pricer.as_bumpable().dependencies()
+        }
+        fn context(&self) -> &PricingContext {
+            self.pricer.as_bumpable().context()
+        }
+        fn new_saveable(&self) -> Box<Saveable> {
+            self.pricer.as_bumpable().new_saveable()
+        }
+        fn restore(&mut self, saved: &Saveable) -> Result<(), qm::Error> {
+            self.pricer.as_mut_bumpable().restore(saved)
+        }
+    }
+
+    impl TimeBumpable for FailingDivsPricer {
+        fn bump_time(&mut self, bump: &BumpTime) -> Result<(), qm::Error> {
+            self.pricer.bump_time(bump)
+        }
+    }
+
+    fn stress_bumps() -> Vec<Bump> {
+        vec![
+            Bump::new_spot("BP.L", BumpSpot::new_relative(-0.1)),
+            Bump::new_vol("BP.L", BumpVol::new_flat_additive(0.05)),
+            Bump::new_yield("LSE", BumpYield::new_flat_annualised(0.005))]
+    }
+
+    #[test]
+    fn three_factor_stress_european() {
+
+        let mut pricer = sample_pricer();
+        let unbumped = pricer.price().unwrap();
+        assert_approx(unbumped, 16.710717400832973, 1e-12);
+
+        let scenario = Scenario::new(stress_bumps(), None);
+        let stressed = scenario.price(&mut *pricer).unwrap();
+
+        // the same bumps applied directly to the market data give the same price
+        let mut market_data = sample_market_data();
+        for bump in stress_bumps().iter() {
+            assert!(market_data.bump(bump, None).unwrap());
+        }
+        let val_date = DateTime::new(Date::from_ymd(2017, 01, 02), TimeOfDay::Open);
+        let expected = sample_european().price(&market_data, val_date).unwrap();
+        assert_approx(stressed, expected, 1e-12);
+        assert!(stressed < unbumped, "stressed={} unbumped={}", stressed, unbumped);
+
+        // everything is restored
+        assert_eq!(pricer.price().unwrap(), unbumped);
+        assert_eq!(pricer.as_bumpable().context().spot("BP.L").unwrap(), 100.0);
+
+        // and the scenario can be repriced, giving the same result
+        let again = scenario.price(&mut *pricer).unwrap();
+        assert_eq!(again, stressed);
+        assert_eq!(pricer.price().unwrap(), unbumped);
+    }
+
+    #[test]
+    fn repeated_bumps_restore_to_start() {
+
+        // two bumps to the same spot must restore to the original spot,
+        // not the spot after the first bump
+        let mut pricer = sample_pricer();
+        let unbumped = pricer.price().unwrap();
+        let scenario = Scenario::new(vec![
+            Bump::new_spot("BP.L", BumpSpot::new_relative(-0.1)),
+            Bump::new_spot("BP.L", BumpSpot::new_relative(-0.1))], None);
+        let stressed = scenario.price(&mut *pricer).unwrap();
+        assert!(stressed < unbumped, "stressed={} unbumped={}", stressed, unbumped);
+        assert_eq!(pricer.price().unwrap(), unbumped);
+        assert_eq!(pricer.as_bumpable().context().spot("BP.L").unwrap(), 100.0);
+    }
+
+    #[test]
+    fn non_commuting_bumps_are_unwound_in_reverse() {
+
+        // Replacing the spot then scaling it differs from scaling then
+        // replacing. Either way, the bumps are undone one at a time in
+        // reverse, so the pricer returns to the original spot.
+        let replace = Bump::new_spot("BP.L", BumpSpot::new_replace(90.0));
+        let scale = Bump::new_spot("BP.L", BumpSpot::new_relative(-0.1));
+        let mut pricer = sample_pricer();
+        let unbumped = pricer.price().unwrap();
+
+        let replace_then_scale = Scenario::new(vec![replace.clone(), scale.clone()], None)
+            .price(&mut *pricer).unwrap();
+        assert_eq!(pricer.price().unwrap(), unbumped);
+        assert_eq!(pricer.as_bumpable().context().spot("BP.L").unwrap(), 100.0);
+
+        let scale_then_replace = Scenario::new(vec![scale, replace], None)
+            .price(&mut *pricer).unwrap();
+        assert_eq!(pricer.price().unwrap(), unbumped);
+        assert_eq!(pricer.as_bumpable().context().spot("BP.L").unwrap(), 100.0);
+
+        // the spot of 81 is further out of the money than 90
+        assert!(replace_then_scale < scale_then_replace,
+            "replace_then_scale={} scale_then_replace={}",
+            replace_then_scale, scale_then_replace);
+    }
+
+    #[test]
+    fn failed_bump_rolls_back() {
+
+        let mut pricer: Box<Pricer> = Box::new(FailingDivsPricer { pricer: sample_pricer() });
+        let unbumped = pricer.price().unwrap();
+
+        // the third bump fails, after the first two have been applied
+        let mut bumps = stress_bumps();
+        bumps.insert(2, Bump::new_divs("BP.L", BumpDivs::new_all_relative(0.01)));
+        let scenario = Scenario::new(bumps, None);
+        assert!(scenario.price(&mut *pricer).is_err());
+
+        // the spot and vol bumps have been rolled back
+        assert_eq!(pricer.price().unwrap(), unbumped);
+        assert_eq!(pricer.as_bumpable().context().spot("BP.L").unwrap(), 100.0);
+    }
+
+    #[test]
+    fn time_bumped_scenario_leaves_pricer_unchanged() {
+
+        let mut pricer = sample_pricer();
+        let unbumped = pricer.price().unwrap();
+        let time_bump = BumpTime::new(Date::from_ymd(2017, 01, 03),
+            Date::from_ymd(2017, 01, 02), SpotDynamics::StickyForward);
+
+        // with no other bumps, the scenario gives the theta-bumped price
+        let mut clone = pricer.clone_box();
+        clone.bump_time(&time_bump).unwrap();
+        let time_bumped = clone.price().unwrap();
+        let scenario = Scenario::new(Vec::new(), Some(time_bump.clone()));
+        assert_eq!(scenario.price(&mut *pricer).unwrap(), time_bumped);
+        assert_eq!(pricer.price().unwrap(), unbumped);
+
+        // with the stress bumps as well, the pricer is still unchanged
+        let scenario = Scenario::new(stress_bumps(), Some(time_bump));
+        let stressed = scenario.price(&mut *pricer).unwrap();
+        assert!(stressed < time_bumped, "stressed={} time_bumped={}", stressed, time_bumped);
+        assert_eq!(pricer.price().unwrap(), unbumped);
+    }
+
+    fn assert_approx(value: f64, expected: f64, tolerance: f64) {
+        assert!(approx_eq(value, expected, tolerance),
+            "value={} expected={}", value, expected);
+    }
+}
@@ -0,0 +1,216 @@
+use std::collections::HashMap;
+use std::any::Any;
+use std::sync::Arc;
+use std::fmt;
+use math::numerics::{ApproxEq, approx_eq};
+use risk::Report;
+use risk::BoxReport;
+use risk::ReportGenerator;
+use risk::ReportTolerances;
+use risk::Pricer;
+use risk::Saveable;
+use risk::ApproxEqReport;
+use risk::bumpcache::{BumpedPriceCache, cached_bumped_price};
+use data::bump::Bump;
+use data::bumpspot::BumpSpot;
+use core::qm;
+use core::factories::TypeId;
+use core::factories::{Qrc, Qbox};
+use serde::Deserialize;
+use erased_serde as esd;
+
+/// A spot ladder shows the price profile against the spot of each
+/// underlying, repricing at each of a grid of relative spot shifts. Unlike
+/// delta and gamma, it captures the full nonlinearity of the price over
+/// large moves.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SpotLadderReport {
+    results: HashMap<String, Vec<(f64, f64)>>
+}
+
+impl Report for SpotLadderReport {
+    fn as_any(&self) -> &Any { self }
+}
+
+impl TypeId for SpotLadderReport {
+    fn get_type_id(&self) -> &'static str { "SpotLadderReport" }
+}
+
+impl SpotLadderReport {
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qbox<Report>, esd::Error> {
+        Ok(Qbox::new(Box::new(SpotLadderReport::deserialize(de)?)))
+    }
+
+    /// The ladder for each underlying, as pairs of relative spot shift and
+    /// price, in the order the shifts were supplied.
+    pub fn results(&self) -> &HashMap<String, Vec<(f64, f64)>> { &self.results }
+}
+
+impl<'v> ApproxEq<ReportTolerances, &'v SpotLadderReport> for &'v SpotLadderReport {
+    fn validate(self, other: &'v SpotLadderReport, tol: &ReportTolerances,
+        _msg: &str, diffs: &mut fmt::Formatter) -> fmt::Result {
+
+        if self.results.len() != other.results.len() {
+            write!(diffs, "SpotLadderReport: number of reports {} != {}", self.results.len(), other.results.len())?;
+        }
+
+        // The ladder contains prices, not differences of prices
+        let tolerance = tol.price();
+
+        for (id, ladder) in &self.results {
+            if let Some(other_ladder) = other.results.get(id) {
+                if ladder.len() != other_ladder.len() {
+                    writeln!(diffs, "SpotLadderReport: {} number of shifts {} != {}",
+                        id, ladder.len(), other_ladder.len())?;
+                }
+                for (&(shift, price), &(other_shift, other_price)) in ladder.iter().zip(other_ladder.iter()) {
+                    if shift != other_shift {
+                        writeln!(diffs, "SpotLadderReport: {} shift {} != {}", id, shift, other_shift)?;
+                    } else if !approx_eq(price, other_price, tolerance) {
+                        writeln!(diffs, "SpotLadderReport: {} shift {} price {} != {} tol={}",
+                            id, shift, price, other_price, tolerance)?;
+                    }
+                }
+            } else {
+                write!(diffs, "SpotLadderReport: {} is missing", id)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl ApproxEqReport for SpotLadderReport {
+    fn validate_report(&self, other: &Report, tol: &ReportTolerances,
+        msg: &str, diffs: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(other_report) = other.as_any().downcast_ref::<SpotLadderReport>() {
+            self.validate(other_report, tol, msg, diffs)
+        } else {
+            write!(diffs, "SpotLadderReport: mismatching report {} != {}", self.get_type_id(), other.get_type_id())?;
+            Ok(())
+        }
+    }
+}
+
+/// Calculator for spot ladders. Each relative shift is applied to the spot
+/// of each underlying independently, restoring between them, so the
+/// bumped states all share the one saveable and pricing context.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SpotLadderReportGenerator {
+    shifts: Vec<f64>
+}
+
+impl SpotLadderReportGenerator {
+    /// Creates a generator for the given relative shifts, such as -0.1 for
+    /// a ten percent fall in spot. A shift of zero gives the unbumped price.
+    /// Shifts must be greater than -1, or generating the report fails.
+    pub fn new(shifts: &[f64]) -> SpotLadderReportGenerator {
+        SpotLadderReportGenerator { shifts: shifts.to_vec() }
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<ReportGenerator>, esd::Error> {
+        Ok(Qrc::new(Arc::new(SpotLadderReportGenerator::deserialize(de)?)))
+    }
+}
+
+impl TypeId for SpotLadderReportGenerator {
+    fn get_type_id(&self) -> &'static str { "SpotLadderReportGenerator" }
+}
+
+impl ReportGenerator for SpotLadderReportGenerator {
+    fn generate(&self, pricer: &mut Pricer, saveable: &mut Saveable, unbumped: f64)
+        -> Result<BoxReport, qm::Error> {
+        self.generate_cached(pricer, saveable, unbumped, &mut BumpedPriceCache::new())
+    }
+
+    fn generate_cached(&self, pricer: &mut Pricer, saveable: &mut Saveable,
+        unbumped: f64, cache: &mut BumpedPriceCache) -> Result<BoxReport, qm::Error> {
+
+        // Note that we need to clone the list of instruments, to avoid
+        // borrowing problems.
+        let instruments = pricer.as_bumpable().dependencies()?.instruments_clone();
+        let mut results = HashMap::new();
+        for id in instruments.iter() {
+            let ladder = spot_ladder(pricer, saveable, id, &self.shifts, unbumped, cache)?;
+            results.insert(id.to_string(), ladder);
+        }
+
+        Ok(Qbox::new(Box::new(SpotLadderReport { results })))
+    }
+}
+
+/// Prices with the spot of the given underlying shifted by each of the
+/// relative shifts in turn, restoring the pricer after each. Returns pairs
+/// of shift and price. It is an error if any shift is -1 or less, as that
+/// would make the spot zero or negative.
+pub fn spot_ladder(pricer: &mut Pricer, saveable: &mut Saveable, id: &str,
+    shifts: &[f64], unbumped: f64, cache: &mut BumpedPriceCache)
+    -> Result<Vec<(f64, f64)>, qm::Error> {
+
+    if let Some(shift) = shifts.iter().find(|&&shift| !(shift > -1.0)) {
+        return Err(qm::Error::new(&format!("Spot ladder shift {} would make \
+            the spot of {} zero or negative", shift, id)))
+    }
+
+    let mut ladder = Vec::with_capacity(shifts.len());
+    for &shift in shifts.iter() {
+        let price = if shift == 0.0 {
+            unbumped
+        } else {
+            let bump = [Bump::new_spot(id, BumpSpot::new_relative(shift))];
+            cached_bumped_price(pricer, saveable, &bump, unbumped, cache)?
+        };
+        ladder.push((shift, price));
+    }
+    Ok(ladder)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use risk::deltagamma::tests::sample_pricer;
+
+    #[test]
+    fn spot_ladder_european() {
+
+        let mut pricer = sample_pricer();
+        let unbumped = pricer.price().unwrap();
+        let mut save = pricer.as_bumpable().new_saveable();
+
+        // shifts from -20% to +20% in steps of 2%
+        let shifts: Vec<f64> = (-10..11).map(|i| i as f64 * 0.02).collect();
+        let generator = SpotLadderReportGenerator::new(&shifts);
+        let report = generator.generate(&mut *pricer, &mut *save, unbumped).unwrap();
+        let results = report.as_any().downcast_ref::<SpotLadderReport>().unwrap().results();
+        assert_eq!(results.len(), 1);
+        let ladder = results.get("BP.L").unwrap();
+        assert_eq!(ladder.len(), shifts.len());
+
+        // the unshifted point is the base price
+        assert_eq!(ladder[10], (0.0, unbumped));
+        assert!(approx_eq(ladder[10].1, 16.710717400832973, 1e-12), "ladder={:?}", ladder);
+
+        // the price of a call increases with spot
+        for pair in ladder.windows(2) {
+            assert!(pair[1].1 > pair[0].1, "ladder={:?}", ladder);
+        }
+
+        // the pricer is left unchanged
+        assert_eq!(pricer.price().unwrap(), unbumped);
+    }
+
+    #[test]
+    fn spot_ladder_rejects_shifts_to_zero_spot() {
+
+        let mut pricer = sample_pricer();
+        let unbumped = pricer.price().unwrap();
+        let mut save = pricer.as_bumpable().new_saveable();
+
+        for &shift in [-1.0, -1.5].iter() {
+            let generator = SpotLadderReportGenerator::new(&[0.1, shift]);
+            let err = generator.generate(&mut *pricer, &mut *save, unbumped).unwrap_err();
+            assert!(format!("{}", err).contains("zero or negative"), "unexpected error: {}", err);
+        }
+        assert_eq!(pricer.price().unwrap(), unbumped);
+    }
+}
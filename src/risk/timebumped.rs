@@ -155,6 +155,11 @@ mod tests {
     use risk::vegavolga::VegaVolgaReport;
     use data::bumpspotdate::SpotDynamics;
     use data::bumpvol::BumpVol;
+    use data::forward::Forward;
+    use dates::Date;
+    use instruments::assets::RcCurrency;
+    use risk::marketdata::tests::sample_currency;
+    use risk::marketdata::tests::sample_equity;
 
     #[test]
     fn theta_european_call() {
@@ -208,6 +213,45 @@ mod tests {
         assert_approx(finally, unbumped, 1e-14);
     }
 
+    #[test]
+    fn theta_european_call_sticky_spot_versus_sticky_forward() {
+        let mut pricer = sample_pricer();
+        let unbumped = pricer.price().unwrap();
+        let spot_date = pricer.as_bumpable().context().spot_date();
+        let theta_date = spot_date + 1;
+
+        let mut theta = |dynamics| {
+            let bump = BumpTime::new(theta_date, theta_date, dynamics);
+            let generator = TimeBumpedReportGenerator::new(bump);
+            let mut save = pricer.as_bumpable().new_saveable();
+            let report = generator.generate(&mut *pricer, &mut *save, unbumped).unwrap();
+            report.as_any().downcast_ref::<TimeBumpedReport>().unwrap().theta()
+        };
+        let sticky_forward = theta(SpotDynamics::StickyForward);
+        let sticky_spot = theta(SpotDynamics::StickySpot);
+        assert_approx(sticky_forward, -0.014051516972845235, 1e-12);
+
+        // Under sticky spot, the forward to expiry loses a day of carry as
+        // well, so the call loses value faster than from time decay alone
+        assert!(sticky_spot < sticky_forward,
+            "sticky_spot={} sticky_forward={}", sticky_spot, sticky_forward);
+
+        // Check the mechanism: spot is unchanged, but the forward falls
+        let currency = RcCurrency::new(Arc::new(sample_currency(2)));
+        let equity = sample_equity(currency, 2);
+        let expiry = Date::from_ymd(2018, 06, 01);
+        let forward_at = |pricer: &Pricer| pricer.as_bumpable().context()
+            .forward_curve(&equity, expiry).unwrap().forward(expiry).unwrap();
+        let forward = forward_at(&*pricer);
+        let mut rolled = pricer.clone_box();
+        let bump = BumpTime::new(theta_date, theta_date, SpotDynamics::StickySpot);
+        rolled.as_mut_time_bumpable().bump_time(&bump).unwrap();
+        assert_eq!(rolled.as_bumpable().context().spot("BP.L").unwrap(), 100.0);
+        let rolled_forward = forward_at(&*rolled);
+        assert!(rolled_forward < forward,
+            "rolled_forward={} forward={}", rolled_forward, forward);
+    }
+
     fn assert_approx(value: f64, expected: f64, tolerance: f64) {
         assert!(approx_eq(value, expected, tolerance),
             "value={} expected={}", value, expected);
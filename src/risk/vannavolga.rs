@@ -0,0 +1,326 @@
+use std::collections::HashMap;
+use std::any::Any;
+use std::sync::Arc;
+use std::fmt;
+use math::numerics::{ApproxEq, approx_eq};
+use risk::Report;
+use risk::BoxReport;
+use risk::ReportGenerator;
+use risk::ReportTolerances;
+use risk::Pricer;
+use risk::Saveable;
+use risk::bumpcache::{BumpedPriceCache, cached_bumped_price};
+use risk::vegavolga::vega_volga;
+use risk::ApproxEqReport;
+use data::bump::Bump;
+use data::bumpspot::BumpSpot;
+use data::bumpvol::BumpVol;
+use core::qm;
+use core::factories::TypeId;
+use core::factories::{Qrc, Qbox};
+use serde::Deserialize;
+use erased_serde as esd;
+
+/// Vanna is the cross derivative of price with respect to the spot and the
+/// volatility of an underlying, in other words the sensitivity of delta to
+/// vol, or of vega to spot. Volga is the second derivative of price with
+/// respect to volatility. Together they are the main sensitivities to the
+/// smile. This report shows the vanna and volga with respect to each of the
+/// underlyings that affect the price. Vol, and volga itself, are as defined
+/// in VegaVolgaReport.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct VannaVolgaReport {
+    spot_bumpsize: f64,
+    vol_bumpsize: f64,
+    results: HashMap<String, VannaVolga>
+}
+
+impl Report for VannaVolgaReport {
+    fn as_any(&self) -> &Any { self }
+}
+
+impl TypeId for VannaVolgaReport {
+    fn get_type_id(&self) -> &'static str { "VannaVolgaReport" }
+}
+
+impl VannaVolgaReport {
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qbox<Report>, esd::Error> {
+        Ok(Qbox::new(Box::new(VannaVolgaReport::deserialize(de)?)))
+    }
+
+    pub fn results(&self) -> &HashMap<String, VannaVolga> { &self.results }
+}
+
+impl<'v> ApproxEq<ReportTolerances, &'v VannaVolgaReport> for &'v VannaVolgaReport {
+    fn validate(self, other: &'v VannaVolgaReport, tol: &ReportTolerances,
+        _msg: &str, diffs: &mut fmt::Formatter) -> fmt::Result {
+
+        if self.results.len() != other.results.len() {
+            write!(diffs, "VannaVolgaReport: number of reports {} != {}", self.results.len(), other.results.len())?;
+        }
+
+        // Vanna is a difference of deltas divided by the vol bumpsize, so
+        // its tolerance is the delta tolerance, which is the unit risk over
+        // the spot bumpsize, further divided by the vol bumpsize. Volga uses
+        // the currency risk tolerance, scaled as in VegaVolgaReport.
+        let vanna = tol.unit_risk() / (self.spot_bumpsize * self.vol_bumpsize);
+        let volga = tol.currency_risk() / self.vol_bumpsize.powi(2);
+        let tolerances = VannaVolgaTolerances { vanna, volga };
+
+        for (id, ref vanna_volga) in &self.results {
+            if let Some(other_vanna_volga) = other.results.get(id) {
+                vanna_volga.validate(other_vanna_volga, &tolerances, &id, diffs)?;
+            } else {
+                write!(diffs, "VannaVolgaReport: {} is missing", id)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl ApproxEqReport for VannaVolgaReport {
+    fn validate_report(&self, other: &Report, tol: &ReportTolerances,
+        msg: &str, diffs: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(other_report) = other.as_any().downcast_ref::<VannaVolgaReport>() {
+            self.validate(other_report, tol, msg, diffs)
+        } else {
+            write!(diffs, "VannaVolgaReport: mismatching report {} != {}", self.get_type_id(), other.get_type_id())?;
+            Ok(())
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct VannaVolga {
+    vanna: f64,
+    volga: f64
+}
+
+impl VannaVolga {
+    pub fn vanna(&self) -> f64 { self.vanna }
+    pub fn volga(&self) -> f64 { self.volga }
+}
+
+struct VannaVolgaTolerances {
+    vanna: f64,
+    volga: f64
+}
+
+impl<'v> ApproxEq<VannaVolgaTolerances, &'v VannaVolga> for &'v VannaVolga {
+    fn validate(self, other: &'v VannaVolga, tol: &VannaVolgaTolerances,
+        msg: &str, diffs: &mut fmt::Formatter) -> fmt::Result {
+
+        if !approx_eq(self.vanna, other.vanna, tol.vanna) {
+            writeln!(diffs, "VannaVolga: {} vanna {} != {} tol={}", msg, self.vanna, other.vanna, tol.vanna)?;
+        }
+        if !approx_eq(self.volga, other.volga, tol.volga) {
+            writeln!(diffs, "VannaVolga: {} volga {} != {} tol={}", msg, self.volga, other.volga, tol.volga)?;
+        }
+        Ok(())
+    }
+}
+
+/// Calculator for vanna and volga by bumping. The spot bump size is
+/// specified as a fraction of the current spot, and the vol bump is a flat
+/// additive bump.
+///
+/// Vanna is calculated by bumping spot and vol up and down together in a
+/// 2x2 pattern, and taking the mixed central difference of the four prices.
+/// Volga is calculated exactly as in VegaVolgaReport, with a flat additive
+/// vol bump of the vol bumpsize. That is six repricings for each underlying,
+/// or four if a vega report with the same bump in the same session has
+/// already priced the vol legs. Each leg is bumped from the unbumped state,
+/// and the pricer restored from the same saveable before the next leg, so
+/// that spot and vol bumps never accumulate.
+///
+/// Cross derivatives amplify noise much more than first derivatives. For
+/// analytic or PDE pricers, a spot bump of about 1% and a vol bump of about
+/// 0.01 give accurate results. For Monte-Carlo, the noise in the price is
+/// divided by the product of the bump sizes, so much larger bumps are
+/// needed, say 5% in spot and 0.05 in vol, and the same random numbers must
+/// be used for every leg. Even so, vanna and volga from Monte-Carlo should
+/// be treated with caution, particularly for payoffs with discontinuities.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct VannaVolgaReportGenerator {
+    spot_bumpsize: f64,
+    vol_bumpsize: f64
+}
+
+impl VannaVolgaReportGenerator {
+    pub fn new(spot_bumpsize: f64, vol_bumpsize: f64) -> VannaVolgaReportGenerator {
+        VannaVolgaReportGenerator { spot_bumpsize: spot_bumpsize, vol_bumpsize: vol_bumpsize }
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<ReportGenerator>, esd::Error> {
+        Ok(Qrc::new(Arc::new(VannaVolgaReportGenerator::deserialize(de)?)))
+    }
+}
+
+impl TypeId for VannaVolgaReportGenerator {
+    fn get_type_id(&self) -> &'static str { "VannaVolgaReportGenerator" }
+}
+
+impl ReportGenerator for VannaVolgaReportGenerator {
+    fn generate(&self, pricer: &mut Pricer, saveable: &mut Saveable, unbumped: f64)
+        -> Result<BoxReport, qm::Error> {
+        self.generate_cached(pricer, saveable, unbumped, &mut BumpedPriceCache::new())
+    }
+
+    fn generate_cached(&self, pricer: &mut Pricer, saveable: &mut Saveable,
+        unbumped: f64, cache: &mut BumpedPriceCache) -> Result<BoxReport, qm::Error> {
+
+        if self.spot_bumpsize <= 0.0 || self.vol_bumpsize <= 0.0 {
+            return Err(qm::Error::new("Vanna and volga bumpsizes must be positive"))
+        }
+
+        // Find the underlyings we should have vanna to. Note that we need to
+        // clone the list of instruments, to avoid borrowing problems.
+        let instruments = pricer.as_bumpable().dependencies()?.instruments_clone();
+        let mut results = HashMap::new();
+        for id in instruments.iter() {
+
+            let spot = pricer.as_bumpable().context().spot(id)?;
+
+            // the four corners, in the order ++, +-, -+, --
+            let mut corners = [0.0; 4];
+            let signs = [(1.0, 1.0), (1.0, -1.0), (-1.0, 1.0), (-1.0, -1.0)];
+            for (corner, &(spot_sign, vol_sign)) in corners.iter_mut().zip(signs.iter()) {
+                let spot_bump = Bump::new_spot(id, BumpSpot::new_relative(spot_sign * self.spot_bumpsize));
+                let vol_bump = Bump::new_vol(id, BumpVol::new_flat_additive(vol_sign * self.vol_bumpsize));
+                *corner = cached_bumped_price(pricer, saveable, &[spot_bump, vol_bump],
+                    unbumped, cache)?;
+            }
+
+            // vol only, shared with the vega report
+            let vol_bump = BumpVol::new_flat_additive(self.vol_bumpsize);
+            let volga = vega_volga(pricer, saveable, id, &vol_bump, unbumped,
+                cache)?.volga();
+
+            // vanna calculation
+            let spot_bumpsize = self.spot_bumpsize * spot;
+            let vanna = (corners[0] - corners[1] - corners[2] + corners[3])
+                / (4.0 * spot_bumpsize * self.vol_bumpsize);
+            results.insert(id.to_string(), VannaVolga { vanna, volga });
+        }
+
+        Ok(Qbox::new(Box::new(VannaVolgaReport {
+            spot_bumpsize: self.spot_bumpsize,
+            vol_bumpsize: self.vol_bumpsize,
+            results: results })))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::numerics::approx_eq;
+    use risk::deltagamma::tests::sample_pricer;
+    use risk::deltagamma::DeltaGammaReport;
+    use risk::deltagamma::DeltaGammaReportGenerator;
+    use risk::vegavolga::VegaVolgaReport;
+    use risk::vegavolga::VegaVolgaReportGenerator;
+    use serde_json;
+
+    fn vanna_volga(pricer: &mut Pricer, spot_bumpsize: f64, vol_bumpsize: f64)
+        -> VannaVolga {
+        let unbumped = pricer.price().unwrap();
+        let generator = VannaVolgaReportGenerator::new(spot_bumpsize, vol_bumpsize);
+        let mut save = pricer.as_bumpable().new_saveable();
+        let report = generator.generate(pricer, &mut *save, unbumped).unwrap();
+        let results = report.as_any().downcast_ref::<VannaVolgaReport>().unwrap().results();
+        assert_eq!(results.len(), 1);
+        results.get("BP.L").unwrap().clone()
+    }
+
+    fn delta_with_vol_bump(pricer: &mut Pricer, vol_bumpsize: f64) -> f64 {
+        let mut save = pricer.as_bumpable().new_saveable();
+        let bump = Bump::new_vol("BP.L", BumpVol::new_flat_additive(vol_bumpsize));
+        assert!(pricer.as_mut_bumpable().bump(&bump, Some(&mut *save)).unwrap());
+        save.clear();   // keep the vol bump while calculating delta
+        let unbumped = pricer.price().unwrap();
+        let generator = DeltaGammaReportGenerator::new(0.01);
+        let report = generator.generate(pricer, &mut *save, unbumped).unwrap();
+        let results = report.as_any().downcast_ref::<DeltaGammaReport>().unwrap().results();
+        let delta = results.get("BP.L").unwrap().delta();
+
+        let bump = Bump::new_vol("BP.L", BumpVol::new_flat_additive(-vol_bumpsize));
+        pricer.as_mut_bumpable().bump(&bump, None).unwrap();
+        delta
+    }
+
+    fn vega_with_bump(pricer: &mut Pricer, bump: &Bump) -> f64 {
+        let mut save = pricer.as_bumpable().new_saveable();
+        assert!(pricer.as_mut_bumpable().bump(bump, Some(&mut *save)).unwrap());
+        let bumped = pricer.price().unwrap();
+        let generator = VegaVolgaReportGenerator::new(BumpVol::new_flat_additive(0.0001));
+        let mut vega_save = pricer.as_bumpable().new_saveable();
+        let report = generator.generate(pricer, &mut *vega_save, bumped).unwrap();
+        let results = report.as_any().downcast_ref::<VegaVolgaReport>().unwrap().results();
+        let vega = results.get("BP.L").unwrap().vega();
+        pricer.as_mut_bumpable().restore(&*save).unwrap();
+        vega
+    }
+
+    #[test]
+    fn vanna_volga_european() {
+
+        // create a pricer for a european at the money call
+        let mut pricer = sample_pricer();
+        let unbumped = pricer.price().unwrap();
+        assert_approx(unbumped, 16.710717400832973, 1e-12);
+
+        let results = vanna_volga(&mut *pricer, 0.01, 0.01);
+
+        assert_approx(results.vanna(), -0.012273447155708439, 1e-12);
+
+        // volga is the same as from the vega report with the same bump
+        assert_approx(results.volga(), 85.49648271277022, 1e-12);
+
+        // the pricer is left unchanged
+        assert_approx(pricer.price().unwrap(), unbumped, 1e-12);
+
+        // vanna is the change in delta with vol, which we can calculate
+        // independently from the delta report
+        let delta_up = delta_with_vol_bump(&mut *pricer, 0.01);
+        let delta_down = delta_with_vol_bump(&mut *pricer, -0.01);
+        let expected_vanna = (delta_up - delta_down) / 0.02;
+        assert_approx(results.vanna(), expected_vanna, 1e-10);
+        assert_approx(pricer.price().unwrap(), unbumped, 1e-12);
+
+        // vanna is also the change in vega with spot. The vega report uses a
+        // slightly different vol bump, so this only matches approximately.
+        let vega_up = vega_with_bump(&mut *pricer, &Bump::new_spot("BP.L", BumpSpot::new_relative(0.01)));
+        let vega_down = vega_with_bump(&mut *pricer, &Bump::new_spot("BP.L", BumpSpot::new_relative(-0.01)));
+        assert_approx(results.vanna(), (vega_up - vega_down) / (2.0 * 0.01 * 100.0), 2e-3);
+        assert_approx(pricer.price().unwrap(), unbumped, 1e-12);
+
+        // smaller bumps give a vanna close to the larger one, as the
+        // analytic pricer is smooth
+        let small = vanna_volga(&mut *pricer, 0.001, 0.001);
+        assert_approx(small.vanna(), results.vanna(), 1e-3);
+    }
+
+    #[test]
+    fn vanna_volga_rejects_bad_bumpsize() {
+        let mut pricer = sample_pricer();
+        let unbumped = pricer.price().unwrap();
+        let generator = VannaVolgaReportGenerator::new(0.0, 0.01);
+        let mut save = pricer.as_bumpable().new_saveable();
+        assert!(generator.generate(&mut *pricer, &mut *save, unbumped).is_err());
+    }
+
+    #[test]
+    fn vanna_volga_serde() {
+        let generator = VannaVolgaReportGenerator::new(0.01, 0.01);
+        let serialized = serde_json::to_string(&generator).unwrap();
+        assert_eq!(serialized, r#"{"spot_bumpsize":0.01,"vol_bumpsize":0.01}"#);
+        let deserialized: VannaVolgaReportGenerator = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(serde_json::to_string(&deserialized).unwrap(), serialized);
+    }
+
+    fn assert_approx(value: f64, expected: f64, tolerance: f64) {
+        assert!(approx_eq(value, expected, tolerance),
+            "value={} expected={}", value, expected);
+    }
+}
@@ -0,0 +1,214 @@
+use std::collections::HashMap;
+use std::any::Any;
+use std::sync::Arc;
+use std::fmt;
+use math::numerics::{ApproxEq, approx_eq};
+use risk::Report;
+use risk::BoxReport;
+use risk::ReportGenerator;
+use risk::ReportTolerances;
+use risk::Pricer;
+use risk::Saveable;
+use risk::bumped_price;
+use risk::restore_and_verify;
+use risk::ApproxEqReport;
+use instruments::RcInstrument;
+use data::bump::Bump;
+use data::bumpvol::BumpVol;
+use data::bumpvol::VolTenor;
+use core::qm;
+use core::factories::TypeId;
+use core::factories::{Qrc, Qbox};
+use serde::Deserialize;
+use erased_serde as esd;
+
+/// A vega ladder splits the vega to each underlying by tenor, showing the
+/// sensitivity to the vols in each tenor bucket of the vol surface. The
+/// buckets are defined by VolTenor, such that the vegas in a ladder add up
+/// to the flat vega, other than the effects of nonlinearity.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct VegaLadderReport {
+    bumpsize: f64,
+    results: HashMap<String, Vec<(f64, f64)>>
+}
+
+impl Report for VegaLadderReport {
+    fn as_any(&self) -> &Any { self }
+}
+
+impl TypeId for VegaLadderReport {
+    fn get_type_id(&self) -> &'static str { "VegaLadderReport" }
+}
+
+impl VegaLadderReport {
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qbox<Report>, esd::Error> {
+        Ok(Qbox::new(Box::new(VegaLadderReport::deserialize(de)?)))
+    }
+
+    /// The ladder for each underlying, as pairs of tenor (in vol time) and
+    /// vega, in increasing order of tenor.
+    pub fn results(&self) -> &HashMap<String, Vec<(f64, f64)>> { &self.results }
+}
+
+impl<'v> ApproxEq<ReportTolerances, &'v VegaLadderReport> for &'v VegaLadderReport {
+    fn validate(self, other: &'v VegaLadderReport, tol: &ReportTolerances,
+        _msg: &str, diffs: &mut fmt::Formatter) -> fmt::Result {
+
+        if self.results.len() != other.results.len() {
+            write!(diffs, "VegaLadderReport: number of reports {} != {}", self.results.len(), other.results.len())?;
+        }
+
+        // Vega is based on diffs, so should use the currency risk tolerance.
+        let tolerance = tol.currency_risk() / self.bumpsize;
+
+        for (id, ladder) in &self.results {
+            if let Some(other_ladder) = other.results.get(id) {
+                if ladder.len() != other_ladder.len() {
+                    writeln!(diffs, "VegaLadderReport: {} number of tenors {} != {}",
+                        id, ladder.len(), other_ladder.len())?;
+                }
+                for (&(tenor, vega), &(other_tenor, other_vega)) in ladder.iter().zip(other_ladder.iter()) {
+                    if tenor != other_tenor {
+                        writeln!(diffs, "VegaLadderReport: {} tenor {} != {}", id, tenor, other_tenor)?;
+                    } else if !approx_eq(vega, other_vega, tolerance) {
+                        writeln!(diffs, "VegaLadderReport: {} tenor {} vega {} != {} tol={}",
+                            id, tenor, vega, other_vega, tolerance)?;
+                    }
+                }
+            } else {
+                write!(diffs, "VegaLadderReport: {} is missing", id)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl ApproxEqReport for VegaLadderReport {
+    fn validate_report(&self, other: &Report, tol: &ReportTolerances,
+        msg: &str, diffs: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(other_report) = other.as_any().downcast_ref::<VegaLadderReport>() {
+            self.validate(other_report, tol, msg, diffs)
+        } else {
+            write!(diffs, "VegaLadderReport: mismatching report {} != {}", self.get_type_id(), other.get_type_id())?;
+            Ok(())
+        }
+    }
+}
+
+/// Calculator for bucketed vega. Each tenor pillar of the vol surface is
+/// bumped up and down independently by an additive bump. Vol surfaces with
+/// no pillars, such as flat ones, are bucketed using the tenors supplied
+/// here instead, which are vol times in increasing order.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct VegaLadderReportGenerator {
+    bumpsize: f64,
+    tenors: Vec<f64>
+}
+
+impl VegaLadderReportGenerator {
+    pub fn new(bumpsize: f64, tenors: &[f64]) -> VegaLadderReportGenerator {
+        VegaLadderReportGenerator { bumpsize, tenors: tenors.to_vec() }
+    }
+
+    pub fn from_serial<'de>(de: &mut esd::Deserializer<'de>) -> Result<Qrc<ReportGenerator>, esd::Error> {
+        Ok(Qrc::new(Arc::new(VegaLadderReportGenerator::deserialize(de)?)))
+    }
+}
+
+impl TypeId for VegaLadderReportGenerator {
+    fn get_type_id(&self) -> &'static str { "VegaLadderReportGenerator" }
+}
+
+impl ReportGenerator for VegaLadderReportGenerator {
+    fn generate(&self, pricer: &mut Pricer, saveable: &mut Saveable, unbumped: f64)
+        -> Result<BoxReport, qm::Error> {
+
+        // Find the underlyings we should have vega to, and their pillars.
+        // Note that we need to clone these, to avoid borrowing problems.
+        let mut underlyings: Vec<(RcInstrument, Vec<f64>)> = Vec::new();
+        {
+            let dependencies = pricer.as_bumpable().dependencies()?;
+            let context = pricer.as_bumpable().context();
+            for (instrument, hwm) in dependencies.vol_surfaces().iter() {
+                let forward_fn = || context.forward_curve(&**instrument, *hwm);
+                let surface = context.vol_surface(&**instrument, *hwm, &forward_fn)?;
+                let pillars = surface.pillar_vol_times();
+                let tenors = if pillars.is_empty() { self.tenors.clone() } else { pillars };
+                underlyings.push((instrument.clone(), tenors));
+            }
+        }
+
+        let mut results = HashMap::new();
+        for (instrument, tenors) in underlyings.iter() {
+            let id = instrument.id();
+            let mut ladder = Vec::with_capacity(tenors.len());
+            for tenor in VolTenor::ladder(tenors).iter() {
+
+                // bump up and down independently, restoring after each
+                let bump = Bump::new_vol(id, BumpVol::new_tenor_additive(*tenor, self.bumpsize));
+                let upbumped = bumped_price(&bump, pricer, Some(saveable), unbumped)?;
+                restore_and_verify(pricer, saveable, unbumped)?;
+                saveable.clear();
+
+                let bump = Bump::new_vol(id, BumpVol::new_tenor_additive(*tenor, -self.bumpsize));
+                let downbumped = bumped_price(&bump, pricer, Some(saveable), unbumped)?;
+                restore_and_verify(pricer, saveable, unbumped)?;
+                saveable.clear();
+
+                let vega = (upbumped - downbumped) / (2.0 * self.bumpsize);
+                ladder.push((tenor.tenor(), vega));
+            }
+            results.insert(id.to_string(), ladder);
+        }
+
+        Ok(Qbox::new(Box::new(VegaLadderReport { bumpsize: self.bumpsize, results })))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use risk::deltagamma::tests::sample_pricer;
+
+    #[test]
+    fn vega_ladder_european() {
+
+        let mut pricer = sample_pricer();
+        let unbumped = pricer.price().unwrap();
+        let mut save = pricer.as_bumpable().new_saveable();
+
+        // The sample surface is flat, so the ladder uses these tenors. The
+        // European expires at a vol time of about 1.4 years.
+        let tenors = [0.25, 0.5, 1.0, 2.0, 5.0];
+        let generator = VegaLadderReportGenerator::new(0.0001, &tenors);
+        let report = generator.generate(&mut *pricer, &mut *save, unbumped).unwrap();
+        let results = report.as_any().downcast_ref::<VegaLadderReport>().unwrap().results();
+        assert_eq!(results.len(), 1);
+        let ladder = results.get("BP.L").unwrap();
+        assert_eq!(ladder.len(), tenors.len());
+
+        // the one year bucket, nearest to expiry, should dominate, and the
+        // buckets not adjacent to expiry should have no vega at all
+        let vegas: Vec<f64> = ladder.iter().map(|&(_, vega)| vega).collect();
+        assert!(vegas[2] > vegas[3] && vegas[3] > 0.0, "vegas={:?}", vegas);
+        for i in [0, 1, 4].iter() {
+            assert_eq!(vegas[*i], 0.0, "vegas={:?}", vegas);
+        }
+
+        // the ladder should sum to the flat vega
+        let mut flat = [0.0; 2];
+        for (price, size) in flat.iter_mut().zip([0.0001, -0.0001].iter()) {
+            let bump = Bump::new_vol("BP.L", BumpVol::new_flat_additive(*size));
+            *price = bumped_price(&bump, &mut *pricer, Some(&mut *save), unbumped).unwrap();
+            pricer.as_mut_bumpable().restore(&*save).unwrap();
+            save.clear();
+        }
+        let flat_vega = (flat[0] - flat[1]) / 0.0002;
+        let total: f64 = vegas.iter().sum();
+        assert!((total - flat_vega).abs() < 1e-4, "total={} flat_vega={}", total, flat_vega);
+
+        // the pricer is left unchanged
+        assert_eq!(pricer.price().unwrap(), unbumped);
+    }
+}
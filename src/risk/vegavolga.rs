@@ -9,7 +9,7 @@ use risk::ReportGenerator;
 use risk::ReportTolerances;
 use risk::Pricer;
 use risk::Saveable;
-use risk::bumped_price;
+use risk::bumpcache::{BumpedPriceCache, cached_bump_sequence};
 use risk::ApproxEqReport;
 use data::bump::Bump;
 use data::bumpvol::BumpVol;
@@ -145,37 +145,50 @@ impl TypeId for VegaVolgaReportGenerator {
 impl ReportGenerator for VegaVolgaReportGenerator {
     fn generate(&self, pricer: &mut Pricer, saveable: &mut Saveable, unbumped: f64)
         -> Result<BoxReport, qm::Error> {
+        self.generate_cached(pricer, saveable, unbumped, &mut BumpedPriceCache::new())
+    }
+
+    fn generate_cached(&self, pricer: &mut Pricer, saveable: &mut Saveable,
+        unbumped: f64, cache: &mut BumpedPriceCache) -> Result<BoxReport, qm::Error> {
 
         let bumpsize = self.bump.bumpsize();
-        let bumpsize_2 = bumpsize.powi(2);
 
         // Find the underlyings we should have vega to. Note that we need to
         // clone the list of instruments, to avoid borrowing problems.
         let instruments = pricer.as_bumpable().dependencies()?.instruments_clone();
         let mut results = HashMap::new();
         for id in instruments.iter() {
-
-            // bump up and reprice
-            let bump = Bump::new_vol(id, self.bump.clone());
-            let upbumped = bumped_price(&bump, pricer, Some(saveable), unbumped)?;
-            
-            // bump down and reprice (do not save the result from this)
-            let bump = Bump::new_vol(id, self.bump.opposite());
-            let downbumped = bumped_price(&bump, pricer, None, unbumped)?;
-
-            pricer.as_mut_bumpable().restore(saveable)?;
-            saveable.clear();
-
-            // vega and volga calculations
-            let vega = (upbumped - downbumped) / (2.0 * bumpsize);
-            let volga = (upbumped + downbumped - 2.0 * unbumped) / bumpsize_2;
-            results.insert(id.to_string(), VegaVolga {vega, volga});
+            let vega_volga = vega_volga(pricer, saveable, id, &self.bump,
+                unbumped, cache)?;
+            results.insert(id.to_string(), vega_volga);
         }
 
         Ok(Qbox::new(Box::new(VegaVolgaReport { bumpsize, results })))
     }
 }
 
+/// Calculates the vega and volga of a single underlying, by bumping its vol
+/// up and then down by the given bump and repricing. The repricings are
+/// cached, so that other reports with the same vol bump, such as
+/// VannaVolgaReport, do not need to repeat them.
+pub fn vega_volga(pricer: &mut Pricer, saveable: &mut Saveable, id: &str,
+    bump: &BumpVol, unbumped: f64, cache: &mut BumpedPriceCache)
+    -> Result<VegaVolga, qm::Error> {
+
+    let bumpsize = bump.bumpsize();
+
+    // bump up and reprice, then bump down and reprice, without restoring
+    // in between
+    let bumps = [Bump::new_vol(id, bump.clone()), Bump::new_vol(id, bump.opposite())];
+    let prices = cached_bump_sequence(pricer, saveable, &bumps, unbumped, cache)?;
+    let (upbumped, downbumped) = (prices[0], prices[1]);
+
+    // vega and volga calculations
+    let vega = (upbumped - downbumped) / (2.0 * bumpsize);
+    let volga = (upbumped + downbumped - 2.0 * unbumped) / bumpsize.powi(2);
+    Ok(VegaVolga { vega, volga })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;